@@ -0,0 +1,531 @@
+//! Types shared by the server and the worker.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// The variants of a release or rootfs asking for the set the build script
+/// builds when given none. An empty list is refused instead, it is more
+/// likely a typo than a wish for the default set.
+pub const DEFAULT_SET: &str = "default";
+
+/// What to build. On the wire of `/done` this is
+/// `{"name": "release", "variants": ["base"]}` or `{"name": "livekit"}`, the
+/// same as the name/variants pair older workers send.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "name", content = "variants", rename_all = "lowercase")]
+pub enum BuildType {
+    Livekit,
+    Release(Vec<String>),
+    /// Rootfs tarballs of the given variants, for container and cloud images.
+    Rootfs(Vec<String>),
+    /// Upload the artifacts of `build_id` again, which the worker kept after
+    /// failing to push them.
+    Repush {
+        build_id: i64,
+    },
+}
+
+impl BuildType {
+    /// Whether two requests would build the same thing, regardless of the
+    /// order variants were given in.
+    pub fn same_job(&self, other: &BuildType) -> bool {
+        match (self, other) {
+            (BuildType::Livekit, BuildType::Livekit) => true,
+            (BuildType::Release(a), BuildType::Release(b))
+            | (BuildType::Rootfs(a), BuildType::Rootfs(b)) => {
+                let mut a = a.iter().collect::<Vec<_>>();
+                let mut b = b.iter().collect::<Vec<_>>();
+                a.sort();
+                a.dedup();
+                b.sort();
+                b.dedup();
+                a == b
+            }
+            (BuildType::Repush { build_id: a }, BuildType::Repush { build_id: b }) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BuildType::Livekit => "livekit",
+            BuildType::Release(_) => "release",
+            BuildType::Rootfs(_) => "rootfs",
+            BuildType::Repush { .. } => "repush",
+        }
+    }
+
+    pub fn variants(&self) -> Option<&[String]> {
+        match self {
+            BuildType::Livekit | BuildType::Repush { .. } => None,
+            BuildType::Release(v) | BuildType::Rootfs(v) => Some(v),
+        }
+    }
+
+    /// Whether it asks for the default set of the build script, see
+    /// [`DEFAULT_SET`].
+    pub fn is_default_set(&self) -> bool {
+        self.variants().is_some_and(|v| v == [DEFAULT_SET])
+    }
+
+    /// Like the [`Display`] form, with each variant marked `ok` or `missing`
+    /// after a build that shipped only some of them, e.g.
+    /// `release(base ok, desktop missing)`.
+    pub fn describe(&self, missing: &[String]) -> String {
+        match self.variants() {
+            Some(v) if !missing.is_empty() => format!(
+                "{}({})",
+                self.name(),
+                v.iter()
+                    .map(|x| if missing.contains(x) {
+                        format!("{x} missing")
+                    } else {
+                        format!("{x} ok")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// The short form builds are shown with, e.g. `release(base, desktop)` or
+/// `release(default set)`.
+impl Display for BuildType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            _ if self.is_default_set() => write!(f, "{}(default set)", self.name()),
+            BuildType::Livekit => write!(f, "livekit"),
+            BuildType::Release(v) => write!(f, "release({})", v.join(", ")),
+            BuildType::Rootfs(v) => write!(f, "rootfs({})", v.join(", ")),
+            BuildType::Repush { build_id } => write!(f, "repush(#{build_id})"),
+        }
+    }
+}
+
+/// The representation of [`BuildType`] in queued builds stored in Redis and
+/// in `/workerisstarted` responses, `"Livekit"` or `{"Release": ["base"]}`.
+/// Use with `#[serde(with = "shipit_common::legacy")]`.
+pub mod legacy {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::BuildType;
+
+    #[derive(Serialize, Deserialize)]
+    enum Legacy {
+        Livekit,
+        Release(Vec<String>),
+        Rootfs(Vec<String>),
+        Repush { build_id: i64 },
+    }
+
+    pub fn serialize<S: Serializer>(t: &BuildType, s: S) -> Result<S::Ok, S::Error> {
+        match t.clone() {
+            BuildType::Livekit => Legacy::Livekit,
+            BuildType::Release(v) => Legacy::Release(v),
+            BuildType::Rootfs(v) => Legacy::Rootfs(v),
+            BuildType::Repush { build_id } => Legacy::Repush { build_id },
+        }
+        .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<BuildType, D::Error> {
+        Ok(match Legacy::deserialize(d)? {
+            Legacy::Livekit => BuildType::Livekit,
+            Legacy::Release(v) => BuildType::Release(v),
+            Legacy::Rootfs(v) => BuildType::Rootfs(v),
+            Legacy::Repush { build_id } => BuildType::Repush { build_id },
+        })
+    }
+}
+
+/// The newest `/workerisstarted` response format, the worker sends the one
+/// it reads in `Accept-Version`. Workers sending none get version 1, the
+/// bare externally tagged enum: `"Pending"`, `{"Ping": 3}` or
+/// `{"Working": {...}}`.
+pub const STATUS_VERSION: u32 = 2;
+
+/// A `/workerisstarted` response from version 2 on, e.g.
+/// `{"v": 2, "status": {"type": "working", "job": {...}}}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope<J> {
+    pub v: u32,
+    pub status: Status<J>,
+}
+
+/// What a worker polling for jobs is told to do, with `J` the job.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Status<J> {
+    Working {
+        job: J,
+    },
+    Pending,
+    /// Answer the ping with this id on `/pong`, then poll again.
+    Ping {
+        id: i64,
+        /// Run the checks of `worker check` and send their results along.
+        #[serde(default)]
+        check: bool,
+    },
+    /// Added after this build, the worker polls again as if pending.
+    #[serde(other)]
+    Unknown,
+}
+
+/// One line of `worker check`, also sent with the answer to
+/// `/ping <arch> --check`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    /// Works, but not as it should, e.g. an optional tool missing.
+    Warn,
+    /// The worker cannot build or upload like this.
+    Fail,
+}
+
+impl Outcome {
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        }
+    }
+}
+
+/// Where the artifacts of a build are published. Builds requested by hand
+/// are releases; scheduled builds go to the nightly channel, which is
+/// date-stamped and pruned after a while.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    #[default]
+    Release,
+    Nightly,
+}
+
+impl Channel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Release => "release",
+            Channel::Nightly => "nightly",
+        }
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "release" => Ok(Channel::Release),
+            "nightly" => Ok(Channel::Nightly),
+            _ => Err(format!(
+                "Unknown channel: {s} (expected release or nightly)"
+            )),
+        }
+    }
+}
+
+/// The worker binary an operator wants running on an arch, served by
+/// `/worker/latest`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerRelease {
+    pub version: String,
+    pub url: String,
+    /// Hex sha256 of the binary at `url`.
+    pub sha256: String,
+    /// Detached minisign signature of the binary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_url: Option<String>,
+}
+
+/// A release continuing a failed one, see `/retry --resume`. The build
+/// type lists only the variants still to build.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Resume {
+    /// The failed build.
+    pub from: i64,
+    /// Variants it uploaded, which are not built again.
+    pub done: Vec<String>,
+    /// The aoscbootstrap commit it was built from, if the worker told.
+    pub commit: Option<String>,
+    /// Build even if aoscbootstrap moved on since.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// How far the checkout of the build scripts a worker built from is
+/// behind the canonical repository.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Staleness {
+    /// Commits missing.
+    pub behind: u64,
+    /// Seconds between the commit built and the newest one missing.
+    pub age_secs: u64,
+    /// e.g. `origin/master`.
+    pub remote: String,
+}
+
+/// Why a build failed, as far as the worker could tell from its log.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Failure {
+    /// Name of the matching pattern, e.g. `mirror_timeout`.
+    pub class: String,
+    /// e.g. `mirror timeout fetching https://repo.aosc.io/...`
+    pub summary: String,
+    /// The matching line and a few around it.
+    pub excerpt: String,
+    /// The start of the matching line, to find it again in the uploaded log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<String>,
+}
+
+/// The stage a worker reports as soon as the build script exits, the rest
+/// of the job is publishing what it built.
+pub const STAGE_PUBLISHING: &str = "publishing";
+
+/// How far the upload of an artifact is, sent with the heartbeats while the
+/// worker uploads.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct UploadProgress {
+    /// The file or directory being uploaded.
+    pub artifact: String,
+    /// Bytes of it on the upload host.
+    pub sent: u64,
+    pub total: u64,
+    /// Seconds since the bytes on the upload host last grew.
+    pub stalled_secs: u64,
+}
+
+/// How uploading one artifact went. Builds upload each file on its own, so
+/// the one failing does not take the others along.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ArtifactPush {
+    /// Below the upload directory, as in the manifest.
+    pub path: String,
+    pub success: bool,
+    pub attempts: u32,
+    /// Exit status, or the error spawning scp, of the last failed attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// One command a worker ran for a job, in UNIX seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Step {
+    pub name: String,
+    pub started_at: u64,
+    #[serde(default)]
+    pub finished_at: Option<u64>,
+    /// `None` once finished means it was killed by a signal or could not be
+    /// run at all.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+}
+
+impl Step {
+    fn status(&self) -> String {
+        match (self.finished_at, self.exit_code) {
+            (None, _) => "running".to_string(),
+            (Some(_), Some(0)) => "ok".to_string(),
+            (Some(_), Some(code)) => format!("exit {code}"),
+            (Some(_), None) => "failed".to_string(),
+        }
+    }
+}
+
+/// `steps` as a table with aligned columns, for monospace display:
+///
+/// ```text
+/// git clone     12s  ok
+/// git pull       3s  ok
+/// aosc-mklive   58m  running
+/// ```
+pub fn timeline(steps: &[Step], now: u64) -> String {
+    let rows = steps
+        .iter()
+        .map(|x| {
+            let end = x.finished_at.unwrap_or(now);
+            (
+                x.name.as_str(),
+                human_duration(end.saturating_sub(x.started_at)),
+                x.status(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let name_width = rows.iter().map(|x| x.0.chars().count()).max().unwrap_or(0);
+    let duration_width = rows.iter().map(|x| x.1.len()).max().unwrap_or(0);
+
+    rows.iter()
+        .map(|(name, duration, status)| {
+            format!("{name:<name_width$}  {duration:>duration_width$}  {status}\n")
+        })
+        .collect()
+}
+
+/// The base URL `value` of the variable `name`, such as `shipit_uri`,
+/// without trailing slashes, so that paths are appended with `/` and never
+/// end up as `//done`. The error names the variable.
+pub fn base_url(name: &str, value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let url = url::Url::parse(value).map_err(|e| format!("Invalid {name} {value:?}: {e}"))?;
+
+    let problem = if !matches!(url.scheme(), "http" | "https") {
+        Some("expected an http or https URL")
+    } else if url.host_str().is_none() {
+        Some("no host")
+    } else if url.query().is_some() || url.fragment().is_some() {
+        Some("a base URL takes no query or fragment")
+    } else {
+        None
+    };
+    if let Some(p) = problem {
+        return Err(format!("Invalid {name} {value:?}: {p}"));
+    }
+
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// Human readable duration with the two most significant units, e.g. `1h5m`.
+pub fn human_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    if h > 0 {
+        format!("{h}h{m}m")
+    } else if m > 0 {
+        format!("{m}m")
+    } else {
+        format!("{s}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::*;
+
+    /// A queued build as stored, with the legacy representation.
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Queued {
+        #[serde(with = "crate::legacy")]
+        build_type: BuildType,
+    }
+
+    fn types() -> [BuildType; 4] {
+        [
+            BuildType::Livekit,
+            BuildType::Release(vec!["base".to_string(), "desktop".to_string()]),
+            BuildType::Rootfs(vec![DEFAULT_SET.to_string()]),
+            BuildType::Repush { build_id: 42 },
+        ]
+    }
+
+    #[test]
+    fn build_type_wire_format() {
+        let wire = [
+            r#"{"name":"livekit"}"#,
+            r#"{"name":"release","variants":["base","desktop"]}"#,
+            r#"{"name":"rootfs","variants":["default"]}"#,
+            r#"{"name":"repush","variants":{"build_id":42}}"#,
+        ];
+
+        for (t, wire) in types().iter().zip(wire) {
+            assert_eq!(serde_json::to_string(t).unwrap(), wire);
+            assert_eq!(&serde_json::from_str::<BuildType>(wire).unwrap(), t);
+        }
+    }
+
+    #[test]
+    fn legacy_wire_format() {
+        let wire = [
+            r#"{"build_type":"Livekit"}"#,
+            r#"{"build_type":{"Release":["base","desktop"]}}"#,
+            r#"{"build_type":{"Rootfs":["default"]}}"#,
+            r#"{"build_type":{"Repush":{"build_id":42}}}"#,
+        ];
+
+        for (t, wire) in types().into_iter().zip(wire) {
+            let q = Queued { build_type: t };
+            assert_eq!(serde_json::to_string(&q).unwrap(), wire);
+            assert_eq!(serde_json::from_str::<Queued>(wire).unwrap(), q);
+        }
+    }
+
+    #[test]
+    fn unknown_build_types_are_refused() {
+        for wire in [
+            json!({"name": "Release", "variants": ["base"]}),
+            json!({"name": "iso"}),
+        ] {
+            // the server answers 422 with this
+            let e = serde_json::from_value::<BuildType>(wire).unwrap_err();
+            assert!(e.to_string().contains("expected one of `livekit`"), "{e}");
+        }
+
+        assert!(serde_json::from_value::<BuildType>(json!({"variants": ["base"]})).is_err());
+        assert!(serde_json::from_value::<Queued>(json!({"build_type": "livekit"})).is_err());
+    }
+
+    #[test]
+    fn the_order_of_variants_does_not_make_another_job() {
+        let release = |v: &[&str]| BuildType::Release(v.iter().map(|x| x.to_string()).collect());
+        let base_desktop = release(&["base", "desktop"]);
+
+        assert!(base_desktop.same_job(&release(&["desktop", "base"])));
+        assert!(base_desktop.same_job(&release(&["desktop", "base", "desktop"])));
+        assert!(!base_desktop.same_job(&release(&["base"])));
+        assert!(!base_desktop.same_job(&release(&["base", "server"])));
+        assert!(!base_desktop.same_job(&BuildType::Rootfs(vec![
+            "base".to_string(),
+            "desktop".to_string()
+        ])));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            types().map(|x| x.to_string()),
+            [
+                "livekit",
+                "release(base, desktop)",
+                "rootfs(default set)",
+                "repush(#42)"
+            ]
+        );
+    }
+
+    #[test]
+    fn describe_marks_the_missing_variants() {
+        let missing = ["desktop".to_string()];
+        assert_eq!(
+            types().map(|x| x.describe(&missing)),
+            [
+                "livekit",
+                "release(base ok, desktop missing)",
+                "rootfs(default ok)",
+                "repush(#42)"
+            ]
+        );
+        assert_eq!(types()[1].describe(&[]), "release(base, desktop)");
+    }
+}
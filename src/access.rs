@@ -0,0 +1,126 @@
+//! Restricting who may talk to the worker-facing endpoints, on top of the
+//! shared secret: a list of allowed source networks, and optionally TLS with
+//! client certificates.
+
+use std::{fs::File, io::BufReader, net::IpAddr, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use eyre::OptionExt;
+use ipnet::IpNet;
+use reqwest::StatusCode;
+use rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use tracing::warn;
+
+use crate::{env_list, AppState};
+
+#[derive(Debug, Default)]
+pub struct Access {
+    /// Empty means any source address.
+    allow: Vec<IpNet>,
+    /// Number of trusted proxies in front of us that append to
+    /// `X-Forwarded-For`, 0 to use the peer address.
+    proxies: usize,
+}
+
+impl Access {
+    /// `shipit_worker_allow` is a list of CIDRs, `shipit_trusted_proxies` the
+    /// number of reverse proxies whose `X-Forwarded-For` entries to trust.
+    pub fn from_env() -> eyre::Result<Self> {
+        let allow = env_list("shipit_worker_allow")
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<IpNet>, _>>()?;
+        let proxies = match std::env::var("shipit_trusted_proxies") {
+            Ok(x) => x.parse()?,
+            Err(_) => 0,
+        };
+
+        Ok(Self { allow, proxies })
+    }
+
+    /// The address of the client, as seen by the outermost trusted proxy.
+    fn client(&self, peer: SocketAddr, headers: &HeaderMap) -> Option<IpAddr> {
+        if self.proxies == 0 {
+            return Some(peer.ip());
+        }
+
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .map(|x| x.trim())
+            .collect::<Vec<_>>();
+
+        forwarded
+            .len()
+            .checked_sub(self.proxies)
+            .and_then(|i| forwarded[i].parse().ok())
+    }
+
+    fn allows(&self, peer: SocketAddr, headers: &HeaderMap) -> bool {
+        if self.allow.is_empty() {
+            return true;
+        }
+
+        match self.client(peer, headers) {
+            Some(ip) => self.allow.iter().any(|x| x.contains(&ip)),
+            None => false,
+        }
+    }
+}
+
+/// Middleware for the worker-facing routes.
+pub async fn check(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.access.allows(peer, request.headers()) {
+        warn!("Refused {} from {peer}", request.uri().path());
+        return (StatusCode::FORBIDDEN, "Address not allowed.").into_response();
+    }
+
+    next.run(request).await
+}
+
+/// TLS settings from `shipit_tls_cert`, `shipit_tls_key` and
+/// `shipit_tls_client_ca`, or `None` to serve plain HTTP. Clients must
+/// present a certificate signed by the CA.
+pub fn tls_config() -> eyre::Result<Option<Arc<ServerConfig>>> {
+    let Ok(cert) = std::env::var("shipit_tls_cert") else {
+        return Ok(None);
+    };
+    let key = std::env::var("shipit_tls_key")?;
+    let client_ca = std::env::var("shipit_tls_client_ca")?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_eyre("No private key found in shipit_tls_key")?;
+
+    let mut roots = RootCertStore::empty();
+    for ca in rustls_pemfile::certs(&mut BufReader::new(File::open(client_ca)?)) {
+        roots.add(ca?)?;
+    }
+
+    let verifier = WebPkiClientVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(rustls::crypto::ring::default_provider()),
+    )
+    .build()?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs, key)?;
+
+    Ok(Some(Arc::new(config)))
+}
@@ -0,0 +1,103 @@
+//! Notices for the chats watching the server with `/watch`, for
+//! `shipit_admin_chat` and for the chats set up for alerts: builds requested or started by others, and whatever
+//! holds up dispatch, such as a worker gone silent during a build.
+
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use tokio::time::sleep;
+use tracing::{error, warn};
+
+use crate::{
+    db::{now, Db},
+    format::human_duration,
+    lang::Msg,
+    lifecycle::{IllegalTransition, Phase},
+    outbox::Notification,
+    setup::{self, Role},
+    AppState,
+};
+
+/// Queue `msg` for every watcher but `except`, in the language of each.
+pub async fn notify(
+    db: &mut Db,
+    state: &AppState,
+    except: Option<i64>,
+    msg: Msg,
+    args: &[(&str, &(dyn Display + Sync))],
+) -> eyre::Result<()> {
+    let mut chats = db.watchers().await?;
+    chats.extend(state.admin_chat);
+    chats.extend(setup::chats(db, Role::Alerts, None).await?);
+    chats.sort_unstable();
+    chats.dedup();
+
+    for chat in chats.into_iter().filter(|x| Some(*x) != except) {
+        let text = db.lang(chat).await?.tr(msg, args);
+        db.push_outbox(&Notification::plain(chat, &text)).await?;
+    }
+
+    Ok(())
+}
+
+/// The title of `chat` as last seen, its id if unknown.
+pub async fn chat_name(db: &mut Db, chat: i64) -> eyre::Result<String> {
+    Ok(db
+        .chat(chat)
+        .await?
+        .and_then(|x| x.title)
+        .unwrap_or_else(|| chat.to_string()))
+}
+
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        let mut db = state.db().await;
+        if let Err(e) = stale_workers(&mut db, &state).await {
+            error!("Failed to check for stale workers: {e}");
+        }
+        drop(db);
+
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+/// Mark every running build whose worker has not sent a heartbeat for
+/// `shipit_worker_offline_after` as lost, and tell the watchers once.
+async fn stale_workers(db: &mut Db, state: &AppState) -> eyre::Result<()> {
+    let now = now();
+
+    for r in db.running_worker().await? {
+        let Some(seen) = r.heartbeat_at.or(r.claimed_at) else {
+            continue;
+        };
+        let silent = now.saturating_sub(seen);
+        if silent <= state.worker_offline_after {
+            continue;
+        }
+        // already lost, the watchers know
+        match db.transition(r.build.id, Phase::Lost).await {
+            Err(e) if e.is::<IllegalTransition>() => continue,
+            x => x?,
+        };
+
+        let worker = r.worker.as_deref().unwrap_or("unnamed");
+        warn!(
+            "Worker {worker} has not sent a heartbeat for #{} in {silent}s",
+            r.build.id
+        );
+        notify(
+            db,
+            state,
+            None,
+            Msg::WatchStale,
+            &[
+                ("worker", &worker),
+                ("id", &r.build.id),
+                ("arch", &r.build.arch),
+                ("ago", &human_duration(silent)),
+            ],
+        )
+        .await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! Named tokens for the HTTP API, for CI, dashboards and scripts that have
+//! no Telegram login, each limited to some scopes, see [`crate::auth`].
+//! Queuing builds through `POST /api/v1/builds` also needs the scope of each
+//! build type, and of the pool if not mainline.
+
+use axum::http::HeaderMap;
+use eyre::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::Scope, db::Channel, env_list};
+
+/// A scope of every token, e.g. for an admin script.
+pub const ANY: &str = "*";
+/// Build types, the scopes of tokens from before `enqueue`, which they
+/// imply.
+const BUILD_TYPES: &[&str] = &["livekit", "release", "rootfs"];
+/// The scope to jump the queue with `"priority": "top"`.
+pub const PRIORITY: &str = "priority";
+
+#[derive(Debug)]
+pub struct ApiToken {
+    /// For the audit log, as `api:<name>`.
+    pub name: String,
+    token: String,
+    /// A [`Scope`], build types it may request, [`PRIORITY`],
+    /// `pool:<name>` or [`ANY`].
+    scopes: Vec<String>,
+}
+
+impl ApiToken {
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|x| x == scope || x == ANY)
+    }
+
+    /// Whether it may use the routes needing `scope`.
+    pub fn has(&self, scope: Scope) -> bool {
+        self.allows(scope.name())
+            || (scope == Scope::Enqueue
+                && self
+                    .scopes
+                    .iter()
+                    .any(|x| BUILD_TYPES.contains(&x.as_str())))
+    }
+
+    pub fn scopes(&self) -> &[String] {
+        &self.scopes
+    }
+
+    /// The first pool it is scoped to.
+    pub fn pool(&self) -> Option<&str> {
+        self.scopes.iter().find_map(|x| x.strip_prefix("pool:"))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ApiTokens {
+    tokens: Vec<ApiToken>,
+    /// Where the notices about the builds queued with a token go.
+    pub chat: Option<i64>,
+    /// The `read` scope is needed to read, see [`crate::auth`].
+    pub read_needs_token: bool,
+}
+
+impl ApiTokens {
+    /// `shipit_api_tokens` is a list of `name:token:scope,scope`, the
+    /// scopes being `worker`, `enqueue`, `read`, `admin`, build types,
+    /// `priority`, `pool:<name>` and `*`. The notices go to
+    /// `shipit_api_chat`, `shipit_admin_chat` if unset. No tokens disable
+    /// the API, but for the mainline secret. `shipit_read_needs_token=on`
+    /// closes the reading routes to requests without the `read` scope.
+    pub fn from_env(admin_chat: Option<i64>) -> eyre::Result<Self> {
+        let chat = match std::env::var("shipit_api_chat") {
+            Ok(x) => Some(x.parse()?),
+            Err(_) => admin_chat,
+        };
+        let read_needs_token = match std::env::var("shipit_read_needs_token").as_deref() {
+            Ok("on") => true,
+            Ok("off") | Err(_) => false,
+            Ok(x) => bail!("Invalid shipit_read_needs_token {x}, expected on or off"),
+        };
+
+        Self::new(
+            &env_list("shipit_api_tokens").unwrap_or_default(),
+            chat,
+            read_needs_token,
+        )
+    }
+
+    /// The tokens of `entries`, each `name:token:scope,scope` as in
+    /// `shipit_api_tokens`.
+    pub fn new(
+        entries: &[String],
+        chat: Option<i64>,
+        read_needs_token: bool,
+    ) -> eyre::Result<Self> {
+        let mut tokens = vec![];
+        for i in entries {
+            let mut split = i.splitn(3, ':');
+            let (Some(name), Some(token), Some(scopes)) =
+                (split.next(), split.next(), split.next())
+            else {
+                bail!("Invalid shipit_api_tokens entry, expected name:token:scope,scope");
+            };
+            if name.is_empty() || token.is_empty() {
+                bail!("Invalid shipit_api_tokens entry {name}, empty name or token");
+            }
+
+            tokens.push(ApiToken {
+                name: name.to_string(),
+                token: token.to_string(),
+                scopes: scopes.split(',').map(|x| x.to_string()).collect(),
+            });
+        }
+
+        if !tokens.is_empty() && chat.is_none() {
+            bail!("shipit_api_tokens needs shipit_api_chat or shipit_admin_chat for the notices");
+        }
+
+        Ok(Self {
+            tokens,
+            chat,
+            read_needs_token,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// The token of `Authorization: Bearer <token>`.
+    pub fn find(&self, header: &HeaderMap) -> Option<&ApiToken> {
+        let token = header
+            .get("authorization")?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+
+        self.tokens.iter().find(|x| x.token == token.trim())
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Ahead of the builds already waiting, needs the [`PRIORITY`] scope.
+    Top,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EnqueueRequest {
+    /// Every enabled arch if empty.
+    #[serde(default)]
+    pub arch: Vec<String>,
+    /// `livekit`, `release` or `rootfs`.
+    pub build_type: String,
+    #[serde(default)]
+    pub variants: Vec<String>,
+    /// A branch or commit of the build scripts, not supported: workers
+    /// build what their checkout pulls.
+    #[serde(default, rename = "ref")]
+    pub git_ref: Option<String>,
+    #[serde(default)]
+    pub channel: Option<Channel>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// POSTed the history entry once the builds finish, like a `/hook`.
+    #[serde(default)]
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Mainline if unset, other pools need the `pool:<name>` scope.
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// Why it is built, like `--note`.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Of a release, like `--flavor`.
+    #[serde(default)]
+    pub flavor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Enqueued {
+    /// 0 for a dry run.
+    pub id: i64,
+    pub arch: String,
+    /// In the queue of the arch, 1 is next.
+    pub position: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnqueueResponse {
+    pub builds: Vec<Enqueued>,
+    /// Why arches were left out, e.g. disabled or already queued.
+    pub rejected: Vec<String>,
+}
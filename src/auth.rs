@@ -0,0 +1,300 @@
+//! Who may do what over HTTP. Every protected route takes an [`Auth`]
+//! naming the scope it needs:
+//!
+//! - `worker`: the endpoints workers poll, report and upload logs to,
+//! - `enqueue`: `POST /api/v1/builds`,
+//! - `read`: statistics, the feed, build pages, logs, the worker and
+//!   freshness lists and the metrics, only if `shipit_read_needs_token` is
+//!   `on`, as completion notices link to the build pages and logs,
+//! - `admin`: `/export`, `/import` and `/migrate`.
+//!
+//! Requests authenticate with a named token of `shipit_api_tokens` as
+//! `Authorization: Bearer <token>`, with the secret of a pool (`worker` for
+//! that pool) or with `shipit_admin_secret` (`admin`). As long as no tokens
+//! are configured, the mainline secret has every scope, as it did before
+//! there were scopes. A request without credentials is refused with 401,
+//! one lacking the scope with 403 naming it.
+
+use std::{fmt::Display, marker::PhantomData, sync::Arc};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+
+use crate::{
+    api::ANY,
+    pool::{Pool, Pools, MAINLINE},
+    AppState, BuildRequestError,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Worker,
+    Enqueue,
+    Read,
+    Admin,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Worker => "worker",
+            Scope::Enqueue => "enqueue",
+            Scope::Read => "read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The scope a route needs, as the type parameter of [`Auth`].
+pub trait Needs {
+    const SCOPE: Scope;
+}
+
+/// The markers for [`Needs`], e.g. `Auth<need::Worker>`.
+pub mod need {
+    use super::{Needs, Scope};
+
+    pub struct Worker;
+    pub struct Enqueue;
+    pub struct Read;
+    pub struct Admin;
+
+    impl Needs for Worker {
+        const SCOPE: Scope = Scope::Worker;
+    }
+
+    impl Needs for Enqueue {
+        const SCOPE: Scope = Scope::Enqueue;
+    }
+
+    impl Needs for Read {
+        const SCOPE: Scope = Scope::Read;
+    }
+
+    impl Needs for Admin {
+        const SCOPE: Scope = Scope::Admin;
+    }
+}
+
+/// A request allowed the scope of `S`.
+pub struct Auth<S> {
+    /// `api:<token name>`, `secret:<pool>`, `admin secret`, or `anonymous`
+    /// for reading without `shipit_read_needs_token`.
+    pub name: String,
+    /// Acts for this pool: the one of the secret, or the first `pool:<name>`
+    /// scope of a token.
+    pool: String,
+    /// What a token may do besides, `None` for everything.
+    scopes: Option<Vec<String>>,
+    scope: PhantomData<S>,
+}
+
+impl<S: Needs> Auth<S> {
+    /// For the audit log, e.g. `api:ci (enqueue)`.
+    pub fn actor(&self) -> String {
+        format!("{} ({})", self.name, S::SCOPE)
+    }
+
+    pub fn pool<'a>(&self, pools: &'a Pools) -> &'a Pool {
+        pools.get(&self.pool).unwrap_or(pools.mainline())
+    }
+
+    /// Whether it may also do `scope`, e.g. a build type, see
+    /// [`crate::api::ApiToken::allows`].
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|x| x.iter().any(|x| x == scope || x == ANY))
+    }
+}
+
+#[async_trait]
+impl<S: Needs + Send> FromRequestParts<Arc<AppState>> for Auth<S> {
+    type Rejection = BuildRequestError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let auth = |name: String, pool: &str, scopes: Option<Vec<String>>| Auth {
+            name,
+            pool: pool.to_string(),
+            scopes,
+            scope: PhantomData,
+        };
+        let lacks = |name: &str| BuildRequestError::Scope {
+            token: name.to_string(),
+            scope: S::SCOPE.name().to_string(),
+        };
+
+        if let Some(token) = state.api.find(&parts.headers) {
+            let name = format!("api:{}", token.name);
+            if !token.has(S::SCOPE) {
+                return Err(lacks(&name));
+            }
+            let pool = token.pool().unwrap_or(MAINLINE);
+            return Ok(auth(name, pool, Some(token.scopes().to_vec())));
+        }
+
+        let admin = state
+            .admin_secret
+            .as_ref()
+            .is_some_and(|s| parts.headers.get("secret").is_some_and(|x| x == s.as_str()));
+        if admin {
+            let name = "admin secret".to_string();
+            if S::SCOPE != Scope::Admin {
+                return Err(lacks(&name));
+            }
+            return Ok(auth(name, MAINLINE, None));
+        }
+
+        if let Some(pool) = state.pools.by_secret(&parts.headers) {
+            let name = format!("secret:{}", pool.name);
+            let legacy = pool.name == MAINLINE && state.api.is_empty();
+            if S::SCOPE != Scope::Worker && !legacy {
+                return Err(lacks(&name));
+            }
+            return Ok(auth(name, &pool.name, None));
+        }
+
+        match S::SCOPE {
+            Scope::Read if !state.api.read_needs_token => {
+                Ok(auth("anonymous".to_string(), MAINLINE, None))
+            }
+            // what workers have been told all along
+            Scope::Worker => Err(BuildRequestError::BadSecret),
+            _ => Err(BuildRequestError::BadToken),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+    use crate::{
+        api::ApiTokens,
+        testing::{redis, ADMIN},
+    };
+
+    /// What a request with `headers` is allowed with `S`, or why not.
+    async fn auth<S: Needs + Send>(
+        state: &Arc<AppState>,
+        headers: &[(&str, &str)],
+    ) -> Result<Auth<S>, BuildRequestError> {
+        let mut request = Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+
+        Auth::<S>::from_request_parts(&mut parts, state).await
+    }
+
+    /// The name of who is allowed, or why not.
+    async fn name<S: Needs + Send>(state: &Arc<AppState>, headers: &[(&str, &str)]) -> String {
+        match auth::<S>(state, headers).await {
+            Ok(a) => a.name,
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn tokens_are_held_to_their_scopes() {
+        let redis = redis().await;
+        let entries = [
+            "ci:ci-token:livekit",
+            "staging:staging-token:worker,pool:staging",
+            "ops:ops-token:*",
+        ]
+        .map(String::from);
+        let server = redis
+            .server_with(|s| {
+                s.api = ApiTokens::new(&entries, Some(ADMIN), false).unwrap();
+                s.admin_secret = Some("admin-secret".to_string());
+            })
+            .await;
+        let state = &server.state;
+        let ci = [("authorization", "Bearer ci-token")];
+        let staging = [("authorization", "Bearer staging-token")];
+        let ops = [("authorization", "Bearer ops-token")];
+        let secret = [("secret", "worker-secret")];
+
+        // a build type scope is enough to enqueue
+        assert_eq!(name::<need::Enqueue>(state, &ci).await, "api:ci");
+        assert_eq!(
+            name::<need::Worker>(state, &ci).await,
+            "Token api:ci lacks the worker scope."
+        );
+        let a = auth::<need::Worker>(state, &staging).await.ok().unwrap();
+        assert_eq!(
+            (a.actor().as_str(), a.pool.as_str()),
+            ("api:staging (worker)", "staging")
+        );
+        for name in [
+            name::<need::Worker>(state, &ops).await,
+            name::<need::Enqueue>(state, &ops).await,
+            name::<need::Admin>(state, &ops).await,
+        ] {
+            assert_eq!(name, "api:ops");
+        }
+
+        // with tokens, the mainline secret is for workers only
+        assert_eq!(
+            name::<need::Worker>(state, &secret).await,
+            "secret:mainline"
+        );
+        assert_eq!(
+            name::<need::Enqueue>(state, &secret).await,
+            "Token secret:mainline lacks the enqueue scope."
+        );
+
+        let admin = [("secret", "admin-secret")];
+        assert_eq!(name::<need::Admin>(state, &admin).await, "admin secret");
+        assert_eq!(
+            name::<need::Read>(state, &admin).await,
+            "Token admin secret lacks the read scope."
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn without_credentials_only_reading_is_open() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let state = &server.state;
+
+        assert_eq!(name::<need::Read>(state, &[]).await, "anonymous");
+        assert!(matches!(
+            auth::<need::Worker>(state, &[]).await,
+            Err(BuildRequestError::BadSecret)
+        ));
+        assert!(matches!(
+            auth::<need::Enqueue>(state, &[]).await,
+            Err(BuildRequestError::BadToken)
+        ));
+        assert!(matches!(
+            auth::<need::Admin>(state, &[("authorization", "Bearer guessed")]).await,
+            Err(BuildRequestError::BadToken)
+        ));
+
+        // no tokens, so the mainline secret may do everything as before
+        let secret = [("secret", "worker-secret")];
+        assert_eq!(name::<need::Admin>(state, &secret).await, "secret:mainline");
+
+        let server = redis
+            .server_with(|s| s.api = ApiTokens::new(&[], None, true).unwrap())
+            .await;
+        assert!(matches!(
+            auth::<need::Read>(&server.state, &[]).await,
+            Err(BuildRequestError::BadToken)
+        ));
+    }
+}
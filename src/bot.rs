@@ -1,17 +1,44 @@
-use std::{borrow::Cow, sync::Arc};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use teloxide::{
-    requests::{Requester, ResponseResult},
-    types::{ChatId, Message},
-    utils::command::BotCommands,
-    Bot,
+    requests::ResponseResult,
+    types::{CallbackQuery, ChatId, MediaKind, MediaText, Message, MessageId, MessageKind, User},
+    utils::{command::BotCommands, html},
 };
 
-use tracing::error;
+use tokio::time::sleep;
+use tracing::{error, warn};
 
 use crate::{
-    db::{Build, BuildType},
-    AppState, ARCHS,
+    alert,
+    buttons::{keyboard, unchanged, without, Action, Button, Posted},
+    chats,
+    db::{
+        now, Build, BuildType, Channel, Db, Disabled, Drain, HistoryEntry, Ping, RequestedState,
+        Resume, CHECK_PING_TIMEOUT, DEFAULT_SET, PING_TIMEOUT,
+    },
+    diff, digest, events,
+    format::{
+        edit_distance, estimate_text, human_age, human_bytes, human_duration, intervals_text,
+    },
+    freshness,
+    hook::Hook,
+    lang::{Lang, Msg},
+    lifecycle::Phase,
+    limits::Cap,
+    outbox::{cancel_dependents, Notification},
+    plan::{
+        enqueue_build, parse_release_args, render, split_options, EnqueueError, Options,
+        ReleaseArgs, Requester,
+    },
+    pool::{Pool, MAINLINE},
+    progress, secret,
+    setup::{Registration, Role},
+    stats::{estimate, stats},
+    streak,
+    telegram::Telegram,
+    window::Window,
+    workers, AppState,
 };
 
 #[derive(BotCommands, Clone, Debug)]
@@ -20,238 +47,3447 @@ use crate::{
     description = "ReleaseIt! supports the following commands:"
 )]
 pub enum Command {
-    #[command(description = "Display usage: /help")]
-    Help,
+    #[command(description = "Display usage: /help [command]")]
+    Help(String),
     #[command(description = "start")]
     Start(String),
     #[command(description = "Login")]
     Login,
+    #[command(description = "Forget the cached login: /logout")]
+    Logout,
+    #[command(description = "Show who you are logged in as: /whoami")]
+    Whoami,
+    #[command(description = "Forget the cached login of a user (admin only): /revoke <user id>")]
+    Revoke(String),
     #[command(
-        description = "Start a build livekit job: /livekit [archs] (e.g., /livekit amd64 arm64)"
+        rename = "rotate-secret",
+        description = "Replace the worker secret of a pool (admin only): /rotate-secret [pool]"
+    )]
+    RotateSecret(String),
+    #[command(
+        description = "Start a build livekit job: /livekit [archs] (e.g., /livekit amd64 arm64), alias /lk"
     )]
     Livekit(String),
     #[command(
-        description = "Start a build release job: /release variants;[archs] (e.g., /release base desktop;amd64 arm64)"
+        description = "Start a build release job: /release variants;[archs] (e.g., /release base desktop;amd64 arm64), alias /rel"
     )]
     Release(String),
-    #[command(description = "Show queue and server status: /status")]
-    Status,
+    #[command(
+        description = "Start a build rootfs tarballs job: /rootfs variants;[archs] (e.g., /rootfs container;amd64)"
+    )]
+    Rootfs(String),
+    #[command(description = "Show queue and server status: /status [--pool <pool>], alias /st")]
+    Status(String),
+    #[command(description = "Check whether a worker is alive: /ping <arch> [--check]")]
+    Ping(String),
+    #[command(
+        description = "List the builds of this chat, queued, running and finished: /mybuilds"
+    )]
+    Mybuilds,
+    #[command(
+        description = "Show who requested the running build of an arch: /whoisbuilding <arch>"
+    )]
+    Whoisbuilding(String),
+    #[command(description = "Show the log of a finished build: /logs <arch|#id>")]
+    Logs(String),
+    #[command(description = "Show the steps of a running or finished build: /timeline <arch|#id>")]
+    Timeline(String),
+    #[command(
+        description = "List recent finished builds: /history [--grep <text>] [--pool <pool>]"
+    )]
+    History(String),
+    #[command(description = "Compare what two finished builds shipped: /diff #<id> #<id>")]
+    Diff(String),
+    #[command(description = "Request a finished build again: /retry <arch|#id>")]
+    Retry(String),
+    #[command(
+        description = "Upload the kept artifacts of a failed push again: /repush <arch|#id>"
+    )]
+    Repush(String),
+    #[command(description = "Cancel a queued or running build: /cancel <arch|#id>")]
+    Cancel(String),
+    #[command(
+        description = "Show the queue of an arch: /queue <arch>, admins may also /queue <arch> drop|top <n> or clear"
+    )]
+    Queue(String),
+    #[command(
+        description = "Manage post-build hooks (admin only): /hook [list|add <hook>|remove <n>]"
+    )]
+    Hook(String),
+    #[command(
+        description = "Show notifications that could not be delivered (admin only): /outbox [clear]"
+    )]
+    Outbox(String),
+    #[command(
+        description = "Stop taking builds for an arch (admin only): /disable <arch> [reason]"
+    )]
+    Disable(String),
+    #[command(description = "Take builds for a disabled arch again (admin only): /enable <arch>")]
+    Enable(String),
+    #[command(
+        description = "Show or set the times of day an arch builds: /window [arch] [HH:MM-HH:MM [time zone]|clear]"
+    )]
+    Window(String),
+    #[command(
+        description = "Show or set the caps on queued builds: /limits [total|arch|requester <n|off>|reset]"
+    )]
+    Limits(String),
+    #[command(
+        description = "List the workers and what they are doing: /workers, admins may also /workers forget <name>"
+    )]
+    Workers(String),
+    #[command(
+        description = "Let a worker or the workers of an arch finish their build and take no more (admin only): /drain <arch|worker> on|off"
+    )]
+    Drain(String),
+    #[command(
+        description = "List the scheduled builds paused after failing in a row, or take them again (admin only): /unpause [<arch> <type> [channel]]"
+    )]
+    Unpause(String),
+    #[command(
+        description = "Get told about builds requested or started by others and worker problems (admin only): /watch [on|off]"
+    )]
+    Watch(String),
+    #[command(description = "Download the server state as JSON (admin only): /export")]
+    Export,
+    #[command(
+        description = "Show when each arch last built and pushed each type of build: /freshness"
+    )]
+    Freshness,
+    #[command(
+        description = "Show the digest of the last day, or send it to the digest chats (admin only): /digest [now]"
+    )]
+    Digest(String),
+    #[command(description = "Show the chats notifications go to (admin only): /chats")]
+    Chats,
+    #[command(
+        description = "Set this chat up for announcements, alerts, default-notify or the digest, or set how progress posts end (admin only): /setup [status|<role>|remove <role>|progress <edit|replace|off>]"
+    )]
+    Setup(String),
+    #[command(description = "Show or set the language of this chat: /lang [en|zh]")]
+    Lang(String),
+    #[command(description = "Show or set the pool of builds this chat uses: /setpool [pool]")]
+    Setpool(String),
+    #[command(description = "off")]
+    Lk(String),
+    #[command(description = "off")]
+    Rel(String),
+    #[command(description = "off")]
+    St(String),
+}
+
+impl Command {
+    /// Resolve short aliases to the command they stand for.
+    fn canonical(self) -> Self {
+        match self {
+            Command::Lk(args) => Command::Livekit(args),
+            Command::Rel(args) => Command::Release(args),
+            Command::St(args) => Command::Status(args),
+            cmd => cmd,
+        }
+    }
+}
+
+/// Detailed usage of a single command, for `/help <command>`.
+fn usage(command: &str, state: &AppState, lang: Lang) -> Option<String> {
+    let archs = state.pools.mainline().archs.join(" ");
+    let pools = state.pools.names();
+    let variants = if state.variants.is_empty() {
+        lang.text(Msg::AnyVariant)
+    } else {
+        state.variants.join(" ")
+    };
+
+    let passthrough = state.passthrough.usage(lang);
+    let flavors = state.flavors.usage(lang);
+    let build = [
+        (
+            "passthrough",
+            &passthrough as &(dyn std::fmt::Display + Sync),
+        ),
+        ("flavors", &flavors),
+        ("variants", &variants),
+        ("archs", &archs),
+        ("pools", &pools),
+    ];
+
+    let msg = match command.trim_start_matches('/') {
+        "livekit" | "lk" => Msg::UsageLivekit,
+        "release" | "rel" => Msg::UsageRelease,
+        "rootfs" => Msg::UsageRootfs,
+        "status" | "st" => Msg::UsageStatus,
+        "ping" => {
+            return Some(lang.tr(
+                Msg::UsagePing,
+                &[
+                    ("timeout", &PING_TIMEOUT),
+                    ("check_timeout", &CHECK_PING_TIMEOUT),
+                    ("all_archs", &state.pools.archs().join(" ")),
+                ],
+            ))
+        }
+        "login" => Msg::UsageLogin,
+        "logout" => Msg::UsageLogout,
+        "whoami" => Msg::UsageWhoami,
+        "revoke" => Msg::UsageRevoke,
+        "rotate-secret" => Msg::UsageRotateSecret,
+        "start" => Msg::UsageStart,
+        "mybuilds" => Msg::UsageMybuilds,
+        "whoisbuilding" => Msg::UsageWhoisbuilding,
+        "logs" => Msg::UsageLogs,
+        "timeline" => Msg::UsageTimeline,
+        "history" => Msg::UsageHistory,
+        "diff" => Msg::UsageDiff,
+        "retry" => Msg::UsageRetry,
+        "repush" => Msg::UsageRepush,
+        "cancel" => Msg::UsageCancel,
+        // its placeholders are those of the templates, left as they are
+        "hook" => return Some(lang.text(Msg::UsageHook)),
+        "queue" => Msg::UsageQueue,
+        "disable" => Msg::UsageDisable,
+        "enable" => Msg::UsageEnable,
+        "window" => Msg::UsageWindow,
+        "limits" => Msg::UsageLimits,
+        "workers" => {
+            return Some(lang.tr(
+                Msg::UsageWorkers,
+                &[("offline", &state.worker_offline_after)],
+            ))
+        }
+        "drain" => Msg::UsageDrain,
+        "unpause" => Msg::UsageUnpause,
+        "watch" => Msg::UsageWatch,
+        "freshness" => {
+            return Some(lang.tr(
+                Msg::UsageFreshness,
+                &[("threshold", &human_age(state.freshness_threshold))],
+            ))
+        }
+        "outbox" => Msg::UsageOutbox,
+        "export" => Msg::UsageExport,
+        "digest" => Msg::UsageDigest,
+        "chats" => Msg::UsageChats,
+        "setup" => Msg::UsageSetup,
+        "lang" => Msg::UsageLang,
+        "setpool" => Msg::UsageSetpool,
+        "help" => Msg::UsageHelp,
+        _ => return None,
+    };
+
+    Some(lang.tr(msg, &build))
+}
+
+/// Names of all commands, including the hidden aliases.
+const COMMANDS: &[&str] = &[
+    "help",
+    "start",
+    "login",
+    "logout",
+    "whoami",
+    "revoke",
+    "rotate-secret",
+    "queue",
+    "hook",
+    "outbox",
+    "disable",
+    "enable",
+    "window",
+    "limits",
+    "workers",
+    "drain",
+    "unpause",
+    "watch",
+    "export",
+    "freshness",
+    "digest",
+    "chats",
+    "setup",
+    "lang",
+    "setpool",
+    "mybuilds",
+    "whoisbuilding",
+    "logs",
+    "timeline",
+    "history",
+    "diff",
+    "retry",
+    "repush",
+    "cancel",
+    "livekit",
+    "release",
+    "rootfs",
+    "status",
+    "ping",
+    "lk",
+    "rel",
+    "st",
+];
+
+/// Reply to a message that looks like a command but could not be parsed.
+pub async fn unknown_command(
+    bot: Telegram,
+    msg: Message,
+    state: Arc<AppState>,
+) -> ResponseResult<()> {
+    let Some(text) = msg.text() else {
+        return Ok(());
+    };
+
+    let name = text
+        .trim_start_matches('/')
+        .split_ascii_whitespace()
+        .next()
+        .unwrap_or("");
+
+    // addressed to some other bot
+    if name.contains('@') {
+        return Ok(());
+    }
+
+    let name = name.to_lowercase();
+    let lang = chats::seen(&state, &msg.chat).await;
+
+    // a known command with arguments that failed to parse
+    if let Some(u) = usage(&name, &state, lang) {
+        return send_text(&bot, msg.chat.id, &u).await;
+    }
+
+    let suggestion = COMMANDS
+        .iter()
+        .map(|x| (edit_distance(&name, x), x))
+        .filter(|(d, x)| *d <= 2 && *d < x.len())
+        .min_by_key(|(d, _)| *d);
+
+    let reply = match suggestion {
+        Some((_, x)) => lang.tr(
+            Msg::UnknownCommandSuggest,
+            &[("name", &name), ("suggestion", x)],
+        ),
+        None => lang.tr(Msg::UnknownCommand, &[("name", &name)]),
+    };
+
+    send_text(&bot, msg.chat.id, &reply).await
 }
 
 pub async fn answer(
-    bot: Bot,
+    bot: Telegram,
     msg: Message,
     cmd: Command,
     state: Arc<AppState>,
 ) -> ResponseResult<()> {
-    let AppState { db, secret, .. } = &*state;
+    let lang = chats::seen(&state, &msg.chat).await;
 
-    match cmd {
-        Command::Help => {
-            bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                .await?;
+    match cmd.canonical() {
+        Command::Help(command) => {
+            let text = if command.trim().is_empty() {
+                lang.text(Msg::HelpList)
+            } else {
+                usage(command.trim(), &state, lang)
+                    .unwrap_or_else(|| lang.tr(Msg::HelpUnknown, &[("command", &command.trim())]))
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
         }
+        Command::Lk(_) | Command::Rel(_) | Command::St(_) => unreachable!(),
         Command::Livekit(args) => {
-            let is_login = is_login(&msg.chat.id, secret).await;
+            let is_login = is_login(&msg.chat.id, &state).await;
 
             if !is_login {
                 return Ok(());
             }
 
-            let mut db = db.lock().await;
-
-            let archs = if args.is_empty() {
-                ARCHS.iter().map(|x| x.to_owned()).collect::<Vec<_>>()
-            } else {
-                args.trim().split_ascii_whitespace().collect()
+            let (args, opts) = match split_options(&args, lang) {
+                Ok(x) => x,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
+                    return Ok(());
+                }
             };
 
-            for i in archs {
-                if !ARCHS.contains(&i) {
-                    bot.send_message(msg.chat.id, format!("Unknown arch: {}", i))
-                       .await?;
-                    continue;
+            let archs = args.split_ascii_whitespace().collect::<Vec<_>>();
+
+            request_builds(&bot, &msg, &state, lang, &archs, BuildType::Livekit, opts).await?;
+        }
+        Command::Release(args) => {
+            request_variants(
+                &bot,
+                &msg,
+                &state,
+                lang,
+                "release",
+                &args,
+                BuildType::Release,
+            )
+            .await?;
+        }
+        Command::Rootfs(args) => {
+            request_variants(&bot, &msg, &state, lang, "rootfs", &args, BuildType::Rootfs).await?;
+        }
+        Command::Ping(args) => {
+            let args = args.split_ascii_whitespace().collect::<Vec<_>>();
+            let check = args.contains(&"--check");
+            let arch = args
+                .iter()
+                .find(|x| !x.starts_with("--"))
+                .copied()
+                .unwrap_or("");
+            let text = if !state.pools.archs().iter().any(|x| x == arch) {
+                lang.tr(Msg::UnknownArch, &[("arch", &arch)])
+            } else {
+                match state
+                    .db()
+                    .await
+                    .request_ping(arch, msg.chat.id.0, check)
+                    .await
+                {
+                    Ok(Some(p)) => {
+                        tokio::spawn(ping_timeout(state.clone(), p, lang));
+                        lang.tr(Msg::PingSent, &[("arch", &arch)])
+                    }
+                    Ok(None) => lang.tr(Msg::PingWaiting, &[("arch", &arch)]),
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
                 }
+            };
 
-                if db.get(i).await.is_ok() {
-                    bot.send_message(msg.chat.id, "Another build task is running.")
-                        .await?;
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Status(args) => {
+            let (_, asked) = take_pool(&args);
+            let mut db = state.db().await;
+            let pool = match pool_for(&mut db, &msg, &state, asked.as_deref(), lang).await {
+                Ok(p) => p,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
                     return Ok(());
                 }
+            };
+            let map = status(&mut db, &state, pool, lang).await;
 
-                match db
-                    .set_building(
-                        i,
-                        &Build {
-                            id: msg.chat.id.0,
-                            arch: i.to_string(),
-                            build_type: BuildType::Livekit,
-                        },
+            match map {
+                Ok(res) => {
+                    send_text(&bot, msg.chat.id, &res).await?;
+                }
+                Err(e) => {
+                    send_text(
+                        &bot,
+                        msg.chat.id,
+                        &lang.tr(Msg::RedisError, &[("error", &e)]),
                     )
-                    .await
-                {
-                    Ok(_) => {
-                        bot.send_message(msg.chat.id, format!("Building {} for livekit", i))
-                            .await?;
-                    }
-                    Err(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Failed to mod redis database: {}", e),
-                        )
-                        .await?;
-                    }
+                    .await?;
                 }
             }
         }
-        Command::Release(args) => {
-            let (variants, archs) = if let Some((x, y)) = args.split_once(';') {
-                (
-                    x.trim().split_ascii_whitespace().collect::<Vec<_>>(),
-                    y.trim().split_ascii_whitespace().collect::<Vec<_>>(),
+        Command::Login => {
+            send_text(&bot, msg.chat.id, "https://github.com/login/oauth/authorize?client_id=Iv1.bf26f3e9dd7883ae&redirect_uri=https://minzhengbu.aosc.io/login").await?;
+        }
+        Command::Logs(args) => {
+            let Some(target) = Target::parse(&args) else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(Msg::Usage, &[("usage", &"/logs <arch|#id>")]),
                 )
+                .await?;
+                return Ok(());
+            };
+
+            let mut db = state.db().await;
+            let pool = match pool_for(&mut db, &msg, &state, None, lang).await {
+                Ok(p) => p,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
+                    return Ok(());
+                }
+            };
+            let text = match find_finished(&mut db, &target, &pool.name).await {
+                Ok(Some(h)) => lang.tr(
+                    Msg::BuildLog,
+                    &[
+                        ("id", &h.id),
+                        ("build", &h.describe()),
+                        ("arch", &h.arch),
+                        (
+                            "log",
+                            &h.log_url
+                                .clone()
+                                .unwrap_or_else(|| lang.text(Msg::LogNotPushed)),
+                        ),
+                    ],
+                ),
+                Ok(None) => lang.tr(Msg::NoFinishedBuild, &[("target", &target)]),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Mybuilds => {
+            let text = if is_login(&msg.chat.id, &state).await {
+                let actor = msg.chat.id.to_string();
+                match mybuilds(&mut *state.db().await, &actor, lang).await {
+                    Ok(text) => text,
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                }
             } else {
-                (
-                    args.trim().split_ascii_whitespace().collect(),
-                    ARCHS.iter().map(|x| x.to_owned()).collect(),
-                )
+                lang.text(Msg::NotLoggedIn)
             };
 
-            let mut db = db.lock().await;
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Whoisbuilding(args) => {
+            if !is_login(&msg.chat.id, &state).await {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::NotLoggedIn)).await?;
+                return Ok(());
+            }
 
-            for i in archs {
-                if !ARCHS.contains(&i) {
-                    bot.send_message(msg.chat.id, format!("Unknown arch: {}", i))
-                       .await?;
-                    continue;
+            let mut db = state.db().await;
+            let pool = match pool_for(&mut db, &msg, &state, None, lang).await {
+                Ok(p) => p,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
+                    return Ok(());
                 }
+            };
+            let text = match whoisbuilding(&mut db, pool, args.trim(), lang).await {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-                if db.get(i).await.is_ok() {
-                    bot.send_message(msg.chat.id, "Another build task is running.")
-                        .await?;
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::History(args) => {
+            let (args, asked) = take_pool(&args);
+            let mut db = state.db().await;
+            let pool = match pool_for(&mut db, &msg, &state, asked.as_deref(), lang).await {
+                Ok(p) => p,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
                     return Ok(());
                 }
+            };
+            let text = match history_command(&mut db, pool, &args, lang).await {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-                match db
-                    .set_building(
-                        i,
-                        &Build {
-                            id: msg.chat.id.0,
-                            arch: i.to_string(),
-                            build_type: BuildType::Release(
-                                variants.iter().map(|x| x.to_string()).collect(),
-                            ),
-                        },
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Timeline(args) => {
+            let Some(target) = Target::parse(&args) else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(Msg::Usage, &[("usage", &"/timeline <arch|#id>")]),
+                )
+                .await?;
+                return Ok(());
+            };
+
+            let mut db = state.db().await;
+            let pool = match pool_for(&mut db, &msg, &state, None, lang).await {
+                Ok(p) => p,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
+                    return Ok(());
+                }
+            };
+            match timeline(&mut db, &state, pool, &target, lang).await {
+                Ok(text) => send_html(&bot, msg.chat.id, &text).await?,
+                Err(e) => {
+                    send_text(
+                        &bot,
+                        msg.chat.id,
+                        &lang.tr(Msg::RedisError, &[("error", &e)]),
                     )
-                    .await
-                {
-                    Ok(_) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Building {} for release ({})", i, variants.join(" ")),
-                        )
-                        .await?;
-                    }
-                    Err(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            format!("Failed to mod redis database: {}", e),
-                        )
-                        .await?;
-                    }
+                    .await?
                 }
             }
         }
-        Command::Status => {
-            let mut db = db.lock().await;
-            let map = db.running_worker().await;
-            let mut res = String::new();
+        Command::Diff(args) => {
+            let text = match diff_command(&mut *state.db().await, &msg, &state, &args, lang).await {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-            match map {
-                Ok(m) => {
-                    for b in m {
-                        res.push_str(&format!("{}: building {}\n", b.arch, b.build_type));
-                    }
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Retry(args) => {
+            if !is_login(&msg.chat.id, &state).await {
+                return Ok(());
+            }
+
+            let (args, mut opts) = match split_options(&args, lang) {
+                Ok(x) => x,
+                Err(e) => {
+                    send_text(&bot, msg.chat.id, &e).await?;
+                    return Ok(());
+                }
+            };
+
+            let Some(target) = Target::parse(&args) else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(Msg::Usage, &[("usage", &"/retry <arch|#id>")]),
+                )
+                .await?;
+                return Ok(());
+            };
 
-                    bot.send_message(msg.chat.id, res).await?;
+            let found = {
+                let mut db = state.db().await;
+                match pool_for(&mut db, &msg, &state, opts.pool.as_deref(), lang).await {
+                    Ok(p) => find_finished(&mut db, &target, &p.name).await,
+                    Err(e) => {
+                        send_text(&bot, msg.chat.id, &e).await?;
+                        return Ok(());
+                    }
+                }
+            };
+            let h = match found {
+                Ok(Some(h)) => h,
+                Ok(None) => {
+                    send_text(
+                        &bot,
+                        msg.chat.id,
+                        &lang.tr(Msg::NoFinishedBuild, &[("target", &target)]),
+                    )
+                    .await?;
+                    return Ok(());
                 }
                 Err(e) => {
-                    bot.send_message(
+                    send_text(
+                        &bot,
                         msg.chat.id,
-                        truncate(&format!("Failed to mod redis database: {}", e)),
+                        &lang.tr(Msg::RedisError, &[("error", &e)]),
                     )
                     .await?;
+                    return Ok(());
+                }
+            };
+
+            let Some(mut build_type) = h.build_type() else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(
+                        Msg::UnknownBuildType,
+                        &[("id", &h.id), ("build_type", &h.build_type)],
+                    ),
+                )
+                .await?;
+                return Ok(());
+            };
+            if opts.resume {
+                match resume(&h, opts.force, lang) {
+                    Ok((t, r)) => {
+                        build_type = t;
+                        opts.resumed = Some(r);
+                        // where the checkout of the failed build still is
+                        opts.worker = h.worker.clone();
+                    }
+                    Err(e) => {
+                        send_text(&bot, msg.chat.id, &e).await?;
+                        return Ok(());
+                    }
                 }
             }
+
+            // a retried nightly stays nightly, and special builds special
+            opts.channel.get_or_insert(h.channel);
+            if opts.env.is_empty() && opts.args.is_empty() {
+                opts.env = h.env.clone();
+                opts.args = h.args.clone();
+            }
+            if opts.note.is_none() {
+                opts.note = h.note.clone();
+            }
+            if opts.flavor.is_none() {
+                opts.flavor = h.flavor.clone();
+            }
+            // in the pool it was built in, if this chat may use it
+            opts.pool = Some(h.pool.clone());
+            request_builds(&bot, &msg, &state, lang, &[&h.arch], build_type, opts).await?;
         }
-        Command::Login => {
-            bot.send_message(msg.chat.id, "https://github.com/login/oauth/authorize?client_id=Iv1.bf26f3e9dd7883ae&redirect_uri=https://minzhengbu.aosc.io/login").await?;
-        }
-        Command::Start(arguments) => {
-            if arguments.len() != 20 {
-                bot.send_message(msg.chat.id, Command::descriptions().to_string())
-                    .await?;
+        Command::Repush(args) => {
+            if !is_login(&msg.chat.id, &state).await {
                 return Ok(());
-            } else {
-                let resp = login_github(&msg, arguments).await;
+            }
 
-                match resp {
-                    Ok(_) => bot.send_message(msg.chat.id, "Login successful!").await?,
+            let Some(target) = Target::parse(&args) else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(Msg::Usage, &[("usage", &"/repush <arch|#id>")]),
+                )
+                .await?;
+                return Ok(());
+            };
+
+            let found = {
+                let mut db = state.db().await;
+                match pool_for(&mut db, &msg, &state, None, lang).await {
+                    Ok(p) => find_finished(&mut db, &target, &p.name).await,
                     Err(e) => {
-                        bot.send_message(
-                            msg.chat.id,
-                            truncate(&format!("Login failed with error: {e}")),
-                        )
-                        .await?
+                        send_text(&bot, msg.chat.id, &e).await?;
+                        return Ok(());
                     }
-                };
-            }
-        }
-    }
+                }
+            };
+            let text = match found {
+                Ok(Some(h)) if h.failed_artifacts.is_empty() => {
+                    lang.tr(Msg::NothingToRepush, &[("id", &h.id)])
+                }
+                Ok(Some(h)) => {
+                    let opts = Options {
+                        channel: Some(h.channel),
+                        worker: h.worker.clone(),
+                        pool: Some(h.pool.clone()),
+                        ..Default::default()
+                    };
+                    let build_type = BuildType::Repush { build_id: h.id };
+                    request_builds(&bot, &msg, &state, lang, &[&h.arch], build_type, opts).await?;
+                    return Ok(());
+                }
+                Ok(None) => lang.tr(Msg::NoFinishedBuild, &[("target", &target)]),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-    Ok(())
-}
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Cancel(args) => {
+            let (args, asked) = take_pool(&args);
+            let Some(target) = Target::parse(&args) else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(
+                        Msg::Usage,
+                        &[("usage", &"/cancel <arch|#id> [--pool <pool>]")],
+                    ),
+                )
+                .await?;
+                return Ok(());
+            };
 
-pub async fn login_github(
-    msg: &Message,
-    arguments: String,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let client = reqwest::Client::new();
+            let mut db = state.db().await;
+            let text = match cancel(&mut db, &msg, &state, &target, asked.as_deref(), lang).await {
+                Ok(t) => t,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-    client
-        .get("https://minzhengbu.aosc.io/login_from_telegram".to_string())
-        .query(&[
-            ("telegram_id", msg.chat.id.0.to_string()),
-            ("rid", arguments),
-        ])
-        .send()
-        .await
-        .and_then(|x| x.error_for_status())
-}
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Queue(args) => {
+            let mut db = state.db().await;
+            let text = match queue_command(&mut db, &msg, &state, &args, lang).await {
+                Ok(t) => t,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-fn truncate(text: &str) -> Cow<str> {
-    if text.chars().count() > 1000 {
-        console::truncate_str(text, 1000, "...")
-    } else {
-        Cow::Borrowed(text)
-    }
-}
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Hook(args) => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsHooks)).await?;
+                return Ok(());
+            }
 
-pub async fn is_login(msg_chatid: &ChatId, secret: &str) -> bool {
-    let client = reqwest::Client::new();
-    let resp = client
-        .get("https://minzhengbu.aosc.io/get_token")
-        .query(&[("id", &msg_chatid.0.to_string())])
-        .header("secret", secret)
-        .send()
-        .await
-        .and_then(|r| r.error_for_status());
+            let mut db = state.db().await;
+            let text = match hook_command(&mut db, &msg, &args, lang).await {
+                Ok(t) => t,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
 
-    match resp {
-        Ok(_) => true,
-        Err(e) => {
-            error!("{e}");
-            false
+            send_text(&bot, msg.chat.id, &text).await?;
         }
+        Command::Disable(args) => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsDisable)).await?;
+                return Ok(());
+            }
+
+            let args = args.trim();
+            let (arch, reason) = args.split_once(' ').unwrap_or((args, ""));
+            let text = if !state.pools.archs().iter().any(|x| x == arch) {
+                lang.tr(Msg::UnknownArch, &[("arch", &arch)])
+            } else {
+                let disabled = Disabled {
+                    reason: Some(reason.trim())
+                        .filter(|x| !x.is_empty())
+                        .map(|x| x.to_string()),
+                    by: msg.chat.id.to_string(),
+                    at: now(),
+                };
+
+                let mut db = state.db().await;
+                let res = async {
+                    db.disable(arch, &disabled).await?;
+                    db.audit(&disabled.by, &format!("disabled {arch}: {disabled}"))
+                        .await
+                }
+                .await;
+
+                match res {
+                    Ok(()) => match disabled.reason {
+                        Some(ref r) => {
+                            lang.tr(Msg::ArchDisabledReason, &[("arch", &arch), ("reason", r)])
+                        }
+                        None => lang.tr(Msg::ArchDisabled, &[("arch", &arch)]),
+                    },
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                }
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Enable(arch) => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsEnable)).await?;
+                return Ok(());
+            }
+
+            let arch = arch.trim();
+            let mut db = state.db().await;
+            let res = async {
+                let was = db.enable(arch).await?;
+                if was {
+                    db.audit(&msg.chat.id.to_string(), &format!("enabled {arch}"))
+                        .await?;
+                }
+
+                Ok::<_, eyre::Error>(was)
+            }
+            .await;
+
+            let text = match res {
+                Ok(true) => lang.tr(Msg::ArchEnabled, &[("arch", &arch)]),
+                Ok(false) => lang.tr(Msg::ArchNotDisabled, &[("arch", &arch)]),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Window(args) => {
+            let text = match window_command(&mut *state.db().await, &msg, &state, &args, lang).await
+            {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Limits(args) => {
+            let text = match limits_command(&mut *state.db().await, &msg, &state, &args, lang).await
+            {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Workers(args) => {
+            let text =
+                match workers_command(&mut *state.db().await, &msg, &state, &args, lang).await {
+                    Ok(text) => text,
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Drain(args) => {
+            let text = match drain_command(&mut *state.db().await, &msg, &state, &args, lang).await
+            {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Unpause(args) => {
+            let text =
+                match unpause_command(&mut *state.db().await, &msg, &state, &args, lang).await {
+                    Ok(text) => text,
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Watch(args) => {
+            let text = match watch_command(&mut *state.db().await, &msg, &state, &args, lang).await
+            {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Export => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsExport)).await?;
+                return Ok(());
+            }
+
+            let dump = state.db().await.export().await.and_then(|mut d| {
+                // the document stays in the chat, group or not
+                d.secrets.clear();
+                let json = serde_json::to_vec_pretty(&d)?;
+                Ok((d, json))
+            });
+
+            match dump {
+                Ok((d, json)) => {
+                    bot.send_document(
+                        msg.chat.id,
+                        format!("shipit-{}.json", d.exported_at),
+                        json,
+                        format!("{}\n{}", d.summary(), lang.text(Msg::ExportWithoutSecrets)),
+                    )
+                    .await?;
+                }
+                Err(e) => {
+                    send_text(
+                        &bot,
+                        msg.chat.id,
+                        &lang.tr(Msg::RedisError, &[("error", &e)]),
+                    )
+                    .await?;
+                }
+            }
+        }
+        Command::Digest(args) => {
+            let text = match digest_command(&mut *state.db().await, &msg, &state, &args, lang).await
+            {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Chats => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsChats)).await?;
+                return Ok(());
+            }
+
+            let text = match state.db().await.chats().await {
+                Ok(chats) => chats::render(&chats, lang),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Setup(args) => {
+            let text = match setup_command(&bot, &msg, &state, &args, lang).await {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Outbox(args) => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsOutbox)).await?;
+                return Ok(());
+            }
+
+            let mut db = state.db().await;
+            let text = if args.trim() == "clear" {
+                match db.clear_failed_notifications().await {
+                    Ok(_) => lang.text(Msg::OutboxCleared),
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                }
+            } else {
+                match db.failed_notifications().await {
+                    Ok(v) if v.is_empty() => lang.text(Msg::OutboxEmpty),
+                    Ok(v) => v
+                        .iter()
+                        .map(|n| {
+                            lang.tr(
+                                Msg::OutboxEntry,
+                                &[
+                                    ("chat", &n.chat),
+                                    ("attempts", &n.attempts),
+                                    (
+                                        "error",
+                                        &n.last_error
+                                            .clone()
+                                            .unwrap_or_else(|| lang.text(Msg::NoError)),
+                                    ),
+                                ],
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                    Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                }
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Logout => {
+            let mut db = state.db().await;
+            let text = match db.clear_login(msg.chat.id.0).await {
+                Ok(_) => lang.text(Msg::LoginCleared),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Revoke(user) => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsRevoke)).await?;
+                return Ok(());
+            }
+
+            let Ok(user) = user.trim().parse::<i64>() else {
+                send_text(
+                    &bot,
+                    msg.chat.id,
+                    &lang.tr(Msg::Usage, &[("usage", &"/revoke <user id>")]),
+                )
+                .await?;
+                return Ok(());
+            };
+
+            let mut db = state.db().await;
+            let text = match db.clear_login(user).await {
+                Ok(true) => lang.tr(Msg::LoginRevoked, &[("user", &user)]),
+                Ok(false) => lang.tr(Msg::NoCachedLogin, &[("user", &user)]),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::RotateSecret(args) => {
+            if !is_admin(&msg, &state) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::OnlyAdminsRotateSecret)).await?;
+                return Ok(());
+            }
+            // the reply carries the secret
+            if !msg.chat.is_private() {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::RotateSecretPrivate)).await?;
+                return Ok(());
+            }
+
+            let pool = match args.trim() {
+                "" => state.pools.mainline(),
+                name => match state.pools.get(name) {
+                    Some(p) => p,
+                    None => {
+                        let text = lang.tr(
+                            Msg::UnknownPool,
+                            &[("pool", &name), ("pools", &state.pools.names())],
+                        );
+                        send_text(&bot, msg.chat.id, &text).await?;
+                        return Ok(());
+                    }
+                },
+            };
+
+            let mut db = state.db().await;
+            let res = async {
+                let secrets = secret::rotate(&mut db, pool, state.secret_grace).await?;
+                db.audit(
+                    &msg.chat.id.to_string(),
+                    &format!("rotated the secret of pool {}", pool.name),
+                )
+                .await?;
+                eyre::Ok(secrets)
+            }
+            .await;
+            let text = match res {
+                Ok(secrets) => lang.tr(
+                    Msg::SecretRotated,
+                    &[
+                        ("pool", &pool.name),
+                        ("secret", &secrets.primary),
+                        ("grace", &human_duration(state.secret_grace)),
+                    ],
+                ),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Start(arguments) => {
+            let rid = arguments.trim();
+
+            if rid.is_empty() {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::HelpList)).await?;
+                return Ok(());
+            }
+
+            if !state.rid.accepts(rid) {
+                send_text(&bot, msg.chat.id, &lang.text(Msg::LoginLinkMalformed)).await?;
+                return Ok(());
+            }
+
+            let text = match login_github(&msg, rid).await {
+                Ok(user) => {
+                    let mut db = state.db().await;
+                    let res = async {
+                        db.set_login_verified(msg.chat.id.0, state.login_grace)
+                            .await?;
+                        if let Some(ref u) = user {
+                            db.set_login_user(msg.chat.id.0, u, state.login_grace)
+                                .await?;
+                        }
+                        eyre::Ok(())
+                    }
+                    .await;
+                    if let Err(e) = res {
+                        error!("Failed to cache login: {e}");
+                    }
+
+                    match user {
+                        Some(u) => lang.tr(Msg::LoggedInAs, &[("user", &u)]),
+                        None => lang.text(Msg::LoginSuccessful),
+                    }
+                }
+                Err(e) if e.status().is_some_and(|s| s.is_client_error()) => {
+                    lang.text(Msg::LoginLinkExpired)
+                }
+                Err(e) => lang.tr(Msg::LoginFailed, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Freshness => {
+            let threshold = state.freshness_threshold;
+            let text = match freshness::list(&mut *state.db().await, now(), threshold).await {
+                Ok(list) => freshness::render(&list, threshold, lang),
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Whoami => {
+            let logged_in = is_login(&msg.chat.id, &state).await;
+            let text = match whoami(&mut *state.db().await, &msg, &state, logged_in, lang).await {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Lang(arg) => {
+            let arg = arg.trim();
+            let text = if arg.is_empty() {
+                lang.text(Msg::LangCurrent)
+            } else {
+                match Lang::parse(arg) {
+                    Some(new) => match state
+                        .db()
+                        .await
+                        .update_chat(msg.chat.id.0, |c| c.lang = new)
+                        .await
+                    {
+                        Ok(_) => new.text(Msg::LangSet),
+                        Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+                    },
+                    None => lang.tr(Msg::Usage, &[("usage", &"/lang [en|zh]")]),
+                }
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+        Command::Setpool(arg) => {
+            let text = match setpool(&mut *state.db().await, &msg, &state, arg.trim(), lang).await {
+                Ok(text) => text,
+                Err(e) => lang.tr(Msg::RedisError, &[("error", &e)]),
+            };
+
+            send_text(&bot, msg.chat.id, &text).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `--pool <pool>` taken out of the arguments of a command that does not
+/// go through [`split_options`].
+fn take_pool(args: &str) -> (String, Option<String>) {
+    let mut rest = vec![];
+    let mut pool = None;
+
+    let mut tokens = args.split_ascii_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "--pool" => pool = tokens.next().map(|x| x.to_string()),
+            t => rest.push(t),
+        }
+    }
+
+    (rest.join(" "), pool)
+}
+
+/// The pool a command in `msg` acts on: `asked` with `--pool`, else the one
+/// set with `/setpool`, else the default of the chat. The error is the
+/// reply, also for a pool the chat may not use.
+async fn pool_for<'a>(
+    db: &mut Db,
+    msg: &Message,
+    state: &'a AppState,
+    asked: Option<&str>,
+    lang: Lang,
+) -> Result<&'a Pool, String> {
+    let chat = msg.chat.id.0;
+    let set = match asked {
+        Some(_) => None,
+        None => db
+            .chat(chat)
+            .await
+            .map_err(|e| lang.tr(Msg::RedisError, &[("error", &e)]))?
+            .and_then(|x| x.pool),
+    };
+
+    let pool = match asked.or(set.as_deref()) {
+        Some(name) => state.pools.get(name).ok_or_else(|| {
+            lang.tr(
+                Msg::UnknownPool,
+                &[("pool", &name), ("pools", &state.pools.names())],
+            )
+        })?,
+        None => state.pools.default_for(chat),
+    };
+    allow_pool(db, msg, state, pool, lang).await?;
+
+    Ok(pool)
+}
+
+/// Refuse, and audit, a command in `msg` acting on a pool its chat may not
+/// use.
+async fn allow_pool(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    pool: &Pool,
+    lang: Lang,
+) -> Result<(), String> {
+    let chat = msg.chat.id.0;
+    if state.pools.may_use(pool, chat, is_admin(msg, state)) {
+        return Ok(());
+    }
+
+    let command = msg
+        .text()
+        .and_then(|x| x.split_ascii_whitespace().next())
+        .unwrap_or("a command");
+    warn!(
+        "Chat {chat} may not use pool {}, refused {command}",
+        pool.name
+    );
+    db.audit(
+        &chat.to_string(),
+        &format!("denied: {command} in pool {}", pool.name),
+    )
+    .await
+    .map_err(|e| lang.tr(Msg::RedisError, &[("error", &e)]))?;
+
+    Err(lang.tr(Msg::PoolDenied, &[("pool", &pool.name)]))
+}
+
+/// Show the pool of the chat, or set it to `arg`.
+async fn setpool(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    arg: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let asked = Some(arg).filter(|x| !x.is_empty());
+    let pool = match pool_for(db, msg, state, asked, lang).await {
+        Ok(p) => p,
+        Err(e) => return Ok(e),
+    };
+    if asked.is_none() {
+        return Ok(lang.tr(Msg::PoolCurrent, &[("pool", &pool.name)]));
+    }
+
+    db.update_chat(msg.chat.id.0, |c| c.pool = Some(pool.name.clone()))
+        .await?;
+
+    Ok(lang.tr(Msg::PoolSet, &[("pool", &pool.name)]))
+}
+
+/// Tell the requester of `ping` if no worker answered it in time.
+async fn ping_timeout(state: Arc<AppState>, ping: Ping, lang: Lang) {
+    sleep(Duration::from_secs(ping.timeout())).await;
+
+    let mut db = state.db().await;
+    let res = async {
+        if db.finish_ping(ping.id).await?.is_none() {
+            return Ok(());
+        }
+
+        let running = db.running(&ping.arch).await?;
+        let text = if running.is_empty() {
+            lang.tr(Msg::PingNoAnswer, &[("arch", &ping.arch)])
+        } else {
+            let builds = running
+                .iter()
+                .map(|r| format!("#{}", r.build.id))
+                .collect::<Vec<_>>()
+                .join(", ");
+            lang.tr(
+                Msg::PingNoAnswerBusy,
+                &[("arch", &ping.arch), ("builds", &builds)],
+            )
+        };
+
+        db.push_outbox(&Notification::plain(ping.chat, &text)).await
+    }
+    .await;
+
+    if let Err(e) = res {
+        error!("Failed to conclude ping #{}: {e}", ping.id);
+    }
+}
+
+/// A build referred to by arch (the latest one) or by `#<id>`.
+enum Target {
+    Arch(String),
+    Id(i64),
+}
+
+impl Target {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+
+        if let Some(id) = s.strip_prefix('#') {
+            return id.parse().ok().map(Target::Id);
+        }
+
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return None;
+        }
+
+        Some(Target::Arch(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Arch(a) => write!(f, "{a}"),
+            Target::Id(id) => write!(f, "#{id}"),
+        }
+    }
+}
+
+/// The build `target` refers to, by arch the latest one in `pool`.
+async fn find_finished(
+    db: &mut Db,
+    target: &Target,
+    pool: &str,
+) -> eyre::Result<Option<HistoryEntry>> {
+    match target {
+        Target::Id(id) => db.find_history(*id).await,
+        Target::Arch(arch) => Ok(db
+            .history_of(pool)
+            .await?
+            .into_iter()
+            .find(|x| x.arch == *arch)),
+    }
+}
+
+/// The builds `actor` requested, from its index. Builds it still has as
+/// queued or running that are no longer, e.g. cancelled, are forgotten.
+async fn mybuilds(db: &mut Db, actor: &str, lang: Lang) -> eyre::Result<String> {
+    let now = now();
+    let mut lines = vec![];
+    let mut stats_of = BTreeMap::new();
+    for r in db.requested(actor).await? {
+        let line = match r.state {
+            RequestedState::Queued => {
+                let queue = db.queue(&r.pool, &r.arch).await?;
+                let Some(i) = queue.iter().position(|b| b.id == r.id) else {
+                    db.unindex_requested(actor, r.id).await?;
+                    continue;
+                };
+                if !stats_of.contains_key(&r.pool) {
+                    let history = db.history_of(&r.pool).await?;
+                    stats_of.insert(r.pool.clone(), stats(&history));
+                }
+                let mut running = db.running(&r.arch).await?;
+                running.retain(|x| x.build.pool == r.pool);
+                let window = db.window(&r.arch).await?;
+                let est = estimate(
+                    &stats_of[&r.pool],
+                    &r.arch,
+                    &running,
+                    &queue[..i],
+                    &queue[i].build_type,
+                    window.as_ref().filter(|_| !queue[i].ignore_window),
+                    now,
+                );
+                lang.tr(
+                    Msg::MyBuildsQueued,
+                    &[
+                        ("id", &r.id),
+                        ("arch", &r.arch),
+                        ("build", &r.build),
+                        ("position", &(i + 1)),
+                        ("estimate", &estimate_text(est.as_ref(), now, lang)),
+                    ],
+                )
+            }
+            RequestedState::Running => {
+                let Some(running) = db.get_running(&r.arch, r.id).await? else {
+                    db.unindex_requested(actor, r.id).await?;
+                    continue;
+                };
+                lang.tr(
+                    Msg::MyBuildsRunning,
+                    &[
+                        ("id", &r.id),
+                        ("arch", &r.arch),
+                        ("build", &r.build),
+                        (
+                            "elapsed",
+                            &human_duration(now.saturating_sub(running.claimed_at.unwrap_or(r.at))),
+                        ),
+                        (
+                            "worker",
+                            &running.worker.unwrap_or_else(|| lang.text(Msg::TheWorker)),
+                        ),
+                    ],
+                )
+            }
+            RequestedState::Succeeded | RequestedState::Failed => lang.tr(
+                Msg::MyBuildsFinished,
+                &[
+                    (
+                        "mark",
+                        &if r.state == RequestedState::Succeeded {
+                            "✅"
+                        } else {
+                            "❌"
+                        },
+                    ),
+                    ("id", &r.id),
+                    ("arch", &r.arch),
+                    ("build", &r.build),
+                    ("ago", &human_age(now.saturating_sub(r.at))),
+                    (
+                        "log",
+                        &r.log.unwrap_or_else(|| lang.text(Msg::LogPushFailed)),
+                    ),
+                ],
+            ),
+        };
+        lines.push(line);
+    }
+
+    if lines.is_empty() {
+        return Ok(lang.text(Msg::MyBuildsNone));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Who requested the builds running on `arch` in `pool`, with their notes.
+async fn whoisbuilding(db: &mut Db, pool: &Pool, arch: &str, lang: Lang) -> eyre::Result<String> {
+    if arch.is_empty() {
+        return Ok(lang.tr(Msg::Usage, &[("usage", &"/whoisbuilding <arch>")]));
+    }
+    if !pool.archs.iter().any(|x| x == arch) {
+        return Ok(lang.tr(Msg::UnknownArch, &[("arch", &arch)]));
+    }
+
+    let mut running = db.running(arch).await?;
+    running.retain(|r| r.build.pool == pool.name);
+    if running.is_empty() {
+        return Ok(lang.tr(Msg::WhoIsBuildingNone, &[("arch", &arch)]));
+    }
+
+    let now = now();
+    let mut lines = vec![];
+    for r in running {
+        let requester = match r.build.requester.as_deref() {
+            Some(actor) if actor.starts_with("api:") => actor.to_string(),
+            _ => alert::chat_name(db, r.build.requester_chat).await?,
+        };
+        let mut line = lang.tr(
+            Msg::WhoIsBuilding,
+            &[
+                ("id", &r.build.id),
+                ("arch", &arch),
+                ("build", &r.build.build_type),
+                ("requester", &requester),
+                (
+                    "elapsed",
+                    &human_duration(
+                        now.saturating_sub(r.claimed_at.or(r.build.started_at).unwrap_or(now)),
+                    ),
+                ),
+            ],
+        );
+        if let Some(ref note) = r.build.note {
+            line += &lang.tr(Msg::StatusNote, &[("note", note)]);
+        }
+        lines.push(line);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Builds `/history` lists at most.
+const HISTORY_LINES: usize = 10;
+
+/// The latest finished builds of `pool`, those matching `--grep` in `args`
+/// if given.
+async fn history_command(db: &mut Db, pool: &Pool, args: &str, lang: Lang) -> eyre::Result<String> {
+    let grep = match args.trim().strip_prefix("--grep").map(str::trim) {
+        Some(text) if !text.is_empty() => Some(text.to_lowercase()),
+        None if args.trim().is_empty() => None,
+        _ => {
+            return Ok(lang.tr(
+                Msg::Usage,
+                &[("usage", &"/history [--grep <text>] [--pool <pool>]")],
+            ))
+        }
+    };
+
+    let now = now();
+    let lines = db
+        .history_of(&pool.name)
+        .await?
+        .into_iter()
+        .filter(|h| {
+            grep.as_deref().is_none_or(|grep| {
+                h.note
+                    .as_deref()
+                    .is_some_and(|x| x.to_lowercase().contains(grep))
+                    || h.describe().to_lowercase().contains(grep)
+            })
+        })
+        .take(HISTORY_LINES)
+        .map(|h| {
+            let mut line = lang.tr(
+                Msg::HistoryLine,
+                &[
+                    ("id", &h.id),
+                    (
+                        "mark",
+                        &if h.success && h.push_success {
+                            "✅"
+                        } else {
+                            "❌"
+                        },
+                    ),
+                    ("arch", &h.arch),
+                    ("build", &h.describe()),
+                    ("ago", &human_age(now.saturating_sub(h.finished_at))),
+                ],
+            );
+            line += &lang.tr(
+                Msg::HistoryIntervals,
+                &[("intervals", &intervals_text(&h, lang))],
+            );
+            if h.pruned {
+                line += &lang.text(Msg::HistoryPruned);
+            }
+            if let Some(ref note) = h.note {
+                line += &lang.tr(Msg::StatusNote, &[("note", note)]);
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+
+    if lines.is_empty() {
+        return Ok(lang.text(Msg::HistoryNone));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Compare the two finished builds `args` names by id, in pools the chat
+/// may use.
+async fn diff_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let ids = args
+        .split_ascii_whitespace()
+        .map(|x| x.strip_prefix('#').and_then(|x| x.parse::<i64>().ok()))
+        .collect::<Option<Vec<_>>>();
+    let Some(&[old, new]) = ids.as_deref() else {
+        return Ok(lang.tr(Msg::Usage, &[("usage", &"/diff #<id> #<id>")]));
+    };
+
+    let mut builds = vec![];
+    for id in [old, new] {
+        let Some(h) = db.find_history(id).await? else {
+            return Ok(lang.tr(Msg::NoFinishedBuild, &[("target", &Target::Id(id))]));
+        };
+        if let Some(pool) = state.pools.get(&h.pool) {
+            if let Err(e) = allow_pool(db, msg, state, pool, lang).await {
+                return Ok(e);
+            }
+        }
+        builds.push(h);
+    }
+
+    Ok(diff::render(&builds[0], &builds[1], lang).unwrap_or_else(|e| e))
+}
+
+/// The steps of the build `target` refers to as HTML, preferring a running
+/// build over finished ones, by arch those of `pool`.
+async fn timeline(
+    db: &mut Db,
+    state: &AppState,
+    pool: &Pool,
+    target: &Target,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let mut running = None;
+    for arch in &state.pools.archs() {
+        running = match target {
+            Target::Id(id) => db.get_running(arch, *id).await?,
+            Target::Arch(a) if a == arch => db
+                .running(arch)
+                .await?
+                .into_iter()
+                .rfind(|r| r.build.pool == pool.name),
+            Target::Arch(_) => None,
+        };
+
+        if running.is_some() {
+            break;
+        }
+    }
+
+    let (title, steps) = match running {
+        Some(r) => (
+            lang.tr(
+                Msg::TimelineRunning,
+                &[
+                    ("id", &r.build.id),
+                    ("build", &r.build.build_type),
+                    ("arch", &r.build.arch),
+                ],
+            ),
+            r.steps,
+        ),
+        None => match find_finished(db, target, &pool.name).await? {
+            Some(h) => (
+                lang.tr(
+                    Msg::TimelineFinished,
+                    &[
+                        ("id", &h.id),
+                        ("build", &h.describe()),
+                        ("arch", &h.arch),
+                        (
+                            "result",
+                            &lang.text(if h.success {
+                                Msg::Success
+                            } else {
+                                Msg::HasError
+                            }),
+                        ),
+                    ],
+                ),
+                h.steps,
+            ),
+            None => return Ok(html::escape(&lang.tr(Msg::NoBuild, &[("target", target)]))),
+        },
+    };
+
+    if steps.is_empty() {
+        return Ok(html::escape(&format!(
+            "{title}\n{}",
+            lang.text(Msg::NoSteps)
+        )));
+    }
+
+    Ok(format!(
+        "{}\n<pre>{}</pre>",
+        html::escape(&title),
+        html::escape(&shipit_common::timeline(&steps, now()))
+    ))
+}
+
+/// Remove a queued build, or stop a running one by id. Only its requester
+/// and admins may do so, in a pool the chat may use.
+async fn cancel(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    target: &Target,
+    asked: Option<&str>,
+    lang: Lang,
+) -> eyre::Result<String> {
+    // by arch in the pool of the chat, by id in any
+    let pools = match target {
+        Target::Arch(_) => match pool_for(db, msg, state, asked, lang).await {
+            Ok(p) => vec![p],
+            Err(e) => return Ok(e),
+        },
+        Target::Id(_) => state.pools.iter().collect(),
+    };
+    let mut found = None;
+
+    'pools: for pool in pools {
+        for arch in &pool.archs {
+            if let Target::Arch(a) = target {
+                if a != arch {
+                    continue;
+                }
+            }
+
+            if let Target::Id(id) = target {
+                if let Some(r) = db.get_running(arch, *id).await? {
+                    return cancel_running(db, msg, state, &r.build, lang).await;
+                }
+            }
+
+            let queue = db.queue(&pool.name, arch).await?;
+            found = match target {
+                Target::Id(id) => queue.into_iter().find(|b| b.id == *id),
+                // the latest one of the caller
+                Target::Arch(_) => queue
+                    .into_iter()
+                    .rev()
+                    .find(|b| b.requester_chat == msg.chat.id.0),
+            };
+
+            if found.is_some() {
+                break 'pools;
+            }
+        }
+    }
+
+    let Some(build) = found else {
+        return Ok(lang.tr(Msg::NoQueuedBuild, &[("target", target)]));
+    };
+
+    if let Some(pool) = state.pools.get(&build.pool) {
+        if let Err(e) = allow_pool(db, msg, state, pool, lang).await {
+            return Ok(e);
+        }
+    }
+
+    if build.requester_chat != msg.chat.id.0 && !is_admin(msg, state) {
+        return Ok(lang.tr(Msg::CancelNotYours, &[("id", &build.id)]));
+    }
+
+    Ok(
+        match db
+            .remove_queued(&build.pool, &build.arch, build.id, events::Kind::Cancelled)
+            .await?
+        {
+            Some(b) => {
+                db.audit(
+                    &msg.chat.id.to_string(),
+                    &format!("cancelled #{} on {}", b.id, b.arch),
+                )
+                .await?;
+                cancel_dependents(db, &b.pool, &b.arch, b.id, Msg::DependencyCancelled).await?;
+                lang.tr(
+                    Msg::Cancelled,
+                    &[("id", &b.id), ("arch", &b.arch), ("build", &b.build_type)],
+                )
+            }
+            None => lang.tr(Msg::NoLongerQueued, &[("id", &build.id)]),
+        },
+    )
+}
+
+/// The part of [`cancel`] stopping the running build `b`, which its worker
+/// learns at its next heartbeat.
+async fn cancel_running(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    b: &Build,
+    lang: Lang,
+) -> eyre::Result<String> {
+    if let Some(pool) = state.pools.get(&b.pool) {
+        if let Err(e) = allow_pool(db, msg, state, pool, lang).await {
+            return Ok(e);
+        }
+    }
+
+    if b.requester_chat != msg.chat.id.0 && !is_admin(msg, state) {
+        return Ok(lang.tr(Msg::CancelNotYours, &[("id", &b.id)]));
+    }
+
+    let Some(r) = db.cancel_running(&b.arch, b.id).await? else {
+        return Ok(lang.tr(Msg::NoLongerRunning, &[("id", &b.id)]));
+    };
+    db.audit(
+        &msg.chat.id.to_string(),
+        &format!("cancelled running #{} on {}", b.id, b.arch),
+    )
+    .await?;
+    cancel_dependents(db, &b.pool, &b.arch, b.id, Msg::DependencyCancelled).await?;
+    if let Err(e) = progress::cancelled(state, b).await {
+        warn!("Failed to end the progress posts of #{}: {e}", b.id);
+    }
+
+    Ok(lang.tr(
+        Msg::CancelledRunning,
+        &[
+            ("id", &b.id),
+            ("arch", &b.arch),
+            ("build", &b.build_type),
+            ("worker", &r.worker.as_deref().unwrap_or("?")),
+        ],
+    ))
+}
+
+async fn queue_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let (args, asked) = take_pool(args);
+    let mut split = args.split_ascii_whitespace();
+    let (Some(arch), sub, n) = (split.next(), split.next(), split.next()) else {
+        return Ok(lang.text(Msg::UsageQueue));
+    };
+
+    let pool = match pool_for(db, msg, state, asked.as_deref(), lang).await {
+        Ok(p) => p,
+        Err(e) => return Ok(e),
+    };
+    if !pool.archs.iter().any(|x| x == arch) {
+        return Ok(lang.tr(Msg::UnknownArch, &[("arch", &arch)]));
+    }
+    let pool = &pool.name;
+
+    let Some(sub) = sub else {
+        return show_queue(db, pool, arch, lang).await;
+    };
+
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsQueue));
+    }
+
+    let n = n.and_then(|x| x.parse::<usize>().ok());
+    let actor = msg.chat.id.to_string();
+
+    // the audit log stays in English
+    let (builds, what, notice, reply) = match (sub, n) {
+        ("drop", Some(n)) => (
+            db.drop_queued_at(pool, arch, n)
+                .await?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            "removed from the queue by an admin",
+            Msg::RequestDropped,
+            Msg::QueueDropped,
+        ),
+        ("top", Some(n)) => (
+            db.move_queued_to_top(pool, arch, n)
+                .await?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            "moved to the front of the queue by an admin",
+            Msg::RequestMovedToTop,
+            Msg::QueueMovedToTop,
+        ),
+        ("clear", None) => (
+            db.clear_queue(pool, arch).await?,
+            "removed from the queue by an admin",
+            Msg::RequestDropped,
+            Msg::QueueDropped,
+        ),
+        _ => return Ok(lang.text(Msg::UsageQueue)),
+    };
+
+    if builds.is_empty() {
+        return Ok(lang.text(Msg::NoSuchQueued));
+    }
+
+    for b in &builds {
+        db.audit(&actor, &format!("{} #{} on {}: {}", sub, b.id, arch, what))
+            .await?;
+        let text = db.lang(b.requester_chat).await?.tr(
+            notice,
+            &[("arch", &arch), ("build", &b.build_type), ("id", &b.id)],
+        );
+        db.push_outbox(&Notification::plain(b.requester_chat, &text))
+            .await?;
+
+        if sub != "top" {
+            cancel_dependents(db, pool, arch, b.id, Msg::DependencyRemoved).await?;
+        }
+    }
+
+    let ids = builds
+        .iter()
+        .map(|b| format!("#{}", b.id))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    Ok(lang.tr(reply, &[("ids", &ids)]))
+}
+
+async fn show_queue(db: &mut Db, pool: &str, arch: &str, lang: Lang) -> eyre::Result<String> {
+    let queue = db.queue(pool, arch).await?;
+    if queue.is_empty() {
+        return Ok(lang.tr(Msg::QueueEmpty, &[("arch", &arch)]));
+    }
+
+    let stats = stats(&db.history_of(pool).await?);
+    let mut running = db.running(arch).await?;
+    running.retain(|r| r.build.pool == pool);
+    let window = db.window(arch).await?;
+    let now = now();
+    let mut res = lang.tr(Msg::QueueHeader, &[("arch", &arch)]);
+    res.push('\n');
+
+    for (i, b) in queue.iter().enumerate() {
+        let window = window.as_ref().filter(|_| !b.ignore_window);
+        let est = estimate(
+            &stats,
+            arch,
+            &running,
+            &queue[..i],
+            &b.build_type,
+            window,
+            now,
+        );
+        res.push_str(
+            &lang.tr(
+                Msg::QueueLine,
+                &[
+                    ("n", &(i + 1)),
+                    ("id", &b.id),
+                    ("build", &b.build_type),
+                    ("requester", &b.requester_chat),
+                    (
+                        "waiting",
+                        &b.queued_at
+                            .map(|x| human_duration(now.saturating_sub(x)))
+                            .unwrap_or_else(|| "?".to_string()),
+                    ),
+                    ("estimate", &estimate_text(est.as_ref(), now, lang)),
+                ],
+            ),
+        );
+        res.push('\n');
+    }
+
+    Ok(res)
+}
+
+async fn window_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let args = args.split_ascii_whitespace().collect::<Vec<_>>();
+    let Some((arch, rest)) = args.split_first() else {
+        let windows = db.windows().await?;
+        if windows.is_empty() {
+            return Ok(lang.text(Msg::NoWindows));
+        }
+
+        return Ok(windows
+            .iter()
+            .map(|(arch, w)| format!("{arch}: {w}"))
+            .collect::<Vec<_>>()
+            .join("\n"));
+    };
+
+    if !state.pools.archs().iter().any(|x| x == arch) {
+        return Ok(lang.tr(Msg::UnknownArch, &[("arch", arch)]));
+    }
+
+    if rest.is_empty() {
+        return Ok(match db.window(arch).await? {
+            Some(w) => lang.tr(Msg::BuildsDuring, &[("arch", arch), ("window", &w)]),
+            None => lang.tr(Msg::BuildsAnyTime, &[("arch", arch)]),
+        });
+    }
+
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsWindow));
+    }
+
+    let actor = msg.chat.id.to_string();
+    match rest {
+        ["clear"] => {
+            if !db.clear_window(arch).await? {
+                return Ok(lang.tr(Msg::NoWindow, &[("arch", arch)]));
+            }
+            db.audit(&actor, &format!("cleared the build window of {arch}"))
+                .await?;
+
+            Ok(lang.tr(Msg::BuildsAnyTimeAgain, &[("arch", arch)]))
+        }
+        [range] | [range, _] => {
+            let window = match Window::parse(range, rest.get(1).unwrap_or(&"UTC")) {
+                Ok(w) => w,
+                Err(e) => return Ok(e),
+            };
+            db.set_window(arch, &window).await?;
+            db.audit(
+                &actor,
+                &format!("set the build window of {arch} to {window}"),
+            )
+            .await?;
+
+            Ok(lang.tr(Msg::WindowSet, &[("arch", arch), ("window", &window)]))
+        }
+        _ => Ok(lang.tr(
+            Msg::Usage,
+            &[("usage", &"/window [arch] [HH:MM-HH:MM [time zone]|clear]")],
+        )),
+    }
+}
+
+/// Show the queue caps, or change them.
+async fn limits_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let args = args.split_ascii_whitespace().collect::<Vec<_>>();
+    let set = db.limits().await?;
+    let mut limits = set.unwrap_or(state.limits);
+    let show = |limit: Option<usize>| match limit {
+        Some(x) => x.to_string(),
+        None => lang.text(Msg::LimitUnlimited),
+    };
+
+    if args.is_empty() {
+        let refused = db.queue_full_counts().await?;
+        let mut lines = vec![lang.text(Msg::LimitsHeader)];
+        for cap in Cap::ALL {
+            lines.push(lang.tr(
+                Msg::LimitsLine,
+                &[
+                    ("cap", &cap),
+                    ("limit", &show(limits.get(cap))),
+                    ("refused", &refused.get(cap.name()).copied().unwrap_or(0)),
+                ],
+            ));
+        }
+        if set.is_some() {
+            lines.push(lang.text(Msg::LimitsSetAtRuntime));
+        }
+
+        return Ok(lines.join("\n"));
+    }
+
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsLimits));
+    }
+
+    let actor = msg.chat.id.to_string();
+    match args[..] {
+        ["reset"] => {
+            db.set_limits(None).await?;
+            db.audit(&actor, "reset the queue caps").await?;
+
+            Ok(lang.text(Msg::LimitsReset))
+        }
+        [cap, value] => {
+            let (Some(cap), Ok(limit)) = (
+                Cap::parse(cap),
+                match value {
+                    "off" => Ok(None),
+                    x => x.parse().map(Some),
+                },
+            ) else {
+                return Ok(lang.tr(
+                    Msg::Usage,
+                    &[("usage", &"/limits [total|arch|requester <n|off>|reset]")],
+                ));
+            };
+            limits.set(cap, limit);
+            db.set_limits(Some(&limits)).await?;
+            db.audit(&actor, &format!("set the {cap} queue cap to {value}"))
+                .await?;
+
+            Ok(lang.tr(Msg::LimitSet, &[("cap", &cap), ("limit", &show(limit))]))
+        }
+        _ => Ok(lang.tr(
+            Msg::Usage,
+            &[("usage", &"/limits [total|arch|requester <n|off>|reset]")],
+        )),
+    }
+}
+
+async fn workers_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    match args.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+        [] => {
+            let workers = workers::inventory(db, now(), state.worker_offline_after).await?;
+            Ok(workers::render(&workers, now(), lang))
+        }
+        ["forget", name] => {
+            if !is_admin(msg, state) {
+                return Ok(lang.text(Msg::OnlyAdminsWorkers));
+            }
+
+            if !db.forget_worker(name).await? {
+                return Ok(lang.tr(Msg::NoSuchWorker, &[("worker", &name)]));
+            }
+            db.audit(&msg.chat.id.to_string(), &format!("forgot worker {name}"))
+                .await?;
+
+            Ok(lang.tr(Msg::WorkerForgotten, &[("worker", &name)]))
+        }
+        _ => Ok(lang.tr(Msg::Usage, &[("usage", &"/workers [forget <name>]")])),
+    }
+}
+
+/// `/setup [status|<role>|remove <role>]`, for the chat it is sent in. The
+/// database is not locked while the bot probes the chat.
+async fn setup_command(
+    bot: &Telegram,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsSetup));
+    }
+
+    let chat = msg.chat.id.0;
+    let usage = || {
+        lang.tr(
+            Msg::Usage,
+            &[(
+                "usage",
+                &"/setup [status|<role>|remove <role>|progress <edit|replace|off>]",
+            )],
+        )
+    };
+    let unknown = |role| {
+        lang.tr(
+            Msg::SetupUnknownRole,
+            &[("role", &role), ("roles", &Role::names())],
+        )
+    };
+    match args.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+        [] | ["status"] => setup_status(&mut *state.db().await, chat, state, lang).await,
+        ["remove", role] => {
+            let Some(role) = Role::parse(role) else {
+                return Ok(unknown(role));
+            };
+
+            let mut db = state.db().await;
+            if !db.unregister(role, chat).await? {
+                return Ok(lang.tr(Msg::SetupNotSet, &[("role", &role.name())]));
+            }
+            db.audit(
+                &chat.to_string(),
+                &format!("removed {} from chat", role.name()),
+            )
+            .await?;
+
+            Ok(lang.tr(Msg::SetupRemoved, &[("role", &role.name())]))
+        }
+        ["progress", mode] => {
+            let Some(mode) = progress::Mode::parse(mode) else {
+                return Ok(usage());
+            };
+
+            let mut db = state.db().await;
+            db.update_chat(chat, |c| c.progress = mode).await?;
+            db.audit(
+                &chat.to_string(),
+                &format!("set progress posts to {}", mode.name()),
+            )
+            .await?;
+
+            Ok(lang.tr(Msg::SetupProgress, &[("mode", &mode.describe(lang))]))
+        }
+        [role] => {
+            let Some(role) = Role::parse(role) else {
+                return Ok(unknown(role));
+            };
+            if let Err(text) = probe(bot, msg.chat.id, lang).await {
+                return Ok(text);
+            }
+
+            let mut db = state.db().await;
+            let pool = match role {
+                Role::Announcements => match pool_for(&mut db, msg, state, None, lang).await {
+                    Ok(pool) => Some(pool.name.clone()),
+                    Err(text) => return Ok(text),
+                },
+                _ => None,
+            };
+            db.register(&Registration {
+                chat,
+                role,
+                pool: pool.clone(),
+                by: user_name(msg),
+                at: now(),
+            })
+            .await?;
+            db.audit(
+                &chat.to_string(),
+                &format!("set up chat for {}", role.name()),
+            )
+            .await?;
+
+            Ok(match pool {
+                Some(pool) => lang.tr(
+                    Msg::SetupDonePool,
+                    &[("role", &role.name()), ("pool", &pool)],
+                ),
+                None => lang.tr(Msg::SetupDone, &[("role", &role.name())]),
+            })
+        }
+        _ => Ok(usage()),
+    }
+}
+
+/// Whether the bot can post, delete and pin in `chat`. The error is the
+/// reply.
+async fn probe(bot: &Telegram, chat: ChatId, lang: Lang) -> Result<(), String> {
+    let sent = bot
+        .send_html(chat, html::escape(&lang.text(Msg::SetupProbe)))
+        .await
+        .map_err(|e| lang.tr(Msg::SetupCannotPost, &[("error", &e)]))?;
+    bot.delete(chat, sent)
+        .await
+        .map_err(|e| lang.tr(Msg::SetupCannotDelete, &[("error", &e)]))?;
+
+    match bot.can_pin(chat).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(lang.text(Msg::SetupCannotPin)),
+        Err(e) => Err(lang.tr(Msg::SetupCannotPinError, &[("error", &e)])),
+    }
+}
+
+/// The roles of `chat`, set up or configured.
+async fn setup_status(
+    db: &mut Db,
+    chat: i64,
+    state: &AppState,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let mut lines = vec![];
+    if state.admin_chat == Some(chat) {
+        lines.push(lang.tr(Msg::SetupConfigured, &[("role", &Role::Alerts.name())]));
+    }
+    for pool in state.pools.iter().filter(|x| x.chat == Some(chat)) {
+        lines.push(lang.tr(
+            Msg::SetupConfiguredPool,
+            &[("role", &Role::Announcements.name()), ("pool", &pool.name)],
+        ));
+    }
+
+    let now = now();
+    for r in db.registrations().await?.iter().filter(|x| x.chat == chat) {
+        let ago = human_age(now.saturating_sub(r.at));
+        lines.push(match r.pool {
+            Some(ref pool) => lang.tr(
+                Msg::SetupLinePool,
+                &[
+                    ("role", &r.role.name()),
+                    ("pool", pool),
+                    ("by", &r.by),
+                    ("ago", &ago),
+                ],
+            ),
+            None => lang.tr(
+                Msg::SetupLine,
+                &[("role", &r.role.name()), ("by", &r.by), ("ago", &ago)],
+            ),
+        });
+    }
+
+    let mode = db.chat(chat).await?.map(|c| c.progress).unwrap_or_default();
+    let progress = lang.tr(Msg::SetupProgress, &[("mode", &mode.describe(lang))]);
+    if lines.is_empty() {
+        let none = lang.tr(Msg::SetupNone, &[("roles", &Role::names())]);
+        return Ok(format!("{none}\n{progress}"));
+    }
+    lines.insert(0, lang.text(Msg::SetupHeader));
+    lines.push(progress);
+
+    Ok(lines.join("\n"))
+}
+
+/// `/digest [now]`: show the digest here, or send it to the digest chats.
+async fn digest_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    match args.trim() {
+        "" => {
+            let d = digest::latest(db, state, now()).await?;
+            Ok(digest::render(&d, state.freshness_threshold, lang))
+        }
+        "now" => {
+            if !is_admin(msg, state) {
+                return Ok(lang.text(Msg::OnlyAdminsDigest));
+            }
+
+            let chats = digest::send(db, state).await?;
+            db.audit(&msg.chat.id.to_string(), "sent the digest")
+                .await?;
+            if chats.is_empty() {
+                return Ok(lang.text(Msg::DigestNoChats));
+            }
+
+            Ok(lang.tr(Msg::DigestSent, &[("count", &chats.len())]))
+        }
+        _ => Ok(lang.tr(Msg::Usage, &[("usage", &"/digest [now]")])),
+    }
+}
+
+/// `/drain <arch|worker> on|off`: the name of an arch drains its workers.
+async fn drain_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsDrain));
+    }
+
+    let [name, switch] = args.split_ascii_whitespace().collect::<Vec<_>>()[..] else {
+        return Ok(lang.tr(Msg::Usage, &[("usage", &"/drain <arch|worker> on|off")]));
+    };
+    let target = if state.pools.archs().iter().any(|x| x == name) {
+        workers::arch_drain(name)
+    } else if db.worker(name).await?.is_some() {
+        workers::worker_drain(name)
+    } else {
+        return Ok(lang.tr(Msg::DrainUnknown, &[("name", &name)]));
+    };
+
+    let by = msg.chat.id.to_string();
+    match switch {
+        "on" => {
+            if db.drains().await?.contains_key(&target) {
+                return Ok(lang.tr(Msg::DrainAlready, &[("name", &name)]));
+            }
+            let drain = Drain {
+                by: by.clone(),
+                at: now(),
+                acked: Default::default(),
+            };
+            db.set_drain(&target, &drain).await?;
+            db.audit(&by, &format!("drained {target}")).await?;
+
+            Ok(lang.tr(Msg::DrainOn, &[("name", &name)]))
+        }
+        "off" => {
+            if !db.undrain(&target).await? {
+                return Ok(lang.tr(Msg::DrainNotOn, &[("name", &name)]));
+            }
+            db.audit(&by, &format!("stopped draining {target}")).await?;
+
+            Ok(lang.tr(Msg::DrainOff, &[("name", &name)]))
+        }
+        _ => Ok(lang.tr(Msg::Usage, &[("usage", &"/drain <arch|worker> on|off")])),
+    }
+}
+
+/// `/unpause [<arch> <type> [channel]]`: the paused builds alone, see
+/// [`streak`].
+async fn unpause_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsUnpause));
+    }
+
+    let usage = || {
+        lang.tr(
+            Msg::Usage,
+            &[("usage", &"/unpause [<arch> <type> [channel]]")],
+        )
+    };
+    let (arch, build_type, channel) = match args.split_ascii_whitespace().collect::<Vec<_>>()[..] {
+        [] => return streak::render(db, lang).await,
+        [arch, build_type] => (arch, build_type, None),
+        [arch, build_type, channel] => match channel.parse::<Channel>() {
+            Ok(c) => (arch, build_type, Some(c)),
+            Err(e) => return Ok(e),
+        },
+        _ => return Ok(usage()),
+    };
+
+    let unpaused = streak::unpause(db, arch, build_type, channel).await?;
+    if unpaused.is_empty() {
+        return Ok(lang.tr(
+            Msg::UnpauseNothing,
+            &[("arch", &arch), ("build", &build_type)],
+        ));
+    }
+    let channels = unpaused
+        .iter()
+        .map(|x| x.name())
+        .collect::<Vec<_>>()
+        .join(" ");
+    db.audit(
+        &msg.chat.id.to_string(),
+        &format!("unpaused {channels} {build_type} on {arch}"),
+    )
+    .await?;
+
+    Ok(lang.tr(
+        Msg::Unpaused,
+        &[
+            ("arch", &arch),
+            ("build", &build_type),
+            ("channels", &channels),
+        ],
+    ))
+}
+
+async fn watch_command(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    args: &str,
+    lang: Lang,
+) -> eyre::Result<String> {
+    let chat = msg.chat.id.0;
+    let on = match args.trim() {
+        "" => {
+            return Ok(if db.watchers().await?.contains(&chat) {
+                lang.text(Msg::Watching)
+            } else {
+                lang.text(Msg::NotWatching)
+            });
+        }
+        "on" => true,
+        "off" => false,
+        _ => return Ok(lang.tr(Msg::Usage, &[("usage", &"/watch [on|off]")])),
+    };
+
+    if !is_admin(msg, state) {
+        return Ok(lang.text(Msg::OnlyAdminsWatch));
+    }
+
+    if db.set_watching(chat, on).await? {
+        db.audit(
+            &chat.to_string(),
+            if on {
+                "started watching"
+            } else {
+                "stopped watching"
+            },
+        )
+        .await?;
+    }
+
+    Ok(lang.text(if on { Msg::WatchOn } else { Msg::WatchOff }))
+}
+
+async fn hook_command(db: &mut Db, msg: &Message, args: &str, lang: Lang) -> eyre::Result<String> {
+    let args = args.trim();
+    let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+    let actor = msg.chat.id.to_string();
+
+    Ok(match sub {
+        "" | "list" => {
+            let hooks = db.hooks().await?;
+            if hooks.is_empty() {
+                lang.text(Msg::NoHooks)
+            } else {
+                hooks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, h)| format!("{i}: {h}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        }
+        "add" => match Hook::parse(rest) {
+            Some(h) => {
+                db.add_hook(&h).await?;
+                db.audit(&actor, &format!("added hook {h}")).await?;
+                lang.tr(Msg::HookAdded, &[("hook", &h)])
+            }
+            None => lang.text(Msg::UsageHook),
+        },
+        "remove" => match rest.trim().parse() {
+            Ok(n) => match db.remove_hook(n).await? {
+                Some(h) => {
+                    db.audit(&actor, &format!("removed hook {h}")).await?;
+                    lang.tr(Msg::HookRemoved, &[("hook", &h)])
+                }
+                None => lang.tr(Msg::NoHook, &[("n", &n)]),
+            },
+            Err(_) => lang.tr(Msg::Usage, &[("usage", &"/hook remove <n>")]),
+        },
+        _ => lang.text(Msg::UsageHook),
+    })
+}
+
+/// Handle a build command taking `variants;[archs]`, `make` turns the
+/// checked variants into the job to queue. Missing variants are answered
+/// with the usage of `command`.
+async fn request_variants(
+    bot: &Telegram,
+    msg: &Message,
+    state: &AppState,
+    lang: Lang,
+    command: &str,
+    args: &str,
+    make: fn(Vec<String>) -> BuildType,
+) -> ResponseResult<()> {
+    let is_login = is_login(&msg.chat.id, state).await;
+
+    if !is_login {
+        return Ok(());
+    }
+
+    let (args, opts) = match split_options(args, lang) {
+        Ok(x) => x,
+        Err(e) => {
+            send_text(bot, msg.chat.id, &e).await?;
+            return Ok(());
+        }
+    };
+
+    let ReleaseArgs { variants, archs } =
+        match parse_release_args(&args, &state.pools.archs(), opts.yes) {
+            Ok(x) => x,
+            Err(e) => {
+                let usage = usage(command, state, lang).unwrap_or_default();
+                let e = e.text(lang);
+                send_text(bot, msg.chat.id, &format!("{e}\n\n{usage}")).await?;
+                return Ok(());
+            }
+        };
+
+    let archs = archs.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    request_builds(bot, msg, state, lang, &archs, make(variants), opts).await
+}
+
+/// Queue the builds through [`enqueue_build`], in the pool of
+/// [`pool_for`], and reply with a summary.
+async fn request_builds(
+    bot: &Telegram,
+    msg: &Message,
+    state: &AppState,
+    lang: Lang,
+    archs: &[&str],
+    build_type: BuildType,
+    mut opts: Options,
+) -> ResponseResult<()> {
+    let requester = Requester {
+        chat: msg.chat.id.0,
+        name: msg.from().map(|u| u.full_name()),
+        actor: msg.chat.id.to_string(),
+        scope: None,
+        admin: is_admin(msg, state),
+    };
+
+    let mut db = state.db().await;
+    match pool_for(&mut db, msg, state, opts.pool.as_deref(), lang).await {
+        Ok(p) => opts.pool = Some(p.name.clone()),
+        Err(e) => return send_text(bot, msg.chat.id, &e).await,
+    }
+    let res = enqueue_build(&mut db, state, &requester, archs, &build_type, &opts, lang).await;
+
+    let mut buttons = vec![];
+    let text = match res {
+        Ok(plan) => {
+            if !opts.dry_run {
+                buttons = plan
+                    .planned
+                    .iter()
+                    .map(|p| Button {
+                        action: Action::Cancel,
+                        id: p.build.id,
+                        label: lang.tr(
+                            Msg::ButtonCancel,
+                            &[("id", &p.build.id), ("arch", &p.build.arch)],
+                        ),
+                    })
+                    .collect();
+            }
+            render(&plan, opts.dry_run, lang)
+        }
+        Err(EnqueueError::Refused(e)) => e,
+        Err(EnqueueError::QueueFull(f)) => f.text(lang),
+        Err(EnqueueError::Db(e)) => lang.tr(Msg::RedisError, &[("error", &e)]),
+    };
+    drop(db);
+
+    let message = send_buttons(bot, msg.chat.id, &html::escape(&text), &buttons).await?;
+    if !buttons.is_empty() {
+        let posted = Posted {
+            chat: msg.chat.id.0,
+            message: message.0,
+            buttons,
+        };
+        if let Err(e) = state.db().await.set_buttons(&posted).await {
+            error!(
+                "Failed to record the cancel buttons of {}: {e}",
+                msg.chat.id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// What `/retry --resume` requests for the failed release `h`: the
+/// variants it did not upload. The error is the reply.
+fn resume(h: &HistoryEntry, force: bool, lang: Lang) -> Result<(BuildType, Resume), String> {
+    if h.success {
+        return Err(lang.tr(Msg::ResumeSucceeded, &[("id", &h.id)]));
+    }
+
+    let variants = match h.build_type() {
+        Some(BuildType::Release(v)) if v != [DEFAULT_SET] => v,
+        _ => return Err(lang.tr(Msg::ResumeNotRelease, &[("id", &h.id)])),
+    };
+    // the worker compares it with its checkout, older ones did not tell
+    if h.scripts_commit.is_none() && !force {
+        return Err(lang.tr(Msg::ResumeNoCommit, &[("id", &h.id)]));
+    }
+
+    let rest = variants
+        .into_iter()
+        .filter(|x| !h.built_variants.contains(x))
+        .collect::<Vec<_>>();
+    if rest.is_empty() {
+        return Err(lang.tr(Msg::NothingToResume, &[("id", &h.id)]));
+    }
+
+    Ok((
+        BuildType::Release(rest),
+        Resume {
+            from: h.id,
+            done: h.built_variants.clone(),
+            commit: h.scripts_commit.clone(),
+            force,
+        },
+    ))
+}
+
+/// The buttons under the notice of the finished build `h`: retry if it
+/// failed, and resume if [`resume`] would.
+pub fn retry_buttons(h: &HistoryEntry, lang: Lang) -> Vec<Button> {
+    if h.success {
+        return vec![];
+    }
+
+    let mut buttons = vec![Button {
+        action: Action::Retry,
+        id: h.id,
+        label: lang.text(Msg::ButtonRetry),
+    }];
+    if resume(h, false, lang).is_ok() {
+        buttons.push(Button {
+            action: Action::Resume,
+            id: h.id,
+            label: lang.text(Msg::ButtonResume),
+        });
+    }
+
+    buttons
+}
+
+/// What pressing a button came to.
+enum Pressed {
+    /// Noted under the message, whose buttons for the build go.
+    Done(String),
+    /// Told only to the one who pressed, the buttons stay.
+    Refused(String),
+    /// The build left the state the button acts on.
+    Expired,
+}
+
+/// A button of [`crate::buttons`] pressed: run its command as the user who
+/// pressed it, in the chat of the message, and note the outcome under it.
+pub async fn pressed(q: CallbackQuery, state: Arc<AppState>) -> ResponseResult<()> {
+    let (Some(message), Some((action, id))) =
+        (&q.message, q.data.as_deref().and_then(Button::parse))
+    else {
+        state.telegram.answer_callback(q.id, None, false).await?;
+        return Ok(());
+    };
+
+    let lang = chats::seen(&state, &message.chat).await;
+    let command = match action {
+        Action::Cancel => format!("/cancel #{id}"),
+        Action::Retry => format!("/retry #{id}"),
+        Action::Resume => format!("/retry #{id} --resume"),
+    };
+    let msg = as_command(message, &q.from, &command);
+
+    let res = match action {
+        Action::Cancel => press_cancel(&msg, &state, id, lang).await,
+        Action::Retry | Action::Resume => {
+            press_retry(&msg, &state, id, action == Action::Resume, lang).await
+        }
+    };
+    let pressed =
+        res.unwrap_or_else(|e| Pressed::Refused(lang.tr(Msg::RedisError, &[("error", &e)])));
+
+    let buttons = message
+        .reply_markup()
+        .map(|m| without(m, id))
+        .unwrap_or_default();
+    let (toast, alert, edit) = match pressed {
+        Pressed::Done(line) => {
+            // appended, so the entities of the text still fit
+            let text = message.text().map(|t| {
+                (
+                    format!("{t}\n\n{line}"),
+                    message.entities().unwrap_or_default().to_vec(),
+                )
+            });
+            (line, false, Some(text))
+        }
+        Pressed::Refused(reason) => (reason, true, None),
+        Pressed::Expired => (lang.text(Msg::ButtonExpired), false, Some(None)),
+    };
+
+    state
+        .telegram
+        .answer_callback(q.id, Some(toast), alert)
+        .await?;
+    if let Some(text) = edit {
+        let res = state
+            .telegram
+            .edit(message.chat.id, message.id, text, buttons)
+            .await;
+        match res {
+            Err(e) if !unchanged(&e) => warn!("Failed to update the buttons of #{id}: {e}"),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// `message` of the bot as if `user` sent `text`, for the code of the
+/// commands to check who asked.
+fn as_command(message: &Message, user: &User, text: &str) -> Message {
+    let mut msg = message.clone();
+    if let MessageKind::Common(ref mut c) = msg.kind {
+        c.from = Some(user.clone());
+        c.media_kind = MediaKind::Text(MediaText {
+            text: text.to_string(),
+            entities: vec![],
+        });
+    }
+
+    msg
+}
+
+fn user_name(msg: &Message) -> String {
+    msg.from()
+        .map(|u| u.full_name())
+        .unwrap_or_else(|| msg.chat.id.to_string())
+}
+
+/// [`cancel`] the build `id`, if it is still queued or running.
+async fn press_cancel(
+    msg: &Message,
+    state: &AppState,
+    id: i64,
+    lang: Lang,
+) -> eyre::Result<Pressed> {
+    let mut db = state.db().await;
+    if db.lifecycle(id).await?.is_some_and(|l| l.phase.is_final()) {
+        return Ok(Pressed::Expired);
+    }
+
+    let text = cancel(&mut db, msg, state, &Target::Id(id), None, lang).await?;
+    Ok(match db.lifecycle(id).await? {
+        Some(l) if l.phase == Phase::Cancelled => {
+            Pressed::Done(text + &lang.tr(Msg::ButtonBy, &[("user", &user_name(msg))]))
+        }
+        _ => Pressed::Refused(text),
+    })
+}
+
+/// `/retry` the failed build `id`, for its requester or an admin.
+async fn press_retry(
+    msg: &Message,
+    state: &Arc<AppState>,
+    id: i64,
+    resumed: bool,
+    lang: Lang,
+) -> eyre::Result<Pressed> {
+    let h = match state.db().await.find_history(id).await? {
+        Some(h) if !h.success => h,
+        _ => return Ok(Pressed::Expired),
+    };
+
+    if h.requester_chat != Some(msg.chat.id.0) && !is_admin(msg, state) {
+        return Ok(Pressed::Refused(
+            lang.tr(Msg::RetryNotYours, &[("id", &id)]),
+        ));
+    }
+    if !is_login(&msg.chat.id, state).await {
+        return Ok(Pressed::Refused(lang.text(Msg::NotLoggedIn)));
+    }
+    if resumed {
+        if let Err(e) = resume(&h, false, lang) {
+            return Ok(Pressed::Refused(e));
+        }
+    }
+
+    let args = msg.text().unwrap_or_default().trim_start_matches("/retry");
+    answer(
+        state.telegram.clone(),
+        msg.clone(),
+        Command::Retry(args.trim().to_string()),
+        state.clone(),
+    )
+    .await?;
+
+    Ok(Pressed::Done(lang.tr(
+        Msg::ButtonRetried,
+        &[("id", &id), ("user", &user_name(msg))],
+    )))
+}
+
+/// The builds of `pool`, and what is wrong with the arches and workers.
+async fn status(db: &mut Db, state: &AppState, pool: &Pool, lang: Lang) -> eyre::Result<String> {
+    let stats = stats(&db.history_of(&pool.name).await?);
+    let windows = db.windows().await?;
+    let now = now();
+    let mut res = String::new();
+    let workers = db.workers().await?;
+
+    let mut running = db.running_worker().await?;
+    running.retain(|r| r.build.pool == pool.name);
+    for r in &running {
+        let b = &r.build;
+        let mut line = lang.tr(
+            Msg::StatusBuilding,
+            &[("arch", &b.arch), ("id", &b.id), ("build", &b.build_type)],
+        );
+        if let Some(ref w) = r.worker {
+            line.push_str(&lang.tr(Msg::StatusWorker, &[("worker", w)]));
+            if r.cross {
+                line.push_str(&lang.text(Msg::StatusCross));
+            }
+            // only worth telling for a worker building for another arch
+            if let Some(w) = workers.iter().find(|x| x.name == *w && x.arches.len() > 1) {
+                line.push_str(&lang.tr(Msg::StatusWorkerArches, &[("arches", &w.arch_list())]));
+            }
+        }
+        if let Some(t) = r.claimed_at {
+            line.push_str(&lang.tr(
+                Msg::StatusElapsed,
+                &[("elapsed", &human_duration(now.saturating_sub(t)))],
+            ));
+        }
+        if let Some(t) = r.heartbeat_at {
+            line.push_str(&lang.tr(
+                Msg::StatusHeartbeat,
+                &[("ago", &human_duration(now.saturating_sub(t)))],
+            ));
+        }
+        match r.upload {
+            Some(ref u) => {
+                line.push_str(&lang.tr(
+                    Msg::StatusUpload,
+                    &[
+                        ("artifact", &u.artifact),
+                        ("sent", &human_bytes(u.sent)),
+                        ("total", &human_bytes(u.total)),
+                        (
+                            "percent",
+                            &(u.sent * 100).checked_div(u.total).unwrap_or(100),
+                        ),
+                    ],
+                ));
+                if u.stalled_secs >= state.upload_stall_after {
+                    line.push_str(&lang.tr(
+                        Msg::StatusUploadStalled,
+                        &[("stalled", &human_duration(u.stalled_secs))],
+                    ));
+                }
+            }
+            None => {
+                if let Some(ref p) = r.progress {
+                    line.push_str(&format!(", {p}"));
+                }
+            }
+        }
+        if let Some(s) = r.steps.iter().rev().find(|x| x.finished_at.is_none()) {
+            line.push_str(&lang.tr(
+                Msg::StatusStep,
+                &[
+                    ("step", &s.name),
+                    ("elapsed", &human_duration(now.saturating_sub(s.started_at))),
+                ],
+            ));
+        }
+        if let Some(ref note) = b.note {
+            line.push_str(&lang.tr(Msg::StatusNote, &[("note", note)]));
+        }
+        res.push_str(&line);
+        res.push('\n');
+    }
+
+    for arch in &pool.archs {
+        let mut running = db.running(arch).await?;
+        running.retain(|r| r.build.pool == pool.name);
+        let queue = db.queue(&pool.name, arch).await?;
+
+        for (i, b) in queue.iter().enumerate() {
+            let window = windows.get(arch).filter(|_| !b.ignore_window);
+            let est = estimate(
+                &stats,
+                arch,
+                &running,
+                &queue[..i],
+                &b.build_type,
+                window,
+                now,
+            );
+            let waiting = match b.after {
+                Some(dep) => lang.tr(Msg::WaitingFor, &[("id", &dep)]),
+                None => String::new(),
+            };
+            res.push_str(&lang.tr(
+                Msg::StatusQueued,
+                &[
+                    ("arch", arch),
+                    ("n", &(i + 1)),
+                    ("id", &b.id),
+                    ("build", &b.build_type),
+                    ("waiting", &waiting),
+                    ("estimate", &estimate_text(est.as_ref(), now, lang)),
+                ],
+            ));
+            if let Some(ref note) = b.note {
+                res.push_str(&lang.tr(Msg::StatusNote, &[("note", note)]));
+            }
+            if let Some(ref worker) = b.worker {
+                res.push_str(&lang.tr(Msg::StatusPinned, &[("worker", worker)]));
+            }
+            res.push('\n');
+        }
+    }
+
+    let mut notes = vec![];
+    if res.is_empty() {
+        notes.push(lang.text(Msg::Idle));
+    }
+    if pool.name != MAINLINE {
+        res.insert_str(
+            0,
+            &format!("{}\n", lang.tr(Msg::PoolHeader, &[("pool", &pool.name)])),
+        );
+    }
+
+    for (arch, d) in db.disabled().await? {
+        notes.push(match d.reason {
+            Some(ref r) => lang.tr(Msg::StatusDisabledReason, &[("arch", &arch), ("reason", r)]),
+            None => lang.tr(Msg::StatusDisabled, &[("arch", &arch)]),
+        });
+    }
+
+    for (arch, w) in &windows {
+        if !w.is_open(now) {
+            notes.push(lang.tr(
+                Msg::OutsideWindow,
+                &[("arch", arch), ("time", &w.opens_at()), ("tz", &w.tz)],
+            ));
+        }
+    }
+
+    let drains = db.drains().await?;
+    for w in workers.iter().filter(|w| w.pool == pool.name) {
+        if workers::drained(&drains, &w.name, &w.arch_names()).is_none() {
+            continue;
+        }
+        notes.push(
+            match running.iter().find(|r| r.worker.as_ref() == Some(&w.name)) {
+                Some(r) => lang.tr(
+                    Msg::StatusDraining,
+                    &[("worker", &w.name), ("id", &r.build.id)],
+                ),
+                None => lang.tr(Msg::StatusDrained, &[("worker", &w.name)]),
+            },
+        );
+    }
+
+    for w in workers.iter().filter(|w| w.pool == pool.name) {
+        if let (Some(reason), true) = (
+            &w.deferring,
+            now.saturating_sub(w.last_seen) <= state.worker_offline_after,
+        ) {
+            notes.push(lang.tr(
+                Msg::StatusDeferring,
+                &[("worker", &w.name), ("reason", reason)],
+            ));
+        }
+    }
+
+    for s in db.clock_skews().await? {
+        notes.push(lang.tr(
+            if s.skew_secs > 0 {
+                Msg::ClockAhead
+            } else {
+                Msg::ClockBehind
+            },
+            &[
+                ("arch", &s.arch),
+                ("worker", &s.worker),
+                ("skew", &human_duration(s.skew_secs.unsigned_abs())),
+            ],
+        ));
+    }
+
+    for u in db.unreachable().await? {
+        notes.push(lang.tr(
+            if u.blocked {
+                Msg::UploadUnreachable
+            } else {
+                Msg::UploadUnreachableWarn
+            },
+            &[
+                ("arch", &u.arch),
+                ("worker", &u.worker),
+                ("error", &u.error),
+            ],
+        ));
+    }
+
+    for l in db.low_disk().await? {
+        notes.push(lang.tr(
+            Msg::LowDisk,
+            &[
+                ("arch", &l.arch),
+                ("worker", &l.worker),
+                ("free", &human_bytes(l.free)),
+                ("min", &human_bytes(l.min)),
+            ],
+        ));
+    }
+
+    let failed = db.failed_notifications().await?;
+    if !failed.is_empty() {
+        notes.push(lang.tr(Msg::FailedNotifications, &[("count", &failed.len())]));
+    }
+
+    res.push_str(&notes.join("\n"));
+
+    Ok(res)
+}
+
+/// Finish a login started from the minzhengbu page. Returns the GitHub user
+/// name if minzhengbu answered with one, as `{"login": "..."}`.
+pub async fn login_github(msg: &Message, rid: &str) -> Result<Option<String>, reqwest::Error> {
+    let client = reqwest::Client::new();
+
+    let body = client
+        .get("https://minzhengbu.aosc.io/login_from_telegram".to_string())
+        .query(&[
+            ("telegram_id", msg.chat.id.0.to_string()),
+            ("rid", rid.to_string()),
+        ])
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())?
+        .text()
+        .await?;
+
+    let user = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| {
+            ["login", "username"]
+                .iter()
+                .find_map(|k| v.get(k)?.as_str().map(|x| x.to_string()))
+        });
+
+    Ok(user)
+}
+
+/// Which `/start` arguments are taken for a login rid: `shipit_rid_len`
+/// (`min-max`, default `8-128`) characters out of ASCII letters, digits
+/// and `shipit_rid_charset` (default `-_`).
+pub struct RidPolicy {
+    min: usize,
+    max: usize,
+    extra: String,
+}
+
+impl RidPolicy {
+    pub fn from_env() -> eyre::Result<Self> {
+        let (min, max) = match std::env::var("shipit_rid_len") {
+            Ok(x) => {
+                let (min, max) = x
+                    .split_once('-')
+                    .ok_or_else(|| eyre::eyre!("shipit_rid_len should look like 8-128"))?;
+                (min.trim().parse()?, max.trim().parse()?)
+            }
+            Err(_) => (8, 128),
+        };
+        let extra = std::env::var("shipit_rid_charset").unwrap_or_else(|_| "-_".to_string());
+
+        Ok(Self { min, max, extra })
+    }
+
+    fn accepts(&self, rid: &str) -> bool {
+        (self.min..=self.max).contains(&rid.len())
+            && rid
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || self.extra.contains(c))
+    }
+}
+
+async fn whoami(
+    db: &mut Db,
+    msg: &Message,
+    state: &AppState,
+    logged_in: bool,
+    lang: Lang,
+) -> eyre::Result<String> {
+    if !logged_in {
+        return Ok(lang.text(Msg::NotLoggedIn));
+    }
+
+    let chat = msg.chat.id.0;
+    let mut s = match db.login_user(chat).await? {
+        Some(u) => lang.tr(Msg::LoggedInAs, &[("user", &u)]),
+        None => lang.text(Msg::LoggedIn),
+    };
+
+    if let Some(at) = db.login_verified_at(chat).await? {
+        let age = now().saturating_sub(at);
+        s.push('\n');
+        s.push_str(&lang.tr(Msg::LoginConfirmed, &[("ago", &human_duration(age))]));
+
+        if age < state.login_ttl {
+            s.push_str(&lang.tr(
+                Msg::LoginRecheck,
+                &[("left", &human_duration(state.login_ttl - age))],
+            ));
+        } else {
+            s.push_str(&lang.tr(
+                Msg::LoginGrace,
+                &[(
+                    "left",
+                    &human_duration(state.login_grace.saturating_sub(age)),
+                )],
+            ));
+        }
+    }
+
+    if is_admin(msg, state) {
+        s.push('\n');
+        s.push_str(&lang.text(Msg::YouAreAdmin));
+    }
+
+    Ok(s)
+}
+
+/// Send plain text. The text is escaped for HTML parse mode, so external
+/// strings (error messages, log excerpts) can never break the markup.
+pub async fn send_text(bot: &Telegram, chat: ChatId, text: &str) -> ResponseResult<()> {
+    send_html(bot, chat, &html::escape(text)).await
+}
+
+/// Send a message that is already HTML and wait until it went out, see
+/// [`Telegram::send_html`].
+pub async fn send_html(bot: &Telegram, chat: ChatId, text: &str) -> ResponseResult<()> {
+    bot.send_html(chat, text.to_string()).await.map(|_| ())
+}
+
+/// [`send_html`] with `buttons` under the message, if any. Returns the
+/// message, to take them off again.
+pub async fn send_buttons(
+    bot: &Telegram,
+    chat: ChatId,
+    text: &str,
+    buttons: &[Button],
+) -> ResponseResult<MessageId> {
+    if buttons.is_empty() {
+        return bot.send_html(chat, text.to_string()).await;
+    }
+
+    bot.send_buttons(chat, text.to_string(), keyboard(buttons))
+        .await
+}
+
+/// Negative answers are only remembered briefly to keep the login flow snappy.
+const LOGIN_DENIED_TTL: u64 = 5;
+
+/// Whether `chat` has logged in with GitHub. Confirmed logins are cached in
+/// Redis for `login_ttl` seconds; if minzhengbu cannot be reached, an older
+/// confirmation is accepted with a warning.
+pub async fn is_login(chat: &ChatId, state: &AppState) -> bool {
+    let mut db = state.db().await;
+    let verified_at = db.login_verified_at(chat.0).await.unwrap_or_else(|e| {
+        error!("Failed to read login cache: {e}");
+        None
+    });
+
+    if verified_at.is_some_and(|x| now().saturating_sub(x) < state.login_ttl) {
+        return true;
+    }
+
+    if db.is_login_denied(chat.0).await.unwrap_or(false) {
+        return false;
+    }
+
+    let client = reqwest::Client::new();
+    let resp = client
+        .get("https://minzhengbu.aosc.io/get_token")
+        .query(&[("id", &chat.0.to_string())])
+        .header("secret", &state.secret)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+
+    match resp {
+        Ok(_) => {
+            if let Err(e) = db.set_login_verified(chat.0, state.login_grace).await {
+                error!("Failed to cache login: {e}");
+            }
+            true
+        }
+        Err(e) if e.status().is_some_and(|s| s.is_client_error()) => {
+            if let Err(e) = db.set_login_denied(chat.0, LOGIN_DENIED_TTL).await {
+                error!("Failed to cache login: {e}");
+            }
+            false
+        }
+        Err(e) => {
+            error!("{e}");
+            if verified_at.is_some() {
+                warn!(
+                    "minzhengbu is unreachable, using cached login of {}",
+                    chat.0
+                );
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+
+fn is_admin(msg: &Message, state: &AppState) -> bool {
+    msg.from()
+        .is_some_and(|u| state.admins.contains(&(u.id.0 as i64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        db::{now, Db, Preference, Requested},
+        secret::{Previous, Secrets},
+        testing::{entry, redis, ADMIN},
+    };
+
+    /// The id of the build a worker takes next on amd64.
+    async fn claim(db: &mut Db) -> i64 {
+        db.claim(
+            "mainline",
+            "amd64",
+            Some("w1"),
+            None,
+            true,
+            Preference::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+        .id
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_dry_run_queues_nothing() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+
+        for text in ["/livekit ?amd64", "/livekit amd64 --dry-run"] {
+            let reply = server.ask(ADMIN, text).await;
+            assert!(reply.starts_with("Dry run, nothing was queued."), "{reply}");
+            assert!(reply.contains("Would queue amd64 for livekit"), "{reply}");
+            assert!(db.queue("mainline", "amd64").await.unwrap().is_empty());
+        }
+
+        let reply = server.ask(ADMIN, "/livekit amd64").await;
+        assert!(reply.contains("Queued #"), "{reply}");
+        assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_busy_arch_is_queued_and_says_what_it_waits_for() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+
+        server.ask(ADMIN, "/livekit amd64").await;
+        let running = claim(&mut db).await;
+
+        let reply = server.ask(ADMIN, "/release base;amd64 arm64 sparc64").await;
+        assert!(
+            reply.contains(&format!(
+                "amd64 for release(base) at position 1 (busy with #{running})"
+            )),
+            "{reply}"
+        );
+        assert!(
+            reply.contains("arm64 for release(base) at position 1,"),
+            "{reply}"
+        );
+        // on a line of its own, not mixed into what was queued
+        assert_eq!(
+            reply
+                .lines()
+                .filter(|x| x.contains("sparc64"))
+                .collect::<Vec<_>>(),
+            ["Unknown arch: sparc64"]
+        );
+        assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 1);
+        assert_eq!(db.queue("mainline", "arm64").await.unwrap().len(), 1);
+        assert!(db.queue("mainline", "sparc64").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn export_leaves_the_pool_secrets_out() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        let secrets = Secrets {
+            primary: "rotated-secret".to_string(),
+            previous: Some(Previous {
+                secret: "worker-secret".to_string(),
+                until: now() + 3600,
+            }),
+        };
+        db.set_secrets("mainline", &secrets).await.unwrap();
+
+        let reply = server.ask(ADMIN, "/export").await;
+        assert!(
+            reply.contains("The pool secrets are left out, GET /export"),
+            "{reply}"
+        );
+        assert!(reply.contains("\"secrets\": {}"), "{reply}");
+        assert!(!reply.contains("rotated-secret"), "{reply}");
+        assert!(!reply.contains("worker-secret"), "{reply}");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn mybuilds_lists_what_the_chat_requested() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+
+        server.ask(ADMIN, "/livekit amd64").await;
+        server.ask(ADMIN, "/release base;amd64").await;
+        let running = claim(&mut db).await;
+        let queued = db.queue("mainline", "amd64").await.unwrap()[0].id;
+        let mut h = entry(1, "arm64", "livekit", false, 600);
+        h.finished_at = now() - 3600;
+        let log = Some("https://buildit.aosc.io/logs/1".to_string());
+        db.index_requested(&ADMIN.to_string(), &Requested::finished(&h, log))
+            .await
+            .unwrap();
+
+        let reply = server.ask(ADMIN, "/mybuilds").await;
+        let lines = reply.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{reply}");
+        for line in [
+            format!("🔨 #{running} amd64 livekit, running for "),
+            format!("⏳ #{queued} amd64 release(base), queued at position 1, "),
+            "❌ #1 arm64 livekit, 1h0m ago: https://buildit.aosc.io/logs/1".to_string(),
+        ] {
+            assert!(
+                lines.iter().any(|x| x.starts_with(&line)),
+                "{line}\n{reply}"
+            );
+        }
+
+        // the index is per chat
+        db.set_login_verified(20, 3600).await.unwrap();
+        assert_eq!(
+            server.ask(20, "/mybuilds").await,
+            "No builds requested from this chat."
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn whoisbuilding_names_the_requester() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+
+        server
+            .ask(ADMIN, "/livekit amd64 --note \"CVE-2024-1234\"")
+            .await;
+        let id = claim(&mut db).await;
+
+        let reply = server.ask(ADMIN, "/whoisbuilding amd64").await;
+        assert!(
+            reply.starts_with(&format!(
+                "#{id} amd64 livekit, requested by {ADMIN}, running for "
+            )),
+            "{reply}"
+        );
+        assert!(reply.contains("CVE-2024-1234"), "{reply}");
+        assert_eq!(
+            server.ask(ADMIN, "/whoisbuilding arm64").await,
+            "Nothing is building on arm64."
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn an_identical_job_needs_force() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+
+        server.ask(ADMIN, "/livekit amd64").await;
+        let id = db.queue("mainline", "amd64").await.unwrap()[0].id;
+        let reply = server.ask(ADMIN, "/livekit amd64").await;
+        let refused = format!(
+            "Identical job #{id} for amd64 already queued at position 1, \
+             use --force to queue anyway"
+        );
+        assert!(reply.contains(&refused), "{reply}");
+        assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 1);
+
+        let reply = server.ask(ADMIN, "/livekit amd64 --force").await;
+        assert!(reply.contains("at position 2"), "{reply}");
+        assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 2);
+
+        // another type is not the same job
+        server.ask(ADMIN, "/release base;amd64").await;
+        assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 3);
     }
 }
@@ -0,0 +1,165 @@
+//! Inline buttons under the messages of the bot: cancel under the reply to
+//! queued builds and under the progress of running ones, see
+//! [`crate::progress`], retry under the notice of a failed one. Pressing one
+//! runs the command it stands for, see [`crate::bot::pressed`]. A button is
+//! gone once pressed, the cancel button of a queued build as soon as it
+//! leaves the queue, and that of a running one when it finishes.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    types::{
+        ChatId, InlineKeyboardButton, InlineKeyboardButtonKind, InlineKeyboardMarkup, MessageId,
+    },
+    ApiError, RequestError,
+};
+use tokio::time::sleep;
+use tracing::{error, warn};
+
+use crate::AppState;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// `/cancel #id`, queued or running
+    Cancel,
+    /// `/retry #id`
+    Retry,
+    /// `/retry #id --resume`
+    Resume,
+}
+
+impl Action {
+    pub const ALL: [Action; 3] = [Action::Cancel, Action::Retry, Action::Resume];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Cancel => "cancel",
+            Action::Retry => "retry",
+            Action::Resume => "resume",
+        }
+    }
+}
+
+/// A button doing `action` to build `id`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Button {
+    pub action: Action,
+    pub id: i64,
+    pub label: String,
+}
+
+impl Button {
+    /// What Telegram hands back when it is pressed, e.g. `cancel:42`.
+    pub fn data(&self) -> String {
+        format!("{}:{}", self.action.name(), self.id)
+    }
+
+    pub fn parse(data: &str) -> Option<(Action, i64)> {
+        let (action, id) = data.split_once(':')?;
+        let action = Action::ALL.into_iter().find(|x| x.name() == action)?;
+
+        Some((action, id.parse().ok()?))
+    }
+}
+
+/// A message with cancel buttons, remembered for each build they cancel.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Posted {
+    pub chat: i64,
+    pub message: i32,
+    pub buttons: Vec<Button>,
+}
+
+impl Posted {
+    /// The builds with a cancel button.
+    pub fn cancels(&self) -> impl Iterator<Item = i64> + '_ {
+        self.buttons
+            .iter()
+            .filter(|b| b.action == Action::Cancel)
+            .map(|b| b.id)
+    }
+}
+
+/// Two buttons a row.
+pub fn keyboard(buttons: &[Button]) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(buttons.chunks(2).map(|row| {
+        row.iter()
+            .map(|b| InlineKeyboardButton::callback(b.label.clone(), b.data()))
+            .collect::<Vec<_>>()
+    }))
+}
+
+/// `markup` without the buttons acting on build `id`.
+pub fn without(markup: &InlineKeyboardMarkup, id: i64) -> InlineKeyboardMarkup {
+    let rows = markup
+        .inline_keyboard
+        .iter()
+        .map(|row| {
+            row.iter()
+                .filter(|b| match &b.kind {
+                    InlineKeyboardButtonKind::CallbackData(data) => {
+                        Button::parse(data).is_none_or(|(_, x)| x != id)
+                    }
+                    _ => true,
+                })
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| !row.is_empty());
+
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// Whether editing a message failed only because it already looks so, e.g.
+/// after two presses at once.
+pub fn unchanged(e: &RequestError) -> bool {
+    matches!(e, RequestError::Api(ApiError::MessageNotModified))
+}
+
+/// Take the cancel buttons of builds that left the queue off their
+/// messages.
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        let next = state.db().await.pop_stale_buttons().await;
+
+        let id = match next {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to read stale buttons: {e}");
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        let posted = match state.db().await.take_buttons(id).await {
+            Ok(Some(p)) => p,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to read the buttons of #{id}: {e}");
+                continue;
+            }
+        };
+
+        let res = state
+            .telegram
+            .edit(
+                ChatId(posted.chat),
+                MessageId(posted.message),
+                None,
+                keyboard(&posted.buttons),
+            )
+            .await;
+        match res {
+            Err(e) if !unchanged(&e) => {
+                warn!("Failed to take the cancel button of #{id} off: {e}")
+            }
+            _ => {}
+        }
+    }
+}
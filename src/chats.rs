@@ -0,0 +1,122 @@
+//! The chat registry: which chats the bot talks to, where groups upgraded
+//! to supergroups went, and which chats cannot be reached. The outbox
+//! consults it before sending.
+
+use std::sync::Arc;
+
+use teloxide::{
+    requests::ResponseResult,
+    types::{Chat, ChatId, ChatMemberUpdated, Message},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    db::{now, ChatRecord},
+    format::human_duration,
+    lang::{Lang, Msg},
+    AppState,
+};
+
+fn title(chat: &Chat) -> Option<String> {
+    chat.title().or(chat.username()).map(|x| x.to_string())
+}
+
+/// `chat` talked to the bot, so it can be sent to again. Returns the
+/// language to answer in.
+pub async fn seen(state: &AppState, chat: &Chat) -> Lang {
+    let res = state
+        .db()
+        .await
+        .update_chat(chat.id.0, |c| {
+            c.title = title(chat).or(c.title.take());
+            c.undeliverable = false;
+        })
+        .await;
+
+    match res {
+        Ok(c) => c.lang,
+        Err(e) => {
+            error!("Failed to update chat registry: {e}");
+            Lang::default()
+        }
+    }
+}
+
+/// The bot was added to or removed from a chat.
+pub async fn member_updated(upd: ChatMemberUpdated, state: Arc<AppState>) -> ResponseResult<()> {
+    if upd.new_chat_member.kind.is_present() {
+        info!("Added to chat {}", upd.chat.id);
+        seen(&state, &upd.chat).await;
+        return Ok(());
+    }
+
+    warn!(
+        "Removed from chat {}, flagging it undeliverable",
+        upd.chat.id
+    );
+    let res = state
+        .db()
+        .await
+        .update_chat(upd.chat.id.0, |c| {
+            c.title = title(&upd.chat).or(c.title.take());
+            c.undeliverable = true;
+            c.last_error = Some("the bot was removed from the chat".to_string());
+        })
+        .await;
+
+    if let Err(e) = res {
+        error!("Failed to update chat registry: {e}");
+    }
+
+    Ok(())
+}
+
+/// A group was upgraded to the supergroup `new`.
+pub async fn migrated(msg: Message, new: ChatId, state: Arc<AppState>) -> ResponseResult<()> {
+    info!("Chat {} migrated to {new}", msg.chat.id);
+    if let Err(e) = state.db().await.migrate_chat(msg.chat.id.0, new.0).await {
+        error!("Failed to record migration of chat {}: {e}", msg.chat.id);
+    }
+
+    Ok(())
+}
+
+/// One line per chat for `/chats`.
+pub fn render(chats: &[ChatRecord], lang: Lang) -> String {
+    if chats.is_empty() {
+        return lang.text(Msg::ChatsNone);
+    }
+
+    let now = now();
+    chats
+        .iter()
+        .map(|c| {
+            let mut line = c.id.to_string();
+            if let Some(ref t) = c.title {
+                line.push_str(&format!(" ({t})"));
+            }
+
+            if let Some(new) = c.migrated_to {
+                line.push_str(&lang.tr(Msg::ChatMigrated, &[("id", &new)]));
+                return line;
+            }
+
+            match c.delivered_at {
+                Some(t) => line.push_str(&lang.tr(
+                    Msg::ChatDelivered,
+                    &[("ago", &human_duration(now.saturating_sub(t)))],
+                )),
+                None => line.push_str(&lang.text(Msg::ChatNothingDelivered)),
+            }
+            if c.undeliverable {
+                line.push_str(&lang.text(Msg::ChatUndeliverable));
+            }
+            if let Some(ref e) = c.last_error {
+                line.push_str(&lang.tr(Msg::ChatLastError, &[("error", e)]));
+            }
+
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,74 +1,3507 @@
-use std::fmt::Display;
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use redis::{aio::MultiplexedConnection, AsyncCommands};
-use serde::{Deserialize, Serialize};
+use redis::{
+    aio::MultiplexedConnection,
+    streams::{StreamMaxlen, StreamReadOptions, StreamReadReply},
+    AsyncCommands,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shipit_common::STAGE_PUBLISHING;
+pub use shipit_common::{
+    ArtifactPush, BuildType, Channel, Failure, Resume, Staleness, Step, UploadProgress, DEFAULT_SET,
+};
+use tracing::warn;
 
+use crate::{
+    buttons::Posted,
+    events::{Event, Kind},
+    hook::{Hook, HookResult},
+    lang::Lang,
+    lifecycle::{IllegalTransition, Intervals, Lifecycle, Phase, Transition},
+    limits::{Cap, Limits},
+    outbox::Notification,
+    pool::MAINLINE,
+    progress::{self, Posts},
+    schema,
+    secret::Secrets,
+    setup::{Registration, Role},
+    streak::Streak,
+    window::Window,
+};
+
+/// A handle on the Redis connection, cheap to clone as every clone shares
+/// it.
+#[derive(Clone)]
 pub struct Db {
     conn: MultiplexedConnection,
+    /// Entries the event stream is trimmed to, see [`crate::events`].
+    events_len: usize,
+    /// Events that could not be published since the start, by any clone.
+    events_dropped: Arc<AtomicU64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Build {
     pub id: i64,
+    pub requester_chat: i64,
     pub arch: String,
+    /// Only its workers take the build, see [`crate::pool`].
+    #[serde(default = "mainline")]
+    pub pool: String,
+    #[serde(with = "shipit_common::legacy")]
     pub build_type: BuildType,
+    #[serde(default)]
+    pub queued_at: Option<u64>,
+    #[serde(default)]
+    pub started_at: Option<u64>,
+    /// Drop the build if no worker picks it up for this long, overriding the
+    /// server-wide maximum queue age.
+    #[serde(default)]
+    pub expire_secs: Option<u64>,
+    /// Only claimable after this build (on the same arch) succeeded.
+    #[serde(default)]
+    pub after: Option<i64>,
+    /// Upload a release even if some of its variants produced nothing.
+    #[serde(default)]
+    pub allow_partial: bool,
+    #[serde(default)]
+    pub channel: Channel,
+    /// Only claimable by the worker of this name, e.g. for a repush of
+    /// artifacts only it has.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker: Option<String>,
+    /// Variables set for the build script.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Appended to the arguments of the build script.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Taken even outside the build window of the arch, see `--now`.
+    #[serde(default)]
+    pub ignore_window: bool,
+    /// See `--no-clean`.
+    #[serde(default)]
+    pub no_clean: bool,
+    /// See `--incremental`.
+    #[serde(default)]
+    pub incremental: bool,
+    /// See `--flavor`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<String>,
+    /// See `/retry --resume`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<Resume>,
+    /// Only claimable by workers for which the arch is native, see
+    /// `--native-only`.
+    #[serde(default)]
+    pub native_only: bool,
+    /// Why it is built, see `--note`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// Where `POST /api/v1/builds` asked the result to be posted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    /// Who requested it, see [`crate::plan::Requester::actor`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+}
+
+/// A build claimed by a worker. Records written before workers identified
+/// themselves are a bare [`Build`], hence the defaults.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunningBuild {
+    #[serde(flatten)]
+    pub build: Build,
+    #[serde(default)]
+    pub worker: Option<String>,
+    #[serde(default)]
+    pub claimed_at: Option<u64>,
+    #[serde(default)]
+    pub heartbeat_at: Option<u64>,
+    /// Last stage reported through `/progress`.
+    #[serde(default)]
+    pub progress: Option<String>,
+    /// Commands run so far, as of the last heartbeat or progress report.
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Claimed by a worker building for another arch than its native one.
+    #[serde(default)]
+    pub cross: bool,
+    /// How far the upload is, as of the last heartbeat, if uploading.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload: Option<UploadProgress>,
+}
+
+/// What a worker polling for jobs may claim of an arch, see
+/// [`Db::claim`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Preference {
+    /// The arch is not native to the worker: no `--native-only` builds.
+    pub cross: bool,
+    /// A worker weighing more is around: only builds queued this many
+    /// seconds ago.
+    pub hold_back: Option<u64>,
+}
+
+/// What a worker shipped, as reported in `/done`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Manifest {
+    pub artifacts: Vec<Artifact>,
+    pub total_bytes: u64,
+    pub upload_secs: f64,
+    #[serde(default)]
+    pub remote_dir: Option<String>,
+    /// Uploaded to a location the public cannot download from.
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub channel: Channel,
+    /// The note of the build, as the worker got it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The earlier build whose livekit cache the images were built on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incremental_from: Option<i64>,
+    /// The flavor of the release, as the worker got it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Artifact {
+    pub path: String,
+    pub size: u64,
+    pub variant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<ChecksumSource>,
+    /// From the checksum file next to it, workers predating `/diff` do not
+    /// tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The earlier build that uploaded it, kept by a resumed release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumSource {
+    Script,
+    Worker,
+}
+
+/// A finished build, kept for statistics.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub requester_chat: Option<i64>,
+    pub arch: String,
+    #[serde(default = "mainline")]
+    pub pool: String,
+    pub build_type: String,
+    pub variants: Option<Vec<String>>,
+    pub success: bool,
+    pub push_success: bool,
+    pub log_url: Option<String>,
+    pub manifest: Option<Manifest>,
+    pub finished_at: u64,
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+    /// Outcome of the post-build hooks fired for this build.
+    #[serde(default)]
+    pub hooks: Vec<HookResult>,
+    #[serde(default)]
+    pub missing_variants: Vec<String>,
+    #[serde(default)]
+    pub channel: Channel,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Seconds the worker spent per phase, e.g. `build` or `upload`.
+    #[serde(default)]
+    pub phase_durations: BTreeMap<String, u64>,
+    /// Why it failed, if the worker recognized it.
+    #[serde(default)]
+    pub failure: Option<Failure>,
+    #[serde(default)]
+    pub worker: Option<String>,
+    /// Artifacts the worker failed to upload and kept for `/repush`, paths
+    /// on the worker.
+    #[serde(default)]
+    pub failed_artifacts: Vec<String>,
+    /// How uploading each artifact went, from workers that upload them one
+    /// by one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifact_pushes: Vec<ArtifactPush>,
+    /// The backends the completion notice was queued for, `telegram` and
+    /// `irc`, see [`crate::notify`]. Queued, not delivered: a Telegram
+    /// notice the outbox gives up on is with the failed notifications, an
+    /// IRC one only in the log.
+    #[serde(default, alias = "notified", skip_serializing_if = "Vec::is_empty")]
+    pub queued: Vec<String>,
+    /// What the build was requested with, see [`Build::env`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// The commit of the build scripts, aosc-mklive for a livekit and
+    /// aoscbootstrap for a release or rootfs. Older livekits lack it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scripts_commit: Option<String>,
+    /// Variants whose artifacts are uploaded, including the ones a resumed
+    /// release kept.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub built_variants: Vec<String>,
+    /// The failed build this one resumed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resumed_from: Option<i64>,
+    /// Built from scripts further behind upstream than the worker allows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stale_scripts: Option<Staleness>,
+    /// From queued to the result, see [`Lifecycle`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transitions: Vec<Transition>,
+    /// Built by a worker for which the arch is not native.
+    #[serde(default)]
+    pub cross: bool,
+    /// Why it was built, see `--note`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// See `--flavor`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<String>,
+    /// Only the outcome is left, see [`HistoryEntry::summarize`].
+    #[serde(default)]
+    pub pruned: bool,
+}
+
+/// How much of the history is kept, see [`crate::retention`].
+#[derive(Debug, Default)]
+pub struct HistorySize {
+    pub full: usize,
+    pub summarized: usize,
+    /// Of the entries as JSON.
+    pub bytes: usize,
+}
+
+/// A build in the index of the builds of its requester, for `/mybuilds`.
+/// Written when the build is queued, claimed and done.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Requested {
+    pub id: i64,
+    pub arch: String,
+    pub pool: String,
+    /// The build type, with the variants that shipped once finished.
+    pub build: String,
+    pub state: RequestedState,
+    /// When it got into `state`.
+    pub at: u64,
+    /// Where to read the log, once finished.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestedState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl Requested {
+    fn of(build: &Build, state: RequestedState, at: u64) -> Self {
+        Self {
+            id: build.id,
+            arch: build.arch.clone(),
+            pool: build.pool.clone(),
+            build: build.build_type.to_string(),
+            state,
+            at,
+            log: None,
+        }
+    }
+
+    /// `entry`, finished, with where to read its log.
+    pub fn finished(entry: &HistoryEntry, log: Option<String>) -> Self {
+        Self {
+            id: entry.id,
+            arch: entry.arch.clone(),
+            pool: entry.pool.clone(),
+            build: entry.describe(),
+            state: if entry.success && entry.push_success {
+                RequestedState::Succeeded
+            } else {
+                RequestedState::Failed
+            },
+            at: entry.finished_at,
+            log,
+        }
+    }
+}
+
+/// Bounded by [`crate::retention`].
+const HISTORY_KEY: &str = "shipit-history";
+/// Finished builds kept in the index of each requester.
+const REQUESTED_LEN: usize = 10;
+const HOOKS_KEY: &str = "shipit-hooks";
+const AUDIT_KEY: &str = "shipit-audit";
+const AUDIT_LEN: isize = 1000;
+const OUTBOX_KEY: &str = "shipit-outbox";
+const OUTBOX_FAILED_KEY: &str = "shipit-outbox-failed";
+const OUTBOX_FAILED_LEN: isize = 100;
+const DISABLED_KEY: &str = "shipit-disabled";
+const CHATS_KEY: &str = "shipit-chats";
+const WINDOWS_KEY: &str = "shipit-windows";
+const IRC_KEY: &str = "shipit-irc";
+const WORKERS_KEY: &str = "shipit-workers";
+/// Chats that asked for `/watch` notices.
+const WATCHERS_KEY: &str = "shipit-watchers";
+/// The queue caps set with `/limits`.
+const LIMITS_KEY: &str = "shipit-limits";
+/// Requests refused for a full queue, by cap.
+const QUEUE_FULL_KEY: &str = "shipit-queue-full";
+/// When each arch last built and pushed each type, by `arch:type`.
+const LAST_SUCCESS_KEY: &str = "shipit-last-success";
+/// The chats set up with `/setup`, by `<role>:<chat>`.
+const SETUP_KEY: &str = "shipit-setup";
+/// The drains set with `/drain`, by `arch:<arch>` or `worker:<name>`.
+const DRAIN_KEY: &str = "shipit-drain";
+/// The messages with cancel buttons, by the build each button cancels.
+const BUTTONS_KEY: &str = "shipit-buttons";
+/// Builds that left the queue, whose cancel buttons are to be taken off.
+const STALE_BUTTONS_KEY: &str = "shipit-stale-buttons";
+/// When the last daily digest was sent.
+const DIGEST_KEY: &str = "shipit-digest";
+/// The secrets of the pools rotated with `/rotate-secret`, by pool.
+const SECRETS_KEY: &str = "shipit-secrets";
+/// Builds failing in a row, by `arch:type:channel`.
+const STREAKS_KEY: &str = "shipit-failure-streaks";
+/// The stream of build events, see [`crate::events`].
+const EVENTS_KEY: &str = "shipit:events";
+/// Milliseconds a read of the event stream waits for new events.
+const EVENTS_BLOCK: usize = 5000;
+/// The progress posts of builds that never finish are forgotten after this
+/// many seconds.
+const PROGRESS_POSTS_TTL: u64 = 7 * 24 * 3600;
+/// IRC notices kept while the connection is down, the oldest are dropped.
+const IRC_LEN: isize = 100;
+/// Identical notifications to a chat within this many seconds are dropped.
+const SENT_TTL: u64 = 600;
+/// A worker reports its skew on every poll, so a fixed clock is forgotten
+/// soon.
+const CLOCK_SKEW_TTL: u64 = 120;
+/// Like [`CLOCK_SKEW_TTL`], for an unreachable upload host.
+const UNREACHABLE_TTL: u64 = 120;
+/// Like [`CLOCK_SKEW_TTL`], for a worker low on disk.
+const LOW_DISK_TTL: u64 = 120;
+/// The lifecycle of a finished build is kept this long, to refuse late
+/// reports about it.
+const LIFECYCLE_TTL: u64 = 7 * 24 * 3600;
+/// How long a ping waits for a worker of its arch to poll. Answered pings
+/// are accepted for as long again.
+pub const PING_TIMEOUT: u64 = 30;
+/// [`PING_TIMEOUT`] of a ping asking for the checks of `worker check`,
+/// which take a while.
+pub const CHECK_PING_TIMEOUT: u64 = 120;
+
+/// Builds, workers and history written before pools are mainline.
+fn mainline() -> String {
+    MAINLINE.to_string()
+}
+
+/// Mainline keeps the key it had before pools.
+fn queue_key(pool: &str, arch: &str) -> String {
+    if pool == MAINLINE {
+        format!("shipit:queue:{arch}")
+    } else {
+        format!("shipit:queue:{pool}:{arch}")
+    }
+}
+
+fn sent_key(chat: i64, text: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+
+    format!("shipit-sent:{chat}:{:x}", hasher.finish())
+}
+
+/// One key per running build, so a second worker on the same arch never sees
+/// the job of the first.
+fn running_key(arch: &str, id: i64) -> String {
+    format!("shipit:running:{arch}:{id}")
+}
+
+fn lifecycle_key(id: i64) -> String {
+    format!("shipit-lifecycle:{id}")
+}
+
+/// `actor` of chat `new` if it is chat `old`, which became `new`.
+fn migrate_actor(actor: String, old: i64, new: i64) -> String {
+    if actor == old.to_string() {
+        new.to_string()
+    } else {
+        actor
+    }
+}
+
+/// The builds of a requester by id, see [`Requested`].
+fn requested_key(actor: &str) -> String {
+    format!("shipit-requested:{actor}")
+}
+
+/// A state change worth knowing about later: who did what.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub time: u64,
+    pub actor: String,
+    pub action: String,
+}
+
+/// Why an arch takes no new builds, see `/disable`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Disabled {
+    pub reason: Option<String>,
+    pub by: String,
+    pub at: u64,
 }
 
+/// A worker, or the workers of an arch, finishing their build and taking no
+/// more, see `/drain`.
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum BuildType {
-    Livekit,
-    Release(Vec<String>),
+pub struct Drain {
+    pub by: String,
+    pub at: u64,
+    /// The workers told by the reply to a heartbeat, and when they said so
+    /// in the next.
+    #[serde(default)]
+    pub acked: BTreeMap<String, u64>,
 }
 
-impl Display for BuildType {
+impl Display for Disabled {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            BuildType::Livekit => write!(f, "livekit"),
-            BuildType::Release(v) => write!(f, "release variant: {}", v.join(" ")),
+        match self.reason {
+            Some(ref r) => write!(f, "disabled: {r}"),
+            None => write!(f, "disabled"),
+        }
+    }
+}
+
+/// What is known about a chat notifications go to, see `/chats`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ChatRecord {
+    pub id: i64,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// The supergroup this group was upgraded to.
+    #[serde(default)]
+    pub migrated_to: Option<i64>,
+    #[serde(default)]
+    pub delivered_at: Option<u64>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Not sent to until the chat talks to the bot again, after a
+    /// notification could not be delivered or the bot was removed.
+    #[serde(default)]
+    pub undeliverable: bool,
+    /// See `/lang`.
+    #[serde(default)]
+    pub lang: Lang,
+    /// See `/setpool`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    /// See `/setup progress`.
+    #[serde(default)]
+    pub progress: progress::Mode,
+}
+
+/// A worker whose upload host did not answer its preflight check.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Unreachable {
+    pub arch: String,
+    pub worker: String,
+    pub error: String,
+    /// The worker takes no jobs until the upload host answers.
+    pub blocked: bool,
+}
+
+/// A worker not handed jobs for `arch` while its free disk is below the
+/// `shipit_min_free_disk` of the arch.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LowDisk {
+    pub arch: String,
+    pub worker: String,
+    /// Bytes free as last reported.
+    pub free: u64,
+    /// Bytes needed.
+    pub min: u64,
+}
+
+/// A worker as it described itself when it last polled for jobs, see
+/// `/workers`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerRecord {
+    pub name: String,
+    /// The one whose secret it polls with.
+    #[serde(default = "mainline")]
+    pub pool: String,
+    /// The native arch, the first of `arches`.
+    pub arch: String,
+    /// Every arch it takes jobs for.
+    #[serde(default)]
+    pub arches: Vec<String>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Last poll or heartbeat.
+    pub last_seen: u64,
+    #[serde(default)]
+    pub load_avg: Option<[f64; 3]>,
+    #[serde(default)]
+    pub free_disk: Option<u64>,
+    /// Seconds the clock of the worker is ahead, negative if behind.
+    #[serde(default)]
+    pub clock_skew: Option<i64>,
+    /// The weight of each of `arches`, see [`WorkerRecord::weight`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weights: Vec<u32>,
+    /// Why it took no job at the last poll, e.g. `load 9.3 > 6.0`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deferring: Option<String>,
+}
+
+/// The weight of the native arch of a worker not telling, see
+/// [`WorkerRecord::weight`].
+pub const NATIVE_WEIGHT: u32 = 100;
+/// The weight of the other arches of a worker not telling.
+pub const CROSS_WEIGHT: u32 = 10;
+
+impl WorkerRecord {
+    /// `arches`, or only `arch` for older workers.
+    pub fn arch_names(&self) -> Vec<String> {
+        if self.arches.is_empty() {
+            vec![self.arch.clone()]
+        } else {
+            self.arches.clone()
+        }
+    }
+
+    /// How much it wants builds of `arch`, `None` if it takes none. Workers
+    /// not telling weigh their native arch [`NATIVE_WEIGHT`] and the others
+    /// [`CROSS_WEIGHT`].
+    pub fn weight(&self, arch: &str) -> Option<u32> {
+        let i = self.arch_names().iter().position(|x| x == arch)?;
+
+        Some(match self.weights.get(i) {
+            Some(w) => *w,
+            None if i == 0 => NATIVE_WEIGHT,
+            None => CROSS_WEIGHT,
+        })
+    }
+
+    /// `amd64,riscv64`. Records written before workers could take several
+    /// arches only have `arch`.
+    pub fn arch_list(&self) -> String {
+        if self.arches.is_empty() {
+            self.arch.clone()
+        } else {
+            self.arches.join(",")
+        }
+    }
+}
+
+/// A worker refusing jobs because its clock is off.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClockSkew {
+    pub arch: String,
+    pub worker: String,
+    /// Positive if the worker is ahead.
+    pub skew_secs: i64,
+}
+
+/// A `/ping` waiting for a worker to answer with `POST /pong`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Ping {
+    pub id: i64,
+    pub arch: String,
+    pub chat: i64,
+    pub sent_at_ms: u64,
+    /// The worker runs its checks before answering, see `/ping --check`.
+    #[serde(default)]
+    pub check: bool,
+}
+
+impl Ping {
+    /// Seconds it waits for a worker.
+    pub fn timeout(&self) -> u64 {
+        if self.check {
+            CHECK_PING_TIMEOUT
+        } else {
+            PING_TIMEOUT
+        }
+    }
+}
+
+/// The whole server state, for moving it to another Redis instance. See
+/// `GET /export` and `POST /import`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Dump {
+    pub exported_at: u64,
+    /// Last allocated build id.
+    pub build_id: i64,
+    /// By arch, `<pool>:<arch>` outside mainline.
+    pub queues: BTreeMap<String, Vec<Build>>,
+    pub running: Vec<RunningBuild>,
+    /// Newest first.
+    pub history: Vec<HistoryEntry>,
+    pub disabled: BTreeMap<String, Disabled>,
+    #[serde(default)]
+    pub windows: BTreeMap<String, Window>,
+    pub hooks: Vec<Hook>,
+    /// Newest first.
+    pub audit: Vec<AuditEntry>,
+    /// When the scheduled digest was last sent, so that the new server does
+    /// not send it again.
+    #[serde(default)]
+    pub digest_sent: Option<u64>,
+    /// Rotated secrets by pool, see `/rotate`.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, Secrets>,
+    /// By build id, those of finished builds until they expire.
+    #[serde(default)]
+    pub lifecycles: BTreeMap<i64, Lifecycle>,
+    /// Language, pool (`/setpool`) and progress settings of the chats.
+    #[serde(default)]
+    pub chats: Vec<ChatRecord>,
+    #[serde(default)]
+    pub watchers: Vec<i64>,
+    /// Chats set up with `/setup`.
+    #[serde(default)]
+    pub registrations: Vec<Registration>,
+    /// Set with `/limits`, the defaults if `None`.
+    #[serde(default)]
+    pub limits: Option<Limits>,
+    /// By worker or arch, see `/drain`.
+    #[serde(default)]
+    pub drains: BTreeMap<String, Drain>,
+    /// By `<arch>:<build type>:<channel>`.
+    #[serde(default)]
+    pub streaks: BTreeMap<String, Streak>,
+    /// The builds of each requester, see `/mine`.
+    #[serde(default)]
+    pub requested: BTreeMap<String, Vec<Requested>>,
+    /// UNIX seconds, by `<arch>:<build type>`.
+    #[serde(default)]
+    pub last_success: BTreeMap<String, u64>,
+}
+
+impl Dump {
+    /// Whether there is nothing an import would overwrite.
+    pub fn is_empty(&self) -> bool {
+        self.queues.values().all(|x| x.is_empty())
+            && self.running.is_empty()
+            && self.history.is_empty()
+            && self.disabled.is_empty()
+            && self.hooks.is_empty()
+            && self.secrets.is_empty()
+            && self.registrations.is_empty()
+            && self.limits.is_none()
+            && self.drains.is_empty()
+            && self.streaks.is_empty()
+    }
+
+    /// One line description, e.g. for the audit log.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} queued, {} running, {} history entries, {} disabled arches, {} hooks, \
+             {} chats, {} registrations, {} drains, {} failure streaks",
+            self.queues.values().map(|x| x.len()).sum::<usize>(),
+            self.running.len(),
+            self.history.len(),
+            self.disabled.len(),
+            self.hooks.len(),
+            self.chats.len(),
+            self.registrations.len(),
+            self.drains.len(),
+            self.streaks.len()
+        )
+    }
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// Milliseconds since the unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Manifest {
+    /// Add what `resume` keeps of `prior`, the manifest of the build it
+    /// resumes, to `manifest`, each artifact labeled with the build that
+    /// uploaded it.
+    pub fn merge_resumed(
+        manifest: Option<Manifest>,
+        prior: Option<&Manifest>,
+        resume: &Resume,
+    ) -> Option<Manifest> {
+        let Some(prior) = prior else {
+            return manifest;
+        };
+        let kept = prior
+            .artifacts
+            .iter()
+            .filter(|a| a.variant.as_ref().is_some_and(|v| resume.done.contains(v)))
+            .map(|a| Artifact {
+                build: a.build.or(Some(resume.from)),
+                ..a.clone()
+            })
+            .collect::<Vec<_>>();
+        if kept.is_empty() {
+            return manifest;
+        }
+
+        // nothing uploaded this time
+        let mut m = manifest.unwrap_or_else(|| Manifest {
+            artifacts: vec![],
+            total_bytes: 0,
+            upload_secs: 0.0,
+            ..prior.clone()
+        });
+        m.total_bytes += kept.iter().map(|a| a.size).sum::<u64>();
+        m.artifacts.extend(kept);
+
+        Some(m)
+    }
+
+    /// Bytes uploaded by this build, without the artifacts kept from an
+    /// earlier one.
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.total_bytes
+            - self
+                .artifacts
+                .iter()
+                .filter(|a| a.build.is_some())
+                .map(|a| a.size)
+                .sum::<u64>()
+    }
+}
+
+impl Build {
+    /// Who requested it, by chat for builds queued before this was recorded.
+    pub fn actor(&self) -> String {
+        self.requester
+            .clone()
+            .unwrap_or_else(|| self.requester_chat.to_string())
+    }
+}
+
+impl HistoryEntry {
+    /// The build type to request again for `/retry`. Builds from before
+    /// the default set had to be asked for by name and listing no variants
+    /// built it, so they ask for it.
+    pub fn build_type(&self) -> Option<BuildType> {
+        let variants = || match self.variants {
+            Some(ref v) if !v.is_empty() => v.clone(),
+            _ => vec![DEFAULT_SET.to_string()],
+        };
+
+        match self.build_type.as_str() {
+            "livekit" => Some(BuildType::Livekit),
+            "release" => Some(BuildType::Release(variants())),
+            "rootfs" => Some(BuildType::Rootfs(variants())),
+            _ => None,
+        }
+    }
+
+    /// What was built, see [`BuildType::describe`], followed by the
+    /// flavor. Types this server no longer knows are shown by name.
+    pub fn describe(&self) -> String {
+        let s = match self.build_type() {
+            Some(t) => t.describe(&self.missing_variants),
+            None => self.build_type.clone(),
+        };
+
+        match self.flavor {
+            Some(ref flavor) => format!("{s} {flavor}"),
+            None => s,
         }
     }
+
+    /// Drop all but the outcome, the duration, the log and the scripts
+    /// commit, and what the build is shown as.
+    pub fn summarize(&mut self) {
+        self.manifest = None;
+        self.hooks.clear();
+        self.steps.clear();
+        self.phase_durations.clear();
+        self.failure = None;
+        self.failed_artifacts.clear();
+        self.artifact_pushes.clear();
+        self.queued.clear();
+        self.env.clear();
+        self.args.clear();
+        self.stale_scripts = None;
+        self.transitions.clear();
+        self.pruned = true;
+    }
+
+    /// Where the time went, see [`Intervals::of`].
+    pub fn intervals(&self) -> Intervals {
+        Intervals::of(&self.transitions)
+    }
 }
 
 impl Db {
-    pub async fn new(redis: &str) -> eyre::Result<Self> {
+    pub async fn new(redis: &str, events_len: usize) -> eyre::Result<Self> {
         let client = redis::Client::open(redis)?;
         let conn = client.get_multiplexed_tokio_connection().await?;
 
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            events_len,
+            events_dropped: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Events that could not be published since the start.
+    pub fn events_dropped(&self) -> u64 {
+        self.events_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Move keys of the old schema, where `shipit:{arch}` held the running
+    /// build and `shipit-queue:{arch}` the queue, to their current names.
+    pub async fn migrate_keys(&mut self, archs: &[String]) -> eyre::Result<()> {
+        for arch in archs {
+            let old = format!("shipit:{arch}");
+            if let Some(s) = self.conn.get::<_, Option<String>>(&old).await? {
+                let (running, _) = schema::decode::<RunningBuild>(&s)?;
+                self.conn
+                    .set::<_, _, ()>(
+                        running_key(arch, running.build.id),
+                        schema::encode(&running)?,
+                    )
+                    .await?;
+                self.conn.del::<_, ()>(&old).await?;
+            }
+
+            let old = format!("shipit-queue:{arch}");
+            if self.conn.exists(&old).await? {
+                let key = queue_key(MAINLINE, arch);
+                let renamed: bool = self.conn.rename_nx(&old, &key).await?;
+                if !renamed {
+                    warn!("Both {old} and {key} exist, leaving {old} alone");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store every queued and running build in the current schema, instead
+    /// of when next read. Returns how many were of an older one.
+    pub async fn migrate_builds(&mut self) -> eyre::Result<usize> {
+        let mut upgraded = 0;
+
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit:queue:*")
+            .query_async(&mut self.conn)
+            .await?;
+        for key in keys {
+            let s: Vec<String> = self.conn.lrange(&key, 0, -1).await?;
+            for raw in s {
+                let (_, old) =
+                    schema::decode::<Build>(&raw).map_err(|e| eyre::eyre!("{key}: {e}"))?;
+                if old {
+                    self.queued(&key, raw).await?;
+                    upgraded += 1;
+                }
+            }
+        }
+
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit:running:*")
+            .query_async(&mut self.conn)
+            .await?;
+        for key in keys {
+            let Some(raw) = self.conn.get::<_, Option<String>>(&key).await? else {
+                continue;
+            };
+            let (_, old) =
+                schema::decode::<RunningBuild>(&raw).map_err(|e| eyre::eyre!("{key}: {e}"))?;
+            if old {
+                self.running_record(&key, &raw).await?;
+                upgraded += 1;
+            }
+        }
+
+        Ok(upgraded)
+    }
+
+    /// Builds of `arch` currently claimed by a worker, oldest first.
+    pub async fn running(&mut self, arch: &str) -> eyre::Result<Vec<RunningBuild>> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg(format!("shipit:running:{arch}:*"))
+            .query_async(&mut self.conn)
+            .await?;
+
+        self.running_at(&keys).await
+    }
+
+    /// The running builds stored at `keys`, sorted by id, in one round trip.
+    async fn running_at(&mut self, keys: &[String]) -> eyre::Result<Vec<RunningBuild>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let s: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(keys)
+            .query_async(&mut self.conn)
+            .await?;
+
+        let mut v: Vec<RunningBuild> = vec![];
+        for (key, raw) in keys.iter().zip(s) {
+            if let Some(raw) = raw {
+                v.push(self.running_record(key, &raw).await?);
+            }
+        }
+        v.sort_by_key(|r| r.build.id);
+
+        Ok(v)
+    }
+
+    /// The running builds and the queue of each of `archs` in `pool`, in
+    /// two round trips however many arches there are.
+    pub async fn arch_states(
+        &mut self,
+        pool: &str,
+        archs: &[&str],
+    ) -> eyre::Result<Vec<(Vec<RunningBuild>, Vec<Build>)>> {
+        let mut pipe = redis::pipe();
+        pipe.cmd("KEYS").arg("shipit:running:*");
+        for arch in archs {
+            pipe.lrange(queue_key(pool, arch), 0, -1);
+        }
+        let mut res: Vec<Vec<String>> = pipe.query_async(&mut self.conn).await?;
+
+        let keys = res.remove(0);
+        let running = self.running_at(&keys).await?;
+
+        let mut v = vec![];
+        for (arch, s) in archs.iter().zip(res) {
+            let key = queue_key(pool, arch);
+            let mut queue = vec![];
+            for i in s {
+                queue.push(self.queued(&key, i).await?.0);
+            }
+            let running = running
+                .iter()
+                .filter(|r| r.build.arch == *arch && r.build.pool == pool)
+                .cloned()
+                .collect();
+            v.push((running, queue));
+        }
+
+        Ok(v)
+    }
+
+    /// The queue of each of `queues`, by pool and arch, in one round trip.
+    pub async fn queues(&mut self, queues: &[(&str, &str)]) -> eyre::Result<Vec<Vec<Build>>> {
+        let mut pipe = redis::pipe();
+        for (pool, arch) in queues {
+            pipe.lrange(queue_key(pool, arch), 0, -1);
+        }
+        let res: Vec<Vec<String>> = pipe.query_async(&mut self.conn).await?;
+
+        let mut v = vec![];
+        for ((pool, arch), s) in queues.iter().zip(res) {
+            let key = queue_key(pool, arch);
+            let mut queue = vec![];
+            for i in s {
+                queue.push(self.queued(&key, i).await?.0);
+            }
+            v.push(queue);
+        }
+
+        Ok(v)
+    }
+
+    pub async fn get_running(&mut self, arch: &str, id: i64) -> eyre::Result<Option<RunningBuild>> {
+        let key = running_key(arch, id);
+        let s: Option<String> = self.conn.get(&key).await?;
+
+        Ok(match s {
+            Some(s) => Some(self.running_record(&key, &s).await?),
+            None => None,
+        })
+    }
+
+    /// Parse the running build `raw` stored at `key`, and store it in the
+    /// current schema if it is of an older one and unchanged since.
+    async fn running_record(&mut self, key: &str, raw: &str) -> eyre::Result<RunningBuild> {
+        let (running, old) = schema::decode(raw)?;
+        if old {
+            redis::Script::new(
+                r"
+                if redis.call('GET', KEYS[1]) == ARGV[1] then
+                    redis.call('SET', KEYS[1], ARGV[2])
+                end
+                ",
+            )
+            .key(key)
+            .arg(raw)
+            .arg(schema::encode(&running)?)
+            .invoke_async::<_, ()>(&mut self.conn)
+            .await?;
+        }
+
+        Ok(running)
+    }
+
+    /// Parse the queued build `raw` of the queue at `key`. If it is of an
+    /// older schema, it is replaced in the queue by the current form, if
+    /// still there. Returns the build and its raw form as now stored.
+    async fn queued(&mut self, key: &str, raw: String) -> eyre::Result<(Build, String)> {
+        let (build, old) = schema::decode(&raw)?;
+        if !old {
+            return Ok((build, raw));
+        }
+
+        let new = schema::encode(&build)?;
+        self.replace_in_list(key, &raw, &new).await?;
+
+        Ok((build, new))
     }
 
-    pub async fn get(&mut self, arch: &str) -> eyre::Result<Build> {
-        let s: String = self.conn.get::<_, _>(format!("shipit:{arch}")).await?;
+    /// Replace `old` in the list at `key` with `new`, found by its value
+    /// rather than its index, which a push in between would shift. Returns
+    /// whether `old` was still there.
+    async fn replace_in_list(&mut self, key: &str, old: &str, new: &str) -> eyre::Result<bool> {
+        let replaced: usize = redis::Script::new(
+            r"
+            for i, v in ipairs(redis.call('LRANGE', KEYS[1], 0, -1)) do
+                if v == ARGV[1] then
+                    redis.call('LSET', KEYS[1], i - 1, ARGV[2])
+                    return 1
+                end
+            end
+            return 0
+            ",
+        )
+        .key(key)
+        .arg(old)
+        .arg(new)
+        .invoke_async(&mut self.conn)
+        .await?;
 
-        Ok(serde_json::from_str(&s)?)
+        Ok(replaced == 1)
     }
 
-    pub async fn set_building(&mut self, arch: &str, build: &Build) -> eyre::Result<()> {
+    /// Record a heartbeat, and the stage if given, of a running build, and
+    /// the upload progress it came with. Returns the updated record, or
+    /// `None` if the build is not running.
+    pub async fn touch_running(
+        &mut self,
+        arch: &str,
+        id: i64,
+        progress: Option<&str>,
+        steps: Vec<Step>,
+        upload: Option<UploadProgress>,
+    ) -> eyre::Result<Option<RunningBuild>> {
+        let Some(mut running) = self.get_running(arch, id).await? else {
+            return Ok(None);
+        };
+
+        // the first report of the worker, or one thought lost, and the
+        // build script exiting
+        if let Some(lifecycle) = self.lifecycle(id).await? {
+            let published = lifecycle
+                .transitions
+                .iter()
+                .any(|x| x.phase == Phase::Publishing);
+            let to = if progress == Some(STAGE_PUBLISHING) || published {
+                Phase::Publishing
+            } else {
+                Phase::Running
+            };
+            if lifecycle.phase == Phase::Claimed && to == Phase::Publishing {
+                self.transition(id, Phase::Running).await?;
+            }
+            if lifecycle.phase != to {
+                self.transition(id, to).await?;
+            }
+        }
+
+        running.heartbeat_at = Some(now());
+        if let Some(p) = progress {
+            running.progress = Some(p.to_string());
+        }
+        if !steps.is_empty() {
+            running.steps = steps;
+        }
+        running.upload = upload;
+
         self.conn
-            .set(format!("shipit:{arch}"), serde_json::to_string(build)?)
+            .set::<_, _, ()>(running_key(arch, id), schema::encode(&running)?)
             .await?;
 
-        Ok(())
+        Ok(Some(running))
     }
 
-    pub async fn set_build_done(&mut self, arch: &str) -> eyre::Result<()> {
-        self.conn.del(format!("shipit:{arch}")).await?;
+    /// Remove the `n`th (1-based) queued build of `arch` in `pool`. Returns
+    /// `None` if there is no such build, or a worker claimed it in the
+    /// meantime.
+    pub async fn drop_queued_at(
+        &mut self,
+        pool: &str,
+        arch: &str,
+        n: usize,
+    ) -> eyre::Result<Option<Build>> {
+        let key = queue_key(pool, arch);
+        let Some(raw) = self.queued_raw_at(&key, n).await? else {
+            return Ok(None);
+        };
+        let (build, raw) = self.queued(&key, raw).await?;
 
-        Ok(())
+        let removed: usize = self.conn.lrem(&key, 1, &raw).await?;
+        if removed == 0 {
+            return Ok(None);
+        }
+
+        self.cancelled(&build, Kind::Cancelled).await?;
+
+        Ok(Some(build))
+    }
+
+    /// Move the `n`th (1-based) queued build of `arch` in `pool` to the
+    /// front.
+    pub async fn move_queued_to_top(
+        &mut self,
+        pool: &str,
+        arch: &str,
+        n: usize,
+    ) -> eyre::Result<Option<Build>> {
+        let key = queue_key(pool, arch);
+        let Some(raw) = self.queued_raw_at(&key, n).await? else {
+            return Ok(None);
+        };
+        let (build, raw) = self.queued(&key, raw).await?;
+
+        // only push it back if it was still queued
+        let moved: usize = redis::Script::new(
+            r"
+            local n = redis.call('LREM', KEYS[1], 1, ARGV[1])
+            if n > 0 then
+                redis.call('LPUSH', KEYS[1], ARGV[1])
+            end
+            return n
+            ",
+        )
+        .key(&key)
+        .arg(&raw)
+        .invoke_async(&mut self.conn)
+        .await?;
+
+        Ok((moved > 0).then_some(build))
     }
 
-    pub async fn running_worker(&mut self) -> eyre::Result<Vec<Build>> {
-        let s: Vec<String> = redis::cmd("KEYS")
-            .arg("shipit:*".to_string())
+    /// Empty the queue of `arch` in `pool`, returning what was in it.
+    pub async fn clear_queue(&mut self, pool: &str, arch: &str) -> eyre::Result<Vec<Build>> {
+        let key = queue_key(pool, arch);
+        let (s, ()): (Vec<String>, ()) = redis::pipe()
+            .atomic()
+            .lrange(&key, 0, -1)
+            .del(&key)
             .query_async(&mut self.conn)
             .await?;
 
         let mut v = vec![];
         for i in s {
-            let entry = self.conn.get::<_, String>(i).await?;
-            v.push(serde_json::from_str(&entry)?);
+            let (build, _) = schema::decode::<Build>(&i)?;
+            self.cancelled(&build, Kind::Cancelled).await?;
+            v.push(build);
+        }
+
+        Ok(v)
+    }
+
+    async fn queued_raw_at(&mut self, key: &str, n: usize) -> eyre::Result<Option<String>> {
+        if n == 0 {
+            return Ok(None);
+        }
+
+        Ok(self.conn.lindex(key, n as isize - 1).await?)
+    }
+
+    /// Remove the queued builds of `arch` in `pool` waiting for build `id`.
+    pub async fn remove_dependents(
+        &mut self,
+        pool: &str,
+        arch: &str,
+        id: i64,
+    ) -> eyre::Result<Vec<Build>> {
+        let key = queue_key(pool, arch);
+        let s: Vec<String> = self.conn.lrange(&key, 0, -1).await?;
+        let mut removed = vec![];
+
+        for raw in s {
+            let (build, raw) = self.queued(&key, raw).await?;
+            if build.after == Some(id) {
+                let n: usize = self.conn.lrem(&key, 1, &raw).await?;
+                if n > 0 {
+                    self.cancelled(&build, Kind::Cancelled).await?;
+                    removed.push(build);
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Reserve `n` consecutive build ids, returning the first one.
+    pub async fn next_build_ids(&mut self, n: usize) -> eyre::Result<i64> {
+        let last: i64 = self.conn.incr("shipit-build-id", n).await?;
+
+        Ok(last - n as i64 + 1)
+    }
+
+    /// Remove the queued build `id` from the queue of `arch` in `pool`,
+    /// published as `kind`: cancelled or expired.
+    pub async fn remove_queued(
+        &mut self,
+        pool: &str,
+        arch: &str,
+        id: i64,
+        kind: Kind,
+    ) -> eyre::Result<Option<Build>> {
+        let key = queue_key(pool, arch);
+        let s: Vec<String> = self.conn.lrange(&key, 0, -1).await?;
+
+        for i in s {
+            let (build, i) = self.queued(&key, i).await?;
+            if build.id == id {
+                let n: usize = self.conn.lrem(&key, 1, &i).await?;
+                // claimed by a worker in the meantime
+                if n == 0 {
+                    return Ok(None);
+                }
+
+                self.cancelled(&build, kind).await?;
+                return Ok(Some(build));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Stop the running build `id` on `arch`: it is cancelled and forgotten
+    /// here, its worker is told at its next heartbeat, see
+    /// [`Db::cancelled_running`]. `None` if it is not running or finished
+    /// in the meantime.
+    pub async fn cancel_running(
+        &mut self,
+        arch: &str,
+        id: i64,
+    ) -> eyre::Result<Option<RunningBuild>> {
+        let Some(running) = self.get_running(arch, id).await? else {
+            return Ok(None);
+        };
+        match self.transition(id, Phase::Cancelled).await {
+            Err(e) if e.is::<IllegalTransition>() => {
+                warn!("{e}");
+                return Ok(None);
+            }
+            x => x?,
+        };
+        self.set_build_done(arch, id).await?;
+        self.publish(
+            &Event::new(Kind::Cancelled, &running.build).with_worker(running.worker.as_deref()),
+        )
+        .await;
+
+        Ok(Some(running))
+    }
+
+    /// Whether build `id` was cancelled while running, for its worker to
+    /// stop it.
+    pub async fn cancelled_running(&mut self, id: i64) -> eyre::Result<bool> {
+        Ok(self.lifecycle(id).await?.is_some_and(|l| {
+            l.phase == Phase::Cancelled && l.transitions.iter().any(|x| x.phase == Phase::Claimed)
+        }))
+    }
+
+    /// Append builds to the queues of their arches in one round trip,
+    /// returning their positions (1-based).
+    pub async fn enqueue_all(&mut self, builds: &[&Build]) -> eyre::Result<Vec<usize>> {
+        let now = now();
+        let lifecycle = serde_json::to_string(&Lifecycle::new(now))?;
+        let mut pipe = redis::pipe();
+        for b in builds {
+            pipe.rpush(queue_key(&b.pool, &b.arch), schema::encode(b)?);
+            pipe.set(lifecycle_key(b.id), &lifecycle).ignore();
+            let requested = Requested::of(b, RequestedState::Queued, now);
+            pipe.hset(
+                requested_key(&b.actor()),
+                b.id,
+                serde_json::to_string(&requested)?,
+            )
+            .ignore();
+        }
+
+        Ok(pipe.query_async(&mut self.conn).await?)
+    }
+
+    pub async fn queue(&mut self, pool: &str, arch: &str) -> eyre::Result<Vec<Build>> {
+        self.queue_at(&queue_key(pool, arch)).await
+    }
+
+    async fn queue_at(&mut self, key: &str) -> eyre::Result<Vec<Build>> {
+        let s: Vec<String> = self.conn.lrange(key, 0, -1).await?;
+
+        let mut v = vec![];
+        for i in s {
+            v.push(self.queued(key, i).await?.0);
         }
 
         Ok(v)
     }
+
+    /// Move the next ready queued build of `arch` in `pool` to running on
+    /// `worker`.
+    /// Every call hands out a different build, or `None` if nothing is ready.
+    /// Builds of types not in `types`, if the worker told which it supports,
+    /// are left for other workers. Unless `in_window`, only builds ignoring
+    /// the build window are handed out.
+    pub async fn claim(
+        &mut self,
+        pool: &str,
+        arch: &str,
+        worker: Option<&str>,
+        types: Option<&[&str]>,
+        in_window: bool,
+        preference: Preference,
+    ) -> eyre::Result<Option<Build>> {
+        let key = queue_key(pool, arch);
+        let s: Vec<String> = self.conn.lrange(&key, 0, -1).await?;
+        let now = now();
+
+        // the first job for this worker whose dependency, if any, has
+        // succeeded
+        for raw in s {
+            let (mut build, raw) = self.queued(&key, raw).await?;
+            let ready = (build.worker.is_none() || build.worker.as_deref() == worker)
+                && types.is_none_or(|x| x.contains(&build.build_type.name()))
+                && (in_window || build.ignore_window)
+                && !(preference.cross && build.native_only)
+                // held back for heavier workers, unless meant for this one
+                && (build.worker.is_some()
+                    || preference.hold_back.is_none_or(|h| {
+                        build
+                            .queued_at
+                            .is_none_or(|t| now.saturating_sub(t) >= h)
+                    }));
+
+            if !ready {
+                continue;
+            }
+
+            if let Some(dep) = build.after {
+                if !self.succeeded(dep).await? {
+                    continue;
+                }
+            }
+
+            if let Some(l) = self.lifecycle(build.id).await? {
+                if !l.phase.can_become(Phase::Claimed) {
+                    warn!(
+                        "Build #{} is {} but still queued, leaving it alone",
+                        build.id, l.phase
+                    );
+                    continue;
+                }
+            }
+
+            build.started_at = Some(now);
+            let running = RunningBuild {
+                build: build.clone(),
+                worker: worker.map(|x| x.to_string()),
+                claimed_at: Some(now),
+                heartbeat_at: Some(now),
+                progress: None,
+                steps: vec![],
+                cross: preference.cross,
+                upload: None,
+            };
+
+            // a job removed from the queue in the meantime is not started
+            let moved: usize = redis::Script::new(
+                r"
+                if redis.call('LREM', KEYS[1], 1, ARGV[1]) > 0 then
+                    redis.call('SET', KEYS[2], ARGV[2])
+                    return 1
+                end
+                return 0
+                ",
+            )
+            .key(&key)
+            .key(running_key(arch, build.id))
+            .arg(&raw)
+            .arg(schema::encode(&running)?)
+            .invoke_async(&mut self.conn)
+            .await?;
+            // another worker was faster, try the next one
+            if moved == 0 {
+                continue;
+            }
+
+            self.transition(build.id, Phase::Claimed).await?;
+            self.index_requested(
+                &build.actor(),
+                &Requested::of(&build, RequestedState::Running, now),
+            )
+            .await?;
+            return Ok(Some(build));
+        }
+
+        Ok(None)
+    }
+
+    /// Whether build `id` succeeded, as its lifecycle tells. Only builds
+    /// whose lifecycle expired or predates lifecycles are looked up in the
+    /// history.
+    async fn succeeded(&mut self, id: i64) -> eyre::Result<bool> {
+        if let Some(l) = self.lifecycle(id).await? {
+            return Ok(l.phase == Phase::Succeeded);
+        }
+
+        Ok(self.find_history(id).await?.is_some_and(|h| h.success))
+    }
+
+    pub async fn lifecycle(&mut self, id: i64) -> eyre::Result<Option<Lifecycle>> {
+        let s: Option<String> = self.conn.get(lifecycle_key(id)).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    /// Move build `id` to `to`, failing with [`IllegalTransition`] if it
+    /// cannot go there from where it is. Builds queued before lifecycles
+    /// were recorded have none, theirs starts here.
+    ///
+    /// The lifecycle is only written if it is still the one read, so of two
+    /// concurrent transitions from the same phase, e.g. a worker finishing
+    /// a build being cancelled, the second one fails or starts over from
+    /// what the first wrote.
+    pub async fn transition(&mut self, id: i64, to: Phase) -> eyre::Result<Lifecycle> {
+        let key = lifecycle_key(id);
+        loop {
+            let current: Option<String> = self.conn.get(&key).await?;
+            let step = Transition {
+                phase: to,
+                at: now(),
+            };
+            let lifecycle = match current {
+                Some(ref s) => {
+                    let mut l: Lifecycle = serde_json::from_str(s)?;
+                    if !l.phase.can_become(to) {
+                        return Err(IllegalTransition {
+                            id,
+                            from: l.phase,
+                            to,
+                        }
+                        .into());
+                    }
+                    l.phase = to;
+                    l.transitions.push(step);
+                    l
+                }
+                None => Lifecycle {
+                    phase: to,
+                    transitions: vec![step],
+                },
+            };
+
+            // the cancel buttons of a queued build are stale once it is
+            // claimed or cancelled, those of a running one were taken
+            // already
+            let stale = matches!(to, Phase::Claimed | Phase::Cancelled);
+            let ttl = if to.is_final() { LIFECYCLE_TTL } else { 0 };
+            let written: usize = redis::Script::new(
+                r"
+                local current = redis.call('GET', KEYS[1])
+                if (ARGV[1] == '' and current) or (ARGV[1] ~= '' and current ~= ARGV[1]) then
+                    return 0
+                end
+                if ARGV[3] == '0' then
+                    redis.call('SET', KEYS[1], ARGV[2])
+                else
+                    redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+                end
+                if ARGV[4] == '1' and redis.call('HEXISTS', KEYS[2], ARGV[5]) == 1 then
+                    redis.call('RPUSH', KEYS[3], ARGV[5])
+                end
+                return 1
+                ",
+            )
+            .key(&key)
+            .key(BUTTONS_KEY)
+            .key(STALE_BUTTONS_KEY)
+            .arg(current.as_deref().unwrap_or(""))
+            .arg(serde_json::to_string(&lifecycle)?)
+            .arg(ttl)
+            .arg(stale as u8)
+            .arg(id)
+            .invoke_async(&mut self.conn)
+            .await?;
+            if written == 1 {
+                return Ok(lifecycle);
+            }
+        }
+    }
+
+    /// Record that `build` was taken out of its queue, as `kind` of event.
+    async fn cancelled(&mut self, build: &Build, kind: Kind) -> eyre::Result<()> {
+        match self.transition(build.id, Phase::Cancelled).await {
+            Err(e) if e.is::<IllegalTransition>() => {
+                warn!("{e}");
+                return Ok(());
+            }
+            x => x?,
+        };
+        self.publish(&Event::new(kind, build)).await;
+
+        Ok(())
+    }
+
+    pub async fn set_build_done(&mut self, arch: &str, id: i64) -> eyre::Result<()> {
+        self.conn.del::<_, ()>(running_key(arch, id)).await?;
+
+        Ok(())
+    }
+
+    pub async fn running_worker(&mut self) -> eyre::Result<Vec<RunningBuild>> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit:running:*".to_string())
+            .query_async(&mut self.conn)
+            .await?;
+
+        self.running_at(&keys).await
+    }
+
+    pub async fn push_history(&mut self, entry: &HistoryEntry) -> eyre::Result<()> {
+        self.conn
+            .lpush::<_, _, ()>(HISTORY_KEY, serde_json::to_string(entry)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace each entry of the history, as read by
+    /// [`Db::raw_history`], that is a key of `changes` with its value, and
+    /// drop those mapped to an empty string. Entries pushed or changed
+    /// since they were read are not among the keys and stay as they are.
+    pub async fn rewrite_history(
+        &mut self,
+        changes: &BTreeMap<String, String>,
+    ) -> eyre::Result<()> {
+        let script = redis::Script::new(
+            r"
+            local changes = {}
+            for i = 1, #ARGV, 2 do
+                changes[ARGV[i]] = ARGV[i + 1]
+            end
+            local history = redis.call('LRANGE', KEYS[1], 0, -1)
+            redis.call('DEL', KEYS[1])
+            for _, v in ipairs(history) do
+                local new = changes[v]
+                if new == nil then
+                    redis.call('RPUSH', KEYS[1], v)
+                elseif new ~= '' then
+                    redis.call('RPUSH', KEYS[1], new)
+                end
+            end
+            return 0
+            ",
+        );
+        let mut invocation = script.key(HISTORY_KEY);
+        for (old, new) in changes {
+            invocation.arg(old).arg(new);
+        }
+        invocation.invoke_async::<_, ()>(&mut self.conn).await?;
+
+        Ok(())
+    }
+
+    pub async fn history_size(&mut self) -> eyre::Result<HistorySize> {
+        let s: Vec<String> = self.conn.lrange(HISTORY_KEY, 0, -1).await?;
+
+        let mut size = HistorySize::default();
+        for raw in &s {
+            let entry: HistoryEntry = serde_json::from_str(raw)?;
+            if entry.pruned {
+                size.summarized += 1;
+            } else {
+                size.full += 1;
+            }
+            size.bytes += raw.len();
+        }
+
+        Ok(size)
+    }
+
+    /// Finished builds, newest first.
+    pub async fn history(&mut self) -> eyre::Result<Vec<HistoryEntry>> {
+        let mut v = vec![];
+        for i in self.raw_history().await? {
+            v.push(serde_json::from_str(&i)?);
+        }
+
+        Ok(v)
+    }
+
+    /// The history as stored, newest first.
+    pub async fn raw_history(&mut self) -> eyre::Result<Vec<String>> {
+        Ok(self.conn.lrange(HISTORY_KEY, 0, -1).await?)
+    }
+
+    /// Finished builds of `pool`, newest first.
+    pub async fn history_of(&mut self, pool: &str) -> eyre::Result<Vec<HistoryEntry>> {
+        let mut v = self.history().await?;
+        v.retain(|x| x.pool == pool);
+
+        Ok(v)
+    }
+
+    /// When the login of `chat` was last confirmed by minzhengbu.
+    pub async fn login_verified_at(&mut self, chat: i64) -> eyre::Result<Option<u64>> {
+        Ok(self.conn.get(format!("shipit-login:{chat}")).await?)
+    }
+
+    /// Remember a confirmed login. The entry outlives the cache TTL by
+    /// `grace` seconds so it can be used while minzhengbu is unreachable.
+    pub async fn set_login_verified(&mut self, chat: i64, grace: u64) -> eyre::Result<()> {
+        self.conn
+            .set_ex::<_, _, ()>(format!("shipit-login:{chat}"), now(), grace)
+            .await?;
+
+        Ok(())
+    }
+
+    /// GitHub user name `chat` logged in as, if minzhengbu told us.
+    pub async fn login_user(&mut self, chat: i64) -> eyre::Result<Option<String>> {
+        Ok(self.conn.get(format!("shipit-login-user:{chat}")).await?)
+    }
+
+    pub async fn set_login_user(&mut self, chat: i64, user: &str, ttl: u64) -> eyre::Result<()> {
+        self.conn
+            .set_ex::<_, _, ()>(format!("shipit-login-user:{chat}"), user, ttl)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_login_denied(&mut self, chat: i64) -> eyre::Result<bool> {
+        Ok(self
+            .conn
+            .exists(format!("shipit-login-denied:{chat}"))
+            .await?)
+    }
+
+    pub async fn set_login_denied(&mut self, chat: i64, ttl: u64) -> eyre::Result<()> {
+        self.conn
+            .set_ex::<_, _, ()>(format!("shipit-login-denied:{chat}"), now(), ttl)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_login(&mut self, chat: i64) -> eyre::Result<bool> {
+        let n: usize = self
+            .conn
+            .del(&[
+                format!("shipit-login:{chat}"),
+                format!("shipit-login-denied:{chat}"),
+                format!("shipit-login-user:{chat}"),
+            ])
+            .await?;
+
+        Ok(n > 0)
+    }
+
+    pub async fn push_outbox(&mut self, n: &Notification) -> eyre::Result<()> {
+        self.conn
+            .rpush::<_, _, ()>(OUTBOX_KEY, serde_json::to_string(n)?)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn pop_outbox(&mut self) -> eyre::Result<Option<Notification>> {
+        let s: Option<String> = self.conn.lpop(OUTBOX_KEY, None).await?;
+
+        Ok(s.map(|x| serde_json::from_str(&x)).transpose()?)
+    }
+
+    /// Notifications waiting in the outbox.
+    pub async fn outbox_len(&mut self) -> eyre::Result<usize> {
+        Ok(self.conn.llen(OUTBOX_KEY).await?)
+    }
+
+    pub async fn push_irc(&mut self, text: &str) -> eyre::Result<()> {
+        self.conn.rpush::<_, _, ()>(IRC_KEY, text).await?;
+        self.conn.ltrim::<_, ()>(IRC_KEY, -IRC_LEN, -1).await?;
+
+        Ok(())
+    }
+
+    pub async fn pop_irc(&mut self) -> eyre::Result<Option<String>> {
+        Ok(self.conn.lpop(IRC_KEY, None).await?)
+    }
+
+    /// Put back an IRC notice that could not be sent, to be sent first.
+    pub async fn requeue_irc(&mut self, text: &str) -> eyre::Result<()> {
+        self.conn.lpush::<_, _, ()>(IRC_KEY, text).await?;
+
+        Ok(())
+    }
+
+    /// Keep a notification that could not be delivered, for `/outbox`.
+    pub async fn push_failed_notification(&mut self, n: &Notification) -> eyre::Result<()> {
+        self.conn
+            .lpush::<_, _, ()>(OUTBOX_FAILED_KEY, serde_json::to_string(n)?)
+            .await?;
+        self.conn
+            .ltrim::<_, ()>(OUTBOX_FAILED_KEY, 0, OUTBOX_FAILED_LEN - 1)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Undelivered notifications, newest first.
+    pub async fn failed_notifications(&mut self) -> eyre::Result<Vec<Notification>> {
+        let s: Vec<String> = self.conn.lrange(OUTBOX_FAILED_KEY, 0, -1).await?;
+
+        let mut v = vec![];
+        for i in s {
+            v.push(serde_json::from_str(&i)?);
+        }
+
+        Ok(v)
+    }
+
+    pub async fn clear_failed_notifications(&mut self) -> eyre::Result<()> {
+        self.conn.del::<_, ()>(OUTBOX_FAILED_KEY).await?;
+
+        Ok(())
+    }
+
+    /// The builds `actor` requested that are queued, running or among the
+    /// last finished, newest first.
+    pub async fn requested(&mut self, actor: &str) -> eyre::Result<Vec<Requested>> {
+        let all: BTreeMap<i64, String> = self.conn.hgetall(requested_key(actor)).await?;
+
+        let mut v = vec![];
+        for s in all.values() {
+            v.push(serde_json::from_str::<Requested>(s)?);
+        }
+        v.sort_by_key(|x| std::cmp::Reverse(x.id));
+
+        Ok(v)
+    }
+
+    /// Record where a build of `actor` is, forgetting the oldest finished
+    /// builds past [`REQUESTED_LEN`].
+    pub async fn index_requested(&mut self, actor: &str, r: &Requested) -> eyre::Result<()> {
+        let key = requested_key(actor);
+        self.conn
+            .hset::<_, _, _, ()>(&key, r.id, serde_json::to_string(r)?)
+            .await?;
+        if matches!(r.state, RequestedState::Queued | RequestedState::Running) {
+            return Ok(());
+        }
+
+        let finished = self
+            .requested(actor)
+            .await?
+            .into_iter()
+            .filter(|x| matches!(x.state, RequestedState::Succeeded | RequestedState::Failed))
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        if finished.len() > REQUESTED_LEN {
+            self.conn
+                .hdel::<_, _, ()>(&key, &finished[REQUESTED_LEN..])
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Forget build `id` of `actor`, e.g. after it was cancelled.
+    pub async fn unindex_requested(&mut self, actor: &str, id: i64) -> eyre::Result<()> {
+        self.conn.hdel::<_, _, ()>(requested_key(actor), id).await?;
+
+        Ok(())
+    }
+
+    pub async fn find_history(&mut self, id: i64) -> eyre::Result<Option<HistoryEntry>> {
+        Ok(self.history().await?.into_iter().find(|x| x.id == id))
+    }
+
+    pub async fn audit(&mut self, actor: &str, action: &str) -> eyre::Result<()> {
+        self.audit_all(actor, &[action.to_string()]).await
+    }
+
+    /// Several actions of `actor`, in one round trip.
+    pub async fn audit_all(&mut self, actor: &str, actions: &[String]) -> eyre::Result<()> {
+        let mut pipe = redis::pipe();
+        for action in actions {
+            let entry = AuditEntry {
+                time: now(),
+                actor: actor.to_string(),
+                action: action.to_string(),
+            };
+            pipe.lpush(AUDIT_KEY, serde_json::to_string(&entry)?)
+                .ignore();
+        }
+        pipe.ltrim(AUDIT_KEY, 0, AUDIT_LEN - 1).ignore();
+        pipe.query_async::<_, ()>(&mut self.conn).await?;
+
+        Ok(())
+    }
+
+    /// Modify the history entry of build `id` in place. `f` is run again
+    /// if the entry changed meanwhile.
+    pub async fn update_history(
+        &mut self,
+        id: i64,
+        mut f: impl FnMut(&mut HistoryEntry),
+    ) -> eyre::Result<bool> {
+        loop {
+            let s: Vec<String> = self.conn.lrange(HISTORY_KEY, 0, -1).await?;
+            let mut found = None;
+            for raw in s {
+                let entry: HistoryEntry = serde_json::from_str(&raw)?;
+                if entry.id == id {
+                    found = Some((entry, raw));
+                    break;
+                }
+            }
+            let Some((mut entry, raw)) = found else {
+                return Ok(false);
+            };
+
+            f(&mut entry);
+            let new = serde_json::to_string(&entry)?;
+            if self.replace_in_list(HISTORY_KEY, &raw, &new).await? {
+                return Ok(true);
+            }
+        }
+    }
+
+    pub async fn hooks(&mut self) -> eyre::Result<Vec<Hook>> {
+        let s: Vec<String> = self.conn.lrange(HOOKS_KEY, 0, -1).await?;
+
+        let mut v = vec![];
+        for i in s {
+            v.push(serde_json::from_str(&i)?);
+        }
+
+        Ok(v)
+    }
+
+    pub async fn add_hook(&mut self, hook: &Hook) -> eyre::Result<()> {
+        self.conn
+            .rpush::<_, _, ()>(HOOKS_KEY, serde_json::to_string(hook)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove the `n`th (0-based) hook.
+    pub async fn remove_hook(&mut self, n: usize) -> eyre::Result<Option<Hook>> {
+        let s: Option<String> = self.conn.lindex(HOOKS_KEY, n as isize).await?;
+        let Some(s) = s else {
+            return Ok(None);
+        };
+
+        self.conn.lrem::<_, _, ()>(HOOKS_KEY, 1, &s).await?;
+
+        Ok(Some(serde_json::from_str(&s)?))
+    }
+
+    /// Everything worth keeping when moving to another Redis instance.
+    /// Logins and notifications are not included, they are recreated.
+    pub async fn export(&mut self) -> eyre::Result<Dump> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit:queue:*")
+            .query_async(&mut self.conn)
+            .await?;
+
+        let mut queues = BTreeMap::new();
+        for key in keys {
+            let queue = self.queue_at(&key).await?;
+            queues.insert(key.trim_start_matches("shipit:queue:").to_string(), queue);
+        }
+
+        let mut running = self.running_worker().await?;
+        running.sort_by_key(|r| r.build.id);
+
+        let s: Vec<String> = self.conn.lrange(AUDIT_KEY, 0, -1).await?;
+        let mut audit = vec![];
+        for i in s {
+            audit.push(serde_json::from_str(&i)?);
+        }
+
+        let mut lifecycles = BTreeMap::new();
+        for key in self.keys("shipit-lifecycle:*").await? {
+            let Ok(id) = key.trim_start_matches("shipit-lifecycle:").parse() else {
+                continue;
+            };
+            if let Some(l) = self.lifecycle(id).await? {
+                lifecycles.insert(id, l);
+            }
+        }
+
+        let mut requested = BTreeMap::new();
+        for key in self.keys("shipit-requested:*").await? {
+            let actor = key.trim_start_matches("shipit-requested:").to_string();
+            let r = self.requested(&actor).await?;
+            requested.insert(actor, r);
+        }
+
+        let mut watchers = self.watchers().await?;
+        watchers.sort();
+
+        Ok(Dump {
+            exported_at: now(),
+            build_id: self
+                .conn
+                .get::<_, Option<i64>>("shipit-build-id")
+                .await?
+                .unwrap_or(0),
+            queues,
+            running,
+            history: self.history().await?,
+            disabled: self.disabled().await?,
+            windows: self.windows().await?,
+            hooks: self.hooks().await?,
+            audit,
+            digest_sent: self.digest_sent_at().await?,
+            secrets: self.hash_all(SECRETS_KEY).await?,
+            lifecycles,
+            chats: self.chats().await?,
+            watchers,
+            registrations: self.registrations().await?,
+            limits: self.limits().await?,
+            drains: self.drains().await?,
+            streaks: self.hash_all(STREAKS_KEY).await?,
+            requested,
+            last_success: self.conn.hgetall(LAST_SUCCESS_KEY).await?,
+        })
+    }
+
+    async fn keys(&mut self, pattern: &str) -> eyre::Result<Vec<String>> {
+        Ok(redis::cmd("KEYS")
+            .arg(pattern)
+            .query_async(&mut self.conn)
+            .await?)
+    }
+
+    /// The JSON values of hash `key`, by field.
+    async fn hash_all<T: DeserializeOwned>(
+        &mut self,
+        key: &str,
+    ) -> eyre::Result<BTreeMap<String, T>> {
+        let all: BTreeMap<String, String> = self.conn.hgetall(key).await?;
+
+        let mut res = BTreeMap::new();
+        for (k, v) in all {
+            res.insert(k, serde_json::from_str(&v)?);
+        }
+
+        Ok(res)
+    }
+
+    /// Replace the server state with `dump`, in one transaction. Unless
+    /// `force` is set, nothing is touched and `false` returned if there is
+    /// state to lose.
+    pub async fn import(&mut self, dump: &Dump, force: bool) -> eyre::Result<bool> {
+        let current = self.export().await?;
+        if !current.is_empty() && !force {
+            return Ok(false);
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for name in current.queues.keys() {
+            pipe.del(format!("shipit:queue:{name}")).ignore();
+        }
+        for r in &current.running {
+            pipe.del(running_key(&r.build.arch, r.build.id)).ignore();
+        }
+        pipe.del(&[
+            HISTORY_KEY,
+            DISABLED_KEY,
+            WINDOWS_KEY,
+            HOOKS_KEY,
+            AUDIT_KEY,
+            DIGEST_KEY,
+            SECRETS_KEY,
+            CHATS_KEY,
+            WATCHERS_KEY,
+            SETUP_KEY,
+            LIMITS_KEY,
+            DRAIN_KEY,
+            STREAKS_KEY,
+            LAST_SUCCESS_KEY,
+        ])
+        .ignore();
+        for id in current.lifecycles.keys() {
+            pipe.del(lifecycle_key(*id)).ignore();
+        }
+        for actor in current.requested.keys() {
+            pipe.del(requested_key(actor)).ignore();
+        }
+
+        // dumps from before lifecycles were exported have none, theirs start
+        // over
+        let now = now();
+        let claimed = Lifecycle {
+            phase: Phase::Claimed,
+            transitions: vec![Transition {
+                phase: Phase::Claimed,
+                at: now,
+            }],
+        };
+        for b in dump.queues.values().flatten() {
+            pipe.rpush(queue_key(&b.pool, &b.arch), schema::encode(b)?)
+                .ignore();
+            if !dump.lifecycles.contains_key(&b.id) {
+                pipe.set(
+                    lifecycle_key(b.id),
+                    serde_json::to_string(&Lifecycle::new(now))?,
+                )
+                .ignore();
+            }
+        }
+        for r in &dump.running {
+            pipe.set(running_key(&r.build.arch, r.build.id), schema::encode(r)?)
+                .ignore();
+            if !dump.lifecycles.contains_key(&r.build.id) {
+                pipe.set(lifecycle_key(r.build.id), serde_json::to_string(&claimed)?)
+                    .ignore();
+            }
+        }
+        for (id, l) in &dump.lifecycles {
+            let s = serde_json::to_string(l)?;
+            if l.phase.is_final() {
+                pipe.set_ex(lifecycle_key(*id), s, LIFECYCLE_TTL).ignore();
+            } else {
+                pipe.set(lifecycle_key(*id), s).ignore();
+            }
+        }
+        for h in &dump.history {
+            pipe.rpush(HISTORY_KEY, serde_json::to_string(h)?).ignore();
+        }
+        for (arch, d) in &dump.disabled {
+            pipe.hset(DISABLED_KEY, arch, serde_json::to_string(d)?)
+                .ignore();
+        }
+        for (arch, w) in &dump.windows {
+            pipe.hset(WINDOWS_KEY, arch, serde_json::to_string(w)?)
+                .ignore();
+        }
+        for h in &dump.hooks {
+            pipe.rpush(HOOKS_KEY, serde_json::to_string(h)?).ignore();
+        }
+        for a in &dump.audit {
+            pipe.rpush(AUDIT_KEY, serde_json::to_string(a)?).ignore();
+        }
+        if let Some(at) = dump.digest_sent {
+            pipe.set(DIGEST_KEY, at).ignore();
+        }
+        for (pool, s) in &dump.secrets {
+            pipe.hset(SECRETS_KEY, pool, serde_json::to_string(s)?)
+                .ignore();
+        }
+        for c in &dump.chats {
+            pipe.hset(CHATS_KEY, c.id, serde_json::to_string(c)?)
+                .ignore();
+        }
+        for id in &dump.watchers {
+            pipe.sadd(WATCHERS_KEY, id).ignore();
+        }
+        for r in &dump.registrations {
+            pipe.hset(
+                SETUP_KEY,
+                format!("{}:{}", r.role.name(), r.chat),
+                serde_json::to_string(r)?,
+            )
+            .ignore();
+        }
+        if let Some(l) = &dump.limits {
+            pipe.set(LIMITS_KEY, serde_json::to_string(l)?).ignore();
+        }
+        for (target, d) in &dump.drains {
+            pipe.hset(DRAIN_KEY, target, serde_json::to_string(d)?)
+                .ignore();
+        }
+        for (k, s) in &dump.streaks {
+            pipe.hset(STREAKS_KEY, k, serde_json::to_string(s)?)
+                .ignore();
+        }
+        for (actor, v) in &dump.requested {
+            for r in v {
+                pipe.hset(requested_key(actor), r.id, serde_json::to_string(r)?)
+                    .ignore();
+            }
+        }
+        for (k, at) in &dump.last_success {
+            pipe.hset(LAST_SUCCESS_KEY, k, at).ignore();
+        }
+
+        // never hand out an id that is already taken
+        let build_id = dump
+            .queues
+            .values()
+            .flatten()
+            .map(|b| b.id)
+            .chain(dump.running.iter().map(|r| r.build.id))
+            .chain(dump.history.iter().map(|h| h.id))
+            .fold(dump.build_id, i64::max);
+        pipe.set("shipit-build-id", build_id).ignore();
+
+        pipe.query_async::<_, ()>(&mut self.conn).await?;
+
+        Ok(true)
+    }
+
+    pub async fn set_clock_skew(
+        &mut self,
+        arch: &str,
+        worker: &str,
+        skew: i64,
+    ) -> eyre::Result<()> {
+        let entry = ClockSkew {
+            arch: arch.to_string(),
+            worker: worker.to_string(),
+            skew_secs: skew,
+        };
+
+        self.conn
+            .set_ex::<_, _, ()>(
+                format!("shipit-skew:{arch}:{worker}"),
+                serde_json::to_string(&entry)?,
+                CLOCK_SKEW_TTL,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Workers that recently reported a clock skew.
+    pub async fn clock_skews(&mut self) -> eyre::Result<Vec<ClockSkew>> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit-skew:*")
+            .query_async(&mut self.conn)
+            .await?;
+
+        let mut v: Vec<ClockSkew> = vec![];
+        for i in keys {
+            if let Some(s) = self.conn.get::<_, Option<String>>(i).await? {
+                v.push(serde_json::from_str(&s)?);
+            }
+        }
+        v.sort_by(|a, b| (&a.arch, &a.worker).cmp(&(&b.arch, &b.worker)));
+
+        Ok(v)
+    }
+
+    pub async fn set_unreachable(&mut self, entry: &Unreachable) -> eyre::Result<()> {
+        self.conn
+            .set_ex::<_, _, ()>(
+                format!("shipit-unreachable:{}:{}", entry.arch, entry.worker),
+                serde_json::to_string(entry)?,
+                UNREACHABLE_TTL,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Workers that recently failed to reach their upload host.
+    pub async fn unreachable(&mut self) -> eyre::Result<Vec<Unreachable>> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit-unreachable:*")
+            .query_async(&mut self.conn)
+            .await?;
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let values: Vec<Option<String>> = self.conn.mget(&keys).await?;
+        let mut v = values
+            .into_iter()
+            .flatten()
+            .map(|x| serde_json::from_str::<Unreachable>(&x))
+            .collect::<Result<Vec<_>, _>>()?;
+        v.sort_by(|a, b| (&a.arch, &a.worker).cmp(&(&b.arch, &b.worker)));
+
+        Ok(v)
+    }
+
+    /// Pause dispatch of `entry.arch` to `entry.worker`. `true` if no worker
+    /// of the arch was low on disk before.
+    pub async fn set_low_disk(&mut self, entry: &LowDisk) -> eyre::Result<bool> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg(format!("shipit-low-disk:{}:*", entry.arch))
+            .query_async(&mut self.conn)
+            .await?;
+
+        self.conn
+            .set_ex::<_, _, ()>(
+                format!("shipit-low-disk:{}:{}", entry.arch, entry.worker),
+                serde_json::to_string(entry)?,
+                LOW_DISK_TTL,
+            )
+            .await?;
+
+        Ok(keys.is_empty())
+    }
+
+    pub async fn clear_low_disk(&mut self, arch: &str, worker: &str) -> eyre::Result<()> {
+        self.conn
+            .del::<_, ()>(format!("shipit-low-disk:{arch}:{worker}"))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Workers recently paused for lack of disk space.
+    pub async fn low_disk(&mut self) -> eyre::Result<Vec<LowDisk>> {
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit-low-disk:*")
+            .query_async(&mut self.conn)
+            .await?;
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let values: Vec<Option<String>> = self.conn.mget(&keys).await?;
+        let mut v = values
+            .into_iter()
+            .flatten()
+            .map(|x| serde_json::from_str::<LowDisk>(&x))
+            .collect::<Result<Vec<_>, _>>()?;
+        v.sort_by(|a, b| (&a.arch, &a.worker).cmp(&(&b.arch, &b.worker)));
+
+        Ok(v)
+    }
+
+    pub async fn set_worker(&mut self, worker: &WorkerRecord) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(WORKERS_KEY, &worker.name, serde_json::to_string(worker)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Mark a known worker as seen now, while it is busy with a build and
+    /// does not poll.
+    pub async fn touch_worker(&mut self, name: &str, free_disk: Option<u64>) -> eyre::Result<()> {
+        let s: Option<String> = self.conn.hget(WORKERS_KEY, name).await?;
+        if let Some(s) = s {
+            let mut worker: WorkerRecord = serde_json::from_str(&s)?;
+            worker.last_seen = now();
+            if free_disk.is_some() {
+                worker.free_disk = free_disk;
+            }
+            self.set_worker(&worker).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn worker(&mut self, name: &str) -> eyre::Result<Option<WorkerRecord>> {
+        let s: Option<String> = self.conn.hget(WORKERS_KEY, name).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    /// Every worker that ever polled and was not forgotten, by arch and
+    /// hostname.
+    pub async fn workers(&mut self) -> eyre::Result<Vec<WorkerRecord>> {
+        let m: BTreeMap<String, String> = self.conn.hgetall(WORKERS_KEY).await?;
+        let mut v = m
+            .values()
+            .map(|x| serde_json::from_str::<WorkerRecord>(x))
+            .collect::<Result<Vec<_>, _>>()?;
+        v.sort_by(|a, b| (&a.arch, &a.hostname, &a.name).cmp(&(&b.arch, &b.hostname, &b.name)));
+
+        Ok(v)
+    }
+
+    /// Drop a decommissioned worker from the list, `false` if unknown. It
+    /// shows up again if it polls.
+    pub async fn forget_worker(&mut self, name: &str) -> eyre::Result<bool> {
+        let n: usize = self.conn.hdel(WORKERS_KEY, name).await?;
+
+        Ok(n > 0)
+    }
+
+    /// Ask the next worker of `arch` polling for jobs to answer. `None` if
+    /// a ping of `arch` is already waiting.
+    pub async fn request_ping(
+        &mut self,
+        arch: &str,
+        chat: i64,
+        check: bool,
+    ) -> eyre::Result<Option<Ping>> {
+        let ping = Ping {
+            id: self.conn.incr("shipit-ping-id", 1).await?,
+            arch: arch.to_string(),
+            chat,
+            sent_at_ms: now_ms(),
+            check,
+        };
+
+        // expires by itself when the ping times out
+        let set: Option<String> = redis::cmd("SET")
+            .arg(format!("shipit-ping:{arch}"))
+            .arg(ping.id)
+            .arg("NX")
+            .arg("EX")
+            .arg(ping.timeout())
+            .query_async(&mut self.conn)
+            .await?;
+        if set.is_none() {
+            return Ok(None);
+        }
+
+        self.conn
+            .set_ex::<_, _, ()>(
+                format!("shipit-ping-id:{}", ping.id),
+                serde_json::to_string(&ping)?,
+                ping.timeout() * 2,
+            )
+            .await?;
+
+        Ok(Some(ping))
+    }
+
+    /// The ping waiting for a worker of `arch`, handed to a single worker.
+    pub async fn take_ping(&mut self, arch: &str) -> eyre::Result<Option<Ping>> {
+        let key = format!("shipit-ping:{arch}");
+        let (id, ()): (Option<i64>, ()) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .del(&key)
+            .query_async(&mut self.conn)
+            .await?;
+        let Some(id) = id else {
+            return Ok(None);
+        };
+
+        let s: Option<String> = self.conn.get(format!("shipit-ping-id:{id}")).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    /// Remove ping `id`, returning it unless it was answered or given up on
+    /// already.
+    pub async fn finish_ping(&mut self, id: i64) -> eyre::Result<Option<Ping>> {
+        let key = format!("shipit-ping-id:{id}");
+        let (s, ()): (Option<String>, ()) = redis::pipe()
+            .atomic()
+            .get(&key)
+            .del(&key)
+            .query_async(&mut self.conn)
+            .await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    /// Known chats, by id.
+    pub async fn chats(&mut self) -> eyre::Result<Vec<ChatRecord>> {
+        let m: BTreeMap<i64, String> = self.conn.hgetall(CHATS_KEY).await?;
+
+        let mut v = vec![];
+        for s in m.values() {
+            v.push(serde_json::from_str(s)?);
+        }
+
+        Ok(v)
+    }
+
+    pub async fn chat(&mut self, id: i64) -> eyre::Result<Option<ChatRecord>> {
+        let s: Option<String> = self.conn.hget(CHATS_KEY, id).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    /// Start or stop sending `/watch` notices to `chat`. `false` if it
+    /// already was or was not watching.
+    pub async fn set_watching(&mut self, chat: i64, on: bool) -> eyre::Result<bool> {
+        let n: usize = if on {
+            self.conn.sadd(WATCHERS_KEY, chat).await?
+        } else {
+            self.conn.srem(WATCHERS_KEY, chat).await?
+        };
+
+        Ok(n > 0)
+    }
+
+    pub async fn watchers(&mut self) -> eyre::Result<Vec<i64>> {
+        Ok(self.conn.smembers(WATCHERS_KEY).await?)
+    }
+
+    /// Modify the record of chat `id`, creating it if unknown.
+    pub async fn update_chat(
+        &mut self,
+        id: i64,
+        f: impl FnOnce(&mut ChatRecord),
+    ) -> eyre::Result<ChatRecord> {
+        let mut chat = self.chat(id).await?.unwrap_or(ChatRecord {
+            id,
+            ..Default::default()
+        });
+        f(&mut chat);
+
+        self.conn
+            .hset::<_, _, _, ()>(CHATS_KEY, id, serde_json::to_string(&chat)?)
+            .await?;
+
+        Ok(chat)
+    }
+
+    /// The language of messages to `chat`.
+    pub async fn lang(&mut self, chat: i64) -> eyre::Result<Lang> {
+        Ok(self.chat(chat).await?.map(|c| c.lang).unwrap_or_default())
+    }
+
+    /// The chat `id` is now, following group to supergroup migrations.
+    pub async fn resolve_chat(&mut self, id: i64) -> eyre::Result<i64> {
+        let mut id = id;
+        // a bound, in case the records ever form a loop
+        for _ in 0..8 {
+            match self.chat(id).await?.and_then(|x| x.migrated_to) {
+                Some(new) => id = new,
+                None => break,
+            }
+        }
+
+        Ok(id)
+    }
+
+    /// Record that chat `old` became `new`, and move queued, running and
+    /// finished builds requested from `old` over.
+    pub async fn migrate_chat(&mut self, old: i64, new: i64) -> eyre::Result<()> {
+        let mut title = None;
+        self.update_chat(old, |c| {
+            c.migrated_to = Some(new);
+            title = c.title.clone();
+        })
+        .await?;
+        self.update_chat(new, |c| {
+            c.title = c.title.take().or(title);
+            c.undeliverable = false;
+        })
+        .await?;
+
+        let keys: Vec<String> = redis::cmd("KEYS")
+            .arg("shipit:queue:*")
+            .query_async(&mut self.conn)
+            .await?;
+        for key in keys {
+            let s: Vec<String> = self.conn.lrange(&key, 0, -1).await?;
+            for raw in s {
+                let (mut build, _) = schema::decode::<Build>(&raw)?;
+                if build.requester_chat == old {
+                    build.requester_chat = new;
+                    build.requester = build.requester.map(|x| migrate_actor(x, old, new));
+                    // gone if claimed or cancelled meanwhile
+                    self.replace_in_list(&key, &raw, &schema::encode(&build)?)
+                        .await?;
+                }
+            }
+        }
+
+        for mut r in self.running_worker().await? {
+            if r.build.requester_chat == old {
+                r.build.requester_chat = new;
+                r.build.requester = r.build.requester.map(|x| migrate_actor(x, old, new));
+                self.conn
+                    .set::<_, _, ()>(running_key(&r.build.arch, r.build.id), schema::encode(&r)?)
+                    .await?;
+            }
+        }
+
+        let requested: BTreeMap<i64, String> =
+            self.conn.hgetall(requested_key(&old.to_string())).await?;
+        if !requested.is_empty() {
+            let items = requested.into_iter().collect::<Vec<_>>();
+            self.conn
+                .hset_multiple::<_, _, _, ()>(requested_key(&new.to_string()), &items)
+                .await?;
+            self.conn
+                .del::<_, ()>(requested_key(&old.to_string()))
+                .await?;
+        }
+
+        for h in self.history().await? {
+            if h.requester_chat == Some(old) {
+                self.update_history(h.id, |x| {
+                    if x.requester_chat == Some(old) {
+                        x.requester_chat = Some(new);
+                    }
+                })
+                .await?;
+            }
+        }
+
+        for mut r in self.registrations().await? {
+            if r.chat == old {
+                self.unregister(r.role, old).await?;
+                r.chat = new;
+                self.register(&r).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every chat set up for a role, by chat.
+    pub async fn registrations(&mut self) -> eyre::Result<Vec<Registration>> {
+        let m: BTreeMap<String, String> = self.conn.hgetall(SETUP_KEY).await?;
+        let mut v = m
+            .values()
+            .map(|x| serde_json::from_str::<Registration>(x))
+            .collect::<Result<Vec<_>, _>>()?;
+        v.sort_by_key(|x| (x.chat, x.role));
+
+        Ok(v)
+    }
+
+    pub async fn register(&mut self, r: &Registration) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(
+                SETUP_KEY,
+                format!("{}:{}", r.role.name(), r.chat),
+                serde_json::to_string(r)?,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether `chat` was set up for `role`.
+    pub async fn unregister(&mut self, role: Role, chat: i64) -> eyre::Result<bool> {
+        let n: usize = self
+            .conn
+            .hdel(SETUP_KEY, format!("{}:{chat}", role.name()))
+            .await?;
+
+        Ok(n > 0)
+    }
+
+    /// Whether `text` was delivered to `chat` a moment ago.
+    pub async fn was_sent(&mut self, chat: i64, text: &str) -> eyre::Result<bool> {
+        Ok(self.conn.exists(sent_key(chat, text)).await?)
+    }
+
+    pub async fn mark_sent(&mut self, chat: i64, text: &str) -> eyre::Result<()> {
+        self.conn
+            .set_ex::<_, _, ()>(sent_key(chat, text), now(), SENT_TTL)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Disabled arches and why.
+    pub async fn disabled(&mut self) -> eyre::Result<BTreeMap<String, Disabled>> {
+        let m: BTreeMap<String, String> = self.conn.hgetall(DISABLED_KEY).await?;
+
+        let mut res = BTreeMap::new();
+        for (arch, s) in m {
+            res.insert(arch, serde_json::from_str(&s)?);
+        }
+
+        Ok(res)
+    }
+
+    pub async fn is_disabled(&mut self, arch: &str) -> eyre::Result<Option<Disabled>> {
+        let s: Option<String> = self.conn.hget(DISABLED_KEY, arch).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    pub async fn disable(&mut self, arch: &str, disabled: &Disabled) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(DISABLED_KEY, arch, serde_json::to_string(disabled)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether `arch` was disabled.
+    pub async fn enable(&mut self, arch: &str) -> eyre::Result<bool> {
+        let n: usize = self.conn.hdel(DISABLED_KEY, arch).await?;
+
+        Ok(n > 0)
+    }
+
+    /// Every drain, by `arch:<arch>` or `worker:<name>`.
+    pub async fn drains(&mut self) -> eyre::Result<BTreeMap<String, Drain>> {
+        let m: BTreeMap<String, String> = self.conn.hgetall(DRAIN_KEY).await?;
+
+        let mut res = BTreeMap::new();
+        for (target, s) in m {
+            res.insert(target, serde_json::from_str(&s)?);
+        }
+
+        Ok(res)
+    }
+
+    pub async fn set_drain(&mut self, target: &str, drain: &Drain) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(DRAIN_KEY, target, serde_json::to_string(drain)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether `target` was draining.
+    pub async fn undrain(&mut self, target: &str) -> eyre::Result<bool> {
+        let n: usize = self.conn.hdel(DRAIN_KEY, target).await?;
+
+        Ok(n > 0)
+    }
+
+    /// Note that `worker` knows of the drain `target`, the first time only.
+    pub async fn ack_drain(&mut self, target: &str, worker: &str) -> eyre::Result<()> {
+        let s: Option<String> = self.conn.hget(DRAIN_KEY, target).await?;
+        if let Some(s) = s {
+            let mut drain: Drain = serde_json::from_str(&s)?;
+            if !drain.acked.contains_key(worker) {
+                drain.acked.insert(worker.to_string(), now());
+                self.set_drain(target, &drain).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn windows(&mut self) -> eyre::Result<BTreeMap<String, Window>> {
+        let m: BTreeMap<String, String> = self.conn.hgetall(WINDOWS_KEY).await?;
+
+        let mut res = BTreeMap::new();
+        for (arch, s) in m {
+            res.insert(arch, serde_json::from_str(&s)?);
+        }
+
+        Ok(res)
+    }
+
+    pub async fn window(&mut self, arch: &str) -> eyre::Result<Option<Window>> {
+        let s: Option<String> = self.conn.hget(WINDOWS_KEY, arch).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    pub async fn set_window(&mut self, arch: &str, window: &Window) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(WINDOWS_KEY, arch, serde_json::to_string(window)?)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether `arch` had a window.
+    pub async fn clear_window(&mut self, arch: &str) -> eyre::Result<bool> {
+        let n: usize = self.conn.hdel(WINDOWS_KEY, arch).await?;
+
+        Ok(n > 0)
+    }
+
+    /// The caps set with `/limits`, `None` if the defaults apply.
+    pub async fn limits(&mut self) -> eyre::Result<Option<Limits>> {
+        let s: Option<String> = self.conn.get(LIMITS_KEY).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    /// Back to the defaults if `limits` is `None`.
+    pub async fn set_limits(&mut self, limits: Option<&Limits>) -> eyre::Result<()> {
+        match limits {
+            Some(l) => {
+                self.conn
+                    .set::<_, _, ()>(LIMITS_KEY, serde_json::to_string(l)?)
+                    .await?
+            }
+            None => self.conn.del::<_, ()>(LIMITS_KEY).await?,
+        }
+
+        Ok(())
+    }
+
+    pub async fn count_queue_full(&mut self, cap: Cap) -> eyre::Result<()> {
+        self.conn
+            .hincr::<_, _, _, ()>(QUEUE_FULL_KEY, cap.name(), 1)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Requests refused by each cap since the counts were last cleared.
+    pub async fn queue_full_counts(&mut self) -> eyre::Result<BTreeMap<String, u64>> {
+        Ok(self.conn.hgetall(QUEUE_FULL_KEY).await?)
+    }
+
+    /// Move the last success of `build_type` on `arch` to `at`, unless it
+    /// is later already.
+    pub async fn set_last_success(
+        &mut self,
+        arch: &str,
+        build_type: &str,
+        at: u64,
+    ) -> eyre::Result<()> {
+        let field = format!("{arch}:{build_type}");
+        let last: Option<u64> = self.conn.hget(LAST_SUCCESS_KEY, &field).await?;
+        if last.is_none_or(|x| x < at) {
+            self.conn
+                .hset::<_, _, _, ()>(LAST_SUCCESS_KEY, &field, at)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn last_success(
+        &mut self,
+        arch: &str,
+        build_type: &str,
+    ) -> eyre::Result<Option<u64>> {
+        Ok(self
+            .conn
+            .hget(LAST_SUCCESS_KEY, format!("{arch}:{build_type}"))
+            .await?)
+    }
+
+    /// By arch and build type.
+    pub async fn last_successes(&mut self) -> eyre::Result<BTreeMap<(String, String), u64>> {
+        let all: BTreeMap<String, u64> = self.conn.hgetall(LAST_SUCCESS_KEY).await?;
+
+        Ok(all
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let (arch, build_type) = k.split_once(':')?;
+                Some(((arch.to_string(), build_type.to_string()), v))
+            })
+            .collect())
+    }
+
+    /// When the daily digest was last sent, on schedule or with `/digest`.
+    pub async fn digest_sent_at(&mut self) -> eyre::Result<Option<u64>> {
+        Ok(self.conn.get(DIGEST_KEY).await?)
+    }
+
+    pub async fn set_digest_sent_at(&mut self, at: u64) -> eyre::Result<()> {
+        self.conn.set::<_, _, ()>(DIGEST_KEY, at).await?;
+
+        Ok(())
+    }
+
+    /// The secrets of `pool` as last rotated, see [`crate::secret`].
+    pub async fn secrets(&mut self, pool: &str) -> eyre::Result<Option<Secrets>> {
+        let s: Option<String> = self.conn.hget(SECRETS_KEY, pool).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    pub async fn set_secrets(&mut self, pool: &str, secrets: &Secrets) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(SECRETS_KEY, pool, serde_json::to_string(secrets)?)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_secrets(&mut self, pool: &str) -> eyre::Result<()> {
+        self.conn.hdel::<_, _, ()>(SECRETS_KEY, pool).await?;
+
+        Ok(())
+    }
+
+    /// The progress posts of build `id`, see [`crate::progress`].
+    pub async fn progress_posts(&mut self, id: i64) -> eyre::Result<Option<Posts>> {
+        let s: Option<String> = self.conn.get(format!("shipit-progress-posts:{id}")).await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    pub async fn set_progress_posts(&mut self, id: i64, posts: &Posts) -> eyre::Result<()> {
+        self.conn
+            .set_ex::<_, _, ()>(
+                format!("shipit-progress-posts:{id}"),
+                serde_json::to_string(posts)?,
+                PROGRESS_POSTS_TTL,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_progress_posts(&mut self, id: i64) -> eyre::Result<()> {
+        self.conn
+            .del::<_, ()>(format!("shipit-progress-posts:{id}"))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Add `event` to the event stream. Best effort: a failure is logged
+    /// and counted, not returned.
+    pub async fn publish(&mut self, event: &Event) {
+        self.publish_all(std::slice::from_ref(event)).await
+    }
+
+    /// [`Db::publish`] for several events, in one round trip.
+    pub async fn publish_all(&mut self, events: &[Event]) {
+        if self.events_len == 0 || events.is_empty() {
+            return;
+        }
+
+        let res = async {
+            let mut pipe = redis::pipe();
+            for event in events {
+                pipe.xadd_maxlen(
+                    EVENTS_KEY,
+                    StreamMaxlen::Approx(self.events_len),
+                    "*",
+                    &[("event", serde_json::to_string(event)?)],
+                )
+                .ignore();
+            }
+            pipe.query_async::<_, ()>(&mut self.conn).await?;
+
+            Ok::<_, eyre::Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            for event in events {
+                warn!(
+                    "Failed to publish the {} event of #{}: {e}",
+                    event.kind, event.id
+                );
+            }
+            self.events_dropped
+                .fetch_add(events.len() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Create the consumer group `group` of the event stream, reading the
+    /// events from now on, unless it exists.
+    pub async fn create_event_group(&mut self, group: &str) -> eyre::Result<()> {
+        match self
+            .conn
+            .xgroup_create_mkstream::<_, _, _, ()>(EVENTS_KEY, group, "$")
+            .await
+        {
+            Err(e) if e.code() != Some("BUSYGROUP") => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// The events not yet delivered to `group`, by id, waiting a while for
+    /// some if there are none.
+    pub async fn read_events(
+        &mut self,
+        group: &str,
+        consumer: &str,
+    ) -> eyre::Result<Vec<(String, String)>> {
+        let opts = StreamReadOptions::default()
+            .group(group, consumer)
+            .block(EVENTS_BLOCK);
+        let reply: Option<StreamReadReply> = self
+            .conn
+            .xread_options(&[EVENTS_KEY], &[">"], &opts)
+            .await?;
+
+        Ok(reply
+            .into_iter()
+            .flat_map(|x| x.keys)
+            .flat_map(|x| x.ids)
+            .map(|x| {
+                let event = x.get::<String>("event").unwrap_or_default();
+                (x.id, event)
+            })
+            .collect())
+    }
+
+    pub async fn ack_event(&mut self, group: &str, id: &str) -> eyre::Result<()> {
+        self.conn
+            .xack::<_, _, _, ()>(EVENTS_KEY, group, &[id])
+            .await?;
+
+        Ok(())
+    }
+
+    /// The failures in a row of `build_type` on `arch` in `channel`, see
+    /// [`crate::streak`].
+    pub async fn streak(
+        &mut self,
+        arch: &str,
+        build_type: &str,
+        channel: Channel,
+    ) -> eyre::Result<Option<Streak>> {
+        let s: Option<String> = self
+            .conn
+            .hget(STREAKS_KEY, format!("{arch}:{build_type}:{channel}"))
+            .await?;
+
+        Ok(match s {
+            Some(s) => Some(serde_json::from_str(&s)?),
+            None => None,
+        })
+    }
+
+    pub async fn set_streak(
+        &mut self,
+        arch: &str,
+        build_type: &str,
+        channel: Channel,
+        streak: &Streak,
+    ) -> eyre::Result<()> {
+        self.conn
+            .hset::<_, _, _, ()>(
+                STREAKS_KEY,
+                format!("{arch}:{build_type}:{channel}"),
+                serde_json::to_string(streak)?,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn clear_streak(
+        &mut self,
+        arch: &str,
+        build_type: &str,
+        channel: Channel,
+    ) -> eyre::Result<()> {
+        self.conn
+            .hdel::<_, _, ()>(STREAKS_KEY, format!("{arch}:{build_type}:{channel}"))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Every streak, by arch, build type and channel.
+    pub async fn streaks(&mut self) -> eyre::Result<BTreeMap<(String, String, Channel), Streak>> {
+        let all: BTreeMap<String, String> = self.conn.hgetall(STREAKS_KEY).await?;
+
+        let mut res = BTreeMap::new();
+        for (k, v) in all {
+            let mut parts = k.splitn(3, ':');
+            let (Some(arch), Some(build_type), Some(channel)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            let Ok(channel) = channel.parse() else {
+                continue;
+            };
+            res.insert(
+                (arch.to_string(), build_type.to_string(), channel),
+                serde_json::from_str(&v)?,
+            );
+        }
+
+        Ok(res)
+    }
+
+    /// Remember `posted` for each build it has a cancel button of.
+    pub async fn set_buttons(&mut self, posted: &Posted) -> eyre::Result<()> {
+        self.store_buttons(posted).await?;
+
+        // left the queue while the message was on its way
+        for id in posted.cancels() {
+            if self
+                .lifecycle(id)
+                .await?
+                .is_some_and(|l| l.phase != Phase::Queued)
+            {
+                self.conn.rpush::<_, _, ()>(STALE_BUTTONS_KEY, id).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn store_buttons(&mut self, posted: &Posted) -> eyre::Result<()> {
+        let s = serde_json::to_string(posted)?;
+        let mut pipe = redis::pipe();
+        for id in posted.cancels() {
+            pipe.hset(BUTTONS_KEY, id, &s).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut self.conn).await?;
+
+        Ok(())
+    }
+
+    /// Forget the cancel button of build `id`, returning its message with
+    /// the buttons left.
+    pub async fn take_buttons(&mut self, id: i64) -> eyre::Result<Option<Posted>> {
+        let s: Option<String> = self.conn.hget(BUTTONS_KEY, id).await?;
+        let Some(s) = s else {
+            return Ok(None);
+        };
+        self.conn.hdel::<_, _, ()>(BUTTONS_KEY, id).await?;
+
+        let mut posted: Posted = serde_json::from_str(&s)?;
+        posted.buttons.retain(|b| b.id != id);
+        // the others of the message must not bring the button back
+        if posted.cancels().next().is_some() {
+            self.store_buttons(&posted).await?;
+        }
+
+        Ok(Some(posted))
+    }
+
+    pub async fn pop_stale_buttons(&mut self) -> eyre::Result<Option<i64>> {
+        Ok(self.conn.lpop(STALE_BUTTONS_KEY, None).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hook::HookAction,
+        testing::{build, entry, redis},
+    };
+
+    #[test]
+    fn history_describes_what_was_built() {
+        let mut h = entry(1, "amd64", "release", false, 600);
+        h.variants = Some(vec!["base".to_string(), "desktop".to_string()]);
+        assert_eq!(h.describe(), "release(base, desktop)");
+        h.missing_variants = vec!["desktop".to_string()];
+        assert_eq!(h.describe(), "release(base ok, desktop missing)");
+
+        // recorded by an older server
+        assert_eq!(entry(2, "amd64", "iso", true, 600).describe(), "iso");
+        assert_eq!(
+            entry(3, "amd64", "release", true, 600).describe(),
+            "release(default set)"
+        );
+    }
+
+    #[test]
+    fn the_backends_of_a_notice_are_recorded_as_queued() {
+        let mut raw = serde_json::to_value(entry(1, "amd64", "livekit", true, 600)).unwrap();
+        // recorded by an older server
+        raw["notified"] = serde_json::json!(["telegram", "irc"]);
+        let h: HistoryEntry = serde_json::from_value(raw).unwrap();
+        assert_eq!(h.queued, ["telegram", "irc"]);
+
+        let raw = serde_json::to_value(&h).unwrap();
+        assert_eq!(raw["queued"], serde_json::json!(["telegram", "irc"]));
+        assert!(raw.get("notified").is_none());
+    }
+
+    async fn claim(db: &mut Db, worker: &str) -> Option<i64> {
+        db.claim(
+            "mainline",
+            "amd64",
+            Some(worker),
+            None,
+            true,
+            Preference::default(),
+        )
+        .await
+        .unwrap()
+        .map(|x| x.id)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn sequential_claims_never_return_the_same_build() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let builds = [
+            build(1, "amd64", BuildType::Livekit),
+            build(2, "amd64", BuildType::Livekit),
+        ];
+        db.enqueue_all(&builds.iter().collect::<Vec<_>>())
+            .await
+            .unwrap();
+
+        assert_eq!(claim(&mut db, "w1").await, Some(1));
+        assert_eq!(claim(&mut db, "w1").await, Some(2));
+        assert_eq!(claim(&mut db, "w1").await, None);
+        assert!(db.queue("mainline", "amd64").await.unwrap().is_empty());
+        assert_eq!(db.running("amd64").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn concurrent_claims_never_return_the_same_build() {
+        const BUILDS: i64 = 20;
+        const WORKERS: usize = 4;
+
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let builds = (1..=BUILDS)
+            .map(|id| build(id, "amd64", BuildType::Livekit))
+            .collect::<Vec<_>>();
+        db.enqueue_all(&builds.iter().collect::<Vec<_>>())
+            .await
+            .unwrap();
+
+        let mut workers = vec![];
+        for i in 0..WORKERS {
+            let mut db = redis.db().await;
+            workers.push(tokio::spawn(async move {
+                let mut claimed = vec![];
+                while let Some(id) = claim(&mut db, &format!("w{i}")).await {
+                    claimed.push(id);
+                }
+                claimed
+            }));
+        }
+
+        let mut claimed = vec![];
+        for w in workers {
+            claimed.extend(w.await.unwrap());
+        }
+        claimed.sort();
+        assert_eq!(claimed, (1..=BUILDS).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn losing_a_race_claims_the_next_build() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let builds = [
+            build(1, "amd64", BuildType::Livekit),
+            build(2, "amd64", BuildType::Livekit),
+        ];
+        db.enqueue_all(&builds.iter().collect::<Vec<_>>())
+            .await
+            .unwrap();
+
+        // both see #1 first, whichever loses it gets #2 rather than nothing
+        let mut other = redis.db().await;
+        let (a, b) = tokio::join!(claim(&mut db, "w1"), claim(&mut other, "w2"));
+        let mut claimed = [a, b];
+        claimed.sort();
+        assert_eq!(claimed, [Some(1), Some(2)]);
+    }
+
+    /// Some of every record an export holds.
+    async fn populate(db: &mut Db) {
+        let queued = build(3, "amd64", BuildType::Livekit);
+        let claimed = build(2, "arm64", BuildType::Release(vec!["base".to_string()]));
+        db.enqueue_all(&[&claimed, &queued]).await.unwrap();
+        db.conn.set::<_, _, ()>("shipit-build-id", 3).await.unwrap();
+        db.claim(
+            "mainline",
+            "arm64",
+            Some("w1"),
+            None,
+            true,
+            Preference::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        db.push_history(&entry(1, "amd64", "livekit", true, 600))
+            .await
+            .unwrap();
+        db.disable(
+            "riscv64",
+            &Disabled {
+                reason: Some("board down".to_string()),
+                by: "10".to_string(),
+                at: 1_700_000_000,
+            },
+        )
+        .await
+        .unwrap();
+        db.set_window(
+            "amd64",
+            &Window {
+                start: 22 * 60,
+                end: 6 * 60,
+                tz: "Asia/Shanghai".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+        db.add_hook(&Hook {
+            build_type: Some("livekit".to_string()),
+            arch: None,
+            action: HookAction::Post {
+                url: "https://example.org/refresh".to_string(),
+            },
+        })
+        .await
+        .unwrap();
+        db.audit("10", "disabled riscv64").await.unwrap();
+        db.set_digest_sent_at(1_700_000_000).await.unwrap();
+        db.set_secrets(
+            "mainline",
+            &Secrets {
+                primary: "new".to_string(),
+                previous: Some(crate::secret::Previous {
+                    secret: "old".to_string(),
+                    until: 1_700_003_600,
+                }),
+            },
+        )
+        .await
+        .unwrap();
+        db.update_chat(-100, |c| {
+            c.pool = Some("staging".to_string());
+            c.lang = Lang::Zh;
+            c.progress = progress::Mode::Off;
+        })
+        .await
+        .unwrap();
+        db.set_watching(-100, true).await.unwrap();
+        db.register(&Registration {
+            chat: -100,
+            role: Role::Digest,
+            pool: None,
+            by: "10".to_string(),
+            at: 1_700_000_000,
+        })
+        .await
+        .unwrap();
+        db.set_limits(Some(&Limits {
+            total: Some(100),
+            per_arch: None,
+            per_requester: Some(5),
+        }))
+        .await
+        .unwrap();
+        db.set_drain(
+            "w1",
+            &Drain {
+                by: "10".to_string(),
+                at: 1_700_000_000,
+                acked: BTreeMap::from([("w1".to_string(), 1_700_000_030)]),
+            },
+        )
+        .await
+        .unwrap();
+        db.set_streak(
+            "amd64",
+            "livekit",
+            Channel::Nightly,
+            &Streak {
+                failures: 3,
+                class: Some("mirror_timeout".to_string()),
+                paused: true,
+            },
+        )
+        .await
+        .unwrap();
+        db.set_last_success("amd64", "livekit", 1_699_990_000)
+            .await
+            .unwrap();
+        // a finished build, its lifecycle kept until it expires
+        db.transition(1, Phase::Queued).await.unwrap();
+        db.transition(1, Phase::Claimed).await.unwrap();
+        db.transition(1, Phase::Succeeded).await.unwrap();
+    }
+
+    /// An export without its time, for comparing.
+    async fn exported(db: &mut Db) -> serde_json::Value {
+        let mut v = serde_json::to_value(db.export().await.unwrap()).unwrap();
+        v.as_object_mut().unwrap().remove("exported_at");
+        v
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn import_of_an_export_is_lossless() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        populate(&mut db).await;
+
+        let before = exported(&mut db).await;
+        // nothing goes untested, a new record type included
+        for (field, value) in before.as_object().unwrap() {
+            let empty = match value {
+                serde_json::Value::Null => true,
+                serde_json::Value::Array(x) => x.is_empty(),
+                serde_json::Value::Object(x) => {
+                    x.is_empty() || x.values().all(|x| x == &serde_json::json!([]))
+                }
+                _ => false,
+            };
+            assert!(!empty, "{field} is not populated");
+        }
+
+        // another database of the same Redis, as good as another server
+        let mut other = Db::new(&format!("{}/1", redis.url), 100).await.unwrap();
+        let dump: Dump =
+            serde_json::from_value(serde_json::to_value(db.export().await.unwrap()).unwrap())
+                .unwrap();
+        assert!(other.import(&dump, false).await.unwrap());
+        assert_eq!(exported(&mut other).await, before);
+
+        // and into itself, replacing everything
+        assert!(!db.import(&dump, false).await.unwrap());
+        assert!(db.import(&dump, true).await.unwrap());
+        assert_eq!(exported(&mut db).await, before);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn concurrent_transitions_from_one_phase_let_one_through() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        for id in 1..=20 {
+            db.transition(id, Phase::Queued).await.unwrap();
+            db.transition(id, Phase::Claimed).await.unwrap();
+        }
+
+        // a build finishing while an admin cancels it, on clones sharing
+        // the connection as the handlers do
+        let mut done = db.clone();
+        let mut failed = db.clone();
+        for id in 1..=20 {
+            let (a, b) = tokio::join!(
+                done.transition(id, Phase::Succeeded),
+                failed.transition(id, Phase::Failed)
+            );
+            assert!(a.is_ok() != b.is_ok(), "#{id}: {a:?} and {b:?}");
+            let loser = a.err().or(b.err()).unwrap();
+            assert!(loser.is::<IllegalTransition>());
+
+            let l = db.lifecycle(id).await.unwrap().unwrap();
+            assert!(l.phase.is_final());
+            assert_eq!(l.transitions.len(), 3);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn history_updates_land_on_their_entry_whatever_is_pushed_meanwhile() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        db.push_history(&entry(1, "amd64", "livekit", true, 600))
+            .await
+            .unwrap();
+
+        let mut pusher = db.clone();
+        let mut updater = db.clone();
+        let mut other = db.clone();
+        for id in 2..=20 {
+            let h = entry(id, "amd64", "livekit", true, 600);
+            let (pushed, updated, also) = tokio::join!(
+                pusher.push_history(&h),
+                updater.update_history(1, |x| x.queued.push(format!("a{id}"))),
+                other.update_history(1, |x| x.queued.push(format!("b{id}")))
+            );
+            pushed.unwrap();
+            assert!(updated.unwrap());
+            assert!(also.unwrap());
+        }
+
+        let history = db.history().await.unwrap();
+        assert_eq!(history.len(), 20);
+        for h in &history[..19] {
+            assert!(h.queued.is_empty(), "#{} got {:?}", h.id, h.queued);
+        }
+        assert_eq!(history[19].id, 1);
+        assert_eq!(history[19].queued.len(), 38);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn transitions_are_refused_as_can_become_says() {
+        use Phase::*;
+
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let phases = [
+            Queued, Claimed, Running, Publishing, Succeeded, Failed, Cancelled, Lost,
+        ];
+
+        let mut id = 0;
+        for from in phases {
+            for to in phases {
+                id += 1;
+                db.transition(id, from).await.unwrap();
+
+                let res = db.transition(id, to).await;
+                let l = db.lifecycle(id).await.unwrap().unwrap();
+                if from.can_become(to) {
+                    res.unwrap();
+                    assert_eq!(l.phase, to, "{from} -> {to}");
+                    assert_eq!(l.transitions.len(), 2);
+                } else {
+                    let e = res.unwrap_err();
+                    assert!(e.is::<IllegalTransition>(), "{from} -> {to}: {e}");
+                    assert_eq!(l.phase, from, "{from} -> {to}");
+                    assert_eq!(l.transitions.len(), 1);
+                }
+            }
+        }
+    }
 }
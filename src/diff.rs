@@ -0,0 +1,172 @@
+//! What changed between the artifacts of two builds, for `/diff`: files
+//! added and removed, size and checksum changes, and the commits of the
+//! build scripts in between.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    db::{Artifact, BuildType, HistoryEntry},
+    format::human_bytes,
+    lang::{Lang, Msg},
+};
+
+const MKLIVE_REPO: &str = "https://github.com/AOSC-Dev/aosc-mklive";
+const AOSCBOOTSTRAP_REPO: &str = "https://github.com/AOSC-Dev/aoscbootstrap";
+
+/// The changes from `old` to `new`, one per line. The error is the reply,
+/// for builds of different arches or types, or without a manifest.
+pub fn render(old: &HistoryEntry, new: &HistoryEntry, lang: Lang) -> Result<String, String> {
+    if old.arch != new.arch || old.build_type != new.build_type {
+        return Err(lang.tr(
+            Msg::DiffMismatch,
+            &[
+                ("old", &old.id),
+                ("old_build", &old.build_type),
+                ("old_arch", &old.arch),
+                ("new", &new.id),
+                ("new_build", &new.build_type),
+                ("new_arch", &new.arch),
+            ],
+        ));
+    }
+    let Some(old_manifest) = &old.manifest else {
+        return Err(lang.tr(Msg::DiffNoManifest, &[("id", &old.id)]));
+    };
+    let Some(new_manifest) = &new.manifest else {
+        return Err(lang.tr(Msg::DiffNoManifest, &[("id", &new.id)]));
+    };
+
+    let mut lines = vec![
+        lang.tr(
+            Msg::DiffHeader,
+            &[
+                ("old", &old.id),
+                ("new", &new.id),
+                ("build", &new.build_type),
+                ("arch", &new.arch),
+            ],
+        ),
+        scripts(old, new, lang),
+        lang.tr(
+            Msg::DiffTotal,
+            &[
+                ("old", &human_bytes(old_manifest.total_bytes)),
+                ("new", &human_bytes(new_manifest.total_bytes)),
+                (
+                    "delta",
+                    &delta(old_manifest.total_bytes, new_manifest.total_bytes),
+                ),
+            ],
+        ),
+    ];
+
+    let by_key = |artifacts: &[Artifact]| {
+        artifacts
+            .iter()
+            .map(|a| (key(&a.path), a.clone()))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let old_artifacts = by_key(&old_manifest.artifacts);
+    let new_artifacts = by_key(&new_manifest.artifacts);
+
+    let mut changes = vec![];
+    for (k, a) in &old_artifacts {
+        if !new_artifacts.contains_key(k) {
+            changes.push(format!("- {} ({})", a.path, human_bytes(a.size)));
+        }
+    }
+    for (k, b) in &new_artifacts {
+        let Some(a) = old_artifacts.get(k) else {
+            changes.push(format!("+ {} ({})", b.path, human_bytes(b.size)));
+            continue;
+        };
+
+        // unknown to older workers, which is no change
+        let checksum = matches!((&a.sha256, &b.sha256), (Some(x), Some(y)) if x != y);
+        if a.size == b.size && !checksum {
+            continue;
+        }
+
+        let mut line = format!(
+            "~ {}: {} → {} ({})",
+            b.path,
+            human_bytes(a.size),
+            human_bytes(b.size),
+            delta(a.size, b.size)
+        );
+        if checksum {
+            line.push_str(&lang.text(Msg::DiffChecksum));
+        }
+        changes.push(line);
+    }
+
+    if changes.is_empty() {
+        lines.push(lang.text(Msg::DiffSame));
+    } else {
+        lines.push(String::new());
+        lines.extend(changes);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// The commit range of the build scripts with a link to compare them.
+fn scripts(old: &HistoryEntry, new: &HistoryEntry, lang: Lang) -> String {
+    let (Some(a), Some(b)) = (&old.scripts_commit, &new.scripts_commit) else {
+        return lang.text(Msg::DiffScriptsUnknown);
+    };
+    if a == b {
+        return lang.tr(Msg::DiffScriptsSame, &[("commit", &short(a))]);
+    }
+
+    let repo = match old.build_type() {
+        Some(BuildType::Livekit) => MKLIVE_REPO,
+        _ => AOSCBOOTSTRAP_REPO,
+    };
+
+    lang.tr(
+        Msg::DiffScripts,
+        &[
+            ("range", &format!("{}..{}", short(a), short(b))),
+            ("url", &format!("{repo}/compare/{a}..{b}")),
+        ],
+    )
+}
+
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(7)]
+}
+
+/// Signed difference from `old` to `new` bytes, e.g. `+1.2 MiB`.
+fn delta(old: u64, new: u64) -> String {
+    if new >= old {
+        format!("+{}", human_bytes(new - old))
+    } else {
+        format!("-{}", human_bytes(old - new))
+    }
+}
+
+/// `path` with the dates stamped into file names of nightly images and
+/// tarballs masked, so that the same artifact of two days is compared.
+fn key(path: &str) -> String {
+    let mut res = String::new();
+    let mut digits = String::new();
+    for c in path.chars().chain(std::iter::once('\0')) {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        if digits.len() == 8 {
+            res.push_str("YYYYMMDD");
+        } else {
+            res.push_str(&digits);
+        }
+        digits.clear();
+        if c != '\0' {
+            res.push(c);
+        }
+    }
+
+    res
+}
@@ -0,0 +1,411 @@
+//! The daily digest: one message summing up the builds of the last 24 hours
+//! for the chats set up with `/setup digest`, as completion notices get
+//! lost in the scroll of a group. Sent at `shipit_digest_at`, or with
+//! `/digest now`.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use chrono::{Days, NaiveTime, TimeZone};
+use chrono_tz::Tz;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::{
+    db::{now, Db, HistoryEntry},
+    format::{human_age, human_bytes, log_link},
+    freshness::{self, Freshness},
+    lang::{Lang, Msg},
+    outbox::Notification,
+    setup::{self, Role},
+    window::parse_time,
+    AppState,
+};
+
+/// What the digest covers, back from when it is sent.
+const PERIOD: u64 = 24 * 3600;
+/// Failures listed one by one, the rest are counted.
+const MAX_FAILURES: usize = 10;
+/// A digest missed by longer, e.g. while the server was down, is skipped.
+const LATE: u64 = 3600;
+
+/// When the digest is sent every day.
+pub struct Schedule {
+    /// Minutes after local midnight.
+    at: u32,
+    tz: Tz,
+}
+
+impl Schedule {
+    /// `shipit_digest_at`, e.g. `08:00`, in `shipit_digest_tz` (default
+    /// UTC). `None` if unset, the digest is then only sent with `/digest`.
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        let Ok(at) = std::env::var("shipit_digest_at") else {
+            return Ok(None);
+        };
+        let at = parse_time(&at)
+            .filter(|x| *x < 24 * 60)
+            .ok_or_else(|| eyre::eyre!("Invalid shipit_digest_at {at}, expected e.g. 08:00"))?;
+        let tz = match std::env::var("shipit_digest_tz") {
+            Ok(x) => x
+                .parse()
+                .map_err(|_| eyre::eyre!("Unknown shipit_digest_tz {x}"))?,
+            Err(_) => Tz::UTC,
+        };
+
+        Ok(Some(Schedule { at, tz }))
+    }
+
+    /// The unix time of the last scheduled digest at or before `now`.
+    fn last(&self, now: u64) -> Option<u64> {
+        let time = NaiveTime::from_hms_opt(self.at / 60, self.at % 60, 0)?;
+        let today = self.tz.timestamp_opt(now as i64, 0).single()?.date_naive();
+
+        [Some(today), today.checked_sub_days(Days::new(1))]
+            .into_iter()
+            .flatten()
+            .filter_map(|day| {
+                let t = self
+                    .tz
+                    .from_local_datetime(&day.and_time(time))
+                    .earliest()?;
+                u64::try_from(t.timestamp()).ok()
+            })
+            .find(|x| *x <= now)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ArchCount {
+    pub success: u32,
+    pub failed: u32,
+}
+
+#[derive(Debug)]
+pub struct Failed {
+    pub id: i64,
+    pub arch: String,
+    pub build: String,
+    /// Why, if the worker recognized it.
+    pub summary: Option<String>,
+    pub log: Option<String>,
+}
+
+/// The builds finished in `since..until`.
+#[derive(Debug)]
+pub struct Digest {
+    pub builds: u32,
+    pub arches: BTreeMap<String, ArchCount>,
+    /// Bytes of artifacts pushed.
+    pub pushed_bytes: u64,
+    /// The latest failures first, at most [`MAX_FAILURES`].
+    pub failures: Vec<Failed>,
+    /// Failures not in `failures`.
+    pub more_failures: usize,
+    /// Arches and types without a success for longer than the threshold.
+    pub stale: Vec<Freshness>,
+}
+
+/// Sum up `history`, newest first as kept, over `since..until`. Failed
+/// builds link to their build pages under `public_url` if set.
+pub fn compile(
+    history: &[HistoryEntry],
+    freshness: Vec<Freshness>,
+    since: u64,
+    until: u64,
+    public_url: Option<&str>,
+) -> Digest {
+    let mut digest = Digest {
+        builds: 0,
+        arches: BTreeMap::new(),
+        pushed_bytes: 0,
+        failures: vec![],
+        more_failures: 0,
+        stale: freshness.into_iter().filter(|x| x.stale).collect(),
+    };
+
+    for h in history
+        .iter()
+        .filter(|x| since <= x.finished_at && x.finished_at < until)
+    {
+        digest.builds += 1;
+        let count = digest.arches.entry(h.arch.clone()).or_default();
+        if h.success && h.push_success {
+            count.success += 1;
+        } else {
+            count.failed += 1;
+            if digest.failures.len() < MAX_FAILURES {
+                digest.failures.push(Failed {
+                    id: h.id,
+                    arch: h.arch.clone(),
+                    build: h.describe(),
+                    summary: h.failure.as_ref().map(|x| x.summary.clone()),
+                    log: log_link(h, public_url),
+                });
+            } else {
+                digest.more_failures += 1;
+            }
+        }
+        if h.push_success {
+            digest.pushed_bytes += h.manifest.as_ref().map_or(0, |m| m.uploaded_bytes());
+        }
+    }
+
+    digest
+}
+
+/// The message, in plain text.
+pub fn render(digest: &Digest, threshold: u64, lang: Lang) -> String {
+    let mut lines = vec![];
+    if digest.builds == 0 {
+        lines.push(lang.text(Msg::DigestNone));
+    } else {
+        lines.push(lang.tr(
+            Msg::DigestHeader,
+            &[
+                ("builds", &digest.builds),
+                ("pushed", &human_bytes(digest.pushed_bytes)),
+            ],
+        ));
+        for (arch, count) in &digest.arches {
+            lines.push(lang.tr(
+                Msg::DigestArch,
+                &[
+                    ("arch", arch),
+                    ("success", &count.success),
+                    ("failed", &count.failed),
+                ],
+            ));
+        }
+    }
+
+    if !digest.failures.is_empty() {
+        lines.push(String::new());
+        lines.push(lang.text(Msg::DigestFailures));
+        for f in &digest.failures {
+            let log = f
+                .log
+                .clone()
+                .unwrap_or_else(|| lang.text(Msg::LogPushFailed));
+            lines.push(match f.summary {
+                Some(ref summary) => lang.tr(
+                    Msg::DigestFailureSummary,
+                    &[
+                        ("id", &f.id),
+                        ("build", &f.build),
+                        ("arch", &f.arch),
+                        ("summary", summary),
+                        ("log", &log),
+                    ],
+                ),
+                None => lang.tr(
+                    Msg::DigestFailure,
+                    &[
+                        ("id", &f.id),
+                        ("build", &f.build),
+                        ("arch", &f.arch),
+                        ("log", &log),
+                    ],
+                ),
+            });
+        }
+        if digest.more_failures > 0 {
+            lines.push(lang.tr(Msg::DigestMoreFailures, &[("count", &digest.more_failures)]));
+        }
+    }
+
+    if !digest.stale.is_empty() {
+        lines.push(String::new());
+        lines.push(lang.tr(Msg::DigestStale, &[("threshold", &human_age(threshold))]));
+        for f in &digest.stale {
+            lines.push(lang.tr(
+                Msg::FreshnessLine,
+                &[
+                    ("mark", &""),
+                    ("arch", &f.arch),
+                    ("build", &f.build_type),
+                    ("ago", &human_age(f.age_secs)),
+                ],
+            ));
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// The digest of the day up to `now`.
+pub async fn latest(db: &mut Db, state: &AppState, now: u64) -> eyre::Result<Digest> {
+    Ok(compile(
+        &db.history().await?,
+        freshness::list(db, now, state.freshness_threshold).await?,
+        now.saturating_sub(PERIOD),
+        now,
+        state.public_url.as_deref(),
+    ))
+}
+
+/// Queue the digest of the last day for every chat set up for it, in the
+/// language of each. Returns the chats.
+pub async fn send(db: &mut Db, state: &AppState) -> eyre::Result<Vec<i64>> {
+    let now = now();
+    let digest = latest(db, state, now).await?;
+
+    let mut chats = setup::chats(db, Role::Digest, None).await?;
+    chats.sort_unstable();
+    chats.dedup();
+    for chat in &chats {
+        let text = render(&digest, state.freshness_threshold, db.lang(*chat).await?);
+        db.push_outbox(&Notification::plain(*chat, &text)).await?;
+    }
+    db.set_digest_sent_at(now).await?;
+
+    Ok(chats)
+}
+
+/// Send the digest once a day at the time of `schedule`, unless it was sent
+/// since.
+pub async fn run(state: Arc<AppState>, schedule: Schedule) {
+    loop {
+        let mut db = state.db().await;
+        if let Err(e) = tick(&mut db, &state, &schedule).await {
+            error!("Failed to send the digest: {e}");
+        }
+        drop(db);
+
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+async fn tick(db: &mut Db, state: &AppState, schedule: &Schedule) -> eyre::Result<()> {
+    let now = now();
+    let Some(due) = schedule.last(now) else {
+        return Ok(());
+    };
+    if now - due > LATE || db.digest_sent_at().await?.is_some_and(|x| x >= due) {
+        return Ok(());
+    }
+
+    let chats = send(db, state).await?;
+    info!("Sent the digest to {} chats", chats.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::entry;
+
+    const BASE: u64 = 1_700_000_000;
+
+    /// Newest first as kept: #1 finished before the day, #2 to #5 in it.
+    fn history() -> Vec<HistoryEntry> {
+        let old = entry(1, "amd64", "livekit", true, 600);
+        let mut pushed = entry(2, "amd64", "livekit", true, 600);
+        pushed.manifest = Some(
+            serde_json::from_value(serde_json::json!({
+                "artifacts": [],
+                "total_bytes": 3 * 1024 * 1024 * 1024u64,
+                "upload_secs": 60.0,
+            }))
+            .unwrap(),
+        );
+        let mut timeout = entry(3, "arm64", "release", false, 600);
+        timeout.log_url = Some("https://repo.aosc.io/logs/3.txt".to_string());
+        timeout.failure = Some(shipit_common::Failure {
+            class: "mirror_timeout".to_string(),
+            summary: "mirror timeout".to_string(),
+            excerpt: String::new(),
+            line: None,
+        });
+        let unknown = entry(4, "arm64", "livekit", false, 600);
+        let mut unpushed = entry(5, "amd64", "livekit", true, 600);
+        unpushed.push_success = false;
+        unpushed.log_url = Some("https://repo.aosc.io/logs/5.txt".to_string());
+
+        vec![unpushed, unknown, timeout, pushed, old]
+    }
+
+    fn freshness() -> Vec<Freshness> {
+        let f = |arch: &str, days: u64| Freshness {
+            arch: arch.to_string(),
+            build_type: "livekit".to_string(),
+            last_success: BASE - days * 86400,
+            age_secs: days * 86400,
+            stale: days > 7,
+        };
+
+        vec![f("amd64", 1), f("riscv64", 10)]
+    }
+
+    fn digest() -> Digest {
+        compile(
+            &history(),
+            freshness(),
+            BASE + 2 * 3600,
+            BASE + 2 * 3600 + PERIOD,
+            None,
+        )
+    }
+
+    #[test]
+    fn snapshot_en() {
+        assert_eq!(
+            render(&digest(), 7 * 86400, Lang::En),
+            "\
+Last 24 hours: 4 builds, 3.0 GiB pushed
+amd64: 1 succeeded, 1 failed
+arm64: 0 succeeded, 2 failed
+
+Failures:
+#5 livekit on amd64: https://repo.aosc.io/logs/5.txt
+#4 livekit on arm64: Failed to push log
+#3 release(default set) on arm64, mirror timeout: https://repo.aosc.io/logs/3.txt
+
+No success for longer than 7d0h:
+riscv64 livekit: 10d0h ago"
+        );
+    }
+
+    #[test]
+    fn snapshot_zh() {
+        assert_eq!(
+            render(&digest(), 7 * 86400, Lang::Zh),
+            "\
+过去 24 小时：4 次构建，推送 3.0 GiB
+amd64：1 次成功，1 次失败
+arm64：0 次成功，2 次失败
+
+失败的构建：
+#5 amd64 上的 livekit：https://repo.aosc.io/logs/5.txt
+#4 arm64 上的 livekit：日志上传失败
+#3 arm64 上的 release(default set)，mirror timeout：https://repo.aosc.io/logs/3.txt
+
+超过 7d0h 未成功构建：
+riscv64 livekit：10d0h 前"
+        );
+    }
+
+    #[test]
+    fn snapshot_of_a_quiet_day() {
+        let digest = compile(&history(), vec![], BASE + 10 * 3600, BASE + 11 * 3600, None);
+
+        assert_eq!(
+            render(&digest, 7 * 86400, Lang::En),
+            "No build finished in the last 24 hours."
+        );
+    }
+
+    #[test]
+    fn failures_past_the_tenth_are_counted() {
+        let history = (1..=12)
+            .rev()
+            .map(|id| entry(id, "amd64", "livekit", false, 600))
+            .collect::<Vec<_>>();
+        let digest = compile(&history, vec![], BASE, BASE + PERIOD, None);
+        let text = render(&digest, 7 * 86400, Lang::En);
+
+        assert_eq!(digest.failures.len(), MAX_FAILURES);
+        assert_eq!(digest.failures[0].id, 12);
+        assert!(text.ends_with("#3 livekit on amd64: Failed to push log\nand 2 more"));
+    }
+}
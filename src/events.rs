@@ -0,0 +1,146 @@
+//! Build events on the Redis stream `shipit:events`, for tools that react
+//! to builds without polling the API. Each entry has one field, `event`,
+//! holding JSON such as
+//!
+//! ```json
+//! {"kind": "done", "id": 42, "at": 1714521600, "pool": "mainline",
+//!  "arch": "amd64", "build_type": {"name": "livekit"}, "worker": "w1",
+//!  "success": false, "push_success": false,
+//!  "log_url": "https://...", "failure": "mirror_timeout"}
+//! ```
+//!
+//! `kind` is one of `enqueued`, `claimed`, `progress` (the worker reported
+//! a new `stage`), `done`, `cancelled` and `expired`. `worker` is set from
+//! `claimed` on, `success`, `push_success`, `log_url` and `failure` (the
+//! class the worker recognized) only for `done`; unset fields are left out.
+//!
+//! The stream is trimmed to about `shipit_events_len` entries (default
+//! 10000, 0 to publish none). Publishing is best effort, a failure is
+//! logged and counted as `shipit_events_dropped_total` on `/metrics`, and
+//! never fails what the event is about. `shipit events tail [group]
+//! [consumer]` follows the stream in a consumer group, as a consumer would.
+
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::db::{now, Build, BuildType, Db, HistoryEntry};
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Enqueued,
+    Claimed,
+    Progress,
+    Done,
+    Cancelled,
+    Expired,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Enqueued => "enqueued",
+            Kind::Claimed => "claimed",
+            Kind::Progress => "progress",
+            Kind::Done => "done",
+            Kind::Cancelled => "cancelled",
+            Kind::Expired => "expired",
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Event {
+    pub kind: Kind,
+    pub id: i64,
+    /// UNIX seconds.
+    pub at: u64,
+    pub pool: String,
+    pub arch: String,
+    pub build_type: BuildType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub success: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push_success: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+}
+
+impl Event {
+    pub fn new(kind: Kind, b: &Build) -> Self {
+        Self {
+            kind,
+            id: b.id,
+            at: now(),
+            pool: b.pool.clone(),
+            arch: b.arch.clone(),
+            build_type: b.build_type.clone(),
+            worker: None,
+            stage: None,
+            success: None,
+            push_success: None,
+            log_url: None,
+            failure: None,
+        }
+    }
+
+    pub fn with_worker(mut self, worker: Option<&str>) -> Self {
+        self.worker = worker.map(|x| x.to_string());
+        self
+    }
+
+    /// The `done` event of `h`, built as `build_type`.
+    pub fn done(h: &HistoryEntry, build_type: &BuildType) -> Self {
+        Self {
+            kind: Kind::Done,
+            id: h.id,
+            at: h.finished_at,
+            pool: h.pool.clone(),
+            arch: h.arch.clone(),
+            build_type: build_type.clone(),
+            worker: h.worker.clone(),
+            stage: None,
+            success: Some(h.success),
+            push_success: Some(h.push_success),
+            log_url: h.log_url.clone(),
+            failure: h.failure.as_ref().map(|x| x.class.clone()),
+        }
+    }
+}
+
+/// The publishing failures, for `/metrics`.
+pub fn metrics(dropped: u64) -> String {
+    format!(
+        "# HELP shipit_events_dropped_total Build events that could not be published.\n\
+         # TYPE shipit_events_dropped_total counter\n\
+         shipit_events_dropped_total {dropped}\n"
+    )
+}
+
+/// `shipit events tail [group] [consumer]`: print the events as they come,
+/// read in consumer group `group` (default `shipit-tail`) as `consumer`
+/// (default `tail`) and acknowledged once printed.
+pub async fn tail(db: &mut Db, group: &str, consumer: &str) -> eyre::Result<()> {
+    db.create_event_group(group).await?;
+    println!("Following the events as {consumer} of group {group}");
+
+    loop {
+        for (id, event) in db.read_events(group, consumer).await? {
+            println!("{id} {event}");
+            db.ack_event(group, &id).await?;
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Drop queued builds that have waited too long, e.g. because the worker of
+//! their arch went away, or the worker they are pinned to, see
+//! `shipit_affinity_timeout`.
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::{
+    db::{now, Db},
+    events::Kind,
+    format::human_duration,
+    lang::Msg,
+    outbox::{cancel_dependents, Notification},
+    AppState,
+};
+
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        let mut db = state.db().await;
+        if let Err(e) = expire(&mut db, &state).await {
+            error!("Failed to expire queued builds: {e}");
+        }
+        drop(db);
+
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+async fn expire(db: &mut Db, state: &AppState) -> eyre::Result<()> {
+    let now = now();
+
+    let mut queues = vec![];
+    for p in state.pools.iter() {
+        queues.extend(p.archs.iter().map(|a| (&p.name, a)));
+    }
+
+    for (pool, arch) in queues {
+        for b in db.queue(pool, arch).await? {
+            let max_age = b.expire_secs.unwrap_or(state.queue_max_age);
+            let Some(queued_at) = b.queued_at else {
+                continue;
+            };
+
+            let age = now.saturating_sub(queued_at);
+            // the worker holding what it needs may be gone for good
+            let pinned = b.worker.as_ref().filter(|_| age >= state.affinity_timeout);
+            if age < max_age && pinned.is_none() {
+                continue;
+            }
+
+            // claimed or cancelled in the meantime
+            if db
+                .remove_queued(pool, arch, b.id, Kind::Expired)
+                .await?
+                .is_none()
+            {
+                continue;
+            }
+
+            let lang = db.lang(b.requester_chat).await?;
+            let (text, action) = match pinned {
+                Some(worker) => {
+                    info!("Queued build #{} on {arch} was not taken by {worker}", b.id);
+                    let text = lang.tr(
+                        Msg::AffinityExpired,
+                        &[
+                            ("arch", arch),
+                            ("build", &b.build_type),
+                            ("id", &b.id),
+                            ("worker", worker),
+                            ("age", &human_duration(state.affinity_timeout)),
+                        ],
+                    );
+                    (
+                        text,
+                        format!("expired #{} on {arch}, pinned to {worker}", b.id),
+                    )
+                }
+                None => {
+                    info!("Queued build #{} on {} expired", b.id, arch);
+                    let text = lang.tr(
+                        Msg::RequestExpired,
+                        &[
+                            ("arch", arch),
+                            ("build", &b.build_type),
+                            ("id", &b.id),
+                            ("age", &human_duration(max_age)),
+                        ],
+                    );
+                    (text, format!("expired #{} on {}", b.id, arch))
+                }
+            };
+
+            db.audit("shipit", &action).await?;
+            db.push_outbox(&Notification::plain(b.requester_chat, &text))
+                .await?;
+            cancel_dependents(db, pool, arch, b.id, Msg::DependencyExpired).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::BuildType,
+        testing::{build, redis},
+    };
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn pinned_builds_expire_once_their_worker_let_them_wait() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        let queued = |id, worker: Option<&str>, hours: u64| {
+            let mut b = build(id, "amd64", BuildType::Livekit);
+            b.worker = worker.map(|x| x.to_string());
+            b.queued_at = Some(now() - hours * 3600);
+            b
+        };
+        let builds = [
+            queued(1, Some("w1"), 7),
+            queued(2, None, 7),
+            queued(3, Some("w1"), 1),
+            queued(4, None, 8 * 24),
+        ];
+        db.enqueue_all(&builds.iter().collect::<Vec<_>>())
+            .await
+            .unwrap();
+
+        expire(&mut db, &server.state).await.unwrap();
+
+        let left = db.queue("mainline", "amd64").await.unwrap();
+        assert_eq!(left.iter().map(|x| x.id).collect::<Vec<_>>(), [2, 3]);
+        let pinned = db.pop_outbox().await.unwrap().unwrap();
+        assert_eq!(pinned.chat, 1);
+        assert_eq!(
+            pinned.text,
+            "Your amd64 livekit request #1 failed: only w1 has what it needs, \
+             and it did not take it within 6h0m. Request a fresh build instead"
+        );
+        let old = db.pop_outbox().await.unwrap().unwrap();
+        assert!(old.text.contains("#4"), "{}", old.text);
+        assert!(db.pop_outbox().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,223 @@
+//! Atom feed of finished builds, for those who would rather follow releases
+//! in a feed reader than in a chat. Mainline builds unless `?pool=` names
+//! another pool.
+
+use chrono::{TimeZone, Utc};
+
+use crate::{db::HistoryEntry, format::completion_text, lang::Lang, pool::MAINLINE};
+
+/// Entries in the feed, newest first.
+pub const FEED_LEN: usize = 100;
+
+/// `release(base, desktop) amd64 ✅`
+fn title(entry: &HistoryEntry) -> String {
+    format!(
+        "{} {} {}",
+        entry.describe(),
+        entry.arch,
+        if entry.success && entry.push_success {
+            "✅"
+        } else {
+            "❌"
+        }
+    )
+}
+
+fn rfc3339(time: u64) -> String {
+    Utc.timestamp_opt(time as i64, 0)
+        .single()
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+/// Escape text for XML element content and attribute values.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The feed of `history` of `pool`, which is ordered newest first.
+pub fn atom(history: &[HistoryEntry], pool: &str) -> String {
+    let history = &history[..history.len().min(FEED_LEN)];
+    let updated = history.first().map(|x| x.finished_at).unwrap_or(0);
+
+    let mut s = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    s.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    // as it was before pools, for the readers already following it
+    if pool == MAINLINE {
+        s.push_str("  <id>urn:shipit:builds</id>\n");
+        s.push_str("  <title>shipit builds</title>\n");
+    } else {
+        s.push_str(&format!("  <id>urn:shipit:builds:{}</id>\n", escape(pool)));
+        s.push_str(&format!(
+            "  <title>shipit builds of {}</title>\n",
+            escape(pool)
+        ));
+    }
+    s.push_str(&format!("  <updated>{}</updated>\n", rfc3339(updated)));
+    s.push_str("  <author><name>shipit</name></author>\n");
+
+    for entry in history {
+        s.push_str("  <entry>\n");
+        s.push_str(&format!("    <id>urn:shipit:build:{}</id>\n", entry.id));
+        s.push_str(&format!("    <title>{}</title>\n", escape(&title(entry))));
+        s.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            rfc3339(entry.finished_at)
+        ));
+
+        if let Some(ref url) = entry.log_url {
+            s.push_str(&format!(
+                "    <link rel=\"alternate\" title=\"log\" href=\"{}\"/>\n",
+                escape(url)
+            ));
+        }
+
+        for a in entry.manifest.iter().flat_map(|m| &m.artifacts) {
+            if let Some(ref url) = a.url {
+                s.push_str(&format!(
+                    "    <link rel=\"related\" title=\"{}\" href=\"{}\"/>\n",
+                    escape(&a.path),
+                    escape(url)
+                ));
+            }
+        }
+
+        s.push_str(&format!(
+            "    <content type=\"text\">{}</content>\n",
+            escape(&completion_text(entry, Lang::En, None, "", ""))
+        ));
+        s.push_str("  </entry>\n");
+    }
+
+    s.push_str("</feed>\n");
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use roxmltree::{Document, Node};
+
+    use super::*;
+    use crate::testing::entry;
+
+    const ATOM: &str = "http://www.w3.org/2005/Atom";
+
+    /// An Atom element: its id, title and updated, and its links as
+    /// `(rel, href)`.
+    #[derive(Debug, PartialEq)]
+    struct Element {
+        id: String,
+        title: String,
+        updated: String,
+        links: Vec<(String, String)>,
+    }
+
+    impl Element {
+        fn of(node: Node) -> Self {
+            let text = |name| {
+                let mut found = node.children().filter(|x| x.has_tag_name((ATOM, name)));
+                let text = found.next().unwrap().text().unwrap_or_default().to_string();
+                assert!(found.next().is_none(), "more than one {name}");
+                text
+            };
+
+            Self {
+                id: text("id"),
+                title: text("title"),
+                updated: text("updated"),
+                links: node
+                    .children()
+                    .filter(|x| x.has_tag_name((ATOM, "link")))
+                    .map(|x| {
+                        (
+                            x.attribute("rel").unwrap().to_string(),
+                            x.attribute("href").unwrap().to_string(),
+                        )
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    /// The feed parsed as XML, and its entries in order.
+    fn parse(feed: &str) -> (Element, Vec<Element>) {
+        let doc = Document::parse(feed).unwrap();
+        let root = doc.root_element();
+        assert!(root.has_tag_name((ATOM, "feed")));
+        let entries = root
+            .children()
+            .filter(|x| x.has_tag_name((ATOM, "entry")))
+            .map(Element::of)
+            .collect();
+
+        (Element::of(root), entries)
+    }
+
+    #[test]
+    fn entries_are_newest_first_and_escaped() {
+        let mut failed = entry(2, "amd64", "livekit", false, 600);
+        failed.log_url = Some("https://buildit.aosc.io/logs?id=2&raw=1".to_string());
+        let mut ok = entry(1, "arm64", "livekit", true, 600);
+        ok.log_url = Some("https://buildit.aosc.io/logs/1.log".to_string());
+        let history = [failed, ok];
+
+        let (feed, entries) = parse(&atom(&history, MAINLINE));
+        assert_eq!(
+            feed,
+            Element {
+                id: "urn:shipit:builds".to_string(),
+                title: "shipit builds".to_string(),
+                updated: "2023-11-15T00:13:20+00:00".to_string(),
+                links: vec![],
+            }
+        );
+        let log = |url: &str| vec![("alternate".to_string(), url.to_string())];
+        assert_eq!(
+            entries,
+            [
+                Element {
+                    id: "urn:shipit:build:2".to_string(),
+                    title: "livekit amd64 ❌".to_string(),
+                    updated: "2023-11-15T00:13:20+00:00".to_string(),
+                    links: log("https://buildit.aosc.io/logs?id=2&raw=1"),
+                },
+                Element {
+                    id: "urn:shipit:build:1".to_string(),
+                    title: "livekit arm64 ✅".to_string(),
+                    updated: "2023-11-14T23:13:20+00:00".to_string(),
+                    links: log("https://buildit.aosc.io/logs/1.log"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn other_pools_have_a_feed_of_their_own() {
+        let (feed, entries) = parse(&atom(&[], "<staging> & 'co'"));
+        assert_eq!(feed.id, "urn:shipit:builds:<staging> & 'co'");
+        assert_eq!(feed.title, "shipit builds of <staging> & 'co'");
+        assert_eq!(feed.updated, "1970-01-01T00:00:00+00:00");
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_entries_are_kept() {
+        let history = (0..FEED_LEN as i64 + 5)
+            .rev()
+            .map(|id| entry(id, "amd64", "livekit", true, 600))
+            .collect::<Vec<_>>();
+
+        let (_, entries) = parse(&atom(&history, MAINLINE));
+        assert_eq!(entries.len(), FEED_LEN);
+        assert_eq!(
+            entries.first().unwrap().id,
+            format!("urn:shipit:build:{}", FEED_LEN + 4)
+        );
+        assert_eq!(entries.last().unwrap().id, "urn:shipit:build:5");
+    }
+}
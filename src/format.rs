@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+
+use chrono::{Local, TimeZone};
+pub use shipit_common::human_duration;
+use teloxide::utils::html;
+
+use crate::{
+    db::{Channel, ChecksumSource, HistoryEntry, Manifest},
+    lang::{Lang, Msg},
+    stats::Estimate,
+};
+
+/// Human readable byte count, e.g. `4.2 GiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Like [`human_duration`], in days and hours from a day on, e.g. `12d3h`.
+pub fn human_age(secs: u64) -> String {
+    if secs < 24 * 3600 {
+        return human_duration(secs);
+    }
+
+    format!("{}d{}h", secs / (24 * 3600), secs % (24 * 3600) / 3600)
+}
+
+/// Parse durations like `90s`, `30m`, `24h` or `7d` into seconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 24 * 3600,
+        _ => return None,
+    };
+
+    s[..s.len() - 1].parse::<u64>().ok().map(|x| x * unit)
+}
+
+/// One line summary of a manifest, followed by one line per variant if the
+/// artifacts carry variant information.
+pub fn manifest_summary(manifest: &Manifest, lang: Lang) -> String {
+    let mut s = lang.tr(
+        Msg::ManifestFiles,
+        &[
+            ("count", &manifest.artifacts.len()),
+            ("size", &human_bytes(manifest.total_bytes)),
+        ],
+    );
+
+    if manifest.upload_secs > 0.0 {
+        s.push_str(&lang.tr(
+            Msg::ManifestUpload,
+            &[
+                ("duration", &human_duration(manifest.upload_secs as u64)),
+                (
+                    "rate",
+                    &human_bytes((manifest.uploaded_bytes() as f64 / manifest.upload_secs) as u64),
+                ),
+            ],
+        ));
+    }
+
+    let mut variants: BTreeMap<(&str, Option<i64>), (usize, u64)> = BTreeMap::new();
+    for a in &manifest.artifacts {
+        if let Some(ref v) = a.variant {
+            let e = variants.entry((v, a.build)).or_default();
+            e.0 += 1;
+            e.1 += a.size;
+        }
+    }
+
+    for ((v, build), (count, size)) in variants {
+        s.push_str("\n  ");
+        s.push_str(&lang.tr(
+            Msg::ManifestVariant,
+            &[
+                ("variant", &v),
+                ("count", &count),
+                ("size", &human_bytes(size)),
+            ],
+        ));
+        // kept by a resumed release
+        if let Some(id) = build {
+            s.push_str(&lang.tr(Msg::ManifestFrom, &[("id", &id)]));
+        }
+    }
+
+    for a in &manifest.artifacts {
+        if a.checksum == Some(ChecksumSource::Worker) {
+            s.push_str("\n  ");
+            s.push_str(&lang.tr(Msg::WorkerChecksum, &[("path", &a.path)]));
+        }
+    }
+
+    s
+}
+
+/// Where to read the log of `entry`: its build page if the server is
+/// reachable at `public_url`, else the raw log. `None` if it was not pushed.
+pub fn log_link(entry: &HistoryEntry, public_url: Option<&str>) -> Option<String> {
+    let url = entry.log_url.as_ref()?;
+
+    Some(match public_url {
+        Some(base) => format!("{base}/builds/{}/view", entry.id),
+        None => url.clone(),
+    })
+}
+
+/// The plain text telling the requester how a build went, led by why it
+/// failed if the worker recognized it. The failure texts are appended to the
+/// log and push lines, see `RetryOutcome`.
+pub fn completion_text(
+    entry: &HistoryEntry,
+    lang: Lang,
+    public_url: Option<&str>,
+    log_push_failure: &str,
+    push_failure: &str,
+) -> String {
+    let mut s = lang.tr(
+        Msg::Completion,
+        &[
+            ("id", &entry.id),
+            ("build", &entry.describe()),
+            (
+                "channel",
+                &match entry.channel {
+                    Channel::Release => String::new(),
+                    c => format!(" [{c}]"),
+                },
+            ),
+            (
+                "result",
+                &lang.text(if entry.success {
+                    Msg::Success
+                } else {
+                    Msg::HasError
+                }),
+            ),
+            ("arch", &entry.arch),
+            (
+                "log",
+                &log_link(entry, public_url).unwrap_or_else(|| lang.text(Msg::LogPushFailed)),
+            ),
+            ("log_failure", &log_push_failure),
+            ("push", &entry.push_success),
+            ("push_failure", &push_failure),
+        ],
+    );
+
+    if let Some(ref note) = entry.note {
+        s.push('\n');
+        s.push_str(&lang.tr(Msg::CompletionNote, &[("note", note)]));
+    }
+
+    if let Some(id) = entry.manifest.as_ref().and_then(|m| m.incremental_from) {
+        s.push('\n');
+        s.push_str(&lang.tr(Msg::CompletionIncremental, &[("id", &id)]));
+    }
+
+    s.push('\n');
+    s.push_str(&lang.tr(
+        Msg::CompletionIntervals,
+        &[("intervals", &intervals_text(entry, lang))],
+    ));
+
+    // only set when something failed
+    if let Some(ref f) = entry.failure {
+        s.insert(0, '\n');
+        s.insert_str(
+            0,
+            &lang.tr(Msg::CompletionFailed, &[("summary", &f.summary)]),
+        );
+    }
+
+    // above all, whatever the result
+    if let Some(ref x) = entry.stale_scripts {
+        s.insert(0, '\n');
+        s.insert_str(
+            0,
+            &lang.tr(
+                Msg::StaleScripts,
+                &[
+                    ("behind", &x.behind),
+                    ("age", &human_duration(x.age_secs)),
+                    ("remote", &x.remote),
+                ],
+            ),
+        );
+    }
+
+    if let Some(p) = passthrough_text(&entry.env, &entry.args) {
+        s.push('\n');
+        s.push_str(&lang.tr(Msg::RequestedWith, &[("passthrough", &p)]));
+    }
+
+    if let Some(id) = entry.resumed_from {
+        let kept = entry
+            .built_variants
+            .iter()
+            .filter(|x| !entry.variants.iter().flatten().any(|v| v == *x))
+            .cloned()
+            .collect::<Vec<_>>();
+        s.push('\n');
+        s.push_str(&lang.tr(
+            Msg::ResumedFrom,
+            &[("id", &id), ("variants", &kept.join(" "))],
+        ));
+    }
+
+    let unpushed = entry
+        .artifact_pushes
+        .iter()
+        .filter(|x| !x.success)
+        .map(|x| x.path.as_str())
+        .collect::<Vec<_>>();
+    if !unpushed.is_empty() {
+        s.push('\n');
+        s.push_str(&lang.tr(
+            Msg::PartialPush,
+            &[
+                ("pushed", &(entry.artifact_pushes.len() - unpushed.len())),
+                ("total", &entry.artifact_pushes.len()),
+                ("failed", &unpushed.join(" ")),
+            ],
+        ));
+    }
+
+    if !entry.failed_artifacts.is_empty() {
+        s.push('\n');
+        s.push_str(
+            &lang.tr(
+                Msg::KeptArtifacts,
+                &[
+                    ("count", &entry.failed_artifacts.len()),
+                    (
+                        "worker",
+                        &entry
+                            .worker
+                            .clone()
+                            .unwrap_or_else(|| lang.text(Msg::TheWorker)),
+                    ),
+                    ("id", &entry.id),
+                ],
+            ),
+        );
+    }
+
+    if let Some(p) = phase_summary(&entry.phase_durations) {
+        s.push('\n');
+        s.push_str(&lang.tr(Msg::PhaseTime, &[("phases", &p)]));
+    }
+
+    if let Some(ref m) = entry.manifest {
+        s.push('\n');
+        s.push_str(&manifest_summary(m, lang));
+    }
+
+    s
+}
+
+/// Variables and arguments as they are passed to the script, e.g.
+/// `MIRROR=https://mirror.example --no-memtest`. `None` if there are none.
+pub fn passthrough_text(env: &BTreeMap<String, String>, args: &[String]) -> Option<String> {
+    let v = env
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .chain(args.iter().cloned())
+        .collect::<Vec<_>>();
+
+    (!v.is_empty()).then(|| v.join(" "))
+}
+
+/// How long `entry` waited in the queue, built and published, e.g. `queued
+/// 2m, built in 1h3m, published in 9m`. Builds finished before the worker
+/// reported when the build script exited show `n/a` for the unknown ones.
+pub fn intervals_text(entry: &HistoryEntry, lang: Lang) -> String {
+    let intervals = entry.intervals();
+    let text = |secs: Option<u64>| match secs {
+        Some(secs) => human_duration(secs),
+        None => lang.text(Msg::IntervalUnknown),
+    };
+
+    lang.tr(
+        Msg::Intervals,
+        &[
+            ("queued", &text(intervals.queued)),
+            ("build", &text(intervals.build)),
+            ("publish", &text(intervals.publish)),
+        ],
+    )
+}
+
+/// Where the time went, e.g. `git 12s, build 52m, upload 9m`, in the order
+/// the phases happen.
+pub fn phase_summary(phases: &BTreeMap<String, u64>) -> Option<String> {
+    const ORDER: &[&str] = &["git", "build", "checksum", "upload", "prune", "log_upload"];
+
+    let mut names = phases.keys().collect::<Vec<_>>();
+    names.sort_by_key(|x| ORDER.iter().position(|o| o == x).unwrap_or(ORDER.len()));
+
+    let s = names
+        .iter()
+        .map(|x| format!("{} {}", x.replace('_', " "), human_duration(phases[*x])))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    (!s.is_empty()).then_some(s)
+}
+
+/// Whether an artifact is worth a download link: images, tarballs and their
+/// checksums and signatures, not every file of the tree.
+fn is_download(path: &str) -> bool {
+    [
+        ".iso",
+        ".squashfs",
+        ".sha256sum",
+        ".sig",
+        ".asc",
+        ".torrent",
+    ]
+    .iter()
+    .any(|x| path.ends_with(x))
+        || path.contains(".tar.")
+}
+
+/// HTML list of download links of `manifest`, or `None` if the worker did
+/// not know where its uploads are served.
+pub fn download_links(manifest: &Manifest, lang: Lang) -> Option<String> {
+    let links = manifest
+        .artifacts
+        .iter()
+        .filter(|a| is_download(&a.path))
+        .filter_map(|a| {
+            let url = a.url.as_ref()?;
+            let name = a.path.rsplit('/').next().unwrap_or(&a.path);
+            Some(html::link(url, &html::escape(name)))
+        })
+        .collect::<Vec<_>>();
+
+    if links.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "{}\n{}",
+        lang.text(if manifest.private {
+            Msg::DownloadsPrivate
+        } else {
+            Msg::Downloads
+        }),
+        links.join("\n")
+    ))
+}
+
+/// `estimated start in ~45m, estimated completion ~16:30`, or a note that
+/// there is not enough history to tell.
+pub fn estimate_text(estimate: Option<&Estimate>, now: u64, lang: Lang) -> String {
+    let Some(e) = estimate else {
+        return lang.text(Msg::NoEstimate);
+    };
+
+    let finish = Local
+        .timestamp_opt((now + e.finish_in) as i64, 0)
+        .single()
+        .map(|x| x.format("%H:%M").to_string())
+        .unwrap_or_else(|| "?".to_string());
+
+    lang.tr(
+        Msg::Estimate,
+        &[("start", &human_duration(e.start_in)), ("finish", &finish)],
+    )
+}
+
+/// Levenshtein distance between two strings.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::entry;
+
+    #[test]
+    fn estimate_without_history() {
+        assert_eq!(estimate_text(None, 0, Lang::En), "no estimate available");
+    }
+
+    #[test]
+    fn estimate_start() {
+        let e = Estimate {
+            start_in: 45 * 60,
+            finish_in: 2 * 3600,
+        };
+
+        assert!(estimate_text(Some(&e), 0, Lang::En).starts_with("estimated start in ~45m,"));
+    }
+
+    #[test]
+    fn intervals_of_old_builds_are_not_available() {
+        use crate::lifecycle::{Phase, Transition};
+
+        let mut h = entry(1, "amd64", "livekit", true, 4500);
+        assert_eq!(
+            intervals_text(&h, Lang::En),
+            "queued n/a, built in n/a, published in n/a"
+        );
+
+        h.transitions = [
+            (Phase::Queued, 100),
+            (Phase::Claimed, 220),
+            (Phase::Running, 230),
+            (Phase::Publishing, 3760),
+            (Phase::Succeeded, 4360),
+        ]
+        .map(|(phase, at)| Transition { phase, at })
+        .to_vec();
+        assert_eq!(
+            intervals_text(&h, Lang::En),
+            "queued 2m, built in 59m, published in 10m"
+        );
+        assert_eq!(intervals_text(&h, Lang::Zh), "排队 2m，构建 59m，发布 10m");
+    }
+}
@@ -0,0 +1,111 @@
+//! When each arch last shipped each type of build, built and pushed, for
+//! alerts like "no successful amd64 livekit in 10 days": the gauge on
+//! `/metrics`, `GET /api/v1/freshness` and `/freshness`.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::{
+    db::{Db, HistoryEntry},
+    format::human_age,
+    lang::{Lang, Msg},
+};
+
+#[derive(Debug, Serialize)]
+pub struct Freshness {
+    pub arch: String,
+    #[serde(rename = "type")]
+    pub build_type: String,
+    /// UNIX seconds the build finished at.
+    pub last_success: u64,
+    pub age_secs: u64,
+    /// Older than the threshold of `shipit_freshness_threshold`.
+    pub stale: bool,
+}
+
+/// Whether `h` counts: built and pushed, and not only a repush.
+fn counts(h: &HistoryEntry) -> bool {
+    h.success && h.push_success && h.build_type().is_some()
+}
+
+/// Record `h` if it counts and is the latest of its arch and type.
+pub async fn record(db: &mut Db, h: &HistoryEntry) -> eyre::Result<()> {
+    if counts(h) {
+        db.set_last_success(&h.arch, &h.build_type, h.finished_at)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Catch up with the history, for builds finished before this was kept.
+pub async fn init(db: &mut Db) -> eyre::Result<()> {
+    for h in db.history().await? {
+        record(db, &h).await?;
+    }
+
+    Ok(())
+}
+
+/// Every arch and type that ever succeeded, stale if older than
+/// `threshold` seconds at `now`.
+pub async fn list(db: &mut Db, now: u64, threshold: u64) -> eyre::Result<Vec<Freshness>> {
+    Ok(db
+        .last_successes()
+        .await?
+        .into_iter()
+        .map(|((arch, build_type), last_success)| {
+            let age_secs = now.saturating_sub(last_success);
+            Freshness {
+                arch,
+                build_type,
+                last_success,
+                age_secs,
+                stale: age_secs > threshold,
+            }
+        })
+        .collect())
+}
+
+/// The gauge in the Prometheus text format.
+pub fn metrics(list: &[Freshness]) -> String {
+    let mut s = String::from(
+        "# HELP shipit_last_success_timestamp UNIX time the last build of an arch and type was built and pushed.\n\
+         # TYPE shipit_last_success_timestamp gauge\n",
+    );
+    for f in list {
+        let _ = writeln!(
+            s,
+            "shipit_last_success_timestamp{{arch=\"{}\",type=\"{}\"}} {}",
+            f.arch, f.build_type, f.last_success
+        );
+    }
+
+    s
+}
+
+/// One line each, the stale ones marked.
+pub fn render(list: &[Freshness], threshold: u64, lang: Lang) -> String {
+    if list.is_empty() {
+        return lang.text(Msg::FreshnessNone);
+    }
+
+    let mut lines = vec![lang.tr(
+        Msg::FreshnessHeader,
+        &[("threshold", &human_age(threshold))],
+    )];
+    for f in list {
+        lines.push(lang.tr(
+            Msg::FreshnessLine,
+            &[
+                ("mark", &if f.stale { "⚠️ " } else { "" }),
+                ("arch", &f.arch),
+                ("build", &f.build_type),
+                ("ago", &human_age(f.age_secs)),
+            ],
+        ));
+    }
+
+    lines.join("\n")
+}
@@ -0,0 +1,194 @@
+//! Actions fired after a successful build, such as poking the downloads page
+//! generator. Hooks are stored in Redis and managed with `/hook`.
+
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use teloxide::utils::html;
+use tokio::time::sleep;
+use tracing::{error, warn};
+
+use crate::{db::HistoryEntry, outbox::Notification, AppState};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Hook {
+    /// Build type to match, `None` matches all.
+    pub build_type: Option<String>,
+    /// Arch to match, `None` matches all.
+    pub arch: Option<String>,
+    pub action: HookAction,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum HookAction {
+    /// POST the history entry, including the manifest, as JSON.
+    Post { url: String },
+    /// Send a message rendered from `template` to `chat`.
+    Message { chat: i64, template: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HookResult {
+    pub hook: String,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+impl Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ",
+            self.build_type.as_deref().unwrap_or("*"),
+            self.arch.as_deref().unwrap_or("*")
+        )?;
+
+        match &self.action {
+            HookAction::Post { url } => write!(f, "post {url}"),
+            HookAction::Message { chat, template } => write!(f, "message {chat} {template}"),
+        }
+    }
+}
+
+impl Hook {
+    /// Parse `<type|*> <arch|*> post <url>` or
+    /// `<type|*> <arch|*> message <chat id> <template>`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut split = s.trim().splitn(4, char::is_whitespace);
+        let any = |x: &str| if x == "*" { None } else { Some(x.to_string()) };
+        let build_type = any(split.next()?);
+        let arch = any(split.next()?);
+
+        let action = match split.next()? {
+            "post" => HookAction::Post {
+                url: split.next()?.trim().to_string(),
+            },
+            "message" => {
+                let (chat, template) = split.next()?.trim().split_once(char::is_whitespace)?;
+                HookAction::Message {
+                    chat: chat.parse().ok()?,
+                    template: template.trim().to_string(),
+                }
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            build_type,
+            arch,
+            action,
+        })
+    }
+
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.build_type
+            .as_ref()
+            .is_none_or(|x| *x == entry.build_type)
+            && self.arch.as_ref().is_none_or(|x| *x == entry.arch)
+    }
+
+    async fn fire_with_retries(&self, state: &AppState, entry: &HistoryEntry) -> HookResult {
+        let mut res = Ok(());
+        for i in 0..3 {
+            if i > 0 {
+                sleep(Duration::from_secs(5 << i)).await;
+            }
+
+            res = self.fire(state, entry).await;
+            match res {
+                Ok(_) => break,
+                Err(ref e) => warn!("Hook `{self}` for #{} failed: {e}", entry.id),
+            }
+        }
+
+        HookResult {
+            hook: self.to_string(),
+            success: res.is_ok(),
+            error: res.err().map(|e| e.to_string()),
+        }
+    }
+
+    async fn fire(&self, state: &AppState, entry: &HistoryEntry) -> eyre::Result<()> {
+        match &self.action {
+            HookAction::Post { url } => {
+                reqwest::Client::new()
+                    .post(url)
+                    .json(entry)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            HookAction::Message { chat, template } => {
+                let text = render(template, entry);
+                state
+                    .db()
+                    .await
+                    .push_outbox(&Notification::new(*chat, html::escape(&text)))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Fill `{id}`, `{arch}`, `{type}`, `{variants}` and `{log_url}` in.
+fn render(template: &str, entry: &HistoryEntry) -> String {
+    template
+        .replace("{id}", &entry.id.to_string())
+        .replace("{arch}", &entry.arch)
+        .replace("{type}", &entry.build_type)
+        .replace(
+            "{variants}",
+            &entry.variants.as_deref().unwrap_or_default().join(" "),
+        )
+        .replace("{log_url}", entry.log_url.as_deref().unwrap_or(""))
+}
+
+/// Fire every hook matching `entry`, retrying each a few times, and record
+/// the outcomes on the history entry.
+pub async fn run(state: Arc<AppState>, entry: HistoryEntry) {
+    let hooks = match state.db().await.hooks().await {
+        Ok(h) => h,
+        Err(e) => {
+            error!("Failed to read hooks: {e}");
+            return;
+        }
+    };
+
+    let mut results = vec![];
+    for hook in hooks.iter().filter(|h| h.matches(&entry)) {
+        results.push(hook.fire_with_retries(&state, &entry).await);
+    }
+
+    record(&state, &entry, results).await;
+}
+
+/// POST `entry` to the webhook its build was requested with, see
+/// `POST /api/v1/builds`. Unlike the hooks, whatever the result.
+pub async fn run_webhook(state: Arc<AppState>, entry: HistoryEntry, url: String) {
+    let hook = Hook {
+        build_type: None,
+        arch: None,
+        action: HookAction::Post { url },
+    };
+    let res = hook.fire_with_retries(&state, &entry).await;
+
+    record(&state, &entry, vec![res]).await;
+}
+
+async fn record(state: &AppState, entry: &HistoryEntry, results: Vec<HookResult>) {
+    if results.is_empty() {
+        return;
+    }
+
+    let res = state
+        .db()
+        .await
+        .update_history(entry.id, |h| h.hooks.extend(results.iter().cloned()))
+        .await;
+
+    if let Err(e) = res {
+        error!("Failed to record hook results of #{}: {e}", entry.id);
+    }
+}
@@ -0,0 +1,218 @@
+//! Completion notices of mainline builds for an IRC channel, next to the
+//! Telegram ones. Just enough of a client to register, join, answer PINGs
+//! and send PRIVMSGs. Notices wait in Redis while the connection is down.
+
+use std::{sync::Arc, time::Duration};
+
+use eyre::{bail, OptionExt};
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use tokio::{
+    io::{split, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+use tokio_rustls::TlsConnector;
+use tracing::{error, info, warn};
+
+use crate::AppState;
+
+/// Servers commonly throttle clients sending faster than a line every two
+/// seconds.
+const SEND_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Bytes of text per PRIVMSG, leaving room for the prefix servers add when
+/// relaying within the 512 byte line limit.
+const MAX_TEXT_LEN: usize = 400;
+
+/// Give up registering after this long.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(60);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+pub struct IrcConfig {
+    host: String,
+    port: u16,
+    tls: bool,
+    channel: String,
+    nick: String,
+}
+
+impl IrcConfig {
+    /// `shipit_irc_server` (`host:port`, IRC is off if unset),
+    /// `shipit_irc_channel`, `shipit_irc_nick` (default `shipit`) and
+    /// `shipit_irc_tls` (default true).
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        let Ok(server) = std::env::var("shipit_irc_server") else {
+            return Ok(None);
+        };
+        let (host, port) = server
+            .rsplit_once(':')
+            .ok_or_eyre("shipit_irc_server must be host:port")?;
+
+        Ok(Some(IrcConfig {
+            host: host.to_string(),
+            port: port.parse()?,
+            tls: match std::env::var("shipit_irc_tls") {
+                Ok(x) => x.parse()?,
+                Err(_) => true,
+            },
+            channel: std::env::var("shipit_irc_channel")?,
+            nick: std::env::var("shipit_irc_nick").unwrap_or_else(|_| "shipit".to_string()),
+        }))
+    }
+}
+
+/// The notice for `text`: its lines joined into one, split into PRIVMSG
+/// sized pieces.
+fn messages(text: &str) -> Vec<String> {
+    let line = text
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    let mut res = vec![];
+    let mut current = String::new();
+    for c in line.chars() {
+        if current.len() + c.len_utf8() > MAX_TEXT_LEN {
+            res.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+    if !current.is_empty() {
+        res.push(current);
+    }
+
+    res
+}
+
+pub async fn run(state: Arc<AppState>) {
+    let Some(ref config) = state.irc else {
+        return;
+    };
+    let mut delay = Duration::from_secs(5);
+
+    loop {
+        let begin = Instant::now();
+        if let Err(e) = connect(&state, config).await {
+            warn!(
+                "IRC connection to {}:{} failed: {e}",
+                config.host, config.port
+            );
+        }
+
+        // a connection that held for a while was not refused, start over
+        if begin.elapsed() > MAX_RECONNECT_DELAY {
+            delay = Duration::from_secs(5);
+        }
+        info!("Reconnecting to IRC in {}s", delay.as_secs());
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn connect(state: &AppState, config: &IrcConfig) -> eyre::Result<()> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    if !config.tls {
+        return session(state, config, tcp).await;
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let tls =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let stream = TlsConnector::from(Arc::new(tls))
+        .connect(ServerName::try_from(config.host.clone())?, tcp)
+        .await?;
+
+    session(state, config, stream).await
+}
+
+async fn session(
+    state: &AppState,
+    config: &IrcConfig,
+    stream: impl AsyncRead + AsyncWrite,
+) -> eyre::Result<()> {
+    let (r, mut w) = split(stream);
+    let mut lines = BufReader::new(r).lines();
+
+    let mut nick = config.nick.clone();
+    w.write_all(format!("NICK {nick}\r\nUSER {nick} 0 * :shipit\r\n").as_bytes())
+        .await?;
+
+    let registered = async {
+        loop {
+            let line = lines.next_line().await?.ok_or_eyre("connection closed")?;
+            if let Some(token) = line.strip_prefix("PING ") {
+                w.write_all(format!("PONG {token}\r\n").as_bytes()).await?;
+                continue;
+            }
+
+            if line.starts_with("ERROR") {
+                bail!("{line}");
+            }
+
+            match line.split(' ').nth(1) {
+                Some("001") => return Ok::<_, eyre::Error>(()),
+                // nickname in use
+                Some("433") => {
+                    nick.push('_');
+                    w.write_all(format!("NICK {nick}\r\n").as_bytes()).await?;
+                }
+                _ => {}
+            }
+        }
+    };
+    timeout(REGISTER_TIMEOUT, registered).await??;
+
+    w.write_all(format!("JOIN {}\r\n", config.channel).as_bytes())
+        .await?;
+    info!("Joined {} on IRC as {nick}", config.channel);
+
+    let mut last_sent = Instant::now();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = line?.ok_or_eyre("connection closed")?;
+                if let Some(token) = line.strip_prefix("PING ") {
+                    w.write_all(format!("PONG {token}\r\n").as_bytes()).await?;
+                } else if line.starts_with("ERROR") {
+                    bail!("{line}");
+                }
+            }
+            _ = sleep(Duration::from_secs(1)) => {
+                let next = state.db().await.pop_irc().await;
+                let text = match next {
+                    Ok(Some(x)) => x,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Failed to read IRC notices: {e}");
+                        continue;
+                    }
+                };
+
+                for m in messages(&text) {
+                    let wait = SEND_INTERVAL.saturating_sub(last_sent.elapsed());
+                    sleep(wait).await;
+
+                    let res = w
+                        .write_all(format!("PRIVMSG {} :{m}\r\n", config.channel).as_bytes())
+                        .await;
+                    last_sent = Instant::now();
+
+                    if let Err(e) = res {
+                        // sent again as a whole after reconnecting
+                        if let Err(e) = state.db().await.requeue_irc(&text).await {
+                            error!("Failed to keep an IRC notice: {e}");
+                        }
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1249 @@
+//! What the bot says, in English and Simplified Chinese. Each chat picks
+//! its language with `/lang`, English if it never did.
+//!
+//! Texts name their placeholders, e.g. `{arch}`, so translations may put
+//! them in a different order. [`check`] makes sure both languages use the
+//! same ones.
+
+use std::{collections::BTreeSet, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Lang {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Lang {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "en" => Some(Lang::En),
+            "zh" | "zh-cn" | "zh_cn" | "zh-hans" => Some(Lang::Zh),
+            _ => None,
+        }
+    }
+
+    /// The text of `msg` with its placeholders filled from `args`.
+    pub fn tr(self, msg: Msg, args: &[(&str, &(dyn Display + Sync))]) -> String {
+        let mut s = String::new();
+        let mut rest = msg.texts()[self as usize];
+
+        // in one pass, values may contain braces themselves
+        while let Some((before, after)) = rest.split_once('{') {
+            s.push_str(before);
+            let arg = after.split_once('}').and_then(|(name, after)| {
+                let (_, value) = args.iter().find(|(x, _)| *x == name)?;
+                Some((value, after))
+            });
+            match arg {
+                Some((value, after)) => {
+                    s.push_str(&value.to_string());
+                    rest = after;
+                }
+                None => {
+                    s.push('{');
+                    rest = after;
+                }
+            }
+        }
+        s.push_str(rest);
+
+        s
+    }
+
+    /// The text of `msg`, which has no placeholders.
+    pub fn text(self, msg: Msg) -> String {
+        self.tr(msg, &[])
+    }
+}
+
+impl Display for Lang {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lang::En => write!(f, "en"),
+            Lang::Zh => write!(f, "zh"),
+        }
+    }
+}
+
+macro_rules! catalog {
+    ($($name:ident: $en:literal, $zh:literal;)*) => {
+        #[derive(Debug, Clone, Copy)]
+        pub enum Msg {
+            $($name,)*
+        }
+
+        impl Msg {
+            const ALL: &'static [Msg] = &[$(Msg::$name,)*];
+
+            /// The texts in the order of [`Lang`].
+            fn texts(self) -> [&'static str; 2] {
+                match self {
+                    $(Msg::$name => [$en, $zh],)*
+                }
+            }
+        }
+    };
+}
+
+catalog! {
+    RedisError: "Failed to mod redis database: {error}", "修改 Redis 数据库失败：{error}";
+    UnknownArch: "Unknown arch: {arch}", "未知架构：{arch}";
+    Usage: "Usage: {usage}", "用法：{usage}";
+    UnknownCommand: "Unknown command /{name}, see /help.", "未知命令 /{name}，请参阅 /help。";
+    UnknownCommandSuggest:
+        "Unknown command /{name}, did you mean /{suggestion}?",
+        "未知命令 /{name}，您是否想使用 /{suggestion}？";
+    HelpUnknown: "Unknown command: {command}", "未知命令：{command}";
+    LangCurrent:
+        "This chat gets messages in English, change it with /lang en|zh.",
+        "本会话的消息使用简体中文，可用 /lang en|zh 切换。";
+    LangSet:
+        "This chat gets messages in English from now on.",
+        "此后本会话的消息将使用简体中文。";
+    UnknownPool: "Unknown pool: {pool}, pools are: {pools}", "未知构建池：{pool}，可用构建池：{pools}";
+    PoolDenied: "This chat may not use pool {pool}.", "本会话无权使用构建池 {pool}。";
+    PoolCurrent:
+        "This chat uses pool {pool}, change it with /setpool <pool>.",
+        "本会话使用构建池 {pool}，可用 /setpool <pool> 切换。";
+    PoolSet:
+        "This chat uses pool {pool} from now on.",
+        "此后本会话将使用构建池 {pool}。";
+    PoolHeader: "Pool {pool}:", "构建池 {pool}：";
+    Success: "success", "成功";
+    HasError: "has error", "出错";
+
+    PingSent: "Pinged {arch}, waiting for a worker to answer.", "已 ping {arch}，正在等待构建机响应。";
+    PingWaiting:
+        "A ping of {arch} is already waiting for an answer.",
+        "已有一个 {arch} 的 ping 在等待响应。";
+    PingNoAnswer: "No response from {arch} worker", "{arch} 构建机无响应";
+    PingNoAnswerBusy:
+        "No response from {arch} worker, it may be busy with {builds}",
+        "{arch} 构建机无响应，它可能正忙于 {builds}";
+    Pong:
+        "{arch} worker {worker} ({hostname}, version {version}) answered in {ms} ms",
+        "{arch} 构建机 {worker}（{hostname}，版本 {version}）在 {ms} 毫秒内响应";
+    PongLoad: ", load {load}", "，负载 {load}";
+    PongFree: ", {free} free", "，剩余空间 {free}";
+
+    NoWorkers: "No worker has polled for jobs yet.", "尚无构建机领取过任务。";
+    WorkerLine:
+        "{arch} {worker} ({hostname}, version {version}): {state}, seen {ago} ago",
+        "{arch} {worker}（{hostname}，版本 {version}）：{state}，{ago} 前在线";
+    WorkerIdle: "idle", "空闲";
+    WorkerBuilding: "building #{id}", "正在构建 #{id}";
+    WorkerOffline: "offline", "离线";
+    WorkerDraining: "draining after #{id}", "排空中，#{id} 完成后停止";
+    WorkerDrainingAcked: "draining after #{id}, acknowledged", "排空中，#{id} 完成后停止，已确认";
+    WorkerDrained: "drained, safe to reboot", "已排空，可安全重启";
+    WorkerClock: ", clock {skew}", "，时钟偏差 {skew}";
+    WorkerPool: ", pool {pool}", "，构建池 {pool}";
+    WorkerUnknown: "unknown", "未知";
+    WorkerForgotten: "Forgot worker {worker}.", "已移除构建机 {worker}。";
+    NoSuchWorker: "No worker named {worker}.", "没有名为 {worker} 的构建机。";
+
+    Watching:
+        "This chat is told about builds requested or started by others and about worker problems.",
+        "本会话会收到他人请求或开始的构建以及构建机问题的通知。";
+    NotWatching: "This chat is not watching, see /watch on.", "本会话未关注服务器动态，参见 /watch on。";
+    WatchOn: "Watching. Stop with /watch off.", "已关注。使用 /watch off 取消。";
+    WatchOff: "No longer watching.", "已取消关注。";
+
+    BuildLog: "Build #{id} {build} {arch}: {log}", "构建 #{id} {build} {arch}：{log}";
+    LogNotPushed: "log was not pushed", "日志未能上传";
+    NoFinishedBuild: "No finished build found for {target}", "未找到 {target} 已完成的构建";
+    UnknownBuildType: "Unknown build type of #{id}: {build_type}", "#{id} 的构建类型未知：{build_type}";
+    ResumeOnlyRetry: "--resume only works with /retry", "--resume 仅可用于 /retry";
+    ResumeSucceeded: "#{id} succeeded, there is nothing to resume", "#{id} 已成功，无需续建";
+    ResumeNotRelease:
+        "#{id} is not a release with listed variants, it cannot be resumed",
+        "#{id} 不是列出变体的发行版构建，无法续建";
+    ResumeNoCommit:
+        "The aoscbootstrap commit of #{id} is unknown, resume with --force to build anyway",
+        "#{id} 的 aoscbootstrap 提交未知，如仍要构建请加 --force";
+    NothingToResume:
+        "Every variant of #{id} was uploaded, use /repush or /retry",
+        "#{id} 的所有变体均已上传，请使用 /repush 或 /retry";
+    NothingToRepush:
+        "#{id} has no kept artifacts to upload, use /retry",
+        "#{id} 没有保留的产物可供上传，请使用 /retry";
+    TimelineRunning: "Build #{id} {build} {arch}, running", "构建 #{id} {build} {arch}，运行中";
+    TimelineFinished: "Build #{id} {build} {arch}, {result}", "构建 #{id} {build} {arch}，{result}";
+    NoBuild: "No build found for {target}", "未找到 {target} 的构建";
+    NoSteps: "No steps reported, the worker may be too old.", "构建机未报告步骤，其版本可能过旧。";
+    DiffNoManifest: "No manifest recorded for #{id}", "#{id} 没有记录产物清单";
+    DiffMismatch:
+        "#{old} is {old_build} {old_arch} but #{new} is {new_build} {new_arch}, only builds of the same arch and type can be compared",
+        "#{old} 是 {old_build} {old_arch}，而 #{new} 是 {new_build} {new_arch}，只能比较相同架构和类型的构建";
+    DiffHeader: "#{old} → #{new}, {build} {arch}", "#{old} → #{new}，{build} {arch}";
+    DiffScripts: "Scripts: {range} {url}", "脚本：{range} {url}";
+    DiffScriptsSame: "Scripts: unchanged at {commit}", "脚本：未变，均为 {commit}";
+    DiffScriptsUnknown: "Scripts: commit not recorded", "脚本：未记录提交";
+    DiffTotal: "Total: {old} → {new} ({delta})", "总计：{old} → {new}（{delta}）";
+    DiffChecksum: ", checksum changed", "，校验和已变";
+    DiffSame: "Same files, sizes and checksums.", "文件、大小和校验和均相同。";
+
+    OnlyAdminsHooks: "Only admins can manage hooks.", "只有管理员可以管理钩子。";
+    OnlyAdminsDisable: "Only admins can disable arches.", "只有管理员可以停用架构。";
+    OnlyAdminsEnable: "Only admins can enable arches.", "只有管理员可以启用架构。";
+    OnlyAdminsExport: "Only admins can export the state.", "只有管理员可以导出状态。";
+    OnlyAdminsChats: "Only admins can list chats.", "只有管理员可以列出会话。";
+    OnlyAdminsOutbox: "Only admins can inspect the outbox.", "只有管理员可以查看发件箱。";
+    OnlyAdminsRevoke: "Only admins can revoke logins.", "只有管理员可以撤销登录。";
+    OnlyAdminsQueue: "Only admins can edit the queue.", "只有管理员可以编辑队列。";
+    OnlyAdminsWindow: "Only admins can change build windows.", "只有管理员可以修改构建时段。";
+    OnlyAdminsLimits: "Only admins can change the queue caps.", "只有管理员可以修改队列上限。";
+    OnlyAdminsWorkers: "Only admins can forget workers.", "只有管理员可以移除构建机。";
+    OnlyAdminsSetup: "Only admins can set up chats.", "只有管理员可以设置会话。";
+    OnlyAdminsDrain: "Only admins can drain workers.", "只有管理员可以排空构建机。";
+    OnlyAdminsUnpause:
+        "Only admins can unpause builds.",
+        "只有管理员可以恢复暂停的构建。";
+    OnlyAdminsDigest: "Only admins can send the digest.", "只有管理员可以发送日报。";
+    OnlyAdminsWatch: "Only admins can watch the server.", "只有管理员可以关注服务器动态。";
+    OnlyAdminsRotateSecret: "Only admins can rotate secrets.", "只有管理员可以轮换密钥。";
+    RotateSecretPrivate:
+        "Run /rotate-secret in a private chat with the bot, the new secret is shown in the reply.",
+        "请在与机器人的私聊中使用 /rotate-secret，新密钥会显示在回复中。";
+    ExportWithoutSecrets:
+        "The pool secrets are left out, GET /export with the admin secret includes them.",
+        "文件不含构建池密钥，使用管理员密钥 GET /export 可导出包含密钥的状态。";
+    SecretRotated:
+        "New secret of pool {pool}, shown only this once:\n{secret}\nThe old one is still taken for {grace}, set shipit_secret on the workers before then.",
+        "构建池 {pool} 的新密钥（仅显示这一次）：\n{secret}\n旧密钥在 {grace} 内仍然有效，请在此之前更新各构建机的 shipit_secret。";
+    ExpiredSecretUsed:
+        "🔑 {count} requests with the old secret of pool {pool} after its grace were refused, a worker still uses it.",
+        "🔑 宽限期后有 {count} 个使用构建池 {pool} 旧密钥的请求被拒绝，仍有构建机在使用旧密钥。";
+    OnlyAdminsNow:
+        "Only admins can build outside the build window.",
+        "只有管理员可以在构建时段外构建。";
+
+    ArchDisabled: "{arch} is now disabled", "{arch} 现已停用";
+    ArchDisabledReason: "{arch} is now disabled: {reason}", "{arch} 现已停用：{reason}";
+    ArchEnabled: "{arch} takes builds again.", "{arch} 已恢复接受构建。";
+    ArchNotDisabled: "{arch} is not disabled.", "{arch} 未被停用。";
+
+    SetupUnknownRole:
+        "Unknown role {role}, expected one of: {roles}",
+        "未知角色 {role}，可选：{roles}";
+    SetupProbe:
+        "Checking that I can post here, this message goes away in a moment.",
+        "正在检查能否在此发言，此消息稍后删除。";
+    SetupCannotPost: "I cannot post here: {error}", "无法在此发言：{error}";
+    SetupCannotDelete:
+        "I can post here but not delete my messages: {error}",
+        "可以在此发言，但无法删除自己的消息：{error}";
+    SetupCannotPin:
+        "I may not pin messages here. Make me an admin allowed to pin messages, then try again.",
+        "无权在此置顶消息。请将我设为可置顶消息的管理员后重试。";
+    SetupCannotPinError:
+        "Failed to check whether I may pin messages here: {error}",
+        "无法检查能否在此置顶消息：{error}";
+    SetupDone: "This chat now gets {role}.", "此会话现接收 {role}。";
+    SetupDonePool: "This chat now gets {role} of pool {pool}.", "此会话现接收池 {pool} 的 {role}。";
+    SetupRemoved: "This chat no longer gets {role}.", "此会话不再接收 {role}。";
+    SetupNotSet: "This chat is not set up for {role}.", "此会话未设置 {role}。";
+    SetupHeader: "Roles of this chat:", "此会话的角色：";
+    SetupLine: "{role}, set up by {by} {ago} ago", "{role}，由 {by} 于 {ago} 前设置";
+    SetupLinePool:
+        "{role} of pool {pool}, set up by {by} {ago} ago",
+        "池 {pool} 的 {role}，由 {by} 于 {ago} 前设置";
+    SetupConfigured: "{role}, from the server configuration", "{role}，来自服务器配置";
+    SetupConfiguredPool:
+        "{role} of pool {pool}, from the server configuration",
+        "池 {pool} 的 {role}，来自服务器配置";
+    SetupNone:
+        "This chat has no roles. /setup <role> sets one up, roles: {roles}",
+        "此会话没有角色。使用 /setup <role> 设置，可选角色：{roles}";
+    SetupProgress:
+        "Progress posts of builds requested here: {mode}.",
+        "此会话请求的构建的进度消息：{mode}。";
+    ProgressModeEdit: "edited into the result", "编辑为构建结果";
+    ProgressModeReplace: "deleted, the result posted anew", "删除后另发构建结果";
+    ProgressModeOff: "not posted", "不发送";
+    ProgressPost:
+        "⏳ #{id} {build} {arch} on {worker}: {stage}, {elapsed} so far",
+        "⏳ #{id} {build} {arch} 在 {worker} 上：{stage}，已用时 {elapsed}";
+    ProgressStarting: "starting", "正在开始";
+    ProgressUpload:
+        "uploading {artifact} {sent}/{total}",
+        "正在上传 {artifact} {sent}/{total}";
+
+    DrainUnknown:
+        "{name} is neither an arch nor a known worker.",
+        "{name} 既不是架构也不是已知的构建机。";
+    DrainOn:
+        "{name} finishes what it is building and takes no new builds. /workers tells when it is safe to reboot.",
+        "{name} 将完成正在进行的构建，不再接受新构建。/workers 会显示何时可以安全重启。";
+    DrainAlready: "{name} is already draining.", "{name} 已在排空中。";
+    DrainOff: "{name} takes builds again.", "{name} 已恢复接受构建。";
+    DrainNotOn: "{name} is not draining.", "{name} 未在排空。";
+
+    OutboxCleared: "Undelivered notifications cleared.", "已清除未送达的通知。";
+    OutboxEmpty: "All notifications were delivered.", "所有通知均已送达。";
+    OutboxEntry:
+        "chat {chat}: {attempts} attempts, last error: {error}",
+        "会话 {chat}：已尝试 {attempts} 次，最后的错误：{error}";
+    NoError: "none", "无";
+
+    LoginCleared:
+        "Cached login cleared, your login will be checked again on the next build command.",
+        "已清除缓存的登录，下次发起构建时将重新检查登录。";
+    LoginRevoked: "Cached login of {user} revoked.", "已撤销 {user} 的缓存登录。";
+    NoCachedLogin: "{user} has no cached login.", "{user} 没有缓存的登录。";
+    LoginLinkMalformed:
+        "This login link is malformed, run /login to get a new one.",
+        "登录链接格式有误，请运行 /login 获取新链接。";
+    LoggedInAs: "Logged in as @{user}", "已登录为 @{user}";
+    LoggedIn: "Logged in", "已登录";
+    LoginSuccessful: "Login successful!", "登录成功！";
+    LoginLinkExpired:
+        "This login link has expired or is invalid, run /login again.",
+        "登录链接已过期或无效，请重新运行 /login。";
+    LoginFailed: "Login failed with error: {error}", "登录失败：{error}";
+    NotLoggedIn: "Not logged in, use /login.", "未登录，请使用 /login。";
+    LoginConfirmed: "Login confirmed by minzhengbu {ago} ago", "minzhengbu 于 {ago} 前确认了登录";
+    LoginRecheck: ", checked again in {left}", "，将在 {left} 后重新检查";
+    LoginGrace:
+        ", minzhengbu is unreachable so the cached login is used for another {left}",
+        "，minzhengbu 无法访问，缓存的登录还可使用 {left}";
+    YouAreAdmin: "You are an admin.", "您是管理员。";
+
+    NoQueuedBuild: "No queued build found for {target}", "未找到 {target} 排队中的构建";
+    CancelNotYours:
+        "#{id} was requested by someone else, only admins can cancel it",
+        "#{id} 由他人发起，只有管理员可以取消";
+    Cancelled: "Cancelled #{id} {arch} for {build}", "已取消 {arch} 上的 #{id} {build}";
+    NoLongerQueued: "#{id} is no longer queued", "#{id} 已不在队列中";
+    CancelledRunning:
+        "Cancelled #{id} {arch} for {build}, {worker} stops it at its next heartbeat",
+        "已取消 {arch} 上的 #{id} {build}，{worker} 将在下次心跳时停止构建";
+    NoLongerRunning: "#{id} is no longer running", "#{id} 已不在运行中";
+    FreshnessHeader:
+        "Last build built and pushed, ⚠️ if older than {threshold}:",
+        "最近一次成功构建并推送的时间，超过 {threshold} 的标有 ⚠️：";
+    FreshnessLine: "{mark}{arch} {build}: {ago} ago", "{mark}{arch} {build}：{ago} 前";
+    MyBuildsQueued:
+        "⏳ #{id} {arch} {build}, queued at position {position}, {estimate}",
+        "⏳ #{id} {arch} {build}，排队第 {position} 位，{estimate}";
+    MyBuildsRunning:
+        "🔨 #{id} {arch} {build}, running for {elapsed} on {worker}",
+        "🔨 #{id} {arch} {build}，已在 {worker} 上运行 {elapsed}";
+    MyBuildsFinished:
+        "{mark} #{id} {arch} {build}, {ago} ago: {log}",
+        "{mark} #{id} {arch} {build}，{ago} 前：{log}";
+    MyBuildsNone: "No builds requested from this chat.", "此聊天没有请求过构建。";
+    WhoIsBuilding:
+        "#{id} {arch} {build}, requested by {requester}, running for {elapsed}",
+        "#{id} {arch} {build}，由 {requester} 请求，已运行 {elapsed}";
+    WhoIsBuildingNone: "Nothing is building on {arch}.", "{arch} 上没有正在进行的构建。";
+    HistoryLine: "{mark} #{id} {arch} {build}, {ago} ago", "{mark} #{id} {arch} {build}，{ago} 前";
+    HistoryNone: "No finished build matches.", "没有符合条件的已完成构建。";
+    HistoryIntervals: " ({intervals})", "（{intervals}）";
+    HistoryPruned: ", details pruned", "，详情已精简";
+    FreshnessNone: "No build was built and pushed yet.", "尚无成功构建并推送的记录。";
+    DigestHeader:
+        "Last 24 hours: {builds} builds, {pushed} pushed",
+        "过去 24 小时：{builds} 次构建，推送 {pushed}";
+    DigestArch: "{arch}: {success} succeeded, {failed} failed", "{arch}：{success} 次成功，{failed} 次失败";
+    DigestNone: "No build finished in the last 24 hours.", "过去 24 小时内没有完成的构建。";
+    DigestFailures: "Failures:", "失败的构建：";
+    DigestFailure: "#{id} {build} on {arch}: {log}", "#{id} {arch} 上的 {build}：{log}";
+    DigestFailureSummary:
+        "#{id} {build} on {arch}, {summary}: {log}",
+        "#{id} {arch} 上的 {build}，{summary}：{log}";
+    DigestMoreFailures: "and {count} more", "另有 {count} 次";
+    DigestStale:
+        "No success for longer than {threshold}:",
+        "超过 {threshold} 未成功构建：";
+    DigestSent: "Sent the digest to {count} chats.", "已向 {count} 个会话发送日报。";
+    DigestNoChats:
+        "No chat is set up for the digest, see /setup.",
+        "没有会话设置为接收日报，参见 /setup。";
+    ButtonCancel: "Cancel #{id} {arch}", "取消 #{id} {arch}";
+    ButtonCancelRunning: "Cancel build", "取消构建";
+    ButtonRetry: "Retry", "重试";
+    ButtonResume: "Retry (resume)", "重试（续建）";
+    ButtonBy: " (by {user})", "（{user}）";
+    ButtonRetried: "{user} asked to retry #{id}.", "{user} 请求重试 #{id}。";
+    ButtonExpired: "This button has expired.", "此按钮已失效。";
+    RetryNotYours:
+        "#{id} was requested by someone else, only admins can retry it here",
+        "#{id} 由他人发起，只有管理员可以在此重试";
+    DependencyCancelled: "#{id} it waited for was cancelled", "所等待的 #{id} 已被取消";
+    DependencyRemoved:
+        "#{id} it waited for was removed from the queue",
+        "所等待的 #{id} 已被移出队列";
+    DependencyFailed: "#{id} it waited for failed", "所等待的 #{id} 失败了";
+    DependencyExpired: "#{id} it waited for expired", "所等待的 #{id} 已过期";
+    RequestCancelled:
+        "Your {arch} {build} request #{id} was cancelled: {reason}",
+        "您在 {arch} 上的 {build} 请求 #{id} 已取消：{reason}";
+    RequestDropped:
+        "Your {arch} {build} request #{id} was removed from the queue by an admin",
+        "您在 {arch} 上的 {build} 请求 #{id} 已被管理员移出队列";
+    RequestMovedToTop:
+        "Your {arch} {build} request #{id} was moved to the front of the queue by an admin",
+        "您在 {arch} 上的 {build} 请求 #{id} 已被管理员移到队列最前";
+    RequestExpired:
+        "Your {arch} {build} request #{id} expired after {age} without a worker",
+        "您在 {arch} 上的 {build} 请求 #{id} 等待 {age} 仍无构建机领取，已过期";
+    AffinityExpired:
+        "Your {arch} {build} request #{id} failed: only {worker} has what it needs, and it did not take it within {age}. Request a fresh build instead",
+        "您在 {arch} 上的 {build} 请求 #{id} 失败：只有 {worker} 上有所需的文件，但它在 {age} 内未领取。请重新发起完整构建";
+    StatusPinned: " (pinned to {worker})", "（限定 {worker}）";
+    QueueDropped: "{ids} removed from the queue by an admin", "{ids} 已被管理员移出队列";
+    QueueMovedToTop:
+        "{ids} moved to the front of the queue by an admin",
+        "{ids} 已被管理员移到队列最前";
+    NoSuchQueued:
+        "No such queued build, it may have been claimed already.",
+        "没有这个排队中的构建，它可能已被领取。";
+    QueueEmpty: "The queue of {arch} is empty.", "{arch} 的队列为空。";
+    QueueHeader: "Queue of {arch}:", "{arch} 的队列：";
+    QueueLine:
+        "{n}. #{id} {build} by {requester}, waiting {waiting}, {estimate}",
+        "{n}. #{id} {build}，发起者 {requester}，已等待 {waiting}，{estimate}";
+
+    NoWindows:
+        "No arch has a build window, all build any time.",
+        "没有架构设置构建时段，均可随时构建。";
+    BuildsDuring: "{arch} builds during {window}.", "{arch} 在 {window} 期间构建。";
+    BuildsAnyTime: "{arch} builds any time.", "{arch} 可随时构建。";
+    NoWindow: "{arch} has no build window.", "{arch} 没有设置构建时段。";
+    BuildsAnyTimeAgain: "{arch} builds any time again.", "{arch} 恢复为随时构建。";
+    WindowSet: "{arch} builds during {window} from now on.", "{arch} 此后在 {window} 期间构建。";
+
+    QueueFullTotal:
+        "The queues are full: {queued} builds are waiting, {adding} more would exceed the limit of {limit}. Try again later.",
+        "队列已满：已有 {queued} 个构建在等待，再加 {adding} 个将超过上限 {limit}。请稍后再试。";
+    QueueFullArch:
+        "The queue of {arch} is full: {queued} builds are waiting, {adding} more would exceed the limit of {limit}. Try again later.",
+        "{arch} 的队列已满：已有 {queued} 个构建在等待，再加 {adding} 个将超过上限 {limit}。请稍后再试。";
+    QueueFullRequester:
+        "You have {queued} builds waiting, {adding} more would exceed the limit of {limit}. Cancel some or wait for them to start.",
+        "您已有 {queued} 个构建在等待，再加 {adding} 个将超过上限 {limit}。请取消部分构建或等待其开始。";
+    LimitsHeader: "Queued builds at most, admins exempt:", "排队构建数上限（管理员不受限）：";
+    LimitsLine: "{cap}: {limit}, refused {refused} times", "{cap}：{limit}，已拒绝 {refused} 次";
+    LimitUnlimited: "unlimited", "不限";
+    LimitsSetAtRuntime:
+        "Set with /limits, /limits reset restores the defaults.",
+        "已通过 /limits 设置，/limits reset 可恢复默认值。";
+    LimitSet: "The {cap} cap is {limit} from now on.", "此后 {cap} 上限为 {limit}。";
+    LimitsReset: "The caps are back to their defaults.", "上限已恢复为默认值。";
+
+    NoHooks: "No hooks configured.", "未配置钩子。";
+    HookAdded: "Added hook: {hook}", "已添加钩子：{hook}";
+    HookRemoved: "Removed hook: {hook}", "已移除钩子：{hook}";
+    NoHook: "No hook {n}", "没有钩子 {n}";
+
+    FlavorReleaseOnly: "--flavor is only for releases.", "--flavor 仅适用于 release 构建。";
+    NoFlavors:
+        "{arch} has no flavors, build it without --flavor.",
+        "{arch} 没有可选风味，请不带 --flavor 构建。";
+    UnknownFlavor:
+        "{arch} has no flavor {flavor}, allowed are: {flavors}",
+        "{arch} 没有风味 {flavor}，可选：{flavors}";
+    UnknownVariants:
+        "Unknown variants: {unknown}\nAvailable: {available}",
+        "未知变体：{unknown}\n可用：{available}";
+    DryRun: "Dry run, nothing was queued.", "试运行，未加入队列。";
+    PlanQueued:
+        "Queued #{id} {arch} for {build}{extra} at position {position}{busy}, {estimate}",
+        "已排队 #{id}：{arch} 的 {build}{extra}，位于第 {position} 位{busy}，{estimate}";
+    PlanWouldQueue:
+        "Would queue {arch} for {build}{extra} at position {position}{busy}, {estimate}",
+        "将排队：{arch} 的 {build}{extra}，位于第 {position} 位{busy}，{estimate}";
+    PlanBusy: " (busy with {builds})", "（正忙于 {builds}）";
+    PlanWith: " with {passthrough}", "，参数 {passthrough}";
+    NothingToBuild: "Nothing to build.", "没有要构建的内容。";
+    PlanDisabled: "{arch} is disabled", "{arch} 已停用";
+    PlanDisabledReason: "{arch} is disabled: {reason}", "{arch} 已停用：{reason}";
+    NoDependency:
+        "No {dep} build is queued or running on {arch} to wait for",
+        "{arch} 上没有排队或运行中的 {dep} 构建可供等待";
+    IdenticalJob:
+        "Identical job #{id} for {arch} already queued at position {position}, use --force to queue anyway",
+        "{arch} 上已有相同的任务 #{id} 排在第 {position} 位，使用 --force 仍可加入队列";
+    NoEstimate: "no estimate available", "暂无预估";
+    Estimate:
+        "estimated start in ~{start}, estimated completion ~{finish}",
+        "预计约 {start} 后开始，约 {finish} 完成";
+
+    StatusBuilding: "{arch}: building #{id} {build}", "{arch}：正在构建 #{id} {build}";
+    StatusWorker: " on {worker}", "，构建机 {worker}";
+    StatusCross: " (cross)", "（交叉构建）";
+    StatusWorkerArches: " ({arches})", "（{arches}）";
+    StatusElapsed: " for {elapsed}", "，已用时 {elapsed}";
+    StatusHeartbeat: ", last heartbeat {ago} ago", "，上次心跳于 {ago} 前";
+    StatusStep: ", step {step} for {elapsed}", "，步骤 {step} 已用时 {elapsed}";
+    StatusUpload:
+        ", uploading {artifact} {sent}/{total} ({percent}%)",
+        "，正在上传 {artifact} {sent}/{total}（{percent}%）";
+    StatusUploadStalled:
+        " ⚠️ no progress for {stalled}, the connection may be dead",
+        " ⚠️ 已 {stalled} 无进展，连接可能已断开";
+    StatusQueued: "{arch}: {n}. #{id} {build}{waiting}, {estimate}", "{arch}：{n}. #{id} {build}{waiting}，{estimate}";
+    WaitingFor: ", waiting for #{id}", "，等待 #{id}";
+    StatusNote: " ({note})", "（{note}）";
+    Idle: "No build is running or queued.", "没有正在运行或排队的构建。";
+    StatusDisabled: "{arch}: disabled", "{arch}：已停用";
+    StatusDraining: "{worker}: draining after #{id}", "{worker}：排空中，#{id} 完成后停止";
+    StatusDrained: "{worker}: drained, safe to reboot", "{worker}：已排空，可安全重启";
+    StatusDisabledReason: "{arch}: disabled: {reason}", "{arch}：已停用：{reason}";
+    OutsideWindow:
+        "{arch}: outside build window, resumes at {time} {tz}",
+        "{arch}：不在构建时段内，将于 {tz} 时间 {time} 恢复";
+    ClockAhead:
+        "{arch}: {worker} has clock skew {skew} ahead, not taking jobs",
+        "{arch}：{worker} 的时钟快了 {skew}，暂不领取任务";
+    ClockBehind:
+        "{arch}: {worker} has clock skew {skew} behind, not taking jobs",
+        "{arch}：{worker} 的时钟慢了 {skew}，暂不领取任务";
+    UploadUnreachable:
+        "{arch}: {worker} cannot reach its upload host ({error}), not taking jobs",
+        "{arch}：{worker} 无法连接上传主机（{error}），暂不领取任务";
+    UploadUnreachableWarn:
+        "{arch}: {worker} cannot reach its upload host ({error}), taking jobs anyway",
+        "{arch}：{worker} 无法连接上传主机（{error}），仍继续领取任务";
+    StatusDeferring:
+        "{worker} is deferring: {reason}",
+        "{worker} 暂不领取任务：{reason}";
+    LowDisk:
+        "{arch}: {worker} has {free} free, needs {min}, dispatch paused",
+        "{arch}：{worker} 剩余空间 {free}，需要 {min}，已暂停分派任务";
+    FailedNotifications:
+        "{count} notifications could not be delivered, see /outbox",
+        "{count} 条通知未能送达，请参阅 /outbox";
+
+    WatchRequested:
+        "📥 {requester} requested {build}: {builds}",
+        "📥 {requester} 请求了 {build}：{builds}";
+    WatchStarted:
+        "▶️ #{id} {build} {arch} started on {worker}, requested by {requester}",
+        "▶️ #{id} {build} {arch} 已在 {worker} 上开始，请求者 {requester}";
+    WatchStale:
+        "⚠️ {worker} has not been heard from for {ago} while building #{id} on {arch}",
+        "⚠️ {worker} 在构建 {arch} 上的 #{id} 时已 {ago} 没有响应";
+    LowDiskAlert:
+        "⚠️ {arch}: dispatch paused, {worker} is low on disk ({free} free, {min} needed). It resumes by itself once the worker reports enough space.",
+        "⚠️ {arch}：已暂停分派任务，{worker} 磁盘空间不足（剩余 {free}，需要 {min}）。构建机报告空间充足后将自动恢复。";
+    FailureStreak:
+        "🚨 {arch} {channel} {build} has failed {count} times in a row; latest failure class: {class}; last success {last_success}. Scheduled builds of it are paused until /unpause {arch} {build}.",
+        "🚨 {arch} {channel} {build} 已连续失败 {count} 次；最近的失败类型：{class}；上次成功：{last_success}。其定时构建已暂停，直至执行 /unpause {arch} {build}。";
+    PausedScheduled:
+        "{arch}: scheduled {channel} {build} builds are paused after failing in a row, see /unpause",
+        "{arch}：{channel} {build} 定时构建因连续失败已暂停，参见 /unpause";
+    PausedHeader: "Paused scheduled builds:", "已暂停的定时构建：";
+    PausedLine:
+        "{arch} {channel} {build}, {count} failures in a row",
+        "{arch} {channel} {build}，连续失败 {count} 次";
+    PausedNone: "No scheduled builds are paused.", "没有暂停的定时构建。";
+    Unpaused:
+        "Scheduled {build} builds of {arch} are taken again: {channels}",
+        "已恢复 {arch} 的 {build} 定时构建：{channels}";
+    UnpauseNothing:
+        "Scheduled {build} builds of {arch} are not paused.",
+        "{arch} 的 {build} 定时构建未暂停。";
+    CompletionFailed: "failed: {summary}", "失败：{summary}";
+    StaleScripts:
+        "built from scripts {behind} commits ({age}) behind {remote}",
+        "构建所用脚本落后 {remote} {behind} 个提交（{age}）";
+    Completion:
+        "Build #{id} {build}{channel} {result}: {arch}\nlog url: {log}{log_failure}\nPush success: {push}{push_failure}",
+        "构建 #{id} {build}{channel} {result}：{arch}\n日志：{log}{log_failure}\n上传成功：{push}{push_failure}";
+    LogPushFailed: "Failed to push log", "日志上传失败";
+    CompletionNote: "Note: {note}", "备注：{note}";
+    CompletionIncremental:
+        "Built incrementally, on the livekit cache of #{id}",
+        "增量构建，基于 #{id} 的 livekit 缓存";
+    CompletionIntervals: "Time: {intervals}", "耗时：{intervals}";
+    Intervals:
+        "queued {queued}, built in {build}, published in {publish}",
+        "排队 {queued}，构建 {build}，发布 {publish}";
+    IntervalUnknown: "n/a", "不详";
+    RetryFailure:
+        ", failed after {attempts} attempts in {duration}",
+        "，尝试 {attempts} 次共 {duration} 后失败";
+    RequestedWith: "Requested with: {passthrough}", "请求参数：{passthrough}";
+    ResumedFrom:
+        "Resumed #{id}, keeping its variants {variants}",
+        "续建 #{id}，沿用其变体 {variants}";
+    PartialPush:
+        "Uploaded {pushed} of {total} files, failed: {failed}",
+        "已上传 {pushed}/{total} 个文件，失败：{failed}";
+    KeptArtifacts:
+        "{count} artifacts kept on {worker}, /repush #{id} to upload them again",
+        "{count} 个产物保留在 {worker} 上，可用 /repush #{id} 重新上传";
+    TheWorker: "the worker", "构建机";
+    PhaseTime: "Time: {phases}", "耗时：{phases}";
+    ManifestFiles: "{count} files, {size}", "{count} 个文件，{size}";
+    ManifestUpload: ", uploaded in {duration} at {rate}/s", "，上传用时 {duration}，速度 {rate}/s";
+    ManifestVariant: "{variant}: {count} files, {size}", "{variant}：{count} 个文件，{size}";
+    ManifestFrom: " (from #{id})", "（来自 #{id}）";
+    WorkerChecksum: "checksum of {path} computed by the worker", "{path} 的校验和由构建机计算";
+    Downloads: "Downloads:", "下载：";
+    DownloadsPrivate:
+        "Downloads (private lookaside, not publicly reachable):",
+        "下载（私有 lookaside，无法公开访问）：";
+
+    HelpList:
+        "ReleaseIt! supports the following commands:\n\n\
+         /help — Display usage: /help [command]\n\
+         /start — start\n\
+         /login — Login\n\
+         /logout — Forget the cached login: /logout\n\
+         /whoami — Show who you are logged in as: /whoami\n\
+         /revoke — Forget the cached login of a user (admin only): /revoke <user id>\n\
+         /rotate-secret — Replace the worker secret of a pool (admin only): /rotate-secret [pool]\n\
+         /livekit — Start a build livekit job: /livekit [archs] (e.g., /livekit amd64 arm64), alias /lk\n\
+         /release — Start a build release job: /release variants;[archs] (e.g., /release base desktop;amd64 arm64), alias /rel\n\
+         /rootfs — Start a build rootfs tarballs job: /rootfs variants;[archs] (e.g., /rootfs container;amd64)\n\
+         /status — Show queue and server status: /status [--pool <pool>], alias /st\n\
+         /ping — Check whether a worker is alive: /ping <arch> [--check]\n\
+         /mybuilds — List the builds of this chat, queued, running and finished: /mybuilds\n\
+         /whoisbuilding — Show who requested the running build of an arch: /whoisbuilding <arch>\n\
+         /logs — Show the log of a finished build: /logs <arch|#id>\n\
+         /timeline — Show the steps of a running or finished build: /timeline <arch|#id>\n\
+         /history — List recent finished builds: /history [--grep <text>] [--pool <pool>]\n\
+         /diff — Compare what two finished builds shipped: /diff #<id> #<id>\n\
+         /retry — Request a finished build again: /retry <arch|#id>\n\
+         /repush — Upload the kept artifacts of a failed push again: /repush <arch|#id>\n\
+         /cancel — Cancel a queued or running build: /cancel <arch|#id>\n\
+         /queue — Show the queue of an arch: /queue <arch>, admins may also /queue <arch> drop|top <n> or clear\n\
+         /hook — Manage post-build hooks (admin only): /hook [list|add <hook>|remove <n>]\n\
+         /outbox — Show notifications that could not be delivered (admin only): /outbox [clear]\n\
+         /disable — Stop taking builds for an arch (admin only): /disable <arch> [reason]\n\
+         /enable — Take builds for a disabled arch again (admin only): /enable <arch>\n\
+         /window — Show or set the times of day an arch builds: /window [arch] [HH:MM-HH:MM [time zone]|clear]\n\
+         /limits — Show or set the caps on queued builds: /limits [total|arch|requester <n|off>|reset]\n\
+         /workers — List the workers and what they are doing: /workers, admins may also /workers forget <name>\n\
+         /drain — Let a worker or the workers of an arch finish their build and take no more (admin only): /drain <arch|worker> on|off\n\
+         /unpause — List the scheduled builds paused after failing in a row, or take them again (admin only): /unpause [<arch> <type> [channel]]\n\
+         /watch — Get told about builds requested or started by others and worker problems (admin only): /watch [on|off]\n\
+         /export — Download the server state as JSON (admin only): /export\n\
+         /freshness — Show when each arch last built and pushed each type of build: /freshness\n\
+         /digest — Show the digest of the last day, or send it to the digest chats (admin only): /digest [now]\n\
+         /chats — Show the chats notifications go to (admin only): /chats\n\
+         /setup — Set this chat up for announcements, alerts, default-notify or the digest, or set how progress posts end (admin only): /setup [status|<role>|remove <role>|progress <edit|replace|off>]\n\
+         /lang — Show or set the language of this chat: /lang [en|zh]\n\
+         /setpool — Show or set the pool of builds this chat uses: /setpool [pool]",
+        "ReleaseIt! 支持以下命令：\n\n\
+         /help — 显示用法：/help [命令]\n\
+         /start — 完成登录：/start <rid>\n\
+         /login — 使用 GitHub 登录：/login\n\
+         /logout — 清除本会话缓存的登录：/logout\n\
+         /whoami — 显示本会话是否已登录及登录身份：/whoami\n\
+         /revoke — 清除某用户缓存的登录（仅管理员）：/revoke <用户 ID>\n\
+         /rotate-secret — 更换构建池的构建机密钥（仅管理员）：/rotate-secret [构建池]\n\
+         /livekit — 发起 livekit 构建任务：/livekit [架构]（例如 /livekit amd64 arm64），别名 /lk\n\
+         /release — 发起发行版构建任务：/release 变体;[架构]（例如 /release base desktop;amd64 arm64），别名 /rel\n\
+         /rootfs — 发起 rootfs 压缩包构建任务：/rootfs 变体;[架构]（例如 /rootfs container;amd64）\n\
+         /status — 显示队列和服务器状态：/status [--pool <构建池>]，别名 /st\n\
+         /ping — 检查构建机是否在线：/ping <架构> [--check]\n\
+         /mybuilds — 列出本会话排队中、运行中和已完成的构建：/mybuilds\n\
+         /whoisbuilding — 显示某架构正在运行的构建由谁请求：/whoisbuilding <架构>\n\
+         /logs — 显示已完成构建的日志：/logs <架构|#ID>\n\
+         /timeline — 显示运行中或已完成构建的步骤：/timeline <架构|#ID>\n\
+         /history — 列出最近完成的构建：/history [--grep <文本>] [--pool <构建池>]\n\
+         /diff — 比较两次已完成构建的产物：/diff #<ID> #<ID>\n\
+         /retry — 重新请求已完成的构建：/retry <架构|#ID>\n\
+         /repush — 重新上传上传失败后保留的产物：/repush <架构|#ID>\n\
+         /cancel — 取消排队中或运行中的构建：/cancel <架构|#ID>\n\
+         /queue — 显示某架构的队列：/queue <架构>，管理员还可使用 /queue <架构> drop|top <n> 或 clear\n\
+         /hook — 管理构建后钩子（仅管理员）：/hook [list|add <钩子>|remove <n>]\n\
+         /outbox — 显示未能送达的通知（仅管理员）：/outbox [clear]\n\
+         /disable — 停止接受某架构的构建（仅管理员）：/disable <架构> [原因]\n\
+         /enable — 重新接受已停用架构的构建（仅管理员）：/enable <架构>\n\
+         /window — 显示或设置某架构的构建时段：/window [架构] [HH:MM-HH:MM [时区]|clear]\n\
+         /limits — 显示或设置排队构建的上限：/limits [total|arch|requester <n|off>|reset]\n\
+         /workers — 列出构建机及其工作状态：/workers，管理员还可使用 /workers forget <名称>\n\
+         /drain — 让某构建机或某架构的构建机完成当前构建后不再接受新任务（仅管理员）：/drain <架构|构建机> on|off\n\
+         /unpause — 列出因连续失败而暂停的定时构建，或恢复它们（仅管理员）：/unpause [<架构> <类型> [渠道]]\n\
+         /watch — 接收他人请求或开始的构建及构建机问题的通知（仅管理员）：/watch [on|off]\n\
+         /export — 以 JSON 格式下载服务器状态（仅管理员）：/export\n\
+         /freshness — 显示各架构各类构建最近一次成功构建和上传的时间：/freshness\n\
+         /digest — 显示过去一天的摘要，或发送到摘要会话（仅管理员）：/digest [now]\n\
+         /chats — 显示通知发送到的会话（仅管理员）：/chats\n\
+         /setup — 将本会话设为公告、警报、默认通知或摘要会话，或设置进度消息的结束方式（仅管理员）：/setup [status|<角色>|remove <角色>|progress <edit|replace|off>]\n\
+         /lang — 显示或设置本会话的语言：/lang [en|zh]\n\
+         /setpool — 显示或设置本会话使用的构建池：/setpool [构建池]";
+    AnyVariant: "any variant known to aoscbootstrap", "aoscbootstrap 已知的任意变体";
+    UsageEnvOption:
+        "--env <NAME=value>: set a variable for the build script, one of {names}",
+        "--env <名称=值>：为构建脚本设置变量，可选 {names}";
+    UsageArgOption:
+        "--arg <argument>: pass an argument to the build script, matching {patterns}",
+        "--arg <参数>：向构建脚本传递参数，须匹配 {patterns}";
+    UsageFlavorOption:
+        "--flavor <flavor>: build in a flavor of the arch, {flavors}",
+        "--flavor <风味>：以该架构的某种风味构建，{flavors}";
+    UsageLivekit:
+        "/livekit [archs]\n\
+         Build livekit ISOs for the given architectures, or all enabled ones if none is given.\n\
+         Alias: /lk\n\n\
+         Examples:\n\
+         /livekit\n\
+         /livekit amd64\n\
+         /lk arm64 riscv64\n\
+         /livekit ?amd64 (dry run)\n\n\
+         Options:\n\
+         --dry-run (or a leading ?): show what would be queued without queuing it\n\
+         --force: queue even if an identical job is already queued\n\
+         --expire <duration>: drop the job if it waits longer than e.g. 24h\n\
+         --nightly: publish to the date-stamped nightly directory\n\
+         --now: build even outside the build window of the arch (admin only)\n\
+         --native-only: leave the build to workers for which the arch is native, not cross builders\n\
+         --pool <pool>: queue in another pool than the one of this chat, see /setpool\n\
+         --note \"<text>\": why it is built, e.g. an advisory, shown with the build and found by /history --grep\n\
+         --no-clean: keep what earlier builds left in the aosc-mklive checkout\n\
+         --incremental: reuse the squashfs trees of the last incremental build of the arch, \
+         if recent and of the same aosc-mklive commit\n\
+         {passthrough}\n\
+         Architectures (mainline): {archs}\n\
+         Pools: {pools}",
+        "/livekit [架构]\n\
+         为指定架构构建 livekit ISO，未指定时构建所有已启用的架构。\n\
+         别名：/lk\n\n\
+         示例：\n\
+         /livekit\n\
+         /livekit amd64\n\
+         /lk arm64 riscv64\n\
+         /livekit ?amd64（试运行）\n\n\
+         选项：\n\
+         --dry-run（或以 ? 开头）：只显示将要排队的任务，不实际排队\n\
+         --force：即使已有相同任务在排队也照样排队\n\
+         --expire <时长>：任务等待超过该时长（如 24h）则丢弃\n\
+         --nightly：发布到带日期的 nightly 目录\n\
+         --now：即使在该架构的构建时段之外也进行构建（仅管理员）\n\
+         --native-only：只交给原生支持该架构的构建机，不使用交叉构建机\n\
+         --pool <构建池>：在本会话所用构建池之外的构建池中排队，参见 /setpool\n\
+         --note \"<文本>\"：构建原因，例如某个安全公告，随构建显示，可用 /history --grep 查找\n\
+         --no-clean：保留之前的构建在 aosc-mklive 检出目录中留下的内容\n\
+         --incremental：复用该架构上次增量构建的 squashfs 树，\
+         前提是该构建较新且基于相同的 aosc-mklive 提交\n\
+         {passthrough}\n\
+         架构（主线）：{archs}\n\
+         构建池：{pools}";
+    UsageRelease:
+        "/release variants;[archs]\n\
+         Build release images of the given variants. Architectures go after the ';', \
+         all enabled ones are built if omitted. The variant default alone builds the \
+         default set of the build script.\n\
+         Alias: /rel\n\n\
+         Examples:\n\
+         /release base\n\
+         /release default;amd64\n\
+         /release base desktop;amd64 arm64\n\
+         /rel server;riscv64\n\
+         /release base;amd64 --dry-run\n\n\
+         Options:\n\
+         --dry-run (or a leading ?): show what would be queued without queuing it\n\
+         --force: queue even if an identical job is already queued\n\
+         --yes: build a variant named close to an arch, taken for a forgotten ';' otherwise\n\
+         --expire <duration>: drop the job if it waits longer than e.g. 24h\n\
+         --after <livekit|release|rootfs|#id>: start only after that build on the same arch succeeded\n\
+         --allow-partial: upload even if some variants produced no tarball\n\
+         --nightly: publish to the nightly directory\n\
+         --now: build even outside the build window of the arch (admin only)\n\
+         --native-only: leave the build to workers for which the arch is native, not cross builders\n\
+         --pool <pool>: queue in another pool than the one of this chat, see /setpool\n\
+         --note \"<text>\": why it is built, e.g. an advisory, shown with the build and found by /history --grep\n\
+         {flavors}\
+         {passthrough}\n\
+         Variants: {variants}\n\
+         Architectures (mainline): {archs}\n\
+         Pools: {pools}",
+        "/release 变体;[架构]\n\
+         构建指定变体的发行版镜像。架构写在 ';' 之后，省略时构建所有已启用的架构。\
+         仅使用变体 default 时构建构建脚本的默认变体集。\n\
+         别名：/rel\n\n\
+         示例：\n\
+         /release base\n\
+         /release default;amd64\n\
+         /release base desktop;amd64 arm64\n\
+         /rel server;riscv64\n\
+         /release base;amd64 --dry-run\n\n\
+         选项：\n\
+         --dry-run（或以 ? 开头）：只显示将要排队的任务，不实际排队\n\
+         --force：即使已有相同任务在排队也照样排队\n\
+         --yes：构建名称与某架构相近的变体，否则会被视为遗漏了 ';'\n\
+         --expire <时长>：任务等待超过该时长（如 24h）则丢弃\n\
+         --after <livekit|release|rootfs|#ID>：待同一架构上的该构建成功后才开始\n\
+         --allow-partial：即使部分变体未生成压缩包也进行上传\n\
+         --nightly：发布到 nightly 目录\n\
+         --now：即使在该架构的构建时段之外也进行构建（仅管理员）\n\
+         --native-only：只交给原生支持该架构的构建机，不使用交叉构建机\n\
+         --pool <构建池>：在本会话所用构建池之外的构建池中排队，参见 /setpool\n\
+         --note \"<文本>\"：构建原因，例如某个安全公告，随构建显示，可用 /history --grep 查找\n\
+         {flavors}\
+         {passthrough}\n\
+         变体：{variants}\n\
+         架构（主线）：{archs}\n\
+         构建池：{pools}";
+    UsageRootfs:
+        "/rootfs variants;[archs]\n\
+         Build rootfs tarballs of the given variants for container and cloud images. \
+         Architectures go after the ';', all enabled ones are built if omitted. \
+         The variant default alone builds the default set of the build script.\n\n\
+         Examples:\n\
+         /rootfs container\n\
+         /rootfs base container;amd64 arm64\n\n\
+         Options:\n\
+         --dry-run (or a leading ?): show what would be queued without queuing it\n\
+         --force: queue even if an identical job is already queued\n\
+         --yes: build a variant named close to an arch, taken for a forgotten ';' otherwise\n\
+         --expire <duration>: drop the job if it waits longer than e.g. 24h\n\
+         --after <livekit|release|rootfs|#id>: start only after that build on the same arch succeeded\n\
+         --allow-partial: upload even if some variants produced no tarball\n\
+         --nightly: publish to the nightly directory\n\
+         --now: build even outside the build window of the arch (admin only)\n\
+         --native-only: leave the build to workers for which the arch is native, not cross builders\n\
+         --pool <pool>: queue in another pool than the one of this chat, see /setpool\n\
+         --note \"<text>\": why it is built, e.g. an advisory, shown with the build and found by /history --grep\n\
+         {passthrough}\n\
+         Variants: {variants}\n\
+         Architectures (mainline): {archs}\n\
+         Pools: {pools}",
+        "/rootfs 变体;[架构]\n\
+         构建指定变体的 rootfs 压缩包，用于容器和云镜像。\
+         架构写在 ';' 之后，省略时构建所有已启用的架构。\
+         仅使用变体 default 时构建构建脚本的默认变体集。\n\n\
+         示例：\n\
+         /rootfs container\n\
+         /rootfs base container;amd64 arm64\n\n\
+         选项：\n\
+         --dry-run（或以 ? 开头）：只显示将要排队的任务，不实际排队\n\
+         --force：即使已有相同任务在排队也照样排队\n\
+         --yes：构建名称与某架构相近的变体，否则会被视为遗漏了 ';'\n\
+         --expire <时长>：任务等待超过该时长（如 24h）则丢弃\n\
+         --after <livekit|release|rootfs|#ID>：待同一架构上的该构建成功后才开始\n\
+         --allow-partial：即使部分变体未生成压缩包也进行上传\n\
+         --nightly：发布到 nightly 目录\n\
+         --now：即使在该架构的构建时段之外也进行构建（仅管理员）\n\
+         --native-only：只交给原生支持该架构的构建机，不使用交叉构建机\n\
+         --pool <构建池>：在本会话所用构建池之外的构建池中排队，参见 /setpool\n\
+         --note \"<文本>\"：构建原因，例如某个安全公告，随构建显示，可用 /history --grep 查找\n\
+         {passthrough}\n\
+         变体：{variants}\n\
+         架构（主线）：{archs}\n\
+         构建池：{pools}";
+    UsageStatus:
+        "/status [--pool <pool>]\n\
+         Show running and queued builds with their estimated start time, in the pool of \
+         this chat unless --pool is given.\n\
+         Alias: /st",
+        "/status [--pool <构建池>]\n\
+         显示运行中和排队中的构建及其预计开始时间，未指定 --pool 时显示本会话所用构建池。\n\
+         别名：/st";
+    UsagePing:
+        "/ping <arch> [--check]\nAsk the next worker of an arch polling for jobs to answer with its version, \
+         load and free disk, and show how long that took. A worker busy with a build does not poll, \
+         so there is no answer after {timeout}s.\n\n\
+         With --check, the worker also runs the checks of `worker check` before answering: the server \
+         and its secret, the upload host, the tools builds need, free disk and the clock. \
+         That may take up to {check_timeout}s.\n\n\
+         Architectures: {all_archs}",
+        "/ping <架构> [--check]\n请该架构下一台轮询任务的构建机回复其版本、负载和剩余磁盘空间，\
+         并显示耗时。正在构建的构建机不会轮询，因此 {timeout} 秒后仍无回复。\n\n\
+         使用 --check 时，构建机在回复前还会运行 `worker check` 的各项检查：服务器及其密钥、\
+         上传主机、构建所需的工具、剩余磁盘空间和时钟。最长可能需要 {check_timeout} 秒。\n\n\
+         架构：{all_archs}";
+    UsageLogin: "/login\nGet a link to log in with your GitHub account.", "/login\n获取使用 GitHub 帐户登录的链接。";
+    UsageLogout: "/logout\nForget the cached login of this chat.", "/logout\n清除本会话缓存的登录。";
+    UsageWhoami:
+        "/whoami\nShow whether this chat is logged in, as which GitHub user, \
+         and when the login is checked with minzhengbu again.",
+        "/whoami\n显示本会话是否已登录、登录的 GitHub 用户，以及何时再次向 minzhengbu 核验登录。";
+    UsageRevoke:
+        "/revoke <user id>\nForget the cached login of a user (admin only).",
+        "/revoke <用户 ID>\n清除某用户缓存的登录（仅管理员）。";
+    UsageRotateSecret:
+        "/rotate-secret [pool]\nReplace the secret the workers of a pool, mainline if none \
+         is given, authenticate with (admin only, in a private chat). The new one is shown once; the old one \
+         is still taken until shipit_secret_grace has passed, and its use after that is told to the admins. \
+         `worker check` tells which of the two a worker uses.",
+        "/rotate-secret [构建池]\n更换某构建池（未指定时为主线）的构建机认证密钥（仅管理员，须在私聊中使用）。\
+         新密钥只显示一次；旧密钥在 shipit_secret_grace 过去之前仍然有效，之后若再被使用会告知管理员。\
+         `worker check` 可显示构建机使用的是哪一个。";
+    UsageStart:
+        "/start <rid>\nFinish logging in, usually opened from the login page.",
+        "/start <rid>\n完成登录，通常从登录页面打开。";
+    UsageMybuilds:
+        "/mybuilds\nList the builds requested from this chat: the queued ones with their \
+         position and estimated start, the running ones and the last finished ones with links to their logs.",
+        "/mybuilds\n列出从本会话请求的构建：排队中的构建及其位置和预计开始时间、运行中的构建，\
+         以及最近完成的构建及其日志链接。";
+    UsageWhoisbuilding:
+        "/whoisbuilding <arch>\nShow who requested the build running on an arch in the pool \
+         of this chat, and its note.",
+        "/whoisbuilding <架构>\n显示本会话所用构建池中某架构正在运行的构建由谁请求，及其备注。";
+    UsageLogs:
+        "/logs <arch|#id>\nShow the log of the latest finished build on an arch, or of a build by id.",
+        "/logs <架构|#ID>\n显示某架构最近完成的构建的日志，或指定 ID 的构建的日志。";
+    UsageTimeline:
+        "/timeline <arch|#id>\nShow the commands the worker ran for the build running on an arch, \
+         or the latest finished one, or a build by id, with how long each took.",
+        "/timeline <架构|#ID>\n显示构建机为某架构正在运行或最近完成的构建，或指定 ID 的构建所运行的命令，\
+         及每条命令的耗时。";
+    UsageHistory:
+        "/history [--grep <text>] [--pool <pool>]\nList the latest finished builds of the pool \
+         of this chat unless --pool is given, with their notes. --grep keeps the builds whose note or \
+         type and variants contain the text, ignoring case.",
+        "/history [--grep <文本>] [--pool <构建池>]\n列出本会话所用构建池（或 --pool 指定的构建池）\
+         最近完成的构建及其备注。--grep 只保留备注或类型与变体中包含该文本的构建，不区分大小写。";
+    UsageDiff:
+        "/diff #<id> #<id>\nCompare the artifacts of two finished builds of the same arch and type: \
+         files added and removed, size and checksum changes, and the commits of the build scripts in between \
+         with a link to compare them on GitHub. Dates in file names are ignored, so nightlies of different days \
+         are compared file by file. Long results are sent as a text file.\n\n\
+         Example:\n\
+         /diff #120 #134",
+        "/diff #<ID> #<ID>\n比较同一架构、同一类型的两次已完成构建的产物：新增和删除的文件、大小和校验和的变化，\
+         以及两者之间构建脚本的提交，并附上在 GitHub 上比较的链接。文件名中的日期会被忽略，\
+         因此不同日期的 nightly 会逐个文件比较。结果较长时以文本文件发送。\n\n\
+         示例：\n\
+         /diff #120 #134";
+    UsageRetry:
+        "/retry <arch|#id> [--dry-run] [--resume [--force]]\nRequest the latest finished build on an arch, or a build by id, again. \
+         --resume builds only the variants a failed release did not upload (see --allow-partial), \
+         refused if aoscbootstrap changed since unless --force is given.",
+        "/retry <架构|#ID> [--dry-run] [--resume [--force]]\n重新请求某架构最近完成的构建，或指定 ID 的构建。\
+         --resume 只构建失败的发行版构建未上传的变体（参见 --allow-partial），\
+         若 aoscbootstrap 此后有变更则拒绝，除非指定 --force。";
+    UsageRepush:
+        "/repush <arch|#id>\nUpload the artifacts of a build whose push failed again, without rebuilding. \
+         The worker keeps them for a while after the failure; once they are gone, use /retry.",
+        "/repush <架构|#ID>\n不重新构建，重新上传上传失败的构建的产物。\
+         构建机会在失败后保留产物一段时间；产物被清除后请使用 /retry。";
+    UsageCancel:
+        "/cancel <arch|#id> [--pool <pool>]\nCancel your latest queued build on an arch, \
+         in the pool of this chat unless --pool is given, or a queued or running build by id.",
+        "/cancel <架构|#ID> [--pool <构建池>]\n取消您在某架构上最近排队的构建（未指定 --pool 时为本会话所用构建池），\
+         或指定 ID 的排队中或运行中的构建。";
+    UsageHook:
+        "/hook [list]\n\
+         /hook add <type|*> <arch|*> post <url>\n\
+         /hook add <type|*> <arch|*> message <chat id> <template>\n\
+         /hook remove <n>\n\
+         Run an action after every successful build matching the type and arch (admin only). \
+         Posts send the build record including its manifest as JSON; message templates may use \
+         {id} {arch} {type} {variants} and {log_url}.\n\n\
+         Examples:\n\
+         /hook add release * post https://example.org/refresh\n\
+         /hook add livekit amd64 message -100123 livekit #{id} is out: {log_url}",
+        "/hook [list]\n\
+         /hook add <类型|*> <架构|*> post <URL>\n\
+         /hook add <类型|*> <架构|*> message <会话 ID> <模板>\n\
+         /hook remove <n>\n\
+         在每次类型和架构匹配的构建成功后执行一个动作（仅管理员）。\
+         post 以 JSON 格式发送构建记录及其清单；消息模板中可使用 \
+         {id} {arch} {type} {variants} 和 {log_url}。\n\n\
+         示例：\n\
+         /hook add release * post https://example.org/refresh\n\
+         /hook add livekit amd64 message -100123 livekit #{id} is out: {log_url}";
+    UsageQueue:
+        "/queue <arch> [--pool <pool>]\n\
+         Show the queued builds of an arch with their requester, age and estimated start, \
+         in the pool of this chat unless --pool is given.\n\n\
+         Admins may also edit the queue:\n\
+         /queue <arch> drop <n>: remove the nth job\n\
+         /queue <arch> top <n>: move the nth job to the front\n\
+         /queue <arch> clear: remove all jobs",
+        "/queue <架构> [--pool <构建池>]\n\
+         显示某架构排队中的构建及其请求者、等待时间和预计开始时间，未指定 --pool 时显示本会话所用构建池。\n\n\
+         管理员还可以编辑队列：\n\
+         /queue <架构> drop <n>：移除第 n 个任务\n\
+         /queue <架构> top <n>：将第 n 个任务移到最前\n\
+         /queue <架构> clear：移除所有任务";
+    UsageDisable:
+        "/disable <arch> [reason]\nRefuse new builds for an arch and stop handing queued ones to its workers, \
+         e.g. while the builder is repaired (admin only).\n\n\
+         Example:\n\
+         /disable ppc64el PSU replacement",
+        "/disable <架构> [原因]\n拒绝某架构的新构建，并停止将排队中的构建交给其构建机，例如在维修构建机期间（仅管理员）。\n\n\
+         示例：\n\
+         /disable ppc64el PSU replacement";
+    UsageEnable:
+        "/enable <arch>\nTake builds for a disabled arch again (admin only).",
+        "/enable <架构>\n重新接受已停用架构的构建（仅管理员）。";
+    UsageWindow:
+        "/window [arch] [HH:MM-HH:MM [time zone]|clear]\n\
+         Show the build windows, or set or clear the one of an arch (admin only). Outside its window \
+         an arch only takes builds requested with --now by an admin. The time zone defaults to UTC.\n\n\
+         Example:\n\
+         /window loongson3 00:00-08:00 Asia/Shanghai",
+        "/window [架构] [HH:MM-HH:MM [时区]|clear]\n\
+         显示各构建时段，或设置、清除某架构的构建时段（仅管理员）。在构建时段之外，\
+         该架构只接受管理员以 --now 请求的构建。时区默认为 UTC。\n\n\
+         示例：\n\
+         /window loongson3 00:00-08:00 Asia/Shanghai";
+    UsageLimits:
+        "/limits [total|arch|requester <n|off>|reset]\n\
+         Show the caps on queued builds and how often each refused a request, or change one (admin only). \
+         total caps the builds waiting in all queues, arch those in the queue of one arch, requester \
+         those of one chat or API token. Requests that would exceed a cap are refused as a whole; \
+         admins are exempt. The defaults come from the server configuration, changes are kept \
+         until /limits reset.\n\n\
+         Example:\n\
+         /limits requester 20",
+        "/limits [total|arch|requester <n|off>|reset]\n\
+         显示排队构建的上限及各上限拒绝请求的次数，或修改某一上限（仅管理员）。\
+         total 限制所有队列中等待的构建数，arch 限制单个架构队列中的构建数，requester \
+         限制单个会话或 API 令牌的构建数。会超出上限的请求将被整体拒绝；管理员不受限制。\
+         默认值来自服务器配置，修改会一直保留到 /limits reset。\n\n\
+         示例：\n\
+         /limits requester 20";
+    UsageWorkers:
+        "/workers\nList every worker that ever polled for jobs by arch and hostname, with its version, \
+         when it was last seen, whether it is idle, building or offline, its load, free disk and clock skew. \
+         A worker not seen for {offline}s is offline.\n\n\
+         Admins may also drop a decommissioned worker from the list:\n\
+         /workers forget <name>",
+        "/workers\n按架构和主机名列出所有轮询过任务的构建机，及其版本、最后在线时间、\
+         空闲、构建中或离线状态、负载、剩余磁盘空间和时钟偏差。{offline} 秒未见的构建机视为离线。\n\n\
+         管理员还可以将已退役的构建机从列表中移除：\n\
+         /workers forget <名称>";
+    UsageDrain:
+        "/drain <arch|worker> on|off\nLet a worker, or every worker of an arch, finish the build \
+         it is running and take no new one until /drain ... off, e.g. before rebooting it (admin only). \
+         Nothing is cancelled or requeued. /workers and /status tell when a drained worker is idle and \
+         safe to reboot; a building one acknowledges with its next heartbeat.\n\n\
+         Example:\n\
+         /drain riscv64 on",
+        "/drain <架构|构建机> on|off\n让某构建机或某架构的所有构建机完成正在运行的构建，\
+         在 /drain ... off 之前不再接受新任务，例如在重启前使用（仅管理员）。\
+         不会取消或重新排队任何构建。/workers 和 /status 会显示排空的构建机何时空闲、可以安全重启；\
+         正在构建的构建机会在下次心跳时确认。\n\n\
+         示例：\n\
+         /drain riscv64 on";
+    UsageUnpause:
+        "/unpause [<arch> <type> [channel]]\nList the scheduled builds paused after failing in a row, \
+         or take those of an arch and type again, in one channel or all (admin only).",
+        "/unpause [<架构> <类型> [渠道]]\n列出因连续失败而暂停的定时构建，\
+         或恢复某架构某类型在某个渠道或所有渠道的定时构建（仅管理员）。";
+    UsageWatch:
+        "/watch [on|off]\nShow whether this chat watches the server, or start or stop (admin only). \
+         Watching chats are told when someone else requests builds or a worker starts one, \
+         when a worker stops sending heartbeats during a build, and when dispatch to an arch \
+         is paused because a worker is low on disk.",
+        "/watch [on|off]\n显示本会话是否在关注服务器，或开始、停止关注（仅管理员）。\
+         关注的会话会在他人请求构建或构建机开始构建、构建机在构建期间停止发送心跳，\
+         以及因构建机磁盘空间不足而暂停向某架构分派任务时收到通知。";
+    UsageFreshness:
+        "/freshness\nShow when each arch last built and pushed each type of build, \
+         marked with ⚠️ if longer ago than {threshold}. The same times are on /metrics as \
+         shipit_last_success_timestamp, for alerts.",
+        "/freshness\n显示各架构各类构建最近一次成功构建并上传的时间，超过 {threshold} 的以 ⚠️ 标出。\
+         同样的时间也以 shipit_last_success_timestamp 出现在 /metrics 中，供告警使用。";
+    UsageOutbox:
+        "/outbox [clear]\nList notifications that could not be delivered, or forget them (admin only).",
+        "/outbox [clear]\n列出未能送达的通知，或将其清除（仅管理员）。";
+    UsageExport:
+        "/export\nSend the server state as a JSON document: queues, running builds, history, \
+         settings of the arches, chats and pools, and who requested what, \
+         for POST /import on another server (admin only). The pool secrets are left out.",
+        "/export\n以 JSON 文档发送服务器状态：队列、运行中的构建、历史、各架构、会话和构建池的设置，\
+         以及各构建的请求者，用于在另一台服务器上 POST /import（仅管理员）。不含构建池密钥。";
+    UsageDigest:
+        "/digest [now]\nShow the digest of the builds of the last 24 hours: builds run, successes and \
+         failures per arch, the volume pushed, the failures with their logs, and the arches and types \
+         without a success for long. With now, send it to the chats set up with /setup digest (admin only), \
+         as is done every day at shipit_digest_at if set.",
+        "/digest [now]\n显示过去 24 小时构建的摘要：各架构运行的构建数、成功和失败数、上传量、\
+         失败的构建及其日志，以及长时间未成功的架构和类型。使用 now 时将其发送到以 /setup digest \
+         设置的会话（仅管理员），若设置了 shipit_digest_at，每天也会在该时间发送。";
+    UsageChats:
+        "/chats\nList the chats the bot knows, when a notification was last delivered to each, \
+         and which ones are flagged undeliverable or were migrated to a supergroup (admin only). \
+         Talking to the bot from a flagged chat clears the flag.",
+        "/chats\n列出机器人所知的会话、每个会话最近一次送达通知的时间，\
+         以及哪些被标记为无法送达或已迁移到超级群组（仅管理员）。在被标记的会话中与机器人对话即可清除标记。";
+    UsageSetup:
+        "/setup [status|<role>|remove <role>|progress <edit|replace|off>]\nSet up the chat this is sent in for a role, show its roles, \
+         or remove one (admin only). Before setting up, the bot checks that it can post here and pin \
+         messages, by sending and deleting a message.\n\n\
+         Roles:\n\
+         announcements: finished builds of the pool of this chat, see /setpool\n\
+         alerts: what /watch tells, such as workers gone silent or low on disk\n\
+         default-notify: finished builds not requested from a chat\n\
+         digest: the daily summary of the builds, see /digest\n\n\
+         Chats configured with shipit_admin_chat or as the chat of a pool have those roles without /setup.\n\n\
+         While a build requested here runs, a message tells how far it is, updated in place. With progress, \
+         set what becomes of it once the build finishes:\n\
+         edit: it is edited into the result (the default)\n\
+         replace: it is deleted and the result posted anew, which notifies the chat\n\
+         off: nothing is posted before the result",
+        "/setup [status|<角色>|remove <角色>|progress <edit|replace|off>]\n为发送此命令的会话设置角色、\
+         显示其角色或移除某一角色（仅管理员）。设置前，机器人会发送并删除一条消息，以确认能在此发言和置顶消息。\n\n\
+         角色：\n\
+         announcements：本会话所用构建池的已完成构建，参见 /setpool\n\
+         alerts：/watch 所通知的内容，例如构建机失去响应或磁盘空间不足\n\
+         default-notify：并非从会话请求的已完成构建\n\
+         digest：每日构建摘要，参见 /digest\n\n\
+         通过 shipit_admin_chat 或作为构建池会话配置的会话无需 /setup 即拥有这些角色。\n\n\
+         在此请求的构建运行期间，会有一条消息原地更新显示进度。使用 progress \
+         设置构建完成后该消息的处理方式：\n\
+         edit：编辑为构建结果（默认）\n\
+         replace：删除后重新发布结果，会通知会话\n\
+         off：在结果之前不发布任何消息";
+    UsageLang:
+        "/lang [en|zh]\nShow or set the language the bot answers this chat in, \
+         including build notifications. English unless set.",
+        "/lang [en|zh]\n显示或设置机器人在本会话中使用的语言，包括构建通知。未设置时为英语。";
+    UsageSetpool:
+        "/setpool [pool]\nShow or set the pool of builds this chat uses when a command is not given \
+         --pool. Each pool has its own arches, queues, workers and history; a chat may only use \
+         the pools it is allowed to. Unless set, the first pool allowing this chat, or mainline.\n\n\
+         Pools: {pools}",
+        "/setpool [构建池]\n显示或设置命令未指定 --pool 时本会话使用的构建池。\
+         每个构建池有各自的架构、队列、构建机和历史；会话只能使用被允许的构建池。\
+         未设置时使用第一个允许本会话的构建池，或主线。\n\n\
+         构建池：{pools}";
+    UsageHelp:
+        "/help [command]\nList commands, or show detailed usage of one.",
+        "/help [命令]\n列出所有命令，或显示某个命令的详细用法。";
+
+    NoteEmpty: "--note needs a text", "--note 需要文本";
+    NoteTooLong: "The note is longer than {max} characters", "备注超过 {max} 个字符";
+    NoteUnclosed: "--note is missing its closing quote", "--note 缺少结尾的引号";
+    AfterNeedsValue: "--after needs a build type or #<id>", "--after 需要构建类型或 #<ID>";
+    FlavorNeedsValue: "--flavor needs a flavor", "--flavor 需要风味名称";
+    PoolNeedsValue: "--pool needs a pool name", "--pool 需要构建池名称";
+    EnvNeedsValue: "--env needs NAME=value, not {value}", "--env 需要 名称=值，而不是 {value}";
+    ArgNeedsValue: "--arg needs an argument", "--arg 需要参数";
+    BadChannel:
+        "Unknown channel: {channel} (expected release or nightly)",
+        "未知渠道：{channel}（应为 release 或 nightly）";
+    BadExpire: "Invalid duration for --expire: {value}", "--expire 的时长无效：{value}";
+    UnknownOption: "Unknown option: {option}", "未知选项：{option}";
+    EnvNotAllowed:
+        "Variable {name} is not allowed, allowed are: {allowed}",
+        "不允许使用变量 {name}，允许的有：{allowed}";
+    ArgNotAllowed:
+        "Argument {arg} is not allowed, allowed are: {allowed}",
+        "不允许使用参数 {arg}，允许的有：{allowed}";
+    NoneAllowed: "none", "无";
+    ReleaseNoVariants:
+        "No variants given, name them before the ';', or {default} for the default set",
+        "未指定变体，请在 ';' 之前写出变体，或使用 {default} 表示默认变体集";
+    ReleaseDefaultWithOthers:
+        "{default} is the whole default set, it goes without other variants",
+        "{default} 即整个默认变体集，不能与其他变体同时使用";
+    ReleaseArchAsVariant:
+        "Did you forget the ';'? Interpreting '{arch}' as a variant looks wrong, archs go after the ';'",
+        "是否遗漏了 ';'？将 '{arch}' 视为变体似乎有误，架构应写在 ';' 之后";
+    ReleaseLooksLikeArch:
+        "'{variant}' looks like the arch {arch}, did you forget the ';'? Add --yes to build it as a variant all the same",
+        "'{variant}' 看起来像架构 {arch}，是否遗漏了 ';'？如确要将其作为变体构建，请加上 --yes";
+
+    ChatsNone: "No chats known yet.", "尚无已知会话。";
+    ChatMigrated: ": migrated to {id}", "：已迁移到 {id}";
+    ChatDelivered: ": last delivery {ago} ago", "：最近一次送达于 {ago} 前";
+    ChatNothingDelivered: ": nothing delivered yet", "：尚未送达任何消息";
+    ChatUndeliverable: ", UNDELIVERABLE", "，无法送达";
+    ChatLastError: ", last error: {error}", "，最近的错误：{error}";
+}
+
+/// Names of the placeholders in `s`, e.g. `arch` for `{arch}`.
+fn placeholders(s: &str) -> BTreeSet<&str> {
+    let mut res = BTreeSet::new();
+    let mut rest = s;
+    while let Some((_, after)) = rest.split_once('{') {
+        let Some((name, after)) = after.split_once('}') else {
+            break;
+        };
+        if !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+            res.insert(name);
+        }
+        rest = after;
+    }
+
+    res
+}
+
+/// Refuse to start with a translation missing a placeholder of the English
+/// text, or using one it does not have.
+pub fn check() -> eyre::Result<()> {
+    let broken = Msg::ALL
+        .iter()
+        .filter(|m| {
+            let [en, zh] = m.texts();
+            placeholders(en) != placeholders(zh)
+        })
+        .map(|m| format!("{m:?}"))
+        .collect::<Vec<_>>();
+
+    if !broken.is_empty() {
+        eyre::bail!(
+            "Translations with placeholders differing from English: {}",
+            broken.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::utils::command::BotCommands;
+
+    use super::*;
+    use crate::bot::Command;
+
+    #[test]
+    fn translations_use_the_placeholders_of_the_english_texts() {
+        check().unwrap();
+    }
+
+    #[test]
+    fn every_text_is_there_in_both_languages() {
+        for m in Msg::ALL {
+            for (lang, text) in [Lang::En, Lang::Zh].into_iter().zip(m.texts()) {
+                assert!(!text.trim().is_empty(), "{m:?} is empty in {lang}");
+            }
+        }
+    }
+
+    #[test]
+    fn filling_the_placeholders_leaves_none() {
+        for &m in Msg::ALL {
+            let [en, _] = m.texts();
+            let names = placeholders(en);
+            let args = names
+                .iter()
+                .map(|x| (*x, &"x" as &(dyn Display + Sync)))
+                .collect::<Vec<_>>();
+            for lang in [Lang::En, Lang::Zh] {
+                let s = lang.tr(m, &args);
+                assert!(placeholders(&s).is_empty(), "{m:?} in {lang}: {s}");
+            }
+        }
+    }
+
+    #[test]
+    fn the_english_help_is_the_command_menu() {
+        assert_eq!(
+            Lang::En.text(Msg::HelpList),
+            Command::descriptions().to_string()
+        );
+    }
+
+    #[test]
+    fn the_help_lists_every_command_in_both_languages() {
+        for c in Command::bot_commands() {
+            let line = format!("\n/{} — ", c.command.trim_start_matches('/'));
+            for lang in [Lang::En, Lang::Zh] {
+                assert!(
+                    lang.text(Msg::HelpList).contains(&line),
+                    "/{} missing in {lang}",
+                    c.command
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,2441 @@
+mod access;
+mod alert;
+mod api;
+mod auth;
+pub mod bot;
+mod buttons;
+mod chats;
+pub mod db;
+mod diff;
+mod digest;
+mod events;
+mod expire;
+mod feed;
+mod format;
+mod freshness;
+mod hook;
+mod irc;
+mod lang;
+mod lifecycle;
+mod limits;
+mod logstore;
+mod notify;
+pub mod outbox;
+mod page;
+mod plan;
+mod pool;
+mod progress;
+mod retention;
+mod schema;
+mod secret;
+mod setup;
+mod stats;
+mod streak;
+pub mod telegram;
+#[cfg(test)]
+mod testing;
+mod traces;
+mod window;
+mod workers;
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use auth::{need, Auth, Scope};
+use axum::{
+    extract::{rejection::JsonRejection, DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap},
+    middleware,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use bot::{answer, pressed, unknown_command, Command};
+use db::{
+    ArtifactPush, Build, BuildType, Channel, Db, Failure, HistoryEntry, Manifest, Requested,
+    Resume, RunningBuild, Staleness, Step,
+};
+use events::Event;
+use eyre::Result;
+use lang::{Lang, Msg};
+use lifecycle::{IllegalTransition, Phase};
+use outbox::{cancel_dependents, Notification};
+use plan::{enqueue_build, EnqueueError, Options, Requester};
+use reqwest::StatusCode;
+use secret::Which;
+use serde::{Deserialize, Serialize};
+use setup::Role;
+use shipit_common::{CheckResult, Envelope, Outcome, Status, WorkerRelease, STATUS_VERSION};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use telegram::Telegram;
+use teloxide::{
+    dispatching::{Dispatcher, HandlerExt, UpdateFilterExt},
+    dptree,
+    types::{CallbackQuery, ChatId, ChatMemberUpdated, Message, Update},
+    Bot,
+};
+use tokio::{
+    io::AsyncReadExt,
+    sync::{Mutex, Semaphore, SemaphorePermit},
+};
+use tracing::{error, info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+/// What every handler shares, see [`AppState::from_env`].
+pub struct AppState {
+    /// Where every message to Telegram goes.
+    telegram: Telegram,
+    /// Shared by every handler, see [`AppState::db`].
+    db: Db,
+    /// Handlers using Redis at the same time, `shipit_redis_concurrency`
+    /// (default 16), so a burst of requests queues up here instead of
+    /// swamping the connection.
+    db_permits: Semaphore,
+    /// Held from checking the queues until the builds are queued, so
+    /// concurrent requests cannot both slip under a cap or both queue an
+    /// identical job.
+    queue_lock: Mutex<()>,
+    /// Sent to minzhengbu, and authenticating the mainline workers until
+    /// rotated, see [`secret`].
+    secret: String,
+    /// Seconds a secret replaced by `/rotate-secret` is still taken.
+    secret_grace: u64,
+    /// Secret for `/export` and `/import`, which are disabled if unset.
+    admin_secret: Option<String>,
+    /// The arches, secrets and users of each pool of builds.
+    pools: pool::Pools,
+    variants: Vec<String>,
+    admins: Vec<i64>,
+    login_ttl: u64,
+    login_grace: u64,
+    queue_max_age: u64,
+    /// Seconds a build pinned to a worker, a repush or a resumed release,
+    /// waits for it before failing.
+    affinity_timeout: u64,
+    access: access::Access,
+    rid: bot::RidPolicy,
+    /// JSON file describing the worker binaries to update to, see
+    /// `WorkerReleases`.
+    worker_release: Option<PathBuf>,
+    /// Script variables and arguments builds may be requested with.
+    passthrough: plan::Passthrough,
+    /// What releases of each arch may be built as with `--flavor`.
+    flavors: plan::Flavors,
+    /// Where completion notices also go, if set.
+    irc: Option<irc::IrcConfig>,
+    /// Where this server is reachable from the outside, completion notices
+    /// link to the build pages under it instead of the raw logs if set.
+    public_url: Option<String>,
+    /// Workers not seen for this many seconds are listed as offline.
+    worker_offline_after: u64,
+    /// `/freshness` warns about arches and types without a success for
+    /// longer.
+    freshness_threshold: u64,
+    /// `/status` flags uploads without progress for longer.
+    upload_stall_after: u64,
+    /// Seconds between updates of the progress posts, 0 for none, see
+    /// [`progress`].
+    progress_interval: u64,
+    /// Held while progress posts change, so a build finishing does not race
+    /// the update of its post.
+    progress_lock: Mutex<()>,
+    /// Seconds a build waits for a worker weighing its arch more before
+    /// lighter ones may claim it, see [`db::WorkerRecord::weight`].
+    cross_hold_back: u64,
+    /// Workers with less free disk are handed no jobs.
+    min_free_disk: workers::DiskPolicy,
+    /// Where operational alerts go, such as an arch paused for lack of
+    /// disk space.
+    admin_chat: Option<i64>,
+    /// Failures in a row that pause scheduled builds, 0 for never, see
+    /// [`streak`].
+    failure_streak: u64,
+    /// Who may queue builds through `POST /api/v1/builds`.
+    api: api::ApiTokens,
+    /// Queue caps unless set with `/limits`.
+    limits: limits::Limits,
+    /// Where workers may upload their logs to, if enabled.
+    logs: Option<logstore::LogStore>,
+    /// Where the build pages may read logs from besides `logs`.
+    log_hosts: page::LogHosts,
+    /// Where the records of finished builds are spooled, if enabled.
+    traces: Option<traces::Traces>,
+    /// How much of the history is kept.
+    retention: retention::Retention,
+}
+
+const ARCHS: &[&str] = &[
+    "amd64",
+    "arm64",
+    "loongarch64",
+    "ppc64el",
+    "loongson3",
+    "riscv64",
+];
+
+impl AppState {
+    /// The settings from the environment, only `shipit_secret` is required.
+    /// Messages go to `telegram`.
+    pub fn from_env(telegram: Telegram, db: Db) -> Result<Self> {
+        let secret = std::env::var("shipit_secret")?;
+        let admin_secret = std::env::var("shipit_admin_secret").ok();
+        let pools = pool::Pools::from_env(archs(), secret.clone())?;
+        let secret_grace = env_secs("shipit_secret_grace", 7 * 24 * 3600)?;
+        // empty means any variant aoscbootstrap knows about
+        let variants = env_list("shipit_variants").unwrap_or_default();
+        let admins = env_list("shipit_admins")
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<i64>, _>>()?;
+        let login_ttl = env_secs("shipit_login_ttl", 3600)?;
+        let login_grace = env_secs("shipit_login_grace", 7 * 24 * 3600)?;
+        let queue_max_age = env_secs("shipit_queue_max_age", 7 * 24 * 3600)?;
+        let affinity_timeout = env_secs("shipit_affinity_timeout", 6 * 3600)?;
+        let worker_offline_after = env_secs("shipit_worker_offline_after", 600)?;
+        let freshness_threshold = env_secs("shipit_freshness_threshold", 10 * 24 * 3600)?;
+        let upload_stall_after = env_secs("shipit_upload_stall_after", 300)?;
+        let progress_interval = env_secs("shipit_progress_interval", 60)?;
+        let cross_hold_back = env_secs("shipit_cross_hold_back", 120)?;
+        let redis_concurrency = match std::env::var("shipit_redis_concurrency") {
+            Ok(x) => x.parse()?,
+            Err(_) => 16,
+        };
+        let min_free_disk = workers::DiskPolicy::from_env()?;
+        let admin_chat = match std::env::var("shipit_admin_chat") {
+            Ok(x) => Some(x.parse()?),
+            Err(_) => None,
+        };
+        let failure_streak = match std::env::var("shipit_failure_streak") {
+            Ok(x) => x.parse()?,
+            Err(_) => 3,
+        };
+        let api = api::ApiTokens::from_env(admin_chat)?;
+        let limits = limits::Limits::from_env()?;
+        let access = access::Access::from_env()?;
+        let rid = bot::RidPolicy::from_env()?;
+        let worker_release = std::env::var("shipit_worker_release")
+            .ok()
+            .map(PathBuf::from);
+        let irc = irc::IrcConfig::from_env()?;
+        let public_url = match std::env::var("shipit_public_url") {
+            Ok(x) => {
+                Some(shipit_common::base_url("shipit_public_url", &x).map_err(|e| eyre::eyre!(e))?)
+            }
+            Err(_) => None,
+        };
+        let logs = logstore::LogStore::from_env(public_url.as_deref())?;
+        let log_hosts = page::LogHosts::from_env()?;
+        let traces = traces::Traces::from_env()?;
+        let retention = retention::Retention::from_env()?;
+        let passthrough = plan::Passthrough {
+            env: env_list("shipit_build_env").unwrap_or_default(),
+            args: env_list("shipit_build_args").unwrap_or_default(),
+        };
+
+        let flavors = plan::Flavors::from_env(&pools);
+
+        Ok(AppState {
+            telegram,
+            db,
+            db_permits: Semaphore::new(redis_concurrency),
+            queue_lock: Mutex::new(()),
+            secret,
+            secret_grace,
+            admin_secret,
+            pools,
+            variants,
+            admins,
+            login_ttl,
+            login_grace,
+            queue_max_age,
+            affinity_timeout,
+            access,
+            rid,
+            worker_release,
+            passthrough,
+            flavors,
+            irc,
+            public_url,
+            worker_offline_after,
+            freshness_threshold,
+            upload_stall_after,
+            progress_interval,
+            progress_lock: Mutex::new(()),
+            cross_hold_back,
+            min_free_disk,
+            admin_chat,
+            failure_streak,
+            api,
+            limits,
+            logs,
+            log_hosts,
+            traces,
+            retention,
+        })
+    }
+}
+
+impl AppState {
+    /// The database, once fewer than `shipit_redis_concurrency` handlers
+    /// use it.
+    pub async fn db(&self) -> DbGuard<'_> {
+        DbGuard {
+            db: self.db.clone(),
+            _permit: self
+                .db_permits
+                .acquire()
+                .await
+                .expect("the semaphore is never closed"),
+        }
+    }
+}
+
+/// See [`AppState::db`].
+pub struct DbGuard<'a> {
+    db: Db,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Deref for DbGuard<'_> {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        &self.db
+    }
+}
+
+impl DerefMut for DbGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Db {
+        &mut self.db
+    }
+}
+
+/// `shipit_archs`, or every arch AOSC OS is built for.
+fn archs() -> Vec<String> {
+    env_list("shipit_archs").unwrap_or_else(|| ARCHS.iter().map(|x| x.to_string()).collect())
+}
+
+/// The server, or one of its subcommands: `shipit events tail` and
+/// `shipit migrate`.
+pub async fn run() -> Result<()> {
+    dotenvy::dotenv().ok();
+    lang::check()?;
+
+    let env_log = EnvFilter::try_from_default_env();
+
+    if let Ok(filter) = env_log {
+        tracing_subscriber::registry()
+            .with(
+                fmt::layer()
+                    .event_format(
+                        tracing_subscriber::fmt::format()
+                            .with_file(true)
+                            .with_line_number(true),
+                    )
+                    .with_filter(filter),
+            )
+            .init();
+    } else {
+        tracing_subscriber::registry()
+            .with(
+                fmt::layer()
+                    .event_format(
+                        tracing_subscriber::fmt::format()
+                            .with_file(true)
+                            .with_line_number(true),
+                    )
+                    .with_filter(LevelFilter::INFO),
+            )
+            .init();
+    }
+
+    let db_uri = std::env::var("shipit_redis")?;
+    let events_len = match std::env::var("shipit_events_len") {
+        Ok(x) => x.parse()?,
+        Err(_) => 10000,
+    };
+    let mut db = Db::new(&db_uri, events_len).await?;
+    // `shipit events tail [group] [consumer]`: follow the event stream
+    let mut args = std::env::args().skip(1);
+    if (args.next().as_deref(), args.next().as_deref()) == (Some("events"), Some("tail")) {
+        let group = args.next().unwrap_or_else(|| "shipit-tail".to_string());
+        let consumer = args.next().unwrap_or_else(|| "tail".to_string());
+        return events::tail(&mut db, &group, &consumer).await;
+    }
+    let listen = std::env::var("shipit")?;
+    db.migrate_keys(&archs()).await?;
+    // `shipit migrate`: upgrade the stored builds and exit
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        let upgraded = db.migrate_builds().await?;
+        println!("Upgraded {upgraded} builds to schema {}", schema::SCHEMA);
+        return Ok(());
+    }
+    freshness::init(&mut db).await?;
+    let tls = access::tls_config()?;
+    let digest = digest::Schedule::from_env()?;
+
+    let bot = Bot::from_env();
+    let (telegram, sender) = telegram::channel(bot.clone());
+    let ac = Arc::new(AppState::from_env(telegram, db)?);
+    secret::load(&mut *ac.db().await, &ac.pools).await?;
+    let messages = Update::filter_message()
+        .branch(
+            dptree::filter_map(|msg: Message| msg.migrate_to_chat_id()).endpoint(
+                |msg: Message, new: ChatId, state: Arc<AppState>| async move {
+                    chats::migrated(msg, new, state).await
+                },
+            ),
+        )
+        .branch(dptree::entry().filter_command::<Command>().endpoint(
+            |msg: Message, cmd: Command, state: Arc<AppState>| async move {
+                answer(state.telegram.clone(), msg, cmd, state).await
+            },
+        ))
+        .branch(
+            dptree::filter(|msg: Message| msg.text().is_some_and(|x| x.starts_with('/'))).endpoint(
+                |msg: Message, state: Arc<AppState>| async move {
+                    unknown_command(state.telegram.clone(), msg, state).await
+                },
+            ),
+        );
+
+    let handler = dptree::entry()
+        .branch(messages)
+        .branch(Update::filter_my_chat_member().endpoint(
+            |upd: ChatMemberUpdated, state: Arc<AppState>| async move {
+                chats::member_updated(upd, state).await
+            },
+        ))
+        .branch(Update::filter_callback_query().endpoint(
+            |q: CallbackQuery, state: Arc<AppState>| async move { pressed(q, state).await },
+        ));
+
+    let mut dispatcher = Dispatcher::builder(bot, handler)
+        // // Pass the shared state to the handler as a dependency.
+        .dependencies(dptree::deps![ac.clone()])
+        .enable_ctrlc_handler()
+        .build();
+
+    tokio::spawn(async move { dispatcher.dispatch().await });
+    tokio::spawn(sender.run());
+    tokio::spawn(outbox::run(ac.clone()));
+    tokio::spawn(expire::run(ac.clone()));
+    tokio::spawn(alert::run(ac.clone()));
+    tokio::spawn(irc::run(ac.clone()));
+    tokio::spawn(logstore::run(ac.clone()));
+    tokio::spawn(buttons::run(ac.clone()));
+    tokio::spawn(traces::run(ac.clone()));
+    tokio::spawn(secret::run(ac.clone()));
+    tokio::spawn(progress::run(ac.clone()));
+    tokio::spawn(retention::run(ac.clone()));
+    if let Some(schedule) = digest {
+        tokio::spawn(digest::run(ac.clone(), schedule));
+    }
+
+    info!("shipit running at: {}", listen);
+    let app = router(ac).into_make_service_with_connect_info::<SocketAddr>();
+
+    match tls {
+        Some(tls) => {
+            axum_server::bind_rustls(listen.parse()?, RustlsConfig::from_config(tls))
+                .serve(app)
+                .await?
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(listen).await.unwrap();
+            axum::serve(listener, app).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whitespace separated list from the environment.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    std::env::var(name)
+        .ok()
+        .map(|x| x.split_ascii_whitespace().map(|x| x.to_string()).collect())
+}
+
+/// Number of seconds from the environment, or `default` if unset.
+fn env_secs(name: &str, default: u64) -> Result<u64> {
+    match std::env::var(name) {
+        Ok(x) => Ok(x.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Largest `/done` body accepted, a manifest of a few hundred artifacts and
+/// the step timeline fit comfortably.
+const DONE_BODY_LIMIT: usize = 1 << 20;
+/// Largest `/heartbeat` and `/progress` body accepted.
+const PROGRESS_BODY_LIMIT: usize = 256 << 10;
+/// Largest chunk of a log upload accepted, workers send 4 MiB by default.
+const LOG_CHUNK_LIMIT: usize = 16 << 20;
+/// Largest `/import` body accepted, a full history with its manifests and
+/// timelines.
+const IMPORT_BODY_LIMIT: usize = 64 << 20;
+
+/// The HTTP API. Nothing in here talks to Telegram directly, notifications
+/// only go into the outbox, so it can be driven against a scratch Redis
+/// alone. Serve it with `into_make_service_with_connect_info::<SocketAddr>`,
+/// the worker allowlist needs the peer address.
+pub fn router(state: Arc<AppState>) -> Router {
+    let workers = Router::new()
+        .route(
+            "/done",
+            post(build_done).layer(DefaultBodyLimit::max(DONE_BODY_LIMIT)),
+        )
+        .route("/workerisstarted", get(build_is_started))
+        .route(
+            "/heartbeat",
+            post(heartbeat).layer(DefaultBodyLimit::max(PROGRESS_BODY_LIMIT)),
+        )
+        .route(
+            "/progress",
+            post(progress).layer(DefaultBodyLimit::max(PROGRESS_BODY_LIMIT)),
+        )
+        .route("/worker/latest", get(worker_latest))
+        .route("/time", get(time))
+        .route("/authcheck", get(authcheck))
+        .route("/pong", post(pong))
+        .route("/logs/uploads", post(start_log_upload))
+        .route(
+            "/logs/uploads/:upload",
+            get(log_upload_status)
+                .put(log_upload_chunk)
+                .layer(DefaultBodyLimit::max(LOG_CHUNK_LIMIT)),
+        )
+        .route("/logs/uploads/:upload/finish", post(finish_log_upload))
+        .route_layer(middleware::from_fn_with_state(state.clone(), access::check));
+
+    Router::new()
+        .merge(workers)
+        .route("/stats", get(build_stats))
+        .route("/feed.atom", get(build_feed))
+        .route("/builds/:id/view", get(build_view))
+        .route("/logs/:name", get(serve_log))
+        .route("/metrics", get(metrics))
+        .route("/api/v1/workers", get(list_workers))
+        .route("/api/v1/freshness", get(list_freshness))
+        .route("/api/v1/builds", post(enqueue))
+        .route("/healthz", get(healthz))
+        .route("/export", get(export))
+        .route(
+            "/import",
+            post(import).layer(DefaultBodyLimit::max(IMPORT_BODY_LIMIT)),
+        )
+        .route("/migrate", post(migrate))
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct BuildDoneRequest {
+    id: i64,
+    arch: String,
+    /// Unknown types are refused with 422 by the extractor.
+    build_type: BuildType,
+    has_error: bool,
+    log_url: Option<String>,
+    push_success: bool,
+    #[serde(default)]
+    manifest: Option<Manifest>,
+    /// Name of the reporting worker, older workers do not send one.
+    #[serde(default)]
+    worker: Option<String>,
+    /// Requested variants for which no tarball or checksum was produced.
+    #[serde(default)]
+    missing_variants: Vec<String>,
+    #[serde(default)]
+    push: Option<RetryOutcome>,
+    #[serde(default)]
+    log_push: Option<RetryOutcome>,
+    #[serde(default)]
+    steps: Vec<Step>,
+    #[serde(default)]
+    phase_durations: BTreeMap<String, u64>,
+    #[serde(default)]
+    failure: Option<Failure>,
+    /// Where the worker kept the artifacts it failed to upload.
+    #[serde(default)]
+    failed_artifacts: Vec<String>,
+    #[serde(default)]
+    artifact_pushes: Vec<ArtifactPush>,
+    #[serde(default)]
+    scripts_commit: Option<String>,
+    #[serde(default)]
+    stale_scripts: bool,
+    #[serde(default)]
+    staleness: Option<Staleness>,
+    /// The log upload holding the full report, which was too large for
+    /// `/done`. It replaces this one.
+    #[serde(default)]
+    report: Option<String>,
+}
+
+/// How uploading went on the worker, after retries.
+#[derive(Deserialize)]
+struct RetryOutcome {
+    success: bool,
+    attempts: u32,
+    total_secs: f64,
+    last_status: Option<String>,
+    excerpt: Option<String>,
+}
+
+impl RetryOutcome {
+    /// `, failed after 5 attempts in 3m: exit status: 1` and the excerpt, or
+    /// nothing if it succeeded.
+    fn failure_text(&self, lang: Lang) -> String {
+        if self.success {
+            return String::new();
+        }
+
+        let mut s = lang.tr(
+            Msg::RetryFailure,
+            &[
+                ("attempts", &self.attempts),
+                ("duration", &format::human_duration(self.total_secs as u64)),
+            ],
+        );
+        if let Some(ref status) = self.last_status {
+            s.push_str(&format!(": {status}"));
+        }
+        if let Some(ref excerpt) = self.excerpt {
+            s.push_str(&format!("\n{excerpt}"));
+        }
+
+        s
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum BuildRequestError {
+    #[snafu(display("Failed to mod redis database."))]
+    Redis { source: eyre::Error },
+    #[snafu(display("Bad secret."))]
+    BadSecret,
+    #[snafu(display("Pool {declared} does not take workers with the secret of pool {pool}."))]
+    PoolMismatch { declared: String, pool: String },
+    #[snafu(display("Build #{id} belongs to pool {build_pool}, not {pool}."))]
+    OtherPool {
+        id: i64,
+        build_pool: String,
+        pool: String,
+    },
+    #[snafu(display("Build #{id} is not running on {arch}."))]
+    NotRunning { id: i64, arch: String },
+    #[snafu(display("Build #{id} was claimed by {claimed_by}, not {worker}."))]
+    WorkerMismatch {
+        id: i64,
+        claimed_by: String,
+        worker: String,
+    },
+    #[snafu(display("No worker release for {arch}."))]
+    NoWorkerRelease { arch: String },
+    #[snafu(display("Failed to read the worker release file."))]
+    WorkerRelease { source: eyre::Error },
+    #[snafu(display("Refusing to overwrite the existing state, import with force=true."))]
+    NotEmpty,
+    #[snafu(display("Ping #{id} is not waiting for an answer."))]
+    NoPing { id: i64 },
+    #[snafu(display("No finished build #{id}."))]
+    NoBuild { id: i64 },
+    /// Too large (413), not JSON (415) or not matching the request type.
+    #[snafu(display("Invalid request body."))]
+    Body { source: JsonRejection },
+    #[snafu(display("{source}"))]
+    Illegal { source: IllegalTransition },
+    #[snafu(display("Missing or unknown API token."))]
+    BadToken,
+    #[snafu(display("Token {token} lacks the {scope} scope."))]
+    Scope { token: String, scope: String },
+    #[snafu(display("Unknown build type {build_type}."))]
+    UnknownBuildType { build_type: String },
+    #[snafu(display("Building a ref is not supported, workers build what their checkout pulls."))]
+    UnsupportedRef,
+    #[snafu(display("{reason}"))]
+    Refused { reason: String },
+    #[snafu(display("{full}"))]
+    QueueFull { full: limits::QueueFull },
+    #[snafu(display("Log uploads are disabled."))]
+    LogsDisabled,
+    #[snafu(display("{source}"))]
+    Log { source: logstore::LogError },
+    #[snafu(display("No log {name}."))]
+    NoLog { name: String },
+    #[snafu(display("Unusable report {name}: {reason}"))]
+    BadReport { name: String, reason: String },
+}
+
+/// Tells an illegal transition from a failed database.
+fn db_error(source: eyre::Error) -> BuildRequestError {
+    match source.downcast::<IllegalTransition>() {
+        Ok(source) => BuildRequestError::Illegal { source },
+        Err(source) => BuildRequestError::Redis { source },
+    }
+}
+
+impl IntoResponse for BuildRequestError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            BuildRequestError::Redis { ref source } => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{}: {}", self, source),
+            )
+                .into_response(),
+            BuildRequestError::BadSecret => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            BuildRequestError::PoolMismatch { .. } | BuildRequestError::OtherPool { .. } => {
+                (StatusCode::FORBIDDEN, self.to_string()).into_response()
+            }
+            BuildRequestError::NotRunning { .. } => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            BuildRequestError::WorkerMismatch { .. } => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            BuildRequestError::NoWorkerRelease { .. } => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            BuildRequestError::WorkerRelease { ref source } => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{}: {}", self, source),
+            )
+                .into_response(),
+            BuildRequestError::NotEmpty => (StatusCode::CONFLICT, self.to_string()).into_response(),
+            BuildRequestError::NoPing { .. } | BuildRequestError::NoBuild { .. } => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            BuildRequestError::Body { ref source } => {
+                (source.status(), format!("{}: {}", self, source.body_text())).into_response()
+            }
+            BuildRequestError::Illegal { .. } => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            BuildRequestError::BadToken => {
+                (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+            }
+            BuildRequestError::Scope { .. } => {
+                (StatusCode::FORBIDDEN, self.to_string()).into_response()
+            }
+            BuildRequestError::UnknownBuildType { .. } => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            BuildRequestError::UnsupportedRef
+            | BuildRequestError::Refused { .. }
+            | BuildRequestError::BadReport { .. } => {
+                (StatusCode::UNPROCESSABLE_ENTITY, self.to_string()).into_response()
+            }
+            BuildRequestError::LogsDisabled | BuildRequestError::NoLog { .. } => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            BuildRequestError::Log { ref source } => {
+                let status = match source {
+                    logstore::LogError::NoUpload { .. } => StatusCode::NOT_FOUND,
+                    logstore::LogError::Offset { .. } => StatusCode::CONFLICT,
+                    logstore::LogError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                    logstore::LogError::Invalid { .. } | logstore::LogError::Mismatch { .. } => {
+                        StatusCode::UNPROCESSABLE_ENTITY
+                    }
+                    logstore::LogError::Io { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, self.to_string()).into_response()
+            }
+            BuildRequestError::QueueFull { ref full } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({
+                    "error": self.to_string(),
+                    "queue_full": full,
+                })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+async fn build_done(
+    auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<BuildDoneRequest>, JsonRejection>,
+) -> Result<(), BuildRequestError> {
+    let Json(request) = request.context(BodySnafu)?;
+    let request = match request.report {
+        Some(ref name) => uploaded_report(&state, name, &request).await?,
+        None => request,
+    };
+    let pool = auth.pool(&state.pools);
+
+    let mut db = state.db().await;
+
+    let finished_at = db::now();
+    let running = db
+        .get_running(&request.arch, request.id)
+        .await
+        .context(RedisSnafu)?;
+
+    match running {
+        Some(ref r) => {
+            check_pool(&mut db, pool, &r.build, request.worker.as_deref()).await?;
+            check_worker(r, request.worker.as_deref())?;
+        }
+        // finished as it was being cancelled, the cancel stands
+        None if db.cancelled_running(request.id).await.context(RedisSnafu)? => {
+            info!(
+                "Build #{} on {} was cancelled, dropping its result",
+                request.id, request.arch
+            );
+            return Ok(());
+        }
+        None => warn!(
+            "Build #{} is not running on {}, its requester is unknown",
+            request.id, request.arch
+        ),
+    }
+
+    let started_at = running.as_ref().and_then(|r| r.build.started_at);
+    let requester_chat = running.as_ref().map(|r| r.build.requester_chat);
+    let channel = running
+        .as_ref()
+        .map(|r| r.build.channel)
+        .unwrap_or_default();
+    let (env, args) = running
+        .as_ref()
+        .map(|r| (r.build.env.clone(), r.build.args.clone()))
+        .unwrap_or_default();
+
+    // refuses e.g. a second report, before anything is changed
+    let lifecycle = db
+        .transition(
+            request.id,
+            if request.has_error {
+                Phase::Failed
+            } else {
+                Phase::Succeeded
+            },
+        )
+        .await
+        .map_err(db_error)?;
+    db.set_build_done(&request.arch, request.id)
+        .await
+        .context(RedisSnafu)?;
+
+    // a resumed release carries the variants kept from the failed one
+    let resume = running.as_ref().and_then(|r| r.build.resume.clone());
+    let manifest = match resume {
+        Some(ref resume) => {
+            let prior = db.find_history(resume.from).await.context(RedisSnafu)?;
+            Manifest::merge_resumed(
+                request.manifest.clone(),
+                prior.as_ref().and_then(|x| x.manifest.as_ref()),
+                resume,
+            )
+        }
+        None => request.manifest.clone(),
+    };
+    let mut built_variants = manifest
+        .iter()
+        .flat_map(|m| &m.artifacts)
+        .filter(|a| request.push_success || a.build.is_some())
+        .filter_map(|a| a.variant.clone())
+        .collect::<Vec<_>>();
+    built_variants.sort();
+    built_variants.dedup();
+
+    // the requester or the chats for builds nobody asked for from a chat,
+    // and the announcement chats of the pool
+    let mut chats = match requester_chat.filter(|x| *x != 0) {
+        Some(chat) => vec![chat],
+        None => setup::chats(&mut db, Role::DefaultNotify, None)
+            .await
+            .context(RedisSnafu)?,
+    };
+    let announce = setup::chats(&mut db, Role::Announcements, Some(&pool.name))
+        .await
+        .context(RedisSnafu)?;
+    for chat in pool.chat.into_iter().chain(announce) {
+        if !chats.contains(&chat) {
+            chats.push(chat);
+        }
+    }
+
+    let mut entry = HistoryEntry {
+        id: request.id,
+        requester_chat,
+        arch: request.arch.clone(),
+        pool: pool.name.clone(),
+        build_type: request.build_type.name().to_string(),
+        variants: request.build_type.variants().map(|x| x.to_vec()),
+        success: !request.has_error,
+        push_success: request.push_success,
+        log_url: request.log_url.clone(),
+        manifest,
+        finished_at,
+        duration_secs: started_at.map(|x| finished_at.saturating_sub(x)),
+        hooks: vec![],
+        missing_variants: request.missing_variants.clone(),
+        channel,
+        steps: request.steps.clone(),
+        phase_durations: request.phase_durations.clone(),
+        failure: request.failure.clone(),
+        worker: request.worker.clone(),
+        failed_artifacts: request.failed_artifacts.clone(),
+        artifact_pushes: request.artifact_pushes.clone(),
+        queued: vec![],
+        env,
+        args,
+        scripts_commit: request.scripts_commit.clone(),
+        built_variants,
+        resumed_from: resume.map(|x| x.from),
+        stale_scripts: request.staleness.clone().filter(|_| request.stale_scripts),
+        transitions: lifecycle.transitions,
+        cross: running.as_ref().is_some_and(|r| r.cross),
+        note: running.as_ref().and_then(|r| r.build.note.clone()),
+        flavor: running.as_ref().and_then(|r| r.build.flavor.clone()),
+        pruned: false,
+    };
+
+    let text = |lang| {
+        format::completion_text(
+            &entry,
+            lang,
+            state.public_url.as_deref(),
+            &request
+                .log_push
+                .as_ref()
+                .map(|x| x.failure_text(lang))
+                .unwrap_or_default(),
+            &request
+                .push
+                .as_ref()
+                .map(|x| x.failure_text(lang))
+                .unwrap_or_default(),
+        )
+    };
+    let notice = notify::Notice {
+        entry: &entry,
+        text: &text,
+        manifest: entry.manifest.as_ref(),
+    };
+    let telegram = notify::TelegramNotifier { chats };
+    let mut notifiers: Vec<&dyn notify::Notifier> = vec![&telegram];
+    if state.irc.is_some() {
+        notifiers.push(&notify::IrcNotifier);
+    }
+    // nobody is told of a build the history lost
+    db.push_history(&entry).await.context(RedisSnafu)?;
+    // the build result is committed, a backend failing to take its notice
+    // must neither keep the others from theirs nor make the worker report
+    // it again
+    let queued = notify::fire(&mut db, &notifiers, &notice).await;
+    if !queued.is_empty() {
+        db.update_history(entry.id, |h| h.queued = queued.clone())
+            .await
+            .context(RedisSnafu)?;
+    }
+    entry.queued = queued;
+    db.publish(&Event::done(&entry, &request.build_type)).await;
+    if let Some(ref traces) = state.traces {
+        if let Err(e) = traces.append(&entry).await {
+            error!("Failed to spool the trace of #{}: {e}", entry.id);
+        }
+    }
+    if let Some(ref r) = running {
+        let log = format::log_link(&entry, state.public_url.as_deref());
+        db.index_requested(&r.build.actor(), &Requested::finished(&entry, log))
+            .await
+            .context(RedisSnafu)?;
+    }
+    freshness::record(&mut db, &entry)
+        .await
+        .context(RedisSnafu)?;
+    streak::record(&mut db, &state, &entry)
+        .await
+        .context(RedisSnafu)?;
+
+    if !entry.success {
+        cancel_dependents(
+            &mut db,
+            &entry.pool,
+            &entry.arch,
+            entry.id,
+            Msg::DependencyFailed,
+        )
+        .await
+        .context(RedisSnafu)?;
+    }
+
+    if entry.success && entry.push_success {
+        tokio::spawn(hook::run(state.clone(), entry.clone()));
+    }
+    if let Some(url) = running.as_ref().and_then(|r| r.build.webhook.clone()) {
+        tokio::spawn(hook::run_webhook(state.clone(), entry.clone(), url));
+    }
+
+    Ok(())
+}
+
+/// Refuse, and audit, a worker of `pool` reporting about a build of another
+/// pool.
+async fn check_pool(
+    db: &mut Db,
+    pool: &pool::Pool,
+    build: &Build,
+    worker: Option<&str>,
+) -> Result<(), BuildRequestError> {
+    if build.pool == pool.name {
+        return Ok(());
+    }
+
+    let worker = worker.unwrap_or("unnamed");
+    warn!(
+        "Worker {worker} of pool {} reported on #{} of pool {}",
+        pool.name, build.id, build.pool
+    );
+    db.audit(
+        &format!("worker:{worker}"),
+        &format!(
+            "denied: report on #{} of pool {} from pool {}",
+            build.id, build.pool, pool.name
+        ),
+    )
+    .await
+    .context(RedisSnafu)?;
+
+    OtherPoolSnafu {
+        id: build.id,
+        build_pool: &build.pool,
+        pool: &pool.name,
+    }
+    .fail()
+}
+
+/// Refuse reports about a build from a worker other than the one that
+/// claimed it. Either side being unnamed predates worker names, let it pass.
+fn check_worker(running: &RunningBuild, worker: Option<&str>) -> Result<(), BuildRequestError> {
+    match (running.worker.as_deref(), worker) {
+        (Some(claimed_by), Some(worker)) if claimed_by != worker => WorkerMismatchSnafu {
+            id: running.build.id,
+            claimed_by,
+            worker,
+        }
+        .fail(),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+struct BuildStartRequest {
+    /// Arches the worker builds for, comma separated, its native one
+    /// first.
+    arch: String,
+    #[serde(default)]
+    worker: Option<String>,
+    /// Seconds the clock of the worker is off, sent instead of claiming a
+    /// job when that is more than the worker accepts.
+    #[serde(default)]
+    skew: Option<i64>,
+    /// The worker answers pings, older ones do not know [`Status::Ping`].
+    #[serde(default)]
+    ping: bool,
+    /// Why the upload host of the worker did not answer its preflight
+    /// check, if it did not.
+    #[serde(default)]
+    unreachable: Option<String>,
+    /// The worker takes no jobs while its upload host is unreachable.
+    #[serde(default)]
+    blocked: bool,
+    /// Why the worker takes no job now, e.g. `load 9.3 > 6.0`.
+    #[serde(default)]
+    deferring: Option<String>,
+    /// Build types the worker supports, comma separated. Workers not
+    /// telling are handed any.
+    #[serde(default)]
+    types: Option<String>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+    /// Seconds the clock of the worker is off as last measured, within
+    /// bounds or not, unlike `skew`.
+    #[serde(default)]
+    clock: Option<i64>,
+    /// Load averages separated by spaces, as in `/proc/loadavg`.
+    #[serde(default)]
+    load: Option<String>,
+    #[serde(default)]
+    free_disk: Option<u64>,
+    /// The pool the worker belongs to, mainline if unset. Its secret must
+    /// be the one of that pool.
+    #[serde(default)]
+    pool: Option<String>,
+    /// The weight of each of `arch`, comma separated, see
+    /// [`db::WorkerRecord::weight`].
+    #[serde(default)]
+    weights: Option<String>,
+}
+
+/// What a worker gets to know about a build. The requester stays on the
+/// server, `/done` looks it up from the running build.
+#[derive(Serialize)]
+struct Job {
+    id: i64,
+    arch: String,
+    #[serde(with = "shipit_common::legacy")]
+    build_type: BuildType,
+    allow_partial: bool,
+    channel: Channel,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    args: Vec<String>,
+    no_clean: bool,
+    incremental: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flavor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resume: Option<Resume>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+impl From<Build> for Job {
+    fn from(b: Build) -> Self {
+        Job {
+            id: b.id,
+            arch: b.arch,
+            build_type: b.build_type,
+            allow_partial: b.allow_partial,
+            channel: b.channel,
+            env: b.env,
+            args: b.args,
+            no_clean: b.no_clean,
+            incremental: b.incremental,
+            flavor: b.flavor,
+            resume: b.resume,
+            note: b.note,
+        }
+    }
+}
+
+/// `/workerisstarted` as answered to workers not sending `Accept-Version`.
+#[derive(Serialize)]
+enum LegacyStatus {
+    Working(Box<Job>),
+    Pending,
+    Ping(i64),
+}
+
+impl From<Status<Job>> for LegacyStatus {
+    fn from(s: Status<Job>) -> Self {
+        match s {
+            Status::Working { job } => LegacyStatus::Working(Box::new(job)),
+            Status::Ping { id, .. } => LegacyStatus::Ping(id),
+            Status::Pending | Status::Unknown => LegacyStatus::Pending,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum StatusReply {
+    Legacy(LegacyStatus),
+    Versioned(Box<Envelope<Job>>),
+}
+
+async fn build_is_started(
+    header: HeaderMap,
+    auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    Query(request): Query<BuildStartRequest>,
+) -> Result<Json<StatusReply>, BuildRequestError> {
+    // the newest format both sides know
+    let version = header
+        .get("accept-version")
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<u32>().ok())
+        .map(|x| x.min(STATUS_VERSION))
+        .unwrap_or(1);
+    let status = next_status(auth.pool(&state.pools), &state, request).await?;
+
+    Ok(Json(if version >= 2 {
+        StatusReply::Versioned(Box::new(Envelope { v: version, status }))
+    } else {
+        StatusReply::Legacy(status.into())
+    }))
+}
+
+/// What to tell a polling worker: record what it reported, then hand it a
+/// ping or a job if there is one.
+async fn next_status(
+    pool: &pool::Pool,
+    state: &AppState,
+    request: BuildStartRequest,
+) -> Result<Status<Job>, BuildRequestError> {
+    let mut db = state.db().await;
+
+    let declared = request.pool.as_deref().unwrap_or(pool::MAINLINE);
+    if declared != pool.name {
+        let worker = request.worker.as_deref().unwrap_or("unnamed");
+        warn!(
+            "Worker {worker} declared pool {declared} with the secret of pool {}",
+            pool.name
+        );
+        db.audit(
+            &format!("worker:{worker}"),
+            &format!(
+                "denied: joining pool {declared} with the secret of pool {}",
+                pool.name
+            ),
+        )
+        .await
+        .context(RedisSnafu)?;
+        return PoolMismatchSnafu {
+            declared,
+            pool: &pool.name,
+        }
+        .fail();
+    }
+
+    let arches = request
+        .arch
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>();
+
+    let worker = db::WorkerRecord {
+        name: request
+            .worker
+            .clone()
+            .unwrap_or_else(|| "unnamed".to_string()),
+        pool: pool.name.clone(),
+        arch: arches.first().unwrap_or(&"").to_string(),
+        arches: arches.iter().map(|x| x.to_string()).collect(),
+        hostname: request.hostname.clone(),
+        version: request.version.clone(),
+        last_seen: db::now(),
+        load_avg: request.load.as_deref().and_then(|x| {
+            let v = x
+                .split_ascii_whitespace()
+                .map(|x| x.parse().ok())
+                .collect::<Option<Vec<f64>>>()?;
+            v.try_into().ok()
+        }),
+        free_disk: request.free_disk,
+        clock_skew: request.clock.or(request.skew),
+        weights: request
+            .weights
+            .as_deref()
+            .and_then(|x| x.split(',').map(|x| x.trim().parse().ok()).collect())
+            .unwrap_or_default(),
+        deferring: request.deferring.clone(),
+    };
+    db.set_worker(&worker).await.context(RedisSnafu)?;
+
+    // answered even by a worker refusing jobs, it is alive after all
+    if request.ping {
+        for arch in &arches {
+            if let Some(p) = db.take_ping(arch).await.context(RedisSnafu)? {
+                return Ok(Status::Ping {
+                    id: p.id,
+                    check: p.check,
+                });
+            }
+        }
+    }
+
+    // busy or hot, it checks again at the next poll
+    if worker.deferring.is_some() {
+        return Ok(Status::Pending);
+    }
+
+    if let Some(skew) = request.skew {
+        let worker = request.worker.as_deref().unwrap_or("unnamed");
+        warn!(
+            "Worker {worker} on {} has a clock skew of {skew}s",
+            request.arch
+        );
+        db.set_clock_skew(&request.arch, worker, skew)
+            .await
+            .context(RedisSnafu)?;
+        return Ok(Status::Pending);
+    }
+
+    if let Some(error) = request.unreachable {
+        let worker = request.worker.as_deref().unwrap_or("unnamed");
+        warn!(
+            "Worker {worker} on {} cannot reach its upload host: {error}",
+            request.arch
+        );
+        db.set_unreachable(&db::Unreachable {
+            arch: request.arch.clone(),
+            worker: worker.to_string(),
+            error,
+            blocked: request.blocked,
+        })
+        .await
+        .context(RedisSnafu)?;
+        if request.blocked {
+            return Ok(Status::Pending);
+        }
+    }
+
+    // the build it may be running goes on, it only gets no new one
+    let drains = db.drains().await.context(RedisSnafu)?;
+    if drains.contains_key(&workers::worker_drain(&worker.name)) {
+        return Ok(Status::Pending);
+    }
+
+    let types = request
+        .types
+        .as_deref()
+        .map(|x| x.split(',').collect::<Vec<_>>());
+
+    // the first arch with a job for this worker, in the order it listed them
+    for arch in &arches {
+        // stale queue entries of a disabled arch stay where they are
+        if db.is_disabled(arch).await.context(RedisSnafu)?.is_some() {
+            continue;
+        }
+
+        if drains.contains_key(&workers::arch_drain(arch)) {
+            continue;
+        }
+
+        if !enough_disk(state, &mut db, arch, &worker).await? {
+            continue;
+        }
+
+        // outside its window, an arch only takes builds requested with --now
+        let in_window = db
+            .window(arch)
+            .await
+            .context(RedisSnafu)?
+            .is_none_or(|w| w.is_open(db::now()));
+
+        let preference = preference(state, &mut db, &worker, arch, &drains).await?;
+        let build = db
+            .claim(
+                &pool.name,
+                arch,
+                request.worker.as_deref(),
+                types.as_deref(),
+                in_window,
+                preference,
+            )
+            .await
+            .context(RedisSnafu)?;
+        if let Some(b) = build {
+            db.publish(&Event::new(events::Kind::Claimed, &b).with_worker(Some(&worker.name)))
+                .await;
+            // the build is claimed, it goes out even if the notice does not
+            if let Err(e) = notify_started(&mut db, state, &b, &worker.name).await {
+                warn!("Failed to notify watchers of #{}: {e}", b.id);
+            }
+            return Ok(Status::Working { job: b.into() });
+        }
+    }
+
+    Ok(Status::Pending)
+}
+
+/// What `worker` may claim of `arch`: a cross builder no `--native-only`
+/// builds, and one weighing the arch less than another live worker of its
+/// pool only builds that waited for the hold-back.
+async fn preference(
+    state: &AppState,
+    db: &mut Db,
+    worker: &db::WorkerRecord,
+    arch: &str,
+    drains: &BTreeMap<String, db::Drain>,
+) -> Result<db::Preference, BuildRequestError> {
+    let weight = worker.weight(arch).unwrap_or_default();
+    let now = db::now();
+    let heaviest = db
+        .workers()
+        .await
+        .context(RedisSnafu)?
+        .iter()
+        .filter(|w| w.pool == worker.pool && w.name != worker.name)
+        .filter(|w| now.saturating_sub(w.last_seen) <= state.worker_offline_after)
+        .filter(|w| workers::drained(drains, &w.name, &w.arch_names()).is_none())
+        .filter_map(|w| w.weight(arch))
+        .max();
+
+    Ok(db::Preference {
+        cross: worker.arch != arch,
+        hold_back: heaviest
+            .filter(|x| *x > weight)
+            .map(|_| state.cross_hold_back),
+    })
+}
+
+/// Tell the watchers but the requester that `worker` started `build`.
+async fn notify_started(
+    db: &mut Db,
+    state: &AppState,
+    build: &Build,
+    worker: &str,
+) -> eyre::Result<()> {
+    let requester = alert::chat_name(db, build.requester_chat).await?;
+
+    alert::notify(
+        db,
+        state,
+        Some(build.requester_chat),
+        Msg::WatchStarted,
+        &[
+            ("id", &build.id),
+            ("build", &build.build_type),
+            ("arch", &build.arch),
+            ("worker", &worker),
+            ("requester", &requester),
+        ],
+    )
+    .await
+}
+
+/// Whether `worker` has the free disk needed to build for `arch`. If not,
+/// dispatch to it is paused until it reports enough, and the watchers are
+/// told when this is the first worker of the arch to run low.
+async fn enough_disk(
+    state: &AppState,
+    db: &mut Db,
+    arch: &str,
+    worker: &db::WorkerRecord,
+) -> Result<bool, BuildRequestError> {
+    let (Some(min), Some(free)) = (state.min_free_disk.min(arch), worker.free_disk) else {
+        return Ok(true);
+    };
+
+    if free >= min {
+        db.clear_low_disk(arch, &worker.name)
+            .await
+            .context(RedisSnafu)?;
+        return Ok(true);
+    }
+
+    warn!(
+        "Worker {} on {arch} has {} free, needs {}, not handing it jobs",
+        worker.name,
+        format::human_bytes(free),
+        format::human_bytes(min)
+    );
+    let entered = db
+        .set_low_disk(&db::LowDisk {
+            arch: arch.to_string(),
+            worker: worker.name.clone(),
+            free,
+            min,
+        })
+        .await
+        .context(RedisSnafu)?;
+
+    if entered {
+        alert::notify(
+            db,
+            state,
+            None,
+            Msg::LowDiskAlert,
+            &[
+                ("arch", &arch),
+                ("worker", &worker.name),
+                ("free", &format::human_bytes(free)),
+                ("min", &format::human_bytes(min)),
+            ],
+        )
+        .await
+        .context(RedisSnafu)?;
+    }
+
+    Ok(false)
+}
+
+#[derive(Deserialize)]
+struct PongRequest {
+    id: i64,
+    worker: String,
+    hostname: String,
+    version: String,
+    /// 1, 5 and 15 minute load averages.
+    #[serde(default)]
+    load_avg: Option<[f64; 3]>,
+    /// Free space where the worker builds.
+    #[serde(default)]
+    free_disk: Option<u64>,
+    /// What `worker check` found, if asked with `/ping --check`.
+    #[serde(default)]
+    checks: Vec<CheckResult>,
+}
+
+/// A worker answering a `/ping`, the requester is told how it is doing.
+async fn pong(
+    _auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<PongRequest>, JsonRejection>,
+) -> Result<(), BuildRequestError> {
+    let Json(request) = request.context(BodySnafu)?;
+
+    let mut db = state.db().await;
+    let Some(ping) = db.finish_ping(request.id).await.context(RedisSnafu)? else {
+        return NoPingSnafu { id: request.id }.fail();
+    };
+
+    let lang = db.lang(ping.chat).await.context(RedisSnafu)?;
+    let mut text = lang.tr(
+        Msg::Pong,
+        &[
+            ("arch", &ping.arch),
+            ("worker", &request.worker),
+            ("hostname", &request.hostname),
+            ("version", &request.version),
+            ("ms", &db::now_ms().saturating_sub(ping.sent_at_ms)),
+        ],
+    );
+    if let Some([a, b, c]) = request.load_avg {
+        text.push_str(&lang.tr(Msg::PongLoad, &[("load", &format!("{a:.2} {b:.2} {c:.2}"))]));
+    }
+    if let Some(free) = request.free_disk {
+        text.push_str(&lang.tr(Msg::PongFree, &[("free", &format::human_bytes(free))]));
+    }
+    for c in &request.checks {
+        let mark = match c.outcome {
+            Outcome::Pass => "✅",
+            Outcome::Warn => "⚠️",
+            Outcome::Fail => "❌",
+        };
+        text.push_str(&format!("\n{mark} {}: {}", c.name, c.detail));
+    }
+
+    db.push_outbox(&Notification::plain(ping.chat, &text))
+        .await
+        .context(RedisSnafu)?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct HeartbeatRequest {
+    id: i64,
+    arch: String,
+    #[serde(default)]
+    worker: Option<String>,
+    #[serde(default)]
+    steps: Vec<Step>,
+    #[serde(default)]
+    free_disk: Option<u64>,
+    /// The worker knows it drains, from the reply to an earlier heartbeat.
+    #[serde(default)]
+    draining: bool,
+    /// How far the upload is, if uploading.
+    #[serde(default)]
+    upload: Option<db::UploadProgress>,
+}
+
+#[derive(Serialize, Default)]
+struct HeartbeatReply {
+    /// Take no new job after this one, see `/drain`.
+    draining: bool,
+    /// Stop the build, it was cancelled, see `/cancel`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    cancel: bool,
+}
+
+async fn heartbeat(
+    auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<HeartbeatRequest>, JsonRejection>,
+) -> Result<Json<HeartbeatReply>, BuildRequestError> {
+    let Json(request) = request.context(BodySnafu)?;
+    touch(auth.pool(&state.pools), &state, request, None).await
+}
+
+/// A heartbeat with the stage the build entered.
+#[derive(Deserialize)]
+struct ProgressRequest {
+    #[serde(flatten)]
+    heartbeat: HeartbeatRequest,
+    stage: String,
+}
+
+async fn progress(
+    auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<ProgressRequest>, JsonRejection>,
+) -> Result<Json<HeartbeatReply>, BuildRequestError> {
+    let Json(request) = request.context(BodySnafu)?;
+    touch(
+        auth.pool(&state.pools),
+        &state,
+        request.heartbeat,
+        Some(&request.stage),
+    )
+    .await
+}
+
+/// Shared by `/heartbeat` and `/progress`: the build must still be running,
+/// and on the worker that claimed it.
+async fn touch(
+    pool: &pool::Pool,
+    state: &AppState,
+    request: HeartbeatRequest,
+    stage: Option<&str>,
+) -> Result<Json<HeartbeatReply>, BuildRequestError> {
+    let HeartbeatRequest {
+        id,
+        ref arch,
+        worker,
+        steps,
+        free_disk,
+        draining,
+        upload,
+    } = request;
+
+    let mut db = state.db().await;
+    let Some(running) = db.get_running(arch, id).await.context(RedisSnafu)? else {
+        if db.cancelled_running(id).await.context(RedisSnafu)? {
+            return Ok(Json(HeartbeatReply {
+                cancel: true,
+                ..Default::default()
+            }));
+        }
+        return NotRunningSnafu { id, arch }.fail();
+    };
+    check_pool(&mut db, pool, &running.build, worker.as_deref()).await?;
+    check_worker(&running, worker.as_deref())?;
+    db.touch_running(arch, id, stage, steps, upload)
+        .await
+        .map_err(db_error)?;
+    if let Some(stage) = stage.filter(|x| running.progress.as_deref() != Some(*x)) {
+        let mut event = Event::new(events::Kind::Progress, &running.build)
+            .with_worker(running.worker.as_deref());
+        event.stage = Some(stage.to_string());
+        db.publish(&event).await;
+    }
+    if let Some(ref worker) = worker {
+        db.touch_worker(worker, free_disk)
+            .await
+            .context(RedisSnafu)?;
+        // back to normal once the next build may start
+        if let (Some(min), Some(free)) = (state.min_free_disk.min(arch), free_disk) {
+            if free >= min {
+                db.clear_low_disk(arch, worker).await.context(RedisSnafu)?;
+            }
+        }
+    }
+
+    let Some(worker) = worker else {
+        return Ok(Json(HeartbeatReply::default()));
+    };
+    let arches = match db.worker(&worker).await.context(RedisSnafu)? {
+        Some(w) => w.arch_names(),
+        None => vec![arch.clone()],
+    };
+    let drains = db.drains().await.context(RedisSnafu)?;
+    let drain = workers::drained(&drains, &worker, &arches);
+    if let (Some((target, d)), true) = (drain, draining) {
+        if !d.acked.contains_key(&worker) {
+            info!("Worker {worker} knows it drains, #{id} is its last build");
+            db.ack_drain(target, &worker).await.context(RedisSnafu)?;
+        }
+    }
+
+    Ok(Json(HeartbeatReply {
+        draining: drain.is_some(),
+        ..Default::default()
+    }))
+}
+
+/// The file behind `shipit_worker_release`, re-read on every request so a
+/// rollout needs no restart:
+/// `{"version": "0.2.0", "archs": {"amd64": {"url": "...", "sha256": "..."}}}`.
+#[derive(Deserialize)]
+struct WorkerReleases {
+    version: String,
+    archs: BTreeMap<String, ArchRelease>,
+}
+
+#[derive(Deserialize)]
+struct ArchRelease {
+    url: String,
+    sha256: String,
+    #[serde(default)]
+    signature_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WorkerLatestRequest {
+    arch: String,
+}
+
+#[derive(Deserialize)]
+struct AuthCheckRequest {
+    #[serde(default)]
+    arch: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuthCheck {
+    /// The pool of the secret.
+    pool: String,
+    /// Whether it is the `primary` secret of the pool or the `previous`
+    /// one, replaced by `/rotate-secret`.
+    secret: &'static str,
+    /// When the previous secret is refused from, if it is that one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_until: Option<u64>,
+    /// Free disk a worker of `arch` needs to be handed jobs, if limited.
+    min_free_disk: Option<u64>,
+}
+
+/// For `worker check`: whether the secret is good, and what the server
+/// wants of a worker of `arch`. Answered without Redis.
+async fn authcheck(
+    header: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Query(request): Query<AuthCheckRequest>,
+) -> Result<Json<AuthCheck>, BuildRequestError> {
+    let (pool, which) = state.pools.authenticate(&header).context(BadSecretSnafu)?;
+    let (secret, previous_until) = match which {
+        Which::Primary => ("primary", None),
+        Which::Previous { until } => ("previous", Some(until)),
+    };
+
+    Ok(Json(AuthCheck {
+        pool: pool.name.clone(),
+        secret,
+        previous_until,
+        min_free_disk: request
+            .arch
+            .as_deref()
+            .and_then(|x| state.min_free_disk.min(x)),
+    }))
+}
+
+/// Seconds since the unix epoch, for workers to compare their clock with.
+async fn time() -> Json<u64> {
+    Json(db::now())
+}
+
+async fn worker_latest(
+    _auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    Query(request): Query<WorkerLatestRequest>,
+) -> Result<Json<WorkerRelease>, BuildRequestError> {
+    let Some(ref path) = state.worker_release else {
+        return NoWorkerReleaseSnafu { arch: request.arch }.fail();
+    };
+
+    let releases = async {
+        let s = tokio::fs::read_to_string(path).await?;
+        eyre::Ok(serde_json::from_str::<WorkerReleases>(&s)?)
+    }
+    .await
+    .context(WorkerReleaseSnafu)?;
+
+    let Some(r) = releases.archs.get(&request.arch) else {
+        return NoWorkerReleaseSnafu { arch: request.arch }.fail();
+    };
+
+    Ok(Json(WorkerRelease {
+        version: releases.version,
+        url: r.url.clone(),
+        sha256: r.sha256.clone(),
+        signature_url: r.signature_url.clone(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct StartLogUploadRequest {
+    #[serde(flatten)]
+    meta: logstore::UploadMeta,
+    #[serde(default)]
+    worker: Option<String>,
+}
+
+/// Start uploading the log of a running build in chunks, or find out how
+/// much of it an earlier attempt committed.
+async fn start_log_upload(
+    auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<StartLogUploadRequest>, JsonRejection>,
+) -> Result<Json<logstore::UploadState>, BuildRequestError> {
+    let Json(request) = request.context(BodySnafu)?;
+    let pool = auth.pool(&state.pools);
+    let logs = state.logs.as_ref().context(LogsDisabledSnafu)?;
+    let (id, arch) = (request.meta.id, &request.meta.arch);
+
+    {
+        let mut db = state.db().await;
+        let Some(running) = db.get_running(arch, id).await.context(RedisSnafu)? else {
+            return NotRunningSnafu { id, arch }.fail();
+        };
+        check_pool(&mut db, pool, &running.build, request.worker.as_deref()).await?;
+        check_worker(&running, request.worker.as_deref())?;
+    }
+
+    Ok(Json(logs.start(request.meta).await.context(LogSnafu)?))
+}
+
+async fn log_upload_status(
+    _auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    Path(upload): Path<String>,
+) -> Result<Json<logstore::UploadState>, BuildRequestError> {
+    let logs = state.logs.as_ref().context(LogsDisabledSnafu)?;
+
+    Ok(Json(logs.status(&upload).await.context(LogSnafu)?))
+}
+
+#[derive(Deserialize)]
+struct ChunkQuery {
+    offset: u64,
+}
+
+async fn log_upload_chunk(
+    _auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    Path(upload): Path<String>,
+    Query(query): Query<ChunkQuery>,
+    chunk: axum::body::Bytes,
+) -> Result<Json<logstore::UploadState>, BuildRequestError> {
+    let logs = state.logs.as_ref().context(LogsDisabledSnafu)?;
+
+    Ok(Json(
+        logs.append(&upload, query.offset, &chunk)
+            .await
+            .context(LogSnafu)?,
+    ))
+}
+
+#[derive(Serialize)]
+struct FinishedLog {
+    url: String,
+}
+
+async fn finish_log_upload(
+    _auth: Auth<need::Worker>,
+    State(state): State<Arc<AppState>>,
+    Path(upload): Path<String>,
+) -> Result<Json<FinishedLog>, BuildRequestError> {
+    let logs = state.logs.as_ref().context(LogsDisabledSnafu)?;
+
+    Ok(Json(FinishedLog {
+        url: logs.finish(&upload).await.context(LogSnafu)?,
+    }))
+}
+
+/// The report of build `stub` the worker uploaded as log `name`.
+async fn uploaded_report(
+    state: &AppState,
+    name: &str,
+    stub: &BuildDoneRequest,
+) -> Result<BuildDoneRequest, BuildRequestError> {
+    let logs = state.logs.as_ref().context(LogsDisabledSnafu)?;
+    let mut file = logs.open(name).await.context(NoLogSnafu { name })?;
+    let mut data = vec![];
+    file.read_to_end(&mut data)
+        .await
+        .map_err(|e| BuildRequestError::BadReport {
+            name: name.to_string(),
+            reason: e.to_string(),
+        })?;
+
+    let report: BuildDoneRequest =
+        serde_json::from_slice(&data).map_err(|e| BuildRequestError::BadReport {
+            name: name.to_string(),
+            reason: e.to_string(),
+        })?;
+    if report.id != stub.id || report.arch != stub.arch || report.report.is_some() {
+        return BadReportSnafu {
+            name,
+            reason: format!("not the report of #{} {}", stub.id, stub.arch),
+        }
+        .fail();
+    }
+
+    Ok(report)
+}
+
+async fn serve_log(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, BuildRequestError> {
+    let logs = state.logs.as_ref().context(LogsDisabledSnafu)?;
+    let file = logs.open(&name).await.context(NoLogSnafu { name })?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    ))
+}
+
+#[derive(Serialize)]
+struct MigrateReply {
+    schema: u32,
+    /// Builds stored in an older schema until now.
+    upgraded: usize,
+}
+
+/// Store every queued and running build in the current schema, like
+/// `shipit migrate`.
+async fn migrate(
+    auth: Auth<need::Admin>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<MigrateReply>, BuildRequestError> {
+    let mut db = state.db().await;
+    let upgraded = db.migrate_builds().await.context(RedisSnafu)?;
+    if upgraded > 0 {
+        info!("Upgraded {upgraded} builds to schema {}", schema::SCHEMA);
+        db.audit(&auth.actor(), &format!("upgraded {upgraded} builds"))
+            .await
+            .context(RedisSnafu)?;
+    }
+
+    Ok(Json(MigrateReply {
+        schema: schema::SCHEMA,
+        upgraded,
+    }))
+}
+
+async fn export(
+    _auth: Auth<need::Admin>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<db::Dump>, BuildRequestError> {
+    let mut db = state.db().await;
+
+    Ok(Json(db.export().await.context(RedisSnafu)?))
+}
+
+#[derive(Deserialize)]
+struct ImportRequest {
+    #[serde(default)]
+    force: bool,
+}
+
+async fn import(
+    auth: Auth<need::Admin>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ImportRequest>,
+    request: Result<Json<db::Dump>, JsonRejection>,
+) -> Result<(), BuildRequestError> {
+    let Json(dump) = request.context(BodySnafu)?;
+
+    let mut db = state.db().await;
+    let imported = db.import(&dump, query.force).await.context(RedisSnafu)?;
+    ensure!(imported, NotEmptySnafu);
+    secret::load(&mut db, &state.pools)
+        .await
+        .context(RedisSnafu)?;
+
+    info!("Imported {}", dump.summary());
+    db.audit(&auth.actor(), &format!("imported {}", dump.summary()))
+        .await
+        .context(RedisSnafu)?;
+
+    Ok(())
+}
+
+/// The pool of `/stats` and `/feed.atom`.
+#[derive(Deserialize)]
+struct PoolQuery {
+    /// Mainline if unset.
+    #[serde(default)]
+    pool: Option<String>,
+}
+
+async fn build_stats(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PoolQuery>,
+) -> Result<Json<BTreeMap<String, stats::Stats>>, BuildRequestError> {
+    let pool = query.pool.as_deref().unwrap_or(pool::MAINLINE);
+    let mut db = state.db().await;
+    let history = db.history_of(pool).await.context(RedisSnafu)?;
+
+    Ok(Json(stats::stats(&history)))
+}
+
+#[derive(Serialize)]
+struct Health {
+    /// The error talking to Redis, if any.
+    redis: Option<String>,
+    /// Notifications waiting in the outbox.
+    outbox: Option<usize>,
+    /// Messages waiting for the Telegram rate limits.
+    telegram_queue: usize,
+    /// Requests refused by each queue cap, see `/limits`.
+    queue_full: BTreeMap<String, u64>,
+    /// Build traces not uploaded yet, if they are exported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    traces: Option<traces::Backlog>,
+}
+
+/// 503 if Redis cannot be reached.
+async fn healthz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let (outbox, queue_full) = {
+        let mut db = state.db().await;
+        (db.outbox_len().await, db.queue_full_counts().await)
+    };
+    let traces = match state.traces {
+        Some(ref t) => Some(t.backlog().await),
+        None => None,
+    };
+    let status = if outbox.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(Health {
+            redis: outbox.as_ref().err().map(|x| x.to_string()),
+            outbox: outbox.ok(),
+            telegram_queue: state.telegram.depth(),
+            queue_full: queue_full.unwrap_or_default(),
+            traces,
+        }),
+    )
+}
+
+/// Queue builds for CI and scripts, checked and planned like the build
+/// commands of the bot. Answers the queued builds and the rejected arches.
+async fn enqueue(
+    auth: Auth<need::Enqueue>,
+    State(state): State<Arc<AppState>>,
+    request: Result<Json<api::EnqueueRequest>, JsonRejection>,
+) -> Result<Json<api::EnqueueResponse>, BuildRequestError> {
+    let Json(request) = request.context(BodySnafu)?;
+    ensure!(request.git_ref.is_none(), UnsupportedRefSnafu);
+
+    let build_type = match request.build_type.as_str() {
+        "livekit" => BuildType::Livekit,
+        "release" => BuildType::Release(request.variants.clone()),
+        "rootfs" => BuildType::Rootfs(request.variants.clone()),
+        x => return UnknownBuildTypeSnafu { build_type: x }.fail(),
+    };
+    let top = request.priority == api::Priority::Top;
+    for scope in [request.build_type.as_str()]
+        .into_iter()
+        .chain(top.then_some(api::PRIORITY))
+    {
+        ensure!(
+            auth.allows(scope),
+            ScopeSnafu {
+                token: &auth.name,
+                scope
+            }
+        );
+    }
+
+    let pool = request.pool.as_deref().unwrap_or(pool::MAINLINE);
+    if pool != pool::MAINLINE && !auth.allows(&format!("pool:{pool}")) {
+        state
+            .db()
+            .await
+            .audit(
+                &auth.actor(),
+                &format!("denied: queue {} in pool {pool}", request.build_type),
+            )
+            .await
+            .context(RedisSnafu)?;
+        return ScopeSnafu {
+            token: &auth.name,
+            scope: format!("pool:{pool}"),
+        }
+        .fail();
+    }
+
+    let requester = Requester {
+        chat: state.api.chat.unwrap_or_default(),
+        name: Some(format!("{} (API)", auth.name.trim_start_matches("api:"))),
+        actor: auth.name.clone(),
+        scope: Some(Scope::Enqueue),
+        admin: false,
+    };
+    let note = match request.note.as_deref() {
+        Some(x) => {
+            Some(plan::note(x, Lang::En).map_err(|reason| BuildRequestError::Refused { reason })?)
+        }
+        None => None,
+    };
+    let opts = Options {
+        dry_run: request.dry_run,
+        channel: request.channel,
+        top,
+        webhook: request.webhook.clone(),
+        pool: Some(pool.to_string()),
+        note,
+        flavor: request.flavor.clone(),
+        ..Default::default()
+    };
+    let archs = request.arch.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+    let mut db = state.db().await;
+    let plan = enqueue_build(
+        &mut db,
+        &state,
+        &requester,
+        &archs,
+        &build_type,
+        &opts,
+        Lang::En,
+    )
+    .await
+    .map_err(|e| match e {
+        EnqueueError::Refused(reason) => BuildRequestError::Refused { reason },
+        EnqueueError::QueueFull(full) => BuildRequestError::QueueFull { full },
+        EnqueueError::Db(source) => BuildRequestError::Redis { source },
+    })?;
+
+    Ok(Json(api::EnqueueResponse {
+        builds: plan
+            .planned
+            .into_iter()
+            .map(|p| api::Enqueued {
+                id: p.build.id,
+                arch: p.build.arch,
+                position: p.position,
+            })
+            .collect(),
+        rejected: plan.rejected,
+    }))
+}
+
+async fn list_workers(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<workers::WorkerInfo>>, BuildRequestError> {
+    let mut db = state.db().await;
+    let workers = workers::inventory(&mut db, db::now(), state.worker_offline_after)
+        .await
+        .context(RedisSnafu)?;
+
+    Ok(Json(workers))
+}
+
+async fn list_freshness(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<freshness::Freshness>>, BuildRequestError> {
+    let mut db = state.db().await;
+    let list = freshness::list(&mut db, db::now(), state.freshness_threshold)
+        .await
+        .context(RedisSnafu)?;
+
+    Ok(Json(list))
+}
+
+/// For Prometheus.
+async fn metrics(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+) -> Result<String, BuildRequestError> {
+    let mut db = state.db().await;
+    let list = freshness::list(&mut db, db::now(), state.freshness_threshold)
+        .await
+        .context(RedisSnafu)?;
+    let size = db.history_size().await.context(RedisSnafu)?;
+
+    Ok(freshness::metrics(&list)
+        + &retention::metrics(&size)
+        + &events::metrics(db.events_dropped()))
+}
+
+#[derive(Deserialize)]
+struct ViewQuery {
+    /// Log page, the last one if unset.
+    page: Option<u64>,
+    /// Show the page with the line the failure was recognized by.
+    #[serde(default)]
+    error: bool,
+}
+
+async fn build_view(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Query(query): Query<ViewQuery>,
+) -> Result<impl IntoResponse, BuildRequestError> {
+    let (entry, requester) = {
+        let mut db = state.db().await;
+        let entry = db
+            .find_history(id)
+            .await
+            .context(RedisSnafu)?
+            .context(NoBuildSnafu { id })?;
+        let requester = match entry.requester_chat {
+            Some(chat) => db
+                .chat(chat)
+                .await
+                .context(RedisSnafu)?
+                .and_then(|x| x.title),
+            None => None,
+        };
+        (entry, requester)
+    };
+
+    let html = page::render(
+        &entry,
+        requester.as_deref(),
+        query.page,
+        query.error,
+        &state.log_hosts,
+        state.logs.as_ref(),
+    )
+    .await;
+
+    Ok(([(header::CONTENT_TYPE, "text/html; charset=utf-8")], html))
+}
+
+async fn build_feed(
+    _auth: Auth<need::Read>,
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PoolQuery>,
+) -> Result<impl IntoResponse, BuildRequestError> {
+    let pool = query.pool.as_deref().unwrap_or(pool::MAINLINE);
+    let mut db = state.db().await;
+    let history = db.history_of(pool).await.context(RedisSnafu)?;
+    let last_modified = history
+        .first()
+        .and_then(|x| chrono::DateTime::from_timestamp(x.finished_at as i64, 0))
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/atom+xml; charset=utf-8".to_string(),
+            ),
+            (header::CACHE_CONTROL, "public, max-age=300".to_string()),
+            (header::LAST_MODIFIED, last_modified),
+        ],
+        feed::atom(&history, pool),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::Artifact,
+        testing::{build, entry, redis, ADMIN},
+    };
+
+    fn job() -> Job {
+        let mut b = build(
+            42,
+            "amd64",
+            BuildType::Release(vec!["base".to_string(), "desktop".to_string()]),
+        );
+        b.allow_partial = true;
+        b.channel = Channel::Nightly;
+        b.env
+            .insert("MIRROR".to_string(), "https://repo.aosc.io".to_string());
+        b.note = Some("CVE-2024-1234".to_string());
+
+        b.into()
+    }
+
+    fn reply(version: u32, status: Status<Job>) -> serde_json::Value {
+        let r = if version >= 2 {
+            StatusReply::Versioned(Box::new(Envelope { v: version, status }))
+        } else {
+            StatusReply::Legacy(status.into())
+        };
+
+        serde_json::to_value(r).unwrap()
+    }
+
+    fn fixture(s: &str) -> serde_json::Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn status_replies_match_what_workers_were_captured_reading() {
+        let cases = [
+            (
+                1,
+                Status::Pending,
+                include_str!("../common/tests/fixtures/status/v1-pending.json"),
+            ),
+            (
+                1,
+                Status::Ping { id: 3, check: true },
+                include_str!("../common/tests/fixtures/status/v1-ping.json"),
+            ),
+            (
+                1,
+                Status::Working { job: job() },
+                include_str!("../common/tests/fixtures/status/v1-working.json"),
+            ),
+            (
+                2,
+                Status::Pending,
+                include_str!("../common/tests/fixtures/status/v2-pending.json"),
+            ),
+            (
+                2,
+                Status::Ping { id: 3, check: true },
+                include_str!("../common/tests/fixtures/status/v2-ping.json"),
+            ),
+            (
+                2,
+                Status::Working { job: job() },
+                include_str!("../common/tests/fixtures/status/v2-working.json"),
+            ),
+        ];
+
+        for (version, status, wire) in cases {
+            assert_eq!(reply(version, status), fixture(wire), "{wire}");
+        }
+    }
+
+    #[test]
+    fn jobs_leave_the_requester_out() {
+        let mut b = build(42, "amd64", BuildType::Livekit);
+        b.requester_chat = 7_000_001;
+        let job = serde_json::to_value(Job::from(b)).unwrap();
+
+        assert!(!job.to_string().contains("7000001"), "{job}");
+    }
+
+    /// The API of `state`, at the URL returned.
+    async fn serve(state: Arc<AppState>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        url
+    }
+
+    /// What amd64 is handed on `/workerisstarted`.
+    async fn poll(url: &str) -> serde_json::Value {
+        reqwest::Client::new()
+            .get(format!("{url}/workerisstarted?arch=amd64&worker=w1"))
+            .header("secret", "worker-secret")
+            .header("accept-version", "2")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    /// An artifact uploaded to the release host.
+    fn artifact(variant: &str) -> Artifact {
+        let path = format!("{variant}/aosc-os_{variant}_20240501_amd64.tar.xz.sha256sum");
+        Artifact {
+            url: Some(format!("https://releases.aosc.io/{path}")),
+            path,
+            size: 100,
+            variant: Some(variant.to_string()),
+            checksum: None,
+            sha256: None,
+            build: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn workers_are_not_told_who_requested_the_build() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        server.ask(ADMIN, "/livekit amd64").await;
+
+        let reply = poll(&serve(server.state.clone()).await).await;
+
+        let job = &reply["status"]["job"];
+        assert_eq!(job["arch"], "amd64", "{reply}");
+        assert!(job.get("requester_chat").is_none(), "{reply}");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_resumed_release_links_what_the_failed_one_kept() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        let url = serve(server.state.clone()).await;
+
+        let variants = vec!["base".to_string(), "desktop".to_string()];
+        let mut failed = entry(1, "amd64", "release", false, 600);
+        failed.variants = Some(variants.clone());
+        failed.manifest = Some(Manifest {
+            artifacts: vec![artifact("base")],
+            total_bytes: 100,
+            upload_secs: 1.0,
+            remote_dir: None,
+            private: false,
+            channel: Channel::Release,
+            note: None,
+            incremental_from: None,
+            flavor: None,
+        });
+        db.push_history(&failed).await.unwrap();
+        let mut b = build(2, "amd64", BuildType::Release(variants));
+        b.requester_chat = ADMIN;
+        b.resume = Some(Resume {
+            from: 1,
+            done: vec!["base".to_string()],
+            commit: None,
+            force: false,
+        });
+        db.enqueue_all(&[&b]).await.unwrap();
+        assert_eq!(poll(&url).await["status"]["job"]["id"], 2);
+
+        let res = reqwest::Client::new()
+            .post(format!("{url}/done"))
+            .header("secret", "worker-secret")
+            .json(&serde_json::json!({
+                "id": 2,
+                "arch": "amd64",
+                "build_type": {"name": "release", "variants": ["base", "desktop"]},
+                "has_error": false,
+                "log_url": null,
+                "push_success": true,
+                "worker": "w1",
+                "manifest": {
+                    "artifacts": [artifact("desktop")],
+                    "total_bytes": 100,
+                    "upload_secs": 1.0,
+                },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            res.status(),
+            StatusCode::OK,
+            "{}",
+            res.text().await.unwrap()
+        );
+
+        let n = db.pop_outbox().await.unwrap().unwrap();
+        assert_eq!(n.chat, ADMIN);
+        for variant in ["base", "desktop"] {
+            assert!(
+                n.text.contains(&format!(
+                    "aosc-os_{variant}_20240501_amd64.tar.xz.sha256sum"
+                )),
+                "{variant}: {}",
+                n.text
+            );
+        }
+    }
+}
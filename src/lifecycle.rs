@@ -0,0 +1,260 @@
+//! The states a build goes through, from the queue to its result. Every
+//! change goes through [`crate::db::Db::transition`], which refuses the
+//! ones not listed in [`Phase::can_become`], e.g. a result reported for a
+//! build that was cancelled.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Queued,
+    /// Handed to a worker, which has not reported back yet.
+    Claimed,
+    /// The worker sent a heartbeat or progress.
+    Running,
+    /// The build script exited, the worker is publishing the artifacts.
+    Publishing,
+    Succeeded,
+    Failed,
+    /// Dropped from the queue, by hand or because it expired or what it
+    /// waited for failed, or stopped by hand while running.
+    Cancelled,
+    /// The worker stopped sending heartbeats.
+    Lost,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Queued => "queued",
+            Phase::Claimed => "claimed",
+            Phase::Running => "running",
+            Phase::Publishing => "publishing",
+            Phase::Succeeded => "succeeded",
+            Phase::Failed => "failed",
+            Phase::Cancelled => "cancelled",
+            Phase::Lost => "lost",
+        }
+    }
+
+    pub fn is_final(self) -> bool {
+        matches!(self, Phase::Succeeded | Phase::Failed | Phase::Cancelled)
+    }
+
+    /// Whether a build may go from `self` to `to`. A lost build whose
+    /// worker turns up again is running, publishing, or done, after all. A
+    /// build may be cancelled until it is done.
+    pub fn can_become(self, to: Phase) -> bool {
+        use Phase::*;
+
+        matches!(
+            (self, to),
+            (Queued, Claimed | Cancelled)
+                | (Claimed, Running | Succeeded | Failed | Cancelled | Lost)
+                | (Running, Publishing | Succeeded | Failed | Cancelled | Lost)
+                | (Publishing, Succeeded | Failed | Cancelled | Lost)
+                | (Lost, Running | Publishing | Succeeded | Failed | Cancelled)
+        )
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Entering `phase` at `at`, in UNIX seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Transition {
+    pub phase: Phase,
+    pub at: u64,
+}
+
+/// Where a build is, and how it got there.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Lifecycle {
+    pub phase: Phase,
+    pub transitions: Vec<Transition>,
+}
+
+impl Lifecycle {
+    pub fn new(at: u64) -> Self {
+        Self {
+            phase: Phase::Queued,
+            transitions: vec![Transition {
+                phase: Phase::Queued,
+                at,
+            }],
+        }
+    }
+}
+
+/// Where the time of a build went, in seconds, from its transitions. `None`
+/// where a transition is missing, e.g. for builds finished before workers
+/// reported when the build script exited.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Intervals {
+    /// From the queue until a worker claimed it.
+    pub queued: Option<u64>,
+    /// From the claim until the build script exited.
+    pub build: Option<u64>,
+    /// From the build script exiting until the result.
+    pub publish: Option<u64>,
+}
+
+impl Intervals {
+    pub fn of(transitions: &[Transition]) -> Self {
+        let last = |phase| {
+            transitions
+                .iter()
+                .rev()
+                .find(|x| x.phase == phase)
+                .map(|x| x.at)
+        };
+        let queued = transitions
+            .first()
+            .filter(|x| x.phase == Phase::Queued)
+            .map(|x| x.at);
+        let claimed = last(Phase::Claimed);
+        let publishing = last(Phase::Publishing);
+        let done = transitions
+            .last()
+            .filter(|x| x.phase.is_final())
+            .map(|x| x.at);
+        let between = |from: Option<u64>, to: Option<u64>| Some(to?.saturating_sub(from?));
+
+        Self {
+            queued: between(queued, claimed),
+            build: between(claimed, publishing),
+            publish: between(publishing, done),
+        }
+    }
+}
+
+/// A transition [`Phase::can_become`] does not allow.
+#[derive(Debug)]
+pub struct IllegalTransition {
+    pub id: i64,
+    pub from: Phase,
+    pub to: Phase,
+}
+
+impl Display for IllegalTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Build #{} is {}, it cannot become {}",
+            self.id, self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for IllegalTransition {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHASES: [Phase; 8] = [
+        Phase::Queued,
+        Phase::Claimed,
+        Phase::Running,
+        Phase::Publishing,
+        Phase::Succeeded,
+        Phase::Failed,
+        Phase::Cancelled,
+        Phase::Lost,
+    ];
+
+    /// Whether the phase of the row may become the one of the column, in
+    /// the order of [`PHASES`].
+    const ALLOWED: [[bool; 8]; 8] = {
+        const X: bool = true;
+        const O: bool = false;
+        [
+            // Qu Cl Ru Pu Su Fa Ca Lo
+            [O, X, O, O, O, O, X, O], // Queued
+            [O, O, X, O, X, X, X, X], // Claimed
+            [O, O, O, X, X, X, X, X], // Running
+            [O, O, O, O, X, X, X, X], // Publishing
+            [O, O, O, O, O, O, O, O], // Succeeded
+            [O, O, O, O, O, O, O, O], // Failed
+            [O, O, O, O, O, O, O, O], // Cancelled
+            [O, O, X, X, X, X, X, O], // Lost
+        ]
+    };
+
+    #[test]
+    fn every_pair_of_phases() {
+        for (i, from) in PHASES.into_iter().enumerate() {
+            for (j, to) in PHASES.into_iter().enumerate() {
+                assert_eq!(from.can_become(to), ALLOWED[i][j], "{from} -> {to}");
+            }
+        }
+    }
+
+    fn transitions(phases: &[(Phase, u64)]) -> Vec<Transition> {
+        phases
+            .iter()
+            .map(|&(phase, at)| Transition { phase, at })
+            .collect()
+    }
+
+    #[test]
+    fn intervals_split_at_the_claim_and_the_script_exiting() {
+        let t = transitions(&[
+            (Phase::Queued, 100),
+            (Phase::Claimed, 220),
+            (Phase::Running, 230),
+            (Phase::Publishing, 4000),
+            (Phase::Succeeded, 4600),
+        ]);
+        assert_eq!(
+            Intervals::of(&t),
+            Intervals {
+                queued: Some(120),
+                build: Some(3780),
+                publish: Some(600),
+            }
+        );
+    }
+
+    #[test]
+    fn intervals_without_their_transitions_are_unknown() {
+        // finished before workers reported the script exiting
+        let t = transitions(&[
+            (Phase::Queued, 100),
+            (Phase::Claimed, 220),
+            (Phase::Running, 230),
+            (Phase::Failed, 4600),
+        ]);
+        assert_eq!(
+            Intervals::of(&t),
+            Intervals {
+                queued: Some(120),
+                build: None,
+                publish: None,
+            }
+        );
+        assert_eq!(Intervals::of(&[]), Intervals::default());
+
+        // still running
+        let t = transitions(&[
+            (Phase::Queued, 100),
+            (Phase::Claimed, 220),
+            (Phase::Publishing, 900),
+        ]);
+        assert_eq!(Intervals::of(&t).publish, None);
+    }
+
+    #[test]
+    fn final_phases_become_nothing() {
+        for from in PHASES.into_iter().filter(|x| x.is_final()) {
+            assert!(PHASES.into_iter().all(|to| !from.can_become(to)), "{from}");
+        }
+    }
+}
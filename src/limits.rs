@@ -0,0 +1,206 @@
+//! Caps on the builds waiting in the queues, so that a runaway script
+//! cannot queue thousands of them. Admins are exempt. The environment sets
+//! the defaults, `/limits` changes them at runtime.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{Build, Db},
+    lang::{Lang, Msg},
+    AppState,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Queued builds of every pool and arch together.
+    pub total: Option<usize>,
+    /// Queued builds of a single queue, one arch in one pool.
+    pub per_arch: Option<usize>,
+    /// Queued builds of a single chat or API token.
+    pub per_requester: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Cap {
+    Total,
+    Arch,
+    Requester,
+}
+
+impl Cap {
+    pub const ALL: [Cap; 3] = [Cap::Total, Cap::Arch, Cap::Requester];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cap::Total => "total",
+            Cap::Arch => "arch",
+            Cap::Requester => "requester",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Cap::ALL.into_iter().find(|x| x.name() == s)
+    }
+}
+
+impl Display for Cap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Limits {
+    /// `shipit_max_queued`, `shipit_max_queued_per_arch` and
+    /// `shipit_max_queued_per_requester`, each unlimited if unset.
+    pub fn from_env() -> eyre::Result<Self> {
+        let read = |name| match std::env::var(name) {
+            Ok(x) => x.parse().map(Some),
+            Err(_) => Ok(None),
+        };
+
+        Ok(Self {
+            total: read("shipit_max_queued")?,
+            per_arch: read("shipit_max_queued_per_arch")?,
+            per_requester: read("shipit_max_queued_per_requester")?,
+        })
+    }
+
+    pub fn get(&self, cap: Cap) -> Option<usize> {
+        match cap {
+            Cap::Total => self.total,
+            Cap::Arch => self.per_arch,
+            Cap::Requester => self.per_requester,
+        }
+    }
+
+    pub fn set(&mut self, cap: Cap, limit: Option<usize>) {
+        match cap {
+            Cap::Total => self.total = limit,
+            Cap::Arch => self.per_arch = limit,
+            Cap::Requester => self.per_requester = limit,
+        }
+    }
+}
+
+/// Why [`check`] refused builds, answered as is by the API.
+#[derive(Debug, Serialize, Clone)]
+pub struct QueueFull {
+    pub cap: Cap,
+    pub limit: usize,
+    /// Waiting already, this request not counted.
+    pub queued: usize,
+    /// What the request would have added.
+    pub adding: usize,
+    /// The full queue, for [`Cap::Arch`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arch: Option<String>,
+}
+
+impl QueueFull {
+    pub fn text(&self, lang: Lang) -> String {
+        let msg = match self.cap {
+            Cap::Total => Msg::QueueFullTotal,
+            Cap::Arch => Msg::QueueFullArch,
+            Cap::Requester => Msg::QueueFullRequester,
+        };
+
+        lang.tr(
+            msg,
+            &[
+                ("queued", &self.queued),
+                ("limit", &self.limit),
+                ("adding", &self.adding),
+                ("arch", &self.arch.as_deref().unwrap_or_default()),
+            ],
+        )
+    }
+}
+
+impl Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text(Lang::En))
+    }
+}
+
+/// The caps in effect: the ones set with `/limits`, else the defaults.
+pub async fn current(db: &mut Db, state: &AppState) -> eyre::Result<Limits> {
+    Ok(db.limits().await?.unwrap_or(state.limits))
+}
+
+/// Whether adding `builds` of `actor` to the queues stays within the caps.
+/// Every enqueue holds the queue lock of [`AppState`] from this check until
+/// the builds are queued, so concurrent requests cannot both slip under a
+/// cap.
+pub async fn check(
+    db: &mut Db,
+    state: &AppState,
+    builds: &[&Build],
+    actor: &str,
+) -> eyre::Result<Result<(), QueueFull>> {
+    let limits = current(db, state).await?;
+    if limits == Limits::default() || builds.is_empty() {
+        return Ok(Ok(()));
+    }
+
+    let names = state
+        .pools
+        .iter()
+        .flat_map(|p| p.archs.iter().map(|a| (p.name.as_str(), a.as_str())))
+        .collect::<Vec<_>>();
+    let queues = names
+        .iter()
+        .map(|(p, a)| (p.to_string(), a.to_string()))
+        .zip(db.queues(&names).await?)
+        .collect::<BTreeMap<_, _>>();
+
+    let full = |cap, limit: Option<usize>, queued: usize, adding: usize, arch: Option<&str>| {
+        limit
+            .filter(|l| queued + adding > *l)
+            .map(|limit| QueueFull {
+                cap,
+                limit,
+                queued,
+                adding,
+                arch: arch.map(|x| x.to_string()),
+            })
+    };
+
+    let total = queues.values().map(|x| x.len()).sum();
+    if let Some(f) = full(Cap::Total, limits.total, total, builds.len(), None) {
+        return Ok(Err(f));
+    }
+
+    let mine = queues
+        .values()
+        .flatten()
+        .filter(|b| b.actor() == actor)
+        .count();
+    if let Some(f) = full(
+        Cap::Requester,
+        limits.per_requester,
+        mine,
+        builds.len(),
+        None,
+    ) {
+        return Ok(Err(f));
+    }
+
+    for b in builds {
+        let queued = queues
+            .get(&(b.pool.clone(), b.arch.clone()))
+            .map(|x| x.len())
+            .unwrap_or(0);
+        let adding = builds
+            .iter()
+            .filter(|x| x.pool == b.pool && x.arch == b.arch)
+            .count();
+        if let Some(f) = full(Cap::Arch, limits.per_arch, queued, adding, Some(&b.arch)) {
+            return Ok(Err(f));
+        }
+    }
+
+    Ok(Ok(()))
+}
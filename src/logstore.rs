@@ -0,0 +1,667 @@
+//! Job logs uploaded to this server in chunks, for workers whose link to
+//! the log host is too flaky to upload a large log at once. An upload is
+//! started with the size and sha256 of the log and named after both, so a
+//! worker starting over finds what it committed before. Chunks are
+//! appended at the committed offset, the length of the partial file, and
+//! the finished log is checked against the size and hash before it is
+//! served. Uploads untouched for longer than the TTL are removed.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+    time::sleep,
+};
+use tracing::{error, info};
+
+use crate::{env_secs, AppState};
+
+/// How often abandoned uploads are looked for.
+const GC_INTERVAL: Duration = Duration::from_secs(600);
+
+pub struct LogStore {
+    /// Finished logs, the partial ones below `partial`.
+    dir: PathBuf,
+    /// Where `dir` is served, `<public url>/logs`.
+    base_url: String,
+    /// Seconds an upload may go without a chunk before it is removed.
+    ttl: u64,
+    max_bytes: u64,
+    /// Held while an upload changes, chunks of one upload must not
+    /// interleave.
+    lock: Mutex<()>,
+}
+
+/// What a worker starts an upload with.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadMeta {
+    pub id: i64,
+    pub arch: String,
+    /// File name of the finished log.
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Finished, the partial file is gone.
+    #[serde(default)]
+    pub done: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadState {
+    pub upload: String,
+    /// Bytes committed so far.
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub enum LogError {
+    NoUpload {
+        upload: String,
+    },
+    /// The chunk does not start where the committed bytes end.
+    Offset {
+        offset: u64,
+        expected: u64,
+    },
+    TooLarge {
+        size: u64,
+        max: u64,
+    },
+    /// Not a plain file name, or not a sha256.
+    Invalid {
+        reason: String,
+    },
+    /// The finished log does not match, the upload starts over.
+    Mismatch {
+        reason: String,
+    },
+    Io {
+        source: std::io::Error,
+    },
+}
+
+impl Display for LogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogError::NoUpload { upload } => write!(f, "No log upload {upload}."),
+            LogError::Offset { offset, expected } => write!(
+                f,
+                "Chunk at offset {offset}, but {expected} bytes are committed."
+            ),
+            LogError::TooLarge { size, max } => {
+                write!(f, "Log of {size} bytes exceeds the limit of {max} bytes.")
+            }
+            LogError::Invalid { reason } | LogError::Mismatch { reason } => f.write_str(reason),
+            LogError::Io { source } => write!(f, "Failed to store the log: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<std::io::Error> for LogError {
+    fn from(source: std::io::Error) -> Self {
+        LogError::Io { source }
+    }
+}
+
+impl LogStore {
+    /// `shipit_log_dir` enables log uploads, which are served under
+    /// `shipit_public_url`. `shipit_log_upload_ttl` (default a day) and
+    /// `shipit_log_max_mib` (default 1024) limit them.
+    pub fn from_env(public_url: Option<&str>) -> eyre::Result<Option<Self>> {
+        let Ok(dir) = std::env::var("shipit_log_dir") else {
+            return Ok(None);
+        };
+        let Some(public_url) = public_url else {
+            eyre::bail!("shipit_log_dir needs shipit_public_url to link the logs");
+        };
+        let max_mib = match std::env::var("shipit_log_max_mib") {
+            Ok(x) => x.parse::<u64>()?,
+            Err(_) => 1024,
+        };
+
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(dir.join("partial"))?;
+
+        Ok(Some(Self {
+            dir,
+            base_url: format!("{public_url}/logs"),
+            ttl: env_secs("shipit_log_upload_ttl", 24 * 3600)?,
+            max_bytes: max_mib << 20,
+            lock: Mutex::new(()),
+        }))
+    }
+
+    fn part(&self, upload: &str) -> PathBuf {
+        self.dir.join("partial").join(format!("{upload}.part"))
+    }
+
+    fn meta_path(&self, upload: &str) -> PathBuf {
+        self.dir.join("partial").join(format!("{upload}.json"))
+    }
+
+    async fn meta(&self, upload: &str) -> Result<UploadMeta, LogError> {
+        // the name ends up in paths
+        let valid = upload.split_once('-').is_some_and(|(id, sha)| {
+            id.parse::<i64>().is_ok() && sha.len() == 64 && sha.bytes().all(is_lower_hex)
+        });
+        let s = match valid {
+            true => fs::read_to_string(self.meta_path(upload)).await.ok(),
+            false => None,
+        };
+        let Some(s) = s else {
+            return Err(LogError::NoUpload {
+                upload: upload.to_string(),
+            });
+        };
+
+        serde_json::from_str(&s).map_err(|e| LogError::Io { source: e.into() })
+    }
+
+    async fn write_meta(&self, upload: &str, meta: &UploadMeta) -> Result<(), LogError> {
+        let s = serde_json::to_string(meta).map_err(|e| LogError::Io { source: e.into() })?;
+        fs::write(self.meta_path(upload), s).await?;
+
+        Ok(())
+    }
+
+    async fn state(&self, upload: &str, meta: &UploadMeta) -> Result<UploadState, LogError> {
+        let offset = if meta.done {
+            meta.size
+        } else {
+            match fs::metadata(self.part(upload)).await {
+                Ok(m) => m.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        Ok(UploadState {
+            upload: upload.to_string(),
+            offset,
+            size: meta.size,
+        })
+    }
+
+    pub fn url(&self, name: &str) -> String {
+        format!("{}/{name}", self.base_url)
+    }
+
+    /// Start an upload, or find the one started with the same build, size
+    /// and hash before.
+    pub async fn start(&self, mut meta: UploadMeta) -> Result<UploadState, LogError> {
+        meta.sha256 = meta.sha256.to_ascii_lowercase();
+        if meta.sha256.len() != 64 || !meta.sha256.bytes().all(is_lower_hex) {
+            return Err(LogError::Invalid {
+                reason: format!("Invalid sha256 {}.", meta.sha256),
+            });
+        }
+        if !is_file_name(&meta.name) {
+            return Err(LogError::Invalid {
+                reason: format!("Invalid log name {}.", meta.name),
+            });
+        }
+        if meta.size > self.max_bytes {
+            return Err(LogError::TooLarge {
+                size: meta.size,
+                max: self.max_bytes,
+            });
+        }
+
+        let upload = format!("{}-{}", meta.id, meta.sha256);
+        let _guard = self.lock.lock().await;
+        match self.meta(&upload).await {
+            Ok(old) if old.size == meta.size && old.name == meta.name => {
+                return self.state(&upload, &old).await
+            }
+            _ => (),
+        }
+
+        meta.done = false;
+        File::create(self.part(&upload)).await?;
+        self.write_meta(&upload, &meta).await?;
+        info!(
+            "Started log upload {upload} of #{} {} ({} bytes)",
+            meta.id, meta.arch, meta.size
+        );
+
+        self.state(&upload, &meta).await
+    }
+
+    pub async fn status(&self, upload: &str) -> Result<UploadState, LogError> {
+        let meta = self.meta(upload).await?;
+
+        self.state(upload, &meta).await
+    }
+
+    /// Append `data` if it starts at the committed offset. A chunk sent
+    /// again after its response was lost is refused, the worker asks for
+    /// the offset and goes on from there.
+    pub async fn append(
+        &self,
+        upload: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<UploadState, LogError> {
+        let _guard = self.lock.lock().await;
+        let meta = self.meta(upload).await?;
+        let state = self.state(upload, &meta).await?;
+        if offset != state.offset || meta.done {
+            return Err(LogError::Offset {
+                offset,
+                expected: state.offset,
+            });
+        }
+        if offset + data.len() as u64 > meta.size {
+            return Err(LogError::TooLarge {
+                size: offset + data.len() as u64,
+                max: meta.size,
+            });
+        }
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.part(upload))
+            .await?;
+        file.write_all(data).await?;
+        file.sync_data().await?;
+
+        self.state(upload, &meta).await
+    }
+
+    /// Check the uploaded log against its size and hash and publish it,
+    /// returning its URL. A log that does not match is dropped.
+    pub async fn finish(&self, upload: &str) -> Result<String, LogError> {
+        let _guard = self.lock.lock().await;
+        let mut meta = self.meta(upload).await?;
+        if meta.done {
+            return Ok(self.url(&meta.name));
+        }
+
+        let part = self.part(upload);
+        let size = fs::metadata(&part).await?.len();
+        let reason = if size != meta.size {
+            Some(format!("Got {size} of {} bytes.", meta.size))
+        } else {
+            let sha256 = sha256_file(&part).await?;
+            (sha256 != meta.sha256)
+                .then(|| format!("Got sha256 {sha256}, expected {}.", meta.sha256))
+        };
+        if let Some(reason) = reason {
+            File::create(&part).await?;
+            return Err(LogError::Mismatch { reason });
+        }
+
+        fs::rename(&part, self.dir.join(&meta.name)).await?;
+        meta.done = true;
+        self.write_meta(upload, &meta).await?;
+        info!("Finished log upload {upload} as {}", meta.name);
+
+        Ok(self.url(&meta.name))
+    }
+
+    /// The name of the log at `url`, if it is one of those here.
+    pub fn name_of<'a>(&self, url: &'a str) -> Option<&'a str> {
+        url.strip_prefix(&self.base_url)?
+            .strip_prefix('/')
+            .filter(|x| is_file_name(x))
+    }
+
+    /// A finished log to serve.
+    pub async fn open(&self, name: &str) -> Option<File> {
+        if !is_file_name(name) {
+            return None;
+        }
+
+        File::open(self.dir.join(name)).await.ok()
+    }
+
+    /// Remove uploads without a chunk for longer than the TTL, and what
+    /// is left of finished ones. Returns how many.
+    async fn collect_garbage(&self) -> eyre::Result<usize> {
+        let _guard = self.lock.lock().await;
+        let ttl = Duration::from_secs(self.ttl);
+        let now = SystemTime::now();
+        let mut removed = 0;
+
+        let mut entries = fs::read_dir(self.dir.join("partial")).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(upload) = path.file_stem().map(|x| x.to_string_lossy().to_string()) else {
+                continue;
+            };
+            // handled with their meta file, if it was written
+            if path.extension().is_some_and(|x| x == "part")
+                && fs::try_exists(self.meta_path(&upload)).await?
+            {
+                continue;
+            }
+
+            // the meta file is written when the upload starts and finishes,
+            // the partial file with every chunk
+            let touched = [path.as_path(), &self.part(&upload)]
+                .into_iter()
+                .filter_map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+                .max();
+            if touched.is_some_and(|t| now.duration_since(t).unwrap_or_default() < ttl) {
+                continue;
+            }
+
+            info!("Removing abandoned log upload {upload}");
+            remove_if_exists(&self.part(&upload)).await?;
+            remove_if_exists(&self.meta_path(&upload)).await?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+}
+
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+async fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn is_lower_hex(c: u8) -> bool {
+    c.is_ascii_digit() || (b'a'..=b'f').contains(&c)
+}
+
+/// A name within the log directory, nothing hidden and no other directory.
+fn is_file_name(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with('.')
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+' | ':'))
+}
+
+pub async fn run(state: Arc<AppState>) {
+    let Some(ref logs) = state.logs else {
+        return;
+    };
+
+    loop {
+        match logs.collect_garbage().await {
+            Ok(0) => (),
+            Ok(n) => info!("Removed {n} abandoned log uploads"),
+            Err(e) => error!("Failed to remove abandoned log uploads: {e}"),
+        }
+
+        sleep(GC_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &[u8] = b"line 1\nline 2\nline 3\n";
+
+    struct Scratch {
+        store: LogStore,
+    }
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("shipit-logstore-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(dir.join("partial")).unwrap();
+
+            Self {
+                store: LogStore {
+                    dir,
+                    base_url: "https://example.org/logs".to_string(),
+                    ttl: 3600,
+                    max_bytes: 1 << 20,
+                    lock: Mutex::new(()),
+                },
+            }
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.store.dir);
+        }
+    }
+
+    fn meta(id: i64, data: &[u8]) -> UploadMeta {
+        UploadMeta {
+            id,
+            arch: "amd64".to_string(),
+            name: format!("{id}.log"),
+            size: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(data)),
+            done: false,
+        }
+    }
+
+    /// Make the files of `upload` look untouched for `secs`.
+    fn age(store: &LogStore, upload: &str, secs: u64) {
+        let then = SystemTime::now() - Duration::from_secs(secs);
+        for path in [store.part(upload), store.meta_path(upload)] {
+            if let Ok(f) = std::fs::File::options().append(true).open(path) {
+                f.set_modified(then).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn only_logs_of_this_store_are_named() {
+        let s = Scratch::new("name");
+
+        assert_eq!(
+            s.store.name_of("https://example.org/logs/1.log"),
+            Some("1.log")
+        );
+        for url in [
+            "https://example.org/logs/",
+            "https://example.org/logs/../secret",
+            "https://example.org/logs/partial/1.part",
+            "https://example.org/logs/.hidden",
+            "https://example.org/logsx/1.log",
+            "https://example.com/logs/1.log",
+        ] {
+            assert_eq!(s.store.name_of(url), None, "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_bad_start_is_refused() {
+        let s = Scratch::new("start");
+        let mut m = meta(1, LOG);
+        m.sha256 = "nope".to_string();
+        assert!(matches!(
+            s.store.start(m).await,
+            Err(LogError::Invalid { .. })
+        ));
+
+        let mut m = meta(1, LOG);
+        m.name = "../1.log".to_string();
+        assert!(matches!(
+            s.store.start(m).await,
+            Err(LogError::Invalid { .. })
+        ));
+
+        let mut m = meta(1, LOG);
+        m.size = 2 << 20;
+        assert!(matches!(
+            s.store.start(m).await,
+            Err(LogError::TooLarge { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn starting_again_finds_the_committed_offset() {
+        let s = Scratch::new("restart");
+        let st = s.store.start(meta(1, LOG)).await.unwrap();
+        s.store.append(&st.upload, 0, &LOG[..7]).await.unwrap();
+
+        // a worker restarting after a crash
+        let again = s.store.start(meta(1, LOG)).await.unwrap();
+        assert_eq!(again.upload, st.upload);
+        assert_eq!(again.offset, 7);
+    }
+
+    #[tokio::test]
+    async fn chunks_not_at_the_committed_offset_are_refused() {
+        let s = Scratch::new("offset");
+        let u = s.store.start(meta(1, LOG)).await.unwrap().upload;
+        s.store.append(&u, 0, &LOG[..7]).await.unwrap();
+
+        // sent again after its answer was lost
+        let e = s.store.append(&u, 0, &LOG[..7]).await.unwrap_err();
+        assert!(
+            matches!(
+                e,
+                LogError::Offset {
+                    offset: 0,
+                    expected: 7
+                }
+            ),
+            "{e}"
+        );
+        // past the end
+        let e = s.store.append(&u, 7, LOG).await.unwrap_err();
+        assert!(matches!(e, LogError::TooLarge { .. }), "{e}");
+        assert_eq!(s.store.status(&u).await.unwrap().offset, 7);
+    }
+
+    #[tokio::test]
+    async fn unknown_uploads_are_not_found() {
+        let s = Scratch::new("unknown");
+        let sha = "0".repeat(64);
+        for upload in [format!("1-{sha}"), "../../etc/passwd".to_string()] {
+            assert!(matches!(
+                s.store.status(&upload).await,
+                Err(LogError::NoUpload { .. })
+            ));
+            assert!(matches!(
+                s.store.append(&upload, 0, LOG).await,
+                Err(LogError::NoUpload { .. })
+            ));
+            assert!(matches!(
+                s.store.finish(&upload).await,
+                Err(LogError::NoUpload { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_torn_chunk_is_resumed_from_what_was_written() {
+        let s = Scratch::new("torn");
+        let u = s.store.start(meta(1, LOG)).await.unwrap().upload;
+        // the server went down halfway through writing a chunk
+        std::fs::write(s.store.part(&u), &LOG[..3]).unwrap();
+
+        let st = s.store.status(&u).await.unwrap();
+        assert_eq!(st.offset, 3);
+        s.store.append(&u, 3, &LOG[3..]).await.unwrap();
+        let url = s.store.finish(&u).await.unwrap();
+        assert_eq!(url, "https://example.org/logs/1.log");
+        assert_eq!(std::fs::read(s.store.dir.join("1.log")).unwrap(), LOG);
+    }
+
+    #[tokio::test]
+    async fn a_log_that_does_not_match_starts_over() {
+        let s = Scratch::new("mismatch");
+        let u = s.store.start(meta(1, LOG)).await.unwrap().upload;
+
+        // short
+        s.store.append(&u, 0, &LOG[..7]).await.unwrap();
+        let e = s.store.finish(&u).await.unwrap_err();
+        assert!(matches!(e, LogError::Mismatch { .. }), "{e}");
+        assert_eq!(s.store.status(&u).await.unwrap().offset, 0);
+
+        // the right size, but garbled
+        let mut garbled = LOG.to_vec();
+        garbled[0] = b'L';
+        s.store.append(&u, 0, &garbled).await.unwrap();
+        let e = s.store.finish(&u).await.unwrap_err();
+        assert!(e.to_string().contains("sha256"), "{e}");
+        assert_eq!(s.store.status(&u).await.unwrap().offset, 0);
+        assert!(s.store.open("1.log").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn finishing_again_answers_the_same_url() {
+        let s = Scratch::new("finish");
+        let u = s.store.start(meta(1, LOG)).await.unwrap().upload;
+        s.store.append(&u, 0, LOG).await.unwrap();
+
+        let url = s.store.finish(&u).await.unwrap();
+        // its answer was lost
+        assert_eq!(s.store.finish(&u).await.unwrap(), url);
+        assert_eq!(s.store.status(&u).await.unwrap().offset, LOG.len() as u64);
+        let e = s
+            .store
+            .append(&u, LOG.len() as u64, b"x")
+            .await
+            .unwrap_err();
+        assert!(matches!(e, LogError::Offset { .. }), "{e}");
+    }
+
+    #[tokio::test]
+    async fn garbage_collection_removes_only_what_outlived_the_ttl() {
+        let s = Scratch::new("gc");
+        let store = &s.store;
+        let fresh = store.start(meta(1, LOG)).await.unwrap().upload;
+        let stale = store.start(meta(2, LOG)).await.unwrap().upload;
+        store.append(&stale, 0, &LOG[..7]).await.unwrap();
+        age(store, &stale, 7200);
+        let finished = store.start(meta(3, LOG)).await.unwrap().upload;
+        store.append(&finished, 0, LOG).await.unwrap();
+        store.finish(&finished).await.unwrap();
+        age(store, &finished, 7200);
+        // a chunk came in recently, the meta file is old
+        let slow = store.start(meta(4, LOG)).await.unwrap().upload;
+        age(store, &slow, 7200);
+        store.append(&slow, 0, &LOG[..7]).await.unwrap();
+        // left by a crash before the meta file was written
+        let orphan = format!("5-{}", "0".repeat(64));
+        std::fs::write(store.part(&orphan), b"x").unwrap();
+        age(store, &orphan, 7200);
+
+        assert_eq!(store.collect_garbage().await.unwrap(), 3);
+
+        for u in [&fresh, &slow] {
+            assert!(store.status(u).await.is_ok(), "{u}");
+        }
+        for u in [&stale, &finished, &orphan] {
+            assert!(!store.part(u).exists(), "{u}");
+            assert!(!store.meta_path(u).exists(), "{u}");
+        }
+        // the finished log itself is served on
+        assert!(store.open("3.log").await.is_some());
+        assert_eq!(store.collect_garbage().await.unwrap(), 0);
+    }
+}
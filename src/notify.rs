@@ -0,0 +1,201 @@
+//! Where the completion notices go: the chats on Telegram, and the IRC
+//! channel if set up. Each backend is handed the notice on its own, one
+//! failing does not keep the others from theirs, and the ones that queued
+//! it are recorded in [`HistoryEntry::queued`]. Delivering it is up to
+//! [`crate::outbox`] and [`crate::irc`].
+
+use std::{future::Future, pin::Pin};
+
+use teloxide::utils::html;
+use tracing::error;
+
+use crate::{
+    bot::retry_buttons,
+    db::{Db, HistoryEntry, Manifest},
+    format,
+    lang::Lang,
+    outbox::Notification,
+    pool::MAINLINE,
+};
+
+pub type Fired<'a> = Pin<Box<dyn Future<Output = eyre::Result<bool>> + Send + 'a>>;
+
+pub trait Notifier: Send + Sync {
+    /// As recorded in [`HistoryEntry::queued`].
+    fn name(&self) -> &'static str;
+
+    /// Queue `notice` for delivery, returning whether there was anywhere to
+    /// deliver it to.
+    fn notify<'a>(&'a self, db: &'a mut Db, notice: &'a Notice<'a>) -> Fired<'a>;
+}
+
+/// The completion notice of a build.
+pub struct Notice<'a> {
+    pub entry: &'a HistoryEntry,
+    /// In plain text.
+    pub text: &'a (dyn Fn(Lang) -> String + Sync),
+    /// What the worker pushed, linked to if the push succeeded.
+    pub manifest: Option<&'a Manifest>,
+}
+
+/// The requester, or whoever is told of builds nobody requested, and the
+/// announcement chats, each in its language.
+pub struct TelegramNotifier {
+    pub chats: Vec<i64>,
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn notify<'a>(&'a self, db: &'a mut Db, notice: &'a Notice<'a>) -> Fired<'a> {
+        Box::pin(async move {
+            let entry = notice.entry;
+            for &chat in &self.chats {
+                let lang = db.lang(chat).await?;
+                let mut text = html::escape(&(notice.text)(lang));
+                if let Some(ref f) = entry.failure {
+                    text.push_str(&format!("\n<pre>{}</pre>", html::escape(&f.excerpt)));
+                }
+                if let Some(links) = notice
+                    .manifest
+                    .filter(|_| entry.push_success)
+                    .and_then(|m| format::download_links(m, lang))
+                {
+                    text.push('\n');
+                    text.push_str(&links);
+                }
+
+                let n = Notification::new(chat, text)
+                    .with_buttons(retry_buttons(entry, lang))
+                    .with_build(entry.id);
+                db.push_outbox(&n).await?;
+            }
+
+            Ok(!self.chats.is_empty())
+        })
+    }
+}
+
+/// The channel of [`crate::irc`], in English, told of mainline builds only.
+pub struct IrcNotifier;
+
+impl Notifier for IrcNotifier {
+    fn name(&self) -> &'static str {
+        "irc"
+    }
+
+    fn notify<'a>(&'a self, db: &'a mut Db, notice: &'a Notice<'a>) -> Fired<'a> {
+        Box::pin(async move {
+            if notice.entry.pool != MAINLINE {
+                return Ok(false);
+            }
+            db.push_irc(&(notice.text)(Lang::En)).await?;
+
+            Ok(true)
+        })
+    }
+}
+
+/// Hand `notice` to each of `notifiers`, returning the names of those that
+/// queued it.
+pub async fn fire(db: &mut Db, notifiers: &[&dyn Notifier], notice: &Notice<'_>) -> Vec<String> {
+    let mut fired = vec![];
+    for n in notifiers {
+        match n.notify(db, notice).await {
+            Ok(true) => fired.push(n.name().to_string()),
+            Ok(false) => {}
+            Err(e) => error!(
+                "Failed to queue the {} notice of #{}: {e}",
+                n.name(),
+                notice.entry.id
+            ),
+        }
+    }
+
+    fired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{entry, redis};
+
+    /// A backend that is down.
+    struct Broken;
+
+    impl Notifier for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn notify<'a>(&'a self, _: &'a mut Db, _: &'a Notice<'a>) -> Fired<'a> {
+            Box::pin(async { eyre::bail!("connection refused") })
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_failing_backend_does_not_keep_the_others_from_their_notice() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let h = entry(7, "amd64", "livekit", false, 600);
+        let text = |_| "Build #7 failed".to_string();
+        let notice = Notice {
+            entry: &h,
+            text: &text,
+            manifest: None,
+        };
+
+        let telegram = TelegramNotifier { chats: vec![1, 2] };
+        let fired = fire(&mut db, &[&Broken, &telegram, &IrcNotifier], &notice).await;
+
+        assert_eq!(fired, ["telegram", "irc"]);
+        for chat in [1, 2] {
+            let n = db.pop_outbox().await.unwrap().unwrap();
+            assert_eq!(n.chat, chat);
+            assert_eq!(n.build, Some(7));
+        }
+        assert_eq!(
+            db.pop_irc().await.unwrap().as_deref(),
+            Some("Build #7 failed")
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_backend_with_nobody_to_tell_is_not_recorded() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let h = entry(7, "amd64", "livekit", true, 600);
+        let text = |_| "Build #7 succeeded".to_string();
+        let notice = Notice {
+            entry: &h,
+            text: &text,
+            manifest: None,
+        };
+
+        let telegram = TelegramNotifier { chats: vec![] };
+        assert!(fire(&mut db, &[&telegram], &notice).await.is_empty());
+        assert!(db.pop_outbox().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn irc_is_told_of_mainline_builds_only() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        let mut h = entry(7, "amd64", "livekit", true, 600);
+        h.pool = "staging".to_string();
+        let text = |_| "Build #7 succeeded".to_string();
+        let notice = Notice {
+            entry: &h,
+            text: &text,
+            manifest: None,
+        };
+
+        assert!(fire(&mut db, &[&IrcNotifier], &notice).await.is_empty());
+        assert!(db.pop_irc().await.unwrap().is_none());
+    }
+}
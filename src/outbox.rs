@@ -0,0 +1,241 @@
+//! Telegram notifications that must not be lost, such as build results.
+//!
+//! Handlers commit their state change first and then put the message into a
+//! Redis-backed outbox. A background task delivers it, retrying on failures
+//! and following chats that migrated to a supergroup. Chats that cannot be
+//! reached are flagged in the chat registry and skipped until they talk to
+//! the bot again.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use teloxide::{types::ChatId, utils::html, ApiError, RequestError};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{
+    bot::send_buttons,
+    buttons::Button,
+    db::{now, Db},
+    lang::{Lang, Msg},
+    progress, AppState,
+};
+
+/// Give up on a notification after this many failed attempts.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notification {
+    pub chat: i64,
+    /// Message text, already HTML.
+    pub text: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Inline buttons under the message, see [`crate::buttons`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buttons: Vec<Button>,
+    /// The build it tells the result of, whose progress posts it ends, see
+    /// [`crate::progress`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<i64>,
+}
+
+impl Notification {
+    pub fn new(chat: i64, text: String) -> Self {
+        Self {
+            chat,
+            text,
+            attempts: 0,
+            last_error: None,
+            buttons: vec![],
+            build: None,
+        }
+    }
+
+    pub fn with_buttons(mut self, buttons: Vec<Button>) -> Self {
+        self.buttons = buttons;
+        self
+    }
+
+    pub fn with_build(mut self, id: i64) -> Self {
+        self.build = Some(id);
+        self
+    }
+
+    /// A notification of plain text, escaped for HTML.
+    pub fn plain(chat: i64, text: &str) -> Self {
+        Self::new(chat, html::escape(text))
+    }
+}
+
+/// Drop the builds waiting for build `id` and tell their requesters why,
+/// `reason` is given the `{id}`.
+pub async fn cancel_dependents(
+    db: &mut Db,
+    pool: &str,
+    arch: &str,
+    id: i64,
+    reason: Msg,
+) -> eyre::Result<()> {
+    for b in db.remove_dependents(pool, arch, id).await? {
+        db.audit(
+            "shipit",
+            &format!(
+                "cancelled #{} on {}: {}",
+                b.id,
+                arch,
+                Lang::En.tr(reason, &[("id", &id)])
+            ),
+        )
+        .await?;
+
+        let lang = db.lang(b.requester_chat).await?;
+        let text = lang.tr(
+            Msg::RequestCancelled,
+            &[
+                ("arch", &arch),
+                ("build", &b.build_type),
+                ("id", &b.id),
+                ("reason", &lang.tr(reason, &[("id", &id)])),
+            ],
+        );
+        db.push_outbox(&Notification::plain(b.requester_chat, &text))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Point `n` to where its chat is now. Returns whether to send it: not if
+/// the chat is flagged undeliverable, or the same text was just delivered
+/// there.
+async fn prepare(db: &mut Db, n: &mut Notification) -> eyre::Result<bool> {
+    n.chat = db.resolve_chat(n.chat).await?;
+
+    if db.chat(n.chat).await?.is_some_and(|c| c.undeliverable) {
+        warn!("Not notifying {}, it is flagged undeliverable", n.chat);
+        n.last_error = Some("chat is flagged undeliverable".to_string());
+        db.push_failed_notification(n).await?;
+        return Ok(false);
+    }
+
+    if db.was_sent(n.chat, &n.text).await? {
+        info!("Dropping a duplicate notification to {}", n.chat);
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        let next = state.db().await.pop_outbox().await;
+
+        let mut n = match next {
+            Ok(Some(n)) => n,
+            Ok(None) => {
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to read outbox: {e}");
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        match prepare(&mut *state.db().await, &mut n).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => error!("Failed to read chat registry, sending anyway: {e}"),
+        }
+
+        let edited = progress::complete(&state, &n).await.unwrap_or_else(|e| {
+            error!("Failed to end the progress posts of {}: {e}", n.chat);
+            None
+        });
+        let mut res = match edited {
+            Some(message) => Ok(message),
+            None => send_buttons(&state.telegram, ChatId(n.chat), &n.text, &n.buttons).await,
+        };
+
+        if let Err(RequestError::MigrateToChatId(new)) = res {
+            info!("Chat {} migrated to {}, resending", n.chat, new);
+            if let Err(e) = state.db().await.migrate_chat(n.chat, new).await {
+                error!("Failed to record migration of chat {}: {e}", n.chat);
+            }
+            n.chat = new;
+            res = send_buttons(&state.telegram, ChatId(n.chat), &n.text, &n.buttons).await;
+        }
+
+        let mut db = state.db().await;
+        let Err(e) = res else {
+            let res = async {
+                db.mark_sent(n.chat, &n.text).await?;
+                db.update_chat(n.chat, |c| {
+                    c.delivered_at = Some(now());
+                    c.last_error = None;
+                })
+                .await
+            }
+            .await;
+            if let Err(e) = res {
+                error!("Failed to record delivery to {}: {e}", n.chat);
+            }
+            continue;
+        };
+
+        n.attempts += 1;
+        n.last_error = Some(e.to_string());
+
+        // retrying will not bring these chats back
+        let gone = matches!(
+            e,
+            RequestError::Api(
+                ApiError::BotBlocked
+                    | ApiError::BotKicked
+                    | ApiError::BotKickedFromSupergroup
+                    | ApiError::ChatNotFound
+                    | ApiError::GroupDeactivated
+                    | ApiError::UserDeactivated
+                    | ApiError::CantInitiateConversation
+            )
+        );
+
+        let res = if n.attempts < MAX_ATTEMPTS && !gone {
+            warn!(
+                "Failed to notify {} (attempt {}): {}",
+                n.chat, n.attempts, e
+            );
+            db.push_outbox(&n).await
+        } else {
+            error!(
+                "Giving up notifying {}, flagging it undeliverable: {}",
+                n.chat, e
+            );
+            let error = e.to_string();
+            async {
+                db.update_chat(n.chat, |c| {
+                    c.undeliverable = true;
+                    c.last_error = Some(error);
+                })
+                .await?;
+                db.push_failed_notification(&n).await
+            }
+            .await
+        };
+        drop(db);
+
+        if let Err(e) = res {
+            error!("Failed to write outbox: {e}");
+        }
+
+        let delay = match e {
+            RequestError::RetryAfter(d) => d,
+            _ => Duration::from_secs(1 << n.attempts),
+        };
+        sleep(delay).await;
+    }
+}
@@ -0,0 +1,600 @@
+//! Per-build pages, so a log can be read on a phone without downloading
+//! megabytes of text first. The log stays on the upload host, it is read a
+//! page at a time with range requests. Only logs below one of
+//! `shipit_log_hosts`, e.g. `https://repo.aosc.io/logs`, and those uploaded
+//! to this server are read, the `log_url` of any other is only linked to.
+
+use std::{io::SeekFrom, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use eyre::{bail, OptionExt};
+use reqwest::{header, redirect, StatusCode, Url};
+use shipit_common::{base_url, timeline};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{
+    db::HistoryEntry,
+    env_list,
+    feed::escape,
+    format::{human_bytes, human_duration, phase_summary},
+    logstore::LogStore,
+};
+
+/// Bytes of log on one page.
+pub const PAGE_BYTES: u64 = 64 << 10;
+/// The end of the log searched for the line the worker matched, it only
+/// classifies the last few KiB.
+const TAIL_BYTES: u64 = 256 << 10;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// The most read of a log host answering, for those ignoring the range and
+/// sending the whole log.
+const MAX_FETCH_BYTES: usize = 16 << 20;
+
+/// Where the logs shown on the pages may be read from.
+pub struct LogHosts {
+    /// Base URLs, without the trailing slash.
+    bases: Vec<String>,
+}
+
+impl LogHosts {
+    pub fn from_env() -> eyre::Result<Self> {
+        let bases = env_list("shipit_log_hosts")
+            .unwrap_or_default()
+            .iter()
+            .map(|x| base_url("shipit_log_hosts", x))
+            .collect::<Result<_, _>>()
+            .map_err(|e| eyre::eyre!(e))?;
+
+        Ok(Self { bases })
+    }
+
+    /// `url` if below one of the hosts, as it would be requested.
+    fn allows(&self, url: &str) -> Option<Url> {
+        let url = Url::parse(url).ok()?;
+        self.bases
+            .iter()
+            .any(|x| url.as_str().starts_with(&format!("{x}/")))
+            .then_some(url)
+    }
+}
+
+/// Where to read the log of a build from.
+enum Source<'a> {
+    Remote(Url),
+    /// Uploaded to this server, by its name.
+    Local(&'a LogStore, &'a str),
+}
+
+impl<'a> Source<'a> {
+    fn of(url: &'a str, hosts: &LogHosts, store: Option<&'a LogStore>) -> Option<Self> {
+        if let Some(name) = store.and_then(|x| x.name_of(url)) {
+            return Some(Source::Local(store?, name));
+        }
+
+        hosts.allows(url).map(Source::Remote)
+    }
+}
+
+/// The bytes of a log to read.
+#[derive(Clone, Copy)]
+enum Range {
+    /// From the first to the last, inclusive.
+    Bytes(u64, u64),
+    /// The last so many.
+    Tail(u64),
+}
+
+impl Range {
+    /// As the `Range` header has it, e.g. `bytes=0-65535`.
+    fn header(self) -> String {
+        match self {
+            Range::Bytes(start, end) => format!("bytes={start}-{end}"),
+            Range::Tail(n) => format!("bytes=-{n}"),
+        }
+    }
+}
+
+/// Part of a log as fetched from the upload host.
+struct Chunk {
+    /// Offset of `bytes` in the log.
+    start: u64,
+    bytes: Vec<u8>,
+    /// Size of the whole log.
+    total: u64,
+}
+
+/// `bytes 0-65535/1234567`
+fn content_range(s: &str) -> Option<(u64, u64)> {
+    let (range, total) = s.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+
+    Some((start.parse().ok()?, total.parse().ok()?))
+}
+
+/// Read `range` of the log. Hosts ignoring the range send the whole log,
+/// which is then used as is, up to [`MAX_FETCH_BYTES`].
+async fn fetch(source: &Source<'_>, range: Range) -> eyre::Result<Chunk> {
+    let url = match source {
+        Source::Remote(url) => url,
+        Source::Local(store, name) => return read(store, name, range).await,
+    };
+    // a redirect could lead anywhere
+    let resp = reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .build()?
+        .get(url.clone())
+        .header(header::RANGE, range.header())
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await?;
+
+    match resp.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            let (start, total) = resp
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|x| x.to_str().ok())
+                .and_then(content_range)
+                .ok_or_eyre("Bad Content-Range from the log host")?;
+            let bytes = body(resp).await?;
+
+            Ok(Chunk {
+                start,
+                bytes,
+                total,
+            })
+        }
+        StatusCode::OK => {
+            let bytes = body(resp).await?;
+
+            Ok(Chunk {
+                start: 0,
+                total: bytes.len() as u64,
+                bytes,
+            })
+        }
+        StatusCode::RANGE_NOT_SATISFIABLE => bail!("No such page"),
+        s => bail!("The log host answered {s}"),
+    }
+}
+
+/// The body of `resp`, if no more than [`MAX_FETCH_BYTES`].
+async fn body(mut resp: reqwest::Response) -> eyre::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    while let Some(chunk) = resp.chunk().await? {
+        if bytes.len() + chunk.len() > MAX_FETCH_BYTES {
+            bail!(
+                "The log host sent more than {}",
+                human_bytes(MAX_FETCH_BYTES as u64)
+            );
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(bytes)
+}
+
+/// Read `range` of the log uploaded to this server as `name`.
+async fn read(store: &LogStore, name: &str, range: Range) -> eyre::Result<Chunk> {
+    let mut file = store.open(name).await.ok_or_eyre("No such log")?;
+    let total = file.metadata().await?.len();
+    let (start, end) = match range {
+        Range::Bytes(start, end) => (start, (end + 1).min(total)),
+        Range::Tail(n) => (total.saturating_sub(n), total),
+    };
+    if start >= end && start > 0 {
+        bail!("No such page");
+    }
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut bytes = vec![0; (end - start) as usize];
+    file.read_exact(&mut bytes).await?;
+
+    Ok(Chunk {
+        start,
+        bytes,
+        total,
+    })
+}
+
+/// One page of a log.
+struct LogPage {
+    page: u64,
+    pages: u64,
+    bytes: Vec<u8>,
+    /// Offset of the matched line into `bytes`, if it is on this page.
+    error_at: Option<usize>,
+}
+
+/// Page `page` of the log, the last page if `None`.
+async fn log_page(
+    source: &Source<'_>,
+    page: Option<u64>,
+    error: Option<u64>,
+) -> eyre::Result<LogPage> {
+    let chunk = match page {
+        Some(n) => {
+            let start = n * PAGE_BYTES;
+            fetch(source, Range::Bytes(start, start + PAGE_BYTES - 1)).await?
+        }
+        None => fetch(source, Range::Tail(PAGE_BYTES)).await?,
+    };
+
+    let pages = chunk.total.div_ceil(PAGE_BYTES).max(1);
+    let page = page.unwrap_or(pages - 1);
+    if page >= pages {
+        bail!("No such page");
+    }
+
+    // the suffix range is not aligned to pages, and a host ignoring ranges
+    // sends everything
+    let start = page * PAGE_BYTES;
+    let from = start.saturating_sub(chunk.start) as usize;
+    let to = ((start + PAGE_BYTES).saturating_sub(chunk.start) as usize).min(chunk.bytes.len());
+    let bytes = chunk.bytes.get(from..to).unwrap_or_default().to_vec();
+    let error_at = error
+        .filter(|x| (start..start + bytes.len() as u64).contains(x))
+        .map(|x| (x - start) as usize);
+
+    Ok(LogPage {
+        page,
+        pages,
+        bytes,
+        error_at,
+    })
+}
+
+/// Offset of the last occurrence of `line` near the end of the log, where
+/// the worker found it. The log is uploaded with the step timeline in
+/// front and long runs cut out, so offsets from the worker would not fit.
+async fn find_line(source: &Source<'_>, line: &str) -> eyre::Result<Option<u64>> {
+    let chunk = fetch(source, Range::Tail(TAIL_BYTES)).await?;
+    let line = line.as_bytes();
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(chunk
+        .bytes
+        .windows(line.len())
+        .rposition(|x| x == line)
+        .map(|x| chunk.start + x as u64))
+}
+
+fn utc(time: u64) -> String {
+    Utc.timestamp_opt(time as i64, 0)
+        .single()
+        .unwrap_or_default()
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
+
+fn row(s: &mut String, name: &str, value: &str) {
+    s.push_str(&format!("<tr><th>{name}</th><td>{value}</td></tr>\n"));
+}
+
+/// The lines of `bytes`, with the one starting at `error_at` marked.
+fn log_html(bytes: &[u8], error_at: Option<usize>) -> String {
+    let Some(at) = error_at else {
+        return escape(&String::from_utf8_lossy(bytes));
+    };
+
+    let end = bytes[at..]
+        .iter()
+        .position(|x| *x == b'\n')
+        .map(|x| at + x)
+        .unwrap_or(bytes.len());
+
+    format!(
+        "{}<mark id=\"error\">{}</mark>{}",
+        escape(&String::from_utf8_lossy(&bytes[..at])),
+        escape(&String::from_utf8_lossy(&bytes[at..end])),
+        escape(&String::from_utf8_lossy(&bytes[end..]))
+    )
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1em;max-width:60em}\
+table{border-collapse:collapse}th,td{text-align:left;padding:.2em .6em;vertical-align:top}\
+pre{background:#f4f4f4;padding:.5em;overflow-x:auto;white-space:pre-wrap;word-break:break-all}\
+mark{background:#fc6}nav a{margin-right:1em}";
+
+/// The page of `entry`. `requester` is the title of the requesting chat,
+/// `page` the log page to show, the last one if `None`, and `error` jumps
+/// to the line the failure was recognized by instead. The log is read from
+/// `store` if uploaded there, else from one of `hosts`.
+pub async fn render(
+    entry: &HistoryEntry,
+    requester: Option<&str>,
+    page: Option<u64>,
+    error: bool,
+    hosts: &LogHosts,
+    store: Option<&LogStore>,
+) -> String {
+    let title = format!("#{} {} {}", entry.id, entry.describe(), entry.arch);
+    let mut s = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{}</title><style>{STYLE}</style></head><body>\n<h1>{}</h1>\n<table>\n",
+        escape(&title),
+        escape(&title)
+    );
+
+    let result = match (entry.success, entry.push_success) {
+        (true, true) => "✅ success",
+        (true, false) => "⚠️ built, push failed",
+        (false, _) => "❌ failed",
+    };
+    row(&mut s, "Result", result);
+    row(&mut s, "Arch", &escape(&entry.arch));
+    row(&mut s, "Type", &escape(&entry.describe()));
+    row(&mut s, "Channel", &entry.channel.to_string());
+    if entry.pruned {
+        row(
+            &mut s,
+            "Details",
+            "pruned, only the outcome, duration, log and scripts commit are kept",
+        );
+    }
+    if let Some(requester) = requester {
+        row(&mut s, "Requested by", &escape(requester));
+    }
+    if let Some(ref note) = entry.note {
+        row(&mut s, "Note", &escape(note));
+    }
+    if let Some(id) = entry.manifest.as_ref().and_then(|m| m.incremental_from) {
+        row(
+            &mut s,
+            "Incremental",
+            &format!("on the livekit cache of #{id}"),
+        );
+    }
+    if let Some(ref worker) = entry.worker {
+        let how = if entry.cross { "cross" } else { "native" };
+        row(&mut s, "Worker", &format!("{} ({how})", escape(worker)));
+    }
+    row(&mut s, "Finished", &utc(entry.finished_at));
+    if !entry.queued.is_empty() {
+        row(
+            &mut s,
+            "Notice queued for",
+            &escape(&entry.queued.join(", ")),
+        );
+    }
+    if let Some(secs) = entry.duration_secs {
+        row(&mut s, "Took", &human_duration(secs));
+    }
+    let intervals = entry.intervals();
+    for (name, secs) in [
+        ("Queued", intervals.queued),
+        ("Build", intervals.build),
+        ("Publish", intervals.publish),
+    ] {
+        let text = secs.map_or_else(|| "n/a".to_string(), human_duration);
+        row(&mut s, name, &text);
+    }
+    if let Some(p) = phase_summary(&entry.phase_durations) {
+        row(&mut s, "Phases", &escape(&p));
+    }
+    s.push_str("</table>\n");
+
+    if let Some(ref f) = entry.failure {
+        s.push_str(&format!(
+            "<h2>Failure</h2>\n<p>{} <code>{}</code></p>\n<pre>{}</pre>\n",
+            escape(&f.summary),
+            escape(&f.class),
+            escape(&f.excerpt)
+        ));
+    }
+
+    let artifacts = entry
+        .manifest
+        .as_ref()
+        .map(|x| x.artifacts.as_slice())
+        .unwrap_or_default();
+
+    if let Some(ref variants) = entry.variants {
+        s.push_str("<h2>Variants</h2>\n<table>\n");
+        for v in variants {
+            let result = if entry.missing_variants.contains(v) {
+                "❌ missing"
+            } else if artifacts.iter().any(|x| x.variant.as_ref() == Some(v)) {
+                "✅ ok"
+            } else {
+                "–"
+            };
+            row(&mut s, &escape(v), result);
+        }
+        s.push_str("</table>\n");
+    }
+
+    if !artifacts.is_empty() {
+        s.push_str("<h2>Artifacts</h2>\n<table>\n");
+        for a in artifacts {
+            let name = match a.url {
+                Some(ref url) => format!("<a href=\"{}\">{}</a>", escape(url), escape(&a.path)),
+                None => escape(&a.path),
+            };
+            let pushed = entry.artifact_pushes.iter().find(|x| x.path == a.path);
+            match pushed {
+                Some(p) if !p.success => row(
+                    &mut s,
+                    &name,
+                    &format!("{} ❌ upload failed", human_bytes(a.size)),
+                ),
+                _ => row(&mut s, &name, &human_bytes(a.size)),
+            }
+        }
+        s.push_str("</table>\n");
+    }
+
+    if !entry.steps.is_empty() {
+        s.push_str(&format!(
+            "<h2>Steps</h2>\n<pre>{}</pre>\n",
+            escape(&timeline(&entry.steps, entry.finished_at))
+        ));
+    }
+
+    s.push_str("<h2 id=\"log\">Log</h2>\n");
+    match entry.log_url {
+        Some(ref url) => match Source::of(url, hosts, store) {
+            Some(source) => s.push_str(&log_section(entry, url, &source, page, error).await),
+            None => s.push_str(&format!(
+                "<p>The log is on a host this server does not read from: <a href=\"{}\">raw log</a></p>\n",
+                escape(url)
+            )),
+        },
+        None => s.push_str("<p>The log was not uploaded.</p>\n"),
+    }
+
+    s.push_str("</body></html>\n");
+
+    s
+}
+
+async fn log_section(
+    entry: &HistoryEntry,
+    url: &str,
+    source: &Source<'_>,
+    page: Option<u64>,
+    error: bool,
+) -> String {
+    let line = entry.failure.as_ref().and_then(|x| x.line.as_deref());
+    let mut s = String::new();
+
+    let mut error_at = None;
+    if let Some(line) = line.filter(|_| error || page.is_none()) {
+        match find_line(source, line).await {
+            Ok(x) => error_at = x,
+            Err(e) => s.push_str(&format!(
+                "<p>Failed to find the error: {}</p>\n",
+                escape(&e.to_string())
+            )),
+        }
+    }
+
+    let page = match (error, error_at) {
+        (true, Some(at)) => Some(at / PAGE_BYTES),
+        _ => page,
+    };
+
+    let log = match log_page(source, page, error_at).await {
+        Ok(x) => x,
+        Err(e) => {
+            s.push_str(&format!(
+                "<p>Failed to fetch the log: {}. <a href=\"{}\">Raw log</a></p>\n",
+                escape(&e.to_string()),
+                escape(url)
+            ));
+            return s;
+        }
+    };
+
+    let link = |page: u64, text: &str| format!("<a href=\"?page={page}#log\">{text}</a>",);
+
+    let mut nav = String::from("<nav>");
+    if log.page > 0 {
+        nav.push_str(&link(0, "« first"));
+        nav.push_str(&link(log.page - 1, "‹ previous"));
+    }
+    nav.push_str(&format!("page {} of {} ", log.page + 1, log.pages));
+    if log.page + 1 < log.pages {
+        nav.push_str(&link(log.page + 1, "next ›"));
+        nav.push_str(&link(log.pages - 1, "last »"));
+    }
+    if line.is_some() {
+        nav.push_str("<a href=\"?error=true#error\">jump to error</a>");
+    }
+    nav.push_str(&format!("<a href=\"{}\">raw log</a></nav>\n", escape(url)));
+
+    s.push_str(&nav);
+    s.push_str(&format!(
+        "<pre>{}</pre>\n",
+        log_html(&log.bytes, log.error_at)
+    ));
+    s.push_str(&nav);
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{response::Redirect, routing::get, Router};
+
+    use super::*;
+
+    /// A log host ignoring ranges, as a plain file server would.
+    async fn host() -> String {
+        let app = Router::new()
+            .route("/logs/small.log", get(|| async { "line 1\nline 2\n" }))
+            .route(
+                "/logs/big.log",
+                get(|| async { vec![b'x'; MAX_FETCH_BYTES + 1] }),
+            )
+            .route(
+                "/logs/moved.log",
+                get(|| async { Redirect::temporary("http://169.254.169.254/") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        url
+    }
+
+    #[test]
+    fn logs_are_read_only_below_the_log_hosts() {
+        let hosts = LogHosts {
+            bases: vec!["https://repo.aosc.io/logs".to_string()],
+        };
+
+        assert!(hosts.allows("https://repo.aosc.io/logs/1.log").is_some());
+        assert!(hosts
+            .allows("https://repo.aosc.io/logs/amd64/1.log")
+            .is_some());
+        for url in [
+            "https://repo.aosc.io/logs",
+            "https://repo.aosc.io/logsx/1.log",
+            "https://repo.aosc.io/logs/../admin",
+            "https://repo.aosc.io.example.org/logs/1.log",
+            "https://repo.aosc.io:8080/logs/1.log",
+            "http://repo.aosc.io/logs/1.log",
+            "http://127.0.0.1:6379/",
+            "file:///etc/passwd",
+            "logs/1.log",
+        ] {
+            assert!(hosts.allows(url).is_none(), "{url}");
+        }
+
+        let none = LogHosts { bases: vec![] };
+        assert!(none.allows("https://repo.aosc.io/logs/1.log").is_none());
+    }
+
+    #[tokio::test]
+    async fn a_host_ignoring_the_range_is_read_up_to_the_cap() {
+        let url = host().await;
+        let source =
+            |name: &str| Source::Remote(Url::parse(&format!("{url}/logs/{name}")).unwrap());
+
+        let page = log_page(&source("small.log"), None, None).await.unwrap();
+        assert_eq!((page.page, page.pages), (0, 1));
+        assert_eq!(page.bytes, b"line 1\nline 2\n");
+
+        let e = fetch(&source("big.log"), Range::Tail(PAGE_BYTES))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "The log host sent more than 16.0 MiB");
+    }
+
+    #[tokio::test]
+    async fn redirects_are_not_followed() {
+        let url = host().await;
+        let source = Source::Remote(Url::parse(&format!("{url}/logs/moved.log")).unwrap());
+
+        let e = fetch(&source, Range::Tail(PAGE_BYTES)).await.err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "The log host answered 307 Temporary Redirect"
+        );
+    }
+}
@@ -0,0 +1,1044 @@
+//! Turning a build command into queued jobs, in two steps: `plan` resolves
+//! and validates everything without side effects, `execute` writes the
+//! result to the queue. Dry runs stop after planning. `enqueue_build` is
+//! both with the checks around them, shared by the bot and the HTTP API.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use tracing::warn;
+
+use crate::{
+    alert,
+    auth::Scope,
+    db::{now, Build, BuildType, Channel, Db, Resume, RunningBuild, DEFAULT_SET},
+    env_list,
+    events::{Event, Kind},
+    format::{edit_distance, estimate_text, parse_duration, passthrough_text},
+    lang::{Lang, Msg},
+    limits::{self, QueueFull},
+    pool::{Pool, Pools, MAINLINE},
+    stats::{estimate, stats, Estimate},
+    streak, AppState,
+};
+
+/// Flags accepted by the build commands in addition to their arguments.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub dry_run: bool,
+    /// Queue even if an identical job is already waiting.
+    pub force: bool,
+    /// Go ahead with arguments that look mistaken, see
+    /// [`parse_release_args`].
+    pub yes: bool,
+    /// Drop the job if it waits longer than this many seconds.
+    pub expire: Option<u64>,
+    /// Wait for the latest build of this type (or `#<id>`) on the same arch.
+    pub after: Option<String>,
+    /// Upload a release even if some variants are missing.
+    pub allow_partial: bool,
+    /// Publish to this channel instead of the release one.
+    pub channel: Option<Channel>,
+    /// Only let this worker take the job, not settable from chat.
+    pub worker: Option<String>,
+    /// Variables set for the build script, see [`Passthrough`].
+    pub env: BTreeMap<String, String>,
+    /// Appended to the arguments of the build script.
+    pub args: Vec<String>,
+    /// Build even outside the build window of the arch, admins only.
+    pub now: bool,
+    /// Keep what earlier livekit builds left in the checkout.
+    pub no_clean: bool,
+    /// Let livekit builds reuse the trees the last one of the arch left.
+    pub incremental: bool,
+    /// Build a release in this flavor of the arch, see [`Flavors`].
+    pub flavor: Option<String>,
+    /// Only build what a failed release did not upload, `/retry` only.
+    pub resume: bool,
+    /// What `--resume` continues, set by `/retry`.
+    pub resumed: Option<Resume>,
+    /// Queue ahead of the waiting builds, not settable from chat.
+    pub top: bool,
+    /// POST the history entry here once finished, not settable from chat.
+    pub webhook: Option<String>,
+    /// Queue in this pool instead of mainline.
+    pub pool: Option<String>,
+    /// Leave the build to workers for which the arch is native.
+    pub native_only: bool,
+    /// Why it is built, see [`note`].
+    pub note: Option<String>,
+}
+
+/// Longest note accepted, in characters.
+pub const NOTE_MAX: usize = 300;
+
+/// `s` as a note: on one line, without control characters, and at most
+/// [`NOTE_MAX`] characters. Messages escape it like any other text.
+pub fn note(s: &str, lang: Lang) -> Result<String, String> {
+    let s = s
+        .split(|c: char| c.is_control() || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if s.is_empty() {
+        return Err(lang.text(Msg::NoteEmpty));
+    }
+    if s.chars().count() > NOTE_MAX {
+        return Err(lang.tr(Msg::NoteTooLong, &[("max", &NOTE_MAX)]));
+    }
+
+    Ok(s)
+}
+
+/// Split flags off the arguments of a build command. A leading `?` is a
+/// shorthand for `--dry-run`.
+pub fn split_options(args: &str, lang: Lang) -> Result<(String, Options), String> {
+    let mut opts = Options::default();
+    let mut rest = vec![];
+
+    let args = args.trim();
+    let args = if let Some(a) = args.strip_prefix('?') {
+        opts.dry_run = true;
+        a
+    } else {
+        args
+    };
+
+    let mut tokens = args.split_ascii_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "--dry-run" => opts.dry_run = true,
+            "--force" => opts.force = true,
+            "--yes" => opts.yes = true,
+            "--allow-partial" => opts.allow_partial = true,
+            "--now" => opts.now = true,
+            "--no-clean" => opts.no_clean = true,
+            "--incremental" => opts.incremental = true,
+            "--resume" => opts.resume = true,
+            "--native-only" => opts.native_only = true,
+            "--note" => {
+                // a quoted note goes on up to the closing quote
+                let mut words = vec![];
+                match tokens.next() {
+                    Some(first) if first.starts_with('"') => {
+                        let mut word = &first[1..];
+                        loop {
+                            if let Some(last) = word.strip_suffix('"') {
+                                words.push(last);
+                                break;
+                            }
+                            words.push(word);
+                            match tokens.next() {
+                                Some(t) => word = t,
+                                None => return Err(lang.text(Msg::NoteUnclosed)),
+                            }
+                        }
+                    }
+                    Some(word) => words.push(word),
+                    None => {}
+                }
+                opts.note = Some(note(&words.join(" "), lang)?);
+            }
+            "--after" => {
+                let v = tokens.next().unwrap_or("");
+                if v.is_empty() {
+                    return Err(lang.text(Msg::AfterNeedsValue));
+                }
+                opts.after = Some(v.to_string());
+            }
+            "--nightly" => opts.channel = Some(Channel::Nightly),
+            "--flavor" => match tokens.next() {
+                Some(v) => opts.flavor = Some(v.to_string()),
+                None => return Err(lang.text(Msg::FlavorNeedsValue)),
+            },
+            "--pool" => match tokens.next() {
+                Some(v) => opts.pool = Some(v.to_string()),
+                None => return Err(lang.text(Msg::PoolNeedsValue)),
+            },
+            "--env" => {
+                let v = tokens.next().unwrap_or("");
+                match v.split_once('=') {
+                    Some((k, v)) if !k.is_empty() => {
+                        opts.env.insert(k.to_string(), v.to_string());
+                    }
+                    _ => return Err(lang.tr(Msg::EnvNeedsValue, &[("value", &format!("{v:?}"))])),
+                }
+            }
+            "--arg" => match tokens.next() {
+                Some(v) => opts.args.push(v.to_string()),
+                None => return Err(lang.text(Msg::ArgNeedsValue)),
+            },
+            "--channel" => {
+                let v = tokens.next().unwrap_or("");
+                opts.channel = Some(
+                    v.parse()
+                        .map_err(|_| lang.tr(Msg::BadChannel, &[("channel", &v)]))?,
+                );
+            }
+            "--expire" => {
+                let v = tokens.next().unwrap_or("");
+                opts.expire = Some(
+                    parse_duration(v)
+                        .ok_or_else(|| lang.tr(Msg::BadExpire, &[("value", &format!("{v:?}"))]))?,
+                );
+            }
+            t if t.starts_with("--") => return Err(lang.tr(Msg::UnknownOption, &[("option", &t)])),
+            t => rest.push(t),
+        }
+    }
+
+    Ok((rest.join(" "), opts))
+}
+
+/// The arguments of `/release` and `/rootfs`, see [`parse_release_args`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReleaseArgs {
+    pub variants: Vec<String>,
+    /// Every enabled arch if empty.
+    pub archs: Vec<String>,
+}
+
+/// Why the arguments of `/release` or `/rootfs` are refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReleaseArgsError {
+    NoVariants,
+    /// [`DEFAULT_SET`] along with other variants.
+    DefaultWithOthers,
+    /// A variant is the name of an arch, most likely with the `;` before it
+    /// forgotten. Building it as a variant is never what was meant.
+    ArchAsVariant {
+        arch: String,
+    },
+    /// A variant is a letter away from the name of an arch, built anyway
+    /// with `--yes`.
+    LooksLikeArch {
+        variant: String,
+        arch: String,
+    },
+}
+
+impl ReleaseArgsError {
+    pub fn text(&self, lang: Lang) -> String {
+        match self {
+            ReleaseArgsError::NoVariants => {
+                lang.tr(Msg::ReleaseNoVariants, &[("default", &DEFAULT_SET)])
+            }
+            ReleaseArgsError::DefaultWithOthers => {
+                lang.tr(Msg::ReleaseDefaultWithOthers, &[("default", &DEFAULT_SET)])
+            }
+            ReleaseArgsError::ArchAsVariant { arch } => {
+                lang.tr(Msg::ReleaseArchAsVariant, &[("arch", arch)])
+            }
+            ReleaseArgsError::LooksLikeArch { variant, arch } => lang.tr(
+                Msg::ReleaseLooksLikeArch,
+                &[("variant", variant), ("arch", arch)],
+            ),
+        }
+    }
+}
+
+/// Split the arguments of `/release` and `/rootfs`, `variants;[archs]`, into
+/// the variants and the archs. Further `;` separate archs like spaces do.
+/// At least one variant is needed, [`DEFAULT_SET`] alone for the set the
+/// build script builds when given none. A variant named like one of
+/// `known_archs` is refused as a forgotten `;`, one close to such a name
+/// unless `confirmed`.
+pub fn parse_release_args(
+    args: &str,
+    known_archs: &[String],
+    confirmed: bool,
+) -> Result<ReleaseArgs, ReleaseArgsError> {
+    let (variants, archs) = args.split_once(';').unwrap_or((args, ""));
+    let words = |s: &str| {
+        s.split(|c: char| c == ';' || c.is_ascii_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+    };
+    let (variants, archs) = (words(variants), words(archs));
+    check_variants(&variants)?;
+
+    for v in &variants {
+        if let Some(arch) = known_archs.iter().find(|x| x.eq_ignore_ascii_case(v)) {
+            return Err(ReleaseArgsError::ArchAsVariant { arch: arch.clone() });
+        }
+        let close = known_archs
+            .iter()
+            .find(|x| edit_distance(&v.to_ascii_lowercase(), x) <= 1);
+        if let (Some(arch), false) = (close, confirmed) {
+            return Err(ReleaseArgsError::LooksLikeArch {
+                variant: v.clone(),
+                arch: arch.clone(),
+            });
+        }
+    }
+
+    Ok(ReleaseArgs { variants, archs })
+}
+
+/// Refuse no variants, and [`DEFAULT_SET`] along with others.
+fn check_variants(variants: &[String]) -> Result<(), ReleaseArgsError> {
+    if variants.is_empty() {
+        return Err(ReleaseArgsError::NoVariants);
+    }
+    if variants.len() > 1 && variants.iter().any(|x| x == DEFAULT_SET) {
+        return Err(ReleaseArgsError::DefaultWithOthers);
+    }
+
+    Ok(())
+}
+
+/// Which script variables and arguments builds may be requested with:
+/// `shipit_build_env` lists variable names, `shipit_build_args` argument
+/// patterns, either exact or ending in `*` to match by prefix. Both empty by
+/// default, refusing any.
+#[derive(Debug, Default)]
+pub struct Passthrough {
+    pub env: Vec<String>,
+    pub args: Vec<String>,
+}
+
+impl Passthrough {
+    /// Refuse variables and arguments of `opts` that are not allowed.
+    pub fn check(&self, opts: &Options, lang: Lang) -> Result<(), String> {
+        for k in opts.env.keys() {
+            if !self.env.contains(k) {
+                return Err(lang.tr(
+                    Msg::EnvNotAllowed,
+                    &[("name", k), ("allowed", &list_or_none(&self.env, lang))],
+                ));
+            }
+        }
+
+        for a in &opts.args {
+            let allowed = self.args.iter().any(|p| match p.strip_suffix('*') {
+                Some(prefix) => a.starts_with(prefix),
+                None => a == p,
+            });
+            if !allowed {
+                return Err(lang.tr(
+                    Msg::ArgNotAllowed,
+                    &[("arg", a), ("allowed", &list_or_none(&self.args, lang))],
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Option lines for the usage of the build commands, empty if nothing
+    /// is allowed.
+    pub fn usage(&self, lang: Lang) -> String {
+        let mut s = String::new();
+        if !self.env.is_empty() {
+            s.push_str(&lang.tr(Msg::UsageEnvOption, &[("names", &self.env.join(" "))]));
+            s.push('\n');
+        }
+        if !self.args.is_empty() {
+            s.push_str(&lang.tr(Msg::UsageArgOption, &[("patterns", &self.args.join(" "))]));
+            s.push('\n');
+        }
+
+        s
+    }
+}
+
+/// The flavors releases of an arch may be built in with `--flavor`, such
+/// as new-world and old-world for loongarch64, listed by
+/// `shipit_flavors_<arch>`. Releases of arches without are built as they
+/// always were, and so are those requested without `--flavor`.
+#[derive(Debug, Default)]
+pub struct Flavors(BTreeMap<String, Vec<String>>);
+
+impl Flavors {
+    pub fn from_env(pools: &Pools) -> Self {
+        let mut m = BTreeMap::new();
+        for arch in pools.iter().flat_map(|p| &p.archs) {
+            if let Some(v) = env_list(&format!("shipit_flavors_{arch}")).filter(|x| !x.is_empty()) {
+                m.insert(arch.clone(), v);
+            }
+        }
+
+        Self(m)
+    }
+
+    /// Refuse `flavor` for `arch` unless listed for it.
+    pub fn check(&self, arch: &str, flavor: &str, lang: Lang) -> Result<(), String> {
+        match self.0.get(arch) {
+            Some(v) if v.iter().any(|x| x == flavor) => Ok(()),
+            Some(v) => Err(lang.tr(
+                Msg::UnknownFlavor,
+                &[
+                    ("arch", &arch),
+                    ("flavor", &flavor),
+                    ("flavors", &v.join(" ")),
+                ],
+            )),
+            None => Err(lang.tr(Msg::NoFlavors, &[("arch", &arch)])),
+        }
+    }
+
+    /// The option line for the usage of `/release`, empty if no arch has
+    /// flavors.
+    pub fn usage(&self, lang: Lang) -> String {
+        if self.0.is_empty() {
+            return String::new();
+        }
+
+        let flavors = self
+            .0
+            .iter()
+            .map(|(arch, v)| format!("{arch}: {}", v.join(" ")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        lang.tr(Msg::UsageFlavorOption, &[("flavors", &flavors)]) + "\n"
+    }
+}
+
+fn list_or_none(v: &[String], lang: Lang) -> String {
+    if v.is_empty() {
+        lang.text(Msg::NoneAllowed)
+    } else {
+        v.join(" ")
+    }
+}
+
+pub struct Planned {
+    pub build: Build,
+    pub position: usize,
+    /// Builds already running on the arch, which this one waits behind.
+    pub behind: Vec<i64>,
+    pub estimate: Option<Estimate>,
+}
+
+#[derive(Default)]
+pub struct Plan {
+    pub planned: Vec<Planned>,
+    pub rejected: Vec<String>,
+}
+
+/// Resolve what `requester` asking for `build_type` on `archs` of `pool` would queue,
+/// all its enabled arches if `archs` is empty. Nothing is written to the
+/// database, why arches are rejected is told in `lang`.
+pub async fn plan(
+    db: &mut Db,
+    pool: &Pool,
+    archs: &[&str],
+    build_type: &BuildType,
+    requester: &Requester,
+    opts: &Options,
+    lang: Lang,
+) -> eyre::Result<Plan> {
+    let known_archs = &pool.archs;
+    let stats = stats(&db.history_of(&pool.name).await?);
+    let disabled = db.disabled().await?;
+    let windows = db.windows().await?;
+    let now = now();
+    let channel = opts.channel.or(pool.channel).unwrap_or_default();
+    let mut plan = Plan::default();
+
+    // no arch given means all of them, except the disabled ones
+    let all;
+    let archs = if archs.is_empty() {
+        all = known_archs
+            .iter()
+            .filter(|x| !disabled.contains_key(*x))
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        &all
+    } else {
+        archs
+    };
+
+    let mut wanted = vec![];
+    for arch in archs {
+        if !known_archs.iter().any(|x| x == arch) {
+            plan.rejected
+                .push(lang.tr(Msg::UnknownArch, &[("arch", arch)]));
+            continue;
+        }
+
+        if let Some(d) = disabled.get(*arch) {
+            plan.rejected.push(match d.reason {
+                Some(ref r) => lang.tr(Msg::PlanDisabledReason, &[("arch", arch), ("reason", r)]),
+                None => lang.tr(Msg::PlanDisabled, &[("arch", arch)]),
+            });
+            continue;
+        }
+
+        if !wanted.contains(arch) {
+            wanted.push(*arch);
+        }
+    }
+
+    // all arches at once, a round trip per arch adds up with a remote redis
+    let states = db.arch_states(&pool.name, &wanted).await?;
+
+    for (arch, (running, ahead)) in wanted.into_iter().zip(states) {
+        let after = match opts.after {
+            Some(ref dep) => match find_dependency(dep, &running, &ahead) {
+                Some(id) => Some(id),
+                None => {
+                    plan.rejected
+                        .push(lang.tr(Msg::NoDependency, &[("dep", dep), ("arch", &arch)]));
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        if !opts.force {
+            if let Some((i, b)) = ahead.iter().enumerate().find(|(_, b)| {
+                b.build_type.same_job(build_type) && b.channel == channel && b.flavor == opts.flavor
+            }) {
+                plan.rejected.push(lang.tr(
+                    Msg::IdenticalJob,
+                    &[("id", &b.id), ("arch", &arch), ("position", &(i + 1))],
+                ));
+                continue;
+            }
+        }
+        let window = windows.get(arch).filter(|_| !opts.now);
+        let estimate = estimate(&stats, arch, &running, &ahead, build_type, window, now);
+
+        plan.planned.push(Planned {
+            build: Build {
+                id: 0,
+                requester_chat: requester.chat,
+                arch: arch.to_string(),
+                pool: pool.name.clone(),
+                build_type: build_type.clone(),
+                queued_at: Some(now),
+                started_at: None,
+                expire_secs: opts.expire,
+                after,
+                allow_partial: opts.allow_partial,
+                channel,
+                // only what follows up on a build needs the worker that made it
+                worker: opts.worker.clone().filter(|_| {
+                    matches!(build_type, BuildType::Repush { .. }) || opts.resumed.is_some()
+                }),
+                env: opts.env.clone(),
+                args: opts.args.clone(),
+                ignore_window: opts.now,
+                no_clean: opts.no_clean,
+                incremental: opts.incremental && matches!(build_type, BuildType::Livekit),
+                flavor: opts.flavor.clone(),
+                resume: opts.resumed.clone(),
+                native_only: opts.native_only,
+                note: opts.note.clone(),
+                webhook: opts.webhook.clone(),
+                requester: Some(requester.actor.clone()),
+            },
+            position: ahead.len() + 1,
+            behind: running.iter().map(|r| r.build.id).collect(),
+            estimate,
+        });
+    }
+
+    Ok(plan)
+}
+
+/// The id of the build `dep` refers to: `#<id>`, or the latest queued or
+/// running build of that type.
+fn find_dependency(dep: &str, running: &[RunningBuild], queue: &[Build]) -> Option<i64> {
+    let matches = |b: &&Build| match dep.strip_prefix('#') {
+        Some(id) => id.parse() == Ok(b.id),
+        None => b.build_type.name() == dep,
+    };
+
+    queue
+        .iter()
+        .rev()
+        .chain(running.iter().rev().map(|r| &r.build))
+        .find(matches)
+        .map(|b| b.id)
+}
+
+/// Who asks for builds, see [`enqueue_build`].
+pub struct Requester {
+    /// Where the notices about the builds go.
+    pub chat: i64,
+    /// As told to the watchers, the title of `chat` if unset.
+    pub name: Option<String>,
+    /// For the audit log and the per-requester queue cap, the chat id,
+    /// `api:<token name>` or `secret:mainline`.
+    pub actor: String,
+    /// What the request was allowed with, for the audit log, if it came
+    /// over HTTP.
+    pub scope: Option<Scope>,
+    pub admin: bool,
+}
+
+/// Why [`enqueue_build`] queued nothing.
+#[derive(Debug)]
+pub enum EnqueueError {
+    /// Told to the requester as is.
+    Refused(String),
+    QueueFull(QueueFull),
+    Db(eyre::Error),
+}
+
+impl Display for EnqueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnqueueError::Refused(s) => f.write_str(s),
+            EnqueueError::QueueFull(x) => x.fmt(f),
+            EnqueueError::Db(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<eyre::Error> for EnqueueError {
+    fn from(e: eyre::Error) -> Self {
+        EnqueueError::Db(e)
+    }
+}
+
+/// Check what `requester` asks for, then plan it and queue it unless this
+/// is a dry run. Arches refused by the plan are in [`Plan::rejected`], the
+/// rest is refused as a whole, also by the queue caps unless `requester`
+/// is an admin. Callers check that `requester` may use the pool of `opts`.
+pub async fn enqueue_build(
+    db: &mut Db,
+    state: &AppState,
+    requester: &Requester,
+    archs: &[&str],
+    build_type: &BuildType,
+    opts: &Options,
+    lang: Lang,
+) -> Result<Plan, EnqueueError> {
+    state
+        .passthrough
+        .check(opts, lang)
+        .map_err(EnqueueError::Refused)?;
+
+    let name = opts.pool.as_deref().unwrap_or(MAINLINE);
+    let Some(pool) = state.pools.get(name) else {
+        return Err(EnqueueError::Refused(lang.tr(
+            Msg::UnknownPool,
+            &[("pool", &name), ("pools", &state.pools.names())],
+        )));
+    };
+
+    if opts.now && !requester.admin {
+        return Err(EnqueueError::Refused(lang.text(Msg::OnlyAdminsNow)));
+    }
+
+    if opts.resume && opts.resumed.is_none() {
+        return Err(EnqueueError::Refused(lang.text(Msg::ResumeOnlyRetry)));
+    }
+
+    if let BuildType::Release(v) | BuildType::Rootfs(v) = build_type {
+        // the API takes the variants as they are
+        check_variants(v).map_err(|e| EnqueueError::Refused(e.text(lang)))?;
+    }
+
+    if let (BuildType::Release(v) | BuildType::Rootfs(v), false) = (
+        build_type,
+        state.variants.is_empty() || build_type.is_default_set(),
+    ) {
+        let unknown = v
+            .iter()
+            .filter(|v| !state.variants.contains(v))
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            return Err(EnqueueError::Refused(lang.tr(
+                Msg::UnknownVariants,
+                &[
+                    ("unknown", &unknown.join(" ")),
+                    ("available", &state.variants.join(" ")),
+                ],
+            )));
+        }
+    }
+
+    if opts.flavor.is_some() && !matches!(build_type, BuildType::Release(_)) {
+        return Err(EnqueueError::Refused(lang.text(Msg::FlavorReleaseOnly)));
+    }
+
+    let _queueing = state.queue_lock.lock().await;
+    let mut plan = plan(db, pool, archs, build_type, requester, opts, lang).await?;
+    if let Some(ref flavor) = opts.flavor {
+        plan.planned
+            .retain(|p| match state.flavors.check(&p.build.arch, flavor, lang) {
+                Ok(()) => true,
+                Err(e) => {
+                    plan.rejected.push(e);
+                    false
+                }
+            });
+    }
+    // scheduled builds are the ones requested over HTTP
+    if requester.scope.is_some() {
+        let paused = streak::paused(db).await?;
+        plan.planned.retain(|p| {
+            let b = &p.build;
+            let key = (b.arch.clone(), b.build_type.name().to_string(), b.channel);
+            if !paused.contains(&key) {
+                return true;
+            }
+            plan.rejected.push(lang.tr(
+                Msg::PausedScheduled,
+                &[
+                    ("arch", &b.arch),
+                    ("channel", &b.channel),
+                    ("build", &key.1),
+                ],
+            ));
+            false
+        });
+    }
+    if !requester.admin {
+        let builds = plan.planned.iter().map(|p| &p.build).collect::<Vec<_>>();
+        if let Err(full) = limits::check(db, state, &builds, &requester.actor).await? {
+            warn!("Refused builds of {}: {full}", requester.actor);
+            if !opts.dry_run {
+                db.count_queue_full(full.cap).await?;
+            }
+            return Err(EnqueueError::QueueFull(full));
+        }
+    }
+    if opts.dry_run || plan.planned.is_empty() {
+        return Ok(plan);
+    }
+
+    execute(db, &mut plan).await?;
+    let actor = match requester.scope {
+        Some(scope) => format!("{} ({scope})", requester.actor),
+        None => requester.actor.clone(),
+    };
+    let mut actions = vec![];
+    for p in &mut plan.planned {
+        if opts.top
+            && db
+                .move_queued_to_top(&pool.name, &p.build.arch, p.position)
+                .await?
+                .is_some()
+        {
+            p.position = 1;
+        }
+        let mut action = format!("queued #{} {} on {}", p.build.id, build_type, p.build.arch);
+        if pool.name != MAINLINE {
+            action.push_str(&format!(" in pool {}", pool.name));
+        }
+        actions.push(action);
+    }
+    db.audit_all(&actor, &actions).await?;
+
+    // queued all the same
+    if let Err(e) = notify_requested(db, state, requester, &plan, build_type).await {
+        warn!("Failed to notify watchers of a request: {e}");
+    }
+
+    Ok(plan)
+}
+
+/// Tell the watchers but the requester about the builds in `plan`, in one
+/// notice for all arches.
+async fn notify_requested(
+    db: &mut Db,
+    state: &AppState,
+    requester: &Requester,
+    plan: &Plan,
+    build_type: &BuildType,
+) -> eyre::Result<()> {
+    let builds = plan
+        .planned
+        .iter()
+        .map(|p| format!("#{} {}", p.build.id, p.build.arch))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let name = match requester.name {
+        Some(ref x) => x.clone(),
+        None => alert::chat_name(db, requester.chat).await?,
+    };
+
+    alert::notify(
+        db,
+        state,
+        Some(requester.chat),
+        Msg::WatchRequested,
+        &[
+            ("requester", &name),
+            ("build", build_type),
+            ("builds", &builds),
+        ],
+    )
+    .await
+}
+
+/// Queue everything in `plan`.
+pub async fn execute(db: &mut Db, plan: &mut Plan) -> eyre::Result<()> {
+    if plan.planned.is_empty() {
+        return Ok(());
+    }
+
+    let first = db.next_build_ids(plan.planned.len()).await?;
+    for (i, p) in plan.planned.iter_mut().enumerate() {
+        p.build.id = first + i as i64;
+    }
+
+    let builds = plan.planned.iter().map(|p| &p.build).collect::<Vec<_>>();
+    let positions = db.enqueue_all(&builds).await?;
+    for (p, position) in plan.planned.iter_mut().zip(positions) {
+        p.position = position;
+    }
+    let events = plan
+        .planned
+        .iter()
+        .map(|p| Event::new(Kind::Enqueued, &p.build))
+        .collect::<Vec<_>>();
+    db.publish_all(&events).await;
+
+    Ok(())
+}
+
+pub fn render(plan: &Plan, dry_run: bool, lang: Lang) -> String {
+    let now = now();
+    let mut s = String::new();
+
+    if dry_run {
+        s.push_str(&lang.text(Msg::DryRun));
+        s.push('\n');
+    }
+
+    for p in &plan.planned {
+        let busy = if p.behind.is_empty() {
+            String::new()
+        } else {
+            let builds = p
+                .behind
+                .iter()
+                .map(|x| format!("#{x}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            lang.tr(Msg::PlanBusy, &[("builds", &builds)])
+        };
+        let mut extra = match p.build.flavor {
+            Some(ref flavor) => format!(" {flavor}"),
+            None => String::new(),
+        };
+        if p.build.channel != Channel::Release {
+            extra.push_str(&format!(" ({})", p.build.channel));
+        }
+        if let Some(x) = passthrough_text(&p.build.env, &p.build.args) {
+            extra.push_str(&lang.tr(Msg::PlanWith, &[("passthrough", &x)]));
+        }
+        s.push_str(&lang.tr(
+            if dry_run {
+                Msg::PlanWouldQueue
+            } else {
+                Msg::PlanQueued
+            },
+            &[
+                ("id", &p.build.id),
+                ("arch", &p.build.arch),
+                ("build", &p.build.build_type),
+                ("extra", &extra),
+                ("position", &p.position),
+                ("busy", &busy),
+                ("estimate", &estimate_text(p.estimate.as_ref(), now, lang)),
+            ],
+        ));
+        s.push('\n');
+    }
+
+    for r in &plan.rejected {
+        s.push_str(r);
+        s.push('\n');
+    }
+
+    if plan.planned.is_empty() && plan.rejected.is_empty() {
+        s.push_str(&lang.text(Msg::NothingToBuild));
+    }
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{build, redis, ADMIN};
+
+    fn archs() -> Vec<String> {
+        ["amd64", "arm64", "riscv64"].map(String::from).to_vec()
+    }
+
+    fn parse(args: &str) -> Result<ReleaseArgs, ReleaseArgsError> {
+        parse_release_args(args, &archs(), false)
+    }
+
+    fn args(variants: &[&str], archs: &[&str]) -> ReleaseArgs {
+        ReleaseArgs {
+            variants: variants.iter().map(|x| x.to_string()).collect(),
+            archs: archs.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn busy_arches_are_queued_behind_what_runs() {
+        let planned = |id, arch, behind: &[i64]| Planned {
+            build: build(id, arch, BuildType::Livekit),
+            position: 1,
+            behind: behind.to_vec(),
+            estimate: None,
+        };
+        let plan = Plan {
+            planned: vec![planned(7, "amd64", &[3, 4]), planned(8, "arm64", &[])],
+            rejected: vec![],
+        };
+
+        assert_eq!(
+            render(&plan, false, Lang::En),
+            "Queued #7 amd64 for livekit at position 1 (busy with #3, #4), no estimate available\n\
+             Queued #8 arm64 for livekit at position 1, no estimate available\n"
+        );
+        assert_eq!(
+            render(&plan, true, Lang::En).lines().nth(1),
+            Some("Would queue amd64 for livekit at position 1 (busy with #3, #4), no estimate available")
+        );
+    }
+
+    fn flavors() -> Flavors {
+        let v = ["new-world", "old-world"].map(String::from).to_vec();
+        Flavors([("loongarch64".to_string(), v)].into())
+    }
+
+    #[test]
+    fn flavors_are_per_arch() {
+        let f = flavors();
+        assert_eq!(f.check("loongarch64", "old-world", Lang::En), Ok(()));
+        assert_eq!(
+            f.check("loongarch64", "world", Lang::En),
+            Err("loongarch64 has no flavor world, allowed are: new-world old-world".to_string())
+        );
+        assert_eq!(
+            f.check("amd64", "old-world", Lang::En),
+            Err("amd64 has no flavors, build it without --flavor.".to_string())
+        );
+        assert_eq!(
+            f.usage(Lang::En),
+            "--flavor <flavor>: build in a flavor of the arch, loongarch64: new-world old-world\n"
+        );
+        assert_eq!(Flavors::default().usage(Lang::En), "");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_flavor_is_checked_per_arch_and_tells_jobs_apart() {
+        let redis = redis().await;
+        let server = redis.server_with(|s| s.flavors = flavors()).await;
+        let mut db = redis.db().await;
+
+        let reply = server
+            .ask(ADMIN, "/release base;amd64 loongarch64 --flavor old-world")
+            .await;
+        assert!(
+            reply.contains("loongarch64 for release(base) old-world at position 1"),
+            "{reply}"
+        );
+        assert!(reply.contains("amd64 has no flavors"), "{reply}");
+        assert!(db.queue("mainline", "amd64").await.unwrap().is_empty());
+        let queued = db.queue("mainline", "loongarch64").await.unwrap();
+        assert_eq!(queued[0].flavor.as_deref(), Some("old-world"));
+
+        // not the same job as the one queued
+        let reply = server.ask(ADMIN, "/release base;loongarch64").await;
+        assert!(reply.contains("at position 2"), "{reply}");
+        let reply = server
+            .ask(ADMIN, "/release base;loongarch64 --flavor old-world")
+            .await;
+        assert!(reply.contains("Identical job"), "{reply}");
+
+        let reply = server
+            .ask(ADMIN, "/livekit loongarch64 --flavor old-world")
+            .await;
+        assert_eq!(reply, "--flavor is only for releases.");
+    }
+
+    #[test]
+    fn a_leading_question_mark_is_a_dry_run() {
+        for a in ["?amd64", " ? amd64", "amd64 --dry-run"] {
+            let (rest, opts) = split_options(a, Lang::En).unwrap();
+            assert_eq!(rest, "amd64", "{a:?}");
+            assert!(opts.dry_run, "{a:?}");
+        }
+        assert!(!split_options("amd64", Lang::En).unwrap().1.dry_run);
+    }
+
+    #[test]
+    fn no_variants_are_refused() {
+        for a in ["", " ", ";", ";amd64", " \t; arm64 riscv64", ";;"] {
+            assert_eq!(parse(a), Err(ReleaseArgsError::NoVariants), "{a:?}");
+        }
+    }
+
+    #[test]
+    fn default_stands_alone() {
+        assert_eq!(parse("default"), Ok(args(&["default"], &[])));
+        assert_eq!(parse("default;amd64"), Ok(args(&["default"], &["amd64"])));
+        for a in ["default desktop", "base default;amd64"] {
+            assert_eq!(parse(a), Err(ReleaseArgsError::DefaultWithOthers), "{a:?}");
+        }
+    }
+
+    #[test]
+    fn whitespace_only_segments_are_nothing() {
+        assert_eq!(
+            parse("  base \t desktop ;  ; arm64 "),
+            Ok(args(&["base", "desktop"], &["arm64"]))
+        );
+        assert_eq!(parse("base;   "), Ok(args(&["base"], &[])));
+    }
+
+    #[test]
+    fn an_arch_among_the_variants_is_a_forgotten_semicolon() {
+        for a in ["base desktop amd64", "base desktop AMD64;arm64"] {
+            assert_eq!(
+                parse(a),
+                Err(ReleaseArgsError::ArchAsVariant {
+                    arch: "amd64".to_string()
+                }),
+                "{a:?}"
+            );
+        }
+        assert_eq!(
+            parse("base desktop;amd64"),
+            Ok(args(&["base", "desktop"], &["amd64"]))
+        );
+    }
+
+    #[test]
+    fn a_near_miss_needs_yes() {
+        let near = ReleaseArgsError::LooksLikeArch {
+            variant: "amd6".to_string(),
+            arch: "amd64".to_string(),
+        };
+        assert_eq!(parse("base amd6"), Err(near));
+
+        let (rest, opts) = split_options("base amd6 --yes", Lang::En).unwrap();
+        assert!(opts.yes);
+        assert_eq!(
+            parse_release_args(&rest, &archs(), opts.yes),
+            Ok(args(&["base", "amd6"], &[]))
+        );
+    }
+
+    #[test]
+    fn stray_semicolons_separate_archs() {
+        for a in [
+            "base;amd64;",
+            "base;;amd64",
+            "base;amd64;arm64",
+            "base; amd64 ;; arm64;",
+        ] {
+            let archs: &[&str] = if a.contains("arm64") {
+                &["amd64", "arm64"]
+            } else {
+                &["amd64"]
+            };
+            assert_eq!(parse(a), Ok(args(&["base"], archs)), "{a:?}");
+        }
+    }
+}
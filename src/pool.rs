@@ -0,0 +1,208 @@
+//! Separate sets of arches, workers and users sharing one server, e.g. for
+//! a sub-project driving its own builds without touching the mainline
+//! queues. Queues, builds, workers and history all belong to a pool;
+//! arch-wide state such as `/disable` and `/window` is shared.
+
+use std::sync::{atomic::AtomicU64, RwLock};
+
+use axum::http::HeaderMap;
+use eyre::bail;
+
+use crate::{
+    db::{now, Channel},
+    env_list,
+    secret::{Secrets, Which},
+};
+
+/// The pool of everything predating pools, configured by `shipit_archs`
+/// and `shipit_secret`.
+pub const MAINLINE: &str = "mainline";
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub archs: Vec<String>,
+    /// Its workers authenticate with them, and only get its builds. See
+    /// [`crate::secret`] for how they change.
+    pub secrets: RwLock<Secrets>,
+    /// Requests with the previous secret after its grace, for the alert.
+    pub expired_uses: AtomicU64,
+    /// Chats that may use it besides the admins, see [`Pools::may_use`].
+    pub users: Vec<i64>,
+    /// Where the completion notices of its builds also go.
+    pub chat: Option<i64>,
+    /// Where its builds publish unless requested otherwise.
+    pub channel: Option<Channel>,
+}
+
+impl Pool {
+    /// The secret its workers are to use.
+    pub fn primary(&self) -> String {
+        self.secrets.read().unwrap().primary.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct Pools {
+    /// Mainline first.
+    pools: Vec<Pool>,
+}
+
+impl Pools {
+    /// Mainline, then the pools listed in `shipit_pools`, each configured
+    /// by `shipit_pool_<name>_archs`, `_secret` (both required), `_users`
+    /// (chat ids), `_chat` and `_channel`. Mainline takes the last three
+    /// too.
+    pub fn from_env(archs: Vec<String>, secret: String) -> eyre::Result<Self> {
+        let mut pools = vec![read(MAINLINE, Some(archs), Some(secret))?];
+
+        for name in env_list("shipit_pools").unwrap_or_default() {
+            if name == MAINLINE || pools.iter().any(|x| x.name == name) {
+                bail!("Pool {name} is listed twice in shipit_pools");
+            }
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                bail!("Invalid pool name {name}, expected lowercase letters, digits and -");
+            }
+
+            let pool = read(&name, None, None)?;
+            if pools.iter().any(|x| x.primary() == pool.primary()) {
+                bail!("Pool {name} shares its secret with another pool");
+            }
+            pools.push(pool);
+        }
+
+        Ok(Self { pools })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Pool> {
+        self.pools.iter().find(|x| x.name == name)
+    }
+
+    pub fn mainline(&self) -> &Pool {
+        &self.pools[0]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Pool> {
+        self.pools.iter()
+    }
+
+    /// Every arch of every pool, once.
+    pub fn archs(&self) -> Vec<String> {
+        let mut v = vec![];
+        for a in self.pools.iter().flat_map(|x| &x.archs) {
+            if !v.contains(a) {
+                v.push(a.clone());
+            }
+        }
+
+        v
+    }
+
+    pub fn names(&self) -> String {
+        self.pools
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The pool whose secret `header` carries.
+    pub fn by_secret(&self, header: &HeaderMap) -> Option<&Pool> {
+        self.authenticate(header).map(|(pool, _)| pool)
+    }
+
+    /// The pool whose secret `header` carries, and which of its secrets it
+    /// is. The previous one of a pool counts until its grace ends, and is
+    /// counted in [`Pool::expired_uses`] after.
+    pub fn authenticate(&self, header: &HeaderMap) -> Option<(&Pool, Which)> {
+        let secret = header.get("secret")?;
+        let now = now();
+
+        self.pools.iter().find_map(|pool| {
+            let secrets = pool.secrets.read().unwrap();
+            if *secret == secrets.primary {
+                return Some((pool, Which::Primary));
+            }
+
+            let previous = secrets.previous.as_ref().filter(|x| *secret == x.secret)?;
+            if now < previous.until {
+                Some((
+                    pool,
+                    Which::Previous {
+                        until: previous.until,
+                    },
+                ))
+            } else {
+                pool.expired_uses
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                None
+            }
+        })
+    }
+
+    /// Whether `chat` may use `pool`: admins every pool, the chats listed
+    /// in the users of a pool that one, and mainline every chat not listed
+    /// in another pool.
+    pub fn may_use(&self, pool: &Pool, chat: i64, admin: bool) -> bool {
+        admin
+            || pool.users.contains(&chat)
+            || (pool.name == MAINLINE && !self.pools.iter().any(|x| x.users.contains(&chat)))
+    }
+
+    /// The pool of a chat that did not pick one with `/setpool`: the first
+    /// listing it, mainline if none does.
+    pub fn default_for(&self, chat: i64) -> &Pool {
+        self.pools
+            .iter()
+            .find(|x| x.users.contains(&chat))
+            .unwrap_or(self.mainline())
+    }
+}
+
+fn read(name: &str, archs: Option<Vec<String>>, secret: Option<String>) -> eyre::Result<Pool> {
+    let var = |x: &str| format!("shipit_pool_{name}_{x}");
+
+    let archs = match archs {
+        Some(x) => x,
+        None => match env_list(&var("archs")) {
+            Some(x) if !x.is_empty() => x,
+            _ => bail!("Pool {name} needs {}", var("archs")),
+        },
+    };
+    let secret = match secret {
+        Some(x) => x,
+        None => match std::env::var(var("secret")) {
+            Ok(x) if !x.is_empty() => x,
+            _ => bail!("Pool {name} needs {}", var("secret")),
+        },
+    };
+    let users = env_list(&var("users"))
+        .unwrap_or_default()
+        .iter()
+        .map(|x| x.parse())
+        .collect::<Result<Vec<i64>, _>>()?;
+    let chat = match std::env::var(var("chat")) {
+        Ok(x) => Some(x.parse()?),
+        Err(_) => None,
+    };
+    let channel = match std::env::var(var("channel")) {
+        Ok(x) => Some(x.parse().map_err(|e: String| eyre::eyre!(e))?),
+        Err(_) => None,
+    };
+
+    Ok(Pool {
+        name: name.to_string(),
+        archs,
+        secrets: RwLock::new(Secrets {
+            primary: secret,
+            previous: None,
+        }),
+        expired_uses: AtomicU64::new(0),
+        users,
+        chat,
+        channel,
+    })
+}
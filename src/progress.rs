@@ -0,0 +1,416 @@
+//! The progress of a build in the chat that requested it: one message,
+//! posted once a worker claimed the build and edited in place every
+//! `shipit_progress_interval` seconds (default 60, 0 for none) as the
+//! build goes on, with a button cancelling the build. Its id is kept in
+//! Redis with the build, along with those of earlier posts if it could not
+//! be edited any more, as Telegram refuses edits after 48 hours, or was
+//! deleted.
+//!
+//! When the build finishes, the outbox hands its completion notice to
+//! [`complete`], which by the preference of the chat set with
+//! `/setup progress` edits the post into it, or deletes the posts so the
+//! notice is posted anew, which unlike an edit notifies the chat. A
+//! cancelled build ends them with [`cancelled`].
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use teloxide::{
+    types::{ChatId, MessageId},
+    utils::html,
+    ApiError, RequestError,
+};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{
+    buttons::{keyboard, Action, Button},
+    db::{now, Build, RunningBuild},
+    format::{human_bytes, human_duration},
+    lang::{Lang, Msg},
+    outbox::Notification,
+    telegram, AppState,
+};
+
+/// How the progress posts of a build end, set for a chat with
+/// `/setup progress`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Edited into the completion notice.
+    #[default]
+    Edit,
+    /// Deleted, the completion notice posted anew.
+    Replace,
+    /// Nothing is posted before the completion notice.
+    Off,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 3] = [Mode::Edit, Mode::Replace, Mode::Off];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Edit => "edit",
+            Mode::Replace => "replace",
+            Mode::Off => "off",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == s)
+    }
+
+    pub fn describe(self, lang: Lang) -> String {
+        lang.text(match self {
+            Mode::Edit => Msg::ProgressModeEdit,
+            Mode::Replace => Msg::ProgressModeReplace,
+            Mode::Off => Msg::ProgressModeOff,
+        })
+    }
+}
+
+/// The progress posts of a build.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Posts {
+    pub chat: i64,
+    /// Oldest first, the last one is edited.
+    pub messages: Vec<i32>,
+    /// Of the last one, to leave it alone while nothing changed.
+    pub text: String,
+}
+
+/// Telegram will not touch the message any more, or it is gone.
+fn gone(e: &RequestError) -> bool {
+    matches!(
+        e,
+        RequestError::Api(
+            ApiError::MessageCantBeEdited
+                | ApiError::MessageToEditNotFound
+                | ApiError::MessageCantBeDeleted
+                | ApiError::MessageToDeleteNotFound
+        )
+    )
+}
+
+/// Edit `message` to `text` under `buttons`, an unchanged message counting
+/// as edited. `Ok(None)` if it cannot be edited.
+async fn edit(
+    state: &AppState,
+    chat: ChatId,
+    message: MessageId,
+    text: &str,
+    buttons: &[Button],
+) -> Result<Option<MessageId>, RequestError> {
+    let buttons = (!buttons.is_empty()).then(|| keyboard(buttons));
+    match state
+        .telegram
+        .edit_html(chat, message, text.to_string(), buttons)
+        .await
+    {
+        Ok(x) => Ok(Some(x)),
+        Err(RequestError::Api(ApiError::MessageNotModified)) => Ok(Some(message)),
+        Err(e) if gone(&e) => {
+            info!("Progress post {message} in {chat} is gone or too old: {e}");
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Delete `messages`, those already gone or too old to delete are left.
+async fn delete(state: &AppState, chat: ChatId, messages: &[i32]) {
+    for &m in messages {
+        match state.telegram.delete(chat, MessageId(m)).await {
+            Ok(_) => {}
+            Err(e) if gone(&e) => info!("Leaving progress post {m} in {chat}: {e}"),
+            Err(e) => warn!("Failed to delete progress post {m} in {chat}: {e}"),
+        }
+    }
+}
+
+/// What the progress post of `r` says.
+fn render(r: &RunningBuild, lang: Lang, now: u64) -> String {
+    let b = &r.build;
+    let stage = match (
+        &r.upload,
+        r.steps.iter().rev().find(|x| x.finished_at.is_none()),
+    ) {
+        (Some(u), _) => lang.tr(
+            Msg::ProgressUpload,
+            &[
+                ("artifact", &u.artifact),
+                ("sent", &human_bytes(u.sent)),
+                ("total", &human_bytes(u.total)),
+            ],
+        ),
+        (None, Some(s)) => s.name.clone(),
+        (None, None) => r
+            .progress
+            .clone()
+            .unwrap_or_else(|| lang.text(Msg::ProgressStarting)),
+    };
+
+    lang.tr(
+        Msg::ProgressPost,
+        &[
+            ("id", &b.id),
+            ("build", &b.build_type),
+            ("arch", &b.arch),
+            ("worker", &r.worker.as_deref().unwrap_or("?")),
+            ("stage", &stage),
+            (
+                "elapsed",
+                &human_duration(now.saturating_sub(r.claimed_at.unwrap_or(now))),
+            ),
+        ],
+    )
+}
+
+/// Post or update the progress of `r` in the chat that requested it.
+async fn update(state: &AppState, r: &RunningBuild, now: u64) -> eyre::Result<()> {
+    let id = r.build.id;
+    let _held = state.progress_lock.lock().await;
+
+    let mut db = state.db().await;
+    // finished since, and its post ended already
+    if db.get_running(&r.build.arch, id).await?.is_none() {
+        return Ok(());
+    }
+    let chat = db.resolve_chat(r.build.requester_chat).await?;
+    let record = db.chat(chat).await?;
+    if record
+        .as_ref()
+        .is_some_and(|c| c.undeliverable || c.progress == Mode::Off)
+    {
+        return Ok(());
+    }
+    let lang = record.map(|c| c.lang).unwrap_or_default();
+    let text = html::escape(&render(r, lang, now));
+    let mut posts = db.progress_posts(id).await?.unwrap_or(Posts {
+        chat,
+        messages: vec![],
+        text: String::new(),
+    });
+    drop(db);
+    if posts.text == text {
+        return Ok(());
+    }
+
+    let chat = ChatId(posts.chat);
+    let buttons = [Button {
+        action: Action::Cancel,
+        id,
+        label: lang.text(Msg::ButtonCancelRunning),
+    }];
+    let edited = match posts.messages.last() {
+        Some(&m) => edit(state, chat, MessageId(m), &text, &buttons).await?,
+        None => None,
+    };
+    if edited.is_none() {
+        let sent = state
+            .telegram
+            .send_buttons(chat, text.clone(), keyboard(&buttons))
+            .await?;
+        posts.messages.push(sent.0);
+    }
+    posts.text = text;
+
+    state.db().await.set_progress_posts(id, &posts).await
+}
+
+/// Keep the progress posts of the running builds up to date.
+pub async fn run(state: Arc<AppState>) {
+    if state.progress_interval == 0 {
+        return;
+    }
+
+    loop {
+        sleep(Duration::from_secs(state.progress_interval)).await;
+
+        let running = state.db().await.running_worker().await;
+        let running = match running {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to read the running builds for progress posts: {e}");
+                continue;
+            }
+        };
+
+        let now = now();
+        for r in running.iter().filter(|x| x.build.requester_chat != 0) {
+            if let Err(e) = update(&state, r, now).await {
+                warn!("Failed to post the progress of #{}: {e}", r.build.id);
+            }
+        }
+    }
+}
+
+/// End the progress posts of the build `n` completes, if it went to the
+/// chat they are in. Returns the message `n` was edited into, if not to be
+/// sent.
+pub async fn complete(state: &AppState, n: &Notification) -> eyre::Result<Option<MessageId>> {
+    let Some(id) = n.build else {
+        return Ok(None);
+    };
+    let _held = state.progress_lock.lock().await;
+
+    let mut db = state.db().await;
+    let Some(posts) = db.progress_posts(id).await?.filter(|x| x.chat == n.chat) else {
+        return Ok(None);
+    };
+    db.clear_progress_posts(id).await?;
+    let mode = db
+        .chat(n.chat)
+        .await?
+        .map(|c| c.progress)
+        .unwrap_or_default();
+    drop(db);
+
+    let chat = ChatId(n.chat);
+    let Some((&last, earlier)) = posts.messages.split_last() else {
+        return Ok(None);
+    };
+    if mode != Mode::Edit || !telegram::fits(&n.text) {
+        delete(state, chat, &posts.messages).await;
+        return Ok(None);
+    }
+
+    let edited = match edit(state, chat, MessageId(last), &n.text, &n.buttons).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Failed to edit progress post {last} in {chat}, posting anew: {e}");
+            None
+        }
+    };
+    match edited {
+        Some(_) => delete(state, chat, earlier).await,
+        None => delete(state, chat, &posts.messages).await,
+    }
+
+    Ok(edited)
+}
+
+/// End the progress posts of `b`, cancelled while running: the last one
+/// tells so, without its cancel button.
+pub async fn cancelled(state: &AppState, b: &Build) -> eyre::Result<()> {
+    let _held = state.progress_lock.lock().await;
+
+    let mut db = state.db().await;
+    let Some(posts) = db.progress_posts(b.id).await? else {
+        return Ok(());
+    };
+    db.clear_progress_posts(b.id).await?;
+    let lang = db.lang(posts.chat).await?;
+    drop(db);
+
+    let Some(&last) = posts.messages.last() else {
+        return Ok(());
+    };
+    let line = lang.tr(
+        Msg::Cancelled,
+        &[("id", &b.id), ("arch", &b.arch), ("build", &b.build_type)],
+    );
+    let text = format!("{}\n\n{}", posts.text, html::escape(&line));
+    edit(state, ChatId(posts.chat), MessageId(last), &text, &[]).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use shipit_common::{Step, UploadProgress};
+
+    use super::*;
+    use crate::{
+        db::{BuildType, Preference},
+        testing::{build, redis, running, ADMIN},
+    };
+
+    const NOW: u64 = 1_800_000_000;
+
+    #[test]
+    fn modes_by_name() {
+        for m in Mode::ALL {
+            assert_eq!(Mode::parse(m.name()), Some(m));
+        }
+        assert_eq!(Mode::parse("Edit"), None);
+        assert_eq!(Mode::default(), Mode::Edit);
+    }
+
+    #[test]
+    fn the_post_tells_the_upload_else_the_step_else_the_stage() {
+        let mut r = running(build(7, "amd64", BuildType::Livekit), "w1", NOW - 125);
+        let post = |r: &RunningBuild| render(r, Lang::En, NOW);
+        assert_eq!(post(&r), "⏳ #7 livekit amd64 on w1: starting, 2m so far");
+
+        r.progress = Some("building".to_string());
+        assert_eq!(post(&r), "⏳ #7 livekit amd64 on w1: building, 2m so far");
+
+        r.steps = vec![
+            serde_json::from_value::<Step>(serde_json::json!({
+                "name": "git pull", "started_at": NOW - 120, "finished_at": NOW - 100,
+                "exit_code": 0,
+            }))
+            .unwrap(),
+            serde_json::from_value::<Step>(serde_json::json!({
+                "name": "aosc-mklive", "started_at": NOW - 100,
+            }))
+            .unwrap(),
+        ];
+        assert_eq!(
+            post(&r),
+            "⏳ #7 livekit amd64 on w1: aosc-mklive, 2m so far"
+        );
+
+        r.upload = Some(UploadProgress {
+            artifact: "aosc-os_livekit_20240501_amd64.iso".to_string(),
+            sent: 512 << 20,
+            total: 2 << 30,
+            stalled_secs: 0,
+        });
+        assert_eq!(
+            post(&r),
+            "⏳ #7 livekit amd64 on w1: uploading aosc-os_livekit_20240501_amd64.iso \
+             512.0 MiB/2.0 GiB, 2m so far"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn the_post_is_edited_in_place_and_into_the_result() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        let mut b = build(7, "amd64", BuildType::Livekit);
+        b.requester_chat = ADMIN;
+        db.enqueue_all(&[&b]).await.unwrap();
+        db.claim(
+            "mainline",
+            "amd64",
+            Some("w1"),
+            None,
+            true,
+            Preference::default(),
+        )
+        .await
+        .unwrap();
+        let mut r = db.get_running("amd64", 7).await.unwrap().unwrap();
+        let now = r.claimed_at.unwrap();
+        let state = &server.state;
+
+        update(state, &r, now).await.unwrap();
+        update(state, &r, now).await.unwrap();
+        r.progress = Some("building".to_string());
+        update(state, &r, now + 60).await.unwrap();
+        let posts = db.progress_posts(7).await.unwrap().unwrap();
+        assert_eq!(posts.messages.len(), 1);
+        assert_eq!(posts.text, "⏳ #7 livekit amd64 on w1: building, 1m so far");
+
+        let n = Notification::plain(ADMIN, "Build #7 succeeded").with_build(7);
+        assert!(complete(state, &n).await.unwrap().is_some());
+        assert!(db.progress_posts(7).await.unwrap().is_none());
+        // the notice is not sent again
+        assert_eq!(complete(state, &n).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,292 @@
+//! Tiered retention of the history, so a year of nightlies does not fill
+//! Redis with manifests, timelines and failure excerpts. Per pool, arch and
+//! type, the newest `shipit_history_full` builds (default 50) are kept as
+//! they are, older ones up to `shipit_history_keep` (default 500) only with
+//! their outcome, duration, log and scripts commit, and the rest dropped.
+//! The history is compacted every `shipit_history_compact_interval` seconds
+//! (default an hour), summarized builds are marked `pruned`.
+
+use std::{collections::BTreeMap, fmt::Write, sync::Arc, time::Duration};
+
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::{
+    db::{Db, HistoryEntry, HistorySize},
+    env_secs, AppState,
+};
+
+pub struct Retention {
+    /// Builds kept in full per pool, arch and type.
+    full: usize,
+    /// Builds kept at all per pool, arch and type.
+    keep: usize,
+    interval: Duration,
+}
+
+/// What a compaction did.
+#[derive(Debug, Default)]
+struct Compacted {
+    summarized: usize,
+    deleted: usize,
+}
+
+impl Retention {
+    pub fn from_env() -> eyre::Result<Self> {
+        let count = |name: &str, default: usize| -> eyre::Result<usize> {
+            match std::env::var(name) {
+                Ok(x) => Ok(x.parse()?),
+                Err(_) => Ok(default),
+            }
+        };
+        let full = count("shipit_history_full", 50)?;
+        let keep = count("shipit_history_keep", 500)?;
+        if full > keep {
+            eyre::bail!("shipit_history_full ({full}) is more than shipit_history_keep ({keep})");
+        }
+
+        Ok(Self {
+            full,
+            keep,
+            interval: Duration::from_secs(env_secs("shipit_history_compact_interval", 3600)?),
+        })
+    }
+
+    /// Summarize and drop the entries of `history`, newest first, past
+    /// their tier.
+    fn compact(&self, history: &mut Vec<HistoryEntry>) -> Compacted {
+        let mut seen: BTreeMap<(String, String, String), usize> = BTreeMap::new();
+        let mut compacted = Compacted::default();
+
+        history.retain_mut(|h| {
+            let n = seen
+                .entry((h.pool.clone(), h.arch.clone(), h.build_type.clone()))
+                .or_default();
+            *n += 1;
+
+            if *n > self.keep {
+                compacted.deleted += 1;
+                return false;
+            }
+            if *n > self.full && !h.pruned {
+                h.summarize();
+                compacted.summarized += 1;
+            }
+            true
+        });
+
+        compacted
+    }
+
+    /// What compacting `raw`, the history as stored, changes, for
+    /// [`Db::rewrite_history`]: the entries to summarize or, mapped to an
+    /// empty string, to drop.
+    fn changes(&self, raw: &[String]) -> eyre::Result<(BTreeMap<String, String>, Compacted)> {
+        let mut history = vec![];
+        for i in raw {
+            history.push(serde_json::from_str::<HistoryEntry>(i)?);
+        }
+        let pruned = history.iter().map(|x| x.pruned).collect::<Vec<_>>();
+        let compacted = self.compact(&mut history);
+
+        let mut kept = history
+            .into_iter()
+            .map(|x| (x.id, x))
+            .collect::<BTreeMap<_, _>>();
+        let mut changes = BTreeMap::new();
+        for (raw, pruned) in raw.iter().zip(pruned) {
+            let id = serde_json::from_str::<HistoryEntry>(raw)?.id;
+            match kept.remove(&id) {
+                None => {
+                    changes.insert(raw.clone(), String::new());
+                }
+                Some(h) if h.pruned != pruned => {
+                    changes.insert(raw.clone(), serde_json::to_string(&h)?);
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok((changes, compacted))
+    }
+}
+
+async fn compact(db: &mut Db, retention: &Retention) -> eyre::Result<Compacted> {
+    let raw = db.raw_history().await?;
+    let (changes, compacted) = retention.changes(&raw)?;
+    if !changes.is_empty() {
+        db.rewrite_history(&changes).await?;
+    }
+
+    Ok(compacted)
+}
+
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        let res = compact(&mut *state.db().await, &state.retention).await;
+        match res {
+            Ok(Compacted {
+                summarized: 0,
+                deleted: 0,
+            }) => {}
+            Ok(c) => info!(
+                "Compacted the history: {} builds summarized, {} dropped",
+                c.summarized, c.deleted
+            ),
+            Err(e) => error!("Failed to compact the history: {e}"),
+        }
+
+        sleep(state.retention.interval).await;
+    }
+}
+
+/// The size of the history, for `/metrics`.
+pub fn metrics(size: &HistorySize) -> String {
+    let mut s = String::from(
+        "# HELP shipit_history_entries Finished builds in the history, by whether their details were pruned.\n\
+         # TYPE shipit_history_entries gauge\n",
+    );
+    let _ = writeln!(s, "shipit_history_entries{{detail=\"full\"}} {}", size.full);
+    let _ = writeln!(
+        s,
+        "shipit_history_entries{{detail=\"summarized\"}} {}",
+        size.summarized
+    );
+    s.push_str(
+        "# HELP shipit_history_bytes Size of the history in Redis, as JSON.\n\
+         # TYPE shipit_history_bytes gauge\n",
+    );
+    let _ = writeln!(s, "shipit_history_bytes {}", size.bytes);
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use shipit_common::Failure;
+
+    use super::*;
+    use crate::testing::{entry, redis};
+
+    fn retention() -> Retention {
+        Retention {
+            full: 2,
+            keep: 4,
+            interval: Duration::from_secs(3600),
+        }
+    }
+
+    /// `n` builds of each of livekit on amd64, livekit on arm64 and
+    /// release on amd64, newest first, all with a failure excerpt.
+    fn history(n: i64) -> Vec<HistoryEntry> {
+        let mut v = vec![];
+        for id in (0..n).rev() {
+            for (i, (arch, t)) in [
+                ("amd64", "livekit"),
+                ("arm64", "livekit"),
+                ("amd64", "release"),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                let mut h = entry(id * 3 + i as i64, arch, t, false, 600);
+                h.failure = Some(Failure {
+                    class: "mirror_timeout".to_string(),
+                    summary: "mirror timeout".to_string(),
+                    excerpt: "curl: (28) Operation timed out".to_string(),
+                    line: None,
+                });
+                v.push(h);
+            }
+        }
+
+        v
+    }
+
+    #[test]
+    fn each_arch_and_type_has_its_own_tiers() {
+        let mut h = history(6);
+        let c = retention().compact(&mut h);
+        assert_eq!((c.summarized, c.deleted), (6, 6));
+        assert_eq!(h.len(), 12);
+
+        for (arch, t) in [
+            ("amd64", "livekit"),
+            ("arm64", "livekit"),
+            ("amd64", "release"),
+        ] {
+            let of = h
+                .iter()
+                .filter(|x| x.arch == arch && x.build_type == t)
+                .collect::<Vec<_>>();
+            assert_eq!(of.len(), 4, "{arch} {t}");
+            assert!(of[..2].iter().all(|x| !x.pruned && x.failure.is_some()));
+            assert!(of[2..].iter().all(|x| x.pruned && x.failure.is_none()));
+            // the newest are kept
+            assert!(of.windows(2).all(|w| w[0].id > w[1].id));
+        }
+        assert!(h
+            .iter()
+            .all(|x| x.log_url.is_none() && x.duration_secs == Some(600)));
+    }
+
+    #[test]
+    fn compacting_again_changes_nothing() {
+        let mut h = history(6);
+        retention().compact(&mut h);
+        let c = retention().compact(&mut h);
+        assert_eq!((c.summarized, c.deleted), (0, 0));
+        assert_eq!(h.len(), 12);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn the_history_is_rewritten_once_compacted() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        for h in history(6).iter().rev() {
+            db.push_history(h).await.unwrap();
+        }
+
+        let c = compact(&mut db, &retention()).await.unwrap();
+        assert_eq!((c.summarized, c.deleted), (6, 6));
+        let size = db.history_size().await.unwrap();
+        assert_eq!((size.full, size.summarized), (6, 6));
+        assert_eq!(db.history().await.unwrap()[0].id, 15);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn what_changes_while_compacting_is_kept() {
+        let redis = redis().await;
+        let mut db = redis.db().await;
+        for h in history(6).iter().rev() {
+            db.push_history(h).await.unwrap();
+        }
+
+        let raw = db.raw_history().await.unwrap();
+        let (changes, _) = retention().changes(&raw).unwrap();
+        // a build finishing and a hook of one to summarize reporting back
+        // in between
+        db.push_history(&entry(100, "amd64", "livekit", true, 600))
+            .await
+            .unwrap();
+        let hooked = db
+            .update_history(6, |x| x.queued.push("hook".to_string()))
+            .await
+            .unwrap();
+        assert!(hooked);
+        db.rewrite_history(&changes).await.unwrap();
+
+        let history = db.history().await.unwrap();
+        assert_eq!(history.len(), 13);
+        assert_eq!(history[0].id, 100);
+        assert_eq!(history[1].id, 15);
+        let h = history.iter().find(|x| x.id == 6).unwrap();
+        assert_eq!(h.queued, ["hook"]);
+        assert!(!h.pruned);
+        // and compacted the next time round
+        let c = compact(&mut db, &retention()).await.unwrap();
+        assert_eq!((c.summarized, c.deleted), (1, 1));
+    }
+}
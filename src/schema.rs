@@ -0,0 +1,165 @@
+//! Versioned JSON of the builds kept in Redis, queued and running, so a
+//! change to [`Build`](crate::db::Build) does not leave records behind that
+//! fail to parse. Records are stored as `{"schema": 3, "data": {...}}`, and
+//! older ones upgraded when read, see [`decode`]. `shipit migrate` and
+//! `POST /migrate` upgrade all at once.
+//!
+//! The schemas:
+//!
+//! 1. A bare build without `requester_chat`, as in the first releases.
+//! 2. A bare build, the fields added since defaulting when missing.
+//! 3. The build of schema 2 in the envelope.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+pub const SCHEMA: u32 = 3;
+
+/// How each schema before [`SCHEMA`] becomes the next, the first upgrading
+/// schema 1.
+const MIGRATIONS: [fn(Value) -> Value; (SCHEMA - 1) as usize] = [v1_to_v2, v2_to_v3];
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    schema: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Stored {
+    schema: u32,
+    data: Value,
+}
+
+/// The stored form of `record`, of the current schema.
+pub fn encode<T: Serialize>(record: &T) -> serde_json::Result<String> {
+    serde_json::to_string(&Envelope {
+        schema: SCHEMA,
+        data: record,
+    })
+}
+
+/// Parse a record of any schema up to [`SCHEMA`]. Returns it and whether it
+/// was of an older one, so the caller writes back the upgraded form.
+pub fn decode<T: DeserializeOwned>(raw: &str) -> eyre::Result<(T, bool)> {
+    let value: Value = serde_json::from_str(raw)?;
+    let (schema, mut data) = if value.get("schema").is_some() && value.get("data").is_some() {
+        let stored: Stored = serde_json::from_value(value)?;
+        (stored.schema, stored.data)
+    } else if value.get("requester_chat").is_some() {
+        (2, value)
+    } else {
+        (1, value)
+    };
+
+    if schema > SCHEMA {
+        eyre::bail!(
+            "Record of schema {schema}, this server only knows up to {SCHEMA} and is too old for it"
+        );
+    }
+    if schema == 0 {
+        eyre::bail!("Record of schema 0, which never existed");
+    }
+
+    for migrate in &MIGRATIONS[schema as usize - 1..] {
+        data = migrate(data);
+    }
+
+    Ok((serde_json::from_value(data)?, schema < SCHEMA))
+}
+
+/// Builds queued before they remembered the chat that requested them are
+/// told nobody.
+fn v1_to_v2(mut data: Value) -> Value {
+    if let Some(m) = data.as_object_mut() {
+        m.entry("requester_chat").or_insert(Value::from(0));
+    }
+
+    data
+}
+
+/// Only the envelope is new.
+fn v2_to_v3(data: Value) -> Value {
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use shipit_common::{BuildType, Channel};
+
+    use super::*;
+    use crate::db::{Build, RunningBuild};
+
+    /// A record as stored by a server of that schema.
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_str!(concat!("../tests/fixtures/schema/", $name, ".json"))
+        };
+    }
+
+    /// `raw` decoded, encoded and decoded again.
+    fn round_trip<T: Serialize + DeserializeOwned>(raw: &str) -> T {
+        let (record, old): (T, _) = decode(raw).unwrap();
+        assert!(old, "{raw}");
+
+        let stored = encode(&record).unwrap();
+        let (record, old) = decode(&stored).unwrap();
+        assert!(!old, "{stored}");
+        record
+    }
+
+    #[test]
+    fn schema_1_is_told_nobody() {
+        let b: Build = round_trip(fixture!("v1-queued"));
+
+        assert_eq!(b.id, 7);
+        assert_eq!(b.requester_chat, 0);
+        assert_eq!(b.pool, "mainline");
+        assert_eq!(
+            b.build_type,
+            BuildType::Release(vec!["base".to_string(), "desktop".to_string()])
+        );
+        assert_eq!(b.channel, Channel::Release);
+    }
+
+    #[test]
+    fn schema_2_keeps_its_fields() {
+        let b: Build = round_trip(fixture!("v2-queued"));
+        assert_eq!(b.id, 8);
+        assert_eq!(b.requester_chat, -1001234567890);
+        assert_eq!(b.build_type, BuildType::Livekit);
+        assert_eq!(b.expire_secs, Some(7200));
+        assert_eq!(b.after, Some(7));
+        assert_eq!(b.channel, Channel::Nightly);
+
+        let r: RunningBuild = round_trip(fixture!("v2-running"));
+        assert_eq!(r.build.id, 8);
+        assert_eq!(r.worker.as_deref(), Some("w1"));
+        assert_eq!(r.heartbeat_at, Some(1714522060));
+        assert_eq!(r.progress.as_deref(), Some("building"));
+    }
+
+    #[test]
+    fn schema_3_is_current() {
+        let raw = fixture!("v3-queued");
+        let (b, old): (Build, _) = decode(raw).unwrap();
+
+        assert!(!old);
+        assert_eq!(b.pool, "staging");
+        assert_eq!(b.build_type, BuildType::Rootfs(vec!["base".to_string()]));
+        assert_eq!(b.env["MIRROR"], "https://repo.aosc.io");
+        assert!(b.allow_partial && b.native_only);
+        assert_eq!(b.note.as_deref(), Some("CVE-2024-1234"));
+        assert_eq!(
+            serde_json::from_str::<Value>(&encode(&b).unwrap()).unwrap(),
+            serde_json::from_str::<Value>(raw).unwrap()
+        );
+    }
+
+    #[test]
+    fn a_newer_schema_says_the_server_is_too_old() {
+        let e = decode::<Build>(fixture!("v4-queued")).unwrap_err();
+
+        assert!(e.to_string().contains("too old"), "{e}");
+    }
+}
@@ -0,0 +1,146 @@
+//! Changing the secret of a pool without restarting anything.
+//! `/rotate-secret` makes a new secret the primary one, and the one it
+//! replaces is still taken for `shipit_secret_grace` seconds (default a
+//! week) while the workers move over; `GET /authcheck` tells a worker which
+//! of the two it uses. After the grace the old one is refused, and the
+//! admins are told if it is still used, see [`run`].
+//!
+//! Rotations are kept in Redis and win over `shipit_secret` and
+//! `shipit_pool_<name>_secret` across restarts, unless the variable is
+//! changed to a secret that is neither of the two.
+
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+use tracing::{error, info, warn};
+
+use crate::{
+    alert,
+    db::{now, Db},
+    lang::Msg,
+    pool::{Pool, Pools},
+    AppState,
+};
+
+/// Uses of an expired secret are told of at most this often per pool.
+const ALERT_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The secrets a pool takes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Secrets {
+    pub primary: String,
+    /// The one the last rotation replaced.
+    #[serde(default)]
+    pub previous: Option<Previous>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Previous {
+    pub secret: String,
+    /// When it is refused from, in UNIX seconds.
+    pub until: u64,
+}
+
+/// Which secret of its pool a request carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Which {
+    Primary,
+    Previous { until: u64 },
+}
+
+/// Take the rotations kept in Redis over the configured secrets.
+pub async fn load(db: &mut Db, pools: &Pools) -> eyre::Result<()> {
+    for pool in pools.iter() {
+        let Some(stored) = db.secrets(&pool.name).await? else {
+            continue;
+        };
+
+        let configured = pool.primary();
+        if stored.primary != configured
+            && stored
+                .previous
+                .as_ref()
+                .is_none_or(|x| x.secret != configured)
+        {
+            info!(
+                "The secret of pool {} was changed in the environment, dropping its rotation",
+                pool.name
+            );
+            db.clear_secrets(&pool.name).await?;
+            continue;
+        }
+
+        *pool.secrets.write().unwrap() = stored;
+    }
+
+    Ok(())
+}
+
+/// Make a new secret the primary one of `pool`, the current one taken for
+/// `grace` seconds more. A previous one still in its grace is refused from
+/// now on.
+pub async fn rotate(db: &mut Db, pool: &Pool, grace: u64) -> eyre::Result<Secrets> {
+    let secrets = Secrets {
+        primary: generate()?,
+        previous: Some(Previous {
+            secret: pool.primary(),
+            until: now() + grace,
+        }),
+    };
+    db.set_secrets(&pool.name, &secrets).await?;
+    *pool.secrets.write().unwrap() = secrets.clone();
+    pool.expired_uses.store(0, Ordering::Relaxed);
+
+    Ok(secrets)
+}
+
+/// 32 random bytes, in hex.
+fn generate() -> eyre::Result<String> {
+    let mut bytes = [0u8; 32];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes.iter().map(|x| format!("{x:02x}")).collect())
+}
+
+/// Tell the admins about requests with a previous secret after its grace,
+/// at most every [`ALERT_INTERVAL`] per pool.
+pub async fn run(state: Arc<AppState>) {
+    // by pool, the uses not told of yet and when they last were
+    let mut pending: BTreeMap<String, (u64, Option<Instant>)> = BTreeMap::new();
+
+    loop {
+        sleep(Duration::from_secs(60)).await;
+
+        for pool in state.pools.iter() {
+            let (uses, alerted) = pending.entry(pool.name.clone()).or_default();
+            *uses += pool.expired_uses.swap(0, Ordering::Relaxed);
+            if *uses == 0 || alerted.is_some_and(|x| x.elapsed() < ALERT_INTERVAL) {
+                continue;
+            }
+
+            warn!(
+                "{uses} requests with the expired secret of pool {}",
+                pool.name
+            );
+            let mut db = state.db().await;
+            let res = alert::notify(
+                &mut db,
+                &state,
+                None,
+                Msg::ExpiredSecretUsed,
+                &[("pool", &pool.name), ("count", uses)],
+            )
+            .await;
+            match res {
+                Ok(()) => (*uses, *alerted) = (0, Some(Instant::now())),
+                Err(e) => error!("Failed to tell of the expired secret of {}: {e}", pool.name),
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Chats set up with `/setup` from inside them, next to those configured in
+//! the environment: the announcements of finished builds of a pool, the
+//! alerts `shipit_admin_chat` gets, the notices of builds no chat is
+//! waiting for, and the daily digest.
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::Db;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Finished builds of a pool, like `shipit_<pool>_chat`.
+    Announcements,
+    /// Operational alerts, like `shipit_admin_chat`.
+    Alerts,
+    /// Finished builds not requested from a chat.
+    DefaultNotify,
+    /// The daily summary of the builds, see [`crate::digest`].
+    Digest,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [
+        Role::Announcements,
+        Role::Alerts,
+        Role::DefaultNotify,
+        Role::Digest,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Announcements => "announcements",
+            Role::Alerts => "alerts",
+            Role::DefaultNotify => "default-notify",
+            Role::Digest => "digest",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == s)
+    }
+
+    /// Every role, for messages.
+    pub fn names() -> String {
+        Self::ALL.map(|x| x.name()).join(", ")
+    }
+}
+
+/// A chat set up for a role.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Registration {
+    pub chat: i64,
+    pub role: Role,
+    /// The pool announced, for [`Role::Announcements`].
+    #[serde(default)]
+    pub pool: Option<String>,
+    pub by: String,
+    pub at: u64,
+}
+
+/// The chats set up for `role`, for announcements only those of `pool`.
+pub async fn chats(db: &mut Db, role: Role, pool: Option<&str>) -> eyre::Result<Vec<i64>> {
+    Ok(db
+        .registrations()
+        .await?
+        .into_iter()
+        .filter(|r| r.role == role)
+        .filter(|r| role != Role::Announcements || r.pool.as_deref() == pool)
+        .map(|r| r.chat)
+        .collect())
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    db::{Build, BuildType, HistoryEntry, RunningBuild},
+    window::Window,
+};
+
+/// Aggregated numbers per `arch/build type`, computed from the history.
+#[derive(Debug, Serialize, Default)]
+pub struct Stats {
+    pub builds: u64,
+    pub successes: u64,
+    pub total_bytes: u64,
+    pub last_bytes: Option<u64>,
+    pub avg_duration_secs: Option<u64>,
+    /// Where the time of the builds went, by phase as reported by workers.
+    pub phases: BTreeMap<String, PhaseStats>,
+    #[serde(skip)]
+    duration_sum: u64,
+    #[serde(skip)]
+    duration_count: u64,
+}
+
+/// Upper bounds of the [`PhaseStats::histogram`] buckets, in seconds.
+pub const PHASE_BUCKETS: &[u64] = &[60, 5 * 60, 15 * 60, 3600, 3 * 3600];
+
+#[derive(Debug, Serialize, Default)]
+pub struct PhaseStats {
+    pub count: u64,
+    pub avg_secs: u64,
+    pub max_secs: u64,
+    /// How many builds spent up to 1m, 5m, 15m, 1h, 3h and longer on the
+    /// phase, see [`PHASE_BUCKETS`].
+    pub histogram: Vec<u64>,
+    #[serde(skip)]
+    sum: u64,
+}
+
+pub fn stats_key(arch: &str, build_type: &str) -> String {
+    format!("{arch}/{build_type}")
+}
+
+pub fn stats(history: &[HistoryEntry]) -> BTreeMap<String, Stats> {
+    let mut map: BTreeMap<String, Stats> = BTreeMap::new();
+
+    // history is stored newest first
+    for entry in history.iter().rev() {
+        let stats = map
+            .entry(stats_key(&entry.arch, &entry.build_type))
+            .or_default();
+
+        stats.builds += 1;
+        if entry.success {
+            stats.successes += 1;
+
+            // failed builds tend to end early, don't let them skew the ETA,
+            // and neither should the queue or the upload. Builds from before
+            // the breakdown count whole.
+            if let Some(d) = entry.intervals().build.or(entry.duration_secs) {
+                stats.duration_sum += d;
+                stats.duration_count += 1;
+            }
+        }
+
+        if let Some(ref m) = entry.manifest {
+            stats.total_bytes += m.total_bytes;
+            stats.last_bytes = Some(m.total_bytes);
+        }
+
+        for (phase, secs) in &entry.phase_durations {
+            let p = stats.phases.entry(phase.clone()).or_default();
+            if p.histogram.is_empty() {
+                p.histogram = vec![0; PHASE_BUCKETS.len() + 1];
+            }
+
+            p.count += 1;
+            p.sum += secs;
+            p.max_secs = p.max_secs.max(*secs);
+            let bucket = PHASE_BUCKETS
+                .iter()
+                .position(|x| secs <= x)
+                .unwrap_or(PHASE_BUCKETS.len());
+            p.histogram[bucket] += 1;
+        }
+    }
+
+    for s in map.values_mut() {
+        s.avg_duration_secs = s.duration_sum.checked_div(s.duration_count);
+
+        for p in s.phases.values_mut() {
+            p.avg_secs = p.sum / p.count;
+        }
+    }
+
+    map
+}
+
+/// When a job is expected to start and finish, in seconds from `now`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Estimate {
+    pub start_in: u64,
+    pub finish_in: u64,
+}
+
+/// Estimate a job of `build_type` on `arch` waiting behind `running` and the
+/// queued builds in `ahead`. Builds start only when `window`, if any, is
+/// open; pass `None` for a build ignoring it.
+///
+/// Returns `None` if any of the involved build types has no recorded
+/// duration yet.
+pub fn estimate(
+    stats: &BTreeMap<String, Stats>,
+    arch: &str,
+    running: &[RunningBuild],
+    ahead: &[Build],
+    build_type: &BuildType,
+    window: Option<&Window>,
+    now: u64,
+) -> Option<Estimate> {
+    let wait = |start_in: u64| window.map_or(0, |w| w.wait_secs(now + start_in));
+
+    let avg = |t: &BuildType| {
+        stats
+            .get(&stats_key(arch, t.name()))
+            .and_then(|s| s.avg_duration_secs)
+    };
+
+    let mut start_in = 0;
+
+    for r in running {
+        let elapsed = r
+            .claimed_at
+            .or(r.build.started_at)
+            .map(|x| now.saturating_sub(x))
+            .unwrap_or(0);
+        start_in += avg(&r.build.build_type)?.saturating_sub(elapsed);
+    }
+
+    for b in ahead {
+        if !b.ignore_window {
+            start_in += wait(start_in);
+        }
+        start_in += avg(&b.build_type)?;
+    }
+    start_in += wait(start_in);
+
+    Some(Estimate {
+        start_in,
+        finish_in: start_in + avg(build_type)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{build, entry, running};
+
+    const NOW: u64 = 1_800_000_000;
+
+    fn release() -> BuildType {
+        BuildType::Release(vec!["base".to_string()])
+    }
+
+    #[test]
+    fn failures_do_not_count_towards_the_duration() {
+        // newest first, as stored
+        let history = [
+            entry(3, "amd64", "livekit", false, 60),
+            entry(2, "amd64", "livekit", true, 3000),
+            entry(1, "amd64", "livekit", true, 1000),
+        ];
+
+        let stats = stats(&history);
+        let s = &stats["amd64/livekit"];
+        assert_eq!((s.builds, s.successes), (3, 2));
+        assert_eq!(s.avg_duration_secs, Some(2000));
+    }
+
+    #[test]
+    fn phases_are_bucketed() {
+        let mut e = entry(1, "amd64", "livekit", true, 4000);
+        e.phase_durations.insert("build".to_string(), 3000);
+        e.phase_durations.insert("upload".to_string(), 30);
+        let mut f = entry(2, "amd64", "livekit", false, 100);
+        f.phase_durations.insert("build".to_string(), 20000);
+
+        let stats = stats(&[f, e]);
+        let build = &stats["amd64/livekit"].phases["build"];
+        assert_eq!(
+            (build.count, build.avg_secs, build.max_secs),
+            (2, 11500, 20000)
+        );
+        assert_eq!(build.histogram, [0, 0, 0, 1, 0, 1]);
+        assert_eq!(stats["amd64/livekit"].phases["upload"].histogram[0], 1);
+    }
+
+    #[test]
+    fn no_estimate_without_history() {
+        let stats = stats(&[]);
+
+        assert_eq!(
+            estimate(&stats, "amd64", &[], &[], &BuildType::Livekit, None, NOW),
+            None
+        );
+    }
+
+    #[test]
+    fn no_estimate_for_a_first_build() {
+        // livekits were built, but never a release
+        let stats = stats(&[entry(1, "amd64", "livekit", true, 3600)]);
+
+        assert_eq!(
+            estimate(&stats, "amd64", &[], &[], &release(), None, NOW),
+            None
+        );
+        // nor while one is ahead in the queue
+        assert_eq!(
+            estimate(
+                &stats,
+                "amd64",
+                &[],
+                &[build(2, "amd64", release())],
+                &BuildType::Livekit,
+                None,
+                NOW
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn estimate_behind_running_and_queued() {
+        let stats = stats(&[
+            entry(2, "amd64", "release", true, 7200),
+            entry(1, "amd64", "livekit", true, 3600),
+        ]);
+        // half through a livekit, then a release ahead
+        let running = [running(
+            build(3, "amd64", BuildType::Livekit),
+            "w1",
+            NOW - 1800,
+        )];
+        let ahead = [build(4, "amd64", release())];
+
+        assert_eq!(
+            estimate(
+                &stats,
+                "amd64",
+                &running,
+                &ahead,
+                &BuildType::Livekit,
+                None,
+                NOW
+            ),
+            Some(Estimate {
+                start_in: 1800 + 7200,
+                finish_in: 1800 + 7200 + 3600,
+            })
+        );
+        // an empty queue starts now
+        assert_eq!(
+            estimate(&stats, "amd64", &[], &[], &release(), None, NOW),
+            Some(Estimate {
+                start_in: 0,
+                finish_in: 7200,
+            })
+        );
+    }
+
+    #[test]
+    fn overdue_builds_are_expected_to_end_now() {
+        let stats = stats(&[entry(1, "amd64", "livekit", true, 3600)]);
+        let running = [running(
+            build(2, "amd64", BuildType::Livekit),
+            "w1",
+            NOW - 5000,
+        )];
+
+        assert_eq!(
+            estimate(
+                &stats,
+                "amd64",
+                &running,
+                &[],
+                &BuildType::Livekit,
+                None,
+                NOW
+            ),
+            Some(Estimate {
+                start_in: 0,
+                finish_in: 3600,
+            })
+        );
+    }
+}
@@ -0,0 +1,306 @@
+//! Builds failing in a row, for breakages nobody notices among the nightly
+//! failures. The failures since the last success are counted per arch, type
+//! and channel, and once they reach `shipit_failure_streak` (default 3, 0
+//! for never) the alert chats are told and scheduled builds of it, the ones
+//! requested over HTTP, are refused until an admin runs `/unpause`. Builds
+//! requested from a chat are still queued, to try a fix. A success resets
+//! the count, but not the pause.
+
+use std::collections::BTreeSet;
+
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alert,
+    db::{Channel, Db, HistoryEntry},
+    lang::{Lang, Msg},
+    AppState,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Streak {
+    /// Since the last success.
+    pub failures: u64,
+    /// Of the latest failure, if the worker recognized it.
+    pub class: Option<String>,
+    /// Scheduled builds are refused, until `/unpause`.
+    pub paused: bool,
+}
+
+/// Count `h` in the streak of its arch, type and channel, and pause it and
+/// tell the alert chats once it is long enough.
+pub async fn record(db: &mut Db, state: &AppState, h: &HistoryEntry) -> eyre::Result<()> {
+    // a repush tells nothing about the build
+    if state.failure_streak == 0 || h.build_type().is_none() {
+        return Ok(());
+    }
+
+    let streak = db.streak(&h.arch, &h.build_type, h.channel).await?;
+    if h.success {
+        match streak {
+            Some(s) if s.paused => {
+                let s = Streak {
+                    failures: 0,
+                    class: None,
+                    paused: true,
+                };
+                db.set_streak(&h.arch, &h.build_type, h.channel, &s).await?;
+            }
+            Some(_) => db.clear_streak(&h.arch, &h.build_type, h.channel).await?,
+            None => {}
+        }
+        return Ok(());
+    }
+
+    let mut streak = streak.unwrap_or_default();
+    streak.failures += 1;
+    streak.class = h.failure.as_ref().map(|x| x.class.clone());
+    let escalate = !streak.paused && streak.failures >= state.failure_streak;
+    streak.paused |= escalate;
+    db.set_streak(&h.arch, &h.build_type, h.channel, &streak)
+        .await?;
+    if !escalate {
+        return Ok(());
+    }
+
+    let class = streak
+        .class
+        .as_deref()
+        .map(|x| x.replace('_', " "))
+        .unwrap_or_else(|| "?".to_string());
+    let last_success = db
+        .last_success(&h.arch, &h.build_type)
+        .await?
+        .and_then(|x| Local.timestamp_opt(x as i64, 0).single())
+        .map(|x| x.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "-".to_string());
+    alert::notify(
+        db,
+        state,
+        None,
+        Msg::FailureStreak,
+        &[
+            ("arch", &h.arch),
+            ("channel", &h.channel),
+            ("build", &h.build_type),
+            ("count", &streak.failures),
+            ("class", &class),
+            ("last_success", &last_success),
+        ],
+    )
+    .await
+}
+
+/// The paused arches, types and channels.
+pub async fn paused(db: &mut Db) -> eyre::Result<BTreeSet<(String, String, Channel)>> {
+    Ok(db
+        .streaks()
+        .await?
+        .into_iter()
+        .filter(|(_, s)| s.paused)
+        .map(|(k, _)| k)
+        .collect())
+}
+
+/// Take scheduled builds of `build_type` on `arch` again, in `channel` or
+/// every channel, and start counting anew. Returns the channels that were
+/// paused.
+pub async fn unpause(
+    db: &mut Db,
+    arch: &str,
+    build_type: &str,
+    channel: Option<Channel>,
+) -> eyre::Result<Vec<Channel>> {
+    let mut unpaused = vec![];
+    for (a, t, c) in paused(db).await? {
+        if a == arch && t == build_type && channel.is_none_or(|x| x == c) {
+            db.clear_streak(&a, &t, c).await?;
+            unpaused.push(c);
+        }
+    }
+
+    Ok(unpaused)
+}
+
+/// One line per paused arch, type and channel, for `/unpause` alone.
+pub async fn render(db: &mut Db, lang: Lang) -> eyre::Result<String> {
+    let streaks = db.streaks().await?;
+    let mut lines = vec![];
+    for ((arch, build_type, channel), s) in streaks.iter().filter(|(_, s)| s.paused) {
+        lines.push(lang.tr(
+            Msg::PausedLine,
+            &[
+                ("arch", arch),
+                ("channel", channel),
+                ("build", build_type),
+                ("count", &s.failures),
+            ],
+        ));
+    }
+
+    if lines.is_empty() {
+        return Ok(lang.text(Msg::PausedNone));
+    }
+    lines.insert(0, lang.text(Msg::PausedHeader));
+
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use shipit_common::Failure;
+
+    use super::*;
+    use crate::testing::{entry, redis, ADMIN};
+
+    /// The alert chat of the tests.
+    const ALERTS: i64 = 99;
+
+    fn failed(id: i64, channel: Channel) -> HistoryEntry {
+        let mut h = entry(id, "amd64", "livekit", false, 600);
+        h.channel = channel;
+        h.failure = Some(Failure {
+            class: "mirror_timeout".to_string(),
+            summary: "Timed out fetching from the mirror".to_string(),
+            excerpt: String::new(),
+            line: None,
+        });
+
+        h
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn the_alert_chats_are_told_once_the_streak_is_long_enough() {
+        let redis = redis().await;
+        let server = redis.server_with(|s| s.admin_chat = Some(ALERTS)).await;
+        let mut db = redis.db().await;
+        let at = 1_700_000_000;
+        db.set_last_success("amd64", "livekit", at).await.unwrap();
+
+        for id in 1..=2 {
+            record(&mut db, &server.state, &failed(id, Channel::Nightly))
+                .await
+                .unwrap();
+        }
+        assert!(paused(&mut db).await.unwrap().is_empty());
+        assert!(db.pop_outbox().await.unwrap().is_none());
+
+        record(&mut db, &server.state, &failed(3, Channel::Nightly))
+            .await
+            .unwrap();
+        let n = db.pop_outbox().await.unwrap().unwrap();
+        assert_eq!(n.chat, ALERTS);
+        let date = Local
+            .timestamp_opt(at as i64, 0)
+            .unwrap()
+            .format("%Y-%m-%d");
+        assert_eq!(
+            n.text,
+            format!(
+                "🚨 amd64 nightly livekit has failed 3 times in a row; latest failure class: \
+                 mirror timeout; last success {date}. Scheduled builds of it are paused until \
+                 /unpause amd64 livekit."
+            )
+        );
+        assert_eq!(
+            paused(&mut db).await.unwrap(),
+            BTreeSet::from([("amd64".to_string(), "livekit".to_string(), Channel::Nightly)])
+        );
+
+        // told once, not at every failure after
+        record(&mut db, &server.state, &failed(4, Channel::Nightly))
+            .await
+            .unwrap();
+        assert!(db.pop_outbox().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_success_resets_the_count_but_not_the_pause() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        let success = entry(9, "amd64", "livekit", true, 600);
+
+        for id in 1..=2 {
+            record(&mut db, &server.state, &failed(id, Channel::Release))
+                .await
+                .unwrap();
+        }
+        record(&mut db, &server.state, &success).await.unwrap();
+        let streak = db.streak("amd64", "livekit", Channel::Release).await;
+        assert!(streak.unwrap().is_none());
+
+        for id in 3..=5 {
+            record(&mut db, &server.state, &failed(id, Channel::Release))
+                .await
+                .unwrap();
+        }
+        record(&mut db, &server.state, &success).await.unwrap();
+        let streak = db.streak("amd64", "livekit", Channel::Release).await;
+        let streak = streak.unwrap().unwrap();
+        assert_eq!(streak.failures, 0);
+        assert!(streak.paused);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn unpause_takes_the_channel_asked_for_or_all() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        for id in 1..=3 {
+            for channel in [Channel::Release, Channel::Nightly] {
+                record(&mut db, &server.state, &failed(id, channel))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(
+            server.ask(ADMIN, "/unpause").await,
+            "Paused scheduled builds:\n\
+             amd64 release livekit, 3 failures in a row\n\
+             amd64 nightly livekit, 3 failures in a row"
+        );
+        assert_eq!(
+            server.ask(ADMIN, "/unpause amd64 livekit nightly").await,
+            "Scheduled livekit builds of amd64 are taken again: nightly"
+        );
+        assert_eq!(
+            render(&mut db, Lang::En).await.unwrap(),
+            "Paused scheduled builds:\namd64 release livekit, 3 failures in a row"
+        );
+        assert_eq!(
+            unpause(&mut db, "amd64", "livekit", None).await.unwrap(),
+            [Channel::Release]
+        );
+        assert_eq!(
+            server.ask(ADMIN, "/unpause amd64 livekit").await,
+            "Scheduled livekit builds of amd64 are not paused."
+        );
+        assert_eq!(
+            render(&mut db, Lang::En).await.unwrap(),
+            "No scheduled builds are paused."
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Docker"]
+    async fn a_chat_still_queues_a_paused_build() {
+        let redis = redis().await;
+        let server = redis.server().await;
+        let mut db = redis.db().await;
+        for id in 1..=3 {
+            record(&mut db, &server.state, &failed(id, Channel::Release))
+                .await
+                .unwrap();
+        }
+
+        let reply = server.ask(ADMIN, "/livekit amd64").await;
+        assert!(reply.contains("Queued #"), "{reply}");
+        assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 1);
+    }
+}
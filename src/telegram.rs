@@ -0,0 +1,556 @@
+//! The one task talking to Telegram. Command replies and the outbox hand
+//! their messages to it, so all of them share the rate limits: Telegram
+//! answers 429 beyond about 30 messages a second overall, one a second to
+//! a chat and 20 a minute to a group. What it talks to is a [`Transport`],
+//! the Bot API, or a recorder in the tests.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use teloxide::{
+    payloads::AnswerCallbackQuerySetters,
+    payloads::{
+        EditMessageReplyMarkupSetters, EditMessageTextSetters, SendDocumentSetters,
+        SendMessageSetters,
+    },
+    requests::{Requester, ResponseResult},
+    types::{
+        ChatId, ChatMemberKind, ChatPermissions, InlineKeyboardMarkup, InputFile, MessageEntity,
+        MessageId, ParseMode,
+    },
+    Bot, RequestError,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep_until, Instant},
+};
+use tracing::warn;
+
+/// Longest message Telegram accepts, in UTF-16 code units.
+const MESSAGE_LIMIT: usize = 4096;
+/// Between any two messages.
+const GLOBAL_INTERVAL: Duration = Duration::from_millis(40);
+/// Between two messages to the same private chat.
+const CHAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Between two messages to the same group.
+const GROUP_INTERVAL: Duration = Duration::from_secs(3);
+/// Network failures and 429s are retried this many times before the error
+/// is handed back.
+const MAX_RETRIES: u32 = 3;
+
+pub enum Payload {
+    /// Sent as a document instead if too long.
+    Html {
+        text: String,
+        buttons: Option<InlineKeyboardMarkup>,
+    },
+    Document {
+        name: String,
+        data: Vec<u8>,
+        caption: String,
+    },
+    /// A message sent before gets `buttons`, and `text` with its entities
+    /// if given.
+    Edit {
+        message: MessageId,
+        text: Option<(String, Vec<MessageEntity>)>,
+        buttons: InlineKeyboardMarkup,
+    },
+    /// A message sent before gets `text`, already HTML, and `buttons`.
+    EditHtml {
+        message: MessageId,
+        text: String,
+        buttons: Option<InlineKeyboardMarkup>,
+    },
+    Delete {
+        message: MessageId,
+    },
+}
+
+/// Where [`Sender`] delivers messages to.
+pub trait Transport: Send + Sync + 'static {
+    /// Send or change a message in `chat`, returning its id.
+    fn request<'a>(&'a self, chat: ChatId, payload: &'a Payload) -> Reply<'a, MessageId>;
+
+    /// See [`Telegram::can_pin`].
+    fn can_pin(&self, chat: ChatId) -> Reply<'_, bool>;
+
+    /// Answer the press of a button with `text`, as an alert if `alert`.
+    fn answer_callback(&self, id: String, text: Option<String>, alert: bool) -> Reply<'_, ()>;
+}
+
+pub type Reply<'a, T> = Pin<Box<dyn Future<Output = ResponseResult<T>> + Send + 'a>>;
+
+struct Outgoing {
+    chat: ChatId,
+    payload: Payload,
+    done: oneshot::Sender<ResponseResult<MessageId>>,
+}
+
+/// Handle to queue messages with, see [`channel`].
+#[derive(Clone)]
+pub struct Telegram {
+    tx: mpsc::UnboundedSender<Outgoing>,
+    depth: Arc<AtomicUsize>,
+    /// For the requests sending nothing, which need no queue.
+    transport: Arc<dyn Transport>,
+}
+
+/// The outcome of a queued message, the id of the message sent or edited.
+/// It is sent whether or not this is awaited.
+pub struct Delivery(oneshot::Receiver<ResponseResult<MessageId>>);
+
+impl Future for Delivery {
+    type Output = ResponseResult<MessageId>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|x| {
+            x.unwrap_or_else(|_| Err(io::Error::other("the Telegram sender stopped").into()))
+        })
+    }
+}
+
+impl Telegram {
+    fn queue(&self, chat: ChatId, payload: Payload) -> Delivery {
+        let (done, rx) = oneshot::channel();
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.tx.send(Outgoing {
+            chat,
+            payload,
+            done,
+        }) {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            let _ =
+                e.0.done
+                    .send(Err(io::Error::other("the Telegram sender stopped").into()));
+        }
+
+        Delivery(rx)
+    }
+
+    /// Send a message that is already HTML. If it does not fit in a single
+    /// message, it is uploaded as a text document instead of being
+    /// truncated.
+    pub fn send_html(&self, chat: ChatId, text: String) -> Delivery {
+        self.queue(
+            chat,
+            Payload::Html {
+                text,
+                buttons: None,
+            },
+        )
+    }
+
+    /// [`Telegram::send_html`] with inline buttons under the message.
+    pub fn send_buttons(
+        &self,
+        chat: ChatId,
+        text: String,
+        buttons: InlineKeyboardMarkup,
+    ) -> Delivery {
+        self.queue(
+            chat,
+            Payload::Html {
+                text,
+                buttons: Some(buttons),
+            },
+        )
+    }
+
+    /// Replace the buttons of `message`, and its text if given. The text is
+    /// plain, formatted by `entities` like the text Telegram handed back.
+    pub fn edit(
+        &self,
+        chat: ChatId,
+        message: MessageId,
+        text: Option<(String, Vec<MessageEntity>)>,
+        buttons: InlineKeyboardMarkup,
+    ) -> Delivery {
+        self.queue(
+            chat,
+            Payload::Edit {
+                message,
+                text,
+                buttons,
+            },
+        )
+    }
+
+    /// Replace the text of `message` with `text`, already HTML, and its
+    /// buttons with `buttons`. See [`fits`] for what may be edited in.
+    pub fn edit_html(
+        &self,
+        chat: ChatId,
+        message: MessageId,
+        text: String,
+        buttons: Option<InlineKeyboardMarkup>,
+    ) -> Delivery {
+        self.queue(
+            chat,
+            Payload::EditHtml {
+                message,
+                text,
+                buttons,
+            },
+        )
+    }
+
+    pub fn send_document(
+        &self,
+        chat: ChatId,
+        name: String,
+        data: Vec<u8>,
+        caption: String,
+    ) -> Delivery {
+        self.queue(
+            chat,
+            Payload::Document {
+                name,
+                data,
+                caption,
+            },
+        )
+    }
+
+    pub fn delete(&self, chat: ChatId, message: MessageId) -> Delivery {
+        self.queue(chat, Payload::Delete { message })
+    }
+
+    /// Whether the bot may pin messages in `chat`: always in a private
+    /// chat, as its owner or an admin allowed to, or in a group letting
+    /// every member pin.
+    pub async fn can_pin(&self, chat: ChatId) -> ResponseResult<bool> {
+        if chat.is_user() {
+            return Ok(true);
+        }
+
+        self.transport.can_pin(chat).await
+    }
+
+    /// Answer the press of a button, with `text` shown to the one who
+    /// pressed it, as an alert if `alert`.
+    pub async fn answer_callback(
+        &self,
+        id: String,
+        text: Option<String>,
+        alert: bool,
+    ) -> ResponseResult<()> {
+        self.transport.answer_callback(id, text, alert).await
+    }
+
+    /// Messages waiting to be sent.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether `text` fits in a single message. Longer messages are sent as a
+/// document, and cannot be edited into a message.
+pub fn fits(text: &str) -> bool {
+    text.encode_utf16().count() <= MESSAGE_LIMIT
+}
+
+/// The sender task, run with [`Sender::run`].
+pub struct Sender {
+    transport: Arc<dyn Transport>,
+    rx: mpsc::UnboundedReceiver<Outgoing>,
+    depth: Arc<AtomicUsize>,
+    pending: VecDeque<Outgoing>,
+    /// When the next message may go out at all.
+    next: Instant,
+    /// When the next message may go to a chat.
+    chats: HashMap<ChatId, Instant>,
+}
+
+pub fn channel(transport: impl Transport) -> (Telegram, Sender) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let depth = Arc::new(AtomicUsize::new(0));
+    let transport: Arc<dyn Transport> = Arc::new(transport);
+
+    (
+        Telegram {
+            tx,
+            depth: depth.clone(),
+            transport: transport.clone(),
+        },
+        Sender {
+            transport,
+            rx,
+            depth,
+            pending: VecDeque::new(),
+            next: Instant::now(),
+            chats: HashMap::new(),
+        },
+    )
+}
+
+impl Sender {
+    pub async fn run(mut self) {
+        loop {
+            while let Ok(o) = self.rx.try_recv() {
+                self.pending.push_back(o);
+            }
+
+            // the oldest message whose chat may be sent to, so one busy
+            // chat does not hold up the others
+            let now = Instant::now();
+            let ready = self
+                .pending
+                .iter()
+                .position(|x| self.chats.get(&x.chat).is_none_or(|t| *t <= now));
+
+            let Some(i) = ready else {
+                let wake = self.chats.values().copied().filter(|t| *t > now).min();
+                tokio::select! {
+                    o = self.rx.recv() => match o {
+                        Some(o) => self.pending.push_back(o),
+                        None if self.pending.is_empty() => return,
+                        None => {}
+                    },
+                    _ = sleep_until(wake.unwrap_or(now)), if wake.is_some() => {}
+                }
+                continue;
+            };
+
+            let o = self.pending.remove(i).unwrap();
+            let res = self.deliver(&o).await;
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            let _ = o.done.send(res);
+
+            let now = Instant::now();
+            self.chats.retain(|_, t| *t > now);
+        }
+    }
+
+    /// Send `o`, retrying 429s and network failures.
+    async fn deliver(&mut self, o: &Outgoing) -> ResponseResult<MessageId> {
+        let mut attempt = 0;
+        loop {
+            sleep_until(self.next).await;
+
+            let res = self.transport.request(o.chat, &o.payload).await;
+            let now = Instant::now();
+            self.next = now + GLOBAL_INTERVAL;
+            self.chats.insert(
+                o.chat,
+                now + if o.chat.0 < 0 {
+                    GROUP_INTERVAL
+                } else {
+                    CHAT_INTERVAL
+                },
+            );
+
+            let delay = match res {
+                Err(RequestError::RetryAfter(d)) => {
+                    warn!("Telegram asks to wait {}s", d.as_secs());
+                    // a flood limit applies to the whole bot
+                    self.next = now + d;
+                    d
+                }
+                Err(RequestError::Network(ref e)) => {
+                    warn!("Failed to reach Telegram (attempt {}): {e}", attempt + 1);
+                    Duration::from_secs(1 << attempt)
+                }
+                res => return res,
+            };
+
+            attempt += 1;
+            if attempt > MAX_RETRIES {
+                return res;
+            }
+            self.next = self.next.max(now + delay);
+        }
+    }
+}
+
+impl Transport for Bot {
+    fn request<'a>(&'a self, chat: ChatId, payload: &'a Payload) -> Reply<'a, MessageId> {
+        Box::pin(async move {
+            let sent = match *payload {
+                Payload::Html {
+                    ref text,
+                    ref buttons,
+                } if fits(text) => {
+                    let mut req = self.send_message(chat, text).parse_mode(ParseMode::Html);
+                    if let Some(b) = buttons {
+                        req = req.reply_markup(b.clone());
+                    }
+                    req.await?
+                }
+                Payload::Html {
+                    ref text,
+                    ref buttons,
+                } => {
+                    let mut req = self
+                        .send_document(
+                            chat,
+                            InputFile::memory(text.clone()).file_name("message.txt"),
+                        )
+                        .caption("Message too long, sent as attachment.");
+                    if let Some(b) = buttons {
+                        req = req.reply_markup(b.clone());
+                    }
+                    req.await?
+                }
+                Payload::Document {
+                    ref name,
+                    ref data,
+                    ref caption,
+                } => {
+                    self.send_document(
+                        chat,
+                        InputFile::memory(data.clone()).file_name(name.clone()),
+                    )
+                    .caption(caption)
+                    .await?
+                }
+                Payload::Edit {
+                    message,
+                    text: Some((ref text, ref entities)),
+                    ref buttons,
+                } => {
+                    self.edit_message_text(chat, message, text)
+                        .entities(entities.clone())
+                        .reply_markup(buttons.clone())
+                        .await?
+                }
+                Payload::Edit {
+                    message,
+                    text: None,
+                    ref buttons,
+                } => {
+                    self.edit_message_reply_markup(chat, message)
+                        .reply_markup(buttons.clone())
+                        .await?
+                }
+                Payload::EditHtml {
+                    message,
+                    ref text,
+                    ref buttons,
+                } => {
+                    let mut req = self
+                        .edit_message_text(chat, message, text)
+                        .parse_mode(ParseMode::Html);
+                    if let Some(b) = buttons {
+                        req = req.reply_markup(b.clone());
+                    }
+                    req.await?
+                }
+                Payload::Delete { message } => {
+                    self.delete_message(chat, message).await?;
+                    return Ok(message);
+                }
+            };
+
+            Ok(sent.id)
+        })
+    }
+
+    fn can_pin(&self, chat: ChatId) -> Reply<'_, bool> {
+        Box::pin(async move {
+            let me = self.get_me().await?;
+            let member = self.get_chat_member(chat, me.id).await?;
+            Ok(match member.kind {
+                ChatMemberKind::Owner(_) => true,
+                ChatMemberKind::Administrator(a) => a.can_pin_messages,
+                ChatMemberKind::Restricted(r) => r.can_pin_messages,
+                ChatMemberKind::Member => self
+                    .get_chat(chat)
+                    .await?
+                    .permissions()
+                    .is_some_and(|p| p.contains(ChatPermissions::PIN_MESSAGES)),
+                ChatMemberKind::Left | ChatMemberKind::Banned(_) => false,
+            })
+        })
+    }
+
+    fn answer_callback(&self, id: String, text: Option<String>, alert: bool) -> Reply<'_, ()> {
+        Box::pin(async move {
+            let mut req = self.answer_callback_query(id).show_alert(alert);
+            if let Some(text) = text {
+                req = req.text(text);
+            }
+            req.await?;
+
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::bot::send_text;
+
+    /// The text of every message sent, as handed to Telegram.
+    #[derive(Clone, Default)]
+    struct Recording(Arc<Mutex<Vec<String>>>);
+
+    impl Transport for Recording {
+        fn request<'a>(&'a self, _: ChatId, payload: &'a Payload) -> Reply<'a, MessageId> {
+            if let Payload::Html { text, .. } = payload {
+                self.0.lock().unwrap().push(text.clone());
+            }
+            Box::pin(async { Ok(MessageId(1)) })
+        }
+
+        fn can_pin(&self, _: ChatId) -> Reply<'_, bool> {
+            Box::pin(async { Ok(true) })
+        }
+
+        fn answer_callback(&self, _: String, _: Option<String>, _: bool) -> Reply<'_, ()> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// What Telegram got for `text` sent with [`send_text`].
+    async fn sent(text: &str) -> String {
+        let recording = Recording::default();
+        let (telegram, sender) = channel(recording.clone());
+        tokio::spawn(sender.run());
+
+        send_text(&telegram, ChatId(1), text).await.unwrap();
+        let sent = recording.0.lock().unwrap().pop().unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn plain_text_cannot_break_the_markup() {
+        assert_eq!(
+            sent("Failed: <stdin> & <b>bold</b>").await,
+            "Failed: &lt;stdin&gt; &amp; &lt;b&gt;bold&lt;/b&gt;"
+        );
+    }
+
+    #[tokio::test]
+    async fn long_text_is_not_truncated() {
+        let text = "x".repeat(MESSAGE_LIMIT * 2);
+        let sent = sent(&text).await;
+
+        assert_eq!(sent, text);
+        assert!(!fits(&sent));
+    }
+
+    #[test]
+    fn the_limit_is_in_utf16_units() {
+        assert!(fits(&"x".repeat(MESSAGE_LIMIT)));
+        assert!(!fits(&"x".repeat(MESSAGE_LIMIT + 1)));
+        // two units each
+        assert!(fits(&"🚀".repeat(MESSAGE_LIMIT / 2)));
+        assert!(!fits(&"🚀".repeat(MESSAGE_LIMIT / 2 + 1)));
+        // one unit each, three bytes in UTF-8
+        assert!(fits(&"构".repeat(MESSAGE_LIMIT)));
+    }
+}
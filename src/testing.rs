@@ -0,0 +1,209 @@
+//! Records for the tests, with only what a test is about set and the rest
+//! as an old record without it would read, and a server to send commands
+//! to.
+
+use std::{
+    sync::{Arc, Mutex, Once},
+    time::Duration,
+};
+
+use serde_json::json;
+use teloxide::{
+    types::{ChatId, Message, MessageId},
+    utils::command::BotCommands,
+};
+use testcontainers_modules::{
+    redis::{Redis, REDIS_PORT},
+    testcontainers::{runners::AsyncRunner, ContainerAsync, ImageExt},
+};
+
+use crate::{
+    bot::{answer, Command},
+    db::{Build, BuildType, Db, HistoryEntry, RunningBuild},
+    telegram::{self, Payload, Reply, Transport},
+    AppState,
+};
+
+/// The chat of the admin, who is also the one requesting builds.
+pub const ADMIN: i64 = 10;
+
+/// A scratch Redis in a container, removed when dropped. Tests using it
+/// need Docker, so they are ignored unless run with `--include-ignored`.
+pub struct Scratch {
+    _container: ContainerAsync<Redis>,
+    pub url: String,
+}
+
+pub async fn redis() -> Scratch {
+    let container = Redis::default()
+        .with_tag("7.2")
+        .start()
+        .await
+        .expect("starting Redis, is Docker running?");
+    let url = format!(
+        "redis://{}:{}",
+        container.get_host().await.unwrap(),
+        container.get_host_port_ipv4(REDIS_PORT).await.unwrap()
+    );
+
+    Scratch {
+        _container: container,
+        url,
+    }
+}
+
+impl Scratch {
+    /// A connection of its own.
+    pub async fn db(&self) -> Db {
+        Db::new(&self.url, 100).await.unwrap()
+    }
+
+    /// A server on this Redis as the environment sets it up with only the
+    /// secret and [`ADMIN`], Telegram replaced by a recorder.
+    pub async fn server(&self) -> Bot {
+        self.server_with(|_| {}).await
+    }
+
+    /// [`Scratch::server`] with the settings `f` changes.
+    pub async fn server_with(&self, f: impl FnOnce(&mut AppState)) -> Bot {
+        static SET: Once = Once::new();
+        SET.call_once(|| {
+            std::env::set_var("shipit_secret", "worker-secret");
+            std::env::set_var("shipit_admins", ADMIN.to_string());
+            // no posts to edit while building
+            std::env::set_var("shipit_progress_interval", "0");
+        });
+
+        let recorder = Recorder::default();
+        let (telegram, sender) = telegram::channel(recorder.clone());
+        tokio::spawn(sender.run());
+        let mut state = AppState::from_env(telegram, self.db().await).unwrap();
+        f(&mut state);
+
+        Bot {
+            state: Arc::new(state),
+            recorder,
+        }
+    }
+}
+
+/// What Telegram got: the text of each message, or the caption of a
+/// document followed by its content, and the chat it went to.
+#[derive(Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<(i64, String)>>>);
+
+impl Transport for Recorder {
+    fn request<'a>(&'a self, chat: ChatId, payload: &'a Payload) -> Reply<'a, MessageId> {
+        let text = match payload {
+            Payload::Html { text, .. } | Payload::EditHtml { text, .. } => text.clone(),
+            Payload::Edit {
+                text: Some((text, _)),
+                ..
+            } => text.clone(),
+            Payload::Document { caption, data, .. } => {
+                format!("{caption}\n{}", String::from_utf8_lossy(data))
+            }
+            Payload::Edit { text: None, .. } | Payload::Delete { .. } => String::new(),
+        };
+        let mut sent = self.0.lock().unwrap();
+        sent.push((chat.0, text));
+        let id = MessageId(sent.len() as i32);
+
+        Box::pin(async move { Ok(id) })
+    }
+
+    fn can_pin(&self, _: ChatId) -> Reply<'_, bool> {
+        Box::pin(async { Ok(true) })
+    }
+
+    fn answer_callback(&self, _: String, _: Option<String>, _: bool) -> Reply<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The server of [`Scratch::server`], to talk to as a chat would.
+pub struct Bot {
+    pub state: Arc<AppState>,
+    pub recorder: Recorder,
+}
+
+impl Bot {
+    /// The replies to `text` sent by `chat`, a private chat with its user,
+    /// one per line, once delivered. Only [`ADMIN`] is logged in.
+    pub async fn ask(&self, chat: i64, text: &str) -> String {
+        let mut db = self.state.db().await;
+        db.set_login_verified(ADMIN, 3600).await.unwrap();
+        drop(db);
+
+        let msg: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 1_700_000_000,
+            "chat": {"id": chat, "type": "private", "first_name": "Test"},
+            "from": {"id": chat, "is_bot": false, "first_name": "Test"},
+            "text": text,
+        }))
+        .unwrap();
+        let cmd = Command::parse(text, "shipit_bot").unwrap();
+        let before = self.recorder.0.lock().unwrap().len();
+        answer(self.state.telegram.clone(), msg, cmd, self.state.clone())
+            .await
+            .unwrap();
+        while self.state.telegram.depth() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let sent = self.recorder.0.lock().unwrap();
+        sent[before..]
+            .iter()
+            .filter(|(c, _)| *c == chat)
+            .map(|(_, text)| text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+pub fn build(id: i64, arch: &str, build_type: BuildType) -> Build {
+    let mut b: Build = serde_json::from_value(json!({
+        "id": id,
+        "requester_chat": 1,
+        "arch": arch,
+        "build_type": "Livekit",
+    }))
+    .unwrap();
+    b.build_type = build_type;
+
+    b
+}
+
+pub fn running(build: Build, worker: &str, claimed_at: u64) -> RunningBuild {
+    let mut r: RunningBuild = serde_json::from_value(json!({
+        "id": build.id,
+        "requester_chat": build.requester_chat,
+        "arch": build.arch,
+        "build_type": "Livekit",
+    }))
+    .unwrap();
+    r.build = build;
+    r.worker = Some(worker.to_string());
+    r.claimed_at = Some(claimed_at);
+
+    r
+}
+
+/// A finished build of `build_type`, e.g. `release`, taking `secs`.
+pub fn entry(id: i64, arch: &str, build_type: &str, success: bool, secs: u64) -> HistoryEntry {
+    serde_json::from_value(json!({
+        "id": id,
+        "requester_chat": 1,
+        "arch": arch,
+        "build_type": build_type,
+        "variants": null,
+        "success": success,
+        "push_success": success,
+        "log_url": null,
+        "manifest": null,
+        "finished_at": 1_700_000_000 + id as u64 * 3600,
+        "duration_secs": secs,
+    }))
+    .unwrap()
+}
@@ -0,0 +1,507 @@
+//! Per-build records for long-term analysis, beyond the gauges of
+//! `/metrics`: every finished build is appended as a line of JSON to a
+//! spool file, which is cut into segments uploaded to an S3-compatible
+//! bucket. A segment is only removed once the bucket took it, so a record
+//! may be uploaded twice but is never lost. Neither a bad configuration nor
+//! the bucket being down holds up a build: records pile up in the spool,
+//! and the backlog shows on `/healthz`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::Utc;
+use reqwest::Url;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+    time::{sleep, Instant},
+};
+use tracing::{error, info};
+
+use crate::{
+    db::{now_ms, HistoryEntry},
+    env_secs, AppState,
+};
+
+/// How often segments are cut and uploaded.
+const INTERVAL: Duration = Duration::from_secs(60);
+/// For each upload of a segment.
+const TIMEOUT: Duration = Duration::from_secs(120);
+/// Where records are appended, in the spool directory.
+const CURRENT: &str = "current.ndjson";
+/// Where cut segments wait for the upload, in the spool directory.
+const SEGMENTS: &str = "segments";
+
+pub struct Traces {
+    dir: PathBuf,
+    /// Why uploads are off, if the bucket is not configured right.
+    bucket: Result<Bucket, String>,
+    /// Cut a segment once the records are this old...
+    rotate_after: Duration,
+    /// ...or this large.
+    rotate_bytes: u64,
+    /// When the first record of the current file was appended. Held while
+    /// the file changes.
+    current: Mutex<Option<Instant>>,
+    last_error: std::sync::Mutex<Option<String>>,
+    client: reqwest::Client,
+}
+
+/// Where segments go.
+struct Bucket {
+    /// E.g. `https://s3.example.org`, the bucket is in the path.
+    endpoint: Url,
+    name: String,
+    region: String,
+    /// Of the object names, e.g. `shipit/`.
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+}
+
+/// What is not uploaded yet, for `/healthz`.
+#[derive(Debug, Serialize)]
+pub struct Backlog {
+    pub segments: usize,
+    /// Of the segments and the records not cut into one yet.
+    pub bytes: u64,
+    /// Why the last upload failed, or why uploads are off.
+    pub error: Option<String>,
+}
+
+/// A line of the spool.
+#[derive(Serialize)]
+struct Trace<'a> {
+    #[serde(flatten)]
+    entry: &'a HistoryEntry,
+    /// Of the manifest as JSON, to tell builds that shipped the same apart
+    /// without comparing the artifacts.
+    manifest_sha256: Option<String>,
+}
+
+impl Bucket {
+    /// `shipit_traces_endpoint`, `shipit_traces_bucket`,
+    /// `shipit_traces_access_key` and `shipit_traces_secret_key`, and
+    /// `shipit_traces_region` (default `us-east-1`) and
+    /// `shipit_traces_prefix` (default `shipit/`).
+    fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).map_err(|_| format!("{name} is not set"));
+
+        let endpoint = var("shipit_traces_endpoint")?;
+        let endpoint = Url::parse(&endpoint)
+            .ok()
+            .filter(|x| x.host_str().is_some())
+            .ok_or_else(|| format!("Invalid shipit_traces_endpoint {endpoint}"))?;
+
+        Ok(Self {
+            endpoint,
+            name: var("shipit_traces_bucket")?,
+            region: var("shipit_traces_region").unwrap_or_else(|_| "us-east-1".to_string()),
+            prefix: var("shipit_traces_prefix").unwrap_or_else(|_| "shipit/".to_string()),
+            access_key: var("shipit_traces_access_key")?,
+            secret_key: var("shipit_traces_secret_key")?,
+        })
+    }
+
+    /// Upload `body` as `key`, signed with AWS Signature Version 4.
+    async fn put(&self, client: &reqwest::Client, key: &str, body: Vec<u8>) -> eyre::Result<()> {
+        let path = format!(
+            "{}/{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.name),
+            uri_encode(&format!("{}{key}", self.prefix))
+        );
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{port}", self.endpoint.host_str().unwrap_or_default()),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+
+        let now = Utc::now();
+        let date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let day = now.format("%Y%m%d").to_string();
+        let payload = hex(&Sha256::digest(&body));
+        let signed = "host;x-amz-content-sha256;x-amz-date";
+        let canonical = format!(
+            "PUT\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload}\nx-amz-date:{date}\n\n{signed}\n{payload}"
+        );
+        let scope = format!("{day}/{}/s3/aws4_request", self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical.as_bytes()))
+        );
+        let key = [day.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(format!("AWS4{}", self.secret_key).into_bytes(), |k, x| {
+                hmac(&k, x.as_bytes()).to_vec()
+            });
+        let signature = hex(&hmac(&key, to_sign.as_bytes()));
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let resp = client
+            .put(url)
+            .header("x-amz-date", &date)
+            .header("x-amz-content-sha256", &payload)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed}, Signature={signature}",
+                    self.access_key
+                ),
+            )
+            .timeout(TIMEOUT)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let text = resp.text().await.unwrap_or_default();
+            eyre::bail!("{status}: {}", text.trim());
+        }
+
+        Ok(())
+    }
+}
+
+impl Traces {
+    /// On if `shipit_traces_dir` is set, the spool. Segments are cut every
+    /// `shipit_traces_rotate` seconds (default an hour) or at
+    /// `shipit_traces_rotate_mib` (default 64). See [`Bucket::from_env`] for
+    /// where they go, records are spooled even if that is not set right.
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        let Ok(dir) = std::env::var("shipit_traces_dir") else {
+            return Ok(None);
+        };
+        let rotate_mib = match std::env::var("shipit_traces_rotate_mib") {
+            Ok(x) => x.parse::<u64>()?,
+            Err(_) => 64,
+        };
+
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(dir.join(SEGMENTS))?;
+        // records left from before a restart are cut with the next ones
+        let pending = std::fs::metadata(dir.join(CURRENT)).is_ok_and(|x| x.len() > 0);
+
+        let bucket = Bucket::from_env();
+        if let Err(ref e) = bucket {
+            error!("Not uploading build traces: {e}");
+        }
+
+        Ok(Some(Self {
+            dir,
+            bucket,
+            rotate_after: Duration::from_secs(env_secs("shipit_traces_rotate", 3600)?),
+            rotate_bytes: rotate_mib << 20,
+            current: Mutex::new(pending.then(Instant::now)),
+            last_error: std::sync::Mutex::new(None),
+            client: reqwest::Client::new(),
+        }))
+    }
+
+    /// Append the record of `entry` to the spool.
+    pub async fn append(&self, entry: &HistoryEntry) -> eyre::Result<()> {
+        let manifest_sha256 = match entry.manifest {
+            Some(ref m) => Some(hex(&Sha256::digest(serde_json::to_vec(m)?))),
+            None => None,
+        };
+        let mut line = serde_json::to_vec(&Trace {
+            entry,
+            manifest_sha256,
+        })?;
+        line.push(b'\n');
+
+        let mut current = self.current.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(CURRENT))
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        current.get_or_insert_with(Instant::now);
+
+        Ok(())
+    }
+
+    /// Cut the records appended so far into a segment if they are old or
+    /// large enough.
+    async fn rotate(&self) -> eyre::Result<()> {
+        let mut current = self.current.lock().await;
+        let Some(since) = *current else {
+            return Ok(());
+        };
+        let path = self.dir.join(CURRENT);
+        let size = fs::metadata(&path).await.map(|x| x.len()).unwrap_or(0);
+        if since.elapsed() < self.rotate_after && size < self.rotate_bytes {
+            return Ok(());
+        }
+
+        if size > 0 {
+            let name = format!("{}.ndjson", now_ms());
+            fs::rename(&path, self.dir.join(SEGMENTS).join(name)).await?;
+        }
+        *current = None;
+
+        Ok(())
+    }
+
+    /// Cut segments waiting for the upload, oldest first.
+    async fn segments(&self) -> eyre::Result<Vec<PathBuf>> {
+        let mut v = vec![];
+        let mut entries = fs::read_dir(self.dir.join(SEGMENTS)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            v.push(entry.path());
+        }
+        v.sort();
+
+        Ok(v)
+    }
+
+    /// Upload the segments, oldest first, until one fails. Returns how many
+    /// were uploaded.
+    async fn upload(&self, bucket: &Bucket) -> eyre::Result<usize> {
+        let mut n = 0;
+        for path in self.segments().await? {
+            let Some(name) = path.file_name().map(|x| x.to_string_lossy().to_string()) else {
+                continue;
+            };
+            let body = fs::read(&path).await?;
+            bucket
+                .put(&self.client, &name, body)
+                .await
+                .map_err(|e| eyre::eyre!("Failed to upload {name}: {e}"))?;
+            fs::remove_file(&path).await?;
+            n += 1;
+        }
+
+        Ok(n)
+    }
+
+    pub async fn backlog(&self) -> Backlog {
+        let segments = self.segments().await.unwrap_or_default();
+        let mut bytes = size(&self.dir.join(CURRENT)).await;
+        for path in &segments {
+            bytes += size(path).await;
+        }
+
+        Backlog {
+            segments: segments.len(),
+            bytes,
+            error: match self.bucket {
+                Ok(_) => self.last_error.lock().unwrap().clone(),
+                Err(ref e) => Some(e.clone()),
+            },
+        }
+    }
+}
+
+async fn size(path: &Path) -> u64 {
+    fs::metadata(path).await.map(|x| x.len()).unwrap_or(0)
+}
+
+/// Cut and upload segments, retrying failed uploads on the next round.
+pub async fn run(state: Arc<AppState>) {
+    let Some(ref traces) = state.traces else {
+        return;
+    };
+
+    loop {
+        if let Err(e) = traces.rotate().await {
+            error!("Failed to cut a segment of build traces: {e}");
+        }
+
+        if let Ok(ref bucket) = traces.bucket {
+            let res = traces.upload(bucket).await;
+            match res {
+                Ok(0) => (),
+                Ok(n) => info!("Uploaded {n} segments of build traces"),
+                Err(ref e) => error!("{e}"),
+            }
+            *traces.last_error.lock().unwrap() = res.err().map(|e| e.to_string());
+        }
+
+        sleep(INTERVAL).await;
+    }
+}
+
+/// HMAC-SHA256 of `data` with `key`.
+fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let inner = Sha256::new()
+        .chain_update(block.map(|x| x ^ 0x36))
+        .chain_update(data)
+        .finalize();
+
+    Sha256::new()
+        .chain_update(block.map(|x| x ^ 0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Percent-encode `s` as S3 wants in paths, keeping the slashes.
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use axum::{
+        extract::{Path as UrlPath, State},
+        http::{HeaderMap, StatusCode},
+        routing::put,
+        Router,
+    };
+
+    use super::*;
+    use crate::testing::entry;
+
+    /// What a bucket got: the path, the authorization header and the body.
+    type Puts = Arc<std::sync::Mutex<Vec<(String, String, String)>>>;
+
+    /// A bucket answering with the status in the `AtomicU16`.
+    async fn bucket() -> (Url, Puts, Arc<AtomicU16>) {
+        let puts = Puts::default();
+        let status = Arc::new(AtomicU16::new(200));
+        let app = Router::new()
+            .route(
+                "/*key",
+                put(
+                    |State((puts, status)): State<(Puts, Arc<AtomicU16>)>,
+                     UrlPath(key): UrlPath<String>,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        let auth = headers["authorization"].to_str().unwrap().to_string();
+                        puts.lock().unwrap().push((key, auth, body));
+                        StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap()
+                    },
+                ),
+            )
+            .with_state((puts.clone(), status.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (url, puts, status)
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hex(&hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let key = [0xaa; 131];
+        assert_eq!(
+            hex(&hmac(
+                &key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn paths_are_encoded_but_their_slashes() {
+        assert_eq!(
+            uri_encode("shipit/1714521600000 a+b.ndjson"),
+            "shipit/1714521600000%20a%2Bb.ndjson"
+        );
+    }
+
+    #[tokio::test]
+    async fn segments_stay_until_the_bucket_takes_them() {
+        let dir = std::env::temp_dir().join(format!("shipit-traces-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(SEGMENTS)).unwrap();
+        let (endpoint, puts, status) = bucket().await;
+        let traces = Traces {
+            dir: dir.clone(),
+            bucket: Ok(Bucket {
+                endpoint,
+                name: "traces".to_string(),
+                region: "us-east-1".to_string(),
+                prefix: "shipit/".to_string(),
+                access_key: "AKID".to_string(),
+                secret_key: "secret".to_string(),
+            }),
+            rotate_after: Duration::ZERO,
+            rotate_bytes: u64::MAX,
+            current: Mutex::new(None),
+            last_error: std::sync::Mutex::new(None),
+            client: reqwest::Client::new(),
+        };
+        let Ok(ref bucket) = traces.bucket else {
+            unreachable!()
+        };
+
+        traces.rotate().await.unwrap();
+        assert_eq!(traces.backlog().await.segments, 0);
+        for id in [1, 2] {
+            traces
+                .append(&entry(id, "amd64", "livekit", true, 600))
+                .await
+                .unwrap();
+        }
+        traces.rotate().await.unwrap();
+        let backlog = traces.backlog().await;
+        assert_eq!(backlog.segments, 1);
+        assert!(backlog.bytes > 0);
+
+        status.store(503, Ordering::SeqCst);
+        let e = traces.upload(bucket).await.unwrap_err();
+        assert!(e.to_string().contains("503"), "{e}");
+        assert_eq!(traces.backlog().await.segments, 1);
+
+        status.store(200, Ordering::SeqCst);
+        assert_eq!(traces.upload(bucket).await.unwrap(), 1);
+        let backlog = traces.backlog().await;
+        assert_eq!((backlog.segments, backlog.bytes), (0, 0));
+
+        let puts = puts.lock().unwrap();
+        assert_eq!(puts.len(), 2);
+        let (key, auth, body) = &puts[1];
+        assert!(
+            key.starts_with("traces/shipit/") && key.ends_with(".ndjson"),
+            "{key}"
+        );
+        assert!(
+            auth.starts_with("AWS4-HMAC-SHA256 Credential=AKID/"),
+            "{auth}"
+        );
+        assert!(auth.contains("/us-east-1/s3/aws4_request"), "{auth}");
+        let ids = body
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap()["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [1, 2]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
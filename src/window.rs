@@ -0,0 +1,101 @@
+//! Times of day an arch takes builds, e.g. when its builder is somebody's
+//! workstation during the day. See `/window`.
+
+use std::fmt::Display;
+
+use chrono::{TimeZone, Timelike};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+const DAY_SECS: u32 = 24 * 3600;
+
+/// A daily window, in the local time of `tz`. It spans midnight if `end` is
+/// before `start`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Window {
+    /// Minutes after local midnight.
+    pub start: u32,
+    pub end: u32,
+    /// IANA time zone name, e.g. `Asia/Shanghai`.
+    pub tz: String,
+}
+
+/// `HH:MM` as minutes after midnight, `24:00` included.
+pub fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+
+    (h <= 24 && m < 60 && h * 60 + m <= 24 * 60).then_some(h * 60 + m)
+}
+
+impl Window {
+    /// `range` like `00:00-08:00`, `tz` like `Asia/Shanghai`.
+    pub fn parse(range: &str, tz: &str) -> Result<Self, String> {
+        let (start, end) = range
+            .split_once('-')
+            .and_then(|(a, b)| Some((parse_time(a)?, parse_time(b)?)))
+            .ok_or_else(|| format!("Invalid window {range:?}, expected e.g. 00:00-08:00"))?;
+        // 24:00 ends a day, it does not start one
+        let start = start % (24 * 60);
+        if start == end {
+            return Err("The window must not be empty".to_string());
+        }
+        tz.parse::<Tz>()
+            .map_err(|_| format!("Unknown time zone: {tz}"))?;
+
+        Ok(Window {
+            start,
+            end,
+            tz: tz.to_string(),
+        })
+    }
+
+    /// Seconds after local midnight at the unix time `at`.
+    fn local_secs(&self, at: u64) -> u32 {
+        let tz: Tz = self.tz.parse().unwrap_or(Tz::UTC);
+        match tz.timestamp_opt(at as i64, 0).single() {
+            Some(t) => t.num_seconds_from_midnight(),
+            None => (at % DAY_SECS as u64) as u32,
+        }
+    }
+
+    pub fn is_open(&self, at: u64) -> bool {
+        self.wait_secs(at) == 0
+    }
+
+    /// Seconds from the unix time `at` until the window opens, 0 if it is
+    /// open. Ignores daylight saving changes in between.
+    pub fn wait_secs(&self, at: u64) -> u64 {
+        let t = self.local_secs(at);
+        let (start, end) = (self.start * 60, self.end * 60);
+
+        let open = if start < end {
+            start <= t && t < end
+        } else {
+            t >= start || t < end
+        };
+        if open {
+            return 0;
+        }
+
+        ((start + DAY_SECS - t) % DAY_SECS) as u64
+    }
+
+    /// When the window opens, in its local time, e.g. `00:00`.
+    pub fn opens_at(&self) -> String {
+        format!("{:02}:{:02}", self.start / 60, self.start % 60)
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{:02}:{:02} {}",
+            self.opens_at(),
+            self.end / 60,
+            self.end % 60,
+            self.tz
+        )
+    }
+}
@@ -0,0 +1,200 @@
+//! The worker inventory behind `/workers` and `GET /api/v1/workers`: every
+//! worker that ever polled for jobs, and what it is doing now.
+
+use std::collections::BTreeMap;
+
+use eyre::bail;
+use serde::Serialize;
+
+use crate::{
+    db::{Db, Drain, WorkerRecord},
+    env_list,
+    format::{human_bytes, human_duration},
+    lang::{Lang, Msg},
+    pool::MAINLINE,
+};
+
+/// Free disk a worker needs to be handed jobs, checked by the server since
+/// only newer workers check it themselves.
+#[derive(Debug, Default)]
+pub struct DiskPolicy {
+    default: Option<u64>,
+    arches: BTreeMap<String, u64>,
+}
+
+impl DiskPolicy {
+    /// `shipit_min_free_disk` is a list of GiB, `50` for any arch and
+    /// `riscv64=80` for one. Unset means no limit.
+    pub fn from_env() -> eyre::Result<Self> {
+        let mut policy = Self::default();
+        for i in env_list("shipit_min_free_disk").unwrap_or_default() {
+            let (arch, gib) = match i.split_once('=') {
+                Some((arch, gib)) => (Some(arch), gib),
+                None => (None, i.as_str()),
+            };
+            let Ok(gib) = gib.parse::<u64>() else {
+                bail!("Invalid shipit_min_free_disk entry {i}, expected GiB or arch=GiB");
+            };
+
+            match arch {
+                Some(arch) => {
+                    policy.arches.insert(arch.to_string(), gib << 30);
+                }
+                None => policy.default = Some(gib << 30),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Bytes needed to build for `arch`, if limited.
+    pub fn min(&self, arch: &str) -> Option<u64> {
+        self.arches.get(arch).copied().or(self.default)
+    }
+}
+
+/// The field of the drain of every worker of `arch`.
+pub fn arch_drain(arch: &str) -> String {
+    format!("arch:{arch}")
+}
+
+/// The field of the drain of the worker `name`.
+pub fn worker_drain(name: &str) -> String {
+    format!("worker:{name}")
+}
+
+/// The drain keeping the worker `name` from new jobs, with its field: its
+/// own, or that of its first arch if all of `arches` drain.
+pub fn drained<'a>(
+    drains: &'a BTreeMap<String, Drain>,
+    name: &str,
+    arches: &[String],
+) -> Option<(&'a str, &'a Drain)> {
+    if let Some((target, d)) = drains.get_key_value(&worker_drain(name)) {
+        return Some((target, d));
+    }
+
+    let first = arches.first()?;
+    if !arches.iter().all(|x| drains.contains_key(&arch_drain(x))) {
+        return None;
+    }
+
+    drains
+        .get_key_value(&arch_drain(first))
+        .map(|(target, d)| (target.as_str(), d))
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerState {
+    Idle,
+    Building,
+    /// Not seen for longer than `shipit_worker_offline_after`.
+    Offline,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WorkerInfo {
+    #[serde(flatten)]
+    pub record: WorkerRecord,
+    pub state: WorkerState,
+    /// The build it is running.
+    pub build: Option<i64>,
+    /// Taking no new jobs, see `/drain`.
+    pub draining: bool,
+    /// Whether it told in a heartbeat that it knows.
+    pub drain_acked: bool,
+}
+
+/// The known workers with their state, by arch and hostname. A worker not
+/// seen for `offline_after` seconds is offline even if it claimed a build.
+pub async fn inventory(db: &mut Db, now: u64, offline_after: u64) -> eyre::Result<Vec<WorkerInfo>> {
+    let running = db.running_worker().await?;
+    let drains = db.drains().await?;
+
+    Ok(db
+        .workers()
+        .await?
+        .into_iter()
+        .map(|record| {
+            let build = running
+                .iter()
+                .find(|x| x.worker.as_ref() == Some(&record.name))
+                .map(|x| x.build.id);
+            let drain = drained(&drains, &record.name, &record.arch_names()).map(|(_, d)| d);
+            let state = if now.saturating_sub(record.last_seen) > offline_after {
+                WorkerState::Offline
+            } else if build.is_some() {
+                WorkerState::Building
+            } else {
+                WorkerState::Idle
+            };
+
+            WorkerInfo {
+                draining: drain.is_some(),
+                drain_acked: drain.is_some_and(|d| d.acked.contains_key(&record.name)),
+                record,
+                state,
+                build,
+            }
+        })
+        .collect())
+}
+
+/// One line per worker.
+pub fn render(workers: &[WorkerInfo], now: u64, lang: Lang) -> String {
+    if workers.is_empty() {
+        return lang.text(Msg::NoWorkers);
+    }
+
+    workers
+        .iter()
+        .map(|w| {
+            let r = &w.record;
+            let state = match (w.state, w.build) {
+                (WorkerState::Building, Some(id)) if w.draining => lang.tr(
+                    if w.drain_acked {
+                        Msg::WorkerDrainingAcked
+                    } else {
+                        Msg::WorkerDraining
+                    },
+                    &[("id", &id)],
+                ),
+                (WorkerState::Building, Some(id)) => lang.tr(Msg::WorkerBuilding, &[("id", &id)]),
+                (WorkerState::Offline, _) => lang.text(Msg::WorkerOffline),
+                _ if w.draining => lang.text(Msg::WorkerDrained),
+                _ => lang.text(Msg::WorkerIdle),
+            };
+            let unknown = lang.text(Msg::WorkerUnknown);
+
+            let mut line = lang.tr(
+                Msg::WorkerLine,
+                &[
+                    ("arch", &r.arch_list()),
+                    ("worker", &r.name),
+                    ("hostname", r.hostname.as_ref().unwrap_or(&unknown)),
+                    ("version", r.version.as_ref().unwrap_or(&unknown)),
+                    ("state", &state),
+                    ("ago", &human_duration(now.saturating_sub(r.last_seen))),
+                ],
+            );
+            if r.pool != MAINLINE {
+                line.push_str(&lang.tr(Msg::WorkerPool, &[("pool", &r.pool)]));
+            }
+            if let Some([a, b, c]) = r.load_avg {
+                line.push_str(
+                    &lang.tr(Msg::PongLoad, &[("load", &format!("{a:.2} {b:.2} {c:.2}"))]),
+                );
+            }
+            if let Some(free) = r.free_disk {
+                line.push_str(&lang.tr(Msg::PongFree, &[("free", &human_bytes(free))]));
+            }
+            if let Some(skew) = r.clock_skew {
+                line.push_str(&lang.tr(Msg::WorkerClock, &[("skew", &format!("{skew:+}s"))]));
+            }
+
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -0,0 +1,249 @@
+//! A server on a scratch Redis, with Telegram replaced by a recorder. The
+//! Redis runs in a container, so the tests need Docker and are ignored
+//! unless run with `--include-ignored`.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::Duration,
+};
+
+use serde_json::json;
+use shipit::{
+    db::Db,
+    telegram::{self, Payload, Reply, Telegram, Transport},
+    AppState,
+};
+use teloxide::types::{ChatId, Message, MessageId};
+use testcontainers_modules::{
+    redis::{Redis, REDIS_PORT},
+    testcontainers::{runners::AsyncRunner, ContainerAsync, ImageExt},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::Instant,
+};
+
+pub const SECRET: &str = "worker-secret";
+/// The chat of the admin, who is also the one requesting builds.
+pub const ADMIN: i64 = 10;
+
+/// A message Telegram got, the text of a message or the caption of a
+/// document.
+#[derive(Debug, Clone)]
+pub struct Sent {
+    pub chat: i64,
+    pub text: String,
+}
+
+#[derive(Clone, Default)]
+pub struct Recorder {
+    sent: Arc<Mutex<Vec<Sent>>>,
+}
+
+impl Transport for Recorder {
+    fn request<'a>(&'a self, chat: ChatId, payload: &'a Payload) -> Reply<'a, MessageId> {
+        let text = match payload {
+            Payload::Html { text, .. } | Payload::EditHtml { text, .. } => text.clone(),
+            Payload::Edit {
+                text: Some((text, _)),
+                ..
+            } => text.clone(),
+            Payload::Document { caption, .. } => caption.clone(),
+            Payload::Edit { text: None, .. } | Payload::Delete { .. } => String::new(),
+        };
+        let mut sent = self.sent.lock().unwrap();
+        sent.push(Sent { chat: chat.0, text });
+        let id = MessageId(sent.len() as i32);
+
+        Box::pin(async move { Ok(id) })
+    }
+
+    fn can_pin(&self, _: ChatId) -> Reply<'_, bool> {
+        Box::pin(async { Ok(true) })
+    }
+
+    fn answer_callback(&self, _: String, _: Option<String>, _: bool) -> Reply<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Recorder {
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// The first message to `chat` matching `f`, waiting for the outbox to
+    /// deliver it.
+    pub async fn wait_for(&self, chat: i64, f: impl Fn(&str) -> bool) -> Sent {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            if let Some(s) = self
+                .sent()
+                .into_iter()
+                .find(|x| x.chat == chat && f(&x.text))
+            {
+                return s;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "no such message to {chat}, got {:#?}",
+                self.sent()
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+pub struct Server {
+    _redis: ContainerAsync<Redis>,
+    redis_url: String,
+    pub state: Arc<AppState>,
+    pub telegram: Telegram,
+    pub recorder: Recorder,
+    /// Where the HTTP API listens, e.g. `http://127.0.0.1:4000`.
+    pub url: String,
+    pub http: reqwest::Client,
+}
+
+fn settings() {
+    static SET: Once = Once::new();
+    SET.call_once(|| {
+        std::env::set_var("shipit_secret", SECRET);
+        std::env::set_var("shipit_admins", ADMIN.to_string());
+        // no posts to edit while building
+        std::env::set_var("shipit_progress_interval", "0");
+    });
+}
+
+impl Server {
+    pub async fn start() -> Self {
+        settings();
+        let redis = Redis::default()
+            .with_tag("7.2")
+            .start()
+            .await
+            .expect("starting Redis, is Docker running?");
+        let redis_url = format!(
+            "redis://{}:{}",
+            redis.get_host().await.unwrap(),
+            redis.get_host_port_ipv4(REDIS_PORT).await.unwrap()
+        );
+
+        let recorder = Recorder::default();
+        let (telegram, sender) = telegram::channel(recorder.clone());
+        tokio::spawn(sender.run());
+        let db = Db::new(&redis_url, 100).await.unwrap();
+        let state = Arc::new(AppState::from_env(telegram.clone(), db).unwrap());
+        tokio::spawn(shipit::outbox::run(state.clone()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = shipit::router(state.clone()).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Server {
+            _redis: redis,
+            redis_url,
+            state,
+            telegram,
+            recorder,
+            url,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// A connection of its own, to look at what the server stored.
+    pub async fn db(&self) -> Db {
+        Db::new(&self.redis_url, 100).await.unwrap()
+    }
+
+    /// A connection of its own through a proxy counting its round trips.
+    pub async fn counted_db(&self) -> (Db, RoundTrips) {
+        let upstream = self.redis_url.trim_start_matches("redis://").to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let trips = RoundTrips::default();
+
+        let counter = trips.clone();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                let server = TcpStream::connect(&upstream).await.unwrap();
+                tokio::spawn(relay(client, server, counter.clone()));
+            }
+        });
+
+        (Db::new(&url, 100).await.unwrap(), trips)
+    }
+
+    /// A message to the bot from `chat`, a private chat with its user.
+    pub fn message(&self, chat: i64, text: &str) -> Message {
+        serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 1_700_000_000,
+            "chat": {"id": chat, "type": "private", "first_name": "Test"},
+            "from": {"id": chat, "is_bot": false, "first_name": "Test"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    /// `path` of the worker API, authenticated with the mainline secret.
+    pub fn worker(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}{path}", self.url))
+            .header("secret", SECRET)
+    }
+}
+
+/// Requests sent to Redis and answered, however many commands each one
+/// pipelined.
+#[derive(Clone, Default)]
+pub struct RoundTrips(Arc<AtomicUsize>);
+
+impl RoundTrips {
+    /// Those since the last call.
+    pub fn take(&self) -> usize {
+        self.0.swap(0, Ordering::SeqCst)
+    }
+}
+
+/// Pass bytes both ways, counting a round trip whenever the client sends
+/// after it got an answer.
+async fn relay(client: TcpStream, server: TcpStream, trips: RoundTrips) {
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut server_read, mut server_write) = server.into_split();
+    let answered = Arc::new(AtomicBool::new(true));
+
+    let up = {
+        let answered = answered.clone();
+        async move {
+            let mut buf = vec![0; 64 * 1024];
+            while let Ok(n @ 1..) = client_read.read(&mut buf).await {
+                if answered.swap(false, Ordering::SeqCst) {
+                    trips.0.fetch_add(1, Ordering::SeqCst);
+                }
+                if server_write.write_all(&buf[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }
+    };
+    let down = async move {
+        let mut buf = vec![0; 64 * 1024];
+        while let Ok(n @ 1..) = server_read.read(&mut buf).await {
+            // before the client can send its next request
+            answered.store(true, Ordering::SeqCst);
+            if client_write.write_all(&buf[..n]).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    tokio::join!(up, down);
+}
@@ -0,0 +1,311 @@
+//! A build from the request in a chat to its result: queued by the command
+//! handler, claimed on `/workerisstarted`, reported on `/done`.
+
+mod common;
+
+use std::sync::Arc;
+
+use common::{Server, ADMIN};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use shipit::{
+    bot::{answer, Command},
+    AppState,
+};
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn livekit_from_request_to_result() {
+    let server = Server::start().await;
+    let mut db = server.db().await;
+    db.set_login_verified(ADMIN, 3600).await.unwrap();
+
+    // queued
+    answer(
+        server.telegram.clone(),
+        server.message(ADMIN, "/livekit amd64"),
+        Command::Livekit("amd64".to_string()),
+        server.state.clone(),
+    )
+    .await
+    .unwrap();
+
+    let queue = db.queue("mainline", "amd64").await.unwrap();
+    assert_eq!(queue.len(), 1);
+    let id = queue[0].id;
+    assert_eq!(queue[0].requester_chat, ADMIN);
+    assert_eq!(
+        db.lifecycle(id).await.unwrap().unwrap().phase.name(),
+        "queued"
+    );
+    server
+        .recorder
+        .wait_for(ADMIN, |x| {
+            x.contains("amd64") && x.contains(&format!("#{id}"))
+        })
+        .await;
+
+    // claimed, once
+    let poll = || async {
+        let res = server
+            .worker(
+                Method::GET,
+                "/workerisstarted?arch=amd64&worker=w1&ping=true",
+            )
+            .header("accept-version", "2")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        res.json::<Value>().await.unwrap()
+    };
+    let status = poll().await;
+    assert_eq!(status["v"], 2);
+    assert_eq!(status["status"]["type"], "working");
+    assert_eq!(status["status"]["job"]["id"], id);
+    assert_eq!(status["status"]["job"]["build_type"], "Livekit");
+    assert_eq!(poll().await["status"]["type"], "pending");
+
+    assert!(db.queue("mainline", "amd64").await.unwrap().is_empty());
+    let running = db.running("amd64").await.unwrap();
+    assert_eq!(running.len(), 1);
+    assert_eq!(running[0].worker.as_deref(), Some("w1"));
+    assert_eq!(
+        db.lifecycle(id).await.unwrap().unwrap().phase.name(),
+        "claimed"
+    );
+
+    // done
+    let res = server
+        .worker(Method::POST, "/done")
+        .json(&json!({
+            "id": id,
+            "arch": "amd64",
+            "build_type": {"name": "livekit"},
+            "has_error": false,
+            "log_url": "https://buildit.aosc.io/logs/shipit-livekit-amd64.txt",
+            "push_success": true,
+            "worker": "w1",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        res.status(),
+        StatusCode::OK,
+        "{}",
+        res.text().await.unwrap()
+    );
+
+    assert!(db.running("amd64").await.unwrap().is_empty());
+    let history = db.history().await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].id, id);
+    assert!(history[0].success && history[0].push_success);
+    assert_eq!(history[0].worker.as_deref(), Some("w1"));
+    // recorded once the history has the build
+    assert_eq!(history[0].queued, ["telegram"]);
+    assert_eq!(
+        db.lifecycle(id).await.unwrap().unwrap().phase.name(),
+        "succeeded"
+    );
+
+    // the requester is told, through the outbox
+    server
+        .recorder
+        .wait_for(ADMIN, |x| {
+            x.contains(&format!("#{id}")) && x.contains("shipit-livekit-amd64.txt")
+        })
+        .await;
+
+    // a second report is refused
+    let res = server
+        .worker(Method::POST, "/done")
+        .json(&json!({
+            "id": id,
+            "arch": "amd64",
+            "build_type": {"name": "livekit"},
+            "has_error": true,
+            "log_url": null,
+            "push_success": false,
+            "worker": "w1",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(db.history().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn workers_need_the_secret() {
+    let server = Server::start().await;
+
+    let res = server
+        .http
+        .get(format!("{}/workerisstarted?arch=amd64", server.url))
+        .header("secret", "wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(server.db().await.workers().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn queueing_on_every_arch_takes_as_many_round_trips_as_on_one() {
+    let server = Server::start().await;
+    let mut db = server.db().await;
+    db.set_login_verified(ADMIN, 3600).await.unwrap();
+    // the caps are checked too
+    db.set_limits(Some(
+        &serde_json::from_value(json!({"total": 100})).unwrap(),
+    ))
+    .await
+    .unwrap();
+
+    let (counted, trips) = server.counted_db().await;
+    let state = Arc::new(AppState::from_env(server.telegram.clone(), counted).unwrap());
+    let livekit = |args: &str| {
+        answer(
+            server.telegram.clone(),
+            server.message(ADMIN, &format!("/livekit {args}")),
+            Command::Livekit(args.to_string()),
+            state.clone(),
+        )
+    };
+
+    // the chat is known from here on
+    livekit("--dry-run amd64").await.unwrap();
+    trips.take();
+
+    livekit("amd64").await.unwrap();
+    let one = trips.take();
+    assert_eq!(db.queue("mainline", "amd64").await.unwrap().len(), 1);
+
+    livekit("arm64 loongarch64 ppc64el loongson3 riscv64")
+        .await
+        .unwrap();
+    let five = trips.take();
+    assert_eq!(db.queue("mainline", "riscv64").await.unwrap().len(), 1);
+
+    assert_eq!(one, five, "round trips for one arch and for five");
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn the_feed_is_of_one_pool() {
+    let server = Server::start().await;
+    let mut db = server.db().await;
+    for (id, pool) in [(1, "mainline"), (2, "staging")] {
+        let entry = serde_json::from_value(json!({
+            "id": id,
+            "requester_chat": ADMIN,
+            "arch": "amd64",
+            "pool": pool,
+            "build_type": "livekit",
+            "variants": null,
+            "success": true,
+            "push_success": true,
+            "log_url": null,
+            "manifest": null,
+            "finished_at": 1_700_000_000 + id as u64,
+            "duration_secs": 600,
+        }))
+        .unwrap();
+        db.push_history(&entry).await.unwrap();
+    }
+
+    let feed = |query: &'static str| {
+        let url = format!("{}/feed.atom{query}", server.url);
+        let http = server.http.clone();
+        async move { http.get(url).send().await.unwrap().text().await.unwrap() }
+    };
+
+    let mainline = feed("").await;
+    assert!(mainline.contains("urn:shipit:build:1<"), "{mainline}");
+    assert!(!mainline.contains("urn:shipit:build:2<"), "{mainline}");
+
+    let staging = feed("?pool=staging").await;
+    assert!(staging.contains("<id>urn:shipit:builds:staging</id>"));
+    assert!(staging.contains("urn:shipit:build:2<"), "{staging}");
+    assert!(!staging.contains("urn:shipit:build:1<"), "{staging}");
+}
+
+#[tokio::test]
+#[ignore = "needs Docker"]
+async fn a_running_build_stops_at_its_next_heartbeat_once_cancelled() {
+    let server = Server::start().await;
+    let mut db = server.db().await;
+    db.set_login_verified(ADMIN, 3600).await.unwrap();
+    answer(
+        server.telegram.clone(),
+        server.message(ADMIN, "/livekit amd64"),
+        Command::Livekit("amd64".to_string()),
+        server.state.clone(),
+    )
+    .await
+    .unwrap();
+    let id = db.queue("mainline", "amd64").await.unwrap()[0].id;
+    server
+        .worker(Method::GET, "/workerisstarted?arch=amd64&worker=w1")
+        .header("accept-version", "2")
+        .send()
+        .await
+        .unwrap();
+    let heartbeat = || async {
+        server
+            .worker(Method::POST, "/heartbeat")
+            .json(&json!({"id": id, "arch": "amd64", "worker": "w1"}))
+            .send()
+            .await
+            .unwrap()
+            .json::<Value>()
+            .await
+            .unwrap()
+    };
+    assert_eq!(heartbeat().await["cancel"], Value::Null);
+
+    answer(
+        server.telegram.clone(),
+        server.message(ADMIN, &format!("/cancel #{id}")),
+        Command::Cancel(format!("#{id}")),
+        server.state.clone(),
+    )
+    .await
+    .unwrap();
+    server
+        .recorder
+        .wait_for(ADMIN, |x| x.contains("next heartbeat"))
+        .await;
+    assert!(db.running("amd64").await.unwrap().is_empty());
+    assert_eq!(
+        db.lifecycle(id).await.unwrap().unwrap().phase.name(),
+        "cancelled"
+    );
+    assert_eq!(heartbeat().await["cancel"], true);
+
+    // finished as it was cancelled, the result is dropped
+    let res = server
+        .worker(Method::POST, "/done")
+        .json(&json!({
+            "id": id,
+            "arch": "amd64",
+            "build_type": {"name": "livekit"},
+            "has_error": false,
+            "log_url": null,
+            "push_success": true,
+            "worker": "w1",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(db.history().await.unwrap().is_empty());
+    assert_eq!(
+        db.lifecycle(id).await.unwrap().unwrap().phase.name(),
+        "cancelled"
+    );
+}
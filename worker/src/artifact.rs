@@ -0,0 +1,95 @@
+//! Vetting files a build script left behind before they are uploaded. A
+//! buggy or malicious script could leave a symlink named like an image that
+//! points at e.g. `/etc/shadow`, or a FIFO that blocks the copy forever.
+
+use std::path::Path;
+
+use tokio::fs;
+
+/// Why `path` must not be uploaded, or `None` if it is a regular file of at
+/// most `max_bytes` that is physically below `root` (which must be
+/// canonical), following symlinks.
+pub async fn suspicious(path: &Path, root: &Path, max_bytes: u64) -> eyre::Result<Option<String>> {
+    let link = fs::symlink_metadata(path).await?;
+
+    if link.file_type().is_symlink() {
+        let Ok(target) = fs::canonicalize(path).await else {
+            return Ok(Some("dangling symlink".to_string()));
+        };
+
+        if !target.starts_with(root) {
+            return Ok(Some(format!(
+                "symlink to {} outside of {}",
+                target.display(),
+                root.display()
+            )));
+        }
+    }
+
+    let meta = fs::metadata(path).await?;
+    if !meta.is_file() {
+        return Ok(Some("not a regular file".to_string()));
+    }
+
+    if meta.len() > max_bytes {
+        return Ok(Some(format!(
+            "{} bytes, more than the limit of {max_bytes}",
+            meta.len()
+        )));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn only_regular_files_below_the_root_within_the_limit_pass() {
+        let base = std::env::temp_dir().join(format!("shipit-artifact-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("livekit");
+        std::fs::create_dir_all(root.join("iso")).unwrap();
+        std::fs::write(base.join("shadow"), "root:*:19000::::::").unwrap();
+        let root = std::fs::canonicalize(&root).unwrap();
+        let iso = root.join("iso/aosc-os_livekit_20240501_amd64.iso");
+        std::fs::write(&iso, [0; 100]).unwrap();
+        symlink(&iso, root.join("iso/latest.iso")).unwrap();
+        symlink(base.join("shadow"), root.join("iso/shadow.iso")).unwrap();
+        symlink(root.join("gone.iso"), root.join("iso/dangling.iso")).unwrap();
+
+        let check = |name: &str, max_bytes| {
+            let path = root.join("iso").join(name);
+            let root = root.clone();
+            async move { suspicious(&path, &root, max_bytes).await.unwrap() }
+        };
+        assert_eq!(check("aosc-os_livekit_20240501_amd64.iso", 100).await, None);
+        assert_eq!(check("latest.iso", 100).await, None);
+        assert_eq!(
+            check("aosc-os_livekit_20240501_amd64.iso", 99)
+                .await
+                .as_deref(),
+            Some("100 bytes, more than the limit of 99")
+        );
+        assert!(check("shadow.iso", 100)
+            .await
+            .unwrap()
+            .starts_with("symlink to "));
+        assert_eq!(
+            check("dangling.iso", 100).await.as_deref(),
+            Some("dangling symlink")
+        );
+        assert_eq!(
+            suspicious(&root.join("iso"), &root, 100)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("not a regular file")
+        );
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! The trees aosc-mklive can reuse, kept between `--incremental` livekit
+//! builds of an arch. After an incremental build succeeded they are moved
+//! out of the checkout to `livekit-cache/<arch>`, out of reach of the
+//! cleanup, and the next incremental build of the arch moves them back and
+//! tells aosc-mklive with `MKLIVE_INCREMENTAL=1`. A cache older than
+//! `livekit_cache_max_age_hours` (default 72) or made by another commit of
+//! aosc-mklive is discarded instead.
+
+use std::{path::Path, time::Duration};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::info;
+
+use crate::{joblog::JobLog, timeline::now};
+
+/// Where the caches are, one directory per arch.
+const CACHE_DIR: &str = "livekit-cache";
+/// The directories of the checkout aosc-mklive reuses.
+const REUSABLE: &[&str] = &["to-squash"];
+/// Describes a cache, in its directory.
+const META_FILE: &str = "cache.json";
+
+/// Set for aosc-mklive when it builds on a restored cache.
+pub const INCREMENTAL_ENV: (&str, &str) = ("MKLIVE_INCREMENTAL", "1");
+
+pub struct CachePolicy {
+    max_age: Duration,
+}
+
+impl CachePolicy {
+    /// `livekit_cache_max_age_hours`, default 72.
+    pub fn from_env() -> eyre::Result<Self> {
+        let hours: u64 = match std::env::var("livekit_cache_max_age_hours") {
+            Ok(x) => x.parse()?,
+            Err(_) => 72,
+        };
+
+        Ok(Self {
+            max_age: Duration::from_secs(hours * 3600),
+        })
+    }
+}
+
+/// Where a cache came from.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    /// The build that left it.
+    build_id: i64,
+    /// Of aosc-mklive, the trees depend on it.
+    commit: String,
+    /// UNIX seconds.
+    created_at: u64,
+}
+
+/// Move the cache of `arch` into `checkout`, at `commit` of aosc-mklive.
+/// Returns the build it came from, or `None` if there is none to reuse, the
+/// reason written to `log`.
+pub async fn restore(
+    policy: &CachePolicy,
+    arch: &str,
+    checkout: &Path,
+    commit: Option<&str>,
+    log: &mut JobLog,
+) -> eyre::Result<Option<i64>> {
+    let dir = Path::new(CACHE_DIR).join(arch);
+    let meta = match fs::read(dir.join(META_FILE)).await {
+        Ok(x) => serde_json::from_slice::<Meta>(&x).map_err(|_| "unreadable".to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            note(log, "No livekit cache yet, building everything").await?;
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let meta = meta.and_then(|m| {
+        if now().saturating_sub(m.created_at) > policy.max_age.as_secs() {
+            Err(format!("older than {}h", policy.max_age.as_secs() / 3600))
+        } else if commit != Some(m.commit.as_str()) {
+            Err(format!(
+                "made by aosc-mklive {}, now at {}",
+                m.commit,
+                commit.unwrap_or("an unknown commit")
+            ))
+        } else {
+            Ok(m)
+        }
+    });
+    let meta = match meta {
+        Ok(x) => x,
+        Err(reason) => {
+            fs::remove_dir_all(&dir).await?;
+            note(
+                log,
+                &format!("Discarded the livekit cache ({reason}), building everything"),
+            )
+            .await?;
+            return Ok(None);
+        }
+    };
+
+    for name in REUSABLE {
+        let (from, to) = (dir.join(name), checkout.join(name));
+        if fs::symlink_metadata(&from).await.is_err() {
+            continue;
+        }
+        if fs::symlink_metadata(&to).await.is_ok() {
+            fs::remove_dir_all(&to).await?;
+        }
+        fs::rename(&from, &to).await?;
+    }
+    fs::remove_dir_all(&dir).await?;
+    note(
+        log,
+        &format!("Reusing the livekit cache of #{}", meta.build_id),
+    )
+    .await?;
+
+    Ok(Some(meta.build_id))
+}
+
+/// Keep the reusable trees of `checkout` as the cache of `arch`, left by
+/// `build_id` at `commit` of aosc-mklive.
+pub async fn save(
+    arch: &str,
+    checkout: &Path,
+    build_id: i64,
+    commit: &str,
+    log: &mut JobLog,
+) -> eyre::Result<()> {
+    let dir = Path::new(CACHE_DIR).join(arch);
+    if fs::symlink_metadata(&dir).await.is_ok() {
+        fs::remove_dir_all(&dir).await?;
+    }
+    fs::create_dir_all(&dir).await?;
+
+    for name in REUSABLE {
+        let from = checkout.join(name);
+        if fs::symlink_metadata(&from).await.is_ok() {
+            fs::rename(&from, dir.join(name)).await?;
+        }
+    }
+    let meta = Meta {
+        build_id,
+        commit: commit.to_string(),
+        created_at: now(),
+    };
+    fs::write(dir.join(META_FILE), serde_json::to_vec(&meta)?).await?;
+    note(log, &format!("Kept the livekit cache of #{build_id}")).await?;
+
+    Ok(())
+}
+
+async fn note(log: &mut JobLog, msg: &str) -> eyre::Result<()> {
+    let msg = format!("{}: {msg}\n", Local::now());
+    log.write(msg.as_bytes()).await?;
+    info!("{}", msg.trim());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Workdir;
+
+    const COMMIT: &str = "3f2a9c1";
+
+    fn policy() -> CachePolicy {
+        CachePolicy {
+            max_age: Duration::from_secs(72 * 3600),
+        }
+    }
+
+    /// A checkout with the trees aosc-mklive left.
+    fn checkout() -> &'static Path {
+        let checkout = Path::new("aosc-mklive");
+        std::fs::create_dir_all(checkout.join("to-squash/livekit")).unwrap();
+        std::fs::write(checkout.join("to-squash/livekit/etc-os-release"), "AOSC OS").unwrap();
+
+        checkout
+    }
+
+    #[tokio::test]
+    async fn a_saved_cache_comes_back_once() {
+        let _dir = Workdir::enter("cache-round-trip").await;
+        let mut log = JobLog::memory();
+        let checkout = checkout();
+
+        save("amd64", checkout, 42, COMMIT, &mut log).await.unwrap();
+        assert!(!checkout.join("to-squash").exists());
+        assert_eq!(
+            restore(&policy(), "arm64", checkout, Some(COMMIT), &mut log)
+                .await
+                .unwrap(),
+            None
+        );
+
+        let restored = restore(&policy(), "amd64", checkout, Some(COMMIT), &mut log)
+            .await
+            .unwrap();
+        assert_eq!(restored, Some(42));
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("to-squash/livekit/etc-os-release")).unwrap(),
+            "AOSC OS"
+        );
+        assert!(log.tail().contains("Reusing the livekit cache of #42"));
+        assert_eq!(
+            restore(&policy(), "amd64", checkout, Some(COMMIT), &mut log)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn a_cache_of_another_commit_is_discarded() {
+        let _dir = Workdir::enter("cache-commit").await;
+        let mut log = JobLog::memory();
+        let checkout = checkout();
+        save("amd64", checkout, 42, COMMIT, &mut log).await.unwrap();
+
+        let restored = restore(&policy(), "amd64", checkout, Some("b7e01d4"), &mut log)
+            .await
+            .unwrap();
+        assert_eq!(restored, None);
+        assert!(log
+            .tail()
+            .contains("Discarded the livekit cache (made by aosc-mklive 3f2a9c1, now at b7e01d4)"));
+        assert!(!Path::new(CACHE_DIR).join("amd64").exists());
+        assert!(!checkout.join("to-squash").exists());
+    }
+
+    #[tokio::test]
+    async fn an_old_cache_is_discarded() {
+        let _dir = Workdir::enter("cache-age").await;
+        let mut log = JobLog::memory();
+        let checkout = checkout();
+        save("amd64", checkout, 42, COMMIT, &mut log).await.unwrap();
+        let meta = Meta {
+            build_id: 42,
+            commit: COMMIT.to_string(),
+            created_at: now() - 73 * 3600,
+        };
+        let path = Path::new(CACHE_DIR).join("amd64").join(META_FILE);
+        std::fs::write(path, serde_json::to_vec(&meta).unwrap()).unwrap();
+
+        let restored = restore(&policy(), "amd64", checkout, Some(COMMIT), &mut log)
+            .await
+            .unwrap();
+        assert_eq!(restored, None);
+        assert!(log.tail().contains("(older than 72h)"));
+    }
+}
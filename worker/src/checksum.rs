@@ -0,0 +1,62 @@
+use std::path::Path;
+
+use chrono::Local;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
+};
+use tracing::warn;
+
+use crate::joblog::JobLog;
+
+/// Hash `path` without reading it into memory at once, ISOs are large.
+pub async fn sha256_file(path: &Path) -> eyre::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Write `<name>.sha256sum`, in the format of `sha256sum`, for every ISO in
+/// `dir` that has no checksum file next to it. Returns the file names of the
+/// ISOs checksummed here.
+pub async fn ensure_checksums(dir: &Path, log: &mut JobLog) -> eyre::Result<Vec<String>> {
+    let mut computed = vec![];
+    let mut entries = fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().map(|x| x != "iso").unwrap_or(true) {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        let sum_file = dir.join(format!("{name}.sha256sum"));
+        if sum_file.exists() || path.with_extension("sha256sum").exists() {
+            continue;
+        }
+
+        let msg = format!(
+            "{}: {name} has no checksum file, computing it\n",
+            Local::now()
+        );
+        log.write(msg.as_bytes()).await?;
+        warn!("{}", msg.trim());
+
+        let sum = sha256_file(&path).await?;
+        fs::write(&sum_file, format!("{sum}  {name}\n")).await?;
+        computed.push(name);
+    }
+
+    Ok(computed)
+}
@@ -0,0 +1,289 @@
+//! Telling from the end of a job log why it failed, so that the requester
+//! does not have to download the log to learn that a mirror timed out.
+
+use std::path::Path;
+
+use eyre::Context;
+use regex::Regex;
+use serde::Deserialize;
+use shipit_common::Failure;
+
+/// Lines around the matching one included in the excerpt.
+const CONTEXT_LINES: usize = 2;
+
+/// Longer excerpt lines are cut, build logs have some very long ones.
+const MAX_LINE_LEN: usize = 200;
+
+/// Tried in order, the first pattern matching any line wins. `summary` may
+/// refer to named groups of `regex` like `$url`.
+const BUILTIN: &[(&str, &str, &str)] = &[
+    (
+        "oom",
+        r"(?i)out of memory|oom-kill|cannot allocate memory",
+        "out of memory",
+    ),
+    (
+        "out_of_disk",
+        r"(?i)no space left on device",
+        "out of disk space",
+    ),
+    (
+        "ssh_auth",
+        r"Permission denied \((?:publickey|password|keyboard-interactive)|Host key verification failed|Too many authentication failures",
+        "ssh authentication failed",
+    ),
+    (
+        "mirror_404",
+        r#"(?i)\b404\b.*?(?P<url>https?://[^\s)'"]*[^\s)'".,:;])"#,
+        "mirror 404 fetching $url",
+    ),
+    ("mirror_404", r"(?i)\b404 not found\b", "mirror 404"),
+    (
+        "mirror_timeout",
+        r#"(?i)(?:timed out|timeout).*?(?P<url>https?://[^\s)'"]*[^\s)'".,:;])"#,
+        "mirror timeout fetching $url",
+    ),
+    (
+        "mirror_timeout",
+        r#"(?i)(?P<url>https?://[^\s)'"]*[^\s)'".,:;]).*?(?:timed out|timeout)"#,
+        "mirror timeout fetching $url",
+    ),
+    (
+        "missing_package",
+        r"(?i)(?:unable to locate package|no such package|package not found):?\s*(?P<package>[\w.+-]*)",
+        "missing package $package",
+    ),
+    ("usage", r"(?i)^\s*usage:", "script usage error"),
+];
+
+#[derive(Deserialize)]
+struct PatternConfig {
+    class: String,
+    regex: String,
+    summary: String,
+}
+
+struct Pattern {
+    class: String,
+    regex: Regex,
+    summary: String,
+}
+
+pub struct Classifier {
+    patterns: Vec<Pattern>,
+}
+
+impl Classifier {
+    /// The built-in patterns, preceded by those in the JSON file
+    /// `failure_patterns` points to if set: a list of objects with `class`,
+    /// `regex` and `summary`.
+    pub async fn from_env() -> eyre::Result<Self> {
+        let mut patterns = vec![];
+
+        if let Ok(path) = std::env::var("failure_patterns") {
+            let s = tokio::fs::read(Path::new(&path))
+                .await
+                .wrap_err_with(|| format!("Failed to read failure_patterns {path}"))?;
+            let config: Vec<PatternConfig> = serde_json::from_slice(&s)
+                .wrap_err_with(|| format!("Failed to parse failure_patterns {path}"))?;
+
+            for c in config {
+                patterns.push(Pattern {
+                    regex: Regex::new(&c.regex)
+                        .wrap_err_with(|| format!("Bad regex for {} in {path}", c.class))?,
+                    class: c.class,
+                    summary: c.summary,
+                });
+            }
+        }
+
+        for (class, regex, summary) in BUILTIN {
+            patterns.push(Pattern {
+                class: class.to_string(),
+                regex: Regex::new(regex)?,
+                summary: summary.to_string(),
+            });
+        }
+
+        Ok(Self { patterns })
+    }
+
+    /// Match `tail`, the end of a job log, against the patterns. The last
+    /// matching line is reported, it is the closest to what ended the build.
+    pub fn classify(&self, tail: &str) -> Option<Failure> {
+        let lines = tail.lines().collect::<Vec<_>>();
+
+        self.patterns.iter().find_map(|p| {
+            let (i, caps) = lines
+                .iter()
+                .enumerate()
+                .rev()
+                .find_map(|(i, line)| Some((i, p.regex.captures(line)?)))?;
+
+            let mut summary = String::new();
+            caps.expand(&p.summary, &mut summary);
+
+            let excerpt = lines
+                [i.saturating_sub(CONTEXT_LINES)..(i + CONTEXT_LINES + 1).min(lines.len())]
+                .iter()
+                .map(|x| match x.char_indices().nth(MAX_LINE_LEN) {
+                    Some((end, _)) => format!("{} ...", &x[..end]),
+                    None => x.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            Some(Failure {
+                class: p.class.clone(),
+                summary: summary.trim().to_string(),
+                excerpt,
+                line: Some(lines[i].chars().take(MAX_LINE_LEN).collect()),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The class and summary a tail is expected to get.
+    type Expected = Option<(&'static str, &'static str)>;
+
+    /// Ends of real job logs, one per class.
+    const TAILS: &[(&str, &str, Expected)] = &[
+        (
+            "mirror_timeout",
+            include_str!("../tests/fixtures/tails/mirror_timeout.log"),
+            Some((
+                "mirror_timeout",
+                "mirror timeout fetching https://repo.aosc.io/debs/pool/stable/main/f/firefox_126.0.1-0_amd64.deb",
+            )),
+        ),
+        (
+            "mirror_404",
+            include_str!("../tests/fixtures/tails/mirror_404.log"),
+            Some((
+                "mirror_404",
+                "mirror 404 fetching https://repo.aosc.io/debs/pool/stable/main/a/apt_2.7.14-1_amd64.deb",
+            )),
+        ),
+        (
+            "out_of_disk",
+            include_str!("../tests/fixtures/tails/out_of_disk.log"),
+            Some(("out_of_disk", "out of disk space")),
+        ),
+        (
+            "oom",
+            include_str!("../tests/fixtures/tails/oom.log"),
+            Some(("oom", "out of memory")),
+        ),
+        (
+            "ssh_auth",
+            include_str!("../tests/fixtures/tails/ssh_auth.log"),
+            Some(("ssh_auth", "ssh authentication failed")),
+        ),
+        (
+            "missing_package",
+            include_str!("../tests/fixtures/tails/missing_package.log"),
+            Some(("missing_package", "missing package linux+kernel-lts")),
+        ),
+        (
+            "usage",
+            include_str!("../tests/fixtures/tails/usage.log"),
+            Some(("usage", "script usage error")),
+        ),
+        (
+            "unrecognized",
+            include_str!("../tests/fixtures/tails/unrecognized.log"),
+            None,
+        ),
+    ];
+
+    fn builtin() -> Classifier {
+        Classifier {
+            patterns: BUILTIN
+                .iter()
+                .map(|(class, regex, summary)| Pattern {
+                    class: class.to_string(),
+                    regex: Regex::new(regex).unwrap(),
+                    summary: summary.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn real_tails() {
+        let c = builtin();
+        for (name, tail, expected) in TAILS {
+            let got = c.classify(tail);
+            assert_eq!(
+                got.as_ref().map(|x| (x.class.as_str(), x.summary.as_str())),
+                *expected,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn excerpt_is_around_the_last_match() {
+        let tail = include_str!("../tests/fixtures/tails/ssh_auth.log");
+        let f = builtin().classify(tail).unwrap();
+
+        assert_eq!(
+            f.line.as_deref(),
+            Some("maintainers@repo.aosc.io: Permission denied (publickey).")
+        );
+        let lines = tail.lines().collect::<Vec<_>>();
+        assert_eq!(f.excerpt, lines[..5].join("\n"));
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let tail = include_str!("../tests/fixtures/tails/mirror_404.log");
+        let f = builtin().classify(tail).unwrap();
+
+        let line = f.line.unwrap();
+        assert_eq!(line.chars().count(), MAX_LINE_LEN);
+        assert!(tail.contains(&line));
+        assert!(f
+            .excerpt
+            .lines()
+            .all(|x| x.chars().count() <= MAX_LINE_LEN + " ...".len()));
+        assert!(f.excerpt.contains(" ...\n"));
+    }
+
+    #[test]
+    fn earlier_patterns_win() {
+        // a mirror hiccup the download retried past, then the real cause
+        let tail = "\
+[WARN] https://repo.aosc.io/debs/pool/stable/main/g/gcc_14.1.0-0_amd64.deb: operation timed out, retrying
+FATAL ERROR: Failed to write to output filesystem: No space left on device
++ exit 1";
+
+        assert_eq!(builtin().classify(tail).unwrap().class, "out_of_disk");
+    }
+
+    #[test]
+    fn configured_patterns_come_first() {
+        let mut c = builtin();
+        c.patterns.insert(
+            0,
+            Pattern {
+                class: "squashfs".to_string(),
+                regex: Regex::new(r"FATAL ERROR: (?P<what>.*)").unwrap(),
+                summary: "mksquashfs: $what".to_string(),
+            },
+        );
+
+        let f = c
+            .classify(include_str!("../tests/fixtures/tails/out_of_disk.log"))
+            .unwrap();
+        assert_eq!(f.class, "squashfs");
+        assert_eq!(
+            f.summary,
+            "mksquashfs: Failed to write to output filesystem: No space left on device"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Cleaning the aosc-mklive checkout before a livekit build. Only what is
+//! listed in `livekit_clean` and what the previous build produced is
+//! removed, never anything merely named like an output.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::Local;
+use eyre::bail;
+use tokio::fs::{self, read_dir};
+use tracing::info;
+
+use crate::joblog::JobLog;
+
+/// Names of the entries a build produced, one per line, kept in the
+/// checkout for the next cleanup.
+const OUTPUTS_FILE: &str = ".shipit-outputs";
+
+/// The working directories of aosc-mklive.
+const DEFAULT_PATHS: &[&str] = &["livekit", "iso", "to-squash", "memtest", "sb"];
+
+pub struct Clean {
+    /// Relative to the checkout.
+    paths: Vec<PathBuf>,
+}
+
+impl Clean {
+    /// `livekit_clean`: whitespace separated paths relative to the checkout,
+    /// the working directories of aosc-mklive by default.
+    pub fn from_env() -> eyre::Result<Self> {
+        let paths = match std::env::var("livekit_clean") {
+            Ok(x) => x.split_whitespace().map(PathBuf::from).collect(),
+            Err(_) => DEFAULT_PATHS.iter().map(PathBuf::from).collect::<Vec<_>>(),
+        };
+
+        for p in &paths {
+            if !is_relative_below(p) {
+                bail!(
+                    "Invalid livekit_clean entry {}: must be relative, without ..",
+                    p.display()
+                );
+            }
+        }
+
+        Ok(Self { paths })
+    }
+}
+
+fn is_relative_below(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path.components().all(|x| matches!(x, Component::Normal(_)))
+}
+
+/// Entries of `dir` with their modification times.
+pub async fn snapshot(dir: &Path) -> eyre::Result<BTreeMap<OsString, Option<SystemTime>>> {
+    let mut entries = BTreeMap::new();
+    let mut iter = read_dir(dir).await?;
+    while let Some(i) = iter.next_entry().await? {
+        let modified = fs::symlink_metadata(i.path())
+            .await
+            .and_then(|x| x.modified())
+            .ok();
+        entries.insert(i.file_name(), modified);
+    }
+
+    Ok(entries)
+}
+
+/// Entries of `dir` that are new or modified since `before`, remembered for
+/// the next cleanup along with those not cleaned up yet.
+pub async fn outputs(
+    dir: &Path,
+    before: &BTreeMap<OsString, Option<SystemTime>>,
+) -> eyre::Result<Vec<OsString>> {
+    let outputs = snapshot(dir)
+        .await?
+        .into_iter()
+        .filter(|(name, modified)| name != OUTPUTS_FILE && before.get(name) != Some(modified))
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+
+    let mut recorded = recorded(dir).await?;
+    recorded.extend(
+        outputs
+            .iter()
+            .filter_map(|x| x.to_str())
+            .map(|x| x.to_string()),
+    );
+    let mut s = recorded.into_iter().collect::<Vec<_>>().join("\n");
+    s.push('\n');
+    fs::write(dir.join(OUTPUTS_FILE), s).await?;
+
+    Ok(outputs)
+}
+
+/// Outputs of earlier builds not cleaned up yet.
+async fn recorded(dir: &Path) -> eyre::Result<BTreeSet<String>> {
+    match fs::read_to_string(dir.join(OUTPUTS_FILE)).await {
+        Ok(s) => Ok(s
+            .lines()
+            .filter(|x| is_relative_below(Path::new(x)) && !x.contains('/'))
+            .map(|x| x.to_string())
+            .collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Remove the configured paths and the recorded outputs from `dir`, writing
+/// each removal and the reason for it to `log`.
+pub async fn clean(dir: &Path, config: &Clean, log: &mut JobLog) -> eyre::Result<()> {
+    let mut targets = config
+        .paths
+        .iter()
+        .map(|x| (x.clone(), "listed in livekit_clean"))
+        .collect::<Vec<_>>();
+    for name in recorded(dir).await? {
+        let name = PathBuf::from(name);
+        if !config.paths.contains(&name) {
+            targets.push((name, "produced by the previous build"));
+        }
+    }
+
+    for (rel, reason) in targets {
+        let path = dir.join(&rel);
+        let Ok(meta) = fs::symlink_metadata(&path).await else {
+            continue;
+        };
+
+        // a symlink is removed itself, never what it points to
+        if meta.is_dir() {
+            fs::remove_dir_all(&path).await?;
+        } else {
+            fs::remove_file(&path).await?;
+        }
+
+        let msg = format!("{}: Removed {} ({reason})\n", Local::now(), rel.display());
+        log.write(msg.as_bytes()).await?;
+        info!("{}", msg.trim());
+    }
+
+    match fs::remove_file(dir.join(OUTPUTS_FILE)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
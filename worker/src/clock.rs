@@ -0,0 +1,105 @@
+//! Comparing the clock of the worker to the one of the server. A builder
+//! whose RTC drifted after a power loss writes misleading logs and history
+//! and fails TLS, so it should not take jobs.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use shipit_common::human_duration;
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+use crate::Server;
+
+/// How often the clock is compared again while polling for jobs.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct ClockCheck {
+    max_skew: u64,
+    /// When the clock was last compared, and the skew if too large.
+    last: Option<(Instant, Option<i64>)>,
+    /// The skew last measured, too large or not.
+    measured: Option<i64>,
+}
+
+impl ClockCheck {
+    /// `clock_skew_max_secs` (default 300).
+    pub fn from_env() -> eyre::Result<Self> {
+        let max_skew = match std::env::var("clock_skew_max_secs") {
+            Ok(x) => x.parse()?,
+            Err(_) => 300,
+        };
+
+        Ok(Self {
+            max_skew,
+            last: None,
+            measured: None,
+        })
+    }
+
+    /// Seconds this worker is ahead of the server (negative if behind) if
+    /// that is more than allowed, `None` if the clock is fine or the server
+    /// cannot tell. Compared again if the last check is long enough ago.
+    pub async fn skew(&mut self, server: &Server) -> Option<i64> {
+        if let Some((at, skew)) = self.last {
+            if at.elapsed() < CHECK_INTERVAL {
+                return skew;
+            }
+        }
+
+        let measured = measure(server).await;
+        if let Ok(x) = measured {
+            self.measured = Some(x);
+        }
+
+        let skew = match measured {
+            Ok(x) if x.unsigned_abs() > self.max_skew => {
+                error!(
+                    "Clock is {} {} of the server, not taking jobs until it is fixed",
+                    human_duration(x.unsigned_abs()),
+                    if x > 0 { "ahead" } else { "behind" }
+                );
+                Some(x)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Failed to compare the clock to the server: {e}");
+                None
+            }
+        };
+        self.last = Some((Instant::now(), skew));
+
+        skew
+    }
+
+    /// Seconds the clock may be off before the worker stops taking jobs.
+    pub fn max_skew(&self) -> u64 {
+        self.max_skew
+    }
+
+    /// Seconds this worker was ahead of the server when last compared,
+    /// reported to the server for its worker list.
+    pub fn measured(&self) -> Option<i64> {
+        self.measured
+    }
+}
+
+/// Local time minus server time, taking the local time halfway through the
+/// request.
+pub async fn measure(server: &Server) -> eyre::Result<i64> {
+    let before = unix_now()?;
+    let server_now: u64 = server
+        .client
+        .get(format!("{}/time", server.uri))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let after = unix_now()?;
+
+    Ok(((before + after) / 2.0).round() as i64 - server_now as i64)
+}
+
+fn unix_now() -> eyre::Result<f64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64())
+}
@@ -0,0 +1,278 @@
+//! Whether a checkout of the build scripts is behind its canonical
+//! repository, e.g. because `git pull` went to a stale mirror and still
+//! succeeded. Failing to tell is logged and never fails the build. git runs
+//! as `run_as` if set, the user the scripts build in the checkout as.
+
+use std::{path::Path, time::Duration};
+
+use chrono::Local;
+use eyre::{bail, OptionExt};
+use shipit_common::{human_duration, Staleness};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::{
+    joblog::JobLog,
+    runner::{CommandRunner, RunAs},
+};
+
+pub const MKLIVE_REPO: &str = "https://github.com/AOSC-Dev/aosc-mklive";
+pub const AOSCBOOTSTRAP_REPO: &str = "https://github.com/AOSC-Dev/aoscbootstrap";
+
+/// For each git command talking to the canonical repository.
+const GIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Freshness {
+    /// More commits behind are stale.
+    max_behind: u64,
+    /// Behind by newer commits than this is stale, in seconds.
+    max_age: u64,
+}
+
+impl Freshness {
+    /// `scripts_max_behind` commits (default 5) and `scripts_max_age_hours`
+    /// (default 24).
+    pub fn from_env() -> eyre::Result<Self> {
+        let max_behind = match std::env::var("scripts_max_behind") {
+            Ok(x) => x.parse()?,
+            Err(_) => 5,
+        };
+        let max_age = match std::env::var("scripts_max_age_hours") {
+            Ok(x) => x.parse::<u64>()? * 3600,
+            Err(_) => 24 * 3600,
+        };
+
+        Ok(Self {
+            max_behind,
+            max_age,
+        })
+    }
+}
+
+/// Compare the checkout in `dir` with `url`, and warn in `log` if it is
+/// further behind than `policy` allows.
+pub async fn check(
+    runner: &impl CommandRunner,
+    run_as: Option<&RunAs>,
+    dir: &Path,
+    url: &str,
+    policy: &Freshness,
+    log: &mut JobLog,
+) -> eyre::Result<Option<Staleness>> {
+    let git = Git {
+        runner,
+        run_as,
+        dir,
+    };
+    let msg = match behind(&git, url).await {
+        Ok(None) => return Ok(None),
+        Ok(Some(s)) if s.behind <= policy.max_behind && s.age_secs <= policy.max_age => {
+            let msg = format!(
+                "{}: {} is {} commits behind {}, within limits\n",
+                Local::now(),
+                dir.display(),
+                s.behind,
+                s.remote
+            );
+            log.write(msg.as_bytes()).await?;
+            info!("{}", msg.trim());
+
+            return Ok(None);
+        }
+        Ok(Some(s)) => {
+            let msg = format!(
+                "{}: WARNING: built from {} {} commits ({}) behind {}, is the mirror stale?\n",
+                Local::now(),
+                dir.display(),
+                s.behind,
+                human_duration(s.age_secs),
+                s.remote
+            );
+            log.write(msg.as_bytes()).await?;
+            warn!("{}", msg.trim());
+
+            return Ok(Some(s));
+        }
+        Err(e) => format!(
+            "{}: Could not verify the freshness of {}: {e}\n",
+            Local::now(),
+            dir.display()
+        ),
+    };
+
+    log.write(msg.as_bytes()).await?;
+    warn!("{}", msg.trim());
+
+    Ok(None)
+}
+
+/// How far `dir` is behind the default branch of `url`, `None` if it is
+/// not.
+async fn behind(git: &Git<'_, impl CommandRunner>, url: &str) -> eyre::Result<Option<Staleness>> {
+    // `ref: refs/heads/master\tHEAD` and `<sha>\tHEAD`
+    let remote = git.run(&["ls-remote", "--symref", url, "HEAD"]).await?;
+    let mut branch = None;
+    let mut sha = None;
+    for line in remote.lines() {
+        match line.strip_prefix("ref: ") {
+            Some(x) => branch = x.split_whitespace().next(),
+            None => sha = line.split_whitespace().next(),
+        }
+    }
+    let sha = sha.ok_or_eyre("No HEAD in the canonical repository")?;
+    let branch = branch
+        .and_then(|x| x.strip_prefix("refs/heads/"))
+        .unwrap_or("HEAD");
+
+    if git.run(&["rev-parse", "HEAD"]).await?.trim() == sha {
+        return Ok(None);
+    }
+
+    // only FETCH_HEAD is updated, not the checkout that was built
+    git.run(&["fetch", "--quiet", url, branch]).await?;
+    let behind = git
+        .run(&["rev-list", "--count", "HEAD..FETCH_HEAD"])
+        .await?
+        .trim()
+        .parse()?;
+    let commit_time = |rev| async move {
+        git.run(&["log", "-1", "--format=%ct", rev])
+            .await?
+            .trim()
+            .parse::<u64>()
+            .map_err(eyre::Report::from)
+    };
+    let local = commit_time("HEAD").await?;
+    let newest = commit_time("FETCH_HEAD").await?;
+
+    Ok(Some(Staleness {
+        behind,
+        age_secs: newest.saturating_sub(local),
+        remote: format!("origin/{branch}"),
+    }))
+}
+
+/// git in the checkout `dir`, see [`RunAs::command`].
+struct Git<'a, R> {
+    runner: &'a R,
+    run_as: Option<&'a RunAs>,
+    dir: &'a Path,
+}
+
+impl<R: CommandRunner> Git<'_, R> {
+    async fn run(&self, args: &[&str]) -> eyre::Result<String> {
+        let (cmd, argv) = RunAs::command(self.run_as, "git", args);
+        let argv = argv.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        let output = timeout(GIT_TIMEOUT, self.runner.output(&cmd, &argv, self.dir)).await??;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            match stderr.lines().last() {
+                Some(line) => bail!("git {}: {}", args[0], line.trim()),
+                None => bail!("git {}: {}", args[0], output.status),
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::fake::Script;
+
+    const POLICY: Freshness = Freshness {
+        max_behind: 5,
+        max_age: 24 * 3600,
+    };
+
+    const REMOTE: &str = "ref: refs/heads/master\tHEAD\nabc123\tHEAD\n";
+
+    async fn check_with(script: &Script, run_as: Option<&RunAs>) -> Option<Staleness> {
+        check(
+            script,
+            run_as,
+            Path::new("aosc-mklive"),
+            MKLIVE_REPO,
+            &POLICY,
+            &mut JobLog::memory(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn up_to_date() {
+        let script = Script::default()
+            .on("git", "ls-remote", 0)
+            .prints(REMOTE)
+            .on("git", "rev-parse", 0)
+            .prints("abc123\n");
+
+        assert!(check_with(&script, None).await.is_none());
+        assert_eq!(script.calls().len(), 2);
+        assert!(script
+            .calls()
+            .iter()
+            .all(|(_, cwd)| cwd == Path::new("aosc-mklive")));
+    }
+
+    #[tokio::test]
+    async fn too_far_behind() {
+        let script = Script::default()
+            .on("git", "ls-remote", 0)
+            .prints(REMOTE)
+            .on("git", "rev-parse", 0)
+            .prints("def456\n")
+            .on("git", "fetch", 0)
+            .on("git", "HEAD..FETCH_HEAD", 0)
+            .prints("7\n")
+            .on("git", "FETCH_HEAD", 0)
+            .prints("1714600000\n")
+            .on("git", "HEAD", 0)
+            .prints("1714500000\n");
+
+        let s = check_with(&script, None).await.unwrap();
+
+        assert_eq!(s.behind, 7);
+        assert_eq!(s.age_secs, 100_000);
+        assert_eq!(s.remote, "origin/master");
+        assert!(script
+            .calls()
+            .iter()
+            .any(|(line, _)| line == &format!("git fetch --quiet {MKLIVE_REPO} master")));
+    }
+
+    #[tokio::test]
+    async fn failing_git_is_not_stale() {
+        let script = Script::default().on("git", "ls-remote", 128);
+
+        assert!(check_with(&script, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn runs_as_the_script_user() {
+        let run_as = RunAs {
+            uid: 1000,
+            gid: 1000,
+        };
+        let script = Script::default()
+            .on("setpriv", "ls-remote", 0)
+            .prints(REMOTE)
+            .on("setpriv", "rev-parse", 0)
+            .prints("abc123\n");
+
+        assert!(check_with(&script, Some(&run_as)).await.is_none());
+        let calls = script.calls();
+        assert_eq!(calls.len(), 2);
+        for (line, _) in calls {
+            assert!(
+                line.starts_with(
+                    "setpriv --reuid=1000 --regid=1000 --init-groups --reset-env git "
+                ),
+                "{line}"
+            );
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Holding off new jobs while the machine is busy or hot, as builds started
+//! on a throttling board take several times longer. `claim_max_load` caps
+//! the 5-minute load average, `claim_max_temp` the temperature in °C of the
+//! hottest of the hwmon files listed in `claim_temp_sensors`, e.g.
+//! `/sys/class/hwmon/hwmon0/temp1_input`. Either is off unless set. While
+//! over, the worker polls without taking a job and tells the server why,
+//! which `/status` shows, then checks again at the next poll.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use eyre::bail;
+use tokio::fs;
+use tracing::{info, warn};
+
+use crate::vitals::Vitals;
+
+pub struct ClaimGuard {
+    max_load: Option<f64>,
+    max_temp: Option<f64>,
+    sensors: Vec<PathBuf>,
+    /// Why the last check deferred, to log only changes.
+    last: Option<String>,
+    /// Sensors that failed to read, told of once as it is checked at every
+    /// poll.
+    unreadable: BTreeSet<PathBuf>,
+}
+
+impl ClaimGuard {
+    pub fn from_env() -> eyre::Result<Self> {
+        let max = |name: &str| -> eyre::Result<Option<f64>> {
+            match std::env::var(name) {
+                Ok(x) => Ok(Some(x.parse()?)),
+                Err(_) => Ok(None),
+            }
+        };
+        let sensors = std::env::var("claim_temp_sensors")
+            .unwrap_or_default()
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let max_temp = max("claim_max_temp")?;
+        if max_temp.is_some() && sensors.is_empty() {
+            bail!("claim_max_temp is set, but no claim_temp_sensors to read");
+        }
+
+        Ok(Self {
+            max_load: max("claim_max_load")?,
+            max_temp,
+            sensors,
+            last: None,
+            unreadable: BTreeSet::new(),
+        })
+    }
+
+    /// Why not to take a job now, e.g. `load 9.3 > 6.0`, or `None`.
+    pub async fn check(&mut self, vitals: &Vitals) -> Option<String> {
+        let mut reasons = vec![];
+        if let (Some(max), Some([_, load, _])) = (self.max_load, vitals.load_avg) {
+            if load > max {
+                reasons.push(format!("load {load:.1} > {max:.1}"));
+            }
+        }
+        if let Some(max) = self.max_temp {
+            match self.temperature().await {
+                Some(temp) if temp > max => {
+                    reasons.push(format!("temperature {temp:.1}°C > {max:.1}°C"))
+                }
+                _ => {}
+            }
+        }
+        let reason = (!reasons.is_empty()).then(|| reasons.join(", "));
+
+        if reason != self.last {
+            match reason {
+                Some(ref r) => info!("Deferring new jobs: {r}"),
+                None => info!("No longer deferring new jobs"),
+            }
+            self.last = reason.clone();
+        }
+
+        reason
+    }
+
+    /// The hottest of the sensors in °C, those that cannot be read left
+    /// out.
+    async fn temperature(&mut self) -> Option<f64> {
+        let mut hottest = None;
+        for path in &self.sensors {
+            let temp = match read_temp(path).await {
+                Ok(x) => {
+                    self.unreadable.remove(path);
+                    Some(x)
+                }
+                Err(e) => {
+                    if self.unreadable.insert(path.clone()) {
+                        warn!(
+                            "Failed to read the temperature from {}: {e}",
+                            path.display()
+                        );
+                    }
+                    None
+                }
+            };
+            hottest = match (hottest, temp) {
+                (Some(a), Some(b)) => Some(f64::max(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+
+        hottest
+    }
+}
+
+/// In °C, from millidegrees as hwmon has it.
+async fn read_temp(path: &Path) -> eyre::Result<f64> {
+    let s = fs::read_to_string(path).await?;
+
+    Ok(s.trim().parse::<f64>()? / 1000.0)
+}
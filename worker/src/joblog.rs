@@ -0,0 +1,168 @@
+use std::{collections::VecDeque, path::Path};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+};
+
+use crate::{redact, timeline::Timeline, transfer::Transfer};
+
+/// Keep this much of the end of the log in memory for error reports.
+const TAIL_LEN: usize = 64 * 1024;
+
+/// Log of a job, written to a file as it goes so that multi-hour builds do
+/// not hold their whole output in memory.
+pub struct JobLog {
+    file: Option<BufWriter<File>>,
+    tail: VecDeque<u8>,
+    /// The end of the last write, held back while it may be the start of a
+    /// secret to mask.
+    pending: Vec<u8>,
+    /// Commands run with this log.
+    pub timeline: Timeline,
+    /// The upload in progress, see [`crate::transfer`].
+    pub transfer: Transfer,
+}
+
+impl JobLog {
+    pub async fn create(path: &Path) -> eyre::Result<Self> {
+        Ok(Self {
+            file: Some(BufWriter::new(File::create(path).await?)),
+            tail: VecDeque::with_capacity(TAIL_LEN),
+            pending: vec![],
+            timeline: Timeline::default(),
+            transfer: Transfer::default(),
+        })
+    }
+
+    /// A log that only keeps its tail, for commands whose output is only
+    /// interesting when they fail.
+    pub fn memory() -> Self {
+        Self {
+            file: None,
+            tail: VecDeque::with_capacity(TAIL_LEN),
+            pending: vec![],
+            timeline: Timeline::default(),
+            transfer: Transfer::default(),
+        }
+    }
+
+    /// Write `buf` with the secrets masked, see [`redact`].
+    pub async fn write(&mut self, buf: &[u8]) -> eyre::Result<()> {
+        self.pending.extend_from_slice(buf);
+        let data = redact::bytes(&self.pending).into_owned();
+        let keep = redact::partial_len(&data);
+        self.pending = data[data.len() - keep..].to_vec();
+
+        self.write_raw(&data[..data.len() - keep]).await
+    }
+
+    async fn write_raw(&mut self, buf: &[u8]) -> eyre::Result<()> {
+        if let Some(ref mut file) = self.file {
+            file.write_all(buf).await?;
+        }
+
+        let buf = &buf[buf.len().saturating_sub(TAIL_LEN)..];
+        let overflow = (self.tail.len() + buf.len()).saturating_sub(TAIL_LEN);
+        self.tail.drain(..overflow);
+        self.tail.extend(buf);
+
+        Ok(())
+    }
+
+    /// Make everything written so far visible in the file, e.g. before
+    /// uploading it.
+    pub async fn flush(&mut self) -> eyre::Result<()> {
+        // not a secret after all
+        let pending = std::mem::take(&mut self.pending);
+        self.write_raw(&pending).await?;
+
+        if let Some(ref mut file) = self.file {
+            file.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    /// The last few KiB of the log.
+    pub fn tail(&self) -> String {
+        let (a, b) = self.tail.as_slices();
+        let mut v = Vec::with_capacity(a.len() + b.len());
+        v.extend_from_slice(a);
+        v.extend_from_slice(b);
+
+        String::from_utf8_lossy(&v).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_file_gets_everything_and_the_tail_the_end() {
+        let path = std::env::temp_dir().join(format!("shipit-joblog-{}.log", std::process::id()));
+        let mut log = JobLog::create(&path).await.unwrap();
+        let line = b"Building the squashfs image...\n";
+        let mut written = vec![];
+        while written.len() <= 2 * TAIL_LEN {
+            log.write(line).await.unwrap();
+            written.extend_from_slice(line);
+        }
+        log.write(b"done").await.unwrap();
+        written.extend_from_slice(b"done");
+        log.flush().await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+        let tail = log.tail();
+        assert_eq!(tail.len(), TAIL_LEN);
+        assert!(tail.ends_with("image...\ndone"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Resident memory of this process in KiB.
+    fn rss() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|x| x.starts_with("VmRSS:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "writes 512 MiB"]
+    async fn hundreds_of_megabytes_do_not_grow_the_memory() {
+        let path =
+            std::env::temp_dir().join(format!("shipit-joblog-large-{}.log", std::process::id()));
+        let mut log = JobLog::create(&path).await.unwrap();
+        let chunk = b"Compiling libLLVM.so with a very long command line...\n".repeat(1 << 10);
+        log.write(&chunk).await.unwrap();
+        let before = rss();
+
+        let mut written = chunk.len();
+        while written < 512 << 20 {
+            log.write(&chunk).await.unwrap();
+            written += chunk.len();
+        }
+        log.flush().await.unwrap();
+
+        let grown = rss().saturating_sub(before);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), written as u64);
+        assert!(grown < 16 << 10, "grew by {grown} KiB");
+        assert_eq!(log.tail().len(), TAIL_LEN);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_memory_log_keeps_the_tail() {
+        let mut log = JobLog::memory();
+        log.write(b"fatal: unable to access ").await.unwrap();
+        log.write(b"'https://github.com/AOSC-Dev/aoscbootstrap/'")
+            .await
+            .unwrap();
+        log.flush().await.unwrap();
+
+        assert_eq!(
+            log.tail(),
+            "fatal: unable to access 'https://github.com/AOSC-Dev/aoscbootstrap/'"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Shrinking a job log before it is uploaded. The full log stays on the
+//! worker, see [`prune`].
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    fs::{self, File},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+};
+use tracing::{info, warn};
+
+/// Lines at the end of a log that are uploaded regardless of its size.
+const KEEP_TAIL_LINES: usize = 500;
+
+/// First line of the block describing the job and the worker.
+pub const ENV_HEADER: &str = "ENVIRONMENT:";
+
+/// Where full logs are kept, and for how long.
+pub const FULL_LOG_DIR: &str = "full_logs";
+
+pub struct LogPolicy {
+    pub max_upload_bytes: u64,
+    pub retention: Duration,
+    /// Upload to the server in chunks of this size instead of with scp,
+    /// see [`crate::logupload`].
+    pub server_chunk_bytes: Option<u64>,
+}
+
+impl LogPolicy {
+    /// `log_max_upload_mib` (default 64) and `log_retention_days` (default 7).
+    /// `log_upload=server` uploads to the server in chunks of
+    /// `log_chunk_mib` (default 4) instead of with scp.
+    pub fn from_env() -> eyre::Result<Self> {
+        let mib = match std::env::var("log_max_upload_mib") {
+            Ok(x) => x.parse()?,
+            Err(_) => 64,
+        };
+        let days: u64 = match std::env::var("log_retention_days") {
+            Ok(x) => x.parse()?,
+            Err(_) => 7,
+        };
+
+        let server_chunk_bytes = match std::env::var("log_upload").as_deref() {
+            Ok("server") => Some(match std::env::var("log_chunk_mib") {
+                Ok(x) => x.parse::<u64>()? << 20,
+                Err(_) => 4 << 20,
+            }),
+            Ok("scp") | Err(_) => None,
+            Ok(x) => eyre::bail!("Invalid log_upload {x}, expected scp or server"),
+        };
+
+        Ok(Self {
+            max_upload_bytes: mib << 20,
+            retention: Duration::from_secs(days * 24 * 3600),
+            server_chunk_bytes,
+        })
+    }
+}
+
+/// Write `src` to `dest` with runs of identical lines collapsed, and cut out
+/// the middle if the result is still larger than `max_bytes`. The environment
+/// block at the start (up to the first empty line) and the last
+/// [`KEEP_TAIL_LINES`] lines are always kept.
+pub async fn prepare_upload(src: &Path, dest: &Path, max_bytes: u64) -> eyre::Result<()> {
+    let collapsed = dest.with_extension("collapsed");
+    let (total, tail_lines) = collapse(src, &collapsed).await?;
+
+    if total <= max_bytes {
+        fs::rename(&collapsed, dest).await?;
+        return Ok(());
+    }
+
+    let half = max_bytes / 2;
+    let tail_start = tail_lines
+        .front()
+        .copied()
+        .unwrap_or(0)
+        .min(total.saturating_sub(half));
+
+    let mut reader = BufReader::new(File::open(&collapsed).await?);
+    let mut writer = BufWriter::new(File::create(dest).await?);
+    let mut line = vec![];
+    let mut offset = 0;
+    let mut in_env = true;
+    let mut skipped = 0;
+
+    loop {
+        line.clear();
+        let n = reader.read_until(b'\n', &mut line).await? as u64;
+        if n == 0 {
+            break;
+        }
+
+        if offset == 0 && !line.starts_with(ENV_HEADER.as_bytes()) {
+            in_env = false;
+        }
+
+        let keep = in_env || offset < half || offset >= tail_start;
+        if in_env && line.iter().all(|x| x.is_ascii_whitespace()) {
+            in_env = false;
+        }
+
+        if keep {
+            if skipped > 0 && offset >= tail_start {
+                writer
+                    .write_all(
+                        format!(
+                            "... truncated {:.1} MiB ...\n",
+                            skipped as f64 / (1 << 20) as f64
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                skipped = 0;
+            }
+            writer.write_all(&line).await?;
+        } else {
+            skipped += n;
+        }
+
+        offset += n;
+    }
+
+    writer.flush().await?;
+    fs::remove_file(&collapsed).await?;
+    info!("Truncated log {} for upload", src.display());
+
+    Ok(())
+}
+
+/// Put `header` in front of the contents of `path`.
+pub async fn prepend(path: &Path, header: &[u8]) -> eyre::Result<()> {
+    let tmp = path.with_extension("prepend");
+    let mut writer = BufWriter::new(File::create(&tmp).await?);
+    writer.write_all(header).await?;
+    tokio::io::copy(&mut File::open(path).await?, &mut writer).await?;
+    writer.flush().await?;
+    fs::rename(&tmp, path).await?;
+
+    Ok(())
+}
+
+/// Collapse runs of identical consecutive lines of `src` into `dest`.
+/// Returns the size of `dest` and the offsets of its last lines.
+async fn collapse(src: &Path, dest: &Path) -> eyre::Result<(u64, VecDeque<u64>)> {
+    let mut reader = BufReader::new(File::open(src).await?);
+    let mut writer = BufWriter::new(File::create(dest).await?);
+    let mut tail_lines = VecDeque::with_capacity(KEEP_TAIL_LINES + 1);
+    let mut offset = 0;
+    let mut prev: Vec<u8> = vec![];
+    let mut line = vec![];
+    let mut repeated = 0;
+
+    fn emit(buf: &[u8], offset: &mut u64, tail_lines: &mut VecDeque<u64>) {
+        tail_lines.push_back(*offset);
+        if tail_lines.len() > KEEP_TAIL_LINES {
+            tail_lines.pop_front();
+        }
+        *offset += buf.len() as u64;
+    }
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+
+        if line == prev {
+            repeated += 1;
+            continue;
+        }
+
+        if repeated > 0 {
+            let msg = format!("... last line repeated {repeated} times\n");
+            writer.write_all(msg.as_bytes()).await?;
+            emit(msg.as_bytes(), &mut offset, &mut tail_lines);
+            repeated = 0;
+        }
+
+        writer.write_all(&line).await?;
+        emit(&line, &mut offset, &mut tail_lines);
+        std::mem::swap(&mut prev, &mut line);
+    }
+
+    if repeated > 0 {
+        let msg = format!("... last line repeated {repeated} times\n");
+        writer.write_all(msg.as_bytes()).await?;
+        emit(msg.as_bytes(), &mut offset, &mut tail_lines);
+    }
+
+    writer.flush().await?;
+
+    Ok((offset, tail_lines))
+}
+
+/// Remove full logs in `dir` older than `max_age`.
+pub async fn prune(dir: &Path, max_age: Duration) -> eyre::Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    let now = SystemTime::now();
+
+    while let Some(entry) = entries.next_entry().await? {
+        let modified = entry.metadata().await?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() > max_age {
+            if let Err(e) = fs::remove_file(entry.path()).await {
+                warn!("Failed to remove old log {}: {e}", entry.path().display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Workdir;
+
+    /// `log` as prepared for an upload of at most `max_bytes`.
+    async fn prepared(dir: &Workdir, log: &str, max_bytes: u64) -> String {
+        let (src, dest) = (dir.path.join("job.log"), dir.path.join("upload.log"));
+        fs::write(&src, log).await.unwrap();
+        prepare_upload(&src, &dest, max_bytes).await.unwrap();
+        assert!(!dir.path.join("upload.collapsed").exists());
+
+        fs::read_to_string(dest).await.unwrap()
+    }
+
+    /// `n` numbered lines of `width` bytes each, newline included.
+    fn lines(n: usize, width: usize) -> String {
+        (0..n)
+            .map(|i| format!("{:<1$}\n", format!("line {i:05}"), width - 1))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_of_identical_lines_are_collapsed() {
+        let dir = Workdir::enter("logproc-collapse").await;
+        let log = "a\nb\nb\nb\nc\nb\nc\nc\n";
+
+        assert_eq!(
+            prepared(&dir, log, 1 << 20).await,
+            "a\nb\n... last line repeated 2 times\nc\nb\nc\n... last line repeated 1 times\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_middle_is_cut_once_over_the_limit() {
+        let dir = Workdir::enter("logproc-cut").await;
+        let env = "ENVIRONMENT:\nWORKER=w1\n\n";
+        let log = format!("{env}{}", lines(5000, 11));
+
+        let up = prepared(&dir, &log, 10_000).await;
+        assert!(up.starts_with(&format!("{env}line 00000\n")), "{up}");
+        assert_eq!(up.matches("... truncated ").count(), 1);
+        // the first half of the limit, then the last 500 lines
+        assert!(up.contains("line 00450\n"));
+        assert!(!up.contains("line 00460\n"));
+        assert!(!up.contains("line 04499\n"));
+        assert!(up.ends_with(&lines(5000, 11)[4500 * 11..]));
+        assert!(up.len() < 11_000, "{}", up.len());
+    }
+
+    #[tokio::test]
+    async fn the_environment_block_is_kept_whole() {
+        let dir = Workdir::enter("logproc-env").await;
+        let env = format!("{ENV_HEADER}\n{}\n", lines(100, 60));
+        let log = format!("{env}{}", lines(5000, 11));
+
+        let up = prepared(&dir, &log, 10_000).await;
+        assert!(up.starts_with(&env));
+        // the environment used up the first half
+        assert!(up[env.len()..].starts_with("... truncated "), "{up}");
+    }
+
+    #[tokio::test]
+    async fn the_last_lines_are_kept_even_past_the_limit() {
+        let dir = Workdir::enter("logproc-tail").await;
+        let log = lines(1000, 100);
+
+        let up = prepared(&dir, &log, 10_000).await;
+        assert!(up.ends_with(&log[500 * 100..]));
+        assert!(up.starts_with(&log[..50 * 100]));
+        assert!(!up.contains("line 00499"));
+        assert_eq!(up.matches("... truncated ").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_log_within_the_limit_is_left_as_collapsed() {
+        let dir = Workdir::enter("logproc-small").await;
+        let log = lines(100, 11);
+
+        assert_eq!(prepared(&dir, &log, 100 * 11).await, log);
+    }
+}
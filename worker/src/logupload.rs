@@ -0,0 +1,463 @@
+//! Uploading the job log to the server in chunks instead of with scp, see
+//! `log_upload`. After a failure the worker asks the server how much it
+//! committed and goes on from there, so a flaky link costs a chunk, not the
+//! whole log. The server checks the size and sha256 once all is there. A
+//! report too large for `/done` goes up the same way, and `/done` names it.
+
+use std::{
+    io::SeekFrom,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use eyre::bail;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    time::sleep,
+};
+use tracing::{info, warn};
+
+use crate::{
+    checksum::sha256_file,
+    joblog::JobLog,
+    retry::{random, RetryOutcome, RetryPolicy},
+};
+
+/// For each request, a chunk over a slow link included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Where the log goes and whose it is.
+pub struct Target<'a> {
+    pub client: &'a Client,
+    pub uri: &'a str,
+    pub secret: &'a str,
+    pub worker: &'a str,
+    pub id: i64,
+    pub arch: &'a str,
+}
+
+#[derive(Serialize)]
+struct StartRequest<'a> {
+    id: i64,
+    arch: &'a str,
+    name: &'a str,
+    size: u64,
+    sha256: &'a str,
+    worker: &'a str,
+}
+
+#[derive(Deserialize)]
+struct UploadState {
+    upload: String,
+    offset: u64,
+}
+
+#[derive(Deserialize)]
+struct Finished {
+    url: String,
+}
+
+/// Upload `path` as `name` in chunks of `chunk_bytes`, retrying per
+/// `policy`. Attempts that commit a chunk do not count towards its
+/// attempts, only the budget limits them. Returns the URL of the log.
+pub async fn upload(
+    target: &Target<'_>,
+    path: &Path,
+    name: &str,
+    chunk_bytes: u64,
+    policy: &RetryPolicy,
+    log: &mut JobLog,
+) -> (RetryOutcome, Option<String>) {
+    let started = Instant::now();
+    let mut outcome = RetryOutcome::default();
+    let checksum = async {
+        let size = tokio::fs::metadata(path).await?.len();
+        eyre::Ok((size, sha256_file(path).await?))
+    };
+    let (size, sha256) = match checksum.await {
+        Ok(x) => x,
+        Err(e) => {
+            outcome.last_status = Some(format!("Failed to read {}: {e}", path.display()));
+            return (outcome, None);
+        }
+    };
+
+    let mut upload = None;
+    let mut offset = 0;
+    let mut stalled = 0;
+    let url = loop {
+        outcome.attempts += 1;
+        let before = offset;
+        let res = attempt(
+            target,
+            path,
+            name,
+            (size, &sha256),
+            chunk_bytes,
+            &mut upload,
+            &mut offset,
+        )
+        .await;
+
+        let e = match res {
+            Ok(url) => {
+                outcome.success = true;
+                break Some(url);
+            }
+            Err(e) => e,
+        };
+        let msg = format!(
+            "{}: Uploading the log failed at {offset} of {size} bytes: {e}\n",
+            Local::now()
+        );
+        let _ = log.write(msg.as_bytes()).await;
+        warn!("{}", msg.trim());
+        outcome.last_status = Some(e.to_string());
+
+        stalled = if offset > before { 0 } else { stalled + 1 };
+        if stalled >= policy.attempts || started.elapsed() >= policy.budget {
+            break None;
+        }
+        sleep(policy.delay(stalled.saturating_sub(1), random())).await;
+    };
+    outcome.total_secs = started.elapsed().as_secs_f64();
+
+    (outcome, url)
+}
+
+/// Ask the server where the upload stands, send the rest and finish it.
+/// `upload` and `offset` are what the server committed, kept across
+/// attempts.
+async fn attempt(
+    target: &Target<'_>,
+    path: &Path,
+    name: &str,
+    (size, sha256): (u64, &str),
+    chunk_bytes: u64,
+    upload: &mut Option<String>,
+    offset: &mut u64,
+) -> eyre::Result<String> {
+    let uploads = format!("{}/logs/uploads", target.uri);
+    let request = match upload {
+        None => target.client.post(&uploads).json(&StartRequest {
+            id: target.id,
+            arch: target.arch,
+            name,
+            size,
+            sha256,
+            worker: target.worker,
+        }),
+        Some(u) => target.client.get(format!("{uploads}/{u}")),
+    };
+    let state: UploadState = request
+        .header("secret", target.secret)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if state.offset > 0 && upload.is_none() {
+        info!("Resuming the log upload at {} bytes", state.offset);
+    }
+    *offset = state.offset;
+    let u = upload.insert(state.upload);
+
+    let mut file = File::open(path).await?;
+    while *offset < size {
+        file.seek(SeekFrom::Start(*offset)).await?;
+        let mut chunk = vec![0; chunk_bytes.min(size - *offset) as usize];
+        file.read_exact(&mut chunk).await?;
+
+        let state: UploadState = target
+            .client
+            .put(format!("{uploads}/{u}"))
+            .query(&[("offset", *offset)])
+            .header("secret", target.secret)
+            .timeout(REQUEST_TIMEOUT)
+            .body(chunk)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        *offset = state.offset;
+    }
+
+    let resp = target
+        .client
+        .post(format!("{uploads}/{u}/finish"))
+        .header("secret", target.secret)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await?;
+    if resp.status() == StatusCode::UNPROCESSABLE_ENTITY {
+        // the server dropped what it had, the next attempt starts over
+        *offset = 0;
+        bail!("The server refused the log: {}", resp.text().await?);
+    }
+
+    Ok(resp.error_for_status()?.json::<Finished>().await?.url)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Bytes,
+        extract::{Path as UrlPath, Query, State},
+        routing::{get, post},
+        Json, Router,
+    };
+    use serde_json::{json, Value};
+    use sha2::{Digest, Sha256};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const UPLOAD: &str = "7-upload";
+
+    /// The upload API of the server, failing the calls listed in `fail`.
+    #[derive(Default)]
+    struct Fake {
+        size: u64,
+        sha256: String,
+        data: Vec<u8>,
+        /// Calls so far, by step.
+        calls: BTreeMap<&'static str, usize>,
+        /// The steps to fail, and at which of their calls, counting from 1:
+        /// `start`, `status`, `chunk` before committing it, `chunk-lost`
+        /// after committing it, `finish`, and `corrupt`, where the server
+        /// finds the log broken and drops it.
+        fail: Vec<(&'static str, usize)>,
+    }
+
+    type Shared = Arc<Mutex<Fake>>;
+    type Reply = Result<Json<Value>, axum::http::StatusCode>;
+
+    impl Fake {
+        /// Count a call of `step`, whether it is to fail.
+        fn fails(&mut self, step: &'static str) -> bool {
+            let n = self.calls.entry(step).or_default();
+            *n += 1;
+            self.fail.contains(&(step, *n))
+        }
+
+        fn state(&self) -> Json<Value> {
+            Json(json!({"upload": UPLOAD, "offset": self.data.len()}))
+        }
+    }
+
+    const ERROR: axum::http::StatusCode = axum::http::StatusCode::INTERNAL_SERVER_ERROR;
+
+    async fn start(State(fake): State<Shared>, Json(req): Json<Value>) -> Reply {
+        let mut fake = fake.lock().unwrap();
+        if fake.fails("start") {
+            return Err(ERROR);
+        }
+        fake.size = req["size"].as_u64().unwrap();
+        fake.sha256 = req["sha256"].as_str().unwrap().to_string();
+
+        Ok(fake.state())
+    }
+
+    async fn status(State(fake): State<Shared>, UrlPath(_): UrlPath<String>) -> Reply {
+        let mut fake = fake.lock().unwrap();
+        if fake.fails("status") {
+            return Err(ERROR);
+        }
+
+        Ok(fake.state())
+    }
+
+    async fn chunk(
+        State(fake): State<Shared>,
+        UrlPath(_): UrlPath<String>,
+        Query(q): Query<BTreeMap<String, u64>>,
+        body: Bytes,
+    ) -> Reply {
+        let mut fake = fake.lock().unwrap();
+        if fake.fails("chunk") {
+            return Err(ERROR);
+        }
+        if q["offset"] != fake.data.len() as u64 {
+            return Err(axum::http::StatusCode::CONFLICT);
+        }
+        fake.data.extend_from_slice(&body);
+        if fake.fails("chunk-lost") {
+            return Err(ERROR);
+        }
+
+        Ok(fake.state())
+    }
+
+    async fn finish(State(fake): State<Shared>, UrlPath(_): UrlPath<String>) -> Reply {
+        let mut fake = fake.lock().unwrap();
+        if fake.fails("finish") {
+            return Err(ERROR);
+        }
+        let sha256 = format!("{:x}", Sha256::digest(&fake.data));
+        if fake.fails("corrupt") || fake.data.len() as u64 != fake.size || sha256 != fake.sha256 {
+            fake.data.clear();
+            return Err(axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+        }
+
+        Ok(Json(json!({"url": "https://example.org/logs/7.log"})))
+    }
+
+    /// Upload a log through a server failing `fail`, returning the outcome
+    /// and what the server ended up with.
+    async fn upload_with(
+        name: &str,
+        fail: &[(&'static str, usize)],
+    ) -> (RetryOutcome, Option<String>, Fake, Vec<u8>) {
+        let fake = Shared::new(Mutex::new(Fake {
+            fail: fail.to_vec(),
+            ..Default::default()
+        }));
+        let app = Router::new()
+            .route("/logs/uploads", post(start))
+            .route("/logs/uploads/:upload", get(status).put(chunk))
+            .route("/logs/uploads/:upload/finish", post(finish))
+            .with_state(fake.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let log = (0..1000)
+            .map(|i| format!("line {i}\n"))
+            .collect::<String>()
+            .into_bytes();
+        let path = std::env::temp_dir().join(format!(
+            "shipit-logupload-{name}-{}.log",
+            std::process::id()
+        ));
+        tokio::fs::write(&path, &log).await.unwrap();
+
+        let client = Client::new();
+        let target = Target {
+            client: &client,
+            uri: &uri,
+            secret: "secret",
+            worker: "w1",
+            id: 7,
+            arch: "amd64",
+        };
+        let policy = RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            budget: Duration::from_secs(60),
+        };
+        let (outcome, url) = upload(
+            &target,
+            &path,
+            "7.log",
+            1000,
+            &policy,
+            &mut JobLog::memory(),
+        )
+        .await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        let fake = std::mem::take(&mut *fake.lock().unwrap());
+        (outcome, url, fake, log)
+    }
+
+    #[tokio::test]
+    async fn an_upload_without_failures_takes_one_attempt() {
+        let (outcome, url, fake, log) = upload_with("clean", &[]).await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 1);
+        assert_eq!(url.as_deref(), Some("https://example.org/logs/7.log"));
+        assert_eq!(fake.data, log);
+        // 8890 bytes in chunks of 1000
+        assert_eq!(fake.calls["chunk"], 9);
+    }
+
+    #[tokio::test]
+    async fn a_failed_start_is_tried_again() {
+        let (outcome, url, fake, log) = upload_with("start", &[("start", 1)]).await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 2);
+        assert!(url.is_some());
+        assert_eq!(fake.data, log);
+    }
+
+    #[tokio::test]
+    async fn a_failed_chunk_resumes_at_the_committed_offset() {
+        let (outcome, _, fake, log) = upload_with("chunk", &[("chunk", 4)]).await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 2);
+        assert_eq!(fake.calls["status"], 1);
+        assert_eq!(fake.data, log);
+        // nothing sent twice
+        assert_eq!(fake.calls["chunk"], 10);
+    }
+
+    #[tokio::test]
+    async fn a_chunk_whose_answer_was_lost_is_not_sent_again() {
+        let (outcome, _, fake, log) = upload_with("lost", &[("chunk-lost", 4)]).await;
+
+        assert!(outcome.success);
+        assert_eq!(fake.data, log);
+        assert_eq!(fake.calls["chunk"], 9);
+    }
+
+    #[tokio::test]
+    async fn a_failed_offset_query_is_tried_again() {
+        let (outcome, _, fake, log) = upload_with("status", &[("chunk", 4), ("status", 1)]).await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(fake.calls["status"], 2);
+        assert_eq!(fake.data, log);
+    }
+
+    #[tokio::test]
+    async fn a_failed_finish_is_tried_again() {
+        let (outcome, url, fake, log) = upload_with("finish", &[("finish", 1)]).await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 2);
+        assert!(url.is_some());
+        assert_eq!(fake.data, log);
+        assert_eq!(fake.calls["chunk"], 9);
+    }
+
+    #[tokio::test]
+    async fn a_log_the_server_drops_is_sent_again_from_the_start() {
+        let (outcome, url, fake, log) = upload_with("corrupt", &[("corrupt", 1)]).await;
+
+        assert!(outcome.success);
+        assert_eq!(outcome.attempts, 2);
+        assert!(url.is_some());
+        assert_eq!(fake.data, log);
+        assert_eq!(fake.calls["chunk"], 18);
+    }
+
+    #[tokio::test]
+    async fn a_server_that_keeps_failing_is_given_up_on() {
+        // one chunk gets through, then nothing
+        let mut fail = (1..=10).map(|n| ("status", n)).collect::<Vec<_>>();
+        fail.push(("chunk", 2));
+        let (outcome, url, fake, _) = upload_with("down", &fail).await;
+
+        assert!(!outcome.success);
+        assert!(url.is_none());
+        assert_eq!(outcome.attempts, 4);
+        assert_eq!(fake.data.len(), 1000);
+        assert!(outcome.last_status.unwrap().contains("500"));
+    }
+}
@@ -1,43 +1,181 @@
-use std::{env::current_dir, fmt::Display, path::Path, process::Output, time::Duration};
+mod artifact;
+mod cache;
+mod checksum;
+mod classify;
+mod clean;
+mod clock;
+mod freshness;
+mod guard;
+mod joblog;
+mod logproc;
+mod logupload;
+mod manifest;
+mod pong;
+mod preflight;
+mod prune;
+mod redact;
+mod retry;
+mod runner;
+mod selftest;
+mod spool;
+mod ssh;
+mod timeline;
+mod torrent;
+mod transfer;
+mod update;
+mod vitals;
 
+use std::{
+    collections::BTreeMap,
+    env::current_dir,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use cache::CachePolicy;
 use chrono::Local;
-use eyre::OptionExt;
-use reqwest::{Client, ClientBuilder};
+use classify::Classifier;
+use clean::Clean;
+use clock::ClockCheck;
+use eyre::{bail, eyre, OptionExt};
+use freshness::Freshness;
+use guard::ClaimGuard;
+use joblog::JobLog;
+use logproc::{LogPolicy, ENV_HEADER, FULL_LOG_DIR};
+use manifest::{ChecksumSource, Destination, Manifest, Publish};
+use preflight::{Preflight, Target, Unreachable};
+use prune::Retention;
+use reqwest::{Client, ClientBuilder, StatusCode};
+use retry::{Retries, RetryOutcome, RetryPolicy};
+use runner::{CommandRunner, ProcessRunner, RunAs};
 use serde::{Deserialize, Serialize};
+use shipit_common::{
+    ArtifactPush, BuildType, Channel, Envelope, Failure, Resume, Staleness, Step, UploadProgress,
+    DEFAULT_SET, STAGE_PUBLISHING, STATUS_VERSION,
+};
+use spool::Upload;
+use ssh::{shell_quote, SshConfig};
+use timeline::{step_name, Timeline};
 use tokio::{
-    fs::{self, create_dir_all, read_dir},
-    process::Command,
+    fs::{self, create_dir_all},
     time::{sleep, Instant},
 };
+use torrent::Torrents;
 use tracing::{error, info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use transfer::Transfer;
+use update::{SelfUpdate, VERSION};
+use vitals::{Vitals, VitalsCheck};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Build {
     pub id: i64,
     pub arch: String,
+    #[serde(with = "shipit_common::legacy")]
     pub build_type: BuildType,
+    #[serde(default)]
+    pub allow_partial: bool,
+    #[serde(default)]
+    pub channel: Channel,
+    /// Variables set for the build script.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Appended to the arguments of the build script.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Leave the outputs of earlier builds in place, for debugging.
+    #[serde(default)]
+    pub no_clean: bool,
+    #[serde(default)]
+    pub resume: Option<Resume>,
+    /// Why it is built, as requested with `--note`.
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Reuse what the last livekit build of the arch left, see
+    /// [`cache`].
+    #[serde(default)]
+    pub incremental: bool,
+    /// Of a release, such as old-world for loongarch64. Handed to the
+    /// release script as [`FLAVOR_ENV`], and the images go below a
+    /// directory of that name, see [`Publish::render`].
+    #[serde(default)]
+    pub flavor: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub enum BuildType {
-    Livekit,
-    Release(Vec<String>),
-}
+/// Set for `generate-releases.sh` to the flavor of the release, unset for
+/// releases without.
+const FLAVOR_ENV: &str = "AOSCBOOTSTRAP_FLAVOR";
 
-impl Display for BuildType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            BuildType::Livekit => write!(f, "livekit"),
-            BuildType::Release(_) => write!(f, "release"),
+impl Build {
+    /// Run the build script with `args` through bash, with the variables and
+    /// extra arguments the build was requested with and `extra_env`, as
+    /// `run_as` if set.
+    fn script_command(
+        &self,
+        mut args: Vec<String>,
+        extra_env: &[(&str, &str)],
+        run_as: Option<&RunAs>,
+    ) -> (&'static str, Vec<String>) {
+        args.extend(self.args.iter().cloned());
+        if self.env.is_empty() && extra_env.is_empty() && run_as.is_none() {
+            return ("bash", args);
         }
+
+        let mut v = match run_as {
+            Some(r) => {
+                let mut v = r.setpriv_args();
+                v.push("env".to_string());
+                v
+            }
+            None => vec![],
+        };
+        v.extend(self.env.iter().map(|(k, v)| format!("{k}={v}")));
+        v.extend(extra_env.iter().map(|(k, v)| format!("{k}={v}")));
+        v.push("bash".to_string());
+        v.extend(args);
+
+        (if run_as.is_some() { "setpriv" } else { "env" }, v)
     }
 }
 
+/// Build types this worker knows, told to the server so that it hands out
+/// no others.
+const BUILD_TYPES: &str = "livekit,release,rootfs,repush";
+
+/// A job is a [`Build`], unless its type is newer than this worker.
+type Status = shipit_common::Status<serde_json::Value>;
+
+/// `/workerisstarted` as answered by servers older than
+/// [`STATUS_VERSION`].
 #[derive(Deserialize)]
-enum Status {
-    Working(Build),
+enum LegacyStatus {
+    Working(serde_json::Value),
     Pending,
+    Ping(i64),
+}
+
+/// Either format, a server is upgraded before or after its workers.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StatusReply {
+    Versioned(Envelope<serde_json::Value>),
+    Legacy(LegacyStatus),
+}
+
+impl From<StatusReply> for Status {
+    fn from(r: StatusReply) -> Self {
+        match r {
+            StatusReply::Versioned(e) => e.status,
+            StatusReply::Legacy(LegacyStatus::Working(job)) => Status::Working { job },
+            StatusReply::Legacy(LegacyStatus::Pending) => Status::Pending,
+            StatusReply::Legacy(LegacyStatus::Ping(id)) => Status::Ping { id, check: false },
+        }
+    }
 }
 
 #[tokio::main]
@@ -48,6 +186,7 @@ async fn main() -> eyre::Result<()> {
         tracing_subscriber::registry()
             .with(
                 fmt::layer()
+                    .with_writer(redact::Stdout)
                     .event_format(
                         tracing_subscriber::fmt::format()
                             .with_file(true)
@@ -60,6 +199,7 @@ async fn main() -> eyre::Result<()> {
         tracing_subscriber::registry()
             .with(
                 fmt::layer()
+                    .with_writer(redact::Stdout)
                     .event_format(
                         tracing_subscriber::fmt::format()
                             .with_file(true)
@@ -73,13 +213,81 @@ async fn main() -> eyre::Result<()> {
     dotenvy::dotenv().ok();
     let arch = libaosc::arch::get_arch_name().ok_or_eyre("Unsupport arch")?;
     let client = ClientBuilder::new().user_agent("shipit_worker").build()?;
-    let server_uri = std::env::var("shipit_uri")?;
-    let secret = std::env::var("shipit_secret")?;
-    let ssh_key = std::env::var("upload_ssh_key")?;
-    let host = std::env::var("rsync_host")?;
+    let server = Server::from_env(client, arch);
+    let log_policy = LogPolicy::from_env()?;
+    let livekit_publish = Publish::from_env(&["livekit", "image"], "/lookaside/private/aosc-os")?;
+    let release_publish = Publish::from_env(&["release", "image"], "/lookaside/private/aosc-os")?;
+    let rootfs_publish = Publish::from_env(&["rootfs"], "/lookaside/private/aosc-os/rootfs")?;
+    let log_publish = {
+        let mut p = Publish::from_env(&["log"], "/buildit/logs")?;
+        p.nightly_subdir = false;
+        p.base_url
+            .get_or_insert_with(|| "https://buildit.aosc.io/logs".to_string());
+        p
+    };
+    let mut publishes = vec![
+        ("livekit", &livekit_publish),
+        ("release", &release_publish),
+        ("rootfs", &rootfs_publish),
+    ];
+    if log_policy.server_chunk_bytes.is_none() {
+        publishes.push(("log", &log_publish));
+    }
+    let upload_targets = preflight::targets(&publishes);
+
+    if std::env::args().nth(1).as_deref() == Some("check") {
+        return selftest::cli(server, &upload_targets, arch).await;
+    }
+    let server = server?;
+
+    let config = Config {
+        ssh: SshConfig::from_env().await?,
+        log_policy,
+        retries: Retries::from_env()?,
+        livekit_publish,
+        livekit_clean: Clean::from_env()?,
+        livekit_cache: CachePolicy::from_env()?,
+        release_publish,
+        rootfs_publish,
+        log_publish,
+        upload_targets,
+        rootfs_script: std::env::var("rootfs_script")
+            .unwrap_or_else(|_| "./contrib/generate-rootfs.sh".to_string()),
+        nightly_retention: Retention::from_env()?,
+        artifact_max_bytes: match std::env::var("artifact_max_gib") {
+            Ok(x) => x.parse::<u64>()? << 30,
+            Err(_) => 16 << 30,
+        },
+        classifier: Classifier::from_env().await?,
+        run_as: RunAs::from_env().await?,
+        freshness: Freshness::from_env()?,
+        torrents: Torrents::from_env()?,
+    };
+    redact::init(&config.ssh.key).await?;
+
+    let mut self_update = SelfUpdate::from_env()?;
+    let mut clock = ClockCheck::from_env()?;
+    let mut preflight = Preflight::from_env()?;
+    let mut vitals = VitalsCheck::default();
+    let mut guard = ClaimGuard::from_env()?;
 
     loop {
-        if let Err(e) = worker(&client, &server_uri, &secret, arch, &ssh_key, &host).await {
+        // between two jobs, never during one
+        if let Some(ref mut u) = self_update {
+            u.check(&server, arch).await;
+        }
+
+        let skew = clock.skew(&server).await;
+        let unreachable = preflight.check(&config.ssh, &config.upload_targets).await;
+        let vitals = vitals.get().await;
+        let report = Report {
+            skew,
+            clock: clock.measured(),
+            unreachable,
+            deferring: guard.check(&vitals).await,
+            vitals,
+        };
+        if let Err(e) = worker(&server, arch, &config, &report, &ProcessRunner).await {
             error!("{e}");
         }
 
@@ -87,128 +295,849 @@ async fn main() -> eyre::Result<()> {
     }
 }
 
-#[derive(Serialize)]
+struct Config {
+    ssh: SshConfig,
+    log_policy: LogPolicy,
+    retries: Retries,
+    livekit_publish: Publish,
+    /// What is removed from the aosc-mklive checkout before a build.
+    livekit_clean: Clean,
+    /// When what `--incremental` builds reuse is too old.
+    livekit_cache: CachePolicy,
+    release_publish: Publish,
+    /// Where rootfs tarballs go, apart from the ISOs.
+    rootfs_publish: Publish,
+    /// Where job logs go.
+    log_publish: Publish,
+    /// The directories above checked before taking a job, see
+    /// [`preflight`].
+    upload_targets: Vec<Target>,
+    /// aoscbootstrap script building rootfs tarballs, given the variants.
+    rootfs_script: String,
+    /// Which nightly artifacts are removed from the upload host.
+    nightly_retention: Retention,
+    /// Larger images are refused, see `artifact::suspicious`.
+    artifact_max_bytes: u64,
+    classifier: Classifier,
+    /// Who the build scripts run as, the worker user if unset.
+    run_as: Option<RunAs>,
+    /// How far behind the canonical repositories the script checkouts may
+    /// be before a build is flagged.
+    freshness: Freshness,
+    /// Torrents for the ISOs, if there are trackers to announce to.
+    torrents: Option<Torrents>,
+}
+
+#[derive(Serialize, Deserialize)]
 struct DoneRequest {
     id: i64,
     arch: String,
-    build_type: BuildTypeRequest,
+    build_type: BuildType,
     has_error: bool,
     log_url: Option<String>,
     push_success: bool,
+    manifest: Option<Manifest>,
+    worker: String,
+    missing_variants: Vec<String>,
+    /// Uploading the images, if it was attempted.
+    push: Option<RetryOutcome>,
+    log_push: RetryOutcome,
+    #[serde(default)]
+    steps: Vec<Step>,
+    /// Seconds spent on git, the build script, checksums, uploads and the
+    /// log upload.
+    #[serde(default)]
+    phase_durations: BTreeMap<String, u64>,
+    /// Why the build or an upload failed, if the log tells.
+    #[serde(default)]
+    failure: Option<Failure>,
+    /// Paths of the artifacts kept after failing to upload them.
+    #[serde(default)]
+    failed_artifacts: Vec<String>,
+    /// How uploading each artifact went.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    artifact_pushes: Vec<ArtifactPush>,
+    /// The commit of the build scripts, aosc-mklive for a livekit and
+    /// aoscbootstrap for a release or rootfs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scripts_commit: Option<String>,
+    /// Built from a checkout further behind the canonical repository than
+    /// allowed, see `staleness`.
+    #[serde(default)]
+    stale_scripts: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    staleness: Option<Staleness>,
 }
 
 #[derive(Serialize)]
-struct BuildTypeRequest {
+struct HeartbeatRequest<'a> {
+    id: i64,
+    arch: &'a str,
+    worker: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<Step>,
+    /// So the server resumes dispatch as soon as space is freed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    free_disk: Option<u64>,
+    /// Acknowledges a drain the server told of.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    draining: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upload: Option<UploadProgress>,
+}
+
+/// Older servers answer with an empty body.
+#[derive(Deserialize, Default)]
+struct HeartbeatReply {
+    #[serde(default)]
+    draining: bool,
+    /// The build was cancelled, stop it.
+    #[serde(default)]
+    cancel: bool,
+}
+
+/// The weight of each of `arches`: 100 for the native one, the first, 10
+/// for those it cross builds, unless `arch_weights` sets some, e.g.
+/// `riscv64=50`. While a worker weighing an arch more is around, the server
+/// holds new jobs of the arch back from the others for a while.
+fn weights(arches: &[String]) -> eyre::Result<Vec<u32>> {
+    let mut set = BTreeMap::new();
+    let var = std::env::var("arch_weights").unwrap_or_default();
+    for i in var
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|x| !x.is_empty())
+    {
+        let Some((arch, weight)) = i.split_once('=') else {
+            bail!("Invalid arch_weights entry {i}, expected arch=weight");
+        };
+        if !arches.iter().any(|x| x == arch) {
+            bail!("arch_weights sets {arch}, which is not in arches");
+        }
+        let weight: u32 = weight
+            .parse()
+            .map_err(|e| eyre!("Invalid weight in arch_weights entry {i}: {e}"))?;
+        set.insert(arch, weight);
+    }
+
+    Ok(arches
+        .iter()
+        .enumerate()
+        .map(|(i, arch)| match set.get(arch.as_str()) {
+            Some(w) => *w,
+            None if i == 0 => 100,
+            None => 10,
+        })
+        .collect())
+}
+
+/// Where to report to, and as whom.
+#[derive(Clone)]
+struct Server {
+    client: Client,
+    uri: String,
+    secret: String,
     name: String,
-    variants: Option<Vec<String>>,
+    /// The arches to take jobs for, in order of preference.
+    arches: Vec<String>,
+    /// How much it wants jobs of each of `arches`, see [`weights`].
+    weights: Vec<u32>,
+    /// The pool of builds `secret` belongs to, mainline if unset.
+    pool: Option<String>,
+    /// Drained by an admin, as of the last heartbeat: no job after this one.
+    draining: Arc<AtomicBool>,
 }
 
-impl From<BuildType> for BuildTypeRequest {
-    fn from(value: BuildType) -> Self {
-        match value {
-            BuildType::Livekit => BuildTypeRequest {
-                name: "livekit".to_owned(),
-                variants: None,
-            },
-            BuildType::Release(v) => BuildTypeRequest {
-                name: "release".to_owned(),
-                variants: Some(v),
-            },
+impl Server {
+    /// `shipit_uri`, `shipit_secret`, `shipit_worker_name` (default the
+    /// hostname), `arches` (default the native one `arch`), `arch_weights`
+    /// and `shipit_pool`.
+    fn from_env(client: Client, arch: &str) -> eyre::Result<Self> {
+        let uri = std::env::var("shipit_uri").map_err(|_| eyre!("shipit_uri is not set"))?;
+        let uri = shipit_common::base_url("shipit_uri", &uri).map_err(|e| eyre!(e))?;
+        let secret =
+            std::env::var("shipit_secret").map_err(|_| eyre!("shipit_secret is not set"))?;
+        let name = std::env::var("shipit_worker_name")
+            .unwrap_or_else(|_| gethostname::gethostname().to_string_lossy().to_string());
+        // e.g. `amd64 i486` for a worker building both
+        let arches = match std::env::var("arches") {
+            Ok(x) => x
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string())
+                .collect(),
+            Err(_) => vec![arch.to_string()],
+        };
+        let weights = weights(&arches)?;
+
+        Ok(Self {
+            client,
+            uri,
+            secret,
+            name,
+            arches,
+            weights,
+            pool: std::env::var("shipit_pool").ok(),
+            draining: Arc::default(),
+        })
+    }
+
+    /// Tell the server the build is still alive, which commands it ran, at
+    /// which stage if `stage` is set, and how far the upload is if uploading.
+    /// Returns whether the build was cancelled.
+    async fn touch(
+        &self,
+        id: i64,
+        arch: &str,
+        stage: Option<&str>,
+        timeline: &Timeline,
+        upload: Option<UploadProgress>,
+    ) -> eyre::Result<bool> {
+        let path = if stage.is_some() {
+            "progress"
+        } else {
+            "heartbeat"
+        };
+
+        let reply = self
+            .client
+            .post(format!("{}/{path}", self.uri))
+            .header("secret", &self.secret)
+            .json(&HeartbeatRequest {
+                id,
+                arch,
+                worker: &self.name,
+                stage,
+                steps: timeline.steps(),
+                free_disk: vitals::free_disk().await.ok(),
+                draining: self.draining.load(Ordering::Relaxed),
+                upload,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<HeartbeatReply>()
+            .await
+            .unwrap_or_default();
+
+        if self.draining.swap(reply.draining, Ordering::Relaxed) != reply.draining {
+            if reply.draining {
+                info!("Draining: #{id} is the last build until the drain is lifted");
+            } else {
+                info!("No longer draining");
+            }
+        }
+
+        Ok(reply.cancel)
+    }
+
+    async fn progress(&self, id: i64, arch: &str, stage: &str, timeline: &Timeline) {
+        if let Err(e) = self.touch(id, arch, Some(stage), timeline, None).await {
+            warn!("Failed to report progress of #{id}: {e}");
         }
     }
 }
 
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+struct BuildOutput {
+    success: bool,
+    push: Option<RetryOutcome>,
+    manifest: Option<Manifest>,
+    missing_variants: Vec<String>,
+    /// What was uploaded, to keep if that failed.
+    upload: Option<Upload>,
+    /// Kept artifacts, if that was already taken care of.
+    failed_artifacts: Vec<String>,
+    /// How uploading each artifact went.
+    artifact_pushes: Vec<ArtifactPush>,
+    /// Why it failed, if known without looking at the log.
+    failure: Option<Failure>,
+}
+
+/// What the worker tells the server about itself when polling.
+struct Report {
+    /// The clock skew, if too large to take jobs.
+    skew: Option<i64>,
+    /// The clock skew as last measured.
+    clock: Option<i64>,
+    unreachable: Option<Unreachable>,
+    /// Why no job is to be taken now, see [`ClaimGuard`].
+    deferring: Option<String>,
+    vitals: Vitals,
+}
+
+/// Poll for a job and build it. With a clock skew the worker only tells
+/// the server about it and takes nothing, the same with an unreachable
+/// upload host if the preflight policy blocks. Jobs for an arch other than
+/// the native `arch` get `ARCH` set for the build scripts.
 async fn worker(
-    client: &Client,
-    uri: &str,
-    secret: &str,
+    server: &Server,
     arch: &str,
-    upload_ssh_key: &str,
-    host: &str,
+    config: &Config,
+    report: &Report,
+    runner: &impl CommandRunner,
 ) -> eyre::Result<()> {
+    let Config {
+        ssh,
+        log_policy,
+        retries,
+        ..
+    } = config;
+    let Server {
+        client,
+        uri,
+        secret,
+        name,
+        arches,
+        weights,
+        pool,
+        ..
+    } = server;
+
+    let mut query = vec![
+        ("arch", arches.join(",")),
+        (
+            "weights",
+            weights
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        ("worker", name.clone()),
+        ("ping", "true".to_string()),
+        ("types", BUILD_TYPES.to_string()),
+        (
+            "hostname",
+            gethostname::gethostname().to_string_lossy().to_string(),
+        ),
+        ("version", VERSION.to_string()),
+    ];
+    if let Some(pool) = pool {
+        query.push(("pool", pool.clone()));
+    }
+    if let Some(skew) = report.skew {
+        query.push(("skew", skew.to_string()));
+    }
+    if let Some(clock) = report.clock {
+        query.push(("clock", clock.to_string()));
+    }
+    if let Some([a, b, c]) = report.vitals.load_avg {
+        query.push(("load", format!("{a:.2} {b:.2} {c:.2}")));
+    }
+    if let Some(free) = report.vitals.free_disk {
+        query.push(("free_disk", free.to_string()));
+    }
+    if let Some(ref reason) = report.deferring {
+        query.push(("deferring", reason.clone()));
+    }
+    if let Some(ref u) = report.unreachable {
+        query.push(("unreachable", u.error.clone()));
+        query.push(("blocked", u.blocking.to_string()));
+    }
+
     let resp = client
         .get(format!("{}/workerisstarted", uri))
         .header("secret", secret)
-        .query(&[("arch", arch)])
+        .header("accept-version", STATUS_VERSION.to_string())
+        .query(&query)
         .send()
         .await?;
 
     let resp = resp.error_for_status()?;
-    let status = resp.json::<Status>().await?;
+    let status = Status::from(resp.json::<StatusReply>().await?);
+
+    if let Status::Unknown = status {
+        warn!("Server answered with a status this worker does not know, polling again");
+    }
+
+    if let Status::Ping { id, check } = status {
+        let checks = match check {
+            true => selftest::run(Ok(server), Ok(ssh), &config.upload_targets, arch).await,
+            false => vec![],
+        };
+        return pong::pong(server, id, checks).await;
+    }
+
+    if let Status::Working { job } = status {
+        let mut build = match Build::deserialize(&job) {
+            Ok(x) => x,
+            Err(e) => return refuse_unsupported(server, config, &job, e).await,
+        };
+
+        if let Some(request) = last_done(build.id).await {
+            warn!(
+                "Server handed out #{} again, which already finished here, its state looks stale. Reporting the result again",
+                build.id
+            );
+            return report_done(server, config, &request).await;
+        }
+
+        let native = arch;
+        let arch = &build.arch.clone();
+        if arch != native {
+            build
+                .env
+                .entry("ARCH".to_string())
+                .or_insert_with(|| arch.clone());
+        }
 
-    if let Status::Working(build) = status {
         info!("{} is started", arch);
-        let (logs, success, push_success) = match build.build_type {
-            BuildType::Livekit => build_livekit(host, upload_ssh_key, arch).await?,
-            BuildType::Release(ref variants) => {
-                build_release(arch, variants, host, upload_ssh_key).await?
-            }
+
+        let timeline = Timeline::default();
+        let transfer = Transfer::default();
+        // ends once the server tells the build was cancelled
+        let mut heartbeat = {
+            let server = server.clone();
+            let (timeline, transfer) = (timeline.clone(), transfer.clone());
+            let (id, arch) = (build.id, arch.to_string());
+            tokio::spawn(async move {
+                let mut last = Instant::now();
+                loop {
+                    // more often while uploading, for the progress
+                    let publishing = tokio::select! {
+                        () = sleep(transfer::INTERVAL) => false,
+                        () = transfer.wait_publishing() => true,
+                    };
+                    let upload = transfer.get();
+                    if !publishing && upload.is_none() && last.elapsed() < HEARTBEAT_INTERVAL {
+                        continue;
+                    }
+                    last = Instant::now();
+
+                    let stage = publishing.then_some(STAGE_PUBLISHING);
+                    match server.touch(id, &arch, stage, &timeline, upload).await {
+                        Ok(true) => return,
+                        Ok(false) => {}
+                        Err(e) => warn!("Failed to send heartbeat of #{id}: {e}"),
+                    }
+                }
+            })
         };
 
         let file_name = format!(
-            "shipit-{}-{}-{}.txt",
+            "shipit-{}-{}-{}-{}.txt",
+            build.id,
             arch,
             gethostname::gethostname().to_string_lossy(),
             Local::now().format("%Y-%m-%d-%H:%M:%S")
         );
+        let full_log_dir = Path::new(FULL_LOG_DIR);
+        create_dir_all(full_log_dir).await?;
+        if let Err(e) = logproc::prune(full_log_dir, log_policy.retention).await {
+            warn!("Failed to prune old logs: {e}");
+        }
+        if let Err(e) = spool::prune(log_policy.retention).await {
+            warn!("Failed to prune kept artifacts: {e}");
+        }
+
+        let full_log = full_log_dir.join(&file_name);
+        let mut log = JobLog::create(&full_log).await?;
+        log.timeline = timeline.clone();
+        log.transfer = transfer;
+        let mut header = format!(
+            "{ENV_HEADER}\nbuild: #{} {}\narch: {arch}\nworker: {name}\nstarted: {}\n",
+            build.id,
+            build.build_type,
+            Local::now()
+        );
+        for (k, v) in &build.env {
+            header.push_str(&format!("env: {k}={v}\n"));
+        }
+        if let Some(ref u) = report.unreachable {
+            header.push_str(&format!(
+                "preflight: upload host unreachable: {}\n",
+                u.error
+            ));
+        }
+        if !build.args.is_empty() {
+            header.push_str(&format!("args: {}\n", build.args.join(" ")));
+        }
+        if let Some(ref note) = build.note {
+            header.push_str(&format!("note: {note}\n"));
+        }
+        if build.incremental {
+            header.push_str("incremental: yes\n");
+        }
+        if let Some(ref flavor) = build.flavor {
+            header.push_str(&format!("flavor: {flavor}\n"));
+        }
+        header.push('\n');
+        log.write(header.as_bytes()).await?;
 
-        fs::write(&file_name, logs).await?;
-
-        let mut log_url = None;
-        let mut scp_log = vec![];
-        if run_logged_with_retry(
-            "scp",
-            &[
-                "-i",
-                &upload_ssh_key,
-                &format!("./{}", &file_name),
-                &format!("maintainers@{}:/buildit/logs", host),
-            ],
-            Path::new("."),
-            &mut scp_log,
+        let date = Local::now().format("%Y%m%d").to_string();
+        let dest = Destination {
+            arch,
+            channel: build.channel,
+            build_id: build.id,
+            date: &date,
+            flavor: build.flavor.as_deref(),
+        };
+
+        server.progress(build.id, arch, "building", &timeline).await;
+        let built = async {
+            match build.build_type {
+                BuildType::Livekit => build_livekit(runner, &build, config, &dest, &mut log).await,
+                BuildType::Release(ref variants) => {
+                    build_release(runner, variants, &build, &dest, config, &mut log).await
+                }
+                BuildType::Rootfs(ref variants) => {
+                    build_rootfs(runner, variants, &build, &dest, config, &mut log).await
+                }
+                BuildType::Repush { build_id } => {
+                    repush(runner, config, build_id, build.id, &mut log).await
+                }
+            }
+        };
+        // dropping the build kills what it runs
+        let output = tokio::select! {
+            output = built => Some(output),
+            _ = &mut heartbeat => None,
+        };
+        heartbeat.abort();
+        let Some(output) = output else {
+            info!("#{} was cancelled, stopped building it", build.id);
+            log.write(format!("{}: Cancelled on the server\n", Local::now()).as_bytes())
+                .await?;
+            log.flush().await?;
+            return Ok(());
+        };
+
+        // what resuming the build is checked against, and `/diff` shows
+        let scripts_commit = match build.build_type {
+            BuildType::Livekit => head_commit(runner, Path::new("aosc-mklive")).await,
+            BuildType::Release(_) | BuildType::Rootfs(_) => {
+                head_commit(runner, Path::new("aoscbootstrap")).await
+            }
+            _ => None,
+        };
+        // the build leaves the checkout where its pull put it
+        let scripts = match build.build_type {
+            BuildType::Livekit => Some(("aosc-mklive", freshness::MKLIVE_REPO)),
+            BuildType::Release(_) | BuildType::Rootfs(_) => {
+                Some(("aoscbootstrap", freshness::AOSCBOOTSTRAP_REPO))
+            }
+            _ => None,
+        };
+        let staleness = match scripts {
+            Some((dir, url)) => {
+                freshness::check(
+                    runner,
+                    config.run_as.as_ref(),
+                    Path::new(dir),
+                    url,
+                    &config.freshness,
+                    &mut log,
+                )
+                .await?
+            }
+            None => None,
+        };
+
+        let BuildOutput {
+            success,
+            push,
+            mut manifest,
+            missing_variants,
+            upload,
+            mut failed_artifacts,
+            artifact_pushes,
+            failure,
+        } = output?;
+        if let Some(ref mut m) = manifest {
+            m.note = build.note.clone();
+            m.flavor = build.flavor.clone();
+        }
+
+        let pushed = push.as_ref().is_some_and(|x| x.success);
+        let unpushed = artifact_pushes
+            .iter()
+            .filter(|x| !x.success)
+            .map(|x| x.path.clone())
+            .collect::<Vec<_>>();
+        if let (Some(upload), Some(m), false) = (upload, manifest.as_ref(), unpushed.is_empty()) {
+            match spool::keep(build.id, upload, m, &unpushed).await {
+                Ok(x) => {
+                    log.write(
+                        format!(
+                            "{}: Kept {} artifacts for a repush in {}\n",
+                            Local::now(),
+                            x.len(),
+                            spool::PUSH_FAILED_DIR
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                    failed_artifacts = x;
+                }
+                Err(e) => warn!("Failed to keep the artifacts of #{}: {e}", build.id),
+            }
+        }
+
+        let failure = match failure {
+            None if !success || !pushed => config.classifier.classify(&log.tail()),
+            x => x,
+        };
+        if build.channel == Channel::Nightly && success && pushed {
+            if let Some(dir) = manifest.as_ref().and_then(|x| x.remote_dir.as_deref()) {
+                server
+                    .progress(build.id, arch, "pruning nightlies", &timeline)
+                    .await;
+                if let Err(e) = prune_nightly(runner, config, dir, &mut log).await {
+                    warn!("Failed to prune nightly artifacts in {dir}: {e}");
+                    log.write(
+                        format!("{}: Failed to prune nightly artifacts: {e}\n", Local::now())
+                            .as_bytes(),
+                    )
+                    .await?;
+                }
+            }
+        }
+
+        server
+            .progress(build.id, arch, "uploading log", &timeline)
+            .await;
+
+        log.flush().await?;
+        drop(log);
+        logproc::prepare_upload(
+            &full_log,
+            Path::new(&file_name),
+            log_policy.max_upload_bytes,
         )
-        .await
-        .unwrap_or(false)
-        {
-            log_url = Some(format!("https://buildit.aosc.io/logs/{file_name}"));
+        .await?;
+        timeline.close();
+        let steps = timeline.steps();
+        let header = format!(
+            "TIMELINE:\n{}\n",
+            shipit_common::timeline(&steps, timeline::now())
+        );
+        logproc::prepend(Path::new(&file_name), header.as_bytes()).await?;
+
+        let mut scp_log = JobLog::memory();
+        let (log_push, log_url) = match log_policy.server_chunk_bytes {
+            Some(chunk_bytes) => {
+                let target = logupload::Target {
+                    client: &server.client,
+                    uri: &server.uri,
+                    secret: &server.secret,
+                    worker: name,
+                    id: build.id,
+                    arch,
+                };
+                logupload::upload(
+                    &target,
+                    Path::new(&file_name),
+                    &file_name,
+                    chunk_bytes,
+                    &retries.log,
+                    &mut scp_log,
+                )
+                .await
+            }
+            None => {
+                let log_publish = config.log_publish.render(&dest);
+                ensure_remote_dir(runner, ssh, &log_publish.dir, &mut scp_log).await?;
+                let scp_args = ssh.scp_args(&[Path::new(&file_name)], &log_publish.dir, false);
+                let log_push = run_logged_with_retry(
+                    runner,
+                    "scp",
+                    &scp_args.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+                    Path::new("."),
+                    &mut scp_log,
+                    &retries.log,
+                )
+                .await;
+                let log_url = log_publish
+                    .base_url
+                    .filter(|_| log_push.success)
+                    .map(|x| format!("{}/{file_name}", x.trim_end_matches('/')));
+                (log_push, log_url)
+            }
+        };
+        let mut phase_durations = timeline.phase_durations();
+        phase_durations.insert("log_upload".to_string(), log_push.total_secs as u64);
+
+        let failure = match failure {
+            None if !log_push.success => config.classifier.classify(&scp_log.tail()),
+            x => x,
+        };
+
+        if log_push.success {
             tokio::spawn(async move { fs::remove_file(file_name).await });
         } else {
-            error!(
-                "Failed to scp log to repo: {}",
-                String::from_utf8_lossy(&scp_log)
-            );
+            error!("Failed to upload log: {}", scp_log.tail());
             let dir = Path::new("./push_failed_logs");
             let to = dir.join(&file_name);
             fs::create_dir_all(dir).await?;
-            fs::copy(file_name, to).await?;
+            fs::rename(&file_name, to).await?;
         };
 
         let request = DoneRequest {
             id: build.id,
             arch: build.arch,
-            build_type: BuildTypeRequest::from(build.build_type),
+            build_type: build.build_type,
             has_error: !success,
-            push_success,
+            push_success: pushed,
             log_url,
+            manifest,
+            worker: name.clone(),
+            missing_variants,
+            push,
+            log_push,
+            steps,
+            phase_durations,
+            failure,
+            failed_artifacts,
+            artifact_pushes,
+            scripts_commit,
+            stale_scripts: staleness.is_some(),
+            staleness,
         };
 
-        for i in 1..=3 {
-            let resp = client
-                .post(format!("{uri}/done"))
-                .header("secret", secret)
-                .json(&request)
-                .send()
-                .await
-                .and_then(|r| r.error_for_status());
-
-            match resp {
-                Ok(_) => break,
-                Err(e) => {
-                    error!("{e}");
-                    if i == 3 {
-                        error!("Failed too many times to POST /done");
-                        return Err(e.into());
-                    }
+        // remember the result before reporting it, so that it is not built
+        // again should the server hand the job out once more
+        let data = serde_json::to_vec(&request)?;
+        if let Err(e) = fs::write(LAST_DONE_FILE, redact::bytes(&data)).await {
+            warn!("Failed to save the result of #{}: {e}", request.id);
+        }
+
+        report_done(server, config, &request).await?;
+    }
+
+    Ok(())
+}
+
+/// Report a job of a type this worker does not know as failed, so that it
+/// does not stay claimed and its requester learns why.
+async fn refuse_unsupported(
+    server: &Server,
+    config: &Config,
+    job: &serde_json::Value,
+    err: serde_json::Error,
+) -> eyre::Result<()> {
+    let (Some(id), Some(arch)) = (job["id"].as_i64(), job["arch"].as_str()) else {
+        bail!("Unreadable job {job}: {err}");
+    };
+
+    // `"Livekit"` or `{"Release": [...]}` here, `{"name": "release",
+    // "variants": [...]}` on `/done`
+    let (name, variants) = match &job["build_type"] {
+        serde_json::Value::String(s) => (s.to_lowercase(), None),
+        serde_json::Value::Object(o) if o.len() == 1 => {
+            let (k, v) = o.iter().next().unwrap();
+            (k.to_lowercase(), Some(v.clone()))
+        }
+        x => bail!("Unreadable build type of #{id}: {x}"),
+    };
+    let summary = format!(
+        "worker does not support build type {name} (version {})",
+        update::VERSION
+    );
+    error!("Refusing #{id}: {summary}");
+
+    let request = DoneRequest {
+        id,
+        arch: arch.to_string(),
+        // replaced below, this worker cannot represent it
+        build_type: BuildType::Livekit,
+        has_error: true,
+        log_url: None,
+        push_success: false,
+        manifest: None,
+        worker: server.name.clone(),
+        missing_variants: vec![],
+        push: None,
+        log_push: RetryOutcome::default(),
+        steps: vec![],
+        phase_durations: BTreeMap::new(),
+        failure: Some(Failure {
+            class: "unsupported".to_string(),
+            summary,
+            excerpt: err.to_string(),
+            line: None,
+        }),
+        failed_artifacts: vec![],
+        artifact_pushes: vec![],
+        scripts_commit: None,
+        stale_scripts: false,
+        staleness: None,
+    };
+    let mut body = serde_json::to_value(&request)?;
+    body["build_type"] = serde_json::json!({ "name": name, "variants": variants });
+
+    post_done(server, config, id, body).await
+}
+
+/// Result of the last build of this worker.
+const LAST_DONE_FILE: &str = "shipit-last-done.json";
+
+/// The saved result of `id` if it is the last build this worker finished.
+async fn last_done(id: i64) -> Option<DoneRequest> {
+    let s = fs::read(LAST_DONE_FILE).await.ok()?;
+    let request: DoneRequest = serde_json::from_slice(&s)
+        .map_err(|e| warn!("Ignoring unreadable {LAST_DONE_FILE}: {e}"))
+        .ok()?;
+
+    (request.id == id).then_some(request)
+}
+
+async fn report_done(server: &Server, config: &Config, request: &DoneRequest) -> eyre::Result<()> {
+    post_done(server, config, request.id, serde_json::to_value(request)?).await
+}
+
+/// Chunks of a report uploaded as a log, see [`upload_report`].
+const REPORT_CHUNK_BYTES: u64 = 1 << 20;
+
+/// POST `body`, the report of build `id`, to `/done`, with retries.
+async fn post_done(
+    server: &Server,
+    config: &Config,
+    id: i64,
+    mut body: serde_json::Value,
+) -> eyre::Result<()> {
+    for i in 1..=3 {
+        let resp = server
+            .client
+            .post(format!("{}/done", server.uri))
+            .header("secret", &server.secret)
+            .json(&body)
+            .send()
+            .await;
+
+        if resp
+            .as_ref()
+            .is_ok_and(|r| r.status() == StatusCode::PAYLOAD_TOO_LARGE)
+        {
+            // the same body will never fit, it goes up in chunks as a log
+            // and the server reads it from there
+            warn!("Report of #{id} is too large for /done, uploading it as a log");
+            let report = upload_report(server, config, id, &body).await;
+            body["steps"] = serde_json::json!([]);
+            for key in ["push", "log_push", "failure"] {
+                if let Some(o) = body.get_mut(key).and_then(|x| x.as_object_mut()) {
+                    o.remove("excerpt");
+                }
+            }
+            match report {
+                Some(name) => body["report"] = serde_json::json!(name),
+                // a server without log uploads gets what it can take,
+                // rather than no report at all
+                None => error!(
+                    "Failed to upload the report of #{id}, sending it without steps and output excerpts"
+                ),
+            }
+        }
+
+        match resp.and_then(|r| r.error_for_status()) {
+            Ok(_) => break,
+            Err(e) => {
+                error!("{e}");
+                if i == 3 {
+                    error!("Failed too many times to POST /done");
+                    return Err(e.into());
                 }
             }
         }
@@ -217,98 +1146,251 @@ async fn worker(
     Ok(())
 }
 
+/// Upload `body`, the report of build `id`, through the chunked log upload
+/// of the server. Returns the name the server has it under.
+async fn upload_report(
+    server: &Server,
+    config: &Config,
+    id: i64,
+    body: &serde_json::Value,
+) -> Option<String> {
+    let name = format!("shipit-{id}-report.json");
+    if let Err(e) = fs::write(&name, body.to_string()).await {
+        error!("Failed to write {name}: {e}");
+        return None;
+    }
+
+    let target = logupload::Target {
+        client: &server.client,
+        uri: &server.uri,
+        secret: &server.secret,
+        worker: &server.name,
+        id,
+        arch: body["arch"].as_str().unwrap_or_default(),
+    };
+    let mut log = JobLog::memory();
+    let (outcome, url) = logupload::upload(
+        &target,
+        Path::new(&name),
+        &name,
+        REPORT_CHUNK_BYTES,
+        &config.retries.log,
+        &mut log,
+    )
+    .await;
+    let _ = fs::remove_file(&name).await;
+    if !outcome.success {
+        warn!("Failed to upload the report of #{id}: {}", log.tail());
+    }
+
+    url.map(|_| name)
+}
+
 async fn build_livekit(
-    host: &str,
-    upload_ssh_key: &str,
-    arch: &str,
-) -> eyre::Result<(Vec<u8>, bool, bool)> {
+    runner: &impl CommandRunner,
+    build: &Build,
+    config: &Config,
+    dest: &Destination<'_>,
+    log: &mut JobLog,
+) -> eyre::Result<BuildOutput> {
+    let Config { retries, .. } = config;
+    let Destination { arch, channel, .. } = *dest;
+    let publish = config.livekit_publish.render(dest);
     let mklive_dir = Path::new("aosc-mklive");
-    let mut logs = vec![];
     if !mklive_dir.is_dir() {
-        get_output_logged(
+        run_logged_with_retry(
+            runner,
             "git",
-            &["clone", "https://github.com/AOSC-Dev/aosc-mklive"],
+            &["clone", freshness::MKLIVE_REPO],
             Path::new("."),
-            &mut logs,
+            log,
+            &retries.git,
         )
-        .await?;
+        .await;
+    }
+    run_logged_with_retry(runner, "git", &["pull"], mklive_dir, log, &retries.git).await;
+    if build.no_clean {
+        let msg = format!(
+            "{}: Not cleaning {} (--no-clean)\n",
+            Local::now(),
+            mklive_dir.display()
+        );
+        log.write(msg.as_bytes()).await?;
+    } else {
+        let step = log.timeline.start("clean".to_string());
+        let res = clean::clean(mklive_dir, &config.livekit_clean, log).await;
+        log.timeline.finish(step, res.as_ref().ok().map(|_| 0));
+        res?;
     }
-    get_output_logged("git", &["pull"], mklive_dir, &mut logs).await?;
-    let mut dir = read_dir(mklive_dir).await?;
-    loop {
-        if let Ok(Some(i)) = dir.next_entry().await {
-            let path = i.path();
-
-            if path
-                .extension()
-                .map(|x| x == "iso" || x == "sha256sum")
-                .unwrap_or(false)
-            {
-                fs::remove_file(i.path()).await?;
-            }
 
-            let name = path.file_name();
-            if name
-                .map(|x| {
-                    ["livekit", "iso", "to-squash", "memtest", "sb"]
-                        .contains(&x.to_string_lossy().to_string().as_str())
-                })
-                .unwrap_or(false)
-            {
-                fs::remove_dir_all(i.path()).await?;
-            }
-        } else {
-            break;
+    let commit = head_commit(runner, mklive_dir).await;
+    let incremental_from = if build.incremental {
+        cache::restore(
+            &config.livekit_cache,
+            arch,
+            mklive_dir,
+            commit.as_deref(),
+            log,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to restore the livekit cache of {arch}: {e}");
+            None
+        })
+    } else {
+        None
+    };
+
+    let before = clean::snapshot(mklive_dir).await?;
+    let extra_env = incremental_from.map(|_| cache::INCREMENTAL_ENV);
+    let (cmd, args) = build.script_command(
+        vec!["./aosc-mklive.sh".to_string()],
+        extra_env.as_slice(),
+        config.run_as.as_ref(),
+    );
+    let args = args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let mklive = get_output_logged(runner, cmd, &args, mklive_dir, log).await?;
+    log.transfer.publishing();
+    let success = mklive.success();
+    // only what this build produced is uploaded, not what an earlier one or
+    // anyone else left in the checkout
+    let outputs = clean::outputs(mklive_dir, &before).await?;
+
+    // a failed build may have left the trees half done
+    if let (true, true, Some(ref commit)) = (build.incremental, success, commit) {
+        if let Err(e) = cache::save(arch, mklive_dir, build.id, commit, log).await {
+            warn!("Failed to keep the livekit cache of {arch}: {e}");
         }
     }
-    let mklive = get_output_logged("bash", &["./aosc-mklive.sh"], mklive_dir, &mut logs).await?;
-    let success = mklive.status.success();
 
     let dir = current_dir()?;
     let os_dir_str = format!("os-{}", arch);
     let livekit_dir = dir.join(&os_dir_str).join("livekit");
+    if livekit_dir.exists() {
+        fs::remove_dir_all(&livekit_dir).await?;
+    }
     create_dir_all(&livekit_dir).await?;
 
-    let mut dir_iter = read_dir(mklive_dir).await?;
-    loop {
-        if let Ok(Some(i)) = dir_iter.next_entry().await {
-            if i.path()
-                .extension()
-                .map(|x| x == "iso" || x == "sha256sum")
-                .unwrap_or(false)
-            {
-                fs::copy(i.path(), &livekit_dir).await?;
+    let root = fs::canonicalize(mklive_dir).await?;
+    let mut skipped = vec![];
+    for file_name in &outputs {
+        let path = mklive_dir.join(file_name);
+        let Some(ext) = path.extension().and_then(|x| x.to_str()) else {
+            continue;
+        };
+
+        let name = match (ext, channel) {
+            ("iso" | "sha256sum", Channel::Release) => file_name.to_string_lossy().to_string(),
+            // renamed, so the checksum files of the script no longer match
+            // and are computed again below
+            ("iso", Channel::Nightly) => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                format!("{stem}-{}.iso", dest.date)
             }
-        } else {
-            break;
+            _ => continue,
+        };
+
+        if let Some(reason) = artifact::suspicious(&path, &root, config.artifact_max_bytes).await? {
+            let msg = format!(
+                "{}: Not uploading {}: {reason}\n",
+                Local::now(),
+                path.display()
+            );
+            log.write(msg.as_bytes()).await?;
+            error!("{}", msg.trim());
+            skipped.push(file_name.to_string_lossy().to_string());
+            continue;
         }
+
+        fs::copy(&path, livekit_dir.join(name)).await?;
     }
 
-    let push_success = run_logged_with_retry(
-        "scp",
-        &[
-            "-i",
-            upload_ssh_key,
-            "-r",
-            &os_dir_str,
-            &format!("maintainers@{}:/lookaside/private/aosc-os", host),
-        ],
-        &dir,
-        &mut logs,
-    )
-    .await
-    .unwrap_or(false);
+    let step = log.timeline.start("checksum".to_string());
+    let computed = checksum::ensure_checksums(&livekit_dir, log).await;
+    log.timeline.finish(step, computed.as_ref().ok().map(|_| 0));
+    let computed = computed?;
+
+    let upload = Upload {
+        cwd: dir.clone(),
+        sources: vec![PathBuf::from(&os_dir_str)],
+        recursive: true,
+        publish,
+    };
+    make_torrents(config, &dir, &os_dir_str, &upload.publish, log).await?;
+    let mut manifest = Manifest::collect(&dir.join(&os_dir_str), None).await?;
+    manifest.incremental_from = incremental_from;
+    for a in &mut manifest.artifacts {
+        if !a.path.ends_with(".iso") {
+            continue;
+        }
+
+        let computed = computed.iter().any(|x| a.path.ends_with(&format!("/{x}")));
+        a.checksum = Some(if computed {
+            ChecksumSource::Worker
+        } else {
+            ChecksumSource::Script
+        });
+    }
+    let (mut push, artifact_pushes) = push_artifacts(runner, config, &upload, log).await?;
+    manifest.upload_secs = push.total_secs;
+    upload.publish.fill(&mut manifest);
+
+    // uploading again would not upload the refused images either
+    let upload = if skipped.is_empty() {
+        Some(upload)
+    } else {
+        push.success = false;
+        push.last_status = Some(format!("refused to upload {}", skipped.join(" ")));
+        None
+    };
 
-    Ok((logs, success, push_success))
+    Ok(BuildOutput {
+        success,
+        push: Some(push),
+        manifest: Some(manifest),
+        missing_variants: vec![],
+        upload,
+        failed_artifacts: vec![],
+        artifact_pushes,
+        failure: None,
+    })
 }
 
+/// Torrents for the ISOs below `dir` in `cwd`, if configured. Failing to
+/// make them is logged, the ISOs are uploaded all the same.
+async fn make_torrents(
+    config: &Config,
+    cwd: &Path,
+    dir: &str,
+    publish: &Publish,
+    log: &mut JobLog,
+) -> eyre::Result<()> {
+    let Some(ref torrents) = config.torrents else {
+        return Ok(());
+    };
+
+    let step = log.timeline.start("torrent".to_string());
+    let res = torrents.create(cwd, dir, publish, log).await;
+    log.timeline.finish(step, res.as_ref().ok().map(|_| 0));
+    if let Err(e) = res {
+        let msg = format!("{}: Failed to create torrents: {e}\n", Local::now());
+        log.write(msg.as_bytes()).await?;
+        warn!("{}", msg.trim());
+    }
+
+    Ok(())
+}
+
+/// Run `cmd`, streaming its output into `log` as it arrives.
 async fn get_output_logged(
+    runner: &impl CommandRunner,
     cmd: &str,
     args: &[&str],
     cwd: &Path,
-    logs: &mut Vec<u8>,
-) -> eyre::Result<Output> {
+    log: &mut JobLog,
+) -> eyre::Result<ExitStatus> {
     let begin = Instant::now();
+    let step = log.timeline.start(step_name(cmd, args));
     let msg = format!(
         "{}: Running `{} {}` in `{}`\n",
         Local::now(),
@@ -316,118 +1398,1098 @@ async fn get_output_logged(
         args.join(" "),
         cwd.display()
     );
-    logs.extend(msg.as_bytes());
+    log.write(msg.as_bytes()).await?;
     info!("{}", msg.trim());
 
-    let output = Command::new(cmd)
-        .args(args)
-        .current_dir(cwd)
-        .output()
-        .await?;
+    let status = match runner.run(cmd, args, cwd, log).await {
+        Ok(x) => x,
+        Err(e) => {
+            log.timeline.finish(step, None);
+            return Err(e);
+        }
+    };
+    log.timeline.finish(step, status.code());
 
     let elapsed = begin.elapsed();
-    logs.extend(
+    log.write(
         format!(
             "{}: `{} {}` finished in {:?} with {}\n",
             Local::now(),
             cmd,
             args.join(" "),
             elapsed,
-            output.status
+            status
         )
         .as_bytes(),
-    );
-    logs.extend("STDOUT:\n".as_bytes());
-    logs.extend(output.stdout.clone());
-    logs.extend("STDERR:\n".as_bytes());
-    logs.extend(output.stderr.clone());
+    )
+    .await?;
 
-    Ok(output)
+    Ok(status)
 }
 
 async fn run_logged_with_retry(
+    runner: &impl CommandRunner,
     cmd: &str,
     args: &[&str],
     cwd: &Path,
-    logs: &mut Vec<u8>,
-) -> eyre::Result<bool> {
-    for i in 0..5 {
+    log: &mut JobLog,
+    policy: &RetryPolicy,
+) -> RetryOutcome {
+    let begin = Instant::now();
+    let mut outcome = RetryOutcome::default();
+
+    for i in 0..policy.attempts {
         if i > 0 {
             info!("Attempt #{i} to run `{cmd} {}`", args.join(" "));
         }
-        match get_output_logged(cmd, args, cwd, logs).await {
-            Ok(output) => {
-                if output.status.success() {
-                    return Ok(true);
+        outcome.attempts += 1;
+
+        match get_output_logged(runner, cmd, args, cwd, log).await {
+            Ok(status) => {
+                outcome.last_status = Some(status.to_string());
+                if status.success() {
+                    outcome.success = true;
+                    outcome.excerpt = None;
+                    break;
                 } else {
-                    warn!(
-                        "Running `{cmd} {}` exited with {}",
-                        args.join(" "),
-                        output.status
-                    );
+                    warn!("Running `{cmd} {}` exited with {}", args.join(" "), status);
                 }
             }
             Err(err) => {
+                outcome.last_status = Some(err.to_string());
                 warn!("Running `{cmd} {}` failed with {err}", args.join(" "));
             }
         }
-        // exponential backoff
-        sleep(Duration::from_secs(1 << i)).await;
+
+        let tail = log.tail();
+        let lines = tail.lines().collect::<Vec<_>>();
+        outcome.excerpt = Some(lines[lines.len().saturating_sub(10)..].join("\n"));
+
+        let delay = policy.delay(i, retry::random());
+        if i + 1 == policy.attempts || begin.elapsed() + delay > policy.budget {
+            break;
+        }
+        sleep(delay).await;
     }
-    warn!("Failed too many times running `{cmd} {}`", args.join(" "));
 
-    Ok(false)
+    outcome.total_secs = begin.elapsed().as_secs_f64();
+    if !outcome.success {
+        warn!(
+            "Giving up running `{cmd} {}` after {} attempts",
+            args.join(" "),
+            outcome.attempts
+        );
+    }
+
+    outcome
 }
 
 async fn build_release(
-    arch: &str,
+    runner: &impl CommandRunner,
     variants: &[String],
-    host: &str,
-    upload_ssh_key: &str,
-) -> eyre::Result<(Vec<u8>, bool, bool)> {
+    build: &Build,
+    dest: &Destination<'_>,
+    config: &Config,
+    log: &mut JobLog,
+) -> eyre::Result<BuildOutput> {
+    let Config { retries, .. } = config;
+    let variants = script_variants(variants)?;
+    let arch = dest.arch;
+    let publish = config.release_publish.render(dest);
+    let aoscbootstrap_dir = Path::new("aoscbootstrap");
+    let os_dir_str = format!("os-{}", arch);
+    let os_dir = aoscbootstrap_dir.join(&os_dir_str);
+    update_aoscbootstrap(runner, &os_dir, log, retries).await?;
+    if let Some(ref resume) = build.resume {
+        if let Some(output) = refuse_resume(runner, resume, aoscbootstrap_dir, log).await? {
+            return Ok(output);
+        }
+    }
+
+    let mut args = vec!["./contrib/generate-releases.sh".to_string()];
+    args.extend(variants.iter().cloned());
+
+    let extra_env = build.flavor.as_deref().map(|x| (FLAVOR_ENV, x));
+    let (cmd, args) = build.script_command(args, extra_env.as_slice(), config.run_as.as_ref());
+    let args = args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let general_release = get_output_logged(runner, cmd, &args, aoscbootstrap_dir, log).await?;
+    log.transfer.publishing();
+    let mut success = general_release.success();
+
+    // the script has exited 0 with variants missing before, see for ourselves
+    let mut manifest = if os_dir.is_dir() {
+        make_torrents(config, aoscbootstrap_dir, &os_dir_str, &publish, log).await?;
+        Manifest::collect(&os_dir, Some(variants)).await?
+    } else {
+        Manifest::default()
+    };
+    let missing_variants = manifest.missing_variants(variants);
+
+    if !missing_variants.is_empty() {
+        if !refuse_partial(&missing_variants, build.allow_partial, log).await? {
+            return Ok(BuildOutput {
+                success: false,
+                push: None,
+                manifest: None,
+                missing_variants,
+                upload: None,
+                failed_artifacts: vec![],
+                artifact_pushes: vec![],
+                failure: None,
+            });
+        }
+
+        success = false;
+    }
+
+    let upload = Upload {
+        cwd: aoscbootstrap_dir.to_path_buf(),
+        sources: vec![PathBuf::from(&os_dir_str)],
+        recursive: true,
+        publish,
+    };
+    let (push, artifact_pushes) = push_artifacts(runner, config, &upload, log).await?;
+
+    manifest.upload_secs = push.total_secs;
+    upload.publish.fill(&mut manifest);
+
+    Ok(BuildOutput {
+        success,
+        push: Some(push),
+        manifest: Some(manifest),
+        missing_variants,
+        upload: Some(upload),
+        failed_artifacts: vec![],
+        artifact_pushes,
+        failure: None,
+    })
+}
+
+/// Check out or update aoscbootstrap and remove the output of the last build
+/// in `out_dir`.
+async fn update_aoscbootstrap(
+    runner: &impl CommandRunner,
+    out_dir: &Path,
+    log: &mut JobLog,
+    retries: &Retries,
+) -> eyre::Result<()> {
     let aoscbootstrap_dir = Path::new("aoscbootstrap");
-    let mut logs = vec![];
     if !aoscbootstrap_dir.is_dir() {
-        get_output_logged(
+        run_logged_with_retry(
+            runner,
             "git",
-            &["clone", "https://github.com/AOSC-Dev/aoscbootstrap"],
+            &["clone", freshness::AOSCBOOTSTRAP_REPO],
             Path::new("."),
-            &mut logs,
+            log,
+            &retries.git,
         )
-        .await?;
+        .await;
     }
 
-    get_output_logged("git", &["pull"], aoscbootstrap_dir, &mut logs).await?;
+    run_logged_with_retry(
+        runner,
+        "git",
+        &["pull"],
+        aoscbootstrap_dir,
+        log,
+        &retries.git,
+    )
+    .await;
 
-    let os_dir_str = format!("os-{}", arch);
-    let os_dir = aoscbootstrap_dir.join(&os_dir_str);
+    if out_dir.exists() {
+        info!("{} exists, removing ...", out_dir.display());
+        fs::remove_dir_all(out_dir).await?;
+    }
 
-    if os_dir.exists() {
-        info!("{os_dir_str} exists, removing ...");
-        fs::remove_dir_all(&os_dir).await?;
+    Ok(())
+}
+
+/// The variants to give the build script: none for the [`DEFAULT_SET`],
+/// whose artifacts are then not told apart by variant. An empty list is
+/// refused, the server does not queue one.
+fn script_variants(variants: &[String]) -> eyre::Result<&[String]> {
+    match variants {
+        [] => eyre::bail!("No variants to build, not falling back to the default set"),
+        [x] if x == DEFAULT_SET => Ok(&[]),
+        x => Ok(x),
     }
+}
 
-    let mut args = vec!["./contrib/generate-releases.sh"];
+/// Log the variants the build script produced nothing for. Returns whether
+/// to upload the rest anyway.
+async fn refuse_partial(
+    missing_variants: &[String],
+    allow_partial: bool,
+    log: &mut JobLog,
+) -> eyre::Result<bool> {
+    let msg = format!(
+        "{}: No tarball or checksum produced for: {}\n",
+        Local::now(),
+        missing_variants.join(" ")
+    );
+    log.write(msg.as_bytes()).await?;
+    warn!("{}", msg.trim());
 
-    args.extend(variants.iter().map(|x| x.as_str()));
+    if !allow_partial {
+        log.write("Refusing to upload a partial build\n".as_bytes())
+            .await?;
+    }
 
-    let general_release = get_output_logged("bash", &args, aoscbootstrap_dir, &mut logs).await?;
-    let success = general_release.status.success();
+    Ok(allow_partial)
+}
+
+/// The commit checked out in `dir`, `None` if git cannot tell.
+async fn head_commit(runner: &impl CommandRunner, dir: &Path) -> Option<String> {
+    let output = runner
+        .output("git", &["rev-parse", "HEAD"], dir)
+        .await
+        .ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The failed result to report instead of resuming `resume`, if
+/// aoscbootstrap in `dir` is no longer at the commit the earlier attempt
+/// used: the variants kept from it would not match the ones built now.
+async fn refuse_resume(
+    runner: &impl CommandRunner,
+    resume: &Resume,
+    dir: &Path,
+    log: &mut JobLog,
+) -> eyre::Result<Option<BuildOutput>> {
+    let msg = format!(
+        "{}: Resuming #{}, keeping its variants {}\n",
+        Local::now(),
+        resume.from,
+        resume.done.join(" ")
+    );
+    log.write(msg.as_bytes()).await?;
+
+    let head = head_commit(runner, dir).await;
+    if resume.force || (resume.commit.is_some() && head == resume.commit) {
+        return Ok(None);
+    }
 
-    let scp_image = run_logged_with_retry(
+    let summary = format!(
+        "aoscbootstrap changed since #{} ({} -> {}), resume with --force to build anyway",
+        resume.from,
+        resume.commit.as_deref().unwrap_or("unknown"),
+        head.as_deref().unwrap_or("unknown")
+    );
+    let msg = format!("{}: Refusing to resume: {summary}\n", Local::now());
+    log.write(msg.as_bytes()).await?;
+    error!("{}", msg.trim());
+
+    Ok(Some(BuildOutput {
+        success: false,
+        push: None,
+        manifest: None,
+        missing_variants: vec![],
+        upload: None,
+        failed_artifacts: vec![],
+        artifact_pushes: vec![],
+        failure: Some(Failure {
+            class: "resume_refused".to_string(),
+            summary,
+            excerpt: msg.trim().to_string(),
+            line: None,
+        }),
+    }))
+}
+
+/// Build rootfs tarballs for container and cloud images. Only the tarballs
+/// and their checksums from `rootfs-{arch}` are uploaded, flat into the
+/// rootfs directory.
+async fn build_rootfs(
+    runner: &impl CommandRunner,
+    variants: &[String],
+    build: &Build,
+    dest: &Destination<'_>,
+    config: &Config,
+    log: &mut JobLog,
+) -> eyre::Result<BuildOutput> {
+    let Config {
+        retries,
+        rootfs_script,
+        ..
+    } = config;
+    let variants = script_variants(variants)?;
+    let arch = dest.arch;
+    let publish = config.rootfs_publish.render(dest);
+    let aoscbootstrap_dir = Path::new("aoscbootstrap");
+    let out_dir = aoscbootstrap_dir.join(format!("rootfs-{arch}"));
+    update_aoscbootstrap(runner, &out_dir, log, retries).await?;
+
+    let mut args = vec![rootfs_script.clone()];
+    args.extend(variants.iter().cloned());
+
+    let (cmd, args) = build.script_command(args, &[], config.run_as.as_ref());
+    let args = args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    let status = get_output_logged(runner, cmd, &args, aoscbootstrap_dir, log).await?;
+    log.transfer.publishing();
+    let mut success = status.success();
+
+    let mut manifest = if out_dir.is_dir() {
+        Manifest::collect(&out_dir, Some(variants)).await?
+    } else {
+        Manifest::default()
+    };
+    manifest.retain_tarballs();
+    let missing_variants = manifest.missing_variants(variants);
+
+    if !missing_variants.is_empty() {
+        if !refuse_partial(&missing_variants, build.allow_partial, log).await? {
+            return Ok(BuildOutput {
+                success: false,
+                push: None,
+                manifest: None,
+                missing_variants,
+                upload: None,
+                failed_artifacts: vec![],
+                artifact_pushes: vec![],
+                failure: None,
+            });
+        }
+
+        success = false;
+    }
+
+    if manifest.artifacts.is_empty() {
+        log.write("Nothing to upload\n".as_bytes()).await?;
+        return Ok(BuildOutput {
+            success: false,
+            push: None,
+            manifest: None,
+            missing_variants,
+            upload: None,
+            failed_artifacts: vec![],
+            artifact_pushes: vec![],
+            failure: None,
+        });
+    }
+
+    let upload = Upload {
+        cwd: aoscbootstrap_dir.to_path_buf(),
+        sources: manifest
+            .artifacts
+            .iter()
+            .map(|a| PathBuf::from(&a.path))
+            .collect(),
+        recursive: false,
+        publish,
+    };
+    let (push, artifact_pushes) = push_artifacts(runner, config, &upload, log).await?;
+
+    manifest.upload_secs = push.total_secs;
+    // uploaded flat, by file name
+    for a in &mut manifest.artifacts {
+        if let Some(name) = Path::new(&a.path).file_name() {
+            a.path = name.to_string_lossy().to_string();
+        }
+    }
+    upload.publish.fill(&mut manifest);
+
+    Ok(BuildOutput {
+        success,
+        push: Some(push),
+        manifest: Some(manifest),
+        missing_variants,
+        upload: Some(upload),
+        failed_artifacts: vec![],
+        artifact_pushes,
+        failure: None,
+    })
+}
+
+/// Upload the artifacts build `build_id` kept after failing to push them,
+/// as build `id`. Kept for `id` if that fails again.
+async fn repush(
+    runner: &impl CommandRunner,
+    config: &Config,
+    build_id: i64,
+    id: i64,
+    log: &mut JobLog,
+) -> eyre::Result<BuildOutput> {
+    let Some(kept) = spool::get(build_id).await? else {
+        let msg = format!(
+            "{}: The artifacts of #{build_id} are no longer available, rebuild required\n",
+            Local::now()
+        );
+        log.write(msg.as_bytes()).await?;
+        error!("{}", msg.trim());
+
+        return Ok(BuildOutput {
+            success: false,
+            push: None,
+            manifest: None,
+            missing_variants: vec![],
+            upload: None,
+            failed_artifacts: vec![],
+            artifact_pushes: vec![],
+            failure: Some(Failure {
+                class: "artifacts_gone".to_string(),
+                summary: "artifacts no longer available, rebuild required".to_string(),
+                excerpt: msg.trim().to_string(),
+                line: None,
+            }),
+        });
+    };
+
+    // nothing to build, the job is all publishing
+    log.transfer.publishing();
+    let (push, artifact_pushes) = push_artifacts(runner, config, &kept.upload, log).await?;
+    let mut manifest = kept.manifest.clone();
+    manifest.upload_secs = push.total_secs;
+
+    let failed_artifacts = if push.success {
+        if let Err(e) = spool::remove(build_id).await {
+            warn!("Failed to remove the kept artifacts of #{build_id}: {e}");
+        }
+        vec![]
+    } else {
+        spool::rename(build_id, id, kept).await?
+    };
+
+    Ok(BuildOutput {
+        success: true,
+        push: Some(push),
+        manifest: Some(manifest),
+        missing_variants: vec![],
+        upload: None,
+        failed_artifacts,
+        artifact_pushes,
+        failure: None,
+    })
+}
+
+/// Upload each file of `upload` on its own, going on past failures, and
+/// remove what a failed transfer left on the upload host. The outcome sums
+/// up the transfers, next to how each went.
+async fn push_artifacts(
+    runner: &impl CommandRunner,
+    config: &Config,
+    upload: &Upload,
+    log: &mut JobLog,
+) -> eyre::Result<(RetryOutcome, Vec<ArtifactPush>)> {
+    let files = upload.files().await?;
+    let mut outcome = RetryOutcome {
+        success: !files.is_empty(),
+        last_status: files.is_empty().then(|| "nothing to upload".to_string()),
+        ..Default::default()
+    };
+    let mut pushes = vec![];
+    for (path, file) in &files {
+        let o = push_upload(runner, config, file, log).await?;
+        outcome.attempts += o.attempts;
+        outcome.total_secs += o.total_secs;
+        if !o.success {
+            outcome.success = false;
+            outcome.excerpt = o.excerpt.clone();
+            remove_partial(runner, config, file, log).await?;
+        }
+        pushes.push(ArtifactPush {
+            path: path.clone(),
+            success: o.success,
+            attempts: o.attempts,
+            error: o.last_status.filter(|_| !o.success),
+        });
+    }
+
+    let failed = pushes.iter().filter(|x| !x.success).collect::<Vec<_>>();
+    if let Some(last) = failed.last() {
+        outcome.last_status = Some(format!(
+            "failed to upload {} of {} files, the last {}: {}",
+            failed.len(),
+            pushes.len(),
+            last.path,
+            last.error.as_deref().unwrap_or("unknown error")
+        ));
+    }
+
+    Ok((outcome, pushes))
+}
+
+/// Remove the file a failed transfer of `upload` may have left on the
+/// upload host, so that nothing serves it half written. A failure is only
+/// logged.
+async fn remove_partial(
+    runner: &impl CommandRunner,
+    config: &Config,
+    upload: &Upload,
+    log: &mut JobLog,
+) -> eyre::Result<()> {
+    let names = upload
+        .sources
+        .iter()
+        .filter_map(|x| x.file_name())
+        .map(|x| shell_quote(&x.to_string_lossy()))
+        .collect::<Vec<_>>();
+    let command = format!(
+        "cd {} && rm -f -- {}",
+        shell_quote(&upload.publish.dir),
+        names.join(" ")
+    );
+    let args = config.ssh.ssh_args(&command);
+    let status = get_output_logged(
+        runner,
+        "ssh",
+        &args.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+        Path::new("."),
+        log,
+    )
+    .await?;
+
+    if !status.success() {
+        warn!(
+            "Failed to remove the partial upload in {}: {status}",
+            upload.publish.dir
+        );
+    }
+
+    Ok(())
+}
+
+/// Create the destination of `upload` and upload it, with retries.
+async fn push_upload(
+    runner: &impl CommandRunner,
+    config: &Config,
+    upload: &Upload,
+    log: &mut JobLog,
+) -> eyre::Result<RetryOutcome> {
+    ensure_remote_dir(runner, &config.ssh, &upload.publish.dir, log).await?;
+    let scp_args = upload.scp_args(&config.ssh);
+    let scp_args = scp_args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+    let transfer = log.transfer.clone();
+    let scp = run_logged_with_retry(
+        runner,
         "scp",
-        &[
-            "-i",
-            upload_ssh_key,
-            "-r",
-            &os_dir_str,
-            &format!("maintainers@{}:/lookaside/private/aosc-os", host),
-        ],
-        &aoscbootstrap_dir,
-        &mut logs,
+        &scp_args,
+        &upload.cwd,
+        log,
+        &config.retries.upload,
+    );
+    tokio::pin!(scp);
+    let outcome = tokio::select! {
+        outcome = &mut scp => outcome,
+        // only ends if there is nothing to watch
+        () = transfer::watch(&config.ssh, upload, &transfer) => scp.await,
+    };
+    transfer.clear();
+
+    Ok(outcome)
+}
+
+/// Create `dir` on the upload host, so that a new layout does not need
+/// preparing by hand. A failure is only logged, the upload reports it.
+async fn ensure_remote_dir(
+    runner: &impl CommandRunner,
+    ssh: &SshConfig,
+    dir: &str,
+    log: &mut JobLog,
+) -> eyre::Result<()> {
+    let msg = format!("{}: Uploading to {}\n", Local::now(), ssh.remote(dir));
+    log.write(msg.as_bytes()).await?;
+    info!("{}", msg.trim());
+
+    let args = ssh.ssh_args(&format!("mkdir -p {}", shell_quote(dir)));
+    let status = get_output_logged(
+        runner,
+        "ssh",
+        &args.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+        Path::new("."),
+        log,
     )
-    .await
-    .unwrap_or(false);
+    .await?;
 
-    Ok((logs, success, scp_image))
+    if !status.success() {
+        warn!("Failed to create {dir} on the upload host: {status}");
+    }
+
+    Ok(())
+}
+
+/// Remove nightly artifacts past their retention from `dir` on the upload
+/// host, logging each with the reason. See [`prune`] for which files are
+/// considered.
+async fn prune_nightly(
+    runner: &impl CommandRunner,
+    config: &Config,
+    dir: &str,
+    log: &mut JobLog,
+) -> eyre::Result<()> {
+    let retention = &config.nightly_retention;
+    if retention.mode == prune::Mode::Off {
+        return Ok(());
+    }
+
+    let paths = list_remote(&config.ssh, dir).await?;
+    let doomed = prune::plan(&paths, Local::now().date_naive(), retention);
+    let dry_run = retention.mode == prune::Mode::DryRun;
+    for (path, reason) in &doomed {
+        let msg = format!(
+            "{}: {} {dir}/{path} ({reason})\n",
+            Local::now(),
+            if dry_run { "Would remove" } else { "Removing" }
+        );
+        log.write(msg.as_bytes()).await?;
+        info!("{}", msg.trim());
+    }
+
+    if dry_run || doomed.is_empty() {
+        return Ok(());
+    }
+
+    let command = format!(
+        "cd {} && rm -f -- {}",
+        shell_quote(dir),
+        doomed
+            .iter()
+            .map(|(x, _)| shell_quote(x))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let args = config.ssh.ssh_args(&command);
+    let status = get_output_logged(
+        runner,
+        "ssh",
+        &args.iter().map(|x| x.as_str()).collect::<Vec<_>>(),
+        Path::new("."),
+        log,
+    )
+    .await?;
+
+    if !status.success() {
+        bail!("ssh {status}");
+    }
+
+    Ok(())
+}
+
+/// Files below `dir` on the upload host, relative to it.
+async fn list_remote(ssh: &SshConfig, dir: &str) -> eyre::Result<Vec<String>> {
+    let args = ssh.ssh_args(&format!(
+        "find {} -type f -printf '%P\\n'",
+        shell_quote(dir)
+    ));
+    let output = tokio::process::Command::new("ssh")
+        .args(&args)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.lines().last() {
+            Some(line) => bail!("Failed to list {dir}: {}", line.trim()),
+            None => bail!("Failed to list {dir}: ssh {}", output.status),
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|x| x.to_string())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use runner::fake::Script;
+    use serde_json::json;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    use super::*;
+
+    const ISO: &str = "aosc-os_livekit_20240501_amd64.iso";
+
+    /// The builds work relative to the working directory of the process, so
+    /// the tests doing one take turns.
+    static CWD: Mutex<()> = Mutex::const_new(());
+
+    /// A scratch working directory, left again on drop. For the tests of
+    /// the other modules too.
+    pub(crate) struct Workdir {
+        pub path: PathBuf,
+        previous: PathBuf,
+        _turn: MutexGuard<'static, ()>,
+    }
+
+    impl Workdir {
+        pub async fn enter(name: &str) -> Self {
+            let turn = CWD.lock().await;
+            let path =
+                std::env::temp_dir().join(format!("shipit-worker-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            let previous = current_dir().unwrap();
+            std::env::set_current_dir(&path).unwrap();
+
+            Self {
+                path,
+                previous,
+                _turn: turn,
+            }
+        }
+    }
+
+    impl Drop for Workdir {
+        fn drop(&mut self) {
+            std::env::set_current_dir(&self.previous).unwrap();
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    async fn config() -> Config {
+        let once = RetryPolicy {
+            attempts: 1,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+            budget: Duration::from_secs(60),
+        };
+
+        Config {
+            ssh: SshConfig {
+                key: "/etc/shipit/id_ed25519".to_string(),
+                user: "maintainers".to_string(),
+                host: "repo.aosc.io".to_string(),
+                port: None,
+                known_hosts: None,
+                strict_host_key_checking: "yes".to_string(),
+            },
+            log_policy: LogPolicy::from_env().unwrap(),
+            retries: Retries {
+                upload: once.clone(),
+                log: once.clone(),
+                git: once,
+            },
+            livekit_publish: Publish::from_env(&["livekit"], "/lookaside/private/aosc-os").unwrap(),
+            livekit_clean: Clean::from_env().unwrap(),
+            livekit_cache: CachePolicy::from_env().unwrap(),
+            release_publish: Publish::from_env(&["release"], "/lookaside/private/aosc-os").unwrap(),
+            rootfs_publish: Publish::from_env(&["rootfs"], "/lookaside/private/aosc-os/rootfs")
+                .unwrap(),
+            log_publish: Publish::from_env(&["log"], "/buildit/logs").unwrap(),
+            upload_targets: vec![],
+            rootfs_script: "./contrib/generate-rootfs.sh".to_string(),
+            nightly_retention: Retention::from_env().unwrap(),
+            artifact_max_bytes: 1 << 20,
+            classifier: Classifier::from_env().await.unwrap(),
+            run_as: None,
+            freshness: Freshness::from_env().unwrap(),
+            torrents: None,
+        }
+    }
+
+    fn livekit() -> Build {
+        serde_json::from_value(json!({"id": 1, "arch": "amd64", "build_type": "Livekit"})).unwrap()
+    }
+
+    fn dest() -> Destination<'static> {
+        Destination {
+            arch: "amd64",
+            channel: Channel::Release,
+            build_id: 1,
+            date: "20240501",
+            flavor: None,
+        }
+    }
+
+    /// aosc-mklive exiting with `code`, after writing an image.
+    fn mklive(code: i32) -> Script {
+        Script::default()
+            .on("git", "clone", 0)
+            .does(|cwd| std::fs::create_dir(cwd.join("aosc-mklive")).unwrap())
+            .on("git", "pull", 0)
+            .on("bash", "./aosc-mklive.sh", code)
+            .does(|cwd| std::fs::write(cwd.join(ISO), "image").unwrap())
+            .on("ssh", "", 0)
+            .on("scp", "", 0)
+    }
+
+    async fn build(script: &Script) -> BuildOutput {
+        build_livekit(
+            script,
+            &livekit(),
+            &config().await,
+            &dest(),
+            &mut JobLog::memory(),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn ran(script: &Script, prefix: &str) -> Vec<PathBuf> {
+        script
+            .calls()
+            .into_iter()
+            .filter(|(line, _)| line.starts_with(prefix))
+            .map(|(_, cwd)| cwd)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn livekit_clones_a_missing_checkout() {
+        let _dir = Workdir::enter("clone").await;
+        let script = mklive(0);
+
+        let out = build(&script).await;
+
+        assert_eq!(
+            ran(&script, "git clone https://github.com/AOSC-Dev/aosc-mklive"),
+            [Path::new(".")]
+        );
+        assert_eq!(ran(&script, "git pull"), [Path::new("aosc-mklive")]);
+        assert_eq!(ran(&script, "bash"), [Path::new("aosc-mklive")]);
+        assert!(out.success);
+        assert!(out.push.unwrap().success);
+        assert!(Path::new("os-amd64/livekit").join(ISO).is_file());
+        assert!(Path::new("os-amd64/livekit")
+            .join(format!("{ISO}.sha256sum"))
+            .is_file());
+    }
+
+    #[tokio::test]
+    async fn livekit_pulls_an_existing_checkout() {
+        let _dir = Workdir::enter("pull").await;
+        std::fs::create_dir("aosc-mklive").unwrap();
+        let script = mklive(0);
+
+        let out = build(&script).await;
+
+        assert!(ran(&script, "git clone").is_empty());
+        assert_eq!(ran(&script, "git pull"), [Path::new("aosc-mklive")]);
+        assert!(out.success);
+    }
+
+    #[tokio::test]
+    async fn livekit_removes_the_images_of_the_last_build() {
+        let _dir = Workdir::enter("clean").await;
+        let old = ["aosc-os_livekit_20240401_amd64.iso", "sha256sums.txt"];
+        std::fs::create_dir_all("aosc-mklive/iso").unwrap();
+        for name in old {
+            std::fs::write(Path::new("aosc-mklive").join(name), "old").unwrap();
+        }
+        std::fs::write("aosc-mklive/.shipit-outputs", old.join("\n")).unwrap();
+        let script = Script::default()
+            .on("git", "pull", 0)
+            .on("bash", "./aosc-mklive.sh", 0)
+            .does(|cwd| {
+                for name in [
+                    "aosc-os_livekit_20240401_amd64.iso",
+                    "sha256sums.txt",
+                    "iso",
+                ] {
+                    assert!(!cwd.join(name).exists(), "{name} is left for the build");
+                }
+                std::fs::write(cwd.join(ISO), "image").unwrap();
+            })
+            .on("ssh", "", 0)
+            .on("scp", "", 0);
+
+        let out = build(&script).await;
+
+        assert!(out.success);
+        let mut uploaded = std::fs::read_dir("os-amd64/livekit")
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        uploaded.sort();
+        assert_eq!(uploaded, [ISO.to_string(), format!("{ISO}.sha256sum")]);
+    }
+
+    #[tokio::test]
+    async fn livekit_fails_with_its_script() {
+        let _dir = Workdir::enter("fail").await;
+        let script = mklive(1);
+
+        let out = build(&script).await;
+
+        assert!(!out.success);
+        // the images are uploaded all the same, for a look at them
+        assert!(out.push.unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn push_fails_if_any_file_does() {
+        let dir = Workdir::enter("push").await;
+        for name in ["a.iso", "b.iso", "c.iso"] {
+            std::fs::write(name, "image").unwrap();
+        }
+        let upload = Upload {
+            cwd: dir.path.clone(),
+            sources: ["a.iso", "b.iso", "c.iso"].map(PathBuf::from).to_vec(),
+            recursive: false,
+            publish: config().await.livekit_publish.render(&dest()),
+        };
+        let script = Script::default()
+            .on("ssh", "", 0)
+            .on("scp", "./b.iso", 1)
+            .on("scp", "", 0);
+
+        let (outcome, pushes) =
+            push_artifacts(&script, &config().await, &upload, &mut JobLog::memory())
+                .await
+                .unwrap();
+
+        assert!(!outcome.success);
+        assert_eq!(outcome.attempts, 3);
+        assert_eq!(
+            pushes.iter().map(|x| x.success).collect::<Vec<_>>(),
+            [true, false, true]
+        );
+        assert!(outcome
+            .last_status
+            .unwrap()
+            .starts_with("failed to upload 1 of 3 files, the last b.iso"));
+        // the partial file is removed, and only that one
+        let removed = script
+            .calls()
+            .into_iter()
+            .filter(|(line, _)| line.contains("rm -f"))
+            .map(|(line, _)| line)
+            .collect::<Vec<_>>();
+        assert_eq!(removed.len(), 1);
+        assert!(removed[0].ends_with("rm -f -- 'b.iso'"));
+
+        let script = Script::default().on("ssh", "", 0).on("scp", "", 0);
+        let (outcome, _) = push_artifacts(&script, &config().await, &upload, &mut JobLog::memory())
+            .await
+            .unwrap();
+        assert!(outcome.success);
+    }
+
+    #[tokio::test]
+    async fn push_of_nothing_fails() {
+        let dir = Workdir::enter("empty").await;
+        let upload = Upload {
+            cwd: dir.path.clone(),
+            sources: vec![],
+            recursive: false,
+            publish: config().await.livekit_publish.render(&dest()),
+        };
+
+        let (outcome, pushes) = push_artifacts(
+            &Script::default(),
+            &config().await,
+            &upload,
+            &mut JobLog::memory(),
+        )
+        .await
+        .unwrap();
+
+        assert!(!outcome.success);
+        assert!(pushes.is_empty());
+        assert_eq!(outcome.last_status.as_deref(), Some("nothing to upload"));
+    }
+
+    fn status(wire: &str) -> Status {
+        serde_json::from_str::<StatusReply>(wire).unwrap().into()
+    }
+
+    #[test]
+    fn status_of_servers_without_versions_is_read() {
+        assert!(matches!(
+            status(include_str!(
+                "../../common/tests/fixtures/status/v1-pending.json"
+            )),
+            Status::Pending
+        ));
+        assert!(matches!(
+            status(include_str!(
+                "../../common/tests/fixtures/status/v1-ping.json"
+            )),
+            Status::Ping {
+                id: 3,
+                check: false
+            }
+        ));
+        let Status::Working { job } = status(include_str!(
+            "../../common/tests/fixtures/status/v1-working.json"
+        )) else {
+            panic!("not working");
+        };
+        assert_eq!(Build::deserialize(&job).unwrap().id, 42);
+    }
+
+    #[test]
+    fn versioned_status_is_read() {
+        assert!(matches!(
+            status(include_str!(
+                "../../common/tests/fixtures/status/v2-pending.json"
+            )),
+            Status::Pending
+        ));
+        assert!(matches!(
+            status(include_str!(
+                "../../common/tests/fixtures/status/v2-ping.json"
+            )),
+            Status::Ping { id: 3, check: true }
+        ));
+        let Status::Working { job } = status(include_str!(
+            "../../common/tests/fixtures/status/v2-working.json"
+        )) else {
+            panic!("not working");
+        };
+        let build = Build::deserialize(&job).unwrap();
+        assert_eq!(build.id, 42);
+        assert_eq!(
+            build.build_type,
+            BuildType::Release(vec!["base".to_string(), "desktop".to_string()])
+        );
+        assert!(build.allow_partial);
+        assert_eq!(build.channel, Channel::Nightly);
+        assert_eq!(build.env["MIRROR"], "https://repo.aosc.io");
+        assert_eq!(build.note.as_deref(), Some("CVE-2024-1234"));
+    }
+
+    #[test]
+    fn statuses_newer_than_the_worker_are_unknown() {
+        assert!(matches!(
+            status(include_str!(
+                "../../common/tests/fixtures/status/v2-unknown.json"
+            )),
+            Status::Unknown
+        ));
+    }
+
+    /// A server answering every heartbeat with `reply`.
+    async fn replying(reply: &'static str) -> Server {
+        use axum::{routing::post, Router};
+
+        let app = Router::new().route("/heartbeat", post(move || async move { reply }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        Server {
+            client: Client::new(),
+            uri,
+            secret: "secret".to_string(),
+            name: "w1".to_string(),
+            arches: vec!["amd64".to_string()],
+            weights: vec![100],
+            pool: None,
+            draining: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeats_tell_of_cancels() {
+        let timeline = Timeline::default();
+        for (reply, cancelled) in [
+            (r#"{"draining":false,"cancel":true}"#, true),
+            (r#"{"draining":true}"#, false),
+            // older servers
+            ("", false),
+        ] {
+            let server = replying(reply).await;
+            let touched = server.touch(42, "amd64", None, &timeline, None).await;
+            assert_eq!(touched.unwrap(), cancelled, "{reply}");
+        }
+    }
 }
@@ -0,0 +1,392 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use shipit_common::Channel;
+use tokio::fs;
+
+/// What was actually shipped by a build, reported to the server in `/done`.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Manifest {
+    pub artifacts: Vec<Artifact>,
+    pub total_bytes: u64,
+    pub upload_secs: f64,
+    /// Directory on the upload host the artifact paths are relative to.
+    #[serde(default)]
+    pub remote_dir: Option<String>,
+    /// Uploaded somewhere not publicly reachable, the URLs are for
+    /// maintainers only.
+    #[serde(default)]
+    pub private: bool,
+    #[serde(default)]
+    pub channel: Channel,
+    /// The note the build was requested with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// The build whose livekit cache the images were built on, see
+    /// [`crate::cache`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incremental_from: Option<i64>,
+    /// The flavor of the release.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Artifact {
+    pub path: String,
+    pub size: u64,
+    pub variant: Option<String>,
+    /// Where the checksum file of an image came from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<ChecksumSource>,
+    /// Read from the checksum file next to it, for `/diff`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumSource {
+    /// Written by the build script.
+    Script,
+    /// Missing from the build script output, computed by the worker.
+    Worker,
+}
+
+impl Manifest {
+    /// Walk `dir` (the directory being uploaded) and record every regular file
+    /// in it, relative to the parent of `dir`.
+    ///
+    /// When `variants` is given, the first path component below `dir` is
+    /// matched against it so that release artifacts can be aggregated per
+    /// variant.
+    pub async fn collect(dir: &Path, variants: Option<&[String]>) -> eyre::Result<Self> {
+        let base = dir.parent().unwrap_or(dir);
+        let mut artifacts = vec![];
+        let mut stack: Vec<PathBuf> = vec![dir.to_path_buf()];
+
+        while let Some(d) = stack.pop() {
+            let mut entries = fs::read_dir(&d).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                let path = entry.path();
+
+                if file_type.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+
+                if !file_type.is_file() {
+                    continue;
+                }
+
+                let size = entry.metadata().await?.len();
+                let variant = variants.and_then(|v| {
+                    let first = path.strip_prefix(dir).ok()?.components().next()?;
+                    let first = first.as_os_str().to_string_lossy();
+                    v.iter().find(|x| **x == first).cloned()
+                });
+
+                artifacts.push(Artifact {
+                    path: path
+                        .strip_prefix(base)
+                        .unwrap_or(&path)
+                        .display()
+                        .to_string(),
+                    size,
+                    variant,
+                    checksum: None,
+                    sha256: None,
+                    url: None,
+                });
+            }
+        }
+
+        artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+        read_checksums(base, &mut artifacts).await?;
+        let total_bytes = artifacts.iter().map(|x| x.size).sum();
+
+        Ok(Self {
+            artifacts,
+            total_bytes,
+            upload_secs: 0.0,
+            remote_dir: None,
+            private: false,
+            channel: Channel::Release,
+            note: None,
+            incremental_from: None,
+            flavor: None,
+        })
+    }
+
+    /// Keep only tarballs and their checksum files.
+    pub fn retain_tarballs(&mut self) {
+        self.artifacts
+            .retain(|a| a.path.contains(".tar") || a.path.ends_with(".sha256sum"));
+        self.total_bytes = self.artifacts.iter().map(|x| x.size).sum();
+    }
+
+    /// Requested variants without at least one tarball and its checksum
+    /// file among the artifacts.
+    pub fn missing_variants(&self, variants: &[String]) -> Vec<String> {
+        variants
+            .iter()
+            .filter(|v| {
+                let files = self
+                    .artifacts
+                    .iter()
+                    .filter(|a| a.variant.as_ref() == Some(*v))
+                    .collect::<Vec<_>>();
+                let checksum = files.iter().any(|a| a.path.ends_with(".sha256sum"));
+                let tarball = files
+                    .iter()
+                    .any(|a| a.path.contains(".tar") && !a.path.ends_with(".sha256sum"));
+
+                !(tarball && checksum)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Fill in the hashes listed in the `.sha256sum` files among `artifacts`,
+/// in the format of `sha256sum`, for the files next to them.
+async fn read_checksums(base: &Path, artifacts: &mut [Artifact]) -> eyre::Result<()> {
+    let mut sums = vec![];
+    for a in artifacts.iter().filter(|a| a.path.ends_with(".sha256sum")) {
+        let dir = Path::new(&a.path).parent().unwrap_or(Path::new(""));
+        let content = fs::read_to_string(base.join(&a.path)).await?;
+        for line in content.lines() {
+            let Some((sum, name)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            // `*` marks binary mode
+            let name = name.trim_start().trim_start_matches('*');
+            sums.push((dir.join(name), sum.to_string()));
+        }
+    }
+
+    for a in artifacts.iter_mut() {
+        if let Some((_, sum)) = sums.iter().find(|(p, _)| *p == Path::new(&a.path)) {
+            a.sha256 = Some(sum.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// Where images are uploaded to, and where they can be downloaded from.
+/// Before [`Publish::render`], `dir` and `base_url` are templates.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Publish {
+    pub dir: String,
+    /// URL serving `dir`.
+    pub base_url: Option<String>,
+    pub private: bool,
+    pub channel: Channel,
+    /// Put nightly builds below `nightly/` unless the directory mentions
+    /// `{channel}`.
+    pub nightly_subdir: bool,
+}
+
+/// What destination templates may refer to.
+pub struct Destination<'a> {
+    pub arch: &'a str,
+    pub channel: Channel,
+    pub build_id: i64,
+    /// `YYYYMMDD`
+    pub date: &'a str,
+    /// Of a release, see `--flavor`.
+    pub flavor: Option<&'a str>,
+}
+
+impl Publish {
+    /// `upload_{kind}_dir` (default `default_dir`), `download_{kind}_base_url`
+    /// and `upload_{kind}_private` (default: whether the directory is below
+    /// `/lookaside/private`), for the first of `kinds` that is set. The
+    /// directory and URL may use `{arch}`, `{channel}`, `{date}`,
+    /// `{build_id}` and `{flavor}`, which is empty for builds without.
+    pub fn from_env(kinds: &[&str], default_dir: &str) -> eyre::Result<Self> {
+        let var = |name: &str| {
+            kinds
+                .iter()
+                .find_map(|kind| std::env::var(name.replace("{kind}", kind)).ok())
+        };
+
+        let dir = var("upload_{kind}_dir").unwrap_or_else(|| default_dir.to_string());
+        let base_url = var("download_{kind}_base_url");
+        let private = match var("upload_{kind}_private") {
+            Some(x) => x.parse()?,
+            None => dir.starts_with("/lookaside/private"),
+        };
+
+        Ok(Self {
+            dir,
+            base_url,
+            private,
+            channel: Channel::Release,
+            nightly_subdir: true,
+        })
+    }
+
+    /// Where a build goes, with the variables of the templates replaced. A
+    /// flavored build goes below a directory named after the flavor unless
+    /// the directory mentions `{flavor}`, and see `nightly_subdir` for
+    /// where nightly builds go.
+    pub fn render(&self, dest: &Destination) -> Self {
+        let render = |x: &str| {
+            let mut s = x
+                .replace("{arch}", dest.arch)
+                .replace("{channel}", dest.channel.name())
+                .replace("{date}", dest.date)
+                .replace("{build_id}", &dest.build_id.to_string())
+                .replace("{flavor}", dest.flavor.unwrap_or_default());
+            if let Some(flavor) = dest.flavor.filter(|_| !self.dir.contains("{flavor}")) {
+                s = format!("{}/{flavor}", s.trim_end_matches('/'));
+            }
+
+            if self.nightly_subdir
+                && dest.channel == Channel::Nightly
+                && !self.dir.contains("{channel}")
+            {
+                format!("{}/nightly", s.trim_end_matches('/'))
+            } else {
+                s
+            }
+        };
+
+        Self {
+            dir: render(&self.dir),
+            base_url: self.base_url.as_deref().map(render),
+            private: self.private,
+            channel: dest.channel,
+            nightly_subdir: self.nightly_subdir,
+        }
+    }
+
+    /// Record in `manifest` where its artifacts went.
+    pub fn fill(&self, manifest: &mut Manifest) {
+        manifest.remote_dir = Some(self.dir.clone());
+        manifest.private = self.private;
+        manifest.channel = self.channel;
+
+        if let Some(ref base) = self.base_url {
+            for a in &mut manifest.artifacts {
+                a.url = Some(format!("{}/{}", base.trim_end_matches('/'), a.path));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `files` written below a scratch `os/`, the directory being uploaded.
+    async fn collect(name: &str, files: &[&str], variants: &[&str]) -> Manifest {
+        let base =
+            std::env::temp_dir().join(format!("shipit-manifest-{name}-{}", std::process::id()));
+        let dir = base.join("os");
+        let _ = std::fs::remove_dir_all(&base);
+        for f in files {
+            let path = dir.join(f);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "x").unwrap();
+        }
+
+        let variants = variants.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        let manifest = Manifest::collect(&dir, Some(&variants)).await.unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+
+        manifest
+    }
+
+    fn variants(v: &[&str]) -> Vec<String> {
+        v.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn a_variant_needs_a_tarball_and_its_checksum() {
+        let m = collect(
+            "missing",
+            &[
+                "base/aosc-os_base_20240501_amd64.tar.xz",
+                "base/aosc-os_base_20240501_amd64.tar.xz.sha256sum",
+                "desktop/aosc-os_desktop_20240501_amd64.tar.xz",
+                "server/aosc-os_server_20240501_amd64.tar.xz.sha256sum",
+                "container/aosc-os_container_20240501_amd64.squashfs",
+                "container/aosc-os_container_20240501_amd64.squashfs.sha256sum",
+            ],
+            &["base", "desktop", "server", "container", "kde"],
+        )
+        .await;
+
+        assert_eq!(
+            m.missing_variants(&variants(&[
+                "base",
+                "desktop",
+                "server",
+                "container",
+                "kde"
+            ])),
+            ["desktop", "server", "container", "kde"]
+        );
+        assert!(m.missing_variants(&variants(&["base"])).is_empty());
+    }
+
+    fn publish(dir: &str) -> Publish {
+        Publish {
+            dir: dir.to_string(),
+            base_url: Some("https://releases.aosc.io/os/{arch}".to_string()),
+            private: false,
+            channel: Channel::Release,
+            nightly_subdir: true,
+        }
+    }
+
+    fn dest(flavor: Option<&str>) -> Destination<'_> {
+        Destination {
+            arch: "loongarch64",
+            channel: Channel::Release,
+            build_id: 42,
+            date: "20240501",
+            flavor,
+        }
+    }
+
+    #[test]
+    fn a_flavor_goes_where_the_template_says_or_below_it() {
+        let p = publish("/lookaside/os/{arch}").render(&dest(Some("old-world")));
+        assert_eq!(p.dir, "/lookaside/os/loongarch64/old-world");
+        assert_eq!(
+            p.base_url.as_deref(),
+            Some("https://releases.aosc.io/os/loongarch64/old-world")
+        );
+
+        let p = publish("/lookaside/os/{arch}-{flavor}/").render(&dest(Some("old-world")));
+        assert_eq!(p.dir, "/lookaside/os/loongarch64-old-world/");
+
+        let p = publish("/lookaside/os/{arch}-{flavor}").render(&dest(None));
+        assert_eq!(p.dir, "/lookaside/os/loongarch64-");
+        let p = publish("/lookaside/os/{arch}").render(&dest(None));
+        assert_eq!(p.dir, "/lookaside/os/loongarch64");
+    }
+
+    #[tokio::test]
+    async fn files_outside_the_variant_directories_count_for_none() {
+        let m = collect(
+            "stray",
+            &[
+                "aosc-os_base_20240501_amd64.tar.xz",
+                "aosc-os_base_20240501_amd64.tar.xz.sha256sum",
+            ],
+            &["base"],
+        )
+        .await;
+
+        assert!(m.artifacts.iter().all(|a| a.variant.is_none()));
+        assert_eq!(m.missing_variants(&variants(&["base"])), ["base"]);
+    }
+}
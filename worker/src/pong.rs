@@ -0,0 +1,49 @@
+//! Answering a `/ping` from chat with how this worker is doing.
+
+use serde::Serialize;
+use shipit_common::CheckResult;
+use tracing::info;
+
+use crate::{update::VERSION, vitals::Vitals, Server};
+
+#[derive(Serialize)]
+struct PongRequest<'a> {
+    id: i64,
+    worker: &'a str,
+    hostname: String,
+    version: &'a str,
+    load_avg: Option<[f64; 3]>,
+    free_disk: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    checks: Vec<CheckResult>,
+}
+
+/// `checks` are the results of `worker check`, if asked for.
+pub async fn pong(server: &Server, id: i64, checks: Vec<CheckResult>) -> eyre::Result<()> {
+    info!("Answering ping #{id}");
+
+    // not the cached readings sent along with every poll
+    let Vitals {
+        load_avg,
+        free_disk,
+    } = Vitals::read().await;
+
+    server
+        .client
+        .post(format!("{}/pong", server.uri))
+        .header("secret", &server.secret)
+        .json(&PongRequest {
+            id,
+            worker: &server.name,
+            hostname: gethostname::gethostname().to_string_lossy().to_string(),
+            version: VERSION,
+            load_avg,
+            free_disk,
+            checks,
+        })
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
@@ -0,0 +1,269 @@
+//! Checking that the upload host answers and each upload directory on it
+//! can be written before taking a job, so that a build does not run for
+//! hours only to find it has nowhere to go.
+
+use std::{process::Stdio, time::Duration};
+
+use eyre::bail;
+use tokio::{
+    process::Command,
+    time::{timeout, Instant},
+};
+use tracing::{error, warn};
+
+use crate::{
+    manifest::Publish,
+    ssh::{shell_quote, SshConfig},
+};
+
+/// How often the upload host is checked again while polling for jobs.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Given to ssh as its connect timeout, and twice that to the whole check.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Take no jobs while the upload host is unreachable.
+    Block,
+    /// Take jobs anyway, telling the server.
+    Warn,
+    Off,
+}
+
+/// The upload host did not answer.
+#[derive(Clone)]
+pub struct Unreachable {
+    pub error: String,
+    /// The worker takes no jobs until it answers again.
+    pub blocking: bool,
+}
+
+pub struct Preflight {
+    policy: Policy,
+    /// When the upload host was last checked, and why it failed.
+    last: Option<(Instant, Option<Unreachable>)>,
+}
+
+impl Preflight {
+    /// `upload_preflight`: `block`, `warn` or `off` (default).
+    pub fn from_env() -> eyre::Result<Self> {
+        let policy = match std::env::var("upload_preflight").as_deref() {
+            Ok("block") => Policy::Block,
+            Ok("warn") => Policy::Warn,
+            Ok("off") | Err(_) => Policy::Off,
+            Ok(x) => bail!("Invalid upload_preflight: {x} (expected block, warn or off)"),
+        };
+
+        Ok(Self { policy, last: None })
+    }
+
+    /// Why the upload host is unreachable or one of `targets` cannot be
+    /// written, `None` if all passed or the check is off. Checked again if the last check is long enough ago.
+    pub async fn check(&mut self, ssh: &SshConfig, targets: &[Target]) -> Option<Unreachable> {
+        if self.policy == Policy::Off {
+            return None;
+        }
+
+        if let Some((at, ref res)) = self.last {
+            if at.elapsed() < CHECK_INTERVAL {
+                return res.clone();
+            }
+        }
+
+        let res = match probe(ssh, targets).await {
+            Ok(()) => None,
+            Err(e) => {
+                let blocking = self.policy == Policy::Block;
+                if blocking {
+                    error!(
+                        "Upload host {} failed the check, not taking jobs until it passes: {e}",
+                        ssh.host
+                    );
+                } else {
+                    warn!(
+                        "Upload host {} failed the check, taking jobs anyway: {e}",
+                        ssh.host
+                    );
+                }
+                Some(Unreachable {
+                    error: e.to_string(),
+                    blocking,
+                })
+            }
+        };
+        self.last = Some((Instant::now(), res.clone()));
+
+        res
+    }
+}
+
+/// A directory on the upload host that uploads go to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Target {
+    /// What goes there, e.g. `livekit, release`.
+    pub name: String,
+    /// The part of the directory template before its first variable.
+    pub dir: String,
+}
+
+/// The targets of `publishes`, one per directory.
+pub fn targets(publishes: &[(&str, &Publish)]) -> Vec<Target> {
+    let mut targets: Vec<Target> = vec![];
+    for (name, publish) in publishes {
+        let dir = fixed_part(&publish.dir);
+        match targets.iter_mut().find(|x| x.dir == dir) {
+            Some(t) => t.name = format!("{}, {name}", t.name),
+            None => targets.push(Target {
+                name: name.to_string(),
+                dir,
+            }),
+        }
+    }
+
+    targets
+}
+
+/// `dir` up to the directory containing its first variable.
+fn fixed_part(dir: &str) -> String {
+    let fixed = match dir.find('{') {
+        Some(i) => dir[..i].rsplit_once('/').map_or("", |x| x.0),
+        None => dir,
+    };
+
+    match fixed {
+        "" if dir.starts_with('/') => "/".to_string(),
+        "" => ".".to_string(),
+        x => x.to_string(),
+    }
+}
+
+/// Shell command failing unless the nearest existing parent of `dir`, which
+/// uploads create when missing, is a writable directory.
+fn writable(dir: &str) -> String {
+    format!(
+        "d={}; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; \
+         [ -d \"$d\" ] && [ -w \"$d\" ] || {{ echo \"$d is not a writable directory\" >&2; exit 1; }}",
+        shell_quote(dir)
+    )
+}
+
+/// Check each of `targets` on the upload host, or only that it answers
+/// without targets. The error names the target that failed.
+pub async fn probe(ssh: &SshConfig, targets: &[Target]) -> eyre::Result<()> {
+    if targets.is_empty() {
+        return run(ssh, "true").await;
+    }
+
+    for t in targets {
+        if let Err(e) = run(ssh, &writable(&t.dir)).await {
+            bail!("{} ({}): {e}", t.name, t.dir);
+        }
+    }
+
+    Ok(())
+}
+
+async fn run(ssh: &SshConfig, command: &str) -> eyre::Result<()> {
+    let mut args = vec![
+        "-o".to_string(),
+        format!("ConnectTimeout={}", CONNECT_TIMEOUT.as_secs()),
+    ];
+    args.extend(ssh.ssh_args(command));
+
+    let output = Command::new("ssh")
+        .args(&args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let Ok(output) = timeout(CONNECT_TIMEOUT * 2, output).await else {
+        bail!("ssh timed out");
+    };
+    let output = output?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.lines().last() {
+            Some(line) => bail!("{}", line.trim()),
+            None => bail!("ssh {}", output.status),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs;
+
+    use super::*;
+    use crate::tests::Workdir;
+
+    fn publish(dir: &str) -> Publish {
+        Publish {
+            dir: dir.to_string(),
+            base_url: None,
+            private: false,
+            channel: shipit_common::Channel::Release,
+            nightly_subdir: true,
+        }
+    }
+
+    /// Run the check of `dir` here instead of on the upload host.
+    async fn check_here(dir: &str) -> Result<(), String> {
+        let output = Command::new("sh")
+            .args(["-c", &writable(dir)])
+            .output()
+            .await
+            .unwrap();
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(String::from_utf8(output.stderr).unwrap().trim().to_string()),
+        }
+    }
+
+    #[test]
+    fn each_directory_is_one_target_up_to_its_first_variable() {
+        let aosc_os = publish("/lookaside/private/aosc-os");
+        let rootfs = publish("/lookaside/private/aosc-os/rootfs/{arch}");
+        let logs = publish("/buildit/logs-{date}");
+        let relative = publish("{channel}/{arch}");
+
+        assert_eq!(
+            targets(&[
+                ("livekit", &aosc_os),
+                ("release", &aosc_os),
+                ("rootfs", &rootfs),
+                ("log", &logs),
+                ("scratch", &relative),
+            ]),
+            [
+                ("livekit, release", "/lookaside/private/aosc-os"),
+                ("rootfs", "/lookaside/private/aosc-os/rootfs"),
+                ("log", "/buildit"),
+                ("scratch", "."),
+            ]
+            .map(|(name, dir)| Target {
+                name: name.to_string(),
+                dir: dir.to_string(),
+            })
+        );
+        assert_eq!(fixed_part("/{arch}"), "/");
+    }
+
+    #[tokio::test]
+    async fn a_missing_directory_is_checked_at_its_nearest_parent() {
+        let dir = Workdir::enter("preflight").await;
+        let file = dir.path.join("aosc-os");
+        fs::write(&file, "not a directory").await.unwrap();
+
+        assert_eq!(
+            check_here(dir.path.join("lookaside/it's/new").to_str().unwrap()).await,
+            Ok(())
+        );
+        assert_eq!(
+            check_here(file.join("rootfs").to_str().unwrap()).await,
+            Err(format!("{} is not a writable directory", file.display()))
+        );
+    }
+}
@@ -0,0 +1,264 @@
+//! Which nightly artifacts to remove from the upload host after a push.
+//! Only files named like the nightly builds name them are ever removed, a
+//! date stamp and an image or tarball extension, anything else in the
+//! directory is left alone.
+
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use eyre::bail;
+
+/// What the artifacts may end in, each optionally followed by `.sha256sum`.
+const EXTENSIONS: &[&str] = &[
+    ".iso",
+    ".img",
+    ".img.xz",
+    ".squashfs",
+    ".tar.xz",
+    ".tar.zst",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    /// Only log what would be removed.
+    DryRun,
+    On,
+}
+
+pub struct Retention {
+    pub mode: Mode,
+    /// Artifacts dated further back are removed.
+    max_age_days: u64,
+    /// Of each series, only the artifacts of this many newest dates are
+    /// kept.
+    keep: Option<usize>,
+}
+
+impl Retention {
+    /// `nightly_prune` is `off` (the default), `dry-run` or `on`.
+    /// `nightly_retention_days` (default 14) and `nightly_retention_count`
+    /// (default unlimited) limit the age and the number of builds kept.
+    pub fn from_env() -> eyre::Result<Self> {
+        let mode = match std::env::var("nightly_prune").as_deref() {
+            Ok("on") => Mode::On,
+            Ok("dry-run") => Mode::DryRun,
+            Err(_) | Ok("off") => Mode::Off,
+            Ok(x) => bail!("Invalid nightly_prune {x}, expected on, dry-run or off"),
+        };
+        let max_age_days = match std::env::var("nightly_retention_days") {
+            Ok(x) => x.parse()?,
+            Err(_) => 14,
+        };
+        let keep = match std::env::var("nightly_retention_count") {
+            Ok(x) => Some(x.parse()?),
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            mode,
+            max_age_days,
+            keep,
+        })
+    }
+}
+
+/// A nightly artifact, found by its name.
+struct Dated<'a> {
+    path: &'a str,
+    /// The path with the date left out, the same for every build.
+    series: String,
+    date: NaiveDate,
+}
+
+/// `path` relative to the upload directory, if named like a nightly
+/// artifact: a `YYYYMMDD` between `-`, `_` or `.`, no other date, and a
+/// known extension.
+fn parse(path: &str) -> Option<Dated<'_>> {
+    if path.is_empty()
+        || path.starts_with('/')
+        || path
+            .split('/')
+            .any(|x| x.is_empty() || x == "." || x == "..")
+    {
+        return None;
+    }
+
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, path),
+    };
+    let base = name.strip_suffix(".sha256sum").unwrap_or(name);
+    if !EXTENSIONS.iter().any(|x| base.ends_with(x)) {
+        return None;
+    }
+
+    let mut dates = name
+        .split(['-', '_', '.'])
+        .filter(|x| x.len() == 8 && x.bytes().all(|b| b.is_ascii_digit()))
+        .filter_map(|x| Some((x, NaiveDate::parse_from_str(x, "%Y%m%d").ok()?)));
+    // livekit names may carry the date twice, from aosc-mklive and from us
+    let (stamp, date) = dates.next()?;
+    if dates.any(|(x, _)| x != stamp) {
+        return None;
+    }
+
+    let name = name.replace(stamp, "{date}");
+    Some(Dated {
+        path,
+        series: match dir {
+            Some(dir) => format!("{dir}/{name}"),
+            None => name,
+        },
+        date,
+    })
+}
+
+/// The files out of `paths` to remove as of `today`, with the reason.
+pub fn plan<'a>(
+    paths: &'a [String],
+    today: NaiveDate,
+    retention: &Retention,
+) -> Vec<(&'a str, String)> {
+    let mut series: BTreeMap<String, Vec<Dated>> = BTreeMap::new();
+    for d in paths.iter().filter_map(|x| parse(x)) {
+        series.entry(d.series.clone()).or_default().push(d);
+    }
+
+    let mut res = vec![];
+    for files in series.values() {
+        let mut dates = files.iter().map(|x| x.date).collect::<Vec<_>>();
+        dates.sort_unstable_by(|a, b| b.cmp(a));
+        dates.dedup();
+
+        for f in files {
+            let age = (today - f.date).num_days();
+            let newer = dates.iter().take_while(|x| **x > f.date).count();
+            if age > retention.max_age_days as i64 {
+                res.push((
+                    f.path,
+                    format!("older than {} days", retention.max_age_days),
+                ));
+            } else if let Some(keep) = retention.keep.filter(|x| newer >= *x) {
+                res.push((f.path, format!("beyond the newest {keep} builds")));
+            }
+        }
+    }
+    res.sort();
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retention(max_age_days: u64, keep: Option<usize>) -> Retention {
+        Retention {
+            mode: Mode::On,
+            max_age_days,
+            keep,
+        }
+    }
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 5, d).unwrap()
+    }
+
+    #[test]
+    fn names_unlike_a_nightly_artifact_are_refused() {
+        for path in [
+            "",
+            "../aosc-os_base_20240501_amd64.tar.xz",
+            "nightly/../aosc-os_base_20240501_amd64.tar.xz",
+            "./aosc-os_base_20240501_amd64.tar.xz",
+            "nightly//aosc-os_base_20240501_amd64.tar.xz",
+            "/lookaside/private/aosc-os/aosc-os_base_20240501_amd64.tar.xz",
+            "aosc-os_base_20240501_20240502_amd64.tar.xz",
+            "aosc-os_base_20240501_amd64.tar.gz",
+            "aosc-os_base_20240501_amd64.tar.xz.asc",
+            "aosc-os_base_amd64.tar.xz",
+            "aosc-os_base_20241340_amd64.tar.xz",
+            "aosc-os_base_2024050_amd64.tar.xz",
+        ] {
+            assert!(parse(path).is_none(), "{path}");
+        }
+    }
+
+    #[test]
+    fn nightly_artifacts_are_parsed_into_their_series() {
+        for (path, series) in [
+            (
+                "base/aosc-os_base_20240501_amd64.tar.xz",
+                "base/aosc-os_base_{date}_amd64.tar.xz",
+            ),
+            (
+                "aosc-os_base_20240501_amd64.tar.xz.sha256sum",
+                "aosc-os_base_{date}_amd64.tar.xz.sha256sum",
+            ),
+            // the same date twice, as livekit names have it
+            (
+                "aosc-os_livekit_20240501_amd64-20240501.iso",
+                "aosc-os_livekit_{date}_amd64-{date}.iso",
+            ),
+        ] {
+            let d = parse(path).unwrap();
+            assert_eq!(d.series, series);
+            assert_eq!(d.date, day(1));
+        }
+    }
+
+    #[test]
+    fn artifacts_past_the_age_or_count_are_removed() {
+        let paths = [
+            "aosc-os_base_20240501_amd64.tar.xz",
+            "aosc-os_base_20240501_amd64.tar.xz.sha256sum",
+            "aosc-os_base_20240506_amd64.tar.xz",
+            "aosc-os_base_20240510_amd64.tar.xz",
+            "aosc-os_base_20240515_amd64.tar.xz",
+            "aosc-os_base_20240520_amd64.tar.xz",
+            "aosc-os_desktop_20240501_amd64.tar.xz",
+            "README",
+            "aosc-os_base_20240401_amd64.txt",
+        ]
+        .map(String::from);
+
+        // 20240506 is 14 days old, not older
+        let by_age = plan(&paths, day(20), &retention(14, None));
+        assert_eq!(
+            by_age,
+            [
+                ("aosc-os_base_20240501_amd64.tar.xz", "older than 14 days"),
+                (
+                    "aosc-os_base_20240501_amd64.tar.xz.sha256sum",
+                    "older than 14 days"
+                ),
+                (
+                    "aosc-os_desktop_20240501_amd64.tar.xz",
+                    "older than 14 days"
+                ),
+            ]
+            .map(|(p, r)| (p, r.to_string()))
+        );
+
+        let by_count = plan(&paths, day(20), &retention(30, Some(2)));
+        assert_eq!(
+            by_count,
+            [
+                (
+                    "aosc-os_base_20240501_amd64.tar.xz",
+                    "beyond the newest 2 builds"
+                ),
+                (
+                    "aosc-os_base_20240506_amd64.tar.xz",
+                    "beyond the newest 2 builds"
+                ),
+                (
+                    "aosc-os_base_20240510_amd64.tar.xz",
+                    "beyond the newest 2 builds"
+                ),
+            ]
+            .map(|(p, r)| (p, r.to_string()))
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Masking secrets in whatever the worker writes down: job logs, its own
+//! tracing output and the spool files. The secrets are known once at
+//! startup, see [`init`]; until then nothing is masked.
+
+use std::{borrow::Cow, io, sync::OnceLock};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+const MASK: &[u8] = b"[redacted]";
+/// Shorter values, and shorter lines of the ssh key, are too likely to
+/// appear by chance to be masked.
+const MIN_LEN: usize = 8;
+
+static SECRETS: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+
+/// Mask `shipit_secret`, every variable whose name contains `secret` or
+/// `token`, those listed in `redact_env`, and the lines of the ssh key at
+/// `ssh_key`.
+pub async fn init(ssh_key: &str) -> eyre::Result<()> {
+    let listed = std::env::var("redact_env")
+        .unwrap_or_default()
+        .split_ascii_whitespace()
+        .map(|x| x.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    let mut secrets = std::env::vars()
+        .filter(|(k, _)| {
+            let k = k.to_ascii_lowercase();
+            k.contains("secret") || k.contains("token") || listed.contains(&k)
+        })
+        .map(|(_, v)| v.trim().to_string())
+        .collect::<Vec<_>>();
+
+    // the key itself is passed by path, only its content is secret
+    let key = tokio::fs::read_to_string(ssh_key).await?;
+    secrets.extend(
+        key.lines()
+            .map(|x| x.trim())
+            .filter(|x| !x.starts_with("-----"))
+            .map(|x| x.to_string()),
+    );
+
+    let mut secrets = secrets
+        .into_iter()
+        .filter(|x| x.len() >= MIN_LEN)
+        .map(|x| x.into_bytes())
+        .collect::<Vec<_>>();
+    // the longest first, should one contain another
+    secrets.sort_unstable_by_key(|x| std::cmp::Reverse(x.len()));
+    secrets.dedup();
+
+    SECRETS
+        .set(secrets)
+        .map_err(|_| eyre::eyre!("Secrets to redact are already set"))
+}
+
+fn secrets() -> &'static [Vec<u8>] {
+    SECRETS.get().map(|x| x.as_slice()).unwrap_or_default()
+}
+
+/// `buf` with every secret masked.
+pub fn bytes(buf: &[u8]) -> Cow<'_, [u8]> {
+    let secrets = secrets();
+    if !secrets.iter().any(|s| contains(buf, s)) {
+        return Cow::Borrowed(buf);
+    }
+
+    let mut res = Vec::with_capacity(buf.len());
+    let mut i = 0;
+    'outer: while i < buf.len() {
+        for s in secrets {
+            if buf[i..].starts_with(s) {
+                res.extend_from_slice(MASK);
+                i += s.len();
+                continue 'outer;
+            }
+        }
+        res.push(buf[i]);
+        i += 1;
+    }
+
+    Cow::Owned(res)
+}
+
+/// How many bytes at the end of `buf` might be the start of a secret
+/// continued by the next write, to hold back until then.
+pub fn partial_len(buf: &[u8]) -> usize {
+    let secrets = secrets();
+    let longest = secrets.first().map(|x| x.len()).unwrap_or(0);
+
+    (1..longest.min(buf.len() + 1))
+        .rev()
+        .find(|n| {
+            let tail = &buf[buf.len() - n..];
+            secrets.iter().any(|s| s.len() > *n && s.starts_with(tail))
+        })
+        .unwrap_or(0)
+}
+
+fn contains(buf: &[u8], s: &[u8]) -> bool {
+    buf.windows(s.len()).any(|x| x == s)
+}
+
+/// The tracing output, one formatted event per write.
+pub struct Stdout;
+
+impl io::Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write_all(&bytes(buf))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for Stdout {
+    type Writer = Stdout;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Stdout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joblog::JobLog;
+
+    const SECRET: &str = "hunter2-0f8a9c1e77d3";
+
+    fn init() {
+        SECRETS.get_or_init(|| vec![SECRET.as_bytes().to_vec()]);
+    }
+
+    /// What ends up in the log file and its tail after writing `writes`.
+    async fn logged(name: &str, writes: &[&[u8]]) -> (String, String) {
+        init();
+        let path =
+            std::env::temp_dir().join(format!("shipit-redact-{name}-{}.log", std::process::id()));
+        let mut log = JobLog::create(&path).await.unwrap();
+        for w in writes {
+            log.write(w).await.unwrap();
+        }
+        log.flush().await.unwrap();
+        let file = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        (file, log.tail())
+    }
+
+    fn line() -> String {
+        format!("curl -H 'Authorization: {SECRET}' https://repo.aosc.io\n")
+    }
+
+    #[tokio::test]
+    async fn a_secret_in_one_write_is_masked() {
+        let (file, tail) = logged("whole", &[line().as_bytes()]).await;
+
+        assert_eq!(
+            file,
+            "curl -H 'Authorization: [redacted]' https://repo.aosc.io\n"
+        );
+        assert_eq!(tail, file);
+    }
+
+    #[tokio::test]
+    async fn a_secret_split_across_writes_is_masked() {
+        let line = line();
+        let start = line.find(SECRET).unwrap();
+        for at in start..=start + SECRET.len() {
+            let (a, b) = line.as_bytes().split_at(at);
+            let (file, tail) = logged(&format!("split-{at}"), &[a, b]).await;
+
+            assert!(!file.contains(SECRET), "split at {at}: {file}");
+            assert!(!tail.contains(SECRET), "split at {at}: {tail}");
+            assert!(file.contains("[redacted]"), "split at {at}: {file}");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_secret_written_a_byte_at_a_time_is_masked() {
+        let line = line();
+        let writes = line.as_bytes().chunks(1).collect::<Vec<_>>();
+        let (file, _) = logged("bytes", &writes).await;
+
+        assert!(!file.contains(SECRET), "{file}");
+        assert!(file.contains("[redacted]"));
+    }
+
+    #[tokio::test]
+    async fn what_only_looked_like_a_secret_is_written_on_flush() {
+        let prefix = &SECRET[..SECRET.len() - 1];
+        let (file, _) = logged("prefix", &[b"token: ", prefix.as_bytes()]).await;
+
+        assert_eq!(file, format!("token: {prefix}"));
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How often and how long to retry a command.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// No attempt is started after this much time in total.
+    pub budget: Duration,
+}
+
+impl RetryPolicy {
+    /// Read `retry_{site}_attempts`, `retry_{site}_base_secs`,
+    /// `retry_{site}_max_secs` and `retry_{site}_budget_secs`, falling back
+    /// to `default` for the unset ones.
+    pub fn from_env(site: &str, default: RetryPolicy) -> eyre::Result<Self> {
+        let var = |name: &str| std::env::var(format!("retry_{site}_{name}")).ok();
+        let secs = |name: &str, default: Duration| -> eyre::Result<Duration> {
+            Ok(match var(name) {
+                Some(x) => Duration::from_secs(x.parse()?),
+                None => default,
+            })
+        };
+
+        Ok(Self {
+            attempts: match var("attempts") {
+                Some(x) => x.parse()?,
+                None => default.attempts,
+            },
+            base_delay: secs("base_secs", default.base_delay)?,
+            max_delay: secs("max_secs", default.max_delay)?,
+            budget: secs("budget_secs", default.budget)?,
+        })
+    }
+
+    /// Delay before retrying after the `attempt`th (0-based) failure: "full
+    /// jitter", uniformly random up to the capped exponential backoff.
+    /// `random` is a uniformly distributed value.
+    pub fn delay(&self, attempt: u32, random: u64) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(31))
+            .min(self.max_delay);
+        let millis = cap.as_millis() as u64;
+
+        Duration::from_millis(if millis == 0 {
+            0
+        } else {
+            random % (millis + 1)
+        })
+    }
+}
+
+/// Policies of the different kinds of retried commands.
+#[derive(Debug, Clone)]
+pub struct Retries {
+    /// Uploading images, which may take hours over a slow link.
+    pub upload: RetryPolicy,
+    pub log: RetryPolicy,
+    pub git: RetryPolicy,
+}
+
+impl Retries {
+    pub fn from_env() -> eyre::Result<Self> {
+        let short = RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            budget: Duration::from_secs(10 * 60),
+        };
+
+        Ok(Self {
+            upload: RetryPolicy::from_env(
+                "upload",
+                RetryPolicy {
+                    attempts: 8,
+                    base_delay: Duration::from_secs(10),
+                    max_delay: Duration::from_secs(10 * 60),
+                    budget: Duration::from_secs(12 * 3600),
+                },
+            )?,
+            log: RetryPolicy::from_env("log", short.clone())?,
+            git: RetryPolicy::from_env("git", short)?,
+        })
+    }
+}
+
+/// What retrying a command amounted to, reported to the server.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RetryOutcome {
+    pub success: bool,
+    pub attempts: u32,
+    pub total_secs: f64,
+    /// Exit status, or the error spawning the command, of the last attempt.
+    pub last_status: Option<String>,
+    /// The last lines of output of the last failed attempt.
+    pub excerpt: Option<String>,
+}
+
+pub fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            budget: Duration::from_secs(600),
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_cap() {
+        let p = policy();
+        for (attempt, cap) in [(0, 1), (1, 2), (5, 32), (6, 60), (40, 60)] {
+            assert_eq!(
+                p.delay(attempt, cap * 1000),
+                Duration::from_secs(cap),
+                "{attempt}"
+            );
+            assert_eq!(p.delay(attempt, 0), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn delays_are_jittered_below_the_cap() {
+        let p = policy();
+        for _ in 0..100 {
+            assert!(p.delay(3, random()) <= Duration::from_secs(8));
+        }
+        let none = RetryPolicy {
+            base_delay: Duration::ZERO,
+            ..policy()
+        };
+        assert_eq!(none.delay(3, random()), Duration::ZERO);
+    }
+
+    #[test]
+    fn the_environment_overrides_what_it_sets() {
+        std::env::set_var("retry_mirror_attempts", "2");
+        std::env::set_var("retry_mirror_budget_secs", "30");
+        let p = RetryPolicy::from_env("mirror", policy()).unwrap();
+        assert_eq!(p.attempts, 2);
+        assert_eq!(p.budget, Duration::from_secs(30));
+        assert_eq!(p.base_delay, Duration::from_secs(1));
+        assert_eq!(p.max_delay, Duration::from_secs(60));
+
+        std::env::set_var("retry_mirror_attempts", "many");
+        assert!(RetryPolicy::from_env("mirror", policy()).is_err());
+    }
+}
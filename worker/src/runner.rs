@@ -0,0 +1,249 @@
+//! Running external commands, behind a trait so the build flows do not
+//! depend on a real process being spawned.
+
+use std::{
+    path::Path,
+    process::{ExitStatus, Output, Stdio},
+};
+
+use eyre::{bail, OptionExt};
+use tokio::{io::AsyncReadExt, process::Command};
+
+use crate::joblog::JobLog;
+
+/// The unprivileged user build scripts run as, see `run_as`. The worker
+/// itself stays root for what needs it, such as reading the ssh key.
+#[derive(Debug, Clone)]
+pub struct RunAs {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl RunAs {
+    /// `run_as` names a user of `/etc/passwd`, unset runs the scripts as
+    /// the worker.
+    pub async fn from_env() -> eyre::Result<Option<Self>> {
+        let Ok(name) = std::env::var("run_as") else {
+            return Ok(None);
+        };
+
+        let passwd = tokio::fs::read_to_string("/etc/passwd").await?;
+        for line in passwd.lines() {
+            let mut split = line.split(':');
+            if split.next() != Some(name.as_str()) {
+                continue;
+            }
+
+            let (Some(uid), Some(gid)) = (split.nth(1), split.next()) else {
+                bail!("Unreadable /etc/passwd entry of {name}");
+            };
+            return Ok(Some(Self {
+                uid: uid.parse()?,
+                gid: gid.parse()?,
+            }));
+        }
+
+        bail!("run_as user {name} does not exist")
+    }
+
+    /// `setpriv` arguments switching to the user with a fresh environment,
+    /// so the script sees none of the secrets of the worker.
+    pub fn setpriv_args(&self) -> Vec<String> {
+        vec![
+            format!("--reuid={}", self.uid),
+            format!("--regid={}", self.gid),
+            "--init-groups".to_string(),
+            "--reset-env".to_string(),
+        ]
+    }
+
+    /// `cmd` with `args`, through `setpriv` if `run_as` is set.
+    pub fn command(run_as: Option<&Self>, cmd: &str, args: &[&str]) -> (String, Vec<String>) {
+        let args = args.iter().map(|x| x.to_string());
+        match run_as {
+            Some(r) => {
+                let mut v = r.setpriv_args();
+                v.push(cmd.to_string());
+                v.extend(args);
+                ("setpriv".to_string(), v)
+            }
+            None => (cmd.to_string(), args.collect()),
+        }
+    }
+}
+
+pub trait CommandRunner {
+    /// Run `cmd` in `cwd` to completion, passing its stdout and stderr to
+    /// `log` as they arrive.
+    async fn run(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        cwd: &Path,
+        log: &mut JobLog,
+    ) -> eyre::Result<ExitStatus>;
+
+    /// Run `cmd` in `cwd` to completion with no input, and return what it
+    /// printed.
+    async fn output(&self, cmd: &str, args: &[&str], cwd: &Path) -> eyre::Result<Output>;
+}
+
+/// Spawns the commands as child processes.
+pub struct ProcessRunner;
+
+impl CommandRunner for ProcessRunner {
+    async fn run(
+        &self,
+        cmd: &str,
+        args: &[&str],
+        cwd: &Path,
+        log: &mut JobLog,
+    ) -> eyre::Result<ExitStatus> {
+        let mut child = Command::new(cmd)
+            .args(args)
+            .current_dir(cwd)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // a cancelled build is dropped
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut stdout = child.stdout.take().ok_or_eyre("stdout is not piped")?;
+        let mut stderr = child.stderr.take().ok_or_eyre("stderr is not piped")?;
+        let mut out_buf = vec![0; 8192];
+        let mut err_buf = vec![0; 8192];
+        let (mut out_done, mut err_done) = (false, false);
+
+        log.write("OUTPUT:\n".as_bytes()).await?;
+        while !(out_done && err_done) {
+            tokio::select! {
+                n = stdout.read(&mut out_buf), if !out_done => match n? {
+                    0 => out_done = true,
+                    n => log.write(&out_buf[..n]).await?,
+                },
+                n = stderr.read(&mut err_buf), if !err_done => match n? {
+                    0 => err_done = true,
+                    n => log.write(&err_buf[..n]).await?,
+                },
+            }
+        }
+
+        Ok(child.wait().await?)
+    }
+
+    async fn output(&self, cmd: &str, args: &[&str], cwd: &Path) -> eyre::Result<Output> {
+        Ok(Command::new(cmd)
+            .args(args)
+            .current_dir(cwd)
+            .stdin(Stdio::null())
+            .output()
+            .await?)
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    //! A [`CommandRunner`] playing a script instead of running anything.
+
+    use std::{os::unix::process::ExitStatusExt, path::PathBuf, sync::Mutex};
+
+    use eyre::bail;
+
+    use super::*;
+
+    struct Step {
+        cmd: &'static str,
+        /// One of the arguments, any if empty.
+        arg: &'static str,
+        code: i32,
+        stdout: &'static str,
+        /// Done in the working directory, as if by the command.
+        effect: Option<fn(&Path)>,
+    }
+
+    /// Answers each command with the first step matching it, and fails the
+    /// ones it has none for.
+    #[derive(Default)]
+    pub struct Script {
+        steps: Vec<Step>,
+        calls: Mutex<Vec<(String, PathBuf)>>,
+    }
+
+    impl Script {
+        /// `cmd` with `arg` among its arguments, or any if empty, exits with
+        /// `code`.
+        pub fn on(mut self, cmd: &'static str, arg: &'static str, code: i32) -> Self {
+            self.steps.push(Step {
+                cmd,
+                arg,
+                code,
+                stdout: "",
+                effect: None,
+            });
+            self
+        }
+
+        /// The last command prints `stdout`.
+        pub fn prints(mut self, stdout: &'static str) -> Self {
+            self.steps.last_mut().expect("no command").stdout = stdout;
+            self
+        }
+
+        /// The last command does `effect` in its working directory.
+        pub fn does(mut self, effect: fn(&Path)) -> Self {
+            self.steps.last_mut().expect("no command").effect = Some(effect);
+            self
+        }
+
+        /// The commands run so far, as `cmd args...`, and where.
+        pub fn calls(&self) -> Vec<(String, PathBuf)> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn play(&self, cmd: &str, args: &[&str], cwd: &Path) -> eyre::Result<&Step> {
+            let line = [&[cmd], args].concat().join(" ");
+            self.calls
+                .lock()
+                .unwrap()
+                .push((line.clone(), cwd.to_path_buf()));
+
+            let Some(step) = self
+                .steps
+                .iter()
+                .find(|x| x.cmd == cmd && (x.arg.is_empty() || args.contains(&x.arg)))
+            else {
+                bail!("unexpected command `{line}`");
+            };
+            if let Some(effect) = step.effect {
+                effect(cwd);
+            }
+
+            Ok(step)
+        }
+    }
+
+    impl CommandRunner for Script {
+        async fn run(
+            &self,
+            cmd: &str,
+            args: &[&str],
+            cwd: &Path,
+            log: &mut JobLog,
+        ) -> eyre::Result<ExitStatus> {
+            let step = self.play(cmd, args, cwd)?;
+            log.write(step.stdout.as_bytes()).await?;
+
+            Ok(ExitStatus::from_raw(step.code << 8))
+        }
+
+        async fn output(&self, cmd: &str, args: &[&str], cwd: &Path) -> eyre::Result<Output> {
+            let step = self.play(cmd, args, cwd)?;
+
+            Ok(Output {
+                status: ExitStatus::from_raw(step.code << 8),
+                stdout: step.stdout.as_bytes().to_vec(),
+                stderr: vec![],
+            })
+        }
+    }
+}
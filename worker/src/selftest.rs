@@ -0,0 +1,273 @@
+//! `worker check`: what a new deployment gets wrong, checked without
+//! building or uploading anything. The server and the secret, the upload
+//! host, the tools builds need, free disk and the clock, printed as a table.
+//! Exits non-zero on a failure. A running worker also runs the checks for
+//! `/ping <arch> --check`.
+
+use std::{path::PathBuf, process::Stdio, time::Duration};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use shipit_common::{human_duration, CheckResult, Outcome};
+use tokio::{process::Command, time::timeout};
+
+use crate::{
+    clock::{self, ClockCheck},
+    preflight::{self, Target},
+    ssh::SshConfig,
+    timeline, vitals, Server,
+};
+
+/// For each request to the server and each tool asked for its version.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The tools builds and uploads run, the version flag if they have one, and
+/// whether they are needed.
+const TOOLS: &[(&str, Option<&str>, bool)] = &[
+    ("git", Some("--version"), true),
+    ("bash", Some("--version"), true),
+    ("ssh", Some("-V"), true),
+    ("scp", None, true),
+    ("mksquashfs", Some("-version"), true),
+    ("rsync", Some("--version"), false),
+];
+
+/// What `GET /authcheck` answers.
+#[derive(Deserialize)]
+struct AuthCheck {
+    pool: String,
+    min_free_disk: Option<u64>,
+    /// Set if the secret was replaced with `/rotate-secret`, when it is
+    /// refused from.
+    #[serde(default)]
+    previous_until: Option<u64>,
+}
+
+/// Run the checks, print them and exit with 1 if one failed. `server` is
+/// the configuration read, or why it could not be.
+pub async fn cli(server: eyre::Result<Server>, targets: &[Target], arch: &str) -> eyre::Result<()> {
+    let ssh = SshConfig::from_env().await;
+    let results = run(server.as_ref(), ssh.as_ref(), targets, arch).await;
+
+    let width = results.iter().map(|x| x.name.len()).max().unwrap_or(0);
+    for r in &results {
+        println!("{:<width$}  {:<4}  {}", r.name, r.outcome.name(), r.detail);
+    }
+
+    if results.iter().any(|x| x.outcome == Outcome::Fail) {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+pub async fn run(
+    server: Result<&Server, &eyre::Report>,
+    ssh: Result<&SshConfig, &eyre::Report>,
+    targets: &[Target],
+    arch: &str,
+) -> Vec<CheckResult> {
+    let mut results = vec![];
+    let result = |name: &str, outcome, detail: String| CheckResult {
+        name: name.to_string(),
+        outcome,
+        detail,
+    };
+
+    let auth = match server {
+        Ok(server) => {
+            let (outcome, detail, auth) = authcheck(server, arch).await;
+            results.push(result("server", outcome, detail));
+            auth
+        }
+        Err(e) => {
+            results.push(result("server", Outcome::Fail, e.to_string()));
+            None
+        }
+    };
+
+    results.push(match ssh {
+        Ok(ssh) => match preflight::probe(ssh, targets).await {
+            Ok(()) => result(
+                "upload",
+                Outcome::Pass,
+                match targets.len() {
+                    0 => format!("{}@{} answers", ssh.user, ssh.host),
+                    n => format!(
+                        "{}@{} answers, {n} upload directories writable",
+                        ssh.user, ssh.host
+                    ),
+                },
+            ),
+            Err(e) => result(
+                "upload",
+                Outcome::Fail,
+                format!("{}@{}: {e}", ssh.user, ssh.host),
+            ),
+        },
+        Err(e) => result("upload", Outcome::Fail, e.to_string()),
+    });
+
+    for (tool, flag, needed) in TOOLS {
+        let name = format!("tool {tool}");
+        results.push(match tool_version(tool, *flag).await {
+            Ok(version) => result(&name, Outcome::Pass, version),
+            Err(e) => result(
+                &name,
+                if *needed {
+                    Outcome::Fail
+                } else {
+                    Outcome::Warn
+                },
+                e.to_string(),
+            ),
+        });
+    }
+
+    let min = auth.and_then(|x| x.min_free_disk);
+    results.push(match (vitals::free_disk().await, min) {
+        (Ok(free), Some(min)) if free < min => result(
+            "disk",
+            Outcome::Fail,
+            format!(
+                "{} free, the server hands out jobs from {}",
+                gib(free),
+                gib(min)
+            ),
+        ),
+        (Ok(free), _) => result("disk", Outcome::Pass, format!("{} free", gib(free))),
+        (Err(e), _) => result("disk", Outcome::Fail, e.to_string()),
+    });
+
+    if let Ok(server) = server {
+        results.push(clock_check(server).await);
+    }
+
+    results
+}
+
+fn gib(bytes: u64) -> String {
+    format!("{:.1} GiB", bytes as f64 / (1u64 << 30) as f64)
+}
+
+/// Whether the server answers and takes the secret.
+async fn authcheck(server: &Server, arch: &str) -> (Outcome, String, Option<AuthCheck>) {
+    let res = server
+        .client
+        .get(format!("{}/authcheck", server.uri))
+        .query(&[("arch", arch)])
+        .header("secret", &server.secret)
+        .timeout(TIMEOUT)
+        .send()
+        .await;
+
+    let resp = match res {
+        Ok(x) => x,
+        Err(e) => return (Outcome::Fail, format!("{}: {e}", server.uri), None),
+    };
+    match resp.status() {
+        StatusCode::OK => {}
+        StatusCode::BAD_REQUEST => {
+            return (
+                Outcome::Fail,
+                format!("{} refuses shipit_secret", server.uri),
+                None,
+            )
+        }
+        StatusCode::NOT_FOUND => {
+            return (
+                Outcome::Warn,
+                format!("{} answers, but is too old to check the secret", server.uri),
+                None,
+            )
+        }
+        status => return (Outcome::Fail, format!("{}: {status}", server.uri), None),
+    }
+
+    let auth: AuthCheck = match resp.json().await {
+        Ok(x) => x,
+        Err(e) => return (Outcome::Fail, format!("{}: {e}", server.uri), None),
+    };
+    let declared = server.pool.as_deref().unwrap_or("mainline");
+    if auth.pool != declared {
+        return (
+            Outcome::Fail,
+            format!(
+                "shipit_secret is the one of pool {}, not of {declared}",
+                auth.pool
+            ),
+            Some(auth),
+        );
+    }
+
+    if let Some(until) = auth.previous_until {
+        let detail = format!(
+            "shipit_secret is the old one of pool {}, refused in {}: set the new one",
+            auth.pool,
+            human_duration(until.saturating_sub(timeline::now()))
+        );
+        return (Outcome::Warn, detail, Some(auth));
+    }
+
+    let detail = format!("{} takes the secret of pool {}", server.uri, auth.pool);
+    (Outcome::Pass, detail, Some(auth))
+}
+
+/// Where `tool` is in `PATH`.
+fn find(tool: &str) -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|x| x.join(tool))
+        .find(|x| x.is_file())
+}
+
+/// The first line `tool` prints for `flag`, or where it is if it has none.
+async fn tool_version(tool: &str, flag: Option<&str>) -> eyre::Result<String> {
+    let Some(path) = find(tool) else {
+        eyre::bail!("not found in PATH");
+    };
+    let Some(flag) = flag else {
+        return Ok(path.display().to_string());
+    };
+
+    let output = Command::new(&path)
+        .arg(flag)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let Ok(output) = timeout(TIMEOUT, output).await else {
+        eyre::bail!("{tool} {flag} timed out");
+    };
+    let output = output?;
+
+    // ssh prints its version to stderr
+    let text = [output.stdout, output.stderr]
+        .iter()
+        .map(|x| String::from_utf8_lossy(x).to_string())
+        .find(|x| !x.trim().is_empty())
+        .unwrap_or_default();
+
+    Ok(text
+        .lines()
+        .next()
+        .map(|x| x.trim().to_string())
+        .unwrap_or_else(|| path.display().to_string()))
+}
+
+async fn clock_check(server: &Server) -> CheckResult {
+    let max = ClockCheck::from_env().map(|x| x.max_skew());
+    let (outcome, detail) = match (clock::measure(server).await, max) {
+        (Ok(skew), Ok(max)) if skew.unsigned_abs() > max => (
+            Outcome::Fail,
+            format!("{skew:+}s off the server, at most {max}s allowed"),
+        ),
+        (Ok(skew), Ok(_)) => (Outcome::Pass, format!("{skew:+}s off the server")),
+        (_, Err(e)) => (Outcome::Fail, e.to_string()),
+        (Err(e), _) => (Outcome::Warn, format!("cannot compare to the server: {e}")),
+    };
+
+    CheckResult {
+        name: "clock".to_string(),
+        outcome,
+        detail,
+    }
+}
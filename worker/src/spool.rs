@@ -0,0 +1,235 @@
+//! Artifacts that could not be uploaded, kept so that `/repush` can upload
+//! them again without a rebuild. Removed after the log retention, see
+//! [`prune`].
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::warn;
+
+use crate::{
+    manifest::{Manifest, Publish},
+    redact,
+    ssh::SshConfig,
+};
+
+pub const PUSH_FAILED_DIR: &str = "push_failed";
+
+/// Describes the kept artifacts, in their spool directory.
+const UPLOAD_FILE: &str = "upload.json";
+
+/// What a build uploads, and where.
+#[derive(Serialize, Deserialize)]
+pub struct Upload {
+    /// Directory `sources` are relative to.
+    pub cwd: PathBuf,
+    pub sources: Vec<PathBuf>,
+    pub recursive: bool,
+    pub publish: Publish,
+}
+
+impl Upload {
+    pub fn scp_args(&self, ssh: &SshConfig) -> Vec<String> {
+        let sources = self.sources.iter().map(|x| x.as_path()).collect::<Vec<_>>();
+
+        ssh.scp_args(&sources, &self.publish.dir, self.recursive)
+    }
+
+    /// Where each file of the upload goes below the upload directory, as the
+    /// manifest has it, and the upload of that file alone. A recursive
+    /// upload keeps the directories of its sources, the others go flat.
+    pub async fn files(&self) -> eyre::Result<Vec<(String, Upload)>> {
+        let mut files = vec![];
+        for source in &self.sources {
+            if !self.recursive {
+                let name = source.file_name().unwrap_or(source.as_os_str());
+                files.push((
+                    name.to_string_lossy().to_string(),
+                    self.only(source.clone(), self.publish.dir.clone()),
+                ));
+                continue;
+            }
+
+            let mut stack = vec![source.clone()];
+            while let Some(path) = stack.pop() {
+                if !fs::metadata(self.cwd.join(&path)).await?.is_dir() {
+                    let dir = match path.parent().filter(|x| !x.as_os_str().is_empty()) {
+                        Some(parent) => format!(
+                            "{}/{}",
+                            self.publish.dir.trim_end_matches('/'),
+                            parent.display()
+                        ),
+                        None => self.publish.dir.clone(),
+                    };
+                    files.push((path.display().to_string(), self.only(path, dir)));
+                    continue;
+                }
+
+                let mut entries = fs::read_dir(self.cwd.join(&path)).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    stack.push(path.join(entry.file_name()));
+                }
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(files)
+    }
+
+    /// The upload of `source` alone into `dir`.
+    fn only(&self, source: PathBuf, dir: String) -> Upload {
+        Upload {
+            cwd: self.cwd.clone(),
+            sources: vec![source],
+            recursive: false,
+            publish: Publish {
+                dir,
+                ..self.publish.clone()
+            },
+        }
+    }
+}
+
+/// Artifacts of a build kept after a failed push, with what was reported
+/// about them.
+#[derive(Serialize, Deserialize)]
+pub struct Kept {
+    pub upload: Upload,
+    pub manifest: Manifest,
+}
+
+fn dir(id: i64) -> PathBuf {
+    Path::new(PUSH_FAILED_DIR).join(id.to_string())
+}
+
+/// Move the files of `upload` that failed to upload, by their paths in
+/// [`Upload::files`], to the spool of build `id`. Returns the paths of the
+/// kept artifacts.
+pub async fn keep(
+    id: i64,
+    upload: Upload,
+    manifest: &Manifest,
+    failed: &[String],
+) -> eyre::Result<Vec<String>> {
+    let dir = dir(id);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).await?;
+    }
+    fs::create_dir_all(&dir).await?;
+
+    let mut sources = vec![];
+    for path in failed {
+        let from = if upload.recursive {
+            upload.cwd.join(path)
+        } else {
+            let Some(s) = upload
+                .sources
+                .iter()
+                .find(|x| x.file_name() == Some(OsStr::new(path)))
+            else {
+                continue;
+            };
+            upload.cwd.join(s)
+        };
+        let to = dir.join(path);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(from, to).await?;
+
+        // a recursive upload is kept as the directories it had
+        let source = match Path::new(path).components().next() {
+            Some(first) if upload.recursive => PathBuf::from(first.as_os_str()),
+            _ => PathBuf::from(path),
+        };
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+
+    let mut manifest = manifest.clone();
+    manifest.artifacts.retain(|a| failed.contains(&a.path));
+    manifest.total_bytes = manifest.artifacts.iter().map(|a| a.size).sum();
+    let kept = Kept {
+        upload: Upload {
+            cwd: dir.clone(),
+            sources,
+            ..upload
+        },
+        manifest,
+    };
+    let data = serde_json::to_vec(&kept)?;
+    fs::write(dir.join(UPLOAD_FILE), redact::bytes(&data)).await?;
+
+    Ok(kept
+        .manifest
+        .artifacts
+        .iter()
+        .map(|a| dir.join(&a.path).display().to_string())
+        .collect())
+}
+
+/// The kept artifacts of build `id`, `None` if they are gone.
+pub async fn get(id: i64) -> eyre::Result<Option<Kept>> {
+    let Ok(s) = fs::read(dir(id).join(UPLOAD_FILE)).await else {
+        return Ok(None);
+    };
+    let kept: Kept = serde_json::from_slice(&s)?;
+
+    let complete = kept
+        .upload
+        .sources
+        .iter()
+        .all(|x| kept.upload.cwd.join(x).exists());
+
+    Ok(complete.then_some(kept))
+}
+
+/// Hand the kept artifacts of build `from` over to build `to`, after a
+/// repush failed again. Returns their paths.
+pub async fn rename(from: i64, to: i64, mut kept: Kept) -> eyre::Result<Vec<String>> {
+    fs::rename(dir(from), dir(to)).await?;
+    kept.upload.cwd = dir(to);
+    let data = serde_json::to_vec(&kept)?;
+    fs::write(dir(to).join(UPLOAD_FILE), redact::bytes(&data)).await?;
+
+    Ok(kept
+        .manifest
+        .artifacts
+        .iter()
+        .map(|a| dir(to).join(&a.path).display().to_string())
+        .collect())
+}
+
+pub async fn remove(id: i64) -> eyre::Result<()> {
+    fs::remove_dir_all(dir(id)).await?;
+
+    Ok(())
+}
+
+/// Remove kept artifacts older than `max_age`.
+pub async fn prune(max_age: Duration) -> eyre::Result<()> {
+    let Ok(mut entries) = fs::read_dir(PUSH_FAILED_DIR).await else {
+        return Ok(());
+    };
+    let now = SystemTime::now();
+
+    while let Some(entry) = entries.next_entry().await? {
+        let modified = entry.metadata().await?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() > max_age {
+            if let Err(e) = fs::remove_dir_all(entry.path()).await {
+                warn!(
+                    "Failed to remove kept artifacts {}: {e}",
+                    entry.path().display()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,383 @@
+use std::{
+    io,
+    net::Ipv6Addr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, OptionExt};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::{info, warn};
+
+/// Where a pinned `upload_host_key` is written, in the work directory.
+const KNOWN_HOSTS_FILE: &str = "shipit_known_hosts";
+
+/// Options shared by every ssh-based transfer (scp uploads of logs and images).
+///
+/// Everything here is passed explicitly via `-o` so that the worker never
+/// depends on `~/.ssh/config` of the service user, and never waits for an
+/// interactive host key prompt.
+pub struct SshConfig {
+    pub key: String,
+    pub user: String,
+    pub host: String,
+    pub port: Option<u16>,
+    pub known_hosts: Option<PathBuf>,
+    pub strict_host_key_checking: String,
+}
+
+impl SshConfig {
+    pub async fn from_env() -> eyre::Result<Self> {
+        let key = std::env::var("upload_ssh_key")
+            .map_err(|_| eyre::eyre!("upload_ssh_key is not set"))?;
+        check_key(Path::new(&key)).await?;
+        let host = std::env::var("rsync_host").map_err(|_| eyre::eyre!("rsync_host is not set"))?;
+        let host = bare_host(&host)?;
+        let user = std::env::var("upload_ssh_user").unwrap_or_else(|_| "maintainers".to_string());
+        let port = match std::env::var("upload_ssh_port") {
+            Ok(p) => Some(p.parse()?),
+            Err(_) => None,
+        };
+
+        let strict_host_key_checking =
+            std::env::var("upload_strict_host_key_checking").unwrap_or_else(|_| "yes".to_string());
+
+        if !["yes", "accept-new", "no"].contains(&strict_host_key_checking.as_str()) {
+            bail!(
+                "Invalid upload_strict_host_key_checking: {} (expected yes, accept-new or no)",
+                strict_host_key_checking
+            );
+        }
+
+        let known_hosts = if let Ok(pinned) = std::env::var("upload_host_key") {
+            // absolute, as scp runs in the directories of the uploads
+            let path = std::env::current_dir()?.join(KNOWN_HOSTS_FILE);
+            write_known_hosts(&path, &known_hosts_line(&host, port, &pinned)?).await?;
+            info!("Pinned upload host key written to {}", path.display());
+            Some(path)
+        } else {
+            std::env::var("upload_known_hosts").ok().map(PathBuf::from)
+        };
+
+        if let Some(ref path) = known_hosts {
+            if !path.is_file() {
+                bail!("Known hosts file {} does not exist", path.display());
+            }
+        }
+
+        Ok(Self {
+            key,
+            user,
+            host,
+            port,
+            known_hosts,
+            strict_host_key_checking,
+        })
+    }
+
+    /// Common `-i`/`-o` options, valid for both `ssh` and `scp`.
+    pub fn options(&self) -> Vec<String> {
+        let mut args = vec![
+            "-i".to_string(),
+            self.key.clone(),
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            format!("StrictHostKeyChecking={}", self.strict_host_key_checking),
+        ];
+
+        if let Some(port) = self.port {
+            args.push("-o".to_string());
+            args.push(format!("Port={port}"));
+        }
+
+        if let Some(ref path) = self.known_hosts {
+            args.push("-o".to_string());
+            args.push(format!("UserKnownHostsFile={}", path.display()));
+        }
+
+        args
+    }
+
+    /// `user@host:path` destination for scp.
+    pub fn remote(&self, path: &str) -> String {
+        format!("{}@{}:{}", self.user, self.host, path)
+    }
+
+    /// Full argument list for `ssh <user@host> <command>`.
+    pub fn ssh_args(&self, command: &str) -> Vec<String> {
+        let mut args = self.options();
+        args.push(format!("{}@{}", self.user, self.host));
+        args.push(command.to_string());
+
+        args
+    }
+
+    /// Full argument list for `scp [-r] <sources> <user@host:dest>`.
+    pub fn scp_args(&self, sources: &[&Path], dest: &str, recursive: bool) -> Vec<String> {
+        let mut args = self.options();
+
+        if recursive {
+            args.push("-r".to_string());
+        }
+
+        args.extend(sources.iter().map(|x| local_source(x)));
+        args.push(self.remote(dest));
+
+        args
+    }
+}
+
+/// scp takes a source with a colon before any slash for `host:path`, such
+/// as a log named after the time, so a relative one gets a `./`.
+fn local_source(path: &Path) -> String {
+    if path.is_relative() && !path.starts_with(".") {
+        format!("./{}", path.display())
+    } else {
+        path.display().to_string()
+    }
+}
+
+/// `rsync_host` is a host name or address only, the user and port have
+/// variables of their own and the directories come from the publish
+/// settings.
+fn bare_host(host: &str) -> eyre::Result<String> {
+    let host = host.trim();
+    if host.is_empty() {
+        bail!("rsync_host is empty");
+    }
+    if host.contains("://") {
+        bail!("rsync_host {host} is a URL, expected a bare host name such as repo.aosc.io");
+    }
+    if let Some((user, rest)) = host.split_once('@') {
+        bail!(
+            "rsync_host {host} includes the user {user}, set rsync_host={rest} and upload_ssh_user={user}"
+        );
+    }
+    if let Some((name, path)) = host.split_once('/') {
+        bail!(
+            "rsync_host {host} includes the path /{path}, \
+            set rsync_host={name} and put the path into upload_*_dir"
+        );
+    }
+    // an IPv6 address has colons of its own
+    let ipv6 = host
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(host);
+    if ipv6.parse::<Ipv6Addr>().is_ok() {
+        return Ok(host.to_string());
+    }
+    if let Some((name, rest)) = host.split_once(':') {
+        match rest.parse::<u16>() {
+            Ok(port) => bail!(
+                "rsync_host {host} includes the port, set rsync_host={name} and upload_ssh_port={port}"
+            ),
+            Err(_) => bail!(
+                "rsync_host {host} includes a path, \
+                set rsync_host={name} and put the path into upload_*_dir"
+            ),
+        }
+    }
+    if host.contains(char::is_whitespace) {
+        bail!("rsync_host {host:?} contains whitespace");
+    }
+
+    Ok(host.to_string())
+}
+
+/// `upload_ssh_key` is a regular file, which ssh refuses to use if others
+/// may read it.
+async fn check_key(path: &Path) -> eyre::Result<()> {
+    let meta = match fs::metadata(path).await {
+        Ok(x) => x,
+        Err(e) => bail!("upload_ssh_key {}: {e}", path.display()),
+    };
+    if !meta.is_file() {
+        bail!("upload_ssh_key {} is not a regular file", path.display());
+    }
+
+    let mode = meta.permissions().mode();
+    if mode & 0o077 != 0 {
+        warn!(
+            "upload_ssh_key {} is accessible by group or others (mode {:o}), ssh may refuse it; chmod 600 it",
+            path.display(),
+            mode & 0o777
+        );
+    }
+
+    Ok(())
+}
+
+/// Write the pinned key for the worker user alone, into a file made anew
+/// so that nothing planted at `path` is followed.
+async fn write_known_hosts(path: &Path, line: &str) -> eyre::Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => bail!("Failed to replace {}: {e}", path.display()),
+    }
+
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await?;
+    f.write_all(line.as_bytes()).await?;
+
+    Ok(())
+}
+
+fn known_hosts_line(host: &str, port: Option<u16>, key: &str) -> eyre::Result<String> {
+    let key = key.trim();
+    let mut split = key.split_ascii_whitespace();
+    let key_type = split.next().ok_or_eyre("upload_host_key is empty")?;
+    let key_data = split
+        .next()
+        .ok_or_eyre("upload_host_key should look like `ssh-ed25519 AAAA...`")?;
+
+    let host = match port {
+        Some(p) if p != 22 => format!("[{host}]:{p}"),
+        _ => host.to_string(),
+    };
+
+    Ok(format!("{host} {key_type} {key_data}\n"))
+}
+
+/// Quote `s` for the remote shell.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> SshConfig {
+        SshConfig {
+            key: "/etc/shipit/id_ed25519".to_string(),
+            user: "maintainers".to_string(),
+            host: "repo.aosc.io".to_string(),
+            port: None,
+            known_hosts: None,
+            strict_host_key_checking: "yes".to_string(),
+        }
+    }
+
+    #[test]
+    fn scp_sources_are_never_remote() {
+        let args = config().scp_args(
+            &[
+                Path::new("shipit-livekit-amd64-2024-05-01-12:30:00.txt"),
+                Path::new("./aosc-os.iso"),
+                Path::new("/var/cache/a:b"),
+            ],
+            "/buildit/logs",
+            false,
+        );
+
+        assert_eq!(
+            args[args.len() - 4..],
+            [
+                "./shipit-livekit-amd64-2024-05-01-12:30:00.txt",
+                "./aosc-os.iso",
+                "/var/cache/a:b",
+                "maintainers@repo.aosc.io:/buildit/logs",
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_hosts_are_taken_as_they_are() {
+        for host in [
+            "repo.aosc.io",
+            " repo.aosc.io\n",
+            "10.0.0.2",
+            "::1",
+            "[2001:db8::1]",
+        ] {
+            assert_eq!(bare_host(host).unwrap(), host.trim());
+        }
+    }
+
+    #[test]
+    fn more_than_a_host_says_where_the_rest_goes() {
+        for (host, hint) in [
+            ("repo.aosc.io/", "put the path into upload_*_dir"),
+            ("maintainers@repo.aosc.io", "upload_ssh_user=maintainers"),
+            ("repo.aosc.io:2222", "upload_ssh_port=2222"),
+            ("repo.aosc.io:/buildit", "put the path into upload_*_dir"),
+            ("repo.aosc.io/buildit", "put the path into upload_*_dir"),
+            ("ssh://repo.aosc.io", "is a URL"),
+            ("https://repo.aosc.io/buildit", "is a URL"),
+            ("repo aosc.io", "contains whitespace"),
+            ("  ", "is empty"),
+        ] {
+            let e = bare_host(host).unwrap_err().to_string();
+            assert!(e.contains(hint), "{host}: {e}");
+        }
+    }
+
+    #[test]
+    fn base_urls_lose_their_trailing_slashes() {
+        for (value, url) in [
+            ("https://buildit.aosc.io", "https://buildit.aosc.io"),
+            ("https://buildit.aosc.io/", "https://buildit.aosc.io"),
+            ("https://buildit.aosc.io//", "https://buildit.aosc.io"),
+            (
+                " http://10.0.0.2:8080/shipit/\n",
+                "http://10.0.0.2:8080/shipit",
+            ),
+            (
+                "https://maintainers@buildit.aosc.io/",
+                "https://maintainers@buildit.aosc.io",
+            ),
+        ] {
+            assert_eq!(shipit_common::base_url("shipit_uri", value).unwrap(), url);
+        }
+    }
+
+    #[test]
+    fn base_urls_must_be_http() {
+        for (value, problem) in [
+            ("buildit.aosc.io", "relative URL without a base"),
+            ("ssh://buildit.aosc.io", "expected an http or https URL"),
+            (
+                "https://buildit.aosc.io/?token=1",
+                "takes no query or fragment",
+            ),
+            ("https://buildit.aosc.io/#top", "takes no query or fragment"),
+        ] {
+            let e = shipit_common::base_url("shipit_uri", value).unwrap_err();
+            assert!(e.starts_with("Invalid shipit_uri"), "{e}");
+            assert!(e.contains(problem), "{value}: {e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn known_hosts_replaces_a_planted_symlink() {
+        let dir = std::env::temp_dir().join(format!("shipit-ssh-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let target = dir.join("target");
+        fs::write(&target, "untouched").await.unwrap();
+        let path = dir.join(KNOWN_HOSTS_FILE);
+        let _ = fs::remove_file(&path).await;
+        fs::symlink(&target, &path).await.unwrap();
+
+        let line =
+            known_hosts_line("repo.aosc.io", Some(2222), "ssh-ed25519 AAAA comment").unwrap();
+        write_known_hosts(&path, &line).await.unwrap();
+
+        assert_eq!(fs::read_to_string(&target).await.unwrap(), "untouched");
+        assert_eq!(
+            fs::read_to_string(&path).await.unwrap(),
+            "[repo.aosc.io]:2222 ssh-ed25519 AAAA\n"
+        );
+        let meta = fs::symlink_metadata(&path).await.unwrap();
+        assert!(meta.is_file());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
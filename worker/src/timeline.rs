@@ -0,0 +1,100 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use shipit_common::Step;
+
+/// The commands run for the current job, shared with the heartbeat task.
+#[derive(Clone, Default)]
+pub struct Timeline(Arc<Mutex<Vec<Step>>>);
+
+impl Timeline {
+    /// Record the start of a step, returns its index for [`Timeline::finish`].
+    pub fn start(&self, name: String) -> usize {
+        let mut steps = self.0.lock().unwrap();
+        steps.push(Step {
+            name,
+            started_at: now(),
+            finished_at: None,
+            exit_code: None,
+        });
+
+        steps.len() - 1
+    }
+
+    pub fn finish(&self, i: usize, exit_code: Option<i32>) {
+        if let Some(step) = self.0.lock().unwrap().get_mut(i) {
+            step.finished_at = Some(now());
+            step.exit_code = exit_code;
+        }
+    }
+
+    /// Mark steps that never finished, e.g. because reading their output
+    /// failed, as failed.
+    pub fn close(&self) {
+        for step in self.0.lock().unwrap().iter_mut() {
+            if step.finished_at.is_none() {
+                step.finished_at = Some(now());
+            }
+        }
+    }
+
+    pub fn steps(&self) -> Vec<Step> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Seconds spent per [`phase`], for finished steps.
+    pub fn phase_durations(&self) -> BTreeMap<String, u64> {
+        let mut map = BTreeMap::new();
+
+        for step in self.0.lock().unwrap().iter() {
+            if let Some(end) = step.finished_at {
+                *map.entry(phase(&step.name).to_string()).or_default() +=
+                    end.saturating_sub(step.started_at);
+            }
+        }
+
+        map
+    }
+}
+
+/// What a step spent its time on: `git`, `build`, `checksum`, `upload` or
+/// `prune`.
+pub fn phase(step_name: &str) -> &'static str {
+    match step_name {
+        x if x.starts_with("git ") => "git",
+        "checksum" => "checksum",
+        "scp" | "ssh mkdir" => "upload",
+        x if x.starts_with("ssh") => "prune",
+        _ => "build",
+    }
+}
+
+/// Short name of a command: `git clone`, `ssh mkdir`, or the script run by
+/// bash.
+pub fn step_name(cmd: &str, args: &[&str]) -> String {
+    match (cmd, args.first()) {
+        ("bash", Some(script)) => Path::new(script)
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| script.to_string()),
+        ("git", Some(sub)) => format!("git {sub}"),
+        // variables go first, named after the command they are set for
+        ("env", _) => match args.iter().position(|x| !x.contains('=')) {
+            Some(i) => step_name(args[i], &args[i + 1..]),
+            None => cmd.to_string(),
+        },
+        // the remote command comes last, after the options
+        ("ssh", _) => match args.last().and_then(|x| x.split_whitespace().next()) {
+            Some(remote) => format!("ssh {remote}"),
+            None => cmd.to_string(),
+        },
+        _ => cmd.to_string(),
+    }
+}
+
+pub fn now() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
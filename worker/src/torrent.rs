@@ -0,0 +1,339 @@
+//! `.torrent` files for the ISOs, uploaded next to them. They are v1
+//! single-file torrents announcing to `torrent_trackers`, with the URL the
+//! ISO is published at as web seed, so the mirror seeds until peers do.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::Local;
+use sha1_smol::Sha1;
+use shipit_common::Channel;
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
+};
+use tracing::info;
+
+use crate::{joblog::JobLog, manifest::Publish};
+
+/// Which builds get torrents, and where they announce.
+pub struct Torrents {
+    trackers: Vec<String>,
+    channels: Vec<Channel>,
+}
+
+impl Torrents {
+    /// `torrent_trackers`, announce URLs separated by whitespace or commas,
+    /// and `torrent_channels` (default `release`), the channels to make
+    /// torrents for. `None` if there are no trackers.
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        let list = |name| {
+            std::env::var(name).ok().map(|x| {
+                x.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let Some(trackers) = list("torrent_trackers").filter(|x| !x.is_empty()) else {
+            return Ok(None);
+        };
+        let channels = match list("torrent_channels") {
+            Some(names) => names
+                .iter()
+                .map(|x| match x.as_str() {
+                    "release" => Ok(Channel::Release),
+                    "nightly" => Ok(Channel::Nightly),
+                    _ => Err(eyre::eyre!(
+                        "Invalid torrent_channels {x}, expected release or nightly"
+                    )),
+                })
+                .collect::<eyre::Result<_>>()?,
+            None => vec![Channel::Release],
+        };
+
+        Ok(Some(Self { trackers, channels }))
+    }
+
+    /// Write `<name>.torrent` next to every ISO below `dir`, a directory in
+    /// `cwd` uploaded as is to `publish`. Nothing for channels without
+    /// torrents and private uploads. Returns the torrents written.
+    pub async fn create(
+        &self,
+        cwd: &Path,
+        dir: &str,
+        publish: &Publish,
+        log: &mut JobLog,
+    ) -> eyre::Result<Vec<String>> {
+        if !self.channels.contains(&publish.channel) || publish.private {
+            return Ok(vec![]);
+        }
+
+        let mut written = vec![];
+        let mut stack: Vec<PathBuf> = vec![cwd.join(dir)];
+        while let Some(d) = stack.pop() {
+            let mut entries = fs::read_dir(&d).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+                if path.extension().is_none_or(|x| x != "iso") {
+                    continue;
+                }
+
+                let rel = path
+                    .strip_prefix(cwd)
+                    .unwrap_or(&path)
+                    .display()
+                    .to_string();
+                let web_seed = publish
+                    .base_url
+                    .as_ref()
+                    .map(|base| format!("{}/{rel}", base.trim_end_matches('/')));
+                let (torrent, pieces) = self.torrent(&path, web_seed).await?;
+
+                let name = format!("{}.torrent", entry.file_name().to_string_lossy());
+                fs::write(path.with_file_name(&name), torrent).await?;
+
+                let msg = format!("{}: Created {name}, {pieces} pieces\n", Local::now());
+                log.write(msg.as_bytes()).await?;
+                info!("{}", msg.trim());
+                written.push(name);
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// The bencoded torrent of `path` and its number of pieces.
+    async fn torrent(&self, path: &Path, web_seed: Option<String>) -> eyre::Result<(Vec<u8>, u64)> {
+        let length = fs::metadata(path).await?.len();
+        let piece_length = piece_length(length);
+
+        // SHA-1 of each piece, one after another
+        let mut pieces = vec![];
+        let mut file = File::open(path).await?;
+        let mut buf = vec![0; piece_length as usize];
+        loop {
+            let mut n = 0;
+            while n < buf.len() {
+                let read = file.read(&mut buf[n..]).await?;
+                if read == 0 {
+                    break;
+                }
+                n += read;
+            }
+            if n == 0 {
+                break;
+            }
+            pieces.extend(Sha1::from(&buf[..n]).digest().bytes());
+        }
+        let count = pieces.len() as u64 / 20;
+
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let info = Value::dict([
+            ("length", Value::Int(length as i64)),
+            ("name", Value::str(&name)),
+            ("piece length", Value::Int(piece_length as i64)),
+            ("pieces", Value::Bytes(pieces)),
+        ]);
+
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut torrent = vec![
+            ("announce", Value::str(&self.trackers[0])),
+            ("created by", Value::str("shipit")),
+            ("creation date", Value::Int(created as i64)),
+            ("info", info),
+        ];
+        if self.trackers.len() > 1 {
+            // one tier each, tried in order
+            let tiers = self
+                .trackers
+                .iter()
+                .map(|x| Value::List(vec![Value::str(x)]))
+                .collect();
+            torrent.push(("announce-list", Value::List(tiers)));
+        }
+        if let Some(url) = web_seed {
+            torrent.push(("url-list", Value::List(vec![Value::str(&url)])));
+        }
+
+        let mut out = vec![];
+        Value::dict(torrent).encode(&mut out);
+
+        Ok((out, count))
+    }
+}
+
+/// About 1500 pieces, a power of two between 256 KiB and 16 MiB each.
+fn piece_length(size: u64) -> u64 {
+    (size / 1500).next_power_of_two().clamp(256 << 10, 16 << 20)
+}
+
+/// What bencode has.
+enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    /// Sorted by key, as bencode wants.
+    Dict(BTreeMap<&'static str, Value>),
+}
+
+impl Value {
+    fn str(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn dict(entries: impl IntoIterator<Item = (&'static str, Value)>) -> Self {
+        Value::Dict(entries.into_iter().collect())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(i) => out.extend(format!("i{i}e").as_bytes()),
+            Value::Bytes(b) => {
+                out.extend(format!("{}:", b.len()).as_bytes());
+                out.extend(b);
+            }
+            Value::List(l) => {
+                out.push(b'l');
+                for x in l {
+                    x.encode(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(d) => {
+                out.push(b'd');
+                for (k, v) in d {
+                    Value::str(k).encode(out);
+                    v.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Workdir;
+
+    fn torrents(trackers: &[&str]) -> Torrents {
+        Torrents {
+            trackers: trackers.iter().map(|x| x.to_string()).collect(),
+            channels: vec![Channel::Release],
+        }
+    }
+
+    fn encoded(v: Value) -> String {
+        let mut out = vec![];
+        v.encode(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    /// `torrent` with the creation date, the one thing that changes, left
+    /// out.
+    fn undated(torrent: &[u8]) -> Vec<u8> {
+        let key = b"13:creation datei";
+        let at = torrent.windows(key.len()).position(|x| x == key).unwrap() + key.len();
+        let end = at + torrent[at..].iter().position(|x| *x == b'e').unwrap();
+        assert!(torrent[at..end].iter().all(|x| x.is_ascii_digit()));
+
+        [&torrent[..at], &torrent[end..]].concat()
+    }
+
+    #[test]
+    fn values_are_bencoded() {
+        assert_eq!(encoded(Value::Int(42)), "i42e");
+        assert_eq!(encoded(Value::Int(-3)), "i-3e");
+        assert_eq!(encoded(Value::str("spam")), "4:spam");
+        assert_eq!(encoded(Value::str("")), "0:");
+        assert_eq!(
+            encoded(Value::List(vec![Value::str("spam"), Value::Int(42)])),
+            "l4:spami42ee"
+        );
+        // keys in byte order, whatever order they were given in
+        assert_eq!(
+            encoded(Value::dict([
+                ("url-list", Value::Int(3)),
+                ("announce-list", Value::Int(2)),
+                ("announce", Value::Int(1)),
+            ])),
+            "d8:announcei1e13:announce-listi2e8:url-listi3ee"
+        );
+    }
+
+    #[test]
+    fn pieces_are_a_power_of_two_between_256_kib_and_16_mib() {
+        assert_eq!(piece_length(0), 256 << 10);
+        assert_eq!(piece_length(1 << 20), 256 << 10);
+        assert_eq!(piece_length(1500 << 20), 1 << 20);
+        assert_eq!(piece_length((1500 << 20) + 1500), 2 << 20);
+        assert_eq!(piece_length(1500 * (16 << 20)), 16 << 20);
+        assert_eq!(piece_length(100 << 30), 16 << 20);
+    }
+
+    #[tokio::test]
+    async fn a_small_iso_gets_a_torrent_of_two_pieces() {
+        let dir = Workdir::enter("torrent").await;
+        let path = dir.path.join("aosc-os_base_20240501_amd64.iso");
+        let data = (0..300 << 10).map(|x| x as u8).collect::<Vec<_>>();
+        fs::write(&path, &data).await.unwrap();
+        let url = "https://releases.aosc.io/os-amd64/base/aosc-os_base_20240501_amd64.iso";
+
+        let (torrent, pieces) = torrents(&["udp://a.example:6969", "https://b.example/announce"])
+            .torrent(&path, Some(url.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(pieces, 2);
+        let hashes = [&data[..256 << 10], &data[256 << 10..]]
+            .iter()
+            .flat_map(|x| Sha1::from(x).digest().bytes())
+            .collect::<Vec<_>>();
+        let expected = [
+            b"d8:announce20:udp://a.example:6969".as_slice(),
+            b"13:announce-listll20:udp://a.example:6969el26:https://b.example/announceee",
+            b"10:created by6:shipit13:creation datei",
+            b"e4:infod6:lengthi307200e4:name31:aosc-os_base_20240501_amd64.iso",
+            b"12:piece lengthi262144e6:pieces40:",
+            &hashes,
+            format!("e8:url-listl{}:{url}ee", url.len()).as_bytes(),
+        ]
+        .concat();
+        assert_eq!(
+            String::from_utf8_lossy(&undated(&torrent)),
+            String::from_utf8_lossy(&expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn one_tracker_needs_no_announce_list() {
+        let dir = Workdir::enter("torrent-one").await;
+        let path = dir.path.join("empty.iso");
+        fs::write(&path, b"").await.unwrap();
+
+        let (torrent, pieces) = torrents(&["udp://a.example:6969"])
+            .torrent(&path, None)
+            .await
+            .unwrap();
+
+        assert_eq!(pieces, 0);
+        assert_eq!(
+            String::from_utf8(undated(&torrent)).unwrap(),
+            "d8:announce20:udp://a.example:696910:created by6:shipit13:creation datei\
+             e4:infod6:lengthi0e4:name9:empty.iso12:piece lengthi262144e6:pieces0:ee"
+        );
+    }
+}
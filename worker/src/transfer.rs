@@ -0,0 +1,185 @@
+//! How far the upload of the artifacts is, for `/status`. scp tells nothing
+//! without a terminal, so while it runs the sizes of the artifacts on the
+//! upload host are polled over ssh, and the heartbeats send the artifact
+//! being uploaded along. The server is also told right away when the build
+//! script exits, so it can tell the time spent building from the time spent
+//! publishing.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use shipit_common::UploadProgress;
+use tokio::{
+    fs,
+    sync::Notify,
+    time::{sleep, Instant},
+};
+use tracing::warn;
+
+use crate::{
+    spool::Upload,
+    ssh::{shell_quote, SshConfig},
+};
+
+/// How often the sizes on the upload host are polled, and the heartbeats
+/// sent while uploading.
+pub const INTERVAL: Duration = Duration::from_secs(30);
+
+/// The upload in progress, shared with the heartbeat task.
+#[derive(Clone, Default)]
+pub struct Transfer {
+    progress: Arc<Mutex<Option<UploadProgress>>>,
+    publishing: Arc<Notify>,
+}
+
+impl Transfer {
+    pub fn get(&self) -> Option<UploadProgress> {
+        self.progress.lock().unwrap().clone()
+    }
+
+    fn set(&self, progress: Option<UploadProgress>) {
+        *self.progress.lock().unwrap() = progress;
+    }
+
+    /// The build script has exited, what follows is publishing.
+    pub fn publishing(&self) {
+        self.publishing.notify_one();
+    }
+
+    /// Wait for [`Transfer::publishing`], which is not missed if called
+    /// before.
+    pub async fn wait_publishing(&self) {
+        self.publishing.notified().await;
+    }
+
+    /// Nothing is uploading any more.
+    pub fn clear(&self) {
+        self.set(None);
+    }
+}
+
+/// A source of an upload and its size here.
+struct Artifact {
+    name: String,
+    size: u64,
+}
+
+/// The sources of `upload` in the order scp sends them.
+async fn artifacts(upload: &Upload) -> eyre::Result<Vec<Artifact>> {
+    let mut v = vec![];
+    for source in &upload.sources {
+        let Some(name) = source.file_name() else {
+            continue;
+        };
+        v.push(Artifact {
+            name: name.to_string_lossy().to_string(),
+            size: size(&upload.cwd.join(source)).await?,
+        });
+    }
+
+    Ok(v)
+}
+
+/// Bytes of the file at `path`, or of the files below it.
+async fn size(path: &Path) -> eyre::Result<u64> {
+    let mut total = 0;
+    let mut stack: Vec<PathBuf> = vec![path.to_path_buf()];
+    while let Some(p) = stack.pop() {
+        let meta = fs::metadata(&p).await?;
+        if !meta.is_dir() {
+            total += meta.len();
+            continue;
+        }
+
+        let mut entries = fs::read_dir(&p).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            stack.push(entry.path());
+        }
+    }
+
+    Ok(total)
+}
+
+/// Bytes of each of `names` in `dir` on the upload host, as `du` counts
+/// them. Missing ones are left out.
+async fn remote_sizes(
+    ssh: &SshConfig,
+    dir: &str,
+    names: &[&str],
+) -> eyre::Result<BTreeMap<String, u64>> {
+    let command = format!(
+        "cd {} && du -sb -- {} 2>/dev/null",
+        shell_quote(dir),
+        names
+            .iter()
+            .map(|x| shell_quote(x))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let output = tokio::process::Command::new("ssh")
+        .args(ssh.ssh_args(&command))
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    // du fails on the ones not there yet, the rest is still listed
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (size, name) = line.split_once('\t')?;
+            Some((name.to_string(), size.parse().ok()?))
+        })
+        .collect())
+}
+
+/// Keep `transfer` at the artifact of `upload` being uploaded until
+/// dropped: the first one smaller on the upload host than here.
+pub async fn watch(ssh: &SshConfig, upload: &Upload, transfer: &Transfer) {
+    let artifacts = match artifacts(upload).await {
+        Ok(x) if !x.is_empty() => x,
+        Ok(_) => return,
+        Err(e) => {
+            warn!("Not reporting the upload progress, failed to size the artifacts: {e}");
+            return;
+        }
+    };
+    let names = artifacts
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>();
+
+    let mut last = (0, Instant::now());
+    loop {
+        sleep(INTERVAL).await;
+
+        let sizes = match remote_sizes(ssh, &upload.publish.dir, &names).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to poll the upload progress: {e}");
+                continue;
+            }
+        };
+
+        let sent = |a: &Artifact| sizes.get(&a.name).copied().unwrap_or(0).min(a.size);
+        let total_sent = artifacts.iter().map(sent).sum::<u64>();
+        if total_sent != last.0 {
+            last = (total_sent, Instant::now());
+        }
+
+        let current = artifacts
+            .iter()
+            .find(|a| sent(a) < a.size)
+            .unwrap_or(&artifacts[artifacts.len() - 1]);
+        transfer.set(Some(UploadProgress {
+            artifact: current.name.clone(),
+            sent: sent(current),
+            total: current.size,
+            stalled_secs: last.1.elapsed().as_secs(),
+        }));
+    }
+}
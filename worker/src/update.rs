@@ -0,0 +1,191 @@
+use std::{
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    path::Path,
+    time::Duration,
+};
+
+use eyre::bail;
+use reqwest::StatusCode;
+use shipit_common::WorkerRelease;
+use tokio::{fs, process::Command, time::Instant};
+use tracing::{error, info};
+
+use crate::{checksum::sha256_file, Server};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Replacing the worker binary with the one `/worker/latest` points to.
+/// Disabled unless `self_update` is set.
+pub struct SelfUpdate {
+    interval: Duration,
+    /// minisign public key, required to accept a signed release.
+    pubkey: Option<String>,
+    last_check: Option<Instant>,
+}
+
+impl SelfUpdate {
+    /// `self_update` (default false), `self_update_interval_secs` (default
+    /// 3600) and `self_update_pubkey`.
+    pub fn from_env() -> eyre::Result<Option<Self>> {
+        let enabled = match std::env::var("self_update") {
+            Ok(x) => x.parse()?,
+            Err(_) => false,
+        };
+
+        if !enabled {
+            return Ok(None);
+        }
+
+        let interval = match std::env::var("self_update_interval_secs") {
+            Ok(x) => Duration::from_secs(x.parse()?),
+            Err(_) => Duration::from_secs(3600),
+        };
+
+        Ok(Some(Self {
+            interval,
+            pubkey: std::env::var("self_update_pubkey").ok(),
+            last_check: None,
+        }))
+    }
+
+    /// Update and re-exec if the server offers another version and the last
+    /// check is long enough ago. Only call this between builds. Returns only
+    /// if nothing was replaced.
+    pub async fn check(&mut self, server: &Server, arch: &str) {
+        if self.last_check.is_some_and(|x| x.elapsed() < self.interval) {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+
+        let release = match latest(server, arch).await {
+            Ok(Some(r)) if r.version != VERSION => r,
+            Ok(_) => return,
+            Err(e) => {
+                error!("Failed to check for a worker update: {e}");
+                return;
+            }
+        };
+
+        info!("Updating worker from {VERSION} to {}", release.version);
+
+        let exe = match std::env::current_exe() {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to locate the worker binary, not updating: {e}");
+                return;
+            }
+        };
+
+        let tmp = exe.with_extension("new");
+        if let Err(e) = self.install(server, &release, &exe, &tmp).await {
+            error!(
+                "WORKER UPDATE TO {} FAILED, still running {VERSION}: {e}",
+                release.version
+            );
+            fs::remove_file(&tmp).await.ok();
+            return;
+        }
+
+        info!("Worker updated to {}, restarting", release.version);
+        let e = std::process::Command::new(&exe)
+            .args(std::env::args_os().skip(1))
+            .exec();
+        error!(
+            "Failed to re-exec {} after the update, restart the worker by hand: {e}",
+            exe.display()
+        );
+    }
+
+    /// Download and verify `release` to `tmp`, then move it over `exe`.
+    async fn install(
+        &self,
+        server: &Server,
+        release: &WorkerRelease,
+        exe: &Path,
+        tmp: &Path,
+    ) -> eyre::Result<()> {
+        let bytes = server
+            .client
+            .get(&release.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        fs::write(tmp, &bytes).await?;
+
+        let sha256 = sha256_file(tmp).await?;
+        if !sha256.eq_ignore_ascii_case(release.sha256.trim()) {
+            bail!("sha256 mismatch: expected {}, got {sha256}", release.sha256);
+        }
+
+        match (&release.signature_url, &self.pubkey) {
+            (Some(url), Some(key)) => self.verify(server, url, key, tmp).await?,
+            (None, Some(_)) => bail!("self_update_pubkey is set but the release is not signed"),
+            _ => {}
+        }
+
+        fs::set_permissions(tmp, std::fs::Permissions::from_mode(0o755)).await?;
+        // same directory, so this is atomic
+        fs::rename(tmp, exe).await?;
+
+        Ok(())
+    }
+
+    async fn verify(&self, server: &Server, url: &str, key: &str, tmp: &Path) -> eyre::Result<()> {
+        let sig = tmp.with_extension("minisig");
+        let bytes = server
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        fs::write(&sig, &bytes).await?;
+
+        let output = Command::new("minisign")
+            .arg("-V")
+            .arg("-P")
+            .arg(key)
+            .arg("-m")
+            .arg(tmp)
+            .arg("-x")
+            .arg(&sig)
+            .output()
+            .await;
+        fs::remove_file(&sig).await.ok();
+
+        let output = output?;
+        if !output.status.success() {
+            bail!(
+                "signature verification failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// What the server wants running on `arch`, `None` if it has no opinion.
+async fn latest(server: &Server, arch: &str) -> eyre::Result<Option<WorkerRelease>> {
+    let resp = server
+        .client
+        .get(format!("{}/worker/latest", server.uri))
+        .header("secret", &server.secret)
+        .query(&[("arch", arch)])
+        .send()
+        .await?;
+
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let release = resp.error_for_status()?.json::<WorkerRelease>().await?;
+    if release.url.is_empty() {
+        bail!("the server sent an empty download URL");
+    }
+
+    Ok(Some(release))
+}
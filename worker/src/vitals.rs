@@ -0,0 +1,92 @@
+//! How the machine of this worker is doing, for `/ping` and the worker
+//! list of the server.
+
+use std::time::Duration;
+
+use eyre::{bail, eyre, OptionExt};
+use tokio::{fs, process::Command, time::Instant};
+use tracing::warn;
+
+/// How often load and free disk are read again while polling for jobs.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Default)]
+pub struct Vitals {
+    pub load_avg: Option<[f64; 3]>,
+    pub free_disk: Option<u64>,
+}
+
+impl Vitals {
+    pub async fn read() -> Self {
+        let load_avg = match load_avg().await {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("Failed to read the load average: {e}");
+                None
+            }
+        };
+        let free_disk = match free_disk().await {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("Failed to get the free disk space: {e}");
+                None
+            }
+        };
+
+        Self {
+            load_avg,
+            free_disk,
+        }
+    }
+}
+
+/// [`Vitals`] read at most every [`CHECK_INTERVAL`].
+#[derive(Default)]
+pub struct VitalsCheck {
+    last: Option<(Instant, Vitals)>,
+}
+
+impl VitalsCheck {
+    pub async fn get(&mut self) -> Vitals {
+        if let Some((at, ref vitals)) = self.last {
+            if at.elapsed() < CHECK_INTERVAL {
+                return vitals.clone();
+            }
+        }
+
+        let vitals = Vitals::read().await;
+        self.last = Some((Instant::now(), vitals.clone()));
+
+        vitals
+    }
+}
+
+async fn load_avg() -> eyre::Result<[f64; 3]> {
+    let s = fs::read_to_string("/proc/loadavg").await?;
+    let mut fields = s.split_ascii_whitespace().map(|x| x.parse::<f64>());
+
+    let mut res = [0.0; 3];
+    for x in &mut res {
+        *x = fields.next().ok_or_eyre("short /proc/loadavg")??;
+    }
+
+    Ok(res)
+}
+
+/// Bytes available in the working directory, where builds happen.
+pub async fn free_disk() -> eyre::Result<u64> {
+    let out = Command::new("df").args(["-Pk", "."]).output().await?;
+    if !out.status.success() {
+        bail!("df exited with {}", out.status);
+    }
+
+    // Filesystem 1024-blocks Used Available Capacity Mounted on
+    let s = String::from_utf8_lossy(&out.stdout);
+    let available = s
+        .lines()
+        .nth(1)
+        .and_then(|x| x.split_ascii_whitespace().nth(3))
+        .ok_or_else(|| eyre!("unexpected df output: {s}"))?;
+
+    Ok(available.parse::<u64>()? * 1024)
+}